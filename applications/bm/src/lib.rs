@@ -1,5 +1,6 @@
 //! A collection of micro-benchmarks for Theseus. 
-//! They include null syscall, context switching, process creation, memory mapping, IPC and file system benchmarks.
+//! They include null syscall, context switching, process creation, memory mapping, IPC, file system,
+//! sleep wakeup latency, and lazy vs. eager linking benchmarks.
 //! 
 //! To run the memory mapping benchmark, Theseus should be compiled with the "bm_map" configuration option.
 //! To run the IPC benchmarks, Theseus should be compiled with the "bm_ipc" configuration option.
@@ -28,8 +29,10 @@ extern crate simple_ipc;
 extern crate getopts;
 extern crate pmu_x86;
 extern crate mod_mgmt;
+extern crate sleep;
 
 use core::str;
+use core::time::Duration;
 use alloc::vec::Vec;
 use alloc::string::{String, ToString};
 use alloc::sync::Arc;
@@ -40,7 +43,7 @@ use fs_node::{DirRef, FileOrDir, FileRef};
 use libtest::*;
 use memory::{create_mapping, EntryFlags};
 use getopts::Options;
-use mod_mgmt::crate_name_from_path;
+use mod_mgmt::{crate_name_from_path, CrateNamespace};
 
 const SEC_TO_NANO: u64 = 1_000_000_000;
 const SEC_TO_MICRO: u64 = 1_000_000;
@@ -89,6 +92,8 @@ pub fn main(args: Vec<String>) -> isize {
     opts.optflag("", "fs_create", "file create");
     opts.optflag("", "fs_delete", "file delete");
     opts.optflag("", "fs", "test code for checking FS' ability");
+    opts.optflag("", "sleep", "wakeup latency of sleeping for short durations");
+    opts.optflagopt("", "lazy_link", "application crate loading time with lazy vs. eager linking (default: wasm)", "APP");
 
     opts.optflag("a", "async", "Run IPC bm for the async channel");
     opts.optflag("r", "rendezvous", "Run IPC bm for the rendezvous channel");
@@ -181,6 +186,10 @@ pub fn main(args: Vec<String>) -> isize {
 			do_fs_delete()
 		} else if matches.opt_present("fs") {
 			do_fs_cap_check()
+		} else if matches.opt_present("sleep") {
+			do_sleep()
+		} else if matches.opt_present("lazy_link") {
+			do_lazy_link(&matches.opt_str("lazy_link").unwrap_or_else(|| String::from("wasm")))
 		} else {
 			printlnwarn!("Unknown command: {}", args[0]);
 			print_usage(opts);
@@ -1542,6 +1551,134 @@ fn do_fs_delete_inner(fsize_b: usize, overhead_ct: u64) -> Result<(), &'static s
 
 
 
+/// Measures the wakeup latency of `sleep()`, i.e., how much later than the requested duration
+/// a sleeping task actually resumes execution, for several short sleep durations.
+/// Calls `do_sleep_inner` multiple times for each duration to perform the actual operation.
+/// 
+/// This also reports how many timer interrupts the other cores took during the measurement,
+/// which shows whether idle cores have stopped their periodic tick (the `tickless` config option).
+fn do_sleep() -> Result<(), &'static str> {
+	const SLEEP_DURATIONS_US: [u64; 4] = [10, 100, 1000, 10_000];
+	const SLEEP_ITERATIONS: usize = 100;
+
+	let my_core = CPU_ID!();
+	print_header(TRIES, SLEEP_ITERATIONS);
+
+	let start_time = sleep::get_current_time();
+	let start_interrupts = other_cores_timer_interrupts(my_core);
+
+	for duration_us in SLEEP_DURATIONS_US {
+		let mut vec = Vec::with_capacity(TRIES);
+		for i in 0..TRIES {
+			let lat = do_sleep_inner(duration_us, SLEEP_ITERATIONS, i+1, TRIES)?;
+			vec.push(lat);
+		}
+		let stats = calculate_stats(&vec).ok_or("couldn't calculate stats")?;
+		printlninfo!("SLEEP {} us wakeup latency result: ({})", duration_us, T_UNIT);
+		printlninfo!("{:?}", stats);
+	}
+
+	let elapsed = sleep::get_current_time() - start_time;
+	let interrupts = other_cores_timer_interrupts(my_core) - start_interrupts;
+	printlninfo!("Other cores took {} timer interrupts during {} ms", interrupts, elapsed.as_millis());
+	printlninfo!("Sleep precision is sub-tick only if Theseus was built with the \"tickless\" config option");
+	Ok(())
+}
+
+/// Internal function that actually calculates the average wakeup latency of `sleep()`.
+/// Measures this by sleeping for `duration_us` microseconds and subtracting that duration
+/// from the measured elapsed time.
+fn do_sleep_inner(duration_us: u64, iterations: usize, th: usize, nr: usize) -> Result<u64, &'static str> {
+	let hpet = get_hpet().ok_or("Could not retrieve hpet counter")?;
+	let duration = Duration::from_micros(duration_us);
+	let requested_time = if cfg!(bm_in_us) { duration_us } else { duration_us * 1000 };
+	let mut total_latency: u64 = 0;
+
+	for _ in 0..iterations {
+		let start_hpet = hpet.get_counter();
+		sleep::sleep(duration).map_err(|_| "failed to put the current task to sleep")?;
+		let end_hpet = hpet.get_counter();
+
+		let elapsed_time = hpet_2_time("", end_hpet - start_hpet);
+		total_latency += elapsed_time.saturating_sub(requested_time);
+	}
+
+	let latency_avg = total_latency / iterations as u64;
+	printlninfo!("sleep_test_inner ({}/{}): sleep {} us -> wakeup latency {} {}",
+		th, nr, duration_us, latency_avg, T_UNIT);
+
+	Ok(latency_avg)
+}
+
+/// Measures the time to load the given application crate with lazy linking,
+/// in which calls to functions that aren't loaded yet go through trampolines, compared to eager linking.
+/// Calls `do_lazy_link_inner` multiple times for each linking mode to perform the actual operation.
+/// 
+/// All lazy loads are measured first, because the first eager load also loads the application's
+/// missing dependencies into the kernel namespace, after which there's nothing left to link lazily.
+fn do_lazy_link(app_name: &str) -> Result<(), &'static str> {
+	let app_prefix = format!("{}-", app_name);
+	let overhead_ct = hpet_timing_overhead()?;
+	print_header(TRIES, 1);
+
+	let trampolines_before = mod_mgmt::lazy::lazy_linking_stats().trampolines;
+	let mut lazy_vec = Vec::with_capacity(TRIES);
+	for i in 0..TRIES {
+		let lat = do_lazy_link_inner(&app_prefix, true, overhead_ct, i+1, TRIES)?;
+		lazy_vec.push(lat);
+	}
+	let trampolines = mod_mgmt::lazy::lazy_linking_stats().trampolines - trampolines_before;
+
+	let mut eager_vec = Vec::with_capacity(TRIES);
+	for i in 0..TRIES {
+		let lat = do_lazy_link_inner(&app_prefix, false, overhead_ct, i+1, TRIES)?;
+		eager_vec.push(lat);
+	}
+	let first_eager_lat = eager_vec.remove(0);
+
+	let lazy_stats = calculate_stats(&lazy_vec).ok_or("couldn't calculate stats")?;
+	let eager_stats = calculate_stats(&eager_vec).ok_or("couldn't calculate stats")?;
+	printlninfo!("LAZY LINK result for {}: ({})", app_name, T_UNIT);
+	printlninfo!("Lazy linking ({} trampolines per load): {:?}", trampolines / TRIES, lazy_stats);
+	printlninfo!("Eager linking, first load including dependencies: {} {}", first_eager_lat, T_UNIT);
+	printlninfo!("Eager linking, subsequent loads: {:?}", eager_stats);
+	Ok(())
+}
+
+/// Internal function that actually calculates the time to load an application crate.
+/// Measures this by loading the crate into a new application namespace, with or without lazy linking.
+fn do_lazy_link_inner(app_prefix: &str, lazy: bool, overhead_ct: u64, th: usize, nr: usize) -> Result<u64, &'static str> {
+	let hpet = get_hpet().ok_or("Could not retrieve hpet counter")?;
+	let kernel_mmi_ref = memory::get_kernel_mmi_ref().ok_or("couldn't get kernel_mmi_ref")?;
+	let namespace = mod_mgmt::create_application_namespace(None)?;
+	if lazy {
+		CrateNamespace::enable_lazy_linking(&namespace);
+	}
+	let (app_file, _ns) = CrateNamespace::get_crate_object_file_starting_with(&namespace, app_prefix)
+		.ok_or("Could not find the application crate")?;
+
+	let start_hpet = hpet.get_counter();
+	let app_crate = CrateNamespace::load_crate_as_application(&namespace, &app_file, kernel_mmi_ref, false)?;
+	let end_hpet = hpet.get_counter();
+	drop(app_crate);
+
+	let delta_hpet = (end_hpet - start_hpet).saturating_sub(overhead_ct);
+	let delta_time = hpet_2_time("", delta_hpet);
+	printlninfo!("lazy_link_inner ({}/{}): {} linking, hpet {} , overhead {}, {} {}",
+		th, nr, if lazy { "lazy" } else { "eager" }, delta_hpet, overhead_ct, delta_time, T_UNIT);
+
+	Ok(delta_time)
+}
+
+/// Helper function to get the total number of timer interrupts taken by all cores except `my_core`
+fn other_cores_timer_interrupts(my_core: u8) -> usize {
+	apic::get_lapics().iter()
+		.filter(|(core, _)| **core != my_core)
+		.map(|(core, _)| sleep::tick::timer_interrupt_count(*core))
+		.sum()
+}
+
+
 /// Helper function to get the name of current task
 fn get_prog_name() -> String {
 	task::with_current_task(|t| t.name.clone())
@@ -1,5 +1,8 @@
 //! This application is used as a demo for evaluating Theseus's live evolution
 //! between synchronous and asynchronous channels.
+//!
+//! It can also compare the cost of sending large buffers through a heap-based channel,
+//! which allocates and copies each buffer, against a zero-copy shared memory ring.
 
 #![no_std]
 
@@ -11,11 +14,18 @@ extern crate unified_channel;
 extern crate task;
 extern crate spawn;
 extern crate apic;
+extern crate async_channel;
+extern crate shared_memory;
+extern crate hpet;
+extern crate libtest;
 
 use core::sync::atomic::{AtomicUsize, Ordering};
 use alloc::vec::Vec;
 use alloc::string::String;
 use getopts::{Options, Matches};
+use hpet::get_hpet;
+use libtest::{hpet_2_ns, calculate_stats};
+use shared_memory::ring::{RingSender, RingReceiver};
 
 
 static ITERATIONS: AtomicUsize = AtomicUsize::new(10);
@@ -28,6 +38,7 @@ pub fn main(args: Vec<String>) -> isize {
     let mut opts = Options::new();
     opts.optflag("h", "help", "print this help menu");
     opts.optopt("n", "iterations", "number of test iterations (default 100)", "ITER");
+    opts.optopt("b", "buffer", "compare sending buffers of SIZE bytes through a heap-based channel and a shared memory ring", "SIZE");
 
     let matches = match opts.parse(&args) {
         Ok(m) => m,
@@ -57,7 +68,15 @@ pub fn main(args: Vec<String>) -> isize {
 }
 
 
-fn rmain(_matches: Matches) -> Result<(), &'static str> {
+fn rmain(matches: Matches) -> Result<(), &'static str> {
+    if let Some(iterations) = matches.opt_str("n") {
+        let iterations = iterations.parse::<usize>().map_err(|_| "invalid number of iterations")?;
+        ITERATIONS.store(iterations, Ordering::SeqCst);
+    }
+    if let Some(size) = matches.opt_str("b") {
+        let size = size.parse::<usize>().map_err(|_| "invalid buffer size")?;
+        return buffer_eval(size, iterations!());
+    }
     test_multiple(iterations!())
 }
 
@@ -109,10 +128,103 @@ fn test_multiple(iterations: usize) -> Result<(), &'static str> {
 }
 
 
+/// The number of times each buffer transfer is measured.
+const BUFFER_EVAL_TRIES: usize = 10;
+/// The number of buffers that can be in flight at once in both the channel and the ring.
+const BUFFER_EVAL_CAPACITY: usize = 16;
+/// The name of the shared memory object that backs the ring.
+const BUFFER_EVAL_RING_NAME: &str = "channel_eval_ring";
+
+/// Compares sending `iterations` buffers of `size` bytes from one task to another
+/// through a heap-based channel against a shared memory ring.
+fn buffer_eval(size: usize, iterations: usize) -> Result<(), &'static str> {
+    if size == 0 {
+        return Err("buffer size must be non-zero");
+    }
+    println!("Sending {} buffers of {} bytes, evaluated {} times.", iterations, size, BUFFER_EVAL_TRIES);
+
+    let mut channel_times: Vec<u64> = Vec::with_capacity(BUFFER_EVAL_TRIES);
+    let mut ring_times: Vec<u64> = Vec::with_capacity(BUFFER_EVAL_TRIES);
+    for _trial in 0..BUFFER_EVAL_TRIES {
+        channel_times.push(channel_buffer_transfer(size, iterations)?);
+        ring_times.push(ring_buffer_transfer(size, iterations)?);
+    }
+
+    println!("Heap-based channel (ns)");
+    let stats_channel = calculate_stats(&channel_times).ok_or("Could not calculate stats for the channel")?;
+    println!("{:?}", stats_channel);
+
+    println!("Shared memory ring (ns)");
+    let stats_ring = calculate_stats(&ring_times).ok_or("Could not calculate stats for the shared memory ring")?;
+    println!("{:?}", stats_ring);
+
+    Ok(())
+}
+
+/// Sends `iterations` heap-allocated buffers of `size` bytes through an `async_channel`
+/// and returns the elapsed time in nanoseconds.
+fn channel_buffer_transfer(size: usize, iterations: usize) -> Result<u64, &'static str> {
+    let start = get_hpet().ok_or("couldn't get HPET timer")?.get_counter();
+
+    let (sender, receiver) = async_channel::new_channel::<Vec<u8>>(BUFFER_EVAL_CAPACITY);
+    let receiver_task = spawn::new_task_builder(move |_: ()| -> Result<usize, &'static str> {
+        let mut checksum = 0;
+        for _ in 0..iterations {
+            let buffer = receiver.receive().map_err(|_| "failed to receive buffer")?;
+            checksum += buffer[buffer.len() - 1] as usize;
+        }
+        Ok(checksum)
+    }, ())
+        .name(String::from("channel_eval_buffer_receiver"))
+        .spawn()?;
+
+    for i in 0..iterations {
+        sender.send(vec![i as u8; size]).map_err(|_| "failed to send buffer")?;
+    }
+    receiver_task.join()?;
+    let _exit = receiver_task.take_exit_value();
+
+    let end = get_hpet().ok_or("couldn't get HPET timer")?.get_counter();
+    Ok(hpet_2_ns(end - start))
+}
+
+/// Sends `iterations` buffers of `size` bytes through a shared memory ring, writing each one in place,
+/// and returns the elapsed time in nanoseconds.
+fn ring_buffer_transfer(size: usize, iterations: usize) -> Result<u64, &'static str> {
+    let start = get_hpet().ok_or("couldn't get HPET timer")?.get_counter();
+
+    let mut sender = RingSender::create(BUFFER_EVAL_RING_NAME, size, BUFFER_EVAL_CAPACITY)?;
+    let receiver_task = spawn::new_task_builder(move |_: ()| -> Result<usize, &'static str> {
+        let mut receiver = RingReceiver::open(BUFFER_EVAL_RING_NAME)?;
+        let mut checksum = 0;
+        for _ in 0..iterations {
+            checksum += receiver.receive_with(|buffer| buffer[buffer.len() - 1] as usize);
+        }
+        Ok(checksum)
+    }, ())
+        .name(String::from("channel_eval_ring_receiver"))
+        .spawn()?;
+
+    for i in 0..iterations {
+        sender.send_with(|slot| {
+            slot.fill(i as u8);
+            slot.len()
+        });
+    }
+    receiver_task.join()?;
+    let _exit = receiver_task.take_exit_value();
+    drop(sender);
+
+    let end = get_hpet().ok_or("couldn't get HPET timer")?.get_counter();
+    Ok(hpet_2_ns(end - start))
+}
+
+
 fn print_usage(opts: Options) {
     println!("{}", opts.usage(USAGE));
 }
 
 
 const USAGE: &'static str = "Usage: channel_eval [ARGS]
-Used for evaluating live evolution between sync/async channels in Theseus.";
+Used for evaluating live evolution between sync/async channels in Theseus.
+With `-b SIZE`, compares sending buffers of SIZE bytes through a heap-based channel against a shared memory ring.";
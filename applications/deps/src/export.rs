@@ -0,0 +1,166 @@
+//! Exports the whole crate or section dependency graph of the current namespace
+//! to the terminal, a file, a serial port, or a TCP connection.
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{convert::TryFrom, str::FromStr};
+use getopts::Matches;
+use hpet::get_hpet;
+use memfs::MemFile;
+use mod_mgmt::graph::{GraphFormat, GraphKind};
+use path::Path;
+use serial_port::{get_serial_port, SerialPortAddress};
+use smoltcp::{
+    socket::{SocketSet, TcpSocket, TcpSocketBuffer, TcpState},
+    wire::{IpEndpoint, Ipv4Address},
+};
+use smoltcp_helper::{connect, get_default_iface, millis_since, poll_iface, STARTING_FREE_PORT};
+use task;
+use get_my_current_namespace;
+
+/// The line printed before a graph that is written to a serial port,
+/// such that the host can extract it from other output on that port, e.g., log messages.
+const SERIAL_BEGIN_MARKER: &'static str = "-----BEGIN THESEUS DEPENDENCY GRAPH-----\n";
+/// The line printed after a graph that is written to a serial port.
+const SERIAL_END_MARKER: &'static str = "\n-----END THESEUS DEPENDENCY GRAPH-----\n";
+
+/// How long to wait for the remote end of a TCP connection to accept more of the graph.
+const TCP_TIMEOUT_MILLIS: u64 = 5000;
+
+
+/// Exports the dependency graph of the given `kind` according to the `--format`, `--output`, and `--local` options.
+pub fn export_graph(kind: &str, matches: &Matches) -> Result<(), String> {
+    let kind = GraphKind::from_str(kind)?;
+    let format = match matches.opt_str("format") {
+        Some(f) => GraphFormat::from_str(&f)?,
+        None => GraphFormat::Dot,
+    };
+    let graph = get_my_current_namespace().dependency_graph(kind, !matches.opt_present("local"));
+
+    let mut output = String::new();
+    graph.write(format, &mut output).map_err(|_e| "failed to write the dependency graph".to_string())?;
+
+    let inconsistent_edges = graph.inconsistent_edges().count();
+    let summary = format!("{} nodes and {} edges ({} with inconsistent dependency metadata)",
+        graph.nodes.len(), graph.edges.len(), inconsistent_edges
+    );
+
+    match matches.opt_str("output") {
+        None => {
+            println!("{}", output);
+            return Ok(());
+        }
+        Some(ref dest) if dest.starts_with("serial:") => {
+            let address = SerialPortAddress::try_from(&dest["serial:".len()..])
+                .map_err(|_e| format!("invalid serial port {:?}, expected COM1 to COM4", dest))?;
+            let serial_port = get_serial_port(address)
+                .ok_or_else(|| format!("serial port {:?} has not been initialized", address))?;
+            let mut serial_port = serial_port.lock();
+            serial_port.out_bytes(SERIAL_BEGIN_MARKER.as_bytes());
+            serial_port.out_bytes(output.as_bytes());
+            serial_port.out_bytes(SERIAL_END_MARKER.as_bytes());
+        }
+        Some(ref dest) if dest.starts_with("tcp:") => {
+            send_over_tcp(output.as_bytes(), &dest["tcp:".len()..])?;
+        }
+        Some(ref path) => {
+            write_to_file(output.as_bytes(), path)?;
+        }
+    }
+    println!("Exported {} to {}.", summary, matches.opt_str("output").unwrap_or_default());
+    Ok(())
+}
+
+
+/// Writes the given bytes to a new file at the given path, relative to the current working directory.
+fn write_to_file(bytes: &[u8], path: &str) -> Result<(), String> {
+    let cwd = task::with_current_task(|t| t.get_env().lock().working_dir.clone())
+        .map_err(|_e| "couldn't get the current task's working directory".to_string())?;
+    let (parent_dir, file_name) = match path.rfind('/') {
+        Some(slash) => {
+            // Keep the leading slash of a file in the root directory.
+            let parent_path = &path[.. core::cmp::max(slash, 1)];
+            let parent_dir = Path::new(parent_path.to_string()).get_dir(&cwd)
+                .ok_or_else(|| format!("couldn't find directory {:?}", parent_path))?;
+            (parent_dir, &path[slash + 1 ..])
+        }
+        None => (cwd, path),
+    };
+    if file_name.is_empty() {
+        return Err(format!("{:?} is not a file path", path));
+    }
+    if parent_dir.lock().get(file_name).is_some() {
+        return Err(format!("{:?} already exists", path));
+    }
+    let file = MemFile::new(file_name.to_string(), &parent_dir)?;
+    file.lock().write_at(bytes, 0)?;
+    Ok(())
+}
+
+
+/// Sends the given bytes over a new TCP connection to the given `IP:PORT` endpoint,
+/// e.g., to `nc -l PORT > graph.json` running on the host.
+fn send_over_tcp(bytes: &[u8], endpoint: &str) -> Result<(), String> {
+    let remote_endpoint = parse_ipv4_endpoint(endpoint)
+        .ok_or_else(|| format!("invalid TCP endpoint {:?}, expected IPV4_ADDRESS:PORT", endpoint))?;
+    let iface = get_default_iface()?;
+    let startup_time = hpet_ticks!();
+    let local_port = STARTING_FREE_PORT + (startup_time % (u16::max_value() - STARTING_FREE_PORT) as u64) as u16;
+
+    let tcp_rx_buffer = TcpSocketBuffer::new(vec![0; 4096]);
+    let tcp_tx_buffer = TcpSocketBuffer::new(vec![0; 4096]);
+    let mut sockets = SocketSet::new(Vec::with_capacity(1));
+    let tcp_handle = sockets.add(TcpSocket::new(tcp_rx_buffer, tcp_tx_buffer));
+    connect(&iface, &mut sockets, tcp_handle, remote_endpoint, local_port, startup_time)?;
+
+    let mut bytes_sent = 0;
+    let mut last_progress = hpet_ticks!();
+    while bytes_sent < bytes.len() {
+        poll_iface(&iface, &mut sockets, startup_time)?;
+        let mut socket = sockets.get::<TcpSocket>(tcp_handle);
+        if socket.can_send() {
+            let sent = socket.send_slice(&bytes[bytes_sent..])
+                .map_err(|_e| "failed to send the dependency graph over TCP".to_string())?;
+            if sent > 0 {
+                bytes_sent += sent;
+                last_progress = hpet_ticks!();
+            }
+        }
+        if millis_since(last_progress)? > TCP_TIMEOUT_MILLIS {
+            socket.abort();
+            return Err(format!("timed out sending the dependency graph to {} after {} of {} bytes", remote_endpoint, bytes_sent, bytes.len()));
+        }
+    }
+
+    // Close the connection once the remote end has received everything.
+    sockets.get::<TcpSocket>(tcp_handle).close();
+    let close_start = hpet_ticks!();
+    loop {
+        poll_iface(&iface, &mut sockets, startup_time)?;
+        let mut socket = sockets.get::<TcpSocket>(tcp_handle);
+        match socket.state() {
+            TcpState::Closed | TcpState::TimeWait => return Ok(()),
+            _ if millis_since(close_start)? > TCP_TIMEOUT_MILLIS => {
+                socket.abort();
+                return Err(format!("timed out closing the TCP connection to {}", remote_endpoint));
+            }
+            _ => { }
+        }
+    }
+}
+
+/// Parses an endpoint of the form `a.b.c.d:port`.
+fn parse_ipv4_endpoint(endpoint: &str) -> Option<IpEndpoint> {
+    let mut parts = endpoint.rsplitn(2, ':');
+    let port = parts.next()?.parse::<u16>().ok()?;
+    let octets = parts.next()?
+        .split('.')
+        .map(|octet| octet.parse::<u8>().ok())
+        .collect::<Option<Vec<u8>>>()?;
+    if octets.len() != 4 {
+        return None;
+    }
+    Some(IpEndpoint::new(Ipv4Address::from_bytes(&octets).into(), port))
+}
@@ -16,6 +16,14 @@ extern crate memory;
 extern crate mod_mgmt;
 extern crate crate_name_utils;
 extern crate spin;
+extern crate memfs;
+extern crate path;
+extern crate serial_port;
+extern crate hpet;
+extern crate smoltcp;
+#[macro_use] extern crate smoltcp_helper;
+
+mod export;
 
 
 use alloc::{
@@ -69,6 +77,10 @@ pub fn main(args: Vec<String>) -> isize {
     opts.optopt ("",  "num-deps-section", "sum up the count of all dependencies for the given section", "SECTION");
     opts.optflag("",  "num-deps-all",     "sum up the count of all dependencies for all crates");
     opts.optflag("",  "num-rodata",       "count the private .rodata sections for all crates");
+    opts.optopt ("e", "export",           "export the whole dependency graph of KIND, either `crates` or `sections`", "KIND");
+    opts.optopt ("",  "format",           "the FORMAT of an exported graph, either `dot` (default) or `json`", "FORMAT");
+    opts.optopt ("o", "output",           "write an exported graph to DEST: a file path, `serial:COMn`, or `tcp:IP:PORT` (default: the terminal)", "DEST");
+    opts.optflag("",  "local",            "only export the current namespace's own crates, not those of its recursive namespaces");
     
 
     let matches = match opts.parse(&args) {
@@ -133,6 +145,9 @@ fn rmain(matches: Matches) -> Result<(), String> {
     else if matches.opt_present("num-rodata") {
         count_private_rodata_sections()
     }
+    else if let Some(kind) = matches.opt_str("export") {
+        export::export_graph(&kind, &matches)
+    }
     else {
         Err(format!("no supported options/arguments found."))
     }
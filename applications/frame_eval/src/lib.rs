@@ -0,0 +1,174 @@
+//! Benchmarks the allocation and deallocation of physical frames
+//! by tasks pinned to one or more CPUs that allocate frames concurrently.
+//!
+//! Running this both with and without the `legacy_frame_allocator` cfg option
+//! compares the buddy frame allocator against the original list of free chunks,
+//! and the `--no-cache` option measures the buddy allocator without its per-CPU caches.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use getopts::{Matches, Options};
+use hpet::get_hpet;
+use libtest::{calculate_stats, hpet_2_ns, hpet_timing_overhead};
+
+/// The number of times each benchmark is repeated.
+const TRIES: usize = 10;
+/// The number of allocations that each worker holds at once before freeing them.
+const BATCH_SIZE: usize = 64;
+
+static ITERATIONS: AtomicUsize = AtomicUsize::new(10_000);
+static FRAMES_PER_ALLOCATION: AtomicUsize = AtomicUsize::new(1);
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optopt("c", "cpus", "the number of CPUs to run workers on (default: all CPUs)", "CPUS");
+    opts.optopt("i", "iterations", "the number of frame allocations made by each worker (default: 10000)", "ITERATIONS");
+    opts.optopt("n", "frames", "the number of frames in each allocation (default: 1)", "FRAMES");
+    opts.optflag("", "no-cache", "disable the per-CPU caches of free frames while benchmarking");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(e) => {
+            println!("{}", e);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn rmain(matches: Matches) -> Result<(), &'static str> {
+    let parse = |opt: &str| -> Result<Option<usize>, &'static str> {
+        matches.opt_str(opt)
+            .map(|s| s.parse::<usize>().map_err(|_| "couldn't parse a numeric argument"))
+            .transpose()
+    };
+    if let Some(iterations) = parse("i")? {
+        ITERATIONS.store(iterations, Ordering::SeqCst);
+    }
+    if let Some(frames) = parse("n")? {
+        if frames == 0 {
+            return Err("each allocation must contain at least one frame");
+        }
+        FRAMES_PER_ALLOCATION.store(frames, Ordering::SeqCst);
+    }
+    let mut cpus: Vec<u8> = apic::get_lapics().iter().map(|(cpu, _)| *cpu).collect();
+    cpus.sort_unstable();
+    if let Some(num_cpus) = parse("c")? {
+        if num_cpus == 0 || num_cpus > cpus.len() {
+            return Err("the number of CPUs must be between 1 and the number of CPUs in the system");
+        }
+        cpus.truncate(num_cpus);
+    }
+
+    let caching = !matches.opt_present("no-cache");
+    match frame_allocator::buddy_allocator_stats() {
+        Some(_) => println!(
+            "Using the buddy frame allocator, with per-CPU caches {}.",
+            if caching { "enabled" } else { "disabled" },
+        ),
+        None => println!("Using the legacy frame allocator."),
+    }
+
+    frame_allocator::set_per_cpu_caching(caching);
+    let result = run_benchmark(&cpus);
+    frame_allocator::set_per_cpu_caching(true);
+    result
+}
+
+/// Runs the benchmark `TRIES` times with one worker pinned to each of the given `cpus`.
+fn run_benchmark(cpus: &[u8]) -> Result<(), &'static str> {
+    let iterations = ITERATIONS.load(Ordering::SeqCst);
+    let hpet_overhead = hpet_timing_overhead()?;
+    let hpet = get_hpet().ok_or("couldn't get HPET timer")?;
+    println!("Running {} workers on CPUs {:?}, each making {} allocations of {} frame(s)...",
+        cpus.len(), cpus, iterations, FRAMES_PER_ALLOCATION.load(Ordering::SeqCst),
+    );
+
+    let mut results = Vec::with_capacity(TRIES);
+    for try_num in 0..TRIES {
+        let mut workers = Vec::with_capacity(cpus.len());
+        let start = hpet.get_counter();
+        for &cpu in cpus {
+            workers.push(
+                spawn::new_task_builder(worker, ())
+                    .name(String::from("frame_eval_worker"))
+                    .pin_on_core(cpu)
+                    .spawn()?
+            );
+        }
+        for worker in &workers {
+            worker.join()?;
+        }
+        let end = hpet.get_counter() - hpet_overhead;
+
+        for worker in workers {
+            match worker.take_exit_value() {
+                Some(task::ExitValue::Completed(value)) if value.downcast_ref::<bool>() == Some(&true) => {}
+                _ => return Err("a worker failed to allocate frames"),
+            }
+        }
+
+        // The average time of one allocation and its deallocation, across all workers.
+        let ns_per_allocation = hpet_2_ns(end - start) / (iterations as u64).max(1);
+        println!("[{}] {} ns per allocation", try_num, ns_per_allocation);
+        results.push(ns_per_allocation);
+    }
+
+    println!("frame allocation stats (ns per allocation, on each CPU)");
+    println!("{:?}", calculate_stats(&results));
+    if let Some(stats) = frame_allocator::buddy_allocator_stats() {
+        println!("{:?}", stats);
+    }
+    Ok(())
+}
+
+/// Repeatedly allocates a batch of frames and then frees them, returning whether all allocations succeeded.
+fn worker(_: ()) -> bool {
+    let iterations = ITERATIONS.load(Ordering::SeqCst);
+    let num_frames = FRAMES_PER_ALLOCATION.load(Ordering::SeqCst);
+    let mut allocations = Vec::with_capacity(BATCH_SIZE);
+
+    let mut remaining = iterations;
+    while remaining > 0 {
+        let batch = remaining.min(BATCH_SIZE);
+        for _ in 0..batch {
+            match frame_allocator::allocate_frames(num_frames) {
+                Some(af) => allocations.push(af),
+                None => return false,
+            }
+        }
+        allocations.clear();
+        remaining -= batch;
+    }
+    true
+}
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+const USAGE: &str = "Usage: frame_eval [OPTIONS]
+Benchmarks allocating and freeing physical frames concurrently on multiple CPUs.";
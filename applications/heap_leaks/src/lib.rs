@@ -0,0 +1,122 @@
+//! This application lists heap allocations that have not been freed since a checkpoint,
+//! which requires that Theseus was built with the debugging heap allocator (`THESEUS_CONFIG=debug_heap`).
+//!
+//! Typical usage is to run `heap_leaks --start`, then run the code under test,
+//! and then run `heap_leaks` to list the allocations it made that are still outstanding.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use core::fmt::Write;
+use getopts::{Matches, Options};
+use memory::VirtualAddress;
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("", "start", "set a new checkpoint and start recording the call sites of new allocations");
+    opts.optflag("", "stop", "stop recording the call sites of new allocations");
+    opts.optflag("c", "checkpoint", "set a new checkpoint without changing whether call sites are recorded");
+    opts.optflag("s", "stats", "print statistics about the debugging heap allocator");
+    opts.optopt("n", "max", "list at most NUM allocations (default 50)", "NUM");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(()) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn rmain(matches: Matches) -> Result<(), &'static str> {
+    if !heap_debug::is_installed() {
+        return Err("the debugging heap allocator is not in use; rebuild Theseus with `THESEUS_CONFIG=debug_heap`");
+    }
+
+    if matches.opt_present("start") {
+        heap_debug::set_call_site_recording(true);
+        println!("Set checkpoint {} and started recording call sites.", heap_debug::set_checkpoint());
+        return Ok(());
+    }
+    if matches.opt_present("stop") {
+        heap_debug::set_call_site_recording(false);
+        println!("Stopped recording call sites.");
+        return Ok(());
+    }
+    if matches.opt_present("c") {
+        println!("Set checkpoint {}.", heap_debug::set_checkpoint());
+        return Ok(());
+    }
+    if matches.opt_present("s") {
+        let stats = heap_debug::stats();
+        println!("{:<24} {}", "live allocations", stats.live_allocations);
+        println!("{:<24} {}", "live bytes", stats.live_bytes);
+        println!("{:<24} {}", "quarantined allocations", stats.quarantined_allocations);
+        println!("{:<24} {}", "invalid frees", stats.invalid_frees);
+        println!("{:<24} {}", "corruptions", stats.corruptions);
+        return Ok(());
+    }
+
+    let max = match matches.opt_str("n") {
+        Some(n) => n.parse::<usize>().map_err(|_| "couldn't parse the maximum number of allocations")?,
+        None => 50,
+    };
+    let checkpoint = heap_debug::checkpoint();
+    let allocations = heap_debug::outstanding_allocations_since(checkpoint);
+    // The namespace used for symbolizing call sites, which is retrieved after listing the allocations
+    // so as to not count this app's own allocations.
+    let namespace = task::with_current_task(|t| t.get_namespace().clone())
+        .map_err(|_| "failed to get current task")?;
+
+    let mut output = String::new();
+    let total_bytes: usize = allocations.iter().map(|a| a.size).sum();
+    let _ = writeln!(output, "{} allocations ({} bytes) are outstanding since checkpoint {}:",
+        allocations.len(), total_bytes, checkpoint,
+    );
+    for allocation in allocations.iter().take(max) {
+        let _ = writeln!(output, "  #{:<8} {:>#018X}  {:>8} bytes  task {}",
+            allocation.sequence, allocation.address, allocation.size, allocation.task_id,
+        );
+        for &call_site in allocation.call_sites() {
+            let symbol = namespace.get_section_containing_address(VirtualAddress::new_canonical(call_site), false);
+            match symbol {
+                Some((section, offset)) => { let _ = writeln!(output, "      {:>#018X} in {} + {:#X}", call_site, section.name, offset); }
+                None => { let _ = writeln!(output, "      {:>#018X} in ??", call_site); }
+            }
+        }
+    }
+    if allocations.len() > max {
+        let _ = writeln!(output, "  ... and {} more", allocations.len() - max);
+    }
+    print!("{}", output);
+    Ok(())
+}
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+const USAGE: &str = "Usage: heap_leaks [OPTION]
+Lists heap allocations that have not been freed since the most recent checkpoint.
+Requires Theseus to be built with the debugging heap allocator, via `THESEUS_CONFIG=debug_heap`.";
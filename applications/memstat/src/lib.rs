@@ -0,0 +1,189 @@
+//! This application shows the memory used by the system as a whole,
+//! by each task, by each crate, and by each crate namespace,
+//! similar to `free` and `memstat` on Linux.
+//!
+//! Task memory usage is the net amount of frames, pages, and heap memory that each task
+//! has allocated and not yet freed, as recorded by the `memory_accounting` crate.
+//! Crate memory usage is the size of each crate's loaded sections.
+//! Stack usage is the size of each task's stack, how much of it is backed by frames,
+//! and, for growable stacks, the most of it that has ever been used (its high-water mark).
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+use alloc::{
+    format,
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+use core::fmt::Write;
+use getopts::{Matches, Options};
+use memory_accounting::MemoryUsage;
+use mod_mgmt::{CrateMemoryUsage, CrateNamespace};
+use task::TASKLIST;
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("t", "tasks", "show the memory used by each task");
+    opts.optflag("c", "crates", "show the memory used by each crate in the current namespace");
+    opts.optflag("n", "namespaces", "show the memory used by each namespace");
+    opts.optflag("s", "stacks", "show the size and high-water mark of each task's stack");
+    opts.optflag("r", "recursive", "include crates in recursive namespaces when used with `--crates`");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(output) => {
+            print!("{}", output);
+            0
+        }
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn rmain(matches: Matches) -> Result<String, &'static str> {
+    let show_all = !(matches.opt_present("t") || matches.opt_present("c") || matches.opt_present("n") || matches.opt_present("s"));
+    let mut output = String::new();
+
+    print_totals(&mut output).map_err(|_| "String formatting error")?;
+    if show_all || matches.opt_present("t") {
+        print_tasks(&mut output).map_err(|_| "String formatting error")?;
+    }
+    if matches.opt_present("c") {
+        let namespace = task::with_current_task(|t| t.get_namespace().clone())
+            .map_err(|_| "failed to get current task")?;
+        print_crates(&mut output, &namespace, matches.opt_present("r")).map_err(|_| "String formatting error")?;
+    }
+    if show_all || matches.opt_present("n") {
+        print_namespaces(&mut output).map_err(|_| "String formatting error")?;
+    }
+    if matches.opt_present("s") {
+        print_stacks(&mut output).map_err(|_| "String formatting error")?;
+    }
+    Ok(output)
+}
+
+fn print_totals(output: &mut String) -> core::fmt::Result {
+    let total = memory_accounting::total_usage();
+    writeln!(output, "{:<12} {:>10} {:>10} {:>14} {:>12}", "", "FRAMES", "PAGES", "HEAP BYTES", "HEAP ALLOCS")?;
+    writeln!(output, "{:<12} {}", "System", format_usage(&total))?;
+    writeln!(output)
+}
+
+fn print_tasks(output: &mut String) -> core::fmt::Result {
+    writeln!(output, "{:<5}  {:>10} {:>10} {:>14} {:>12}  NAME", "ID", "FRAMES", "PAGES", "HEAP BYTES", "HEAP ALLOCS")?;
+    for (id, task) in TASKLIST.lock().iter() {
+        writeln!(output, "{:<5}  {}  {}", id, format_usage(&task.memory_usage()), task.name)?;
+    }
+    writeln!(output)
+}
+
+fn print_crates(output: &mut String, namespace: &CrateNamespace, recursive: bool) -> core::fmt::Result {
+    let mut crates: Vec<(String, CrateMemoryUsage)> = Vec::new();
+    namespace.for_each_crate(recursive, |crate_name, crate_ref| {
+        crates.push((String::from(crate_name), crate_ref.lock_as_ref().memory_usage()));
+        true
+    });
+    // Show the largest crates first.
+    crates.sort_by(|(_, a), (_, b)| b.total_bytes().cmp(&a.total_bytes()));
+
+    writeln!(output, "{:>10} {:>10} {:>10} {:>10}  CRATE", "TEXT", "RODATA", "DATA", "TOTAL")?;
+    for (crate_name, usage) in &crates {
+        writeln!(output, "{}  {}", format_crate_usage(usage), crate_name)?;
+    }
+    writeln!(output)
+}
+
+fn print_namespaces(output: &mut String) -> core::fmt::Result {
+    // Collect every namespace that a task runs in, along with the namespaces they are built atop.
+    let mut namespaces: Vec<Arc<CrateNamespace>> = Vec::new();
+    for task in TASKLIST.lock().values() {
+        let mut next = Some(task.get_namespace());
+        while let Some(namespace) = next {
+            if !namespaces.iter().any(|ns| Arc::ptr_eq(ns, namespace)) {
+                namespaces.push(namespace.clone());
+            }
+            next = namespace.recursive_namespace();
+        }
+    }
+
+    writeln!(output, "{:>10} {:>10} {:>10} {:>10}  {:>10} {:>10} {:>14} {:>12}  {:>5}  NAMESPACE",
+        "TEXT", "RODATA", "DATA", "TOTAL", "FRAMES", "PAGES", "HEAP BYTES", "HEAP ALLOCS", "TASKS",
+    )?;
+    for namespace in &namespaces {
+        let mut task_usage = MemoryUsage::default();
+        let mut num_tasks = 0;
+        for task in TASKLIST.lock().values() {
+            if Arc::ptr_eq(task.get_namespace(), namespace) {
+                task_usage += task.memory_usage();
+                num_tasks += 1;
+            }
+        }
+        writeln!(output, "{}  {}  {:>5}  {}",
+            format_crate_usage(&namespace.memory_usage(false)),
+            format_usage(&task_usage),
+            num_tasks,
+            namespace.name(),
+        )?;
+    }
+    writeln!(output)
+}
+
+fn print_stacks(output: &mut String) -> core::fmt::Result {
+    writeln!(output, "{:<5}  {:>10} {:>10} {:>10}  NAME", "ID", "SIZE", "RESIDENT", "HIGH WATER")?;
+    for (id, task) in TASKLIST.lock().iter() {
+        let (size, resident, high_water) = task.with_kstack(|kstack| (
+            kstack.size_in_bytes(),
+            kstack.resident_size_in_bytes(),
+            kstack.high_water_mark(),
+        ));
+        // Only growable stacks have a known high-water mark.
+        let high_water = high_water.map(|bytes| format!("{}", bytes)).unwrap_or_else(|| String::from("-"));
+        writeln!(output, "{:<5}  {:>10} {:>10} {:>10}  {}", id, size, resident, high_water, task.name)?;
+    }
+    writeln!(output)
+}
+
+fn format_usage(usage: &MemoryUsage) -> String {
+    format!("{:>10} {:>10} {:>14} {:>12}", usage.frames, usage.pages, usage.heap_bytes, usage.heap_allocations)
+}
+
+fn format_crate_usage(usage: &CrateMemoryUsage) -> String {
+    format!("{:>10} {:>10} {:>10} {:>10}", usage.text_bytes, usage.rodata_bytes, usage.data_bytes, usage.total_bytes())
+}
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+const USAGE: &str = "Usage: memstat [OPTION]...
+Shows the memory used by the whole system, by each task, by each crate, and by each namespace.
+By default, shows the system totals, tasks, and namespaces.
+
+    FRAMES, PAGES:  the net number of physical frames and virtual pages allocated.
+    HEAP BYTES:     the net number of heap bytes allocated.
+    HEAP ALLOCS:    the number of heap allocations that have not yet been freed.
+    TEXT, RODATA, DATA: the size in bytes of each kind of loaded crate section.
+    SIZE, RESIDENT: the size in bytes of a task's stack, and how much of it is backed by frames.
+    HIGH WATER:     the most bytes of a task's growable stack that have ever been used.
+
+A task's usage can be negative if it frees memory that was allocated by another task.";
@@ -1,6 +1,7 @@
 //! This application tests the performance of two memory mapping implementations,
 //! which is used to compare our spill-free `MappedPages` approach 
 //! with a standard memory mapping implementation based on `VirtualMemoryArea`s.
+//! It can also measure the TLB benefit of mapping memory with 2MiB huge pages.
 
 #![no_std]
 
@@ -30,7 +31,7 @@ extern crate mapper_spillful;
 use getopts::{Matches, Options};
 use kernel_config::memory::PAGE_SIZE;
 use libtest::{hpet_timing_overhead, hpet_2_ns, calculate_stats, check_myrq};
-use memory::{allocate_pages, AllocatedPages, VirtualAddress, Mapper, MappedPages, EntryFlags, mapper_from_current, mapped_pages_unmap};
+use memory::{allocate_pages, allocate_huge_pages, AllocatedPages, VirtualAddress, Mapper, MappedPages, EntryFlags, PageSize, mapper_from_current, mapped_pages_unmap};
 use mapper_spillful::MapperSpillful;
 use hpet::get_hpet;
 
@@ -195,6 +196,7 @@ pub fn main(args: Vec<String>) -> isize {
     opts.optflag("p", "spillful", "run the state spillful memory mapping evaluation");
     opts.optopt("n", "", "create 'N' mappings ", "NUM");
     opts.optopt("s", "--size", "specify the size (in pages) for each mapping", "SIZE");
+    opts.optflag("t", "tlb", "run the TLB evaluation comparing normal 4KiB pages with 2MiB huge pages");
 
     let matches = match opts.parse(&args) {
         Ok(m) => m,
@@ -229,6 +231,11 @@ pub fn main(args: Vec<String>) -> isize {
 pub fn rmain(matches: &Matches, _opts: &Options) -> Result<(), &'static str> {
     const TRIES: usize = 10;
     let mut mapper_normal   = mapper_from_current();
+
+    if matches.opt_present("t") {
+        return tlb_eval(&mut mapper_normal, TRIES);
+    }
+
     let mut mapper_spillful = MapperSpillful::new();
 
     let num_mappings = matches.opt_str("n")
@@ -328,6 +335,78 @@ pub fn rmain(matches: &Matches, _opts: &Options) -> Result<(), &'static str> {
 }
 
 
+/// The size of the memory region accessed by the TLB evaluation, 64 MiB.
+const TLB_EVAL_SIZE_IN_BYTES: usize = 64 * 1024 * 1024;
+/// The number of times each page is accessed during one trial of the TLB evaluation.
+const TLB_EVAL_PASSES: usize = 16;
+
+/// Compares the time to access memory mapped using normal 4KiB pages versus 2MiB huge pages.
+///
+/// Each trial touches one word in every page of a 64 MiB region in a scattered order,
+/// which needs far more TLB entries with normal pages than with huge pages.
+fn tlb_eval(mapper: &mut Mapper, tries: usize) -> Result<(), &'static str> {
+    let overhead = hpet_timing_overhead()?;
+    let flags = EntryFlags::WRITABLE | EntryFlags::PRESENT;
+
+    let normal_pages = allocate_pages(TLB_EVAL_SIZE_IN_BYTES / PAGE_SIZE)
+        .ok_or("couldn't allocate sufficient pages")?;
+    let mut normal_mp = mapper.map_allocated_pages(normal_pages, flags)?;
+
+    let huge_pages = allocate_huge_pages(PageSize::Huge2M, TLB_EVAL_SIZE_IN_BYTES / PageSize::Huge2M.size_in_bytes())
+        .ok_or("couldn't allocate sufficient huge pages")?;
+    let mut huge_mp = mapper.map_allocated_huge_pages(huge_pages, flags, PageSize::Huge2M)?;
+
+    println!("Running TLB evaluation on a {} MiB region, accessing each page {} times, evaluated {} times.",
+        TLB_EVAL_SIZE_IN_BYTES / (1024 * 1024), TLB_EVAL_PASSES, tries
+    );
+
+    let mut normal_times: Vec<u64> = Vec::with_capacity(tries);
+    let mut huge_times: Vec<u64> = Vec::with_capacity(tries);
+    for _trial in 0..tries {
+        normal_times.push(access_pages(&mut normal_mp, overhead)?);
+        huge_times.push(access_pages(&mut huge_mp, overhead)?);
+    }
+
+    println!("Access 4KiB pages (ns)");
+    let stats_normal = calculate_stats(&mut normal_times).ok_or("Could not calculate stats for 4KiB pages")?;
+    println!("{:?}", stats_normal);
+
+    println!("Access 2MiB huge pages (ns)");
+    let stats_huge = calculate_stats(&mut huge_times).ok_or("Could not calculate stats for 2MiB huge pages")?;
+    println!("{:?}", stats_huge);
+
+    Ok(())
+}
+
+/// Writes one word in every page of the given mapping, `TLB_EVAL_PASSES` times,
+/// and returns the elapsed time in nanoseconds.
+///
+/// Consecutive accesses are spread far apart to defeat caching and prefetching,
+/// such that each access is likely to need a different TLB entry.
+fn access_pages(mp: &mut MappedPages, hpet_overhead: u64) -> Result<u64, &'static str> {
+    const WORDS_PER_PAGE: usize = PAGE_SIZE / core::mem::size_of::<usize>();
+    // An odd stride visits every page exactly once, because the number of pages is a power of two.
+    const PAGE_STRIDE: usize = 4099;
+
+    let num_pages = mp.size_in_pages();
+    let words: &mut [usize] = mp.as_slice_mut(0, num_pages * WORDS_PER_PAGE)?;
+    let hpet = get_hpet().ok_or("couldn't get HPET timer")?;
+    let start_time = hpet.get_counter();
+
+    for pass in 0..TLB_EVAL_PASSES {
+        let mut page = pass % num_pages;
+        for _ in 0..num_pages {
+            let word = &mut words[page * WORDS_PER_PAGE];
+            unsafe { core::ptr::write_volatile(word, core::ptr::read_volatile(word).wrapping_add(1)); }
+            page = (page + PAGE_STRIDE) % num_pages;
+        }
+    }
+
+    let end_time = hpet.get_counter() - hpet_overhead;
+    Ok(hpet_2_ns(end_time - start_time))
+}
+
+
 fn print_usage(opts: &Options) {
     println!("{}", opts.usage(USAGE));
 }
@@ -335,7 +414,8 @@ fn print_usage(opts: &Options) {
 
 const USAGE: &'static str = "Usage: mm_eval [ARGS]
 Evaluates two different memory mapping implementations.
-The normal spill-free MappedPages approach is evaluated by default.";
+The normal spill-free MappedPages approach is evaluated by default.
+With --tlb, instead evaluates the TLB benefit of mapping memory with 2MiB huge pages.";
 
 } // end of cfg_if
 else {
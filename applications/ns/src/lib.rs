@@ -9,12 +9,14 @@ extern crate getopts;
 extern crate memory;
 extern crate task;
 extern crate mod_mgmt;
+extern crate crate_swap;
 extern crate fs_node;
 extern crate path;
 
 use core::{
     ops::Deref,
     fmt::Write,
+    time::Duration,
 };
 use alloc::{
     string::String,
@@ -22,7 +24,8 @@ use alloc::{
     vec::Vec,
 };
 use getopts::{Options, Matches};
-use mod_mgmt::CrateNamespace;
+use mod_mgmt::{CrateNamespace, unload::UnloadedCrates};
+use crate_swap::{QuiescencePolicy, RestoredCheckpoint};
 use fs_node::FileRef;
 use path::Path;
 
@@ -33,6 +36,13 @@ pub fn main(args: Vec<String>) -> isize {
     opts.optflag("r", "recursive", "include recursive namespaces");
     opts.optflag("f", "files", "lists crate object files available in this namespace rather than currently-loaded crates");
     opts.optopt("", "load", "load a crate into the current namespace. Ignores all other arguments.", "CRATE_OBJ_FILE_PATH");
+    opts.optopt("", "unload", "unload a crate that no other crate depends on from the current namespace (or its recursive namespace). Ignores all other arguments.", "CRATE_NAME_PREFIX");
+    opts.optflag("", "unload-unreferenced", "unload all crates in the current namespace that are no longer referenced. Ignores all other arguments.");
+    opts.optopt("", "lazy-linking", "enable or disable lazy linking of crates subsequently loaded into the current namespace. Ignores all other arguments.", "on|off");
+    opts.optopt("", "checkpoint", "take a checkpoint of the crates in the current namespace under the given name. Ignores all other arguments.", "NAME");
+    opts.optopt("", "restore", "restore the current namespace to the checkpoint with the given name. Ignores all other arguments.", "NAME");
+    opts.optopt("", "remove-checkpoint", "remove the checkpoint with the given name. Ignores all other arguments.", "NAME");
+    opts.optflag("", "checkpoints", "list all checkpoints. Ignores all other arguments.");
 
     let matches = match opts.parse(&args) {
         Ok(m) => m,
@@ -72,6 +82,43 @@ fn rmain(matches: Matches) -> Result<(), String> {
             format!("Couldn't resolve path to crate object file at {:?}", path)
         )?;
         load_crate(&mut output, file, &namespace)?;
+    } else if let Some(crate_name_prefix) = matches.opt_str("unload") {
+        unload_crate(&mut output, &crate_name_prefix, &namespace)?;
+    } else if matches.opt_present("unload-unreferenced") {
+        let unloaded = namespace.unload_unreferenced_crates();
+        print_unloaded(&mut output, &unloaded)
+            .map_err(|_e| String::from("String formatting error"))?;
+    } else if let Some(lazy_linking) = matches.opt_str("lazy-linking") {
+        match lazy_linking.as_str() {
+            "on" => CrateNamespace::enable_lazy_linking(&namespace),
+            "off" => namespace.disable_lazy_linking(),
+            _ => return Err(format!("Invalid --lazy-linking value {:?}, expected \"on\" or \"off\"", lazy_linking)),
+        }
+        let stats = mod_mgmt::lazy::lazy_linking_stats();
+        writeln!(output, "Lazy linking is {} for namespace {:?}. {} of {} lazily-linked functions have been resolved.",
+            if namespace.is_lazy_linking_enabled() { "enabled" } else { "disabled" },
+            namespace.name(), stats.resolved, stats.trampolines,
+        ).unwrap();
+    } else if let Some(name) = matches.opt_str("checkpoint") {
+        let checkpoint = crate_swap::checkpoint_namespace(&namespace, &name)
+            .map_err(|e| format!("Couldn't take checkpoint {:?}: {}", name, e))?;
+        writeln!(output, "Took checkpoint {:?} of {} crates in namespace {:?}", checkpoint.name, checkpoint.crates.len(), checkpoint.namespace).unwrap();
+    } else if let Some(name) = matches.opt_str("restore") {
+        let kernel_mmi_ref = memory::get_kernel_mmi_ref().ok_or_else(|| format!("Cannot get kernel_mmi_ref"))?;
+        let restored = crate_swap::restore_checkpoint(&name, kernel_mmi_ref, false, QuiescencePolicy::Wait(RESTORE_QUIESCENCE_TIMEOUT))
+            .map_err(|e| format!("Couldn't restore checkpoint {:?}: {}", name, e))?;
+        print_restored(&mut output, &restored)
+            .map_err(|_e| String::from("String formatting error"))?;
+    } else if let Some(name) = matches.opt_str("remove-checkpoint") {
+        crate_swap::remove_checkpoint(&name)
+            .ok_or_else(|| format!("No checkpoint named {:?} exists", name))?;
+        writeln!(output, "Removed checkpoint {:?}", name).unwrap();
+    } else if matches.opt_present("checkpoints") {
+        for checkpoint in crate_swap::checkpoints() {
+            writeln!(output, "{:?}: namespace {:?}, {} crates, {} symbols, {} relocations, taken at {:?}",
+                checkpoint.name, checkpoint.namespace, checkpoint.crates.len(), checkpoint.symbols, checkpoint.relocations, checkpoint.time,
+            ).unwrap();
+        }
     } else if matches.opt_present("f") {
         print_files(&mut output, 0, namespace.deref(), recursive)
             .map_err(|_e| String::from("String formatting error"))?;
@@ -98,6 +145,55 @@ fn load_crate(output: &mut String, crate_file_ref: FileRef, namespace: &Arc<Crat
 }
 
 
+fn unload_crate(output: &mut String, crate_name_prefix: &str, namespace: &Arc<CrateNamespace>) -> Result<(), String> {
+    let (crate_name, crate_ref, crate_namespace) = CrateNamespace::get_crate_starting_with(namespace, crate_name_prefix)
+        .ok_or_else(|| format!("Couldn't find a single loaded crate starting with {:?}", crate_name_prefix))?;
+
+    let blocking_tasks = crate_swap::tasks_executing_in(&[crate_ref]);
+    if !blocking_tasks.is_empty() {
+        for blocking_task in &blocking_tasks {
+            writeln!(output, "{}", blocking_task).unwrap();
+        }
+        println!("{}", output);
+        return Err(format!("Couldn't unload crate {}, as other tasks are executing within it", crate_name));
+    }
+
+    let unloaded = crate_namespace.unload_crate(&crate_name)
+        .map_err(|e| format!("Couldn't unload crate {}: {}", crate_name, e))?;
+    print_unloaded(output, &unloaded)
+        .map_err(|_e| String::from("String formatting error"))
+}
+
+
+fn print_unloaded(output: &mut String, unloaded: &UnloadedCrates) -> core::fmt::Result {
+    for crate_name in &unloaded.crate_names {
+        writeln!(output, "Unloaded crate {}", crate_name)?;
+    }
+    writeln!(output, "Reclaimed {} bytes: {} text, {} rodata, {} data",
+        unloaded.memory.total_bytes(), unloaded.memory.text_bytes, unloaded.memory.rodata_bytes, unloaded.memory.data_bytes,
+    )
+}
+
+
+fn print_restored(output: &mut String, restored: &RestoredCheckpoint) -> core::fmt::Result {
+    for (old_crate, restored_crate) in &restored.swapped_crates {
+        writeln!(output, "Swapped crate {} back to {}", old_crate, restored_crate)?;
+    }
+    for crate_name in &restored.readded_crates {
+        writeln!(output, "Added crate {} back", crate_name)?;
+    }
+    if !restored.unloaded.crate_names.is_empty() {
+        print_unloaded(output, &restored.unloaded)?;
+    }
+    for crate_name in &restored.remaining_crates {
+        writeln!(output, "Couldn't unload crate {}, which was loaded after the checkpoint", crate_name)?;
+    }
+    writeln!(output, "Restored {} symbols and {} relocations; {} relocations still refer to different crates outside this namespace",
+        restored.restored_symbols, restored.repaired_relocations, restored.changed_relocations,
+    )
+}
+
+
 fn print_files(output: &mut String, indent: usize, namespace: &CrateNamespace, recursive: bool) -> core::fmt::Result {
     writeln!(output, "\n{:indent$}{} CrateNamespace has crate object files:", "", namespace.name(), indent = indent)?;
     let mut files = namespace.dir().lock().list();
@@ -144,5 +240,11 @@ fn print_usage(opts: Options) {
 }
 
 
+/// How long restoring a checkpoint waits for tasks to leave the crates being swapped out or unloaded.
+const RESTORE_QUIESCENCE_TIMEOUT: Duration = Duration::from_secs(1);
+
 const USAGE: &'static str = "\nUsage: ns [OPTION]
-Lists the crates that are loaded in the currently-active crate namespace.";
+Lists the crates that are loaded in the currently-active crate namespace.
+A crate can only be unloaded if no other crate depends on it and no task is executing within it.
+With lazy linking, calls to functions in crates that aren't loaded yet are resolved upon their first call.
+Restoring a checkpoint swaps the checkpointed crates back in and unloads the crates that were loaded after it.";
@@ -1,9 +1,12 @@
 //! A simple application to print the fault log
+//! and the recovery policies that decide how to recover from faults.
 
 #![no_std]
 
 extern crate alloc;
+#[macro_use] extern crate terminal_print;
 extern crate fault_log;
+extern crate fault_policy;
 
 use alloc::vec::Vec;
 use alloc::string::String;
@@ -11,5 +14,9 @@ use fault_log::print_fault_log;
 
 pub fn main(_args: Vec<String>) -> isize {
     print_fault_log();
+    println!("------------------ RECOVERY POLICIES -------------------");
+    for (scope, policy) in fault_policy::policies() {
+        println!("{}: {}", scope, policy);
+    }
     0
 }
@@ -24,6 +24,7 @@ extern crate terminal_print;
 extern crate print;
 extern crate environment;
 extern crate libterm;
+extern crate task_group;
 
 #[macro_use] extern crate alloc;
 #[macro_use] extern crate log;
@@ -47,6 +48,7 @@ use core2::io::Write;
 use core::ops::Deref;
 use app_io::IoStreams;
 use fs_node::FileOrDir;
+use task_group::{ResourceLimits, TaskGroup, TaskGroupRef};
 
 /// The status of a job.
 #[derive(PartialEq)]
@@ -88,7 +90,10 @@ struct Job {
     /// The output reader of the job. It is the reader of `pipe_queues[N]`.
     stdout_reader: StdioReader,
     /// Command line that was used to create the job.
-    cmd: String
+    cmd: String,
+    /// The task group that all tasks in this job belong to,
+    /// which bounds the resources that this job can consume.
+    group: TaskGroupRef,
 }
 
 /// A main function that spawns a new shell and waits for the shell loop to exit before returning an exit value
@@ -169,7 +174,9 @@ struct Shell {
     /// The terminal's current environment
     env: Arc<Mutex<Environment>>,
     /// the terminal that is bind with the shell instance
-    terminal: Arc<Mutex<Terminal>>
+    terminal: Arc<Mutex<Terminal>>,
+    /// The resource limits applied to the task group of each newly-created job.
+    job_limits: ResourceLimits,
 }
 
 impl Shell {
@@ -210,7 +217,8 @@ impl Shell {
             print_consumer,
             print_producer,
             env: Arc::new(Mutex::new(env)),
-            terminal
+            terminal,
+            job_limits: ResourceLimits::default(),
         })
     }
 
@@ -652,8 +660,8 @@ impl Shell {
     }
 
     /// Create a single task. `cmd` is the name of the application. `args` are the provided
-    /// arguments. It returns a task reference on success.
-    fn create_single_task(&mut self, cmd: String, args: Vec<String>) -> Result<JoinableTaskRef, AppErr> {
+    /// arguments. The new task joins the given task `group`. It returns a task reference on success.
+    fn create_single_task(&mut self, cmd: String, args: Vec<String>, group: &TaskGroupRef) -> Result<JoinableTaskRef, AppErr> {
 
         // Check that the application actually exists
         let namespace_dir = task::with_current_task(|t|
@@ -670,6 +678,7 @@ impl Shell {
         let taskref = spawn::new_application_task_builder(app_path, None)
             .map_err(|e| AppErr::SpawnErr(e.to_string()))?
             .argument(args)
+            .group(group.clone())
             .block()
             .spawn()
             .map_err(|e| AppErr::SpawnErr(e.to_string()))?;
@@ -683,7 +692,9 @@ impl Shell {
     /// Evaluate the command line. It creates a sequence of jobs, which forms a chain of applications that
     /// pipe the output from one to the next, and finally back to the shell. If any task fails to start up,
     /// all tasks that have already been spawned will be killed immeidately before returning error.
-    fn eval_cmdline(&mut self) -> Result<Vec<JoinableTaskRef>, AppErr> {
+    /// 
+    /// All tasks in the job are spawned into a new task group, which is returned alongside them.
+    fn eval_cmdline(&mut self) -> Result<(Vec<JoinableTaskRef>, TaskGroupRef), AppErr> {
 
         let cmdline = self.cmdline.trim().to_string();
         let mut task_refs = Vec::new();
@@ -693,6 +704,10 @@ impl Shell {
             return Err(AppErr::NotFound(cmdline))
         }
 
+        // Each job gets its own task group, nested within the shell's group (if any).
+        let shell_group = task::with_current_task(|t| t.group().cloned()).ok().flatten();
+        let group = TaskGroup::new(cmdline.clone(), self.job_limits, shell_group);
+
         for single_task_cmd in cmdline.split('|') {
             let mut args: Vec<String> = single_task_cmd.split_whitespace().map(|s| s.to_string()).collect();
             let command = args.remove(0);
@@ -703,7 +718,7 @@ impl Shell {
                     args.pop();
                 }
             }
-            match self.create_single_task(command, args) {
+            match self.create_single_task(command, args, &group) {
                 Ok(task_ref) => task_refs.push(task_ref),
 
                 // Once we run into an error, we must kill all previously spawned tasks in this command line.
@@ -717,13 +732,13 @@ impl Shell {
                 }
             }
         }
-        Ok(task_refs)
+        Ok((task_refs, group))
     }
 
     /// Start a new job in the shell by the command line.
     fn build_new_job(&mut self) -> Result<isize, &'static str> {
         match self.eval_cmdline() {
-            Ok(task_refs) => {
+            Ok((task_refs, group)) => {
 
                 let mut task_ids = Vec::new();
                 let mut pipe_queues = Vec::new();
@@ -772,7 +787,8 @@ impl Shell {
                     stderr_queues,
                     stdin_writer: job_stdin_writer,
                     stdout_reader: job_stdout_reader,
-                    cmd: self.cmdline.clone()
+                    cmd: self.cmdline.clone(),
+                    group,
                 };
 
                 // All IO streams have been set up for the new tasks. Safe to unblock them now.
@@ -825,7 +841,7 @@ impl Shell {
     /// Try to match the incomplete command against all internal commands. Returns a
     /// vector that contains all matching results.
     fn find_internal_cmd_match(&mut self, incomplete_cmd: &String) -> Result<Vec<String>, &'static str> {
-        let internal_cmds = vec!["fg", "bg", "jobs", "clear"];
+        let internal_cmds = vec!["fg", "bg", "jobs", "clear", "limit"];
         let mut match_cmds = Vec::new();
         for cmd in internal_cmds.iter() {
             if cmd.starts_with(incomplete_cmd) {
@@ -1381,6 +1397,7 @@ impl Shell {
                 "fg" => return true,
                 "bg" => return true,
                 "clear" => return true,
+                "limit" => return true,
                 _ => return false
             }
         }
@@ -1398,6 +1415,7 @@ impl Shell {
                 "fg" => self.execute_internal_fg(),
                 "bg" => self.execute_internal_bg(),
                 "clear" => self.execute_internal_clear(),
+                "limit" => self.execute_internal_limit(),
                 _ => Ok(())
             }
         } else {
@@ -1484,7 +1502,7 @@ impl Shell {
                 JobStatus::Running => "running",
                 JobStatus::Stopped => "stopped"
             };
-            self.terminal.lock().print_to_terminal(format!("[{}] [{}] {}\n", job_num, status, job_ref.cmd).to_string());
+            self.terminal.lock().print_to_terminal(format!("[{}] [{}] (group {}) {}\n", job_num, status, job_ref.group.id(), job_ref.cmd).to_string());
         }
         if self.jobs.is_empty() {
             self.terminal.lock().print_to_terminal("No running or stopped jobs.\n".to_string());
@@ -1493,6 +1511,77 @@ impl Shell {
         self.redisplay_prompt();
         Ok(())
     }
+
+    /// Execute `limit` command. It sets the resource limits of the task group of either
+    /// a given job or all subsequently-created jobs, and prints those limits.
+    fn execute_internal_limit(&mut self) -> Result<(), &'static str> {
+        const USAGE: &str = "Usage: limit [%job_num] [tasks|heap|frames|cpu <value|none>]...\n\
+            \x20 tasks:  maximum number of tasks\n\
+            \x20 heap:   maximum heap usage in bytes\n\
+            \x20 frames: maximum number of physical frames\n\
+            \x20 cpu:    maximum CPU share as a percentage of one CPU\n\
+            Without a job number, the limits apply to all subsequently-created jobs.\n";
+
+        let cmdline_copy = self.cmdline.clone();
+        let mut args: Vec<&str> = cmdline_copy.split_whitespace().skip(1).collect();
+
+        let job_group = match args.first().copied().and_then(|a| a.strip_prefix('%')) {
+            Some(job_num) => {
+                let job_num = job_num.parse::<isize>().ok();
+                match job_num.and_then(|n| self.jobs.get(&n)) {
+                    Some(job) => {
+                        args.remove(0);
+                        Some(job.group.clone())
+                    }
+                    None => {
+                        self.terminal.lock().print_to_terminal(format!("No job {} found!\n", args[0]));
+                        self.clear_cmdline(false)?;
+                        self.redisplay_prompt();
+                        return Ok(());
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let mut limits = job_group.as_ref().map(|g| g.limits()).unwrap_or(self.job_limits);
+        let mut valid = args.len() % 2 == 0;
+        for pair in args.chunks_exact(2) {
+            let value = if pair[1] == "none" {
+                Ok(None)
+            } else {
+                pair[1].parse::<usize>().map(Some)
+            };
+            match (pair[0], value) {
+                ("tasks", Ok(v))  => limits.max_tasks = v,
+                ("heap", Ok(v))   => limits.max_heap_bytes = v,
+                ("frames", Ok(v)) => limits.max_frames = v,
+                ("cpu", Ok(v))    => limits.cpu_percent = v.map(|p| p as u32),
+                _ => valid = false,
+            }
+        }
+
+        if !valid {
+            self.terminal.lock().print_to_terminal(USAGE.to_string());
+        } else {
+            match &job_group {
+                Some(group) => group.set_limits(limits),
+                None => self.job_limits = limits,
+            }
+            let show = |l: Option<usize>| l.map(|v| v.to_string()).unwrap_or_else(|| "none".to_string());
+            self.terminal.lock().print_to_terminal(format!(
+                "{}: tasks {}, heap {}, frames {}, cpu {}\n",
+                job_group.as_ref().map(|g| format!("group {}", g.id())).unwrap_or_else(|| "new jobs".to_string()),
+                show(limits.max_tasks),
+                show(limits.max_heap_bytes),
+                show(limits.max_frames),
+                limits.cpu_percent.map(|p| format!("{}%", p)).unwrap_or_else(|| "none".to_string()),
+            ));
+        }
+        self.clear_cmdline(false)?;
+        self.redisplay_prompt();
+        Ok(())
+    }
 }
 
 
@@ -18,14 +18,15 @@ extern crate task;
 extern crate path;
 extern crate fs_node;
 
+use core::time::Duration;
 use alloc::{
     string::{String, ToString},
     vec::Vec,
     sync::Arc,
 };
 use getopts::{Options, Matches};
-use mod_mgmt::{NamespaceDir, IntoCrateObjectFile};
-use crate_swap::SwapRequest;
+use mod_mgmt::{NamespaceDir, IntoCrateObjectFile, abi::{self, AbiCheckPolicy}};
+use crate_swap::{HealthCheck, QuiescencePolicy, SwapPlan, SwapRequest};
 use hpet::get_hpet;
 use path::Path;
 use fs_node::{FileOrDir, DirRef};
@@ -42,6 +43,12 @@ pub fn main(args: Vec<String>) -> isize {
     opts.optflag("c", "cache", "enable caching of the old crate(s) removed by the swapping action");
     opts.optopt("d", "directory-crates", "the absolute path of the base directory where new crates will be loaded from", "PATH");
     opts.optmulti("t", "state-transfer", "the fully-qualified symbol names of state transfer functions, to be run in the order given", "SYMBOL");
+    opts.optflag("n", "dry-run", "report what the swapping action would change, without actually swapping any crates");
+    opts.optopt("", "health-check", "the fully-qualified symbol name of a health check function; if it fails, the old crate(s) are swapped back in", "SYMBOL");
+    opts.optopt("", "health-timeout", "how long to wait for the health check to pass, in milliseconds (default 1000)", "MS");
+    opts.optopt("", "quiescence", "how to handle tasks executing within the old crate(s): 'ignore', 'reject', 'wait' (default), or 'park'", "POLICY");
+    opts.optopt("", "quiescence-timeout", "how long to wait for the old crate(s) to become quiescent, in milliseconds (default 1000)", "MS");
+    opts.optopt("", "abi-check", "set the system-wide policy for ABI-incompatible crates: 'ignore', 'warn', or 'reject' (default)", "POLICY");
 
     let matches = match opts.parse(&args) {
         Ok(m) => m,
@@ -85,6 +92,39 @@ fn rmain(matches: Matches) -> Result<(), String> {
     let verbose = matches.opt_present("v");
     let cache_old_crates = matches.opt_present("c");
     let state_transfer_functions = matches.opt_strs("t");
+    let dry_run = matches.opt_present("n");
+    let health_check = match matches.opt_str("health-check") {
+        Some(function) => {
+            let timeout_ms = match matches.opt_str("health-timeout") {
+                Some(ms) => ms.parse::<u64>().map_err(|_e| format!("invalid health check timeout {:?}: {}", ms, _e))?,
+                None => DEFAULT_HEALTH_CHECK_TIMEOUT_MS,
+            };
+            Some(HealthCheck { function, timeout: Duration::from_millis(timeout_ms) })
+        }
+        None => None,
+    };
+
+    let quiescence_timeout = match matches.opt_str("quiescence-timeout") {
+        Some(ms) => Duration::from_millis(ms.parse::<u64>().map_err(|_e| format!("invalid quiescence timeout {:?}: {}", ms, _e))?),
+        None => Duration::from_millis(DEFAULT_QUIESCENCE_TIMEOUT_MS),
+    };
+    let quiescence = match matches.opt_str("quiescence").as_deref() {
+        Some("ignore") => QuiescencePolicy::Ignore,
+        Some("reject") => QuiescencePolicy::Reject,
+        Some("wait") | None => QuiescencePolicy::Wait(quiescence_timeout),
+        Some("park") => QuiescencePolicy::Park(quiescence_timeout),
+        Some(other) => return Err(format!("invalid quiescence policy {:?}, expected 'ignore', 'reject', 'wait', or 'park'", other)),
+    };
+
+    if let Some(policy) = matches.opt_str("abi-check") {
+        let policy = match policy.as_str() {
+            "ignore" => AbiCheckPolicy::Ignore,
+            "warn" => AbiCheckPolicy::Warn,
+            "reject" => AbiCheckPolicy::Reject,
+            _ => return Err(format!("invalid ABI check policy {:?}, expected 'ignore', 'warn', or 'reject'", policy)),
+        };
+        abi::set_abi_check_policy(policy);
+    }
 
     let free_args = matches.free.join(" ");
     println!("arguments: {}", free_args);
@@ -99,7 +139,10 @@ fn rmain(matches: Matches) -> Result<(), String> {
         override_namespace_crate_dir,
         state_transfer_functions,
         verbose,
-        cache_old_crates
+        cache_old_crates,
+        dry_run,
+        health_check,
+        quiescence,
     )
 }
 
@@ -155,7 +198,10 @@ fn do_swap(
     override_namespace_crate_dir: Option<NamespaceDir>, 
     state_transfer_functions: Vec<String>,
     verbose_log: bool,
-    cache_old_crates: bool
+    cache_old_crates: bool,
+    dry_run: bool,
+    health_check: Option<HealthCheck>,
+    quiescence: QuiescencePolicy,
 ) -> Result<(), String> {
     let kernel_mmi_ref = memory::get_kernel_mmi_ref().ok_or_else(|| "couldn't get kernel_mmi_ref".to_string())?;
     let namespace = task::with_current_task(|t| t.get_namespace().clone())
@@ -189,6 +235,20 @@ fn do_swap(
         }
         requests
     };
+
+    if dry_run {
+        let plan = crate_swap::plan_swap_crates(
+            &namespace,
+            swap_requests,
+            override_namespace_crate_dir,
+            state_transfer_functions,
+            kernel_mmi_ref,
+            verbose_log,
+            health_check.as_ref(),
+        )?;
+        print_plan(&plan);
+        return Ok(());
+    }
     
     let start = get_hpet().as_ref().ok_or("couldn't get HPET timer")?.get_counter();
 
@@ -200,6 +260,8 @@ fn do_swap(
         kernel_mmi_ref,
         verbose_log,
         cache_old_crates,
+        health_check,
+        quiescence,
     );
     
     let end = get_hpet().as_ref().ok_or("couldn't get HPET timer")?.get_counter();
@@ -219,16 +281,69 @@ fn do_swap(
 }
 
 
+/// Prints the changes that a swap would make, as determined by a dry run.
+fn print_plan(plan: &SwapPlan) {
+    println!("Dry run: no crates were swapped.");
+    if plan.from_cache {
+        println!("New crates would be swapped in from the cache of previously swapped-out crates.");
+    }
+    for cs in &plan.crate_swaps {
+        if !cs.old_crate_loaded {
+            println!("{:?} is not loaded; its object file would be replaced by {}'s.", cs.old_crate, cs.new_crate);
+            continue;
+        }
+        println!("{:?} -> {} (in namespace {}):", cs.old_crate, cs.new_crate, cs.new_namespace);
+        println!("    .data/.bss sections to transfer: {}", cs.data_sections);
+        println!("    relocations to rewrite: {}", cs.relocations);
+        println!("    symbols to reexport: {}", cs.reexported_symbols);
+        for dependent in &cs.dependent_crates {
+            println!("    dependent crate: {}", dependent);
+        }
+        for incompatibility in &cs.abi_incompatibilities {
+            println!("    ABI-incompatible symbol {}: {} (used by {:?})", 
+                incompatibility.symbol, incompatibility.reason, incompatibility.dependent_crates
+            );
+        }
+    }
+    for dep in &plan.new_dependencies {
+        println!("New dependency crate to load: {}", dep);
+    }
+    for st_fn in &plan.state_transfer_functions {
+        println!("State transfer function: {}", st_fn);
+    }
+    for migration in &plan.state_migrations {
+        println!("State migration: {} from version {} to {}", migration.name, migration.from_version, migration.to_version);
+    }
+    if let Some(hc_fn) = &plan.health_check_function {
+        println!("Health check function: {}", hc_fn);
+    }
+    for blocking_task in &plan.blocking_tasks {
+        println!("Blocking task: {}", blocking_task);
+    }
+}
+
 
 fn print_usage(opts: Options) {
     println!("{}", opts.usage(USAGE));
 }
 
 
+/// The default time to wait for a health check to pass after swapping.
+const DEFAULT_HEALTH_CHECK_TIMEOUT_MS: u64 = 1000;
+
+/// The default time to wait for the old crates to become quiescent before swapping.
+const DEFAULT_QUIESCENCE_TIMEOUT_MS: u64 = 1000;
+
 const USAGE: &'static str = "Usage: swap (OLD1, NEW1 [, true | false]) [(OLD2, NEW2 [, true | false])]...
 Swaps the given list of crate tuples, with NEW# replacing OLD# in each tuple.
 The OLD and NEW values are crate names, such as \"my_crate-<hash>\".
 Both the old crate name and the new crate name can be prefixes, e.g., \"my_cra\" will find \"my_crate-<hash>\", 
 but *only* if there is a single matching crate or object file.
 A third element of each tuple is the optional 'reexport_new_symbols_as_old' boolean, which if true, 
-will reexport new symbols under their old names, if those symbols match (excluding hashes).";
+will reexport new symbols under their old names, if those symbols match (excluding hashes).
+If any step of the swap fails, the old crates are left in place and continue to be used.
+Use --dry-run to see what a swap would change without performing it.
+A swap is rejected if a new crate is ABI-incompatible with a crate that depends on the old crate,
+unless that dependent crate is swapped too; use --abi-check to change this policy.
+By default, a swap waits for other tasks to stop executing within the old crates
+and fails if they haven't by the timeout; use --quiescence to change this policy.";
@@ -0,0 +1,97 @@
+//! Tests that every loaded crate's ABI metadata is consistent with that of the crates it links against,
+//! and that a crate is always considered ABI-compatible with itself.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    vec::Vec,
+};
+use mod_mgmt::{
+    abi::{self, AbiCheckPolicy, SymbolAbi},
+    StrongCrateRef,
+};
+
+
+pub fn main(_args: Vec<String>) -> isize {
+    match rmain() {
+        Ok(_) => {
+            println!("test_abi_check passed.");
+            0
+        }
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn rmain() -> Result<(), &'static str> {
+    let namespace = task::with_current_task(|t| t.get_namespace().clone())
+        .map_err(|_| "couldn't get current task")?;
+
+    let mut crates: Vec<StrongCrateRef> = Vec::new();
+    namespace.for_each_crate(true, |_name, crate_ref| {
+        crates.push(crate_ref.clone_shallow());
+        true
+    });
+
+    // Gather all crates' ABI metadata, and every symbol that they export.
+    let mut abis = Vec::new();
+    let mut exports: BTreeMap<String, SymbolAbi> = BTreeMap::new();
+    for crate_ref in &crates {
+        let (crate_name, object_file) = {
+            let krate = crate_ref.lock_as_ref();
+            (krate.crate_name.clone(), krate.object_file.clone())
+        };
+        // The `nano_core`'s object file is a serialized symbol table rather than an ELF file.
+        if let Ok(Some(crate_abi)) = abi::read_crate_abi(&object_file) {
+            exports.extend(crate_abi.exports.iter().map(|(name, sym)| (name.clone(), *sym)));
+            abis.push((crate_name, crate_abi));
+        }
+    }
+    if abis.is_empty() {
+        println!("No loaded crates have ABI metadata; build with `make crate_abi=yes` to check ABI compatibility.");
+        return Ok(());
+    }
+    println!("{} of {} loaded crates have ABI metadata.", abis.len(), crates.len());
+
+    // Every crate was built against the other crates in this build, so all of its imports must be compatible.
+    let mut checked_imports = 0;
+    for (crate_name, crate_abi) in &abis {
+        for (symbol, expected) in &crate_abi.imports {
+            // Crates without ABI metadata don't contribute any exports.
+            let Some(found) = exports.get(symbol) else { continue };
+            if !found.is_compatible_with(expected) {
+                println!("{} imports {} as {:?}, but it was exported as {:?}", crate_name, symbol, expected, found);
+                return Err("a crate's imported symbol was incompatible with the exported symbol");
+            }
+            checked_imports += 1;
+        }
+    }
+    println!("Checked {} imported symbols.", checked_imports);
+
+    // A crate must be able to replace itself without breaking any of the crates that depend on it.
+    for crate_ref in &crates {
+        let object_file = crate_ref.lock_as_ref().object_file.clone();
+        let incompatibilities = abi::check_crate_replacement(crate_ref, &object_file);
+        if !incompatibilities.is_empty() {
+            println!("{:?} is incompatible with itself: {:?}", crate_ref, incompatibilities);
+            return Err("a crate was ABI-incompatible with itself");
+        }
+    }
+    println!("Checked {} crates against themselves.", crates.len());
+
+    let policy = abi::abi_check_policy();
+    abi::set_abi_check_policy(AbiCheckPolicy::Warn);
+    let changed = abi::abi_check_policy() == AbiCheckPolicy::Warn;
+    abi::set_abi_check_policy(policy);
+    if !changed || abi::abi_check_policy() != policy {
+        return Err("couldn't change the ABI check policy");
+    }
+    Ok(())
+}
@@ -0,0 +1,93 @@
+//! Tests that copy-on-write mappings share frames until written to,
+//! and that writes to either mapping are not visible through the other one.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use memory::{EntryFlags, MappedPages};
+
+const NUM_PAGES: usize = 16;
+const PAGE_SIZE: usize = 4096;
+
+
+pub fn main(_args: Vec<String>) -> isize {
+    match rmain() {
+        Ok(_) => {
+            println!("test_copy_on_write passed.");
+            0
+        }
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn rmain() -> Result<(), &'static str> {
+    let kernel_mmi_ref = memory::get_kernel_mmi_ref().ok_or("KERNEL_MMI was not yet initialized")?;
+    let mut original = memory::create_mapping(NUM_PAGES * PAGE_SIZE, EntryFlags::WRITABLE)?;
+    for page in 0..NUM_PAGES {
+        *original.as_type_mut::<u64>(page * PAGE_SIZE)? = page as u64;
+    }
+
+    let stats_before = memory::copy_on_write_stats();
+    let mut copy = original.copy_on_write(None, &mut kernel_mmi_ref.lock().page_table)?;
+    println!("shared {:?} as {:?}", original, copy);
+
+    let stats = memory::copy_on_write_stats();
+    if stats.shared_frames != stats_before.shared_frames + NUM_PAGES {
+        return Err("the copy did not share all of the original's frames");
+    }
+    for page in 0..NUM_PAGES {
+        if read(&copy, page)? != page as u64 {
+            return Err("the copy's contents differ from the original's");
+        }
+    }
+
+    // Writing to the copy must copy the page, leaving the original unchanged.
+    *copy.as_type_mut::<u64>(0)? = 100;
+    if read(&original, 0)? != 0 || read(&copy, 0)? != 100 {
+        return Err("a write to the copy was visible in the original");
+    }
+    let stats = memory::copy_on_write_stats();
+    if stats.copied_pages != stats_before.copied_pages + 1 {
+        return Err("a write to a shared page of the copy did not copy exactly one page");
+    }
+
+    // The original is now the only mapping of that frame, so it can reclaim it without copying.
+    *original.as_type_mut::<u64>(0)? = 200;
+    let stats = memory::copy_on_write_stats();
+    if stats.reclaimed_pages != stats_before.reclaimed_pages + 1 || stats.copied_pages != stats_before.copied_pages + 1 {
+        return Err("the last mapping of a shared frame did not reclaim it");
+    }
+
+    // Writing to the original must copy the page, leaving the copy unchanged.
+    *original.as_type_mut::<u64>(PAGE_SIZE)? = 300;
+    if read(&copy, 1)? != 1 || read(&original, 1)? != 300 || read(&copy, 0)? != 100 {
+        return Err("a write to the original was visible in the copy");
+    }
+
+    drop(copy);
+    for page in 2..NUM_PAGES {
+        if read(&original, page)? != page as u64 {
+            return Err("the original's contents changed after dropping the copy");
+        }
+    }
+    drop(original);
+    let stats = memory::copy_on_write_stats();
+    if stats.shared_frames != stats_before.shared_frames {
+        println!("stats before: {:?}, after: {:?}", stats_before, stats);
+        return Err("dropping both mappings did not release their shared frames");
+    }
+    Ok(())
+}
+
+fn read(mp: &MappedPages, page: usize) -> Result<u64, &'static str> {
+    mp.as_type::<u64>(page * PAGE_SIZE).copied()
+}
@@ -0,0 +1,76 @@
+//! Tests that crate object files are only accepted under the `Require` signature policy
+//! if they are correctly signed by a trusted key, and that tampering with a signed crate is detected.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use crate_signatures::{CrateSignature, SignaturePolicy};
+use fs_node::File;
+
+/// The prefix of the crate object file whose signature is checked.
+const CRATE_PREFIX: &str = "hello-";
+
+
+pub fn main(_args: Vec<String>) -> isize {
+    match rmain() {
+        Ok(_) => {
+            println!("test_crate_signatures passed.");
+            0
+        }
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn rmain() -> Result<(), &'static str> {
+    let namespace = task::with_current_task(|t| t.get_namespace().clone())
+        .map_err(|_| "couldn't get current task")?;
+    let file = namespace.dir().get_file_starting_with(CRATE_PREFIX)
+        .ok_or("couldn't find the crate object file")?;
+    let bytes: Vec<u8> = {
+        let file = file.lock();
+        let mapped_pages = file.as_mapping()?;
+        mapped_pages.as_slice::<u8>(0, file.len())?.to_vec()
+    };
+
+    let (contents, signature) = CrateSignature::split(&bytes);
+    println!("{} trusted keys, signature policy {:?}; crate object file is {}signed",
+        crate_signatures::trusted_keys().len(), crate_signatures::signature_policy(),
+        if signature.is_some() { "" } else { "not " },
+    );
+
+    // An unsigned crate is only accepted if signatures aren't required.
+    if verify(contents, SignaturePolicy::Require).is_ok() {
+        return Err("an unsigned crate was accepted when signatures are required");
+    }
+    verify(contents, SignaturePolicy::Warn)?;
+    verify(contents, SignaturePolicy::Ignore)?;
+
+    // A signed crate is accepted if it's signed by a trusted key, but not once it has been modified.
+    let Some(signature) = signature else {
+        println!("Skipping signed crate checks; build with `make signing_key=<KEY_FILE>` to sign crates.");
+        return Ok(());
+    };
+    let signed_by_trusted_key = crate_signatures::trusted_keys().contains(&signature.public_key);
+    if verify(&bytes, SignaturePolicy::Require).is_ok() != signed_by_trusted_key {
+        return Err("whether a signed crate was accepted didn't match whether its signer is trusted");
+    }
+    let mut tampered = bytes.clone();
+    tampered[contents.len() / 2] ^= 0xFF;
+    if verify(&tampered, SignaturePolicy::Require).is_ok() {
+        return Err("a signed crate was accepted after it was modified");
+    }
+    Ok(())
+}
+
+fn verify(crate_object_file: &[u8], policy: SignaturePolicy) -> Result<(), &'static str> {
+    crate_signatures::verify_crate_object_with_policy(crate_object_file, CRATE_PREFIX, policy)
+}
@@ -0,0 +1,103 @@
+//! Tests that the crate and section dependency graphs of the current namespace contain this crate
+//! and its dependencies, that they can be written as DOT and JSON,
+//! and that every edge's dependent and depended-on sections agree on its relocations.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use mod_mgmt::graph::{DependencyGraph, GraphFormat, GraphKind};
+
+pub fn main(_args: Vec<String>) -> isize {
+    match rmain() {
+        Ok(_) => {
+            println!("test_dependency_graph passed.");
+            0
+        }
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn rmain() -> Result<(), &'static str> {
+    let namespace = task::with_current_task(|t| t.get_namespace().clone())
+        .map_err(|_| "couldn't get the current task's namespace")?;
+
+    let crates = namespace.dependency_graph(GraphKind::Crates, true);
+    check_graph(&crates)?;
+    let this_crate = crates.nodes.iter()
+        .position(|node| node.crate_name.contains("test_dependency_graph"))
+        .ok_or("the crate graph doesn't contain this crate")?;
+    if crates.nodes[this_crate].namespace.as_deref() != Some(namespace.name()) {
+        return Err("this crate's node isn't in the current namespace");
+    }
+    let mod_mgmt = crates.nodes.iter()
+        .position(|node| node.crate_name.contains("mod_mgmt"))
+        .ok_or("the crate graph doesn't contain mod_mgmt")?;
+    if !crates.edges.iter().any(|edge| edge.from == this_crate && edge.to == mod_mgmt && edge.strong > 0) {
+        return Err("the crate graph doesn't contain the edge from this crate to mod_mgmt");
+    }
+    println!("The crate graph has {} nodes and {} edges.", crates.nodes.len(), crates.edges.len());
+
+    let local_crates = namespace.dependency_graph(GraphKind::Crates, false);
+    check_graph(&local_crates)?;
+    if local_crates.namespaces.len() > crates.namespaces.len()
+        || local_crates.nodes.iter().filter(|node| node.namespace.is_some()).count()
+            > crates.nodes.iter().filter(|node| node.namespace.is_some()).count()
+    {
+        return Err("the local crate graph contains more crates than the recursive crate graph");
+    }
+
+    let sections = namespace.dependency_graph(GraphKind::Sections, true);
+    check_graph(&sections)?;
+    let main_section = sections.nodes.iter()
+        .find(|node| node.crate_name.contains("test_dependency_graph")
+            && node.section_name.as_deref().map_or(false, |name| name.starts_with("test_dependency_graph::main")))
+        .ok_or("the section graph doesn't contain this crate's main function")?;
+    if !main_section.id.contains('/') {
+        return Err("a section node's ID doesn't include its crate");
+    }
+    println!("The section graph has {} nodes and {} edges.", sections.nodes.len(), sections.edges.len());
+
+    for graph in [&crates, &sections] {
+        let mut dot = String::new();
+        graph.write(GraphFormat::Dot, &mut dot).map_err(|_| "failed to write a graph as DOT")?;
+        if !dot.starts_with("digraph") || !dot.contains(&crates.nodes[this_crate].id) {
+            return Err("the DOT output is malformed or missing this crate");
+        }
+        let mut json = String::new();
+        graph.write(GraphFormat::Json, &mut json).map_err(|_| "failed to write a graph as JSON")?;
+        if !json.starts_with('{') || !json.trim_end().ends_with('}') || !json.contains("\"edges\": [") {
+            return Err("the JSON output is malformed");
+        }
+    }
+    println!("Wrote both graphs as DOT and JSON.");
+
+    Ok(())
+}
+
+/// Checks that every edge of the given graph refers to valid nodes and has consistent relocation counts.
+fn check_graph(graph: &DependencyGraph) -> Result<(), &'static str> {
+    if graph.nodes.is_empty() || graph.edges.is_empty() {
+        return Err("a dependency graph is empty");
+    }
+    if graph.edges.iter().any(|edge| edge.from >= graph.nodes.len() || edge.to >= graph.nodes.len()) {
+        return Err("an edge refers to a node that doesn't exist");
+    }
+    if graph.nodes.windows(2).any(|pair| pair[0].id >= pair[1].id) {
+        return Err("the nodes aren't sorted by unique IDs");
+    }
+    let inconsistent = graph.inconsistent_edges().count();
+    if inconsistent > 0 {
+        println!("{} edges of the {} graph have inconsistent dependency metadata.", inconsistent, graph.kind);
+        return Err("the dependent and depended-on sections of an edge disagree on its relocations");
+    }
+    Ok(())
+}
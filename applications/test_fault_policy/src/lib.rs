@@ -0,0 +1,112 @@
+//! Tests that a recovery policy restarts a faulting task only as many times as it allows,
+//! delays each restart by its backoff, records its decisions in the fault log,
+//! and notifies the supervisor once it gives up on the task.
+//!
+//! Restarts are counted within a short window, so this test can be run again after that window has elapsed.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+use alloc::{
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use fault_log::RecoveryAction;
+use fault_policy::{Backoff, PolicyScope, RecoveryPolicy, RecoveryStep, ReplaceableCrates};
+
+const TASK_NAME: &str = "test_fault_policy_task";
+const MAX_RESTARTS: usize = 2;
+const BACKOFF: Duration = Duration::from_millis(20);
+
+/// The number of times the faulting task has run.
+static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+fn always_panics(_: ()) {
+    RUNS.fetch_add(1, Ordering::SeqCst);
+    panic!("test_fault_policy: intentional panic");
+}
+
+pub fn main(_args: Vec<String>) -> isize {
+    let scope = PolicyScope::App("test_fault_policy".to_string());
+    let previous_policy = fault_policy::set_policy(scope.clone(), RecoveryPolicy {
+        max_restarts: MAX_RESTARTS,
+        window: Duration::from_secs(2),
+        backoff: Some(Backoff { initial: BACKOFF, max: BACKOFF * 2 }),
+        escalation: vec![RecoveryStep::RestartTask],
+        replaceable_crates: ReplaceableCrates::None,
+        notify_supervisor: true,
+    });
+    let (sender, receiver) = async_channel::new_channel(4);
+    let previous_supervisor = fault_policy::set_supervisor(Some(sender));
+
+    let result = rmain(&scope, &receiver);
+
+    fault_policy::set_supervisor(previous_supervisor);
+    match previous_policy {
+        Some(policy) => fault_policy::set_policy(scope, policy),
+        None => fault_policy::remove_policy(scope),
+    };
+
+    match result {
+        Ok(_) => {
+            println!("test_fault_policy passed.");
+            0
+        }
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn rmain(scope: &PolicyScope, supervisor: &async_channel::Receiver<fault_log::FaultEntry>) -> Result<(), &'static str> {
+    RUNS.store(0, Ordering::SeqCst);
+    spawn::new_task_builder(always_panics, ())
+        .name(String::from(TASK_NAME))
+        .spawn_restartable(None)?;
+
+    // The supervisor is notified once the policy gives up on the task.
+    let notification = supervisor.receive().map_err(|_| "failed to receive the supervisor notification")?;
+    if notification.running_task.as_deref() != Some(TASK_NAME) || notification.action_taken != RecoveryAction::GaveUp {
+        return Err("the supervisor was notified about the wrong fault");
+    }
+    let runs = RUNS.load(Ordering::SeqCst);
+    println!("The policy gave up on the task after {} runs.", runs);
+    if runs != MAX_RESTARTS + 1 {
+        return Err("the task wasn't restarted exactly as many times as the policy allows");
+    }
+
+    // The last faults of the task show the policy's decisions.
+    let faults: Vec<_> = fault_log::get_fault_log().into_iter()
+        .filter(|fe| fe.running_task.as_deref() == Some(TASK_NAME) && fe.action_taken != RecoveryAction::MultipleFaultRecovery)
+        .collect();
+    let faults = &faults[faults.len().saturating_sub(MAX_RESTARTS + 1)..];
+    let actions: Vec<_> = faults.iter().map(|fe| fe.action_taken.clone()).collect();
+    if actions != [RecoveryAction::TaskRestarted, RecoveryAction::TaskRestarted, RecoveryAction::GaveUp] {
+        println!("Actions taken: {:?}", actions);
+        return Err("the fault log doesn't show the expected recovery actions");
+    }
+    let policy_name = scope.to_string();
+    if faults.iter().any(|fe| fe.recovery_policy.as_deref() != Some(policy_name.as_str())) {
+        return Err("the fault log doesn't show the policy that handled the faults");
+    }
+    for (i, pair) in faults.windows(2).enumerate() {
+        let (Some(earlier), Some(later)) = (pair[0].time, pair[1].time) else {
+            return Err("a fault has no timestamp");
+        };
+        let expected_backoff = BACKOFF * 2u32.pow(i as u32);
+        if later.saturating_sub(earlier) < expected_backoff {
+            return Err("a restart wasn't delayed by the policy's backoff");
+        }
+    }
+    println!("The fault log shows the expected recovery actions and backoff.");
+
+    Ok(())
+}
@@ -0,0 +1,107 @@
+//! Tests that the buddy frame allocator frees split allocations back into whole blocks,
+//! returns frames held in its per-CPU caches when they're requested at a specific address,
+//! and aligns huge frame allocations.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use memory::{PageSize, PhysicalAddress, PAGE_SIZE};
+
+const NUM_SINGLE_FRAMES: usize = 64;
+
+
+pub fn main(_args: Vec<String>) -> isize {
+    if frame_allocator::buddy_allocator_stats().is_none() {
+        println!("test_frame_allocator skipped: the buddy frame allocator is not in use.");
+        return 0;
+    }
+    let result = rmain();
+    frame_allocator::set_per_cpu_caching(true);
+    match result {
+        Ok(_) => {
+            println!("test_frame_allocator passed.");
+            0
+        }
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn rmain() -> Result<(), &'static str> {
+    test_single_frames()?;
+    test_cached_frame_at_address()?;
+    test_merging()?;
+    test_huge_frames()?;
+    Ok(())
+}
+
+/// Allocates many single frames, which must all be distinct.
+fn test_single_frames() -> Result<(), &'static str> {
+    let mut frames = Vec::with_capacity(NUM_SINGLE_FRAMES);
+    for _ in 0..NUM_SINGLE_FRAMES {
+        frames.push(frame_allocator::allocate_frames(1).ok_or("couldn't allocate a single frame")?);
+    }
+    let mut addresses: Vec<PhysicalAddress> = frames.iter().map(|af| af.start_address()).collect();
+    addresses.sort_unstable();
+    addresses.dedup();
+    if addresses.len() != NUM_SINGLE_FRAMES {
+        return Err("the same frame was allocated more than once");
+    }
+    Ok(())
+}
+
+/// Frees a single frame, which may go into the current CPU's cache, and then allocates it at its address.
+fn test_cached_frame_at_address() -> Result<(), &'static str> {
+    let frame = frame_allocator::allocate_frames(1).ok_or("couldn't allocate a single frame")?;
+    let address = frame.start_address();
+    drop(frame);
+    let frame = memory::allocate_frames_at(address, 1)
+        .map_err(|_| "couldn't re-allocate a freed single frame at its address")?;
+    if frame.start_address() != address {
+        return Err("a frame allocated at a specific address started elsewhere");
+    }
+    Ok(())
+}
+
+/// Splits a block into several allocations and frees them, after which the whole block must be free again.
+fn test_merging() -> Result<(), &'static str> {
+    frame_allocator::set_per_cpu_caching(false);
+
+    let block = frame_allocator::allocate_frames(16).ok_or("couldn't allocate 16 frames")?;
+    let start = block.start_address();
+    drop(block);
+
+    let parts = [
+        memory::allocate_frames_at(start, 1)?,
+        memory::allocate_frames_at(start + PAGE_SIZE, 3)?,
+        memory::allocate_frames_at(start + 4 * PAGE_SIZE, 12)?,
+    ];
+    drop(parts);
+
+    let block = memory::allocate_frames_at(start, 16)
+        .map_err(|_| "couldn't re-allocate frames that were freed in separate parts")?;
+    println!("merged freed frames back into {:?}", block);
+    println!("{:?}", frame_allocator::buddy_allocator_stats());
+    Ok(())
+}
+
+/// Allocates a 2 MiB huge frame, which must be aligned to its size.
+fn test_huge_frames() -> Result<(), &'static str> {
+    let huge_frame = memory::allocate_huge_frames(PageSize::Huge2M, 1)
+        .ok_or("couldn't allocate a 2 MiB huge frame")?;
+    if huge_frame.start_address().value() % (2 * 1024 * 1024) != 0 {
+        return Err("a 2 MiB huge frame was not aligned to 2 MiB");
+    }
+    if huge_frame.size_in_frames() != PageSize::Huge2M.num_4k_pages() {
+        return Err("a 2 MiB huge frame had the wrong number of frames");
+    }
+    Ok(())
+}
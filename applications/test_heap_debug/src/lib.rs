@@ -0,0 +1,87 @@
+//! Tests that the debugging heap allocator detects buffer overflows, double frees,
+//! and writes to freed memory, and that it lists outstanding allocations since a checkpoint.
+//!
+//! This requires Theseus to be built with `THESEUS_CONFIG=debug_heap`.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+use alloc::{
+    alloc::{alloc, dealloc, Layout},
+    boxed::Box,
+    string::String,
+    vec::Vec,
+};
+
+const SIZE: usize = 100;
+
+
+pub fn main(_args: Vec<String>) -> isize {
+    match rmain() {
+        Ok(_) => {
+            println!("test_heap_debug passed.");
+            0
+        }
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn rmain() -> Result<(), &'static str> {
+    if !heap_debug::is_installed() {
+        return Err("the debugging heap allocator is not in use; rebuild Theseus with `THESEUS_CONFIG=debug_heap`");
+    }
+    let layout = Layout::from_size_align(SIZE, 8).map_err(|_| "invalid layout")?;
+
+    // A write just past the end of an allocation must be detected when it's freed.
+    let before = heap_debug::stats();
+    unsafe {
+        let ptr = alloc(layout);
+        ptr.add(SIZE).write(0);
+        dealloc(ptr, layout);
+    }
+    if heap_debug::stats().corruptions != before.corruptions + 1 {
+        return Err("a buffer overflow was not detected");
+    }
+
+    // Freeing the same allocation twice must be detected and otherwise ignored.
+    let before = heap_debug::stats();
+    unsafe {
+        let ptr = alloc(layout);
+        dealloc(ptr, layout);
+        dealloc(ptr, layout);
+    }
+    if heap_debug::stats().invalid_frees != before.invalid_frees + 1 {
+        return Err("a double free was not detected");
+    }
+
+    // Freed memory must be poisoned.
+    unsafe {
+        let ptr = alloc(layout);
+        ptr.write_bytes(0, SIZE);
+        dealloc(ptr, layout);
+        if ptr.read_volatile() == 0 {
+            return Err("freed memory was not poisoned");
+        }
+    }
+
+    // An allocation made after a checkpoint is outstanding until it's freed.
+    let checkpoint = heap_debug::set_checkpoint();
+    let leaked = Box::new([0u8; SIZE]);
+    let address = &*leaked as *const _ as usize;
+    let is_outstanding = || heap_debug::outstanding_allocations_since(checkpoint)
+        .iter()
+        .any(|a| a.address == address && a.size == SIZE);
+    if !is_outstanding() {
+        return Err("an allocation made after the checkpoint was not listed as outstanding");
+    }
+    drop(leaked);
+    if is_outstanding() {
+        return Err("a freed allocation was still listed as outstanding");
+    }
+    Ok(())
+}
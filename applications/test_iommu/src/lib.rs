@@ -0,0 +1,72 @@
+//! Tests mapping memory into the DMA domain of the e1000 NIC,
+//! and checks that the NIC hasn't caused any DMA remapping faults.
+//!
+//! Run this in QEMU with the `IOMMU=yes` and `net=user` options to emulate an IOMMU and an e1000 NIC.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use memory::{create_contiguous_mapping, EntryFlags, PAGE_SIZE};
+
+const INTEL_VENDOR_ID: u16 = 0x8086;
+const E1000_DEVICE_ID: u16 = 0x100E;
+
+
+pub fn main(_args: Vec<String>) -> isize {
+    match rmain() {
+        Ok(_) => {
+            println!("test_iommu passed.");
+            0
+        }
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn rmain() -> Result<(), &'static str> {
+    println!("IOMMU present: {}", iommu::iommu_present());
+
+    // Only use a device whose driver has already attached it to a domain,
+    // since attaching any other device would block all of its DMA.
+    let Some(device) = pci::pci_device_iter().find(|dev|
+        dev.vendor_id == INTEL_VENDOR_ID && dev.device_id == E1000_DEVICE_ID
+    ) else {
+        println!("No e1000 NIC was found, skipping test.");
+        return Ok(());
+    };
+
+    let domain = iommu::domain_for_device(device)?;
+    println!("e1000 NIC at {} is in {:?}", device.location, domain);
+    if iommu::iommu_present() && !domain.is_remapped() {
+        println!("Warning: the IOMMU doesn't support DMA remapping.");
+    }
+    let same_domain = iommu::domain_for_device(device)?;
+    if same_domain.id() != domain.id() {
+        return Err("a device was given two different DMA domains");
+    }
+
+    let (mp, paddr) = create_contiguous_mapping(4 * PAGE_SIZE, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE)?;
+    let iova = domain.map(&mp, true)?;
+    println!("Mapped {:#X} (physical address {:#X}) into the domain at IOVA {:#X}", mp.start_address(), paddr, iova);
+    if iova != paddr {
+        return Err("DMA domains should identity-map physical memory");
+    }
+    domain.unmap(&mp)?;
+    // Unmapping memory that isn't mapped should have no effect.
+    domain.unmap(&mp)?;
+
+    let faults = iommu::report_faults();
+    if faults != 0 {
+        println!("The IOMMU recorded {} DMA remapping faults; see the log for details.", faults);
+        return Err("the IOMMU recorded DMA remapping faults");
+    }
+    Ok(())
+}
@@ -0,0 +1,93 @@
+//! Tests that lazy mappings are only backed by frames upon first access,
+//! and that those frames are zeroed and freed when the mapping is dropped.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use memory::{EntryFlags, MappedPages};
+
+const MAPPING_SIZE: usize = 64 * 1024 * 1024;
+const PAGE_SIZE: usize = 4096;
+/// The offsets (in pages) of the pages that are accessed within the lazy mapping.
+const TOUCHED_PAGES: [usize; 4] = [0, 1, 5000, MAPPING_SIZE / PAGE_SIZE - 1];
+
+
+pub fn main(_args: Vec<String>) -> isize {
+    match rmain() {
+        Ok(_) => {
+            println!("test_lazy_mapping passed.");
+            0
+        }
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn rmain() -> Result<(), &'static str> {
+    let stats_before = memory::lazy_mapping_stats();
+    let mut mp = memory::create_lazy_mapping(MAPPING_SIZE, EntryFlags::WRITABLE)?;
+    println!("created lazy mapping: {:?}", mp);
+
+    if !mp.is_lazy() || mp.resident_pages() != 0 {
+        return Err("a new lazy mapping should not have any resident pages");
+    }
+    let stats = memory::lazy_mapping_stats();
+    if stats.reserved_pages != stats_before.reserved_pages + mp.size_in_pages() {
+        return Err("the lazy mapping's pages were not counted as reserved");
+    }
+
+    for (i, page) in TOUCHED_PAGES.iter().enumerate() {
+        touch_page(&mut mp, *page, i as u64 + 1)?;
+        if mp.resident_pages() != i + 1 {
+            return Err("accessing a lazy page did not make exactly one page resident");
+        }
+    }
+    // Accessing already-resident pages again must not allocate more frames.
+    for (i, page) in TOUCHED_PAGES.iter().enumerate() {
+        let value: &u64 = mp.as_type(page * PAGE_SIZE)?;
+        if *value != i as u64 + 1 {
+            return Err("a lazy page lost the value written to it");
+        }
+    }
+    if mp.resident_pages() != TOUCHED_PAGES.len() {
+        return Err("re-accessing resident lazy pages changed the number of resident pages");
+    }
+    let stats = memory::lazy_mapping_stats();
+    if stats.resident_pages < stats_before.resident_pages + TOUCHED_PAGES.len() {
+        return Err("the lazy mapping's resident pages were not counted in the global stats");
+    }
+    println!("{} of {} lazy pages are resident, global stats: {:?}", mp.resident_pages(), mp.size_in_pages(), stats);
+
+    let second_page = *mp.start() + 1;
+    let mp = match mp.split(second_page) {
+        Ok(_) => return Err("splitting a lazy mapping should fail"),
+        Err(mp) => mp,
+    };
+
+    drop(mp);
+    let stats_after = memory::lazy_mapping_stats();
+    if stats_after != stats_before {
+        println!("stats before: {:?}, after: {:?}", stats_before, stats_after);
+        return Err("dropping the lazy mapping did not release its reserved and resident pages");
+    }
+    Ok(())
+}
+
+/// Checks that the given page of the lazy mapping reads as zero, then writes `value` into it.
+fn touch_page(mp: &mut MappedPages, page: usize, value: u64) -> Result<(), &'static str> {
+    let offset = page * PAGE_SIZE;
+    let contents: &[u64] = mp.as_slice(offset, PAGE_SIZE / core::mem::size_of::<u64>())?;
+    if contents.iter().any(|word| *word != 0) {
+        return Err("a newly-accessed lazy page was not zeroed");
+    }
+    *mp.as_type_mut::<u64>(offset)? = value;
+    Ok(())
+}
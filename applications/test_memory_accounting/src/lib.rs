@@ -0,0 +1,76 @@
+//! Tests that heap, page, and frame allocations are charged to the current task
+//! and to the system-wide totals, and that they are credited back when freed.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use memory::EntryFlags;
+use memory_accounting::MemoryUsage;
+
+const NUM_PAGES: usize = 16;
+const PAGE_SIZE: usize = 4096;
+const HEAP_BYTES: usize = 10000;
+
+
+pub fn main(_args: Vec<String>) -> isize {
+    match rmain() {
+        Ok(_) => {
+            println!("test_memory_accounting passed.");
+            0
+        }
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn rmain() -> Result<(), &'static str> {
+    let before = current_usage()?;
+    let total_before = memory_accounting::total_usage();
+
+    let buffer: Vec<u8> = Vec::with_capacity(HEAP_BYTES);
+    let after_heap = current_usage()?;
+    if after_heap.heap_bytes < before.heap_bytes + HEAP_BYTES as isize {
+        println!("before: {:?}, after: {:?}", before, after_heap);
+        return Err("a heap allocation was not charged to the current task");
+    }
+    drop(buffer);
+    let after_free = current_usage()?;
+    if after_free.heap_bytes > after_heap.heap_bytes - HEAP_BYTES as isize {
+        println!("before: {:?}, after: {:?}", after_heap, after_free);
+        return Err("a heap deallocation was not credited back to the current task");
+    }
+
+    let mapping = memory::create_mapping(NUM_PAGES * PAGE_SIZE, EntryFlags::WRITABLE)?;
+    let after_map = current_usage()?;
+    if after_map.pages < after_free.pages + NUM_PAGES as isize
+        || after_map.frames < after_free.frames + NUM_PAGES as isize
+    {
+        println!("before: {:?}, after: {:?}", after_free, after_map);
+        return Err("a mapping's pages and frames were not charged to the current task");
+    }
+    let total_map = memory_accounting::total_usage();
+    if total_map.frames < total_before.frames + NUM_PAGES as isize {
+        return Err("a mapping's frames were not charged to the system-wide totals");
+    }
+    drop(mapping);
+    let after_unmap = current_usage()?;
+    if after_unmap.pages > after_map.pages - NUM_PAGES as isize
+        || after_unmap.frames > after_map.frames - NUM_PAGES as isize
+    {
+        println!("before: {:?}, after: {:?}", after_map, after_unmap);
+        return Err("a mapping's pages and frames were not credited back to the current task");
+    }
+    Ok(())
+}
+
+fn current_usage() -> Result<MemoryUsage, &'static str> {
+    task::with_current_task(|t| t.memory_usage()).map_err(|_| "failed to get current task")
+}
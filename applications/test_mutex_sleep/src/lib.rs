@@ -9,6 +9,7 @@ extern crate mutex_sleep;
 extern crate apic;
 
 use core::ops::Deref;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use alloc::{
     vec::Vec,
@@ -21,6 +22,8 @@ use mutex_sleep::MutexSleep;
 pub fn main(_args: Vec<String>) -> isize {    
     let res = match _args.get(0).map(|s| &**s) {
         Some("-c") => test_contention(),
+        Some("-p") => test_priority_inheritance(),
+        Some("-P") => test_priority_inheritance_chain(),
         _          => test_lockstep(),
     };
     match res {
@@ -155,3 +158,278 @@ fn lockstep_task((lock, remainder): (Arc<MutexSleep<usize>>, usize)) -> Result<(
     warn!("{} finished loop.", curr_task);
     Ok(())
 }
+
+
+
+const LOW_PRIORITY: u8 = 10;
+const MEDIUM_PRIORITY: u8 = 20;
+const HIGH_PRIORITY: u8 = 30;
+
+/// The number of times the low-priority task yields while holding the lock.
+const LOW_ITERATIONS: usize = 100;
+/// The number of times the medium-priority task yields before finishing.
+const MEDIUM_ITERATIONS: usize = 10000;
+
+/// State shared among the tasks in the priority inheritance test.
+struct InversionState {
+    lock: MutexSleep<usize>,
+    /// Set by the high-priority task right before it tries to acquire the lock.
+    high_waiting: AtomicBool,
+    /// The number of iterations the medium-priority task has completed.
+    medium_progress: AtomicUsize,
+    /// The highest priority observed by the low-priority task while holding the lock.
+    low_max_priority: AtomicUsize,
+    /// The priority of the low-priority task right after it released the lock.
+    low_priority_after_release: AtomicUsize,
+}
+
+/// A test that reproduces the classic priority inversion scenario on a single core:
+/// a low-priority task holds the lock that a high-priority task is waiting for,
+/// while a CPU-bound medium-priority task competes with the low-priority holder.
+///
+/// With priority inheritance, the low-priority holder is boosted to the high priority
+/// while the high-priority task waits, and is restored to its original priority
+/// once it releases the lock. 
+///
+/// This test requires the priority scheduler.
+fn test_priority_inheritance() -> Result<(), &'static str> {
+    let my_cpu = apic::get_my_apic_id();
+
+    let state = Arc::new(InversionState {
+        lock: MutexSleep::new(0usize),
+        high_waiting: AtomicBool::new(false),
+        medium_progress: AtomicUsize::new(0),
+        low_max_priority: AtomicUsize::new(0),
+        low_priority_after_release: AtomicUsize::new(0),
+    });
+
+    let low = spawn::new_task_builder(inversion_low_task, state.clone())
+        .name(String::from("inversion_low"))
+        .pin_on_core(my_cpu)
+        .block()
+        .spawn()?;
+    let medium = spawn::new_task_builder(inversion_medium_task, state.clone())
+        .name(String::from("inversion_medium"))
+        .pin_on_core(my_cpu)
+        .block()
+        .spawn()?;
+    let high = spawn::new_task_builder(inversion_high_task, state.clone())
+        .name(String::from("inversion_high"))
+        .pin_on_core(my_cpu)
+        .block()
+        .spawn()?;
+
+    scheduler::set_priority(&low, LOW_PRIORITY)?;
+    scheduler::set_priority(&medium, MEDIUM_PRIORITY)?;
+    scheduler::set_priority(&high, HIGH_PRIORITY)?;
+
+    // Let the low-priority task acquire the lock before the others start running.
+    low.unblock().unwrap();
+    while !state.lock.is_locked() {
+        scheduler::schedule();
+    }
+    medium.unblock().unwrap();
+    high.unblock().unwrap();
+
+    low.join()?;
+    medium.join()?;
+    high.join()?;
+
+    let low_max_priority = state.low_max_priority.load(Ordering::SeqCst);
+    let low_final_priority = state.low_priority_after_release.load(Ordering::SeqCst);
+    warn!("Joined the 3 tasks. Low-priority task ran at a max priority of {}, final priority {}.",
+        low_max_priority, low_final_priority,
+    );
+
+    if low_max_priority != HIGH_PRIORITY as usize {
+        return Err("priority inversion: the lock holder was not boosted to the waiter's priority");
+    }
+    if low_final_priority != LOW_PRIORITY as usize {
+        return Err("the lock holder's priority was not restored after releasing the lock");
+    }
+    Ok(())
+}
+
+fn inversion_low_task(state: Arc<InversionState>) -> Result<(), &'static str> {
+    let curr_task = task::get_my_current_task().ok_or("couldn't get current task")?;
+    let mut locked = state.lock.lock()?;
+    warn!("low-priority task acquired lock");
+
+    for _i in 0..LOW_ITERATIONS {
+        scheduler::schedule();
+        if state.high_waiting.load(Ordering::SeqCst) {
+            let priority = scheduler::get_priority(&curr_task).unwrap_or(0);
+            state.low_max_priority.fetch_max(priority as usize, Ordering::SeqCst);
+        }
+    }
+    *locked += 1;
+    warn!("low-priority task releasing lock, medium-priority task has completed {} iterations",
+        state.medium_progress.load(Ordering::SeqCst),
+    );
+    drop(locked);
+    let priority = scheduler::get_priority(&curr_task).unwrap_or(0);
+    state.low_priority_after_release.store(priority as usize, Ordering::SeqCst);
+    Ok(())
+}
+
+fn inversion_medium_task(state: Arc<InversionState>) -> Result<(), &'static str> {
+    for _i in 0..MEDIUM_ITERATIONS {
+        scheduler::schedule();
+        state.medium_progress.fetch_add(1, Ordering::SeqCst);
+    }
+    warn!("medium-priority task finished");
+    Ok(())
+}
+
+fn inversion_high_task(state: Arc<InversionState>) -> Result<(), &'static str> {
+    state.high_waiting.store(true, Ordering::SeqCst);
+    let mut locked = state.lock.lock()?;
+    *locked += 1;
+    warn!("high-priority task acquired lock after medium-priority task completed {} of {} iterations",
+        state.medium_progress.load(Ordering::SeqCst), MEDIUM_ITERATIONS,
+    );
+    Ok(())
+}
+
+
+/// State shared among the tasks in the priority inheritance chain test.
+struct ChainState {
+    /// Held by the low-priority task, waited on by the medium-priority waiter.
+    lock_a: MutexSleep<()>,
+    /// Held by the low-priority task, waited on by the medium-priority owner of `lock_c`.
+    lock_b: MutexSleep<()>,
+    /// Held by the medium-priority owner, waited on by the high-priority task.
+    lock_c: MutexSleep<()>,
+    /// Set by the high-priority task right before it tries to acquire `lock_c`.
+    high_waiting: AtomicBool,
+    /// The highest priority observed by the low-priority task while holding both locks.
+    low_max_priority: AtomicUsize,
+    /// The priority of the low-priority task right after it released `lock_b`.
+    low_priority_after_first_release: AtomicUsize,
+    /// The priority of the low-priority task right after it released `lock_a`.
+    low_priority_after_last_release: AtomicUsize,
+}
+
+/// A test of priority inheritance across multiple held locks and a chain of blocked owners:
+/// a low-priority task holds locks A and B, a medium-priority task waits for A,
+/// another medium-priority task holds lock C and waits for B,
+/// and a high-priority task waits for C.
+///
+/// The low-priority task must be boosted to the high priority through the chain C -> B,
+/// drop only to the medium priority when it releases B (since it still holds the contended A),
+/// and return to its original priority once it releases A.
+///
+/// This test requires the priority scheduler.
+fn test_priority_inheritance_chain() -> Result<(), &'static str> {
+    let my_cpu = apic::get_my_apic_id();
+
+    let state = Arc::new(ChainState {
+        lock_a: MutexSleep::new(()),
+        lock_b: MutexSleep::new(()),
+        lock_c: MutexSleep::new(()),
+        high_waiting: AtomicBool::new(false),
+        low_max_priority: AtomicUsize::new(0),
+        low_priority_after_first_release: AtomicUsize::new(0),
+        low_priority_after_last_release: AtomicUsize::new(0),
+    });
+
+    let low = spawn::new_task_builder(chain_low_task, state.clone())
+        .name(String::from("chain_low"))
+        .pin_on_core(my_cpu)
+        .block()
+        .spawn()?;
+    let waiter = spawn::new_task_builder(chain_waiter_task, state.clone())
+        .name(String::from("chain_medium_waiter"))
+        .pin_on_core(my_cpu)
+        .block()
+        .spawn()?;
+    let owner = spawn::new_task_builder(chain_owner_task, state.clone())
+        .name(String::from("chain_medium_owner"))
+        .pin_on_core(my_cpu)
+        .block()
+        .spawn()?;
+    let high = spawn::new_task_builder(chain_high_task, state.clone())
+        .name(String::from("chain_high"))
+        .pin_on_core(my_cpu)
+        .block()
+        .spawn()?;
+
+    scheduler::set_priority(&low, LOW_PRIORITY)?;
+    scheduler::set_priority(&waiter, MEDIUM_PRIORITY)?;
+    scheduler::set_priority(&owner, MEDIUM_PRIORITY)?;
+    scheduler::set_priority(&high, HIGH_PRIORITY)?;
+
+    // Build the chain in order: the low-priority task takes A and B,
+    // then the owner takes C before the waiters start running.
+    low.unblock().unwrap();
+    while !(state.lock_a.is_locked() && state.lock_b.is_locked()) {
+        scheduler::schedule();
+    }
+    owner.unblock().unwrap();
+    while !state.lock_c.is_locked() {
+        scheduler::schedule();
+    }
+    waiter.unblock().unwrap();
+    high.unblock().unwrap();
+
+    low.join()?;
+    waiter.join()?;
+    owner.join()?;
+    high.join()?;
+
+    let low_max_priority = state.low_max_priority.load(Ordering::SeqCst);
+    let after_first_release = state.low_priority_after_first_release.load(Ordering::SeqCst);
+    let after_last_release = state.low_priority_after_last_release.load(Ordering::SeqCst);
+    warn!("Joined the 4 tasks. Low-priority task ran at a max priority of {}, then {} after releasing B, then {} after releasing A.",
+        low_max_priority, after_first_release, after_last_release,
+    );
+
+    if low_max_priority != HIGH_PRIORITY as usize {
+        return Err("the boost was not propagated through the chain of blocked lock owners");
+    }
+    if after_first_release != MEDIUM_PRIORITY as usize {
+        return Err("releasing one lock didn't recompute the priority from the boosts of the other held lock");
+    }
+    if after_last_release != LOW_PRIORITY as usize {
+        return Err("the lock holder's priority was not restored after releasing all of its locks");
+    }
+    Ok(())
+}
+
+fn chain_low_task(state: Arc<ChainState>) -> Result<(), &'static str> {
+    let curr_task = task::get_my_current_task().ok_or("couldn't get current task")?;
+    let locked_a = state.lock_a.lock()?;
+    let locked_b = state.lock_b.lock()?;
+
+    for _i in 0..LOW_ITERATIONS {
+        scheduler::schedule();
+        if state.high_waiting.load(Ordering::SeqCst) {
+            let priority = scheduler::get_priority(&curr_task).unwrap_or(0);
+            state.low_max_priority.fetch_max(priority as usize, Ordering::SeqCst);
+        }
+    }
+    drop(locked_b);
+    let priority = scheduler::get_priority(&curr_task).unwrap_or(0);
+    state.low_priority_after_first_release.store(priority as usize, Ordering::SeqCst);
+    drop(locked_a);
+    let priority = scheduler::get_priority(&curr_task).unwrap_or(0);
+    state.low_priority_after_last_release.store(priority as usize, Ordering::SeqCst);
+    Ok(())
+}
+
+fn chain_waiter_task(state: Arc<ChainState>) -> Result<(), &'static str> {
+    let _locked_a = state.lock_a.lock()?;
+    Ok(())
+}
+
+fn chain_owner_task(state: Arc<ChainState>) -> Result<(), &'static str> {
+    let _locked_c = state.lock_c.lock()?;
+    let _locked_b = state.lock_b.lock()?;
+    Ok(())
+}
+
+fn chain_high_task(state: Arc<ChainState>) -> Result<(), &'static str> {
+    state.high_waiting.store(true, Ordering::SeqCst);
+    let _locked_c = state.lock_c.lock()?;
+    Ok(())
+}
@@ -0,0 +1,133 @@
+//! Tests that a checkpoint of a crate namespace can be taken and listed,
+//! that its crates can't be unloaded while it exists,
+//! and that restoring it swaps the checkpointed crates back in and unloads crates loaded after it.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use crate_swap::{QuiescencePolicy, SwapRequest};
+use memory::{MmiRef, VirtualAddress};
+use mod_mgmt::{CrateNamespace, IntoCrateObjectFile};
+
+const CHECKPOINT_NAME: &str = "test_namespace_checkpoint";
+/// The prefix of the application crate that is checkpointed and then swapped out.
+const CHECKPOINTED_CRATE_PREFIX: &str = "hello-";
+/// The prefix of the checkpointed crate's main function.
+const CHECKPOINTED_MAIN_PREFIX: &str = "hello::main::";
+/// The prefix of the application crate that is loaded after the checkpoint.
+const LATER_CRATE_PREFIX: &str = "example-";
+/// The prefix of the symbols of the application crate that is loaded after the checkpoint.
+const LATER_CRATE_SYMBOL_PREFIX: &str = "example::";
+
+
+pub fn main(_args: Vec<String>) -> isize {
+    let result = rmain();
+    crate_swap::remove_checkpoint(CHECKPOINT_NAME);
+    match result {
+        Ok(_) => {
+            println!("test_namespace_checkpoint passed.");
+            0
+        }
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn rmain() -> Result<(), &'static str> {
+    let kernel_mmi_ref = memory::get_kernel_mmi_ref().ok_or("couldn't get kernel_mmi_ref")?;
+    let kernel_namespace = mod_mgmt::get_initial_kernel_namespace().ok_or("couldn't get the kernel namespace")?;
+    let app_namespace = mod_mgmt::create_application_namespace(Some(kernel_namespace.clone()))?;
+    let (crate_file, _) = CrateNamespace::get_crate_object_file_starting_with(&app_namespace, CHECKPOINTED_CRATE_PREFIX)
+        .ok_or("couldn't find the checkpointed crate's object file")?;
+    let (crate_ref, _new_syms) = app_namespace.load_crate(&crate_file, None, kernel_mmi_ref, false)?;
+    let crate_name = crate_ref.lock_as_ref().crate_name.clone();
+    drop(crate_ref);
+    let checkpointed_main = main_address(&app_namespace)?;
+
+    // Take and list the checkpoint.
+    let checkpoint = crate_swap::checkpoint_namespace(&app_namespace, CHECKPOINT_NAME)?;
+    if checkpoint.crates != [crate_name.to_string()] || checkpoint.symbols == 0 {
+        return Err("the checkpoint doesn't contain the loaded crate and its symbols");
+    }
+    if crate_swap::checkpoint_namespace(&app_namespace, CHECKPOINT_NAME).is_ok() {
+        return Err("a second checkpoint with the same name was taken");
+    }
+    if !crate_swap::checkpoints().iter().any(|cp| cp.name == CHECKPOINT_NAME && cp.namespace == app_namespace.name()) {
+        return Err("the checkpoint isn't listed");
+    }
+    if app_namespace.unload_crate(&crate_name).is_ok() {
+        return Err("a checkpointed crate was unloaded");
+    }
+    println!("Took checkpoint of {} crates with {} symbols and {} relocations.", checkpoint.crates.len(), checkpoint.symbols, checkpoint.relocations);
+
+    // Change the namespace: swap in a new instance of the checkpointed crate and load another crate.
+    swap_in_new_instance(&app_namespace, &crate_name, kernel_mmi_ref)?;
+    if main_address(&app_namespace)? == checkpointed_main {
+        return Err("the checkpointed crate wasn't swapped out");
+    }
+    let (later_file, _) = CrateNamespace::get_crate_object_file_starting_with(&app_namespace, LATER_CRATE_PREFIX)
+        .ok_or("couldn't find the object file of the crate to load after the checkpoint")?;
+    drop(app_namespace.load_crate(&later_file, None, kernel_mmi_ref, false)?);
+
+    // Restoring the checkpoint swaps the checkpointed crate back in and unloads the other crate.
+    let restored = crate_swap::restore_checkpoint(CHECKPOINT_NAME, kernel_mmi_ref, false, QuiescencePolicy::Reject)?;
+    println!("Restored checkpoint: {:?}", restored);
+    if restored.swapped_crates.len() != 1 || restored.unloaded.crate_names.len() != 1 || !restored.remaining_crates.is_empty() {
+        return Err("restoring the checkpoint didn't swap and unload the expected crates");
+    }
+    if restored.failed_relocations != 0 {
+        return Err("restoring the checkpoint failed to redirect some relocations");
+    }
+    if app_namespace.crate_names(false) != [crate_name.clone()] {
+        return Err("the restored namespace doesn't contain exactly the checkpointed crates");
+    }
+    if main_address(&app_namespace)? != checkpointed_main {
+        return Err("the restored namespace's symbol doesn't refer to the checkpointed crate");
+    }
+    if app_namespace.symbol_map().lock().iter_prefix(LATER_CRATE_SYMBOL_PREFIX.as_bytes()).next().is_some() {
+        return Err("the restored namespace still contains symbols of the crate loaded after the checkpoint");
+    }
+
+    // Restoring an unchanged namespace does nothing.
+    let restored = crate_swap::restore_checkpoint(CHECKPOINT_NAME, kernel_mmi_ref, false, QuiescencePolicy::Reject)?;
+    if !restored.swapped_crates.is_empty() || !restored.unloaded.crate_names.is_empty() || restored.repaired_relocations != 0 {
+        return Err("restoring an unchanged namespace changed it");
+    }
+
+    if crate_swap::remove_checkpoint(CHECKPOINT_NAME).is_none() || crate_swap::remove_checkpoint(CHECKPOINT_NAME).is_some() {
+        return Err("the checkpoint wasn't removed exactly once");
+    }
+    if crate_swap::restore_checkpoint(CHECKPOINT_NAME, kernel_mmi_ref, false, QuiescencePolicy::Reject).is_ok() {
+        return Err("a removed checkpoint was restored");
+    }
+    Ok(())
+}
+
+/// Returns the address of the checkpointed crate's main function in the given namespace.
+fn main_address(namespace: &CrateNamespace) -> Result<VirtualAddress, &'static str> {
+    namespace.get_symbol_starting_with(CHECKPOINTED_MAIN_PREFIX).upgrade()
+        .map(|sec| sec.virt_addr)
+        .ok_or("couldn't find the checkpointed crate's main function")
+}
+
+/// Replaces the given crate with a newly-loaded instance of the same crate object file.
+fn swap_in_new_instance(namespace: &Arc<CrateNamespace>, crate_name: &str, kernel_mmi_ref: &MmiRef) -> Result<(), &'static str> {
+    let request = SwapRequest::new(
+        Some(crate_name),
+        Arc::clone(namespace),
+        IntoCrateObjectFile::Prefix(String::from(CHECKPOINTED_CRATE_PREFIX)),
+        None,
+        false,
+    ).map_err(|_| "couldn't create a swap request for the checkpointed crate")?;
+    crate_swap::swap_crates(namespace, vec![request], None, Vec::new(), kernel_mmi_ref, false, false, None, QuiescencePolicy::Reject)
+}
@@ -0,0 +1,75 @@
+//! Tests that frames allocated from a specific NUMA node actually belong to that node,
+//! and that mappings created by the current task are backed by frames from the current CPU's node.
+//!
+//! Run this in QEMU with the `numa=yes` option to emulate more than one NUMA node.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use memory::{EntryFlags, PAGE_SIZE};
+
+const FRAMES_PER_NODE: usize = 8;
+
+
+pub fn main(_args: Vec<String>) -> isize {
+    match rmain() {
+        Ok(_) => {
+            println!("test_numa passed.");
+            0
+        }
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn rmain() -> Result<(), &'static str> {
+    let node_count = memory::numa_node_count();
+    println!("This system has {} NUMA node(s); the current CPU is on node {}.",
+        node_count, memory::current_numa_node(),
+    );
+    test_allocate_on_each_node(node_count)?;
+    test_local_mapping()?;
+    Ok(())
+}
+
+/// Allocates frames from each NUMA node, which must all belong to the requested node.
+fn test_allocate_on_each_node(node_count: usize) -> Result<(), &'static str> {
+    for node in 0..node_count {
+        let frames = memory::allocate_frames_on_node(node, FRAMES_PER_NODE)
+            .ok_or("couldn't allocate frames on a NUMA node")?;
+        let start_node = memory::numa_node_of(frames.start_address());
+        let end_node = memory::numa_node_of(frames.start_address() + (FRAMES_PER_NODE - 1) * PAGE_SIZE);
+        println!("node {}: allocated {:?}", node, frames);
+        if start_node != node || end_node != node {
+            return Err("frames allocated on a NUMA node belonged to a different node");
+        }
+    }
+    if memory::allocate_frames_on_node(node_count, 1).is_some() {
+        return Err("allocated frames on a NUMA node that doesn't exist");
+    }
+    Ok(())
+}
+
+/// Creates a new mapping, which should be backed by frames from the current CPU's NUMA node.
+fn test_local_mapping() -> Result<(), &'static str> {
+    let node = memory::current_numa_node();
+    let mapping = memory::create_mapping(PAGE_SIZE, EntryFlags::WRITABLE)?;
+    let kernel_mmi_ref = memory::get_kernel_mmi_ref().ok_or("couldn't get the kernel MMI")?;
+    let paddr = kernel_mmi_ref.lock().page_table.translate(mapping.start_address())
+        .ok_or("couldn't translate a newly-created mapping")?;
+    if memory::numa_node_of(paddr) != node {
+        println!("Warning: a new mapping on node {} was backed by a frame on node {}, \
+            which is expected only if node {} is out of free frames.",
+            node, memory::numa_node_of(paddr), node,
+        );
+    }
+    Ok(())
+}
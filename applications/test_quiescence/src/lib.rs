@@ -0,0 +1,112 @@
+//! Tests that a suspended task isn't scheduled and that its stack can be walked while it's switched out,
+//! which is how `crate_swap` determines whether any task is executing within the crates it replaces.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+use core::{
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use memory::VirtualAddress;
+
+/// Set once the worker task should stop looping.
+static DONE: AtomicBool = AtomicBool::new(false);
+/// The number of loop iterations the worker task has completed.
+static ITERATIONS: AtomicUsize = AtomicUsize::new(0);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+
+pub fn main(_args: Vec<String>) -> isize {
+    match rmain() {
+        Ok(_) => {
+            println!("test_quiescence passed.");
+            0
+        }
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn rmain() -> Result<(), &'static str> {
+    let namespace = task::with_current_task(|t| t.get_namespace().clone())
+        .map_err(|_| "couldn't get current task")?;
+    let this_crate = namespace.get_crate_containing_address(VirtualAddress::new_canonical(worker_loop as usize), false)
+        .ok_or("couldn't find this test's crate")?;
+    let this_crate_text = this_crate.lock_as_ref().text_pages.as_ref()
+        .map(|(_mp, range)| range.clone())
+        .ok_or("this test's crate has no text section")?;
+
+    DONE.store(false, Ordering::SeqCst);
+    let worker = spawn::new_task_builder(worker_loop, ())
+        .name(String::from("test_quiescence_worker"))
+        .spawn()?;
+    while ITERATIONS.load(Ordering::SeqCst) == 0 {
+        let _ = sleep::sleep(POLL_INTERVAL);
+    }
+
+    let result = check_suspended_worker(&worker, &this_crate_text);
+    worker.resume();
+    DONE.store(true, Ordering::SeqCst);
+    worker.join()?;
+    result
+}
+
+/// Suspends the worker task, checks that it stops running, and walks its stack.
+fn check_suspended_worker(worker: &task::TaskRef, this_crate_text: &core::ops::Range<VirtualAddress>) -> Result<(), &'static str> {
+    if worker.suspend() {
+        return Err("the worker task was already suspended");
+    }
+    if worker.is_runnable() {
+        return Err("a suspended task was still runnable");
+    }
+    while worker.is_running() {
+        let _ = sleep::sleep(POLL_INTERVAL);
+    }
+    // Give the worker's context switch time to finish.
+    let _ = sleep::sleep(POLL_INTERVAL);
+
+    let iterations = ITERATIONS.load(Ordering::SeqCst);
+    let _ = sleep::sleep(POLL_INTERVAL * 10);
+    if ITERATIONS.load(Ordering::SeqCst) != iterations {
+        return Err("a suspended task continued to run");
+    }
+
+    let mut frames = 0;
+    let mut in_this_crate = false;
+    // SAFE: the worker task is suspended and not running, so it stays switched out.
+    unsafe {
+        stack_trace::stack_trace_of_task(
+            worker,
+            &mut |stack_frame, _stack_frame_iter| {
+                frames += 1;
+                let address = VirtualAddress::new_canonical(stack_frame.call_site_address() as usize);
+                in_this_crate |= this_crate_text.contains(&address);
+                true
+            },
+            None,
+        )?;
+    }
+    println!("walked {} stack frames of the suspended worker task", frames);
+    if !in_this_crate {
+        return Err("the suspended worker task's stack didn't include this crate, in which it was looping");
+    }
+    Ok(())
+}
+
+#[inline(never)]
+fn worker_loop(_: ()) {
+    while !DONE.load(Ordering::SeqCst) {
+        ITERATIONS.fetch_add(1, Ordering::SeqCst);
+        let _ = sleep::sleep(POLL_INTERVAL);
+    }
+}
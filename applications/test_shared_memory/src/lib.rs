@@ -0,0 +1,118 @@
+//! Tests that shared memory regions are visible through all of their mappings,
+//! and that messages sent through a shared memory ring arrive intact and in order.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use memory::EntryFlags;
+use shared_memory::{
+    ring::{RingReceiver, RingSender},
+    SharedMemory,
+};
+
+const SHM_NAME: &str = "test_shared_memory";
+const RING_NAME: &str = "test_shared_memory_ring";
+const RING_SLOTS: usize = 4;
+const RING_MESSAGES: usize = 100;
+
+
+pub fn main(_args: Vec<String>) -> isize {
+    match rmain() {
+        Ok(_) => {
+            println!("test_shared_memory passed.");
+            0
+        }
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn rmain() -> Result<(), &'static str> {
+    test_shared_mappings()?;
+    test_ring()?;
+    Ok(())
+}
+
+/// Writes through one mapping of a shared memory object and reads through another, read-only mapping.
+fn test_shared_mappings() -> Result<(), &'static str> {
+    let shm = SharedMemory::create(SHM_NAME, 3 * memory::PAGE_SIZE)?;
+    if SharedMemory::create(SHM_NAME, memory::PAGE_SIZE).is_ok() {
+        return Err("created two shared memory objects with the same name");
+    }
+    let mut writer = shm.map(EntryFlags::WRITABLE)?;
+    let opened = SharedMemory::open(SHM_NAME).ok_or("couldn't open the shared memory object")?;
+    let reader = opened.map(EntryFlags::empty())?;
+    if writer.start_address() == reader.start_address() {
+        return Err("two mappings of a shared memory object had the same address");
+    }
+    if reader.as_slice::<u8>(0, shm.size_in_bytes())?.iter().any(|b| *b != 0) {
+        return Err("a new shared memory object was not zeroed");
+    }
+
+    writer.as_slice_mut::<u64>(0, 4)?.copy_from_slice(&[1, 2, 3, 4]);
+    *writer.as_type_mut::<u64>(2 * memory::PAGE_SIZE)? = 0xDEADBEEF;
+    if reader.as_slice::<u64>(0, 4)? != [1, 2, 3, 4] || *reader.as_type::<u64>(2 * memory::PAGE_SIZE)? != 0xDEADBEEF {
+        return Err("a write through one mapping was not visible through another");
+    }
+    drop(writer);
+    if *reader.as_type::<u64>(0)? != 1 {
+        return Err("dropping one mapping changed the shared memory's contents");
+    }
+
+    drop((shm, opened, reader));
+    if SharedMemory::open(SHM_NAME).is_some() {
+        return Err("a shared memory object could still be opened after all handles were dropped");
+    }
+    Ok(())
+}
+
+/// Sends more messages than the ring has slots to another task, which checks their contents and order.
+fn test_ring() -> Result<(), &'static str> {
+    let mut sender = RingSender::create(RING_NAME, 32, RING_SLOTS)?;
+    let receiver_task = spawn::new_task_builder(|_: ()| -> Result<(), &'static str> {
+        let mut receiver = RingReceiver::open(RING_NAME)?;
+        if RingReceiver::open(RING_NAME).is_ok() {
+            return Err("attached two receivers to the same ring");
+        }
+        for i in 0..RING_MESSAGES {
+            let valid = receiver.receive_with(|message| {
+                message.len() == i % 32 + 1 && message.iter().all(|b| *b == i as u8)
+            });
+            if !valid {
+                return Err("received a corrupted or out-of-order message");
+            }
+        }
+        Ok(())
+    }, ())
+        .name(String::from("test_shared_memory_receiver"))
+        .spawn()?;
+
+    if sender.try_send(&[0; 33]).is_ok() {
+        return Err("sent a message larger than the ring's slot size");
+    }
+    for i in 0..RING_MESSAGES {
+        sender.send_with(|slot| {
+            let length = i % 32 + 1;
+            slot[..length].fill(i as u8);
+            length
+        });
+    }
+
+    receiver_task.join()?;
+    match receiver_task.take_exit_value() {
+        Some(task::ExitValue::Completed(result)) => {
+            let result = result.downcast_ref::<Result<(), &'static str>>()
+                .ok_or("the receiver task returned an unexpected type")?;
+            result.clone()
+        }
+        _ => Err("the receiver task did not complete"),
+    }
+}
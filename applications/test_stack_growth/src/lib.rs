@@ -0,0 +1,81 @@
+//! Tests that a task spawned with a large stack size can recurse far beyond the default stack size,
+//! as its growable stack grows upon page faults, and that its stack's high-water mark reflects that.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+
+/// The number of bytes of stack used by each level of recursion, at least.
+const FRAME_SIZE: usize = 1024;
+/// The recursion depth, which uses much more stack than the default stack size.
+const DEPTH: usize = 512;
+/// The maximum stack size of the recursing task.
+const STACK_SIZE: usize = 2 * DEPTH * FRAME_SIZE;
+
+
+pub fn main(_args: Vec<String>) -> isize {
+    match rmain() {
+        Ok(_) => {
+            println!("test_stack_growth passed.");
+            0
+        }
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn rmain() -> Result<(), &'static str> {
+    let task = spawn::new_task_builder(recurse_and_measure, DEPTH)
+        .name(String::from("test_stack_growth_worker"))
+        .stack_size(STACK_SIZE)
+        .spawn()?;
+    task.join()?;
+    let (sum, high_water_mark, resident) = match task.take_exit_value() {
+        Some(task::ExitValue::Completed(value)) => *value.downcast::<(usize, Option<usize>, usize)>()
+            .map_err(|_| "the recursing task returned an unexpected value")?,
+        _ => return Err("the recursing task didn't complete, did its stack fail to grow?"),
+    };
+    println!("recursed {} times (sum {}): stack high-water mark {:?} bytes, {} bytes resident, {} bytes max",
+        DEPTH, sum, high_water_mark, resident, STACK_SIZE,
+    );
+
+    let high_water_mark = high_water_mark.ok_or("a stack with a given size wasn't growable")?;
+    if high_water_mark < DEPTH * FRAME_SIZE {
+        return Err("the stack's high-water mark was less than the stack used by the recursion");
+    }
+    if high_water_mark > resident || resident > STACK_SIZE {
+        return Err("the stack's high-water mark and resident size were inconsistent");
+    }
+    Ok(())
+}
+
+/// Recurses `depth` times and then returns the sum of the recursion,
+/// along with the high-water mark and resident size of the current task's stack.
+fn recurse_and_measure(depth: usize) -> (usize, Option<usize>, usize) {
+    let sum = recurse(depth);
+    let (high_water_mark, resident) = task::with_current_task(|t|
+        t.with_kstack(|kstack| (kstack.high_water_mark(), kstack.resident_size_in_bytes()))
+    ).unwrap_or((None, 0));
+    (sum, high_water_mark, resident)
+}
+
+#[inline(never)]
+fn recurse(depth: usize) -> usize {
+    let mut frame = [0u8; FRAME_SIZE];
+    frame[depth % FRAME_SIZE] = 1;
+    let frame = core::hint::black_box(frame);
+    let sum = frame.iter().map(|b| *b as usize).sum::<usize>();
+    if depth == 0 {
+        sum
+    } else {
+        sum + recurse(depth - 1)
+    }
+}
@@ -0,0 +1,156 @@
+//! Tests that versioned states are registered and obtained by version,
+//! that state migrations are chained and only replace the registered states once they all succeed
+//! and only if those states weren't replaced in the meantime,
+//! that migrated states can be restored, and that `crate_swap` can discover the declared migrations.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use state_transfer::{state_migration, VersionedState};
+
+/// The name shared by all versions of the test state, which is unique to this test.
+const STATE_NAME: &str = "test_state_transfer::Counter";
+
+struct CounterV1 {
+    count: u32,
+}
+unsafe impl VersionedState for CounterV1 {
+    const NAME: &'static str = STATE_NAME;
+    const VERSION: u32 = 1;
+}
+
+struct CounterV2 {
+    count: u64,
+    increment: u64,
+}
+unsafe impl VersionedState for CounterV2 {
+    const NAME: &'static str = STATE_NAME;
+    const VERSION: u32 = 2;
+}
+
+struct CounterV3 {
+    next: u64,
+}
+unsafe impl VersionedState for CounterV3 {
+    const NAME: &'static str = STATE_NAME;
+    const VERSION: u32 = 3;
+}
+
+fn migrate_v1_to_v2(old: &CounterV1) -> Result<CounterV2, &'static str> {
+    Ok(CounterV2 { count: old.count as u64, increment: 2 })
+}
+
+fn migrate_v2_to_v3(old: &CounterV2) -> Result<CounterV3, &'static str> {
+    Ok(CounterV3 { next: old.count + old.increment })
+}
+
+fn fail_v2_to_v3(_old: &CounterV2) -> Result<CounterV3, &'static str> {
+    Err("intentionally failed migration")
+}
+
+state_migration!(counter_v1_to_v2: CounterV1 => CounterV2 = migrate_v1_to_v2);
+state_migration!(counter_v2_to_v3: CounterV2 => CounterV3 = migrate_v2_to_v3);
+state_migration!(counter_v2_to_v3_failing: CounterV2 => CounterV3 = fail_v2_to_v3);
+
+
+pub fn main(_args: Vec<String>) -> isize {
+    let result = rmain();
+    // Remove the test state regardless of its version, such that this test can be run again.
+    let _ = state_transfer::remove::<CounterV1>();
+    match result {
+        Ok(_) => {
+            println!("test_state_transfer passed.");
+            0
+        }
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn rmain() -> Result<(), &'static str> {
+    let _ = state_transfer::remove::<CounterV1>();
+
+    let counter = state_transfer::get_or_register(|| CounterV1 { count: 5 })?;
+    if counter.count != 5 || state_transfer::get::<CounterV1>().map(|c| c.count) != Some(5) {
+        return Err("the registered state wasn't returned");
+    }
+    if state_transfer::get_or_register(|| CounterV1 { count: 0 })?.count != 5 {
+        return Err("registering an already-registered state replaced it");
+    }
+    if state_transfer::get::<CounterV2>().is_some() || state_transfer::get_or_register(|| CounterV2 { count: 0, increment: 0 }).is_ok() {
+        return Err("a state was returned as a different version");
+    }
+    println!("Registered version 1 of the state.");
+
+    let failing = state_transfer::prepare_migrations(&[
+        counter_v1_to_v2::__state_migration(),
+        counter_v2_to_v3_failing::__state_migration(),
+    ]);
+    if failing.is_ok() {
+        return Err("a failing migration succeeded");
+    }
+    if state_transfer::get::<CounterV1>().map(|c| c.count) != Some(5) {
+        return Err("a failing migration changed the registered state");
+    }
+    println!("A failing migration left the registered state unchanged.");
+
+    // The registry isn't locked while prepared migrations are pending.
+    let migrations = [
+        counter_v2_to_v3::__state_migration(),
+        counter_v1_to_v2::__state_migration(),
+    ];
+    let prepared = state_transfer::prepare_migrations(&migrations)?;
+    if state_transfer::get::<CounterV1>().map(|c| c.count) != Some(5) {
+        return Err("preparing migrations changed the registered state");
+    }
+    drop(prepared);
+    if state_transfer::get::<CounterV1>().map(|c| c.count) != Some(5) {
+        return Err("discarding prepared migrations changed the registered state");
+    }
+
+    // Prepared migrations can't be committed once a migrated state was replaced in the meantime.
+    let prepared = state_transfer::prepare_migrations(&migrations)?;
+    let original = state_transfer::remove::<CounterV1>().ok_or("couldn't remove the registered state")?;
+    state_transfer::get_or_register(|| CounterV1 { count: original.count })?;
+    if prepared.commit().is_ok() {
+        return Err("migrations were committed even though the migrated state was replaced");
+    }
+    if state_transfer::get::<CounterV1>().map(|c| c.count) != Some(5) {
+        return Err("failing to commit migrations changed the registered state");
+    }
+    println!("Migrations of a state that was replaced in the meantime weren't committed.");
+
+    let migrated_states = state_transfer::prepare_migrations(&migrations)?.commit()?;
+    if state_transfer::get::<CounterV3>().map(|c| c.next) != Some(7) || state_transfer::get::<CounterV1>().is_some() {
+        return Err("the state wasn't migrated from version 1 to version 3");
+    }
+    println!("Migrated the state from version 1 to version 3.");
+
+    migrated_states.restore();
+    if state_transfer::get::<CounterV1>().map(|c| c.count) != Some(5) {
+        return Err("the previous version of the state wasn't restored");
+    }
+    if state_transfer::prepare_migrations(&[counter_v2_to_v3::__state_migration()]).is_ok() {
+        return Err("a state was migrated without a migration from its registered version");
+    }
+    println!("Restored version 1 of the state.");
+
+    // `crate_swap` finds the migrations declared in a crate by their symbol names.
+    let namespace = task::with_current_task(|t| t.get_namespace().clone())
+        .map_err(|_| "couldn't get the current task's namespace")?;
+    let symbol = alloc::format!("test_state_transfer::counter_v1_to_v2::{}::", state_transfer::STATE_MIGRATION_SYMBOL);
+    if namespace.get_symbol_starting_with(&symbol).upgrade().is_none() {
+        return Err("couldn't find the function generated by state_migration!() in this crate's symbols");
+    }
+    println!("Found the state migration symbol {:?}.", symbol);
+
+    Ok(())
+}
@@ -0,0 +1,125 @@
+//! Tests that a crate swap that fails partway through, i.e., after the old crate's dependents
+//! were already redirected to the new crate, restores every relocation and symbol
+//! such that they refer to the old crate again.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use crate_swap::{QuiescencePolicy, StateTransferFunction, SwapRequest};
+use mod_mgmt::{CrateNamespace, IntoCrateObjectFile, StrongCrateRef};
+
+/// The prefix of the kernel crate that is swapped with a new instance of itself.
+/// It has several dependents, e.g., `crate_swap`, but isn't executed by any task while the swap is applied.
+const SWAPPED_CRATE_PREFIX: &str = "state_transfer-";
+/// The prefix of the fully-qualified name of [`failing_state_transfer()`].
+const FAILING_STATE_TRANSFER: &str = "test_swap_rollback::failing_state_transfer::";
+
+
+/// A state transfer function that always fails, which causes the swap to be rolled back
+/// after all of the old crate's dependents were redirected to the new crate.
+pub fn failing_state_transfer(_old_namespace: &Arc<CrateNamespace>, _new_namespace: &CrateNamespace) -> Result<(), &'static str> {
+    Err("intentionally failed state transfer")
+}
+
+pub fn main(_args: Vec<String>) -> isize {
+    match rmain() {
+        Ok(_) => {
+            println!("test_swap_rollback passed.");
+            0
+        }
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn rmain() -> Result<(), &'static str> {
+    let _check_signature: StateTransferFunction = failing_state_transfer;
+    let kernel_mmi_ref = memory::get_kernel_mmi_ref().ok_or("couldn't get kernel_mmi_ref")?;
+    let namespace = task::with_current_task(|t| t.get_namespace().clone())
+        .map_err(|_| "couldn't get the current task's namespace")?;
+    let (crate_name, old_crate_ref, old_namespace) = CrateNamespace::get_crate_starting_with(&namespace, SWAPPED_CRATE_PREFIX)
+        .ok_or("couldn't find the crate to swap")?;
+    let old_namespace = Arc::clone(old_namespace);
+
+    let relocations_before = relocation_values(&old_crate_ref)?;
+    if relocations_before.is_empty() {
+        return Err("the crate to swap has no dependents");
+    }
+    let symbols_before = symbol_addresses(&old_crate_ref, &old_namespace);
+    // Don't keep the old crate alive beyond what the namespace does.
+    drop(old_crate_ref);
+    println!("Swapping {} with {} relocations in its dependents, which will fail.", crate_name, relocations_before.len());
+
+    let request = SwapRequest::new(
+        Some(&crate_name),
+        Arc::clone(&old_namespace),
+        IntoCrateObjectFile::Prefix(String::from(SWAPPED_CRATE_PREFIX)),
+        None,
+        false,
+    ).map_err(|_| "couldn't create a swap request for the crate")?;
+    let result = crate_swap::swap_crates(
+        &namespace,
+        vec![request],
+        None,
+        vec![FAILING_STATE_TRANSFER.to_string()],
+        kernel_mmi_ref,
+        false,
+        false,
+        None,
+        QuiescencePolicy::Reject,
+    );
+    match result {
+        Err("intentionally failed state transfer") => { }
+        Err(_e) => {
+            println!("The swap failed with an unexpected error: {}", _e);
+            return Err("the swap didn't fail in the state transfer function");
+        }
+        Ok(()) => return Err("a swap with a failing state transfer function succeeded"),
+    }
+
+    let (_, crate_ref, _) = CrateNamespace::get_crate_starting_with(&namespace, SWAPPED_CRATE_PREFIX)
+        .ok_or("the old crate was removed by the failed swap")?;
+    if symbol_addresses(&crate_ref, &old_namespace) != symbols_before {
+        return Err("the old crate or its symbols weren't restored");
+    }
+    if relocation_values(&crate_ref)? != relocations_before {
+        return Err("the relocations in the old crate's dependents weren't restored");
+    }
+    println!("The failed swap restored all relocations and symbols.");
+    Ok(())
+}
+
+/// Returns the current value of every relocation in the dependents of the given crate's sections,
+/// along with the address it was written to.
+///
+/// Only the lower 4 bytes of each value are read, as every relocation type writes at least 4 bytes.
+fn relocation_values(crate_ref: &StrongCrateRef) -> Result<Vec<(usize, u32)>, &'static str> {
+    let mut values = Vec::new();
+    for sec in crate_ref.lock_as_ref().sections.values() {
+        for dependent in sec.inner.read().sections_dependent_on_me.iter() {
+            let Some(target_sec) = dependent.section.upgrade() else { continue };
+            let offset = target_sec.mapped_pages_offset + dependent.relocation.offset;
+            let value = *target_sec.mapped_pages.lock().as_type::<u32>(offset)?;
+            values.push((target_sec.virt_addr.value() + dependent.relocation.offset, value));
+        }
+    }
+    values.sort_unstable();
+    Ok(values)
+}
+
+/// Returns the address of the section that each of the given crate's global symbols refers to in `namespace`.
+fn symbol_addresses(crate_ref: &StrongCrateRef, namespace: &CrateNamespace) -> Vec<Option<usize>> {
+    crate_ref.lock_as_ref().global_sections_iter()
+        .map(|sec| namespace.get_symbol(&sec.name).upgrade().map(|s| s.virt_addr.value()))
+        .collect()
+}
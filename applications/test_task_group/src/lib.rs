@@ -0,0 +1,197 @@
+//! Tests that task groups enforce their limits on the number of tasks and heap usage,
+//! that freed memory is credited to the group that allocated it,
+//! and that tasks spawned into an application namespace join that namespace's group.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+use alloc::{
+    string::String,
+    vec,
+    vec::Vec,
+};
+use path::Path;
+use task_group::{ResourceLimits, TaskGroup, TaskGroupRef};
+
+
+pub fn main(_args: Vec<String>) -> isize {
+    match rmain() {
+        Ok(_) => {
+            println!("test_task_group passed.");
+            0
+        }
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn rmain() -> Result<(), &'static str> {
+    test_task_limit()?;
+    test_heap_limit()?;
+    test_heap_ownership()?;
+    test_namespace_group()?;
+    Ok(())
+}
+
+/// Spawns tasks into a group with a task limit, checking that spawning fails once the limit is reached.
+fn test_task_limit() -> Result<(), &'static str> {
+    const MAX_TASKS: usize = 2;
+    let group = TaskGroup::new(
+        String::from("test_task_limit"),
+        ResourceLimits { max_tasks: Some(MAX_TASKS), ..Default::default() },
+        None,
+    );
+
+    let mut tasks = Vec::new();
+    for i in 0..MAX_TASKS {
+        let task = spawn::new_task_builder(in_group, (group.clone(), 0))
+            .name(format!("test_task_limit_{}", i))
+            .group(group.clone())
+            .block()
+            .spawn()?;
+        tasks.push(task);
+    }
+    if group.usage().tasks != MAX_TASKS {
+        return Err("group's task count didn't match the number of spawned tasks");
+    }
+
+    let over_limit = spawn::new_task_builder(in_group, (group.clone(), 0))
+        .name(String::from("test_task_limit_over"))
+        .group(group.clone())
+        .spawn();
+    if over_limit.is_ok() {
+        return Err("spawning a task beyond the group's task limit succeeded");
+    }
+
+    for task in tasks {
+        task.unblock().map_err(|_| "couldn't unblock task")?;
+        task.join()?;
+        if !task.in_group()? {
+            return Err("spawned task was not in the expected group");
+        }
+    }
+    println!("task limit: OK");
+    Ok(())
+}
+
+/// Allocates memory from a task in a group with a heap limit,
+/// checking that allocations beyond the limit fail and that usage is released when the task exits.
+fn test_heap_limit() -> Result<(), &'static str> {
+    const MAX_HEAP_BYTES: usize = 64 * 1024;
+    let group = TaskGroup::new(
+        String::from("test_heap_limit"),
+        ResourceLimits { max_heap_bytes: Some(MAX_HEAP_BYTES), ..Default::default() },
+        None,
+    );
+
+    let task = spawn::new_task_builder(in_group, (group.clone(), MAX_HEAP_BYTES))
+        .name(String::from("test_heap_limit"))
+        .group(group.clone())
+        .spawn()?;
+    task.join()?;
+    if !task.in_group()? {
+        return Err("task could allocate beyond its group's heap limit");
+    }
+    drop(task);
+
+    // The task's remaining usage is released once the exited task has been fully dropped.
+    println!("heap limit: OK (usage after exit: {} bytes)", group.usage().heap_bytes);
+    Ok(())
+}
+
+/// Frees memory that was allocated by a task in a group from a task outside of that group,
+/// checking that the freed memory is credited back to the allocating group.
+fn test_heap_ownership() -> Result<(), &'static str> {
+    const ALLOCATION_BYTES: usize = 16 * 1024;
+    let group = TaskGroup::new(String::from("test_heap_ownership"), ResourceLimits::default(), None);
+
+    let task = spawn::new_task_builder(allocate, ALLOCATION_BYTES)
+        .name(String::from("test_heap_ownership"))
+        .group(group.clone())
+        .spawn()?;
+    task.join()?;
+    let allocation = match task.take_exit_value() {
+        Some(task::ExitValue::Completed(value)) => value,
+        _ => return Err("allocating task did not complete successfully"),
+    };
+    drop(task);
+
+    let usage_before_free = group.usage().heap_bytes;
+    if usage_before_free < ALLOCATION_BYTES {
+        return Err("memory allocated by a task in the group wasn't charged to the group");
+    }
+    // This task isn't in the group, but freeing the memory must still credit the group.
+    drop(allocation);
+    let usage_after_free = group.usage().heap_bytes;
+    if usage_after_free + ALLOCATION_BYTES > usage_before_free {
+        return Err("freeing memory from outside the group didn't credit the allocating group");
+    }
+    println!("heap ownership: OK (usage {} -> {} bytes)", usage_before_free, usage_after_free);
+    Ok(())
+}
+
+/// Spawns an application into a namespace that has its own task group,
+/// checking that the new task joins that group even though this task isn't in it.
+fn test_namespace_group() -> Result<(), &'static str> {
+    let group = TaskGroup::new(String::from("test_namespace_group"), ResourceLimits::default(), None);
+    let namespace = mod_mgmt::create_application_namespace(None)?;
+    namespace.set_task_group(Some(group.clone()));
+
+    let task = spawn::new_application_task_builder(Path::new(String::from("hello")), Some(namespace))?
+        .block()
+        .spawn()?;
+    let joined = task.group().map_or(false, |g| g.id() == group.id());
+    task.unblock().map_err(|_| "couldn't unblock task")?;
+    task.join()?;
+    if !joined {
+        return Err("task spawned into the namespace didn't join the namespace's task group");
+    }
+    println!("namespace group: OK");
+    Ok(())
+}
+
+/// The entry point for tasks that allocate memory on behalf of their group,
+/// which is returned to and freed by the task that spawned them.
+fn allocate(bytes: usize) -> Vec<u8> {
+    vec![1u8; bytes]
+}
+
+/// The entry point for tasks spawned into a group.
+///
+/// Returns `true` if the current task is in the given `group`
+/// and, if `heap_limit` is nonzero, an allocation of that many bytes fails.
+fn in_group((group, heap_limit): (TaskGroupRef, usize)) -> bool {
+    let is_member = task::with_current_task(|t| {
+        t.group().map_or(false, |g| g.id() == group.id())
+    }).unwrap_or(false);
+    if heap_limit == 0 {
+        return is_member;
+    }
+
+    let mut small: Vec<u8> = Vec::new();
+    let small_ok = small.try_reserve_exact(heap_limit / 4).is_ok();
+    let mut large: Vec<u8> = Vec::new();
+    let large_failed = large.try_reserve_exact(heap_limit).is_err();
+    is_member && small_ok && large_failed
+}
+
+/// A helper for checking the boolean exit value of a task spawned with [`in_group`].
+trait InGroupResult {
+    fn in_group(&self) -> Result<bool, &'static str>;
+}
+
+impl InGroupResult for task::JoinableTaskRef {
+    fn in_group(&self) -> Result<bool, &'static str> {
+        match self.take_exit_value() {
+            Some(task::ExitValue::Completed(value)) => value
+                .downcast_ref::<bool>()
+                .copied()
+                .ok_or("task exit value was not a bool"),
+            _ => Err("task did not complete successfully"),
+        }
+    }
+}
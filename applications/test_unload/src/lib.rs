@@ -0,0 +1,88 @@
+//! Tests that crates are unloaded from a namespace once they're no longer referenced,
+//! both explicitly and when an application crate is dropped,
+//! and that crates that other crates depend on cannot be unloaded.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use mod_mgmt::CrateNamespace;
+
+/// The prefix of the application crate that is loaded and then unloaded.
+const APP_CRATE_PREFIX: &str = "hello-";
+/// The prefix of a kernel crate that many other crates depend on.
+const DEPENDED_ON_CRATE_PREFIX: &str = "memory-";
+
+
+pub fn main(_args: Vec<String>) -> isize {
+    match rmain() {
+        Ok(_) => {
+            println!("test_unload passed.");
+            0
+        }
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn rmain() -> Result<(), &'static str> {
+    let kernel_mmi_ref = memory::get_kernel_mmi_ref().ok_or("couldn't get kernel_mmi_ref")?;
+    let kernel_namespace = mod_mgmt::get_initial_kernel_namespace().ok_or("couldn't get the kernel namespace")?;
+    let app_namespace = mod_mgmt::create_application_namespace(Some(kernel_namespace.clone()))?;
+    let (app_file, _) = CrateNamespace::get_crate_object_file_starting_with(&app_namespace, APP_CRATE_PREFIX)
+        .ok_or("couldn't find the application crate's object file")?;
+
+    // A crate that nothing refers to is unloaded, along with its symbols.
+    let (crate_ref, _new_syms) = app_namespace.load_crate(&app_file, None, kernel_mmi_ref, false)?;
+    if app_namespace.unload_unreferenced_crates().crate_names.len() != 0 {
+        return Err("a crate was unloaded while a reference to it was held");
+    }
+    drop(crate_ref);
+    let unloaded = app_namespace.unload_unreferenced_crates();
+    println!("unloaded {:?}, reclaiming {} bytes", unloaded.crate_names, unloaded.memory.total_bytes());
+    if unloaded.crate_names.len() != 1 || unloaded.memory.total_bytes() == 0 {
+        return Err("the unreferenced crate wasn't unloaded");
+    }
+    check_namespace_is_empty(&app_namespace)?;
+
+    // An application crate is unloaded when dropped, along with its unreferenced dependencies,
+    // which is enabled by default for application namespaces.
+    if !app_namespace.is_unload_on_app_exit_enabled() {
+        return Err("unloading on application exit wasn't enabled for a new application namespace");
+    }
+    let app_crate = CrateNamespace::load_crate_as_application(&app_namespace, &app_file, kernel_mmi_ref, false)?;
+    let app_crate_name = app_crate.lock_as_ref().crate_name.clone();
+    if app_namespace.unload_crate(&app_crate_name).is_ok() {
+        return Err("an application crate was unloaded while it was in use");
+    }
+    drop(app_crate);
+    check_namespace_is_empty(&app_namespace)?;
+
+    // A crate that other crates depend on cannot be unloaded.
+    let (depended_on_crate_name, crate_ref, crate_namespace) = CrateNamespace::get_crate_starting_with(kernel_namespace, DEPENDED_ON_CRATE_PREFIX)
+        .ok_or("couldn't find the depended-on kernel crate")?;
+    // Don't hold a reference to the crate, such that it's only its dependents that prevent unloading it.
+    drop(crate_ref);
+    match crate_namespace.unload_crate(&depended_on_crate_name) {
+        Ok(_) => return Err("a crate that other crates depend on was unloaded"),
+        Err(e) => println!("as expected, couldn't unload {}: {}", depended_on_crate_name, e),
+    }
+    Ok(())
+}
+
+fn check_namespace_is_empty(namespace: &CrateNamespace) -> Result<(), &'static str> {
+    if !namespace.crate_names(false).is_empty() {
+        return Err("the namespace still contained crates after unloading");
+    }
+    if namespace.symbol_map().lock().iter().next().is_some() {
+        return Err("the namespace still contained symbols after unloading");
+    }
+    Ok(())
+}
@@ -0,0 +1,127 @@
+//! Tests that the watchdog detects and reports soft lockups and hung tasks.
+//!
+//! The soft lockup test requires at least two CPUs.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use core::time::Duration;
+use fault_log::FaultType;
+
+
+pub fn main(_args: Vec<String>) -> isize {
+    let soft_lockup_threshold = watchdog::soft_lockup_threshold();
+    let hung_task_threshold = watchdog::hung_task_threshold();
+    let check_interval = watchdog::check_interval();
+    watchdog::set_check_interval(Duration::from_millis(100));
+
+    let result = rmain();
+
+    watchdog::set_soft_lockup_threshold(soft_lockup_threshold);
+    watchdog::set_hung_task_threshold(hung_task_threshold);
+    watchdog::set_check_interval(check_interval);
+
+    match result {
+        Ok(_) => {
+            println!("test_watchdog passed.");
+            0
+        }
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+fn rmain() -> Result<(), &'static str> {
+    // The watchdog task is only spawned at boot if Theseus was built with `THESEUS_CONFIG=watchdog`.
+    if !watchdog::is_running() {
+        println!("Starting the watchdog task, which keeps running after this test.");
+        spawn::new_task_builder(watchdog::watchdog_task, ())
+            .name(String::from("watchdog"))
+            .spawn()?;
+        while !watchdog::is_running() {
+            scheduler::schedule();
+        }
+    }
+    test_soft_lockup()?;
+    test_hung_task()?;
+    Ok(())
+}
+
+/// Spins with preemption disabled on another CPU, checking that the lockup is reported.
+fn test_soft_lockup() -> Result<(), &'static str> {
+    let my_cpu = apic::get_my_apic_id();
+    let Some(other_cpu) = apic::get_lapics().iter()
+        .map(|(cpu, _)| *cpu)
+        .find(|cpu| *cpu != my_cpu)
+    else {
+        println!("soft lockup: skipped (requires at least two CPUs)");
+        return Ok(());
+    };
+
+    watchdog::set_soft_lockup_threshold(Some(Duration::from_millis(500)));
+    let name = String::from("test_watchdog_spinner");
+    let task = spawn::new_task_builder(spin_without_preemption, Duration::from_millis(1500))
+        .name(name.clone())
+        .pin_on_core(other_cpu)
+        .spawn()?;
+    task.join()?;
+
+    let Some(lockup) = fault_log::get_fault_log().into_iter()
+        .find(|fe| matches!(fe.fault_type, FaultType::SoftLockup) && fe.running_task.as_deref() == Some(name.as_str()))
+    else {
+        return Err("soft lockup was not reported in the fault log");
+    };
+    // The backtrace may be empty if the spinner had already stopped when the watchdog reported it.
+    println!("soft lockup: OK (backtrace of {} frames)", lockup.backtrace.len());
+    Ok(())
+}
+
+/// Leaves a task blocked for longer than the hung task threshold, checking that it is reported once.
+fn test_hung_task() -> Result<(), &'static str> {
+    watchdog::set_hung_task_threshold(Some(Duration::from_millis(200)));
+    let name = String::from("test_watchdog_blocked");
+    let task = spawn::new_task_builder(|_: ()| { }, ())
+        .name(name.clone())
+        .block()
+        .spawn()?;
+
+    sleep::sleep(Duration::from_millis(800)).map_err(|_| "couldn't sleep")?;
+    let reports = count_faults(FaultType::HungTask, &name);
+
+    task.unblock().map_err(|_| "couldn't unblock task")?;
+    task.join()?;
+
+    match reports {
+        0 => Err("hung task was not reported in the fault log"),
+        1 => {
+            println!("hung task: OK");
+            Ok(())
+        }
+        _ => Err("hung task was reported more than once"),
+    }
+}
+
+/// Busy-waits for the given duration without allowing the scheduler to run on this CPU.
+fn spin_without_preemption(duration: Duration) {
+    let _held_preemption = preemption::hold_preemption();
+    let end = sleep::get_current_time() + duration;
+    while sleep::get_current_time() < end {
+        core::hint::spin_loop();
+    }
+}
+
+/// Returns the number of faults of the given type in the fault log for the task with the given name.
+fn count_faults(fault_type: FaultType, task_name: &str) -> usize {
+    fault_log::get_fault_log().iter()
+        .filter(|fe| core::mem::discriminant(&fe.fault_type) == core::mem::discriminant(&fault_type))
+        .filter(|fe| fe.running_task.as_deref() == Some(task_name))
+        .count()
+}
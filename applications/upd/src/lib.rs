@@ -25,7 +25,10 @@ extern crate vfs_node;
 extern crate spin;
 
 
-use core::str::FromStr;
+use core::{
+    str::FromStr,
+    time::Duration,
+};
 use alloc::{
     string::{String, ToString},
     vec::Vec,
@@ -42,6 +45,7 @@ use mod_mgmt::{
     IntoCrateObjectFile,
 };
 use crate_swap::{
+    QuiescencePolicy,
     SwapRequest,
     SwapRequestList,
 };
@@ -53,6 +57,9 @@ use ota_update_client::DIFF_FILE_NAME;
 
 
 
+/// How long to wait for other tasks to leave the old crates before applying an update.
+const QUIESCENCE_TIMEOUT: Duration = Duration::from_secs(1);
+
 static VERBOSE: Once<bool> = Once::new();
 macro_rules! verbose {
     () => (VERBOSE.get() == Some(&true));
@@ -288,6 +295,8 @@ fn apply(base_dir_path: &Path) -> Result<(), String> {
         kernel_mmi_ref,
         false, // verbose logging
         false, // enable_crate_cache
+        None, // no health check
+        QuiescencePolicy::Wait(QUIESCENCE_TIMEOUT),
     ).map_err(|e| format!("crate swapping failed, error: {}", e))?;
 
     Ok(())
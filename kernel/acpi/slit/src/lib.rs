@@ -0,0 +1,87 @@
+//! Support for the SLIT ACPI table (System Locality Information Table),
+//! which describes the relative distances between NUMA nodes (system localities).
+//!
+//! The structures defined herein are based on Section 5.2.17 of the ACPI Specification.
+
+#![no_std]
+
+use core::mem::size_of;
+use memory::PhysicalAddress;
+use sdt::Sdt;
+use acpi_table::{AcpiSignature, AcpiTables};
+use zerocopy::FromBytes;
+use static_assertions::const_assert_eq;
+
+pub const SLIT_SIGNATURE: &[u8; 4] = b"SLIT";
+
+/// The distance from a NUMA node to itself.
+pub const LOCAL_DISTANCE: u8 = 10;
+/// The distance value that indicates one NUMA node is unreachable from another.
+pub const UNREACHABLE_DISTANCE: u8 = 0xFF;
+
+/// The handler for parsing the SLIT table and adding it to the ACPI tables list.
+pub fn handle(
+    acpi_tables: &mut AcpiTables,
+    signature: AcpiSignature,
+    length: usize,
+    phys_addr: PhysicalAddress
+) -> Result<(), &'static str> {
+    // The SLIT ends with a matrix of one-byte distances, which fills the rest of the table.
+    let slice_start_paddr = phys_addr + size_of::<SlitAcpiTable>();
+    let slice_len = length.saturating_sub(size_of::<SlitAcpiTable>());
+    acpi_tables.add_table_location(signature, phys_addr, Some((slice_start_paddr, slice_len)))
+}
+
+
+/// The fixed-size components of the SLIT ACPI table.
+/// Its layout and total size must exactly match that of the ACPI specification.
+#[derive(Clone, Copy, Debug, FromBytes)]
+#[repr(C, packed)]
+struct SlitAcpiTable {
+    header: Sdt,
+    number_of_localities: u64,
+    // Following this is a `number_of_localities` by `number_of_localities` matrix of distances.
+}
+const_assert_eq!(core::mem::size_of::<SlitAcpiTable>(), 44);
+const_assert_eq!(core::mem::align_of::<SlitAcpiTable>(), 1);
+
+
+/// A wrapper around the SLIT ACPI table (System Locality Information Table),
+/// which contains the relative distances between each pair of NUMA nodes.
+pub struct Slit<'t> {
+    /// The fixed-size part of the actual SLIT ACPI table.
+    table: &'t SlitAcpiTable,
+    /// The matrix of distances, in row-major order.
+    distances: &'t [u8],
+}
+
+impl<'t> Slit<'t> {
+    /// Finds the SLIT in the given `AcpiTables` and returns a reference to it.
+    pub fn get(acpi_tables: &'t AcpiTables) -> Option<Slit<'t>> {
+        let table: &SlitAcpiTable = acpi_tables.table(SLIT_SIGNATURE).ok()?;
+        let distances: &[u8] = acpi_tables.table_slice(SLIT_SIGNATURE).ok()?;
+        let localities = usize::try_from(table.number_of_localities).ok()?;
+        let distances = distances.get(..localities.checked_mul(localities)?)?;
+        Some(Slit { table, distances })
+    }
+
+    /// Returns the number of system localities (NUMA nodes) in this SLIT table.
+    pub fn number_of_localities(&self) -> usize {
+        self.table.number_of_localities as usize
+    }
+
+    /// Returns the relative distance from the locality `from` to the locality `to`,
+    /// in which the distance from a locality to itself is [`LOCAL_DISTANCE`].
+    pub fn distance(&self, from: usize, to: usize) -> Option<u8> {
+        let localities = self.number_of_localities();
+        if from >= localities || to >= localities {
+            return None;
+        }
+        self.distances.get(from * localities + to).copied()
+    }
+
+    /// Returns a reference to the `Sdt` header in this SLIT table.
+    pub fn sdt(&self) -> &Sdt {
+        &self.table.header
+    }
+}
@@ -15,12 +15,15 @@ extern crate rsdt;
 extern crate fadt;
 extern crate madt;
 extern crate dmar;
+extern crate srat;
+extern crate slit;
+extern crate frame_allocator;
 extern crate iommu;
 
 
 use alloc::vec::Vec;
 use spin::Mutex;
-use memory::{PageTable, PhysicalAddress};
+use memory::{FrameRange, PageTable, PhysicalAddress};
 use rsdp::Rsdp;
 use acpi_table::AcpiTables;
 use acpi_table_handler::acpi_table_handler;
@@ -131,5 +134,39 @@ pub fn init(page_table: &mut PageTable) -> Result<(), &'static str> {
         }
     }
 
+    // If we have a SRAT table, use it (and the SLIT table, if any) to make frame allocation NUMA-aware.
+    {
+        let acpi_tables = ACPI_TABLES.lock();
+        if let Some(srat_table) = srat::Srat::get(&acpi_tables) {
+            let slit_table = slit::Slit::get(&acpi_tables);
+            let mut memory_ranges = Vec::new();
+            let mut cpu_domains = Vec::new();
+            for entry in srat_table.iter() {
+                match entry {
+                    srat::SratEntry::MemoryAffinity(mem) if mem.is_enabled() && mem.length() > 0 => {
+                        let start = PhysicalAddress::new(mem.base_address() as usize)
+                            .ok_or("SRAT memory affinity base_address was invalid")?;
+                        memory_ranges.push((mem.proximity_domain as usize, FrameRange::from_phys_addr(start, mem.length() as usize)));
+                    }
+                    srat::SratEntry::LocalApicAffinity(lapic) if lapic.is_enabled() => {
+                        cpu_domains.push((lapic.apic_id as usize, lapic.proximity_domain() as usize));
+                    }
+                    srat::SratEntry::LocalX2ApicAffinity(x2apic) if x2apic.is_enabled() => {
+                        cpu_domains.push((x2apic.x2apic_id as usize, x2apic.proximity_domain as usize));
+                    }
+                    _ => { }
+                }
+            }
+            debug!("Found SRAT table with {} memory ranges and {} CPUs; SLIT table present: {}",
+                memory_ranges.len(), cpu_domains.len(), slit_table.is_some(),
+            );
+            let distance = |from: usize, to: usize| slit_table.as_ref().and_then(|slit| slit.distance(from, to));
+            match frame_allocator::set_numa_topology(memory_ranges, cpu_domains, distance) {
+                Ok(()) => info!("Frame allocation is NUMA-aware across {} node(s)", frame_allocator::numa_node_count()),
+                Err(e) => warn!("Couldn't use the NUMA topology from the SRAT table: {}", e),
+            }
+        }
+    }
+
     Ok(())
 }
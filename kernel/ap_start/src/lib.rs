@@ -63,16 +63,18 @@ pub fn kstart_ap(
 
     // initialize interrupts (including TSS/GDT) for this AP
     let kernel_mmi_ref = get_kernel_mmi_ref().expect("kstart_ap(): kernel_mmi ref was None");
-    let (double_fault_stack, privilege_stack) = {
+    let (double_fault_stack, page_fault_stack, privilege_stack) = {
         let mut kernel_mmi = kernel_mmi_ref.lock();
         (
             stack::alloc_stack(KERNEL_STACK_SIZE_IN_PAGES, &mut kernel_mmi.page_table)
                 .expect("kstart_ap(): could not allocate double fault stack"),
+            stack::alloc_stack(KERNEL_STACK_SIZE_IN_PAGES, &mut kernel_mmi.page_table)
+                .expect("kstart_ap(): could not allocate page fault stack"),
             stack::alloc_stack(1, &mut kernel_mmi.page_table)
                 .expect("kstart_ap(): could not allocate privilege stack"),
         )
     };
-    let _idt = interrupts::init_ap(apic_id, double_fault_stack.top_unusable(), privilege_stack.top_unusable())
+    let _idt = interrupts::init_ap(apic_id, double_fault_stack.top_unusable(), page_fault_stack.top_unusable(), privilege_stack.top_unusable())
         .expect("kstart_ap(): failed to initialize interrupts!");
 
     // Initialize this CPU's Local APIC such that we can use everything that depends on APIC IDs.
@@ -67,6 +67,14 @@ pub fn has_x2apic() -> bool {
     *res // because call_once returns a reference to the cached IS_X2APIC value
 }
 
+/// Returns true if the machine supports the TSC-deadline mode of the Local APIC timer.
+pub fn has_tsc_deadline() -> bool {
+    static HAS_TSC_DEADLINE: Once<bool> = Once::new(); // caches the result
+    *HAS_TSC_DEADLINE.call_once(|| {
+        CpuId::new().get_feature_info().expect("Couldn't get CpuId feature info").has_tsc_deadline()
+    })
+}
+
 /// Returns a reference to the list of LocalApics, one per CPU core.
 pub fn get_lapics() -> &'static AtomicMap<u8, RwLockIrqSafe<LocalApic>> {
 	&LOCAL_APICS
@@ -169,7 +177,9 @@ const IA32_APIC_XAPIC_ENABLE: u64 = 1 << 11; // 0x800
 const IA32_APIC_X2APIC_ENABLE: u64 = 1 << 10; // 0x400
 const IA32_APIC_BASE_MSR_IS_BSP: u64 = 1 << 8; // 0x100
 const APIC_SW_ENABLE: u32 = 1 << 8;
+const APIC_TIMER_ONE_SHOT:  u32 = 0x0_0000;
 const APIC_TIMER_PERIODIC:  u32 = 0x2_0000;
+const APIC_TIMER_TSC_DEADLINE: u32 = 0x4_0000;
 const APIC_DISABLE: u32 = 0x1_0000;
 const APIC_NMI: u32 = 4 << 8;
 
@@ -261,6 +271,27 @@ pub struct RegisterArray {
 }
 const_assert_eq!(core::mem::size_of::<RegisterArray>(), 8 * (4 + 12));
 
+/// The modes in which the Local APIC's LVT timer can operate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LapicTimerMode {
+    /// The timer fires once when its count reaches zero.
+    OneShot,
+    /// The timer fires every time its count reaches zero, and then reloads its initial count.
+    Periodic,
+    /// The timer fires once when the TSC reaches the value written to the `IA32_TSC_DEADLINE` MSR.
+    TscDeadline,
+}
+impl LapicTimerMode {
+    /// Returns the timer mode bits for the LVT timer register.
+    fn lvt_bits(&self) -> u32 {
+        match self {
+            Self::OneShot => APIC_TIMER_ONE_SHOT,
+            Self::Periodic => APIC_TIMER_PERIODIC,
+            Self::TscDeadline => APIC_TIMER_TSC_DEADLINE,
+        }
+    }
+}
+
 /// The Local APIC's vector table local interrupt pins.
 #[doc(alias("lvt", "lint", "lint0", "lint1"))]
 pub enum LvtLint {
@@ -327,6 +358,11 @@ pub struct LocalApic {
     processor_id: u8,
     /// Whether this Local APIC is the BootStrap Processor (the first CPU to boot up).
     is_bsp: bool,
+    /// The number of timer ticks (with a divide value of 16) that elapse
+    /// during one timeslice of `CONFIG_TIMESLICE_PERIOD_MICROSECONDS`.
+    timer_period: u64,
+    /// The mode that the LVT timer is currently configured to run in.
+    timer_mode: LapicTimerMode,
 }
 impl fmt::Debug for LocalApic {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -335,6 +371,7 @@ impl fmt::Debug for LocalApic {
             .field("apic_id", &self.apic_id)
             .field("processor_id", &self.processor_id)
             .field("is_bsp", &self.is_bsp)
+            .field("timer_mode", &self.timer_mode)
             .finish()
     }
 }
@@ -424,6 +461,8 @@ impl LocalApic {
             processor_id,
             apic_id: u8::MAX, // placeholder, is replaced below.
             is_bsp,
+            timer_period: 0, // placeholder, is replaced in `init_lvt_timer()` below.
+            timer_mode: LapicTimerMode::Periodic,
         };
 
         // Now that the APIC hardware is enabled, we can safely obtain this Local APIC's ID.
@@ -442,6 +481,8 @@ impl LocalApic {
         unsafe { wrmsr(IA32_TSC_AUX, actual_apic_id as u64); }
         if is_bsp {
             BSP_PROCESSOR_ID.call_once(|| actual_apic_id); 
+            // The frame allocator can now use a separate cache of free frames for each CPU.
+            frame_allocator::set_current_cpu_func(|| get_my_apic_id() as usize);
         }
 
         let _existing = LOCAL_APICS.insert(actual_apic_id, RwLockIrqSafe::new(lapic));
@@ -564,6 +605,8 @@ impl LocalApic {
             self.calibrate_lapic_timer(CONFIG_TIMESLICE_PERIOD_MICROSECONDS)
         };
         trace!("LocalApic {}, timer period count: {} ({:#X})", self.apic_id, apic_period, apic_period);
+        self.timer_period = apic_period;
+        self.timer_mode = LapicTimerMode::Periodic;
 
         match &mut self.inner {
             LapicType::X2Apic => unsafe {
@@ -596,10 +639,10 @@ impl LocalApic {
 
     /// Enable (unmask) or disable (mask) the LVT timer interrupt on this lapic.
     /// 
-    /// This does **not** modify the timer's current count value.
+    /// This does **not** modify the timer's current count value or mode.
     pub fn enable_lvt_timer(&mut self, enable: bool) {
         let value = if enable {
-            LOCAL_APIC_LVT_IRQ as u32 | APIC_TIMER_PERIODIC
+            LOCAL_APIC_LVT_IRQ as u32 | self.timer_mode.lvt_bits()
         } else {
             APIC_DISABLE
         };
@@ -611,6 +654,82 @@ impl LocalApic {
         }
     }
 
+    /// Returns the mode that this lapic's LVT timer is currently configured to run in.
+    pub fn timer_mode(&self) -> LapicTimerMode { self.timer_mode }
+
+    /// Configures this lapic's LVT timer to fire periodically, once every timeslice 
+    /// of `CONFIG_TIMESLICE_PERIOD_MICROSECONDS`, which is the default mode.
+    pub fn set_timer_periodic(&mut self) {
+        let period = self.timer_period;
+        self.set_lvt_timer(LapicTimerMode::Periodic, period);
+    }
+
+    /// Configures this lapic's LVT timer to fire once after the given number of `microseconds`.
+    /// 
+    /// The delay is rounded to the nearest timer tick and is clamped to the maximum
+    /// value of the timer's initial count register. 
+    pub fn set_timer_one_shot(&mut self, microseconds: u64) {
+        let ticks = (self.timer_period as u128 * microseconds as u128)
+            / CONFIG_TIMESLICE_PERIOD_MICROSECONDS as u128;
+        // An initial count of zero stops the timer, so we must count at least one tick.
+        let ticks = ticks.clamp(1, u32::MAX as u128) as u64;
+        self.set_lvt_timer(LapicTimerMode::OneShot, ticks);
+    }
+
+    /// Configures this lapic's LVT timer to fire once when the TSC reaches the given `deadline`.
+    /// 
+    /// Returns an error if this machine doesn't support TSC-deadline mode, see [`has_tsc_deadline()`].
+    pub fn set_timer_tsc_deadline(&mut self, deadline: u64) -> Result<(), &'static str> {
+        if !has_tsc_deadline() {
+            return Err("TSC-deadline mode is not supported by the Local APIC timer");
+        }
+        self.set_lvt_timer(LapicTimerMode::TscDeadline, 0);
+        // A deadline of zero disarms the timer, so we must use a deadline of at least one.
+        unsafe { wrmsr(IA32_TSC_DEADLINE, core::cmp::max(deadline, 1)); }
+        Ok(())
+    }
+
+    /// Stops this lapic's LVT timer such that it no longer generates interrupts
+    /// until it is re-armed by another `set_timer_*()` function.
+    /// 
+    /// The timer's mode is left unchanged.
+    pub fn stop_timer(&mut self) {
+        match self.timer_mode {
+            LapicTimerMode::TscDeadline => unsafe { wrmsr(IA32_TSC_DEADLINE, 0) },
+            _ => match &mut self.inner {
+                LapicType::X2Apic => unsafe { wrmsr(IA32_X2APIC_INIT_COUNT, 0) },
+                LapicType::XApic(regs) => regs.timer_initial_count.write(0),
+            }
+        }
+    }
+
+    /// Sets the mode of the LVT timer and then writes the given `initial_count`.
+    /// 
+    /// Writing the initial count (re)starts the timer in one-shot or periodic mode;
+    /// the `initial_count` is ignored in TSC-deadline mode.
+    fn set_lvt_timer(&mut self, mode: LapicTimerMode, initial_count: u64) {
+        let lvt_value = LOCAL_APIC_LVT_IRQ as u32 | mode.lvt_bits();
+        match &mut self.inner {
+            LapicType::X2Apic => unsafe {
+                if mode != self.timer_mode {
+                    wrmsr(IA32_X2APIC_LVT_TIMER, lvt_value as u64);
+                }
+                if mode != LapicTimerMode::TscDeadline {
+                    wrmsr(IA32_X2APIC_INIT_COUNT, initial_count);
+                }
+            }
+            LapicType::XApic(regs) => {
+                if mode != self.timer_mode {
+                    regs.lvt_timer.write(lvt_value);
+                }
+                if mode != LapicTimerMode::TscDeadline {
+                    regs.timer_initial_count.write(initial_count as u32);
+                }
+            }
+        }
+        self.timer_mode = mode;
+    }
+
     /// Returns the ID of this Local APIC (fast).
     /// 
     /// Unlike [`read_apic_id()`], this does not read any hardware registers.
@@ -6,6 +6,10 @@
 //! 
 //! Only `Send` types can be sent or received through the channel.
 //! 
+//! Both ends of the channel offer blocking functions (e.g., [`Sender::send()`])
+//! as well as `async` functions (e.g., [`Sender::send_async()`]) that can be `.await`ed
+//! by futures running on an asynchronous executor.
+//! 
 //! This is not a zero-copy channel; 
 //! to avoid copying large messages, use a reference (layer of indirection) like `Box`.
 
@@ -198,6 +202,46 @@ impl <T: Send> Sender<T> {
         res
     }
 
+    /// Send a message asynchronously, waiting until space in the channel's buffer is available. 
+    /// 
+    /// This is the `async` counterpart of [`Sender::send()`]: 
+    /// rather than blocking the current task, the returned future waits on the channel's 
+    /// waitqueue and is woken up when a receiver drains a message from the buffer.
+    /// 
+    /// Returns `Ok(())` if the message was sent successfully,
+    /// otherwise returns an error of `ChannelError` type. 
+    pub async fn send_async(&self, msg: T) -> Result<(), ChannelError> {
+        // Fast path: attempt to send the message, assuming the buffer isn't full
+        let mut msg = match self.try_send(msg) {
+            Ok(()) => return Ok(()),
+            Err((returned_msg, ChannelError::ChannelFull)) => Some(returned_msg),
+            Err((_, channel_error)) => return Err(channel_error),
+        };
+
+        // Slow path: wait until space becomes available, retrying each time we're woken up.
+        // As with `send()`, this closure is invoked from within a locked context,
+        // so we must notify the receivers only after it returns.
+        let res = self.channel.waiting_senders.wait_until_async(|| {
+            let result = msg.take().and_then(|m| match self.channel.queue.push(m) {
+                Ok(()) => Some(Ok(())),
+                Err(returned_msg) => {
+                    msg = Some(returned_msg);
+                    None
+                }
+            });
+            if self.channel.is_disconnected() {
+                Some(Err(ChannelError::ChannelDisconnected))
+            } else {
+                result
+            }
+        }).await;
+
+        if res.is_ok() {
+            self.channel.waiting_receivers.notify_one();
+        }
+        res
+    }
+
     /// Tries to send the message, only succeeding if buffer space is available.
     /// 
     /// If no buffer space is available, it returns the `msg`  with `ChannelError` back to the caller without blocking. 
@@ -313,6 +357,37 @@ impl <T: Send> Receiver<T> {
         res
     }
 
+    /// Receive a message asynchronously, waiting until a message is available in the buffer.
+    /// 
+    /// This is the `async` counterpart of [`Receiver::receive()`]: 
+    /// rather than blocking the current task, the returned future waits on the channel's 
+    /// waitqueue and is woken up when a sender pushes a message into the buffer.
+    /// 
+    /// Returns the message if it was received properly, otherwise returns an error of `ChannelError` type.
+    pub async fn receive_async(&self) -> Result<T, ChannelError> {
+        // Fast path: attempt to receive a message, assuming the buffer isn't empty
+        match self.try_receive() {
+            Err(ChannelError::ChannelEmpty) => {},
+            x => return x,
+        };
+
+        // Slow path: wait until a message is sent.
+        // As with `receive()`, this closure is invoked from within a locked context,
+        // so we must notify the senders only after it returns.
+        let res = self.channel.waiting_receivers.wait_until_async(|| {
+            match self.channel.queue.pop() {
+                Some(msg) => Some(Ok(msg)),
+                None if self.channel.is_disconnected() => Some(Err(ChannelError::ChannelDisconnected)),
+                None => None,
+            }
+        }).await;
+
+        if res.is_ok() {
+            self.channel.waiting_senders.notify_one();
+        }
+        res
+    }
+
     /// Tries to receive a message, only succeeding if a message is already available in the buffer.
     /// 
     /// If receive succeeds returns `Some(Ok(T))`. 
@@ -0,0 +1,397 @@
+//! An executor for running `async`/`await`-based [`Future`]s on Theseus tasks.
+//!
+//! Much of Theseus's driver and service code is written as blocking loops,
+//! in which each I/O-bound activity occupies an entire task.
+//! Instead, an [`Executor`] allows a single task to multiplex many I/O-bound activities,
+//! each of which is a `Future` that is polled only when it is able to make progress.
+//!
+//! The [`Waker`]s created by this crate unblock the task running the executor,
+//! so they interoperate with all Theseus primitives that support wakers, including:
+//! * [`wait_queue::WaitQueue::wait_until_async()`], and thus all waitqueue-based primitives,
+//!   e.g., `async_channel::Receiver::receive_async()`,
+//! * [`sleep::sleep_async()`] for timers, and
+//! * NIC receive interrupts via `network_interface_card::wait_for_received_frame()`.
+//!
+//! Futures can be run either by [`block_on()`], which runs a single future on the current task,
+//! or by spawning many futures onto an [`Executor`] and then [running](Executor::run) it,
+//! optionally on a new dedicated task via [`Executor::spawn_runner()`].
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, collections::VecDeque, string::String, sync::Arc, task::Wake};
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use irq_safety::MutexIrqSafe;
+use log::error;
+use spin::Mutex;
+use task::{JoinableTaskRef, TaskRef};
+
+/// A future that has been spawned onto an executor, boxed for dynamic dispatch.
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// Puts the current task to sleep until `notified` is set by a waker,
+/// atomically with respect to that waker.
+///
+/// This is the mechanism by which an idle executor blocks its task
+/// without missing a wakeup that occurs concurrently.
+fn park_current_task(notified: &MutexIrqSafe<bool>) -> Result<(), &'static str> {
+    {
+        let mut notified = notified.lock();
+        if !*notified {
+            task::with_current_task(|t| t.block())
+                .map_err(|_| "async_executor: couldn't get current task")?
+                .map_err(|_| "async_executor: couldn't block current task")?;
+        }
+        *notified = false;
+    }
+    scheduler::schedule();
+    Ok(())
+}
+
+/// Sets `notified` and unblocks the given parked `task`, if it was blocked.
+fn unpark_task(notified: &MutexIrqSafe<bool>, task: &TaskRef) {
+    let mut notified = notified.lock();
+    *notified = true;
+    // The task may not be blocked, e.g., if it is currently polling a future.
+    let _ = task.unblock();
+}
+
+
+/// A waker that unparks the task that is running [`block_on()`].
+struct BlockOnWaker {
+    task: TaskRef,
+    notified: MutexIrqSafe<bool>,
+}
+
+impl Wake for BlockOnWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        unpark_task(&self.notified, &self.task)
+    }
+}
+
+/// Runs the given `future` to completion on the current task, returning its output.
+///
+/// The current task is blocked whenever the future cannot make progress,
+/// and is unblocked when the future's `Waker` is woken.
+pub fn block_on<F: Future>(future: F) -> Result<F::Output, &'static str> {
+    let mut future = Box::pin(future);
+    let block_on_waker = Arc::new(BlockOnWaker {
+        task: task::get_my_current_task().ok_or("async_executor: couldn't get current task")?,
+        notified: MutexIrqSafe::new(false),
+    });
+    let waker = Waker::from(block_on_waker.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return Ok(output);
+        }
+        park_current_task(&block_on_waker.notified)?;
+    }
+}
+
+
+/// The state of an executor that is shared with the wakers of its spawned futures.
+struct ExecutorInner {
+    /// The futures that have been woken and are ready to be polled.
+    ready: MutexIrqSafe<VecDeque<Arc<SpawnedFuture>>>,
+    /// Whether the executor has been woken since it last checked its `ready` queue.
+    notified: MutexIrqSafe<bool>,
+    /// The task that is currently running this executor, if any.
+    ///
+    /// Like the other fields used by [`ExecutorInner::schedule()`], this must be an IRQ-safe lock
+    /// because wakers may be invoked from interrupt handlers, e.g., a NIC's receive interrupt or the timer.
+    runner: MutexIrqSafe<Option<TaskRef>>,
+    /// The number of spawned futures that have not yet completed.
+    pending: AtomicUsize,
+}
+
+impl ExecutorInner {
+    /// Adds the given `future` to the ready queue and wakes up the executor's task.
+    fn schedule(&self, future: Arc<SpawnedFuture>) {
+        self.ready.lock().push_back(future);
+        match &*self.runner.lock() {
+            Some(runner) => unpark_task(&self.notified, runner),
+            None => *self.notified.lock() = true,
+        }
+    }
+}
+
+/// A future that has been spawned onto an [`Executor`], which acts as its own `Waker`.
+struct SpawnedFuture {
+    /// The future itself, which is `None` once it has completed.
+    future: Mutex<Option<BoxFuture>>,
+    /// Whether this future is currently in its executor's ready queue,
+    /// which avoids adding it to the ready queue multiple times.
+    queued: AtomicBool,
+    executor: Arc<ExecutorInner>,
+}
+
+impl Wake for SpawnedFuture {
+    fn wake(self: Arc<Self>) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            let executor = self.executor.clone();
+            executor.schedule(self);
+        }
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.clone().wake()
+    }
+}
+
+
+/// An executor that runs many spawned futures on a single task.
+///
+/// Futures are spawned onto the executor using [`Executor::spawn()`],
+/// and are polled when [`Executor::run()`] is invoked.
+/// An `Executor` can be cheaply cloned and shared across tasks,
+/// such that any task can spawn new futures onto it.
+#[derive(Clone)]
+pub struct Executor {
+    inner: Arc<ExecutorInner>,
+}
+
+impl Executor {
+    /// Creates a new executor with no spawned futures.
+    pub fn new() -> Executor {
+        Executor {
+            inner: Arc::new(ExecutorInner {
+                ready: MutexIrqSafe::new(VecDeque::new()),
+                notified: MutexIrqSafe::new(false),
+                runner: MutexIrqSafe::new(None),
+                pending: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Spawns the given `future` onto this executor.
+    ///
+    /// The future will begin executing the next time this executor is run.
+    /// The returned [`JoinHandle`] can be used to obtain the future's output,
+    /// either by `.await`ing it or by polling it with [`JoinHandle::try_take()`].
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let state = Arc::new(Mutex::new(JoinState { output: None, waker: None }));
+        let join_state = state.clone();
+        let wrapped: BoxFuture = Box::pin(async move {
+            let output = future.await;
+            let mut state = join_state.lock();
+            state.output = Some(output);
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        });
+
+        self.inner.pending.fetch_add(1, Ordering::AcqRel);
+        let spawned = Arc::new(SpawnedFuture {
+            future: Mutex::new(Some(wrapped)),
+            queued: AtomicBool::new(true),
+            executor: self.inner.clone(),
+        });
+        self.inner.schedule(spawned);
+        JoinHandle { state }
+    }
+
+    /// Returns the number of spawned futures that have not yet completed.
+    pub fn pending(&self) -> usize {
+        self.inner.pending.load(Ordering::Acquire)
+    }
+
+    /// Runs this executor on the current task until all spawned futures have completed,
+    /// including futures that were spawned while running.
+    ///
+    /// The current task is blocked whenever none of the spawned futures can make progress.
+    /// Only one task can run an executor at a time.
+    pub fn run(&self) -> Result<(), &'static str> {
+        {
+            let mut runner = self.inner.runner.lock();
+            if runner.is_some() {
+                return Err("async_executor: executor is already being run by another task");
+            }
+            *runner = Some(task::get_my_current_task().ok_or("async_executor: couldn't get current task")?);
+        }
+
+        let res = self.run_inner();
+        *self.inner.runner.lock() = None;
+        res
+    }
+
+    fn run_inner(&self) -> Result<(), &'static str> {
+        while self.pending() > 0 {
+            let next = self.inner.ready.lock().pop_front();
+            let Some(spawned) = next else {
+                park_current_task(&self.inner.notified)?;
+                continue;
+            };
+
+            // Clear the `queued` flag before polling, such that the future can be
+            // re-queued if it is woken during or after this poll.
+            spawned.queued.store(false, Ordering::Release);
+            let waker = Waker::from(spawned.clone());
+            let mut cx = Context::from_waker(&waker);
+
+            let mut future_slot = spawned.future.lock();
+            let completed = match future_slot.as_mut() {
+                Some(future) => future.as_mut().poll(&mut cx).is_ready(),
+                // This future already completed but was woken again, so there's nothing to do.
+                None => false,
+            };
+            if completed {
+                *future_slot = None;
+                self.inner.pending.fetch_sub(1, Ordering::AcqRel);
+            }
+        }
+        Ok(())
+    }
+
+    /// Spawns a new task with the given `name` that runs this executor until all of
+    /// its spawned futures have completed.
+    pub fn spawn_runner(&self, name: String) -> Result<JoinableTaskRef, &'static str> {
+        let executor = self.clone();
+        spawn::new_task_builder(
+            |executor: Executor| {
+                if let Err(e) = executor.run() {
+                    error!("async_executor: error running executor: {}", e);
+                }
+            },
+            executor,
+        )
+        .name(name)
+        .spawn()
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Executor::new()
+    }
+}
+
+impl fmt::Debug for Executor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Executor")
+            .field("pending", &self.pending())
+            .finish()
+    }
+}
+
+
+/// The output of a spawned future, shared with its [`JoinHandle`].
+struct JoinState<T> {
+    output: Option<T>,
+    /// The waker of the task that is awaiting the `JoinHandle`, if any.
+    waker: Option<Waker>,
+}
+
+/// A handle to a future that was spawned onto an [`Executor`],
+/// which can be used to obtain that future's output once it completes.
+///
+/// `.await`ing a `JoinHandle` resolves to the spawned future's output.
+#[must_use = "dropping a JoinHandle discards the spawned future's output"]
+pub struct JoinHandle<T> {
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Returns the spawned future's output if it has completed, without blocking.
+    ///
+    /// The output can only be taken once.
+    pub fn try_take(&self) -> Option<T> {
+        self.state.lock().output.take()
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        let mut state = self.state.lock();
+        match state.output.take() {
+            Some(output) => Poll::Ready(output),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+
+/// Returns a future that yields once to the executor,
+/// allowing other ready futures to be polled before this one continues.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+/// A future that is pending the first time it is polled. See [`yield_now()`].
+#[must_use = "futures do nothing unless polled or `.await`ed"]
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+
+/// The error returned by [`timeout()`] if the given future did not complete in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimedOut;
+
+/// Runs the given `future`, but gives up if it does not complete within the given `duration`.
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep::sleep_async(duration),
+    }
+}
+
+/// A future that completes with an error if its inner future does not complete in time.
+/// See [`timeout()`].
+#[must_use = "futures do nothing unless polled or `.await`ed"]
+pub struct Timeout<F> {
+    future: F,
+    sleep: sleep::Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, TimedOut>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // SAFETY: `future` is structurally pinned: it is never moved out of `self`.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut this.sleep).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(TimedOut)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
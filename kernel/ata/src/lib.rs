@@ -16,6 +16,7 @@ use spin::Mutex;
 use alloc::{boxed::Box, format, string::String, sync::Arc};
 use port_io::{Port, PortReadOnly, PortWriteOnly};
 use pci::PciDevice;
+use iommu::DmaDomain;
 use storage_device::{StorageDevice, StorageDeviceRef, StorageController};
 use io::{BlockIo, BlockReader, BlockWriter, IoError, KnownLength};
 use x86_64::structures::idt::InterruptStackFrame;
@@ -712,6 +713,10 @@ pub struct IdeController {
 	pub primary_slave:    Option<AtaDriveRef>,
 	pub secondary_master: Option<AtaDriveRef>,
 	pub secondary_slave:  Option<AtaDriveRef>,
+	/// The DMA domain of this controller.
+	/// Drives are currently only accessed using PIO, so nothing is mapped into it,
+	/// which blocks all DMA from the controller.
+	pub dma_domain:       Arc<DmaDomain>,
 }
 
 impl IdeController {
@@ -746,8 +751,9 @@ impl IdeController {
 			}
 		};
 
-		// TODO: use the BAR4 for DMA in the future
+		// TODO: use the BAR4 for DMA in the future, mapping the DMA buffers into `dma_domain`.
 		let _bus_master_base = pci_device.bars[4]; 
+		let dma_domain = iommu::domain_for_device(pci_device)?;
 
 		// Register interrupt handlers for the primary and secondary ATA buses.
 		// They're not yet used for anything but will determine when a DMA transfer has completed.
@@ -796,6 +802,7 @@ impl IdeController {
 			primary_slave:    primary_slave.ok().map(|d| Arc::new(Mutex::new(d))),
 			secondary_master: secondary_master.ok().map(|d| Arc::new(Mutex::new(d))),
 			secondary_slave:  secondary_slave.ok().map(|d| Arc::new(Mutex::new(d))),
+			dma_domain,
 		})
 	}
 
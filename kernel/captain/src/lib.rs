@@ -46,7 +46,9 @@ extern crate network_manager;
 extern crate window_manager;
 extern crate tlb_shootdown;
 extern crate multiple_heaps;
+#[cfg(debug_heap)] extern crate heap_debug;
 extern crate console;
+#[cfg(watchdog)] extern crate watchdog;
 #[cfg(simd_personality)] extern crate simd_personality;
 
 
@@ -102,16 +104,18 @@ pub fn init(
     device_manager::early_init(kernel_mmi_ref.lock().deref_mut())?;
 
     // initialize the rest of the BSP's interrupt stuff, including TSS & GDT
-    let (double_fault_stack, privilege_stack) = {
+    let (double_fault_stack, page_fault_stack, privilege_stack) = {
         let mut kernel_mmi = kernel_mmi_ref.lock();
         (
             stack::alloc_stack(KERNEL_STACK_SIZE_IN_PAGES, &mut kernel_mmi.page_table)
                 .ok_or("could not allocate double fault stack")?,
+            stack::alloc_stack(KERNEL_STACK_SIZE_IN_PAGES, &mut kernel_mmi.page_table)
+                .ok_or("could not allocate page fault stack")?,
             stack::alloc_stack(1, &mut kernel_mmi.page_table)
                 .ok_or("could not allocate privilege stack")?,
         )
     };
-    let idt = interrupts::init(double_fault_stack.top_unusable(), privilege_stack.top_unusable())?;
+    let idt = interrupts::init(double_fault_stack.top_unusable(), page_fault_stack.top_unusable(), privilege_stack.top_unusable())?;
     
     // get BSP's apic id
     let bsp_apic_id = apic::get_bsp_id().ok_or("captain::init(): Coudln't get BSP's apic_id!")?;
@@ -138,7 +142,11 @@ pub fn init(
     tlb_shootdown::init();
     
     // //initialize the per core heaps
+    #[cfg(not(debug_heap))]
     multiple_heaps::switch_to_multiple_heaps()?;
+    // or wrap them in the debugging allocator, which detects heap corruption and leaks
+    #[cfg(debug_heap)]
+    heap_debug::switch_to_debug_heap()?;
     info!("Initialized per-core heaps");
 
     // initialize window manager.
@@ -167,6 +175,10 @@ pub fn init(
 
     // Now that key subsystems are initialized, we can spawn various system tasks/daemons
     // and then the first application(s).
+    #[cfg(watchdog)]
+    spawn::new_task_builder(watchdog::watchdog_task, ())
+        .name(alloc::string::String::from("watchdog"))
+        .spawn()?;
     console::start_connection_detection()?;
     first_application::start()?;
 
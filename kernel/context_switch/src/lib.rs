@@ -4,7 +4,7 @@
 #![no_std]
 #![feature(naked_functions)]
 
-pub use context_switch_regular::read_first_register;
+pub use context_switch_regular::{read_first_register, ContextRegular};
 
 // If `simd_personality` is enabled, all of the `context_switch*` implementation crates are simultaneously enabled,
 // in order to allow choosing one of them based on the configuration options of each Task (SIMD, regular, etc).
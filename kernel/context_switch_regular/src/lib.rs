@@ -55,6 +55,20 @@ impl ContextRegular {
     pub fn set_first_register(&mut self, value: usize) {
         self.r15 = value;
     }
+
+    /// Returns the instruction pointer at which the Task that saved this context will resume execution.
+    pub fn instruction_pointer(&self) -> usize {
+        self.rip
+    }
+
+    /// Returns the values of the callee-saved registers in this context,
+    /// in the order `[rbx, rbp, r12, r13, r14, r15]`.
+    ///
+    /// Along with the instruction pointer and stack pointer, these are the registers
+    /// needed to walk the call stack of the Task that saved this context.
+    pub fn callee_saved_registers(&self) -> [usize; 6] {
+        [self.rbx, self.rbp, self.r12, self.r13, self.r14, self.r15]
+    }
 }
 
 /// Reads the value of the first register from the actual CPU register hardware.
@@ -96,6 +96,26 @@ pub const RODATA_SECTION_FLAGS:   EntryFlags = EntryFlags::from_bits_truncate(En
 /// `.data` and `.bss` sections are read-write and non-executable.
 pub const DATA_BSS_SECTION_FLAGS: EntryFlags = EntryFlags::from_bits_truncate(EntryFlags::PRESENT.bits() | EntryFlags::NO_EXECUTE.bits() | EntryFlags::WRITABLE.bits());
 
+/// The crates whose pages are never shared copy-on-write when deep copying them, see [`LoadedCrate::deep_copy()`].
+///
+/// Resolving a write to a shared page allocates frames and may allocate heap memory,
+/// so a write by one of these crates to its own shared `.data` or `.bss` section,
+/// e.g., to a lock that it already holds, would recurse into that crate and deadlock.
+#[cfg(internal_deps)]
+const COPY_ON_WRITE_EXCLUDED_CRATES: &[&str] = &[
+    "frame_allocator",
+    "page_allocator",
+    "memory",
+    "memory_accounting",
+    "task_group",
+    "heap",
+    "multiple_heaps",
+    "slabmalloc",
+    "slabmalloc_safe",
+    "slabmalloc_unsafe",
+    "tlb_shootdown",
+];
+
 
 /// The Theseus Makefile appends prefixes onto bootloader module names,
 /// which are separated by the "#" character. 
@@ -184,6 +204,33 @@ impl CrateType {
 }
 
 
+/// The amount of memory occupied by the sections of a [`LoadedCrate`], in bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CrateMemoryUsage {
+    /// The size of this crate's executable sections.
+    pub text_bytes: usize,
+    /// The size of this crate's read-only sections.
+    pub rodata_bytes: usize,
+    /// The size of this crate's writable `.data` and `.bss` sections.
+    pub data_bytes: usize,
+}
+
+impl CrateMemoryUsage {
+    /// Returns the combined size of all of this crate's sections.
+    pub fn total_bytes(&self) -> usize {
+        self.text_bytes + self.rodata_bytes + self.data_bytes
+    }
+}
+
+impl core::ops::AddAssign for CrateMemoryUsage {
+    fn add_assign(&mut self, other: CrateMemoryUsage) {
+        self.text_bytes += other.text_bytes;
+        self.rodata_bytes += other.rodata_bytes;
+        self.data_bytes += other.data_bytes;
+    }
+}
+
+
 /// Represents a single crate whose object file has been 
 /// loaded and linked into at least one `CrateNamespace`.
 pub struct LoadedCrate {
@@ -319,6 +366,19 @@ impl LoadedCrate {
             .unwrap_or(&self.crate_name)
     }
 
+    /// Returns the amount of memory occupied by this crate's sections,
+    /// based on the range of virtual addresses covered by each kind of section.
+    pub fn memory_usage(&self) -> CrateMemoryUsage {
+        let size_of = |pages: &Option<(Arc<Mutex<MappedPages>>, Range<VirtualAddress>)>| {
+            pages.as_ref().map_or(0, |(_, range)| range.end.value() - range.start.value())
+        };
+        CrateMemoryUsage {
+            text_bytes: size_of(&self.text_pages),
+            rodata_bytes: size_of(&self.rodata_pages),
+            data_bytes: size_of(&self.data_pages),
+        }
+    }
+
     /// Returns this crate name as a symbol prefix, including a trailing "`::`".
     /// If there is no hash, then it returns the entire name with a trailing "`::`".
     /// # Example
@@ -360,7 +420,9 @@ impl LoadedCrate {
 
     /// Creates a new copy of this `LoadedCrate`, which is a relatively slow process
     /// because it must do the following:    
-    /// * Deep copy all of the MappedPages into completely new memory regions.
+    /// * Copy all of the MappedPages into new memory regions.
+    ///   These share their frames with the original crate in a copy-on-write manner,
+    ///   so only the pages that are modified (e.g., by rewriting relocations) are actually copied.
     /// * Duplicate every section within this crate.
     /// * Recalculate every relocation entry to point to the newly-copied sections,
     ///   which is the most time-consuming component of this function.
@@ -382,16 +444,27 @@ impl LoadedCrate {
         page_table: &mut PageTable, 
     ) -> Result<StrongCrateRef, &'static str> {
 
-        // This closure deep copies the given mapped_pages (mapping them as WRITABLE)
+        // This closure copies the given mapped_pages (mapping them as WRITABLE)
         // and recalculates the the range of addresses covered by the new mapping.
+        // The pages are shared copy-on-write, such that only the pages we later write relocations into are copied;
+        // if they can't be shared, they are eagerly deep copied instead.
+        let share_pages = !COPY_ON_WRITE_EXCLUDED_CRATES.contains(&self.crate_name_without_hash());
         let mut deep_copy_mp = |old_mp_range: &(Arc<Mutex<MappedPages>>, Range<VirtualAddress>), flags: EntryFlags|
             -> Result<(Arc<Mutex<MappedPages>>, Range<VirtualAddress>), &'static str> 
         {
-            let old_mp_locked = old_mp_range.0.lock();
+            let mut old_mp_locked = old_mp_range.0.lock();
             let old_start_address = old_mp_range.1.start.value();
             let size = old_mp_range.1.end.value() - old_start_address;
             let offset = old_start_address - old_mp_locked.start_address().value();
-            let new_mp = old_mp_range.0.lock().deep_copy(Some(flags | EntryFlags::WRITABLE), page_table)?;
+            let shared_mp = if share_pages {
+                old_mp_locked.copy_on_write(Some(flags | EntryFlags::WRITABLE), page_table).ok()
+            } else {
+                None
+            };
+            let new_mp = match shared_mp {
+                Some(new_mp) => new_mp,
+                None => old_mp_locked.deep_copy(Some(flags | EntryFlags::WRITABLE), page_table)?,
+            };
             let new_start_address = new_mp.start_address() + offset;
             Ok((Arc::new(Mutex::new(new_mp)), new_start_address .. (new_start_address + size)))
         };
@@ -982,6 +1055,12 @@ impl RelocationEntry {
             _ => false,
         }
     }
+
+    /// Returns true if the relocation writes the full 64-bit address of the source section
+    /// into the target section, which is how the large code model refers to functions in other crates.
+    pub fn is_absolute_64bit(&self) -> bool {
+        self.typ == R_X86_64_64
+    }
 }
 
 
@@ -9,6 +9,10 @@
 //! This is currently only used to parse and serialize the `nano_core` binary at compile time.
 //! The `nano_core`'s [`SerializedCrate`] is then included as a boot module
 //! so it can be deserialized into a LoadedCrate at runtime by `mod_mgmt`.
+//!
+//! This crate also defines [`CrateAbi`], the ABI metadata that the build
+//! (`tools/emit_crate_abi`) embeds into each crate object file,
+//! and [`CrateSignature`], which the build (`tools/sign_crates`) appends to each crate object file.
 //! 
 //! Some other types have been moved from `crate_metadata` into this crate because
 //! they are required for (de)serialization, e.g., [`SectionType`].
@@ -150,3 +154,101 @@ impl SectionType {
         }
     }
 }
+
+
+/// The name of the non-loaded ELF section in a crate object file that holds
+/// that crate's [`CrateAbi`], serialized with `bincode`'s standard config.
+pub const ABI_SECTION_NAME: &str = ".theseus_abi";
+
+/// A description of a crate's binary interface, 
+/// used to check whether one crate can safely replace or link against another.
+///
+/// All symbol names are demangled and exclude the hash suffix, e.g., `my_crate::foo`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CrateAbi {
+    /// The public symbols defined by this crate.
+    pub exports: BTreeMap<String, SymbolAbi>,
+    /// The public symbols from other crates that this crate uses, 
+    /// along with the ABI those symbols had when this crate was built.
+    pub imports: BTreeMap<String, SymbolAbi>,
+}
+
+/// The binary interface of a single symbol.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SymbolAbi {
+    /// What kind of item the symbol refers to.
+    pub kind: SymbolKind,
+    /// The size in bytes of a data symbol; always `0` for functions.
+    pub size: u64,
+    /// A hash of the symbol's type: the parameter and return types of a function,
+    /// or the type layout of a static variable. 
+    /// This is `0` if the type could not be determined.
+    pub layout_hash: u64,
+}
+
+impl SymbolAbi {
+    /// Returns `true` if this symbol can be used by code that expects a symbol with the `expected` ABI.
+    ///
+    /// Type layouts are only compared if they are known for both symbols.
+    pub fn is_compatible_with(&self, expected: &SymbolAbi) -> bool {
+        self.kind == expected.kind
+            && self.size == expected.size
+            && (self.layout_hash == 0 || expected.layout_hash == 0 || self.layout_hash == expected.layout_hash)
+    }
+}
+
+/// The kinds of symbols described by a [`SymbolAbi`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SymbolKind {
+    /// A function.
+    Function,
+    /// A static variable or constant.
+    Data,
+    /// A thread-local variable.
+    Tls,
+}
+
+
+/// The magic bytes at the very end of a signed crate object file.
+pub const CRATE_SIGNATURE_MAGIC: [u8; 8] = *b"THSIG001";
+
+/// An Ed25519 signature over the contents of a crate object file.
+///
+/// A signature is appended to the end of the crate object file that it signs,
+/// as a trailer consisting of the signature, the signer's public key, and [`CRATE_SIGNATURE_MAGIC`].
+/// ELF parsers ignore these trailing bytes, but any other modification to the object file
+/// invalidates its signature, so signing must be the final step of building a crate object file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CrateSignature {
+    /// The Ed25519 signature over the crate object file's contents, excluding this trailer.
+    pub signature: [u8; 64],
+    /// The Ed25519 public key of the signer.
+    pub public_key: [u8; 32],
+}
+
+impl CrateSignature {
+    /// The length of the trailer that holds a `CrateSignature` at the end of a crate object file.
+    pub const TRAILER_LEN: usize = 64 + 32 + CRATE_SIGNATURE_MAGIC.len();
+
+    /// Splits the given crate object file into its signed contents and its signature,
+    /// or returns the entire file and `None` if it isn't signed.
+    pub fn split(file: &[u8]) -> (&[u8], Option<CrateSignature>) {
+        if file.len() < Self::TRAILER_LEN || !file.ends_with(&CRATE_SIGNATURE_MAGIC) {
+            return (file, None);
+        }
+        let (contents, trailer) = file.split_at(file.len() - Self::TRAILER_LEN);
+        let mut signature = CrateSignature { signature: [0; 64], public_key: [0; 32] };
+        signature.signature.copy_from_slice(&trailer[..64]);
+        signature.public_key.copy_from_slice(&trailer[64..96]);
+        (contents, Some(signature))
+    }
+
+    /// Returns the trailer that should be appended to the signed crate object file.
+    pub fn to_trailer(&self) -> [u8; Self::TRAILER_LEN] {
+        let mut trailer = [0; Self::TRAILER_LEN];
+        trailer[..64].copy_from_slice(&self.signature);
+        trailer[64..96].copy_from_slice(&self.public_key);
+        trailer[96..].copy_from_slice(&CRATE_SIGNATURE_MAGIC);
+        trailer
+    }
+}
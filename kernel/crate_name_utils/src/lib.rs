@@ -14,7 +14,7 @@ use alloc::{
 };
 use itertools::Itertools;
 use path::Path;
-use crate_metadata::CrateType;
+use crate_metadata::{CrateType, CRATE_HASH_DELIMITER};
 
 
 
@@ -34,6 +34,26 @@ pub fn crate_name_from_path<'p>(object_file_path: &'p Path) -> &'p str {
     }
 }
 
+/// Returns the given crate name without its trailing hash and without any module file prefix.
+///
+/// Only a trailing part that consists of hexadecimal digits is considered to be a hash,
+/// such that a crate name that itself contains a `"-"` is preserved.
+///
+/// # Examples
+/// * `"my_crate-d6a9b7f3c2e8a1b0"` -> `"my_crate"`
+/// * `"k#my_crate-d6a9b7f3c2e8a1b0"` -> `"my_crate"`
+/// * `"my-crate"` -> `"my-crate"`
+pub fn crate_name_without_hash(crate_name: &str) -> &str {
+    let name = match CrateType::from_module_name(crate_name) {
+        Ok((_crate_type, _prefix, name)) => name,
+        Err(_) => crate_name,
+    };
+    match name.rsplit_once(CRATE_HASH_DELIMITER) {
+        Some((name_without_hash, hash)) if !hash.is_empty() && hash.chars().all(|c| c.is_ascii_hexdigit()) => name_without_hash,
+        _ => name,
+    }
+}
+
 /// Crate names must be only alphanumeric characters, an underscore, or a dash.
 ///  
 /// See: <https://www.reddit.com/r/rust/comments/4rlom7/what_characters_are_allowed_in_a_crate_name/>
@@ -0,0 +1,130 @@
+//! Verifies the Ed25519 signatures of crate object files against a set of trusted keys.
+//!
+//! The trusted keys are listed in this crate's `trusted_keys.txt` file, which is built into
+//! the `nano_core` such that they cannot be changed by loading or swapping crates.
+//! Crate object files are signed at build time by `tools/sign_crates`,
+//! which appends a [`CrateSignature`] trailer to each object file.
+//!
+//! Unsigned or incorrectly-signed crates are handled according to the system-wide [`SignaturePolicy`],
+//! which is also chosen at build time and cannot be changed at runtime.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use ed25519_compact::{PublicKey, Signature};
+use log::{error, warn};
+use spin::Once;
+pub use crate_metadata_serde::CrateSignature;
+
+/// The contents of the trusted keys file, one hex-encoded Ed25519 public key per line.
+const TRUSTED_KEYS_FILE: &str = include_str!("../trusted_keys.txt");
+
+static TRUSTED_KEYS: Once<Vec<[u8; 32]>> = Once::new();
+
+/// Returns the Ed25519 public keys that are trusted to sign crate object files.
+pub fn trusted_keys() -> &'static [[u8; 32]] {
+    TRUSTED_KEYS.call_once(|| {
+        TRUSTED_KEYS_FILE.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let key = parse_hex_key(line);
+                if key.is_none() {
+                    warn!("Ignoring invalid trusted crate signing key {:?}", line);
+                }
+                key
+            })
+            .collect()
+    })
+}
+
+fn parse_hex_key(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = [0; 32];
+    for (byte, digits) in key.iter_mut().zip(hex.as_bytes().chunks_exact(2)) {
+        let digits = core::str::from_utf8(digits).ok()?;
+        *byte = u8::from_str_radix(digits, 16).ok()?;
+    }
+    Some(key)
+}
+
+
+/// What to do when a crate object file is unsigned or its signature can't be verified.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignaturePolicy {
+    /// Don't check crate signatures at all.
+    Ignore,
+    /// Log a warning and continue.
+    Warn,
+    /// Return an error, refusing to load the crate.
+    Require,
+}
+
+/// Returns the system-wide [`SignaturePolicy`].
+///
+/// This is chosen at build time with the `signature_policy_ignore`, `signature_policy_warn`,
+/// or `signature_policy_require` cfg options, e.g., via `make signature_policy=<POLICY>`.
+/// By default, this is `Require` if any trusted keys were built into the `nano_core`, or `Ignore` otherwise.
+pub fn signature_policy() -> SignaturePolicy {
+    if cfg!(signature_policy_ignore) {
+        SignaturePolicy::Ignore
+    } else if cfg!(signature_policy_warn) {
+        SignaturePolicy::Warn
+    } else if cfg!(signature_policy_require) || !trusted_keys().is_empty() {
+        SignaturePolicy::Require
+    } else {
+        SignaturePolicy::Ignore
+    }
+}
+
+
+/// Checks whether the given crate object file is signed by a trusted key,
+/// regardless of the current [`SignaturePolicy`].
+///
+/// Returns the public key of the signer if the signature is valid.
+pub fn check_signature(crate_object_file: &[u8]) -> Result<[u8; 32], &'static str> {
+    let (contents, signature) = CrateSignature::split(crate_object_file);
+    let signature = signature.ok_or("crate object file is not signed")?;
+    if !trusted_keys().contains(&signature.public_key) {
+        return Err("crate object file was signed by an untrusted key");
+    }
+    let public_key = PublicKey::new(signature.public_key);
+    public_key.verify(contents, &Signature::new(signature.signature))
+        .map_err(|_e| "crate object file's signature is invalid")?;
+    Ok(signature.public_key)
+}
+
+/// Verifies the signature of the given crate object file according to the system-wide [`SignaturePolicy`].
+///
+/// Returns an error if the policy is `Require` and the crate isn't signed by a trusted key.
+/// The `crate_name` is only used for logging.
+pub fn verify_crate_object(crate_object_file: &[u8], crate_name: &str) -> Result<(), &'static str> {
+    verify_crate_object_with_policy(crate_object_file, crate_name, signature_policy())
+}
+
+/// Same as [`verify_crate_object()`], but according to the given `policy`
+/// instead of the system-wide one, which is unaffected.
+pub fn verify_crate_object_with_policy(
+    crate_object_file: &[u8],
+    crate_name: &str,
+    policy: SignaturePolicy,
+) -> Result<(), &'static str> {
+    if policy == SignaturePolicy::Ignore {
+        return Ok(());
+    }
+    match check_signature(crate_object_file) {
+        Ok(_) => Ok(()),
+        Err(e) if policy == SignaturePolicy::Warn => {
+            warn!("Crate {:?} failed signature verification: {}", crate_name, e);
+            Ok(())
+        }
+        Err(e) => {
+            error!("Refusing to load crate {:?}: {}", crate_name, e);
+            Err(e)
+        }
+    }
+}
@@ -0,0 +1,470 @@
+//! Named checkpoints of a `CrateNamespace` that can later be restored.
+//!
+//! A checkpoint records the crates loaded into a namespace (not its recursive namespace),
+//! the namespace's symbol map, and the relocations in each of those crates' sections.
+//! The checkpoint is a copy-on-write clone of the namespace (see [`CrateNamespace::clone_on_write()`]),
+//! which keeps the checkpointed crates in memory and marks them as shared,
+//! which also prevents them from being unloaded while the checkpoint exists.
+//! A shared crate can still be swapped out, and swapped back in as long as its reexported symbols needn't change.
+//!
+//! Restoring a checkpoint uses `swap_crates()` to replace each crate that has been swapped out since the checkpoint
+//! with its checkpointed version, just like swapping a previously-cached crate back in.
+//! Thus, every dependency on the current crate is redirected to the restored crate,
+//! and the contents of the current crate's `.data` and `.bss` sections are carried over as with any other swap.
+//! Crates that were loaded after the checkpoint are then unloaded if nothing refers to them,
+//! and the checkpointed relocations and symbols are restored wherever they still differ.
+//! The relocations in crates loaded after the checkpoint that couldn't be unloaded are redirected
+//! to the checkpointed sections of the same name (without the hash), too.
+//! Once the checkpointed crates have been swapped in, restoring can no longer fail,
+//! so any relocation that can't be redirected is logged and counted rather than aborting the restore.
+//!
+//! A current crate is matched to its checkpointed version by its name without the hash,
+//! so a crate that was swapped for a crate with a different name is not swapped back.
+
+use core::time::Duration;
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use spin::Mutex;
+use memory::MmiRef;
+use fs_node::FsNode;
+use mod_mgmt::{
+    CrateNamespace,
+    IntoCrateObjectFile,
+    LoadedSection,
+    RelocationEntry,
+    StrongCrateRef,
+    StrongSectionRef,
+    WeakDependent,
+    WeakSectionRef,
+    crate_name_from_path,
+    unload::UnloadedCrates,
+};
+use path::Path;
+use sleep;
+use quiescence::{self, OldCrateText};
+use super::{
+    QuiescencePolicy,
+    SwapRequest,
+    SwapRequestList,
+    UNLOADED_CRATE_CACHE,
+    replace_strong_dependency,
+    rewrite_relocation,
+    swap_crates,
+};
+
+
+lazy_static! {
+    /// The checkpoints that have been taken, keyed by name.
+    static ref CHECKPOINTS: Mutex<BTreeMap<String, Arc<Checkpoint>>> = Mutex::new(BTreeMap::new());
+}
+
+/// The recorded state of a `CrateNamespace` at the time a checkpoint was taken.
+struct Checkpoint {
+    name: String,
+    namespace: Arc<CrateNamespace>,
+    /// The checkpointed crates and symbol map.
+    snapshot: CrateNamespace,
+    /// Each section in the checkpointed crates that depends on other sections,
+    /// along with the section that each of its relocations referred to.
+    relocations: Vec<(StrongSectionRef, Vec<(RelocationEntry, WeakSectionRef)>)>,
+    time: Duration,
+}
+
+impl Checkpoint {
+    fn info(&self) -> CheckpointInfo {
+        CheckpointInfo {
+            name: self.name.clone(),
+            namespace: self.namespace.name().to_string(),
+            crates: self.snapshot.crate_names(false).iter().map(|crate_name| crate_name.to_string()).collect(),
+            symbols: self.snapshot.symbol_map().lock().iter().count(),
+            relocations: self.relocations.iter().map(|(_sec, deps)| deps.len()).sum(),
+            time: self.time,
+        }
+    }
+
+    /// Returns the addresses of every section in the checkpointed crates.
+    fn section_ptrs(&self) -> BTreeSet<*const LoadedSection> {
+        let mut section_ptrs = BTreeSet::new();
+        self.snapshot.for_each_crate(false, |_crate_name, crate_ref| {
+            section_ptrs.extend(crate_ref.lock_as_ref().sections.values().map(Arc::as_ptr));
+            true
+        });
+        section_ptrs
+    }
+}
+
+/// A description of a checkpoint, as returned by [`checkpoints()`].
+#[derive(Clone, Debug)]
+pub struct CheckpointInfo {
+    /// The name of the checkpoint.
+    pub name: String,
+    /// The name of the checkpointed namespace.
+    pub namespace: String,
+    /// The names of the checkpointed crates.
+    pub crates: Vec<String>,
+    /// The number of symbols in the checkpointed symbol map.
+    pub symbols: usize,
+    /// The number of relocations recorded in the checkpointed crates.
+    pub relocations: usize,
+    /// The time at which the checkpoint was taken, since the TSC was reset.
+    pub time: Duration,
+}
+
+/// The changes made by [`restore_checkpoint()`].
+#[derive(Debug, Default)]
+pub struct RestoredCheckpoint {
+    /// The crates that were swapped out, paired with the checkpointed crates that were swapped in to replace them.
+    pub swapped_crates: Vec<(String, String)>,
+    /// The checkpointed crates that had been removed from the namespace and were added back to it.
+    pub readded_crates: Vec<String>,
+    /// The crates loaded after the checkpoint that were unloaded.
+    pub unloaded: UnloadedCrates,
+    /// The crates loaded after the checkpoint that couldn't be unloaded, e.g., because other crates depend on them.
+    pub remaining_crates: Vec<String>,
+    /// The number of symbols that were changed back to refer to their checkpointed sections.
+    pub restored_symbols: usize,
+    /// The number of relocations that were rewritten to refer to their checkpointed sections again.
+    pub repaired_relocations: usize,
+    /// The number of relocations in the checkpointed crates that still refer to a different section than at the checkpoint,
+    /// because the checkpointed section isn't in one of the checkpointed crates, e.g., it was in the recursive namespace.
+    pub changed_relocations: usize,
+    /// The number of relocations that couldn't be rewritten to refer to their checkpointed sections,
+    /// which still refer to the current sections because the checkpointed crates had already been swapped in.
+    pub failed_relocations: usize,
+}
+
+
+/// Takes a checkpoint of the given `namespace` under the given `name`,
+/// which can later be restored with [`restore_checkpoint()`].
+///
+/// Only the crates in the given namespace itself are checkpointed, not those in its recursive namespace.
+/// Returns an error if a checkpoint with the same name already exists.
+pub fn checkpoint_namespace(namespace: &Arc<CrateNamespace>, name: &str) -> Result<CheckpointInfo, &'static str> {
+    if name.is_empty() {
+        return Err("checkpoint name must not be empty");
+    }
+    let mut checkpoints = CHECKPOINTS.lock();
+    if checkpoints.contains_key(name) {
+        return Err("a checkpoint with the given name already exists");
+    }
+
+    let snapshot = namespace.clone_on_write();
+    let mut relocations = Vec::new();
+    snapshot.for_each_crate(false, |_crate_name, crate_ref| {
+        for sec in crate_ref.lock_as_ref().sections.values() {
+            let deps: Vec<(RelocationEntry, WeakSectionRef)> = sec.inner.read().sections_i_depend_on.iter()
+                .map(|strong_dep| (strong_dep.relocation, Arc::downgrade(&strong_dep.section)))
+                .collect();
+            if !deps.is_empty() {
+                relocations.push((Arc::clone(sec), deps));
+            }
+        }
+        true
+    });
+
+    let checkpoint = Checkpoint {
+        name: name.to_string(),
+        namespace: Arc::clone(namespace),
+        snapshot,
+        relocations,
+        time: sleep::get_current_time(),
+    };
+    let info = checkpoint.info();
+    #[cfg(not(loscd_eval))]
+    info!("Took checkpoint {:?} of namespace {:?} with {} crates", name, info.namespace, info.crates.len());
+    checkpoints.insert(name.to_string(), Arc::new(checkpoint));
+    Ok(info)
+}
+
+
+/// Returns a description of every checkpoint, ordered by name.
+pub fn checkpoints() -> Vec<CheckpointInfo> {
+    CHECKPOINTS.lock().values().map(|checkpoint| checkpoint.info()).collect()
+}
+
+
+/// Removes the checkpoint with the given name, allowing its crates to be unloaded once they're no longer used.
+///
+/// Returns a description of the removed checkpoint, if it existed.
+pub fn remove_checkpoint(name: &str) -> Option<CheckpointInfo> {
+    CHECKPOINTS.lock().remove(name).map(|checkpoint| checkpoint.info())
+}
+
+
+/// Restores the namespace of the checkpoint with the given name to the state it was in when the checkpoint was taken;
+/// see the [module-level docs](crate::checkpoint) for details.
+///
+/// The checkpoint is kept, so it can be restored again later.
+///
+/// # Arguments
+/// * `name`: the name of the checkpoint to restore.
+/// * `kernel_mmi_ref`: a reference to the kernel's `MemoryManagementInfo`.
+/// * `verbose_log`: enable verbose logging.
+/// * `quiescence`: how to handle tasks that are executing within the crates being swapped out or unloaded.
+///
+/// If swapping the checkpointed crates back in fails, nothing is changed and the error is returned.
+/// Crates loaded after the checkpoint that can't be unloaded (or aren't quiescent) are left in place
+/// and listed in the returned [`RestoredCheckpoint`].
+pub fn restore_checkpoint(
+    name: &str,
+    kernel_mmi_ref: &MmiRef,
+    verbose_log: bool,
+    quiescence: QuiescencePolicy,
+) -> Result<RestoredCheckpoint, &'static str> {
+    let checkpoint = CHECKPOINTS.lock().get(name).cloned().ok_or("no checkpoint exists with the given name")?;
+    let namespace = &checkpoint.namespace;
+    let mut restored = RestoredCheckpoint::default();
+
+    // Find the current crates that aren't the checkpointed crates, and vice versa.
+    let mut unmatched_current: Vec<(String, String)> = Vec::new();
+    let mut unmatched_checkpointed: Vec<StrongCrateRef> = Vec::new();
+    {
+        let current_tree = namespace.crate_tree().lock();
+        let checkpointed_tree = checkpoint.snapshot.crate_tree().lock();
+        for (crate_name, crate_ref) in current_tree.iter() {
+            if !checkpointed_tree.get(crate_name.as_bytes()).map_or(false, |cp_crate| cp_crate.ptr_eq(crate_ref)) {
+                let name_without_hash = crate_ref.lock_as_ref().crate_name_without_hash().to_string();
+                unmatched_current.push((crate_name.to_string(), name_without_hash));
+            }
+        }
+        for (crate_name, crate_ref) in checkpointed_tree.iter() {
+            if !current_tree.get(crate_name.as_bytes()).map_or(false, |cur_crate| cur_crate.ptr_eq(crate_ref)) {
+                unmatched_checkpointed.push(crate_ref.clone());
+            }
+        }
+    }
+
+    // Each checkpointed crate replaces the current crate with the same name (without the hash), if one exists.
+    // The checkpointed crates are placed in the cache of unloaded crates such that `swap_crates()` swaps them in directly.
+    let mut swap_requests = SwapRequestList::new();
+    let cached_crates = CrateNamespace::new(format!("checkpoint--{}", checkpoint.name), namespace.dir().clone(), None);
+    let mut readded_crates = Vec::new();
+    for cp_crate in unmatched_checkpointed {
+        let (name_without_hash, object_file, reexport_new_symbols_as_old) = {
+            let krate = cp_crate.lock_as_ref();
+            (krate.crate_name_without_hash().to_string(), krate.object_file.clone(), !krate.reexported_symbols.is_empty())
+        };
+        let current_name = match unmatched_current.iter().position(|(_name, nwh)| *nwh == name_without_hash) {
+            Some(i) => unmatched_current.remove(i).0,
+            None => {
+                readded_crates.push(cp_crate);
+                continue;
+            }
+        };
+        let swap_request = SwapRequest::new(
+            Some(current_name.as_str()),
+            Arc::clone(namespace),
+            IntoCrateObjectFile::File(object_file.clone()),
+            Some(Arc::clone(namespace)),
+            reexport_new_symbols_as_old,
+        ).map_err(|_e| {
+            error!("restore_checkpoint(): couldn't create swap request for crate {:?}: {:?}", current_name, _e);
+            "couldn't create a swap request to restore a checkpointed crate"
+        })?;
+        let cp_crate_name = crate_name_from_path(&Path::new(object_file.lock().get_name())).to_string();
+        cached_crates.add_symbols(cp_crate.lock_as_ref().sections.values(), verbose_log);
+        cached_crates.crate_tree().lock().insert(cp_crate_name.as_str().into(), cp_crate);
+        restored.swapped_crates.push((current_name, cp_crate_name));
+        swap_requests.push(swap_request);
+    }
+
+    // The optimized swap routine assumes that the cached crates' sections already know about their dependents,
+    // which isn't true of dependents added after the checkpoint, so collect those dependents before the swap.
+    let mut dependents: Vec<(WeakSectionRef, RelocationEntry)> = Vec::new();
+    for (current_name, _cp_crate_name) in &restored.swapped_crates {
+        if let Some(crate_ref) = namespace.get_crate(current_name) {
+            for sec in crate_ref.lock_as_ref().global_sections_iter() {
+                dependents.extend(sec.inner.read().sections_dependent_on_me.iter().map(|weak_dep| (weak_dep.section.clone(), weak_dep.relocation)));
+            }
+        }
+    }
+
+    if !swap_requests.is_empty() {
+        UNLOADED_CRATE_CACHE.lock().insert(swap_requests.clone(), cached_crates);
+        if let Err(e) = swap_crates(namespace, swap_requests.clone(), None, Vec::new(), kernel_mmi_ref, verbose_log, false, None, quiescence) {
+            // A failed swap puts the checkpointed crates back into the cache, where they shouldn't remain.
+            UNLOADED_CRATE_CACHE.lock().remove(&swap_requests);
+            return Err(e);
+        }
+    }
+
+    for cp_crate in readded_crates {
+        let crate_name = cp_crate.lock_as_ref().crate_name.clone();
+        #[cfg(not(loscd_eval))]
+        info!("restore_checkpoint(): adding crate {:?} back to namespace {:?}", crate_name, namespace.name());
+        namespace.add_symbols(cp_crate.lock_as_ref().sections.values(), verbose_log);
+        namespace.crate_tree().lock().insert(crate_name.clone(), cp_crate);
+        restored.readded_crates.push(crate_name.to_string());
+    }
+
+    let added_crates: Vec<String> = unmatched_current.into_iter().map(|(crate_name, _)| crate_name).collect();
+    if !added_crates.is_empty() {
+        let added_crate_texts: Vec<OldCrateText> = added_crates.iter()
+            .filter_map(|crate_name| namespace.get_crate(crate_name))
+            .filter_map(|crate_ref| OldCrateText::of(&crate_ref, Some(namespace)))
+            .collect();
+        match quiescence::wait_for_quiescence(&added_crate_texts, quiescence) {
+            Ok(_parked_tasks) => unload_crates(namespace, added_crates, &mut restored),
+            Err(_e) => {
+                warn!("restore_checkpoint(): not unloading crates loaded after the checkpoint: {}", _e);
+                restored.remaining_crates = added_crates;
+            }
+        }
+    }
+
+    let checkpointed_sections = checkpoint.section_ptrs();
+    for (target_sec, relocation) in dependents {
+        if let Some(target_sec) = target_sec.upgrade() {
+            if let Some(source_sec) = current_dependency(&target_sec, relocation) {
+                if checkpointed_sections.contains(&Arc::as_ptr(&source_sec)) {
+                    add_weak_dependent(&source_sec, &target_sec, relocation);
+                }
+            }
+        }
+    }
+
+    // Restore any relocations in the checkpointed crates that the swap didn't redirect back.
+    for (target_sec, deps) in &checkpoint.relocations {
+        for (relocation, checkpointed_source) in deps {
+            match (current_dependency(target_sec, *relocation), checkpointed_source.upgrade()) {
+                (Some(current_source), Some(checkpointed_source)) if Arc::ptr_eq(&current_source, &checkpointed_source) => { }
+                (Some(current_source), Some(checkpointed_source)) if checkpointed_sections.contains(&Arc::as_ptr(&checkpointed_source)) => {
+                    match redirect_relocation(target_sec, *relocation, &current_source, &checkpointed_source, kernel_mmi_ref, verbose_log) {
+                        Ok(()) => restored.repaired_relocations += 1,
+                        Err(_e) => {
+                            error!("restore_checkpoint(): couldn't redirect relocation {:?} in section {:?} to {:?}: {}",
+                                relocation, target_sec.name, checkpointed_source.name, _e);
+                            restored.failed_relocations += 1;
+                        }
+                    }
+                }
+                _ => restored.changed_relocations += 1,
+            }
+        }
+    }
+
+    // Crates loaded after the checkpoint that couldn't be unloaded may depend on sections that replaced checkpointed ones,
+    // e.g., in crates that were unloaded or swapped in after the checkpoint, so redirect them to the checkpointed sections.
+    // Sections are matched by name without the hash, as a crate swapped in after the checkpoint has different hashes.
+    let checkpointed_sections_by_name: BTreeMap<String, StrongSectionRef> = checkpoint.snapshot.symbol_map().lock().iter()
+        .filter_map(|(name, weak_sec)| weak_sec.upgrade().map(|sec| (name, sec)))
+        .filter(|(_name, sec)| checkpointed_sections.contains(&Arc::as_ptr(sec)))
+        .map(|(name, sec)| (LoadedSection::section_name_without_hash(name.as_str()).to_string(), sec))
+        .collect();
+    for crate_name in &restored.remaining_crates {
+        let Some(crate_ref) = namespace.get_crate(crate_name) else { continue };
+        let sections: Vec<StrongSectionRef> = crate_ref.lock_as_ref().sections.values().cloned().collect();
+        let own_sections: BTreeSet<*const LoadedSection> = sections.iter().map(Arc::as_ptr).collect();
+        for target_sec in &sections {
+            let deps: Vec<(RelocationEntry, StrongSectionRef)> = target_sec.inner.read().sections_i_depend_on.iter()
+                .filter(|strong_dep| {
+                    let source_ptr = Arc::as_ptr(&strong_dep.section);
+                    !checkpointed_sections.contains(&source_ptr) && !own_sections.contains(&source_ptr)
+                })
+                .map(|strong_dep| (strong_dep.relocation, Arc::clone(&strong_dep.section)))
+                .collect();
+            for (relocation, current_source) in deps {
+                let checkpointed_source = checkpointed_sections_by_name.get(current_source.name_without_hash());
+                if let Some(checkpointed_source) = checkpointed_source {
+                    match redirect_relocation(target_sec, relocation, &current_source, checkpointed_source, kernel_mmi_ref, verbose_log) {
+                        Ok(()) => restored.repaired_relocations += 1,
+                        Err(_e) => {
+                            error!("restore_checkpoint(): couldn't redirect relocation {:?} in section {:?} to {:?}: {}",
+                                relocation, target_sec.name, checkpointed_source.name, _e);
+                            restored.failed_relocations += 1;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Restore the symbols that refer to the checkpointed crates' sections.
+    {
+        let checkpointed_symbols = checkpoint.snapshot.symbol_map().lock();
+        let mut symbol_map = namespace.symbol_map().lock();
+        for (symbol, weak_sec) in checkpointed_symbols.iter() {
+            if !checkpointed_sections.contains(&Weak::as_ptr(weak_sec)) {
+                continue;
+            }
+            if symbol_map.get(symbol).map_or(true, |current| !Weak::ptr_eq(current, weak_sec)) {
+                symbol_map.insert(symbol.clone(), weak_sec.clone());
+                restored.restored_symbols += 1;
+            }
+        }
+    }
+
+    #[cfg(not(loscd_eval))]
+    info!("Restored checkpoint {:?} of namespace {:?}: {:?}", checkpoint.name, namespace.name(), restored);
+    Ok(restored)
+}
+
+
+/// Unloads as many of the given crates from the `namespace` as possible,
+/// recording which ones were unloaded and which ones remain in `restored`.
+fn unload_crates(namespace: &CrateNamespace, mut crate_names: Vec<String>, restored: &mut RestoredCheckpoint) {
+    // Unloading a crate may leave other given crates unreferenced, so repeat until no more can be unloaded.
+    loop {
+        let remaining = crate_names.len();
+        crate_names.retain(|crate_name| match namespace.unload_crate(crate_name) {
+            Ok(unloaded) => {
+                restored.unloaded.crate_names.extend(unloaded.crate_names);
+                restored.unloaded.memory += unloaded.memory;
+                false
+            }
+            Err(_e) => true,
+        });
+        if crate_names.len() == remaining {
+            break;
+        }
+    }
+    restored.remaining_crates = crate_names;
+}
+
+
+/// Rewrites the given `relocation` in the `target_sec` to refer to the `checkpointed_source` section
+/// instead of the `current_source` section, and updates the dependencies between them accordingly.
+fn redirect_relocation(
+    target_sec: &StrongSectionRef,
+    relocation: RelocationEntry,
+    current_source: &StrongSectionRef,
+    checkpointed_source: &StrongSectionRef,
+    kernel_mmi_ref: &MmiRef,
+    verbose_log: bool,
+) -> Result<(), &'static str> {
+    #[cfg(not(loscd_eval))]
+    debug!("restore_checkpoint(): redirecting relocation in {:?} from {:?} back to {:?}", target_sec.name, current_source.name, checkpointed_source.name);
+    rewrite_relocation(target_sec, relocation, checkpointed_source.virt_addr, kernel_mmi_ref, verbose_log)?;
+    replace_strong_dependency(target_sec, relocation, current_source, checkpointed_source);
+    current_source.inner.write().sections_dependent_on_me.retain(|weak_dep|
+        !(weak_dep.relocation == relocation && Weak::as_ptr(&weak_dep.section) == Arc::as_ptr(target_sec))
+    );
+    add_weak_dependent(checkpointed_source, target_sec, relocation);
+    Ok(())
+}
+
+
+/// Returns the section that the given `target_sec` currently depends on via the given `relocation`.
+fn current_dependency(target_sec: &LoadedSection, relocation: RelocationEntry) -> Option<StrongSectionRef> {
+    target_sec.inner.read().sections_i_depend_on.iter()
+        .find(|strong_dep| strong_dep.relocation == relocation)
+        .map(|strong_dep| Arc::clone(&strong_dep.section))
+}
+
+
+/// Adds the `target_sec` as a dependent of the `source_sec` via the given `relocation`, unless it already is one.
+fn add_weak_dependent(source_sec: &LoadedSection, target_sec: &StrongSectionRef, relocation: RelocationEntry) {
+    let mut source_inner = source_sec.inner.write();
+    let exists = source_inner.sections_dependent_on_me.iter()
+        .any(|weak_dep| weak_dep.relocation == relocation && Weak::as_ptr(&weak_dep.section) == Arc::as_ptr(target_sec));
+    if !exists {
+        source_inner.sections_dependent_on_me.push(WeakDependent {
+            section: Arc::downgrade(target_sec),
+            relocation,
+        });
+    }
+}
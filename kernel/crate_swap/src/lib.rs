@@ -1,5 +1,8 @@
 //! Defines functions and types for crate swapping, used in live evolution and fault recovery.
 //! 
+//! The [`checkpoint_namespace()`] and [`restore_checkpoint()`] functions build on crate swapping
+//! to return a namespace to a previously-recorded set of crates.
+//! 
 
 #![no_std]
 #![cfg_attr(loscd_eval, allow(unused_assignments, unused_variables))]
@@ -15,6 +18,10 @@ extern crate fs_node;
 extern crate qp_trie;
 extern crate path;
 extern crate by_address;
+extern crate sleep;
+extern crate task;
+extern crate stack_trace;
+extern crate state_transfer;
 
 #[cfg(loscd_eval)]
 extern crate hpet;
@@ -22,17 +29,18 @@ extern crate hpet;
 use core::{
     fmt,
     ops::Deref,
+    time::Duration,
 };
 use spin::Mutex;
 use alloc::{
     borrow::Cow,
     collections::BTreeSet,
     string::{String, ToString},
-    sync::Arc,
+    sync::{Arc, Weak},
     vec::Vec,
 };
 use hashbrown::HashMap;
-use memory::{EntryFlags, MmiRef};
+use memory::{EntryFlags, MmiRef, VirtualAddress};
 use fs_node::{FsNode, FileOrDir, FileRef, DirRef};
 use mod_mgmt::{
     CrateNamespace,
@@ -41,11 +49,26 @@ use mod_mgmt::{
     write_relocation,
     crate_name_from_path,
     replace_containing_crate_name,
+    LoadedSection,
+    RelocationEntry,
+    SectionType,
+    SECTION_HASH_DELIMITER,
+    StrongCrateRef,
     StrongSectionRef,
+    WeakSectionRef,
     WeakDependent, StrRef,
+    abi::{self, AbiCheckPolicy, AbiIncompatibility},
 };
 use path::Path;
 use by_address::ByAddress;
+use state_transfer::{MigratedStates, StateMigration, StateMigrationDescriptor, STATE_MIGRATION_SYMBOL};
+
+mod quiescence;
+pub use quiescence::{QuiescencePolicy, BlockingTask, BlockingReason};
+use quiescence::OldCrateText;
+
+mod checkpoint;
+pub use checkpoint::{CheckpointInfo, RestoredCheckpoint, checkpoint_namespace, checkpoints, remove_checkpoint, restore_checkpoint};
 
 
 lazy_static! {
@@ -72,16 +95,43 @@ pub fn clear_unloaded_crate_cache() {
 /// See the `swap_crates()` function for more details. 
 pub type StateTransferFunction = fn(&Arc<CrateNamespace>, &CrateNamespace) -> Result<(), &'static str>;
 
+/// A health check function is invoked repeatedly after swapping crates to determine 
+/// whether the newly swapped-in crates are working correctly.
+/// 
+/// It should return `Ok(true)` once the new crates are known to be healthy,
+/// `Ok(false)` if their health cannot be determined yet,
+/// or an error if the new crates are not working correctly. 
+/// 
+/// See the `swap_crates()` function for more details. 
+pub type HealthCheckFunction = fn() -> Result<bool, &'static str>;
+
+/// How long to wait between successive invocations of a [`HealthCheckFunction`].
+const HEALTH_CHECK_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A check that is run after new crates have been swapped in, 
+/// which causes the old crates to be swapped back in if it fails.
+#[derive(Clone, Debug)]
+pub struct HealthCheck {
+    /// The fully-qualified symbol name of the [`HealthCheckFunction`],
+    /// which should exist in the new crates (or the crates they depend on).
+    pub function: String,
+    /// How long to wait for the health check function to report that the new crates are healthy.
+    pub timeout: Duration,
+}
+
 
 /// Swaps in new crates that can optionally replace existing crates in this `CrateNamespace`.
 /// 
 /// See the documentation of the [`SwapRequest`](#struct.SwapRequest.html) struct for more details.
 /// 
-/// In general, the strategy for replacing an old crate `C` with a new crate `C2` consists of three steps:
+/// In general, the strategy for replacing an old crate `C` with a new crate `C2` consists of these steps:
 /// 1) Load the new replacement crate `C2` from its object file.
-/// 2) Copy the .data and .bss sections from old crate `C` to the new crate `C2`
-/// 3) Set up new relocation entries that redirect all dependencies on the old crate `C` to the new crate `C2`.
-/// 4) Remove crate `C` and clean it up, e.g., removing its entries from the symbol map.
+/// 2) Find every section in `C2` that corresponds to a section in `C` that other crates depend on,
+///    without modifying any running crates. If this fails, the swap is aborted and nothing is changed.
+/// 3) Copy the .data and .bss sections from old crate `C` to the new crate `C2`
+/// 4) Set up new relocation entries that redirect all dependencies on the old crate `C` to the new crate `C2`,
+///    invoke the state transfer functions, and run the state migrations declared in the new crates.
+/// 5) Remove crate `C` and clean it up, e.g., removing its entries from the symbol map.
 ///    Save the removed crate (and its symbol subtrie) in a cache for later use to expedite future swapping operations.
 /// 
 /// The given `CrateNamespace` is used as the backup namespace for resolving unknown symbols,
@@ -111,12 +161,53 @@ pub type StateTransferFunction = fn(&Arc<CrateNamespace>, &CrateNamespace) -> Re
 ///   Both namespaces may (and likely will) contain more crates than just the old and new crates specified in the swap request list.
 /// * `kernel_mmi_ref`: a reference to the kernel's `MemoryManagementInfo`.
 /// * `verbose_log`: enable verbose logging.
+/// * `cache_old_crates`: whether to save the old crates in a cache such that they can be quickly swapped back in later.
+/// * `health_check`: an optional [`HealthCheck`] that is run after the swap has completed.
+///   If it fails or times out, the old crates are swapped back in and an error is returned.
+///   The old crates are always cached when a health check is given, as they're needed to undo the swap.
+/// * `quiescence`: how to handle other tasks that are executing within the old crates;
+///   see the "Quiescence" section below.
+/// 
+/// # State migrations
+/// Every state migration declared with `state_transfer::state_migration!()` in the new crates
+/// (or the crates newly loaded alongside them) is run on the states registered in `state_transfer`,
+/// after the state transfer functions are invoked.
+/// The migrated states only replace the registered states once every migration has succeeded,
+/// and only if none of the states was replaced or removed while being migrated; otherwise, the swap is rolled back.
+/// The migrations run on a snapshot of the states, so changes that other tasks make to a state's contents
+/// in the meantime are lost, which quiescing those tasks avoids.
+/// If the swap is undone because of a failed health check, the previous versions of the states are restored.
+/// 
+/// # Rollback
+/// If an error occurs at any point before the old crates are removed,
+/// including an error returned by a state transfer function or a state migration,
+/// all relocations, dependencies, and symbols that were redirected to the new crates are restored to point to the old crates,
+/// the new crates are discarded, and the old crates continue to be used.
+/// State migrations are also discarded, but any other side effects of the state transfer functions are not undone.
+/// 
+/// Once the old crates are removed, the swap can no longer fail:
+/// any error in the remaining bookkeeping, e.g., moving the crate object files, is logged rather than returned.
+/// 
+/// Use [`plan_swap_crates()`] to see what a swap would change without actually performing it.
+/// 
+/// # ABI compatibility
+/// Before anything is changed, each new crate's ABI metadata is compared against that of the old crate it replaces.
+/// If the new crate removes or changes the type or size of a symbol that another crate depends on,
+/// and that dependent crate isn't also being replaced in the same list of `swap_requests`,
+/// the swap is rejected or a warning is logged, depending on the [`AbiCheckPolicy`].
+/// Crates without ABI metadata are not checked.
+/// 
+/// # Quiescence
+/// Before anything is changed, every other task whose namespace can reach the old crates is briefly suspended and its stack is walked
+/// to determine whether it is executing within (or will return into) one of the old crates.
+/// Depending on the [`QuiescencePolicy`], the swap is aborted immediately if any such task exists,
+/// or after waiting for those tasks to leave the old crates; the blocking tasks are logged in either case.
+/// With [`QuiescencePolicy::Park`], those tasks are kept suspended while the swap is being applied.
 /// 
 /// # Warning: Correctness not guaranteed
-/// This function currently makes no attempt to guarantee correct operation after a crate is swapped. 
-/// For example, if the new crate changes a function or data structure, there is no guarantee that 
-/// other crates will be swapped alongside that one. 
-/// It will most likely error out, but this responsibility is currently left to the caller.
+/// Beyond the above ABI check, this function makes no attempt to guarantee correct operation after a crate is swapped;
+/// for example, a function's behavior may change while its signature stays the same.
+/// A `health_check` can be used to detect and undo a swap that broke the system.
 /// 
 /// # Crate swapping optimizations
 /// When one or more crates is swapped out, they are not fully unloaded, but rather saved in a cache
@@ -129,14 +220,206 @@ pub fn swap_crates(
     state_transfer_functions: Vec<String>,
     kernel_mmi_ref: &MmiRef,
     verbose_log: bool,
-    cache_old_crates: bool
+    cache_old_crates: bool,
+    health_check: Option<HealthCheck>,
+    quiescence: QuiescencePolicy,
 ) -> Result<(), &'static str> {
+    // Undoing a swap after it completes requires the cache of old crates, which isn't used when evaluating swapping.
+    #[cfg(loscd_eval)] {
+        if health_check.is_some() {
+            return Err("swap_crates(): health checks are not supported in loscd_eval builds");
+        }
+    }
+
+    let outcome = swap_crates_internal(
+        this_namespace,
+        swap_requests,
+        override_namespace_dir,
+        state_transfer_functions,
+        kernel_mmi_ref,
+        verbose_log,
+        cache_old_crates || health_check.is_some(),
+        health_check.as_ref().map(|hc| hc.function.as_str()),
+        quiescence,
+        false,
+    )?;
+
+    let (health_check, reverse_requests, health_check_sec, migrated_states) = match (health_check, outcome) {
+        (Some(hc), SwapOutcome::Committed { reverse_requests: Some(rr), health_check_sec: Some(sec), migrated_states }) => (hc, rr, sec, migrated_states),
+        (None, SwapOutcome::Committed { .. }) => return Ok(()),
+        _ => return Err("BUG: swap_crates(): the swap didn't complete as expected"),
+    };
+
+    let health = run_health_check(&health_check_sec, health_check.timeout);
+    // Don't keep the health check function's crate alive in case it's swapped back out below.
+    drop(health_check_sec);
+    match health {
+        Ok(()) => {
+            // The old crates were only cached to allow undoing the swap, so remove them if caching wasn't requested.
+            if !cache_old_crates {
+                UNLOADED_CRATE_CACHE.lock().remove(&reverse_requests);
+            }
+            Ok(())
+        }
+        Err(e) => {
+            error!("swap_crates(): health check {:?} failed: {}. Swapping the old crates back in...", health_check.function, e);
+            swap_crates_internal(
+                this_namespace,
+                reverse_requests,
+                None,
+                Vec::new(),
+                kernel_mmi_ref,
+                verbose_log,
+                false,
+                None,
+                quiescence,
+                false,
+            ).map_err(|_e| {
+                error!("swap_crates(): failed to swap the old crates back in after a failed health check: {}", _e);
+                "new crates failed their health check, and the old crates couldn't be swapped back in"
+            })?;
+            // The old crates expect the previous versions of any migrated states,
+            // whereas the new crates still need the migrated states if they couldn't be swapped back out.
+            migrated_states.restore();
+            Err("new crates failed their health check and were swapped back out")
+        }
+    }
+}
+
+
+/// Returns the tasks, other than the current task, that are executing within (or will return into) any of the given crates.
+/// 
+/// This is the same check that `swap_crates()` uses to determine whether the old crates are quiescent,
+/// so each other task is briefly suspended in order to walk its stack.
+/// It can also be used to check whether a crate can be safely unloaded.
+pub fn tasks_executing_in(crates: &[StrongCrateRef]) -> Vec<BlockingTask> {
+    let crate_texts: Vec<OldCrateText> = crates.iter().filter_map(|crate_ref| OldCrateText::of(crate_ref, None)).collect();
+    quiescence::find_blocking_tasks(&crate_texts)
+}
+
+
+/// Determines what `swap_crates()` would do with the given arguments, without actually swapping any crates.
+/// 
+/// The new crates are loaded and every dependency on the old crates is resolved against them,
+/// so this returns the same errors that `swap_crates()` would return before modifying any running crates.
+/// The new crates are then discarded; no running crates are changed. 
+/// The returned plan also lists the tasks that are currently executing within the old crates,
+/// which other tasks are briefly suspended to determine.
+/// 
+/// See [`swap_crates()`] for a description of the arguments. 
+pub fn plan_swap_crates(
+    this_namespace: &Arc<CrateNamespace>,
+    swap_requests: SwapRequestList,
+    override_namespace_dir: Option<NamespaceDir>,
+    state_transfer_functions: Vec<String>,
+    kernel_mmi_ref: &MmiRef,
+    verbose_log: bool,
+    health_check: Option<&HealthCheck>,
+) -> Result<SwapPlan, &'static str> {
+    let outcome = swap_crates_internal(
+        this_namespace,
+        swap_requests,
+        override_namespace_dir,
+        state_transfer_functions,
+        kernel_mmi_ref,
+        verbose_log,
+        false,
+        health_check.map(|hc| hc.function.as_str()),
+        QuiescencePolicy::Ignore,
+        true,
+    )?;
+    match outcome {
+        SwapOutcome::Planned(plan) => Ok(plan),
+        SwapOutcome::Committed { .. } => Err("BUG: plan_swap_crates(): crates were swapped during a dry run"),
+    }
+}
+
+
+/// A description of the changes that `swap_crates()` would make, as returned by [`plan_swap_crates()`].
+#[derive(Debug)]
+pub struct SwapPlan {
+    /// Whether the new crates would be swapped in from the cache of previously swapped-out crates,
+    /// rather than being loaded from their object files.
+    pub from_cache: bool,
+    /// The changes for each swap request, in the order they were given.
+    pub crate_swaps: Vec<PlannedCrateSwap>,
+    /// Crates that the new crates depend on that would be newly loaded alongside them.
+    pub new_dependencies: Vec<String>,
+    /// The fully-qualified names of the state transfer functions that would be invoked, in order.
+    pub state_transfer_functions: Vec<String>,
+    /// The state migrations declared in the new crates, which would be run on the registered states.
+    pub state_migrations: Vec<StateMigration>,
+    /// The fully-qualified name of the health check function that would be invoked, if any.
+    pub health_check_function: Option<String>,
+    /// The tasks that were executing within the old crates when the plan was made,
+    /// which would block the swap unless the [`QuiescencePolicy`] is `Ignore`.
+    pub blocking_tasks: Vec<BlockingTask>,
+}
+
+/// The changes that `swap_crates()` would make for a single `SwapRequest`. 
+#[derive(Debug)]
+pub struct PlannedCrateSwap {
+    /// The name of the old crate that would be replaced, if any.
+    pub old_crate: Option<String>,
+    /// Whether the old crate is currently loaded. 
+    /// If not, only its object file would be replaced, and the rest of the fields are empty.
+    pub old_crate_loaded: bool,
+    /// The name of the new crate.
+    pub new_crate: String,
+    /// The name of the namespace that the new crate would be added to.
+    pub new_namespace: String,
+    /// The number of `.data` and `.bss` sections whose contents would be copied from the old crate to the new crate.
+    pub data_sections: usize,
+    /// The number of relocations in other sections that would be rewritten to point to the new crate.
+    pub relocations: usize,
+    /// The names of the crates that contain those rewritten relocations.
+    pub dependent_crates: BTreeSet<String>,
+    /// The number of the new crate's symbols that would be reexported under the old crate's symbol names.
+    pub reexported_symbols: usize,
+    /// The old crate's symbols that the new crate doesn't provide compatibly,
+    /// which would break the listed dependent crates unless the ABI check policy is `Warn` or `Ignore`.
+    pub abi_incompatibilities: Vec<AbiIncompatibility>,
+}
+
+
+/// The result of [`swap_crates_internal()`]. 
+enum SwapOutcome {
+    /// The swap was a dry run, so nothing was changed.
+    Planned(SwapPlan),
+    /// The new crates were swapped in.
+    Committed {
+        /// The swap requests that will swap the old crates back in from the cache, if they were cached.
+        reverse_requests: Option<SwapRequestList>,
+        /// The section of the requested health check function, if any.
+        health_check_sec: Option<StrongSectionRef>,
+        /// The previous versions of the states that were migrated, which are needed to undo the swap.
+        migrated_states: MigratedStates,
+    },
+}
+
+
+/// The implementation of `swap_crates()` and `plan_swap_crates()`. 
+/// 
+/// If `dry_run` is `true`, this returns after staging the swap without changing any running crates.
+fn swap_crates_internal(
+    this_namespace: &Arc<CrateNamespace>,
+    swap_requests: SwapRequestList,
+    override_namespace_dir: Option<NamespaceDir>,
+    state_transfer_functions: Vec<String>,
+    kernel_mmi_ref: &MmiRef,
+    verbose_log: bool,
+    cache_old_crates: bool,
+    health_check_symbol: Option<&str>,
+    quiescence: QuiescencePolicy,
+    dry_run: bool,
+) -> Result<SwapOutcome, &'static str> {
 
     #[cfg(not(loscd_eval))]
-    debug!("swap_crates()[0]: \n\t-->override dir: {:?}, \n\t-->cache_old_crates: {:?}, \n\t-->state transfer: {:?},\n\t-->swap_requests: {:?}", 
+    debug!("swap_crates()[0]: \n\t-->override dir: {:?}, \n\t-->cache_old_crates: {:?}, \n\t-->state transfer: {:?},\n\t-->dry run: {:?},\n\t-->swap_requests: {:?}", 
         override_namespace_dir.as_ref().map(|d| d.lock().get_name()), 
         cache_old_crates,
         state_transfer_functions,
+        dry_run,
         swap_requests
     );
 
@@ -192,6 +475,197 @@ pub fn swap_crates(
     #[cfg(loscd_eval)]
     let hpet_after_load_crates = hpet.get_counter();
 
+    // Before changing anything, find the new section that will replace each old section that other sections depend on.
+    // If any of them can't be found, the swap can't succeed, so we abort it here while the old crates are still untouched.
+    let staged = match stage_swap_requests(
+        this_namespace,
+        &swap_requests,
+        &namespace_of_new_crates,
+        is_optimized,
+        &state_transfer_functions,
+        health_check_symbol,
+        kernel_mmi_ref,
+        verbose_log,
+    ) {
+        Ok(staged) => staged,
+        Err(e) => {
+            return_to_cache(swap_requests, namespace_of_new_crates, is_optimized);
+            return Err(e);
+        }
+    };
+
+    #[cfg(loscd_eval)]
+    let hpet_total_symbol_finding = hpet.get_counter() - hpet_after_load_crates;
+
+    if dry_run {
+        let plan = staged.to_plan(&swap_requests, &namespace_of_new_crates, is_optimized);
+        drop(staged);
+        return_to_cache(swap_requests, namespace_of_new_crates, is_optimized);
+        return Ok(SwapOutcome::Planned(plan));
+    }
+
+    // Don't change the old crates while other tasks are executing within them.
+    // Parked tasks stay suspended until this function returns, i.e., after the swap is committed or rolled back.
+    let _parked_tasks = match quiescence::wait_for_quiescence(&staged.old_crate_texts(), quiescence) {
+        Ok(parked_tasks) => parked_tasks,
+        Err(e) => {
+            drop(staged);
+            return_to_cache(swap_requests, namespace_of_new_crates, is_optimized);
+            return Err(e);
+        }
+    };
+
+    #[cfg(loscd_eval)]
+    let mut hpet_total_rewriting_relocations = 0;
+    #[cfg(loscd_eval)]
+    let mut hpet_total_fixing_dependencies = 0;
+    #[cfg(loscd_eval)]
+    let mut hpet_total_bss_transfer = 0;
+
+    // Every change made to the old crates' dependents is recorded here, such that it can be undone if the swap fails.
+    let mut undo_log = UndoLog {
+        relocations: Vec::new(),
+        reexports: Vec::new(),
+        added_weak_dependents: !is_optimized,
+    };
+
+    // Now that we have loaded all of the new modules into the new namepsace in isolation,
+    // we simply need to fix up all of the relocations `WeakDependents` for each of the existing sections
+    // that depend on the old crate that we're replacing here,
+    // such that they refer to the new_module instead of the old_crate.
+    let mut apply_staged_swaps = || -> Result<MigratedStates, &'static str> {
+        for crate_swap in staged.crate_swaps.iter().flatten() {
+            #[cfg(loscd_eval)]
+            let hpet_start_bss_transfer = hpet.get_counter();
+
+            // Copy over the old crate's `.data` and `.bss` sections into the new crate's,
+            // as they represent static variables that would otherwise result in a loss of data.
+            for (old_sec, new_dest_sec) in &crate_swap.data_transfers {
+                #[cfg(not(loscd_eval))]
+                debug!("swap_crates(): copying .data or .bss section from old {:?} to new {:?}", &**old_sec, new_dest_sec);
+                old_sec.copy_section_data_to(new_dest_sec)?;
+            }
+
+            #[cfg(loscd_eval)] {
+                let hpet_end_bss_transfer = hpet.get_counter();
+                hpet_total_bss_transfer += hpet_end_bss_transfer - hpet_start_bss_transfer;
+            }
+
+            // scope the lock on the `new_crate_ref`
+            {
+                // A shared new crate (e.g., one swapped back in from the cache or a checkpoint) can't be modified,
+                // but staging ensured that its reexports don't need to change.
+                let mut new_crate = crate_swap.new_crate_ref.lock_as_mut();
+                if new_crate.is_none() && !crate_swap.reexports.is_empty() {
+                    return Err("BUG: swap_crates(): new_crate was unexpectedly shared in another namespace (couldn't get as exclusively mutable)...?");
+                }
+
+                // currently we're always clearing out the new crate's reexports because we recalculate them every time
+                if let Some(new_crate) = new_crate.as_mut() {
+                    new_crate.reexported_symbols.clear();
+                }
+
+                // reexport each new source section under the old sec's name, i.e., redirect the old mapping to the new source sec
+                for (old_sec_ns, reexported_name, new_source_sec) in &crate_swap.reexports {
+                    if let Some(new_crate) = new_crate.as_mut() {
+                        new_crate.reexported_symbols.insert(reexported_name.clone());
+                    }
+                    let old_val = old_sec_ns.symbol_map().lock().insert(reexported_name.clone(), Arc::downgrade(new_source_sec));
+                    if old_val.is_none() { 
+                        warn!("swap_crates(): reexported new crate section that replaces old section {:?}, but that old section unexpectedly didn't exist in the symbol map", reexported_name);
+                    }
+                    undo_log.reexports.push((*old_sec_ns, reexported_name.clone(), old_val));
+                }
+            }
+
+            for staged_relocation in &crate_swap.relocations {
+                let StagedRelocation { target_sec, relocation, old_source_sec, new_source_sec } = staged_relocation;
+
+                #[cfg(not(loscd_eval))]
+                debug!("    swap_crates(): target_sec: {:?}, old source sec: {:?}, new source sec: {:?}", &**target_sec, &**old_source_sec, &**new_source_sec);
+
+                #[cfg(loscd_eval)]
+                let start_rewriting_relocations = hpet.get_counter();
+
+                // Record this relocation before rewriting it, as a failed rewrite may have already changed it.
+                undo_log.relocations.push(staged_relocation.clone());
+                rewrite_relocation(target_sec, *relocation, new_source_sec.virt_addr, kernel_mmi_ref, verbose_log)?;
+
+                #[cfg(loscd_eval)] {
+                    let end_rewriting_relocations = hpet.get_counter();
+                    hpet_total_rewriting_relocations += end_rewriting_relocations - start_rewriting_relocations;
+                }
+
+                #[cfg(loscd_eval)]
+                let start_fixing_dependencies = hpet.get_counter();
+
+                // Tell the new source_sec that the existing target_sec depends on it.
+                // Note that we don't need to do this if we're re-swapping in a cached crate,
+                // because that crate's sections' dependents are already properly set up from when it was first swapped in.
+                if !is_optimized {
+                    new_source_sec.inner.write().sections_dependent_on_me.push(WeakDependent {
+                        section: Arc::downgrade(target_sec),
+                        relocation: *relocation,
+                    });
+                }
+
+                // Tell the existing target_sec that it no longer depends on the old source section (old_sec),
+                // and that it now depends on the new source_sec.
+                if !replace_strong_dependency(target_sec, *relocation, old_source_sec, new_source_sec) {
+                    error!("Couldn't find/remove the existing StrongDependency from target_sec {:?} to old_sec {:?}",
+                        target_sec.name, old_source_sec.name);
+                    return Err("Couldn't find/remove the target_sec's StrongDependency on the old crate section");
+                }
+
+                #[cfg(loscd_eval)] {
+                    let end_fixing_dependencies = hpet.get_counter();
+                    hpet_total_fixing_dependencies += end_fixing_dependencies - start_fixing_dependencies;
+                }
+            }
+
+            // Remove the old crate's dependents that no longer exist.
+            for old_sec in crate_swap.old_crate_ref.lock_as_ref().global_sections_iter() {
+                old_sec.inner.write().sections_dependent_on_me.retain(|weak_dep| weak_dep.section.strong_count() > 0);
+            }
+        }
+
+        // Execute the provided state transfer functions
+        for (symbol, state_transfer_fn_sec) in &staged.state_transfer_functions {
+            // FIXME SAFETY: None. swap_crates should probably be unsafe as there is no guaranteed that the state transfer functions have the correct signature.
+            let st_fn = unsafe { state_transfer_fn_sec.as_func::<StateTransferFunction>() }?;
+            #[cfg(not(loscd_eval))]
+            debug!("swap_crates(): invoking the state transfer function {:?} with old_ns: {:?}, new_ns: {:?}", symbol, this_namespace.name(), namespace_of_new_crates.name());
+            st_fn(this_namespace, &namespace_of_new_crates)?;
+        }
+
+        // Run the state migrations on a snapshot of the registered states, without holding the registry's lock,
+        // as the tasks parked by this swap may be waiting for it. Committing them is the last step that can fail:
+        // it fails if a migrated state was replaced in the meantime, in which case the swap is rolled back.
+        let state_migrations: Vec<StateMigration> = staged.state_migrations.iter().map(|(_sec, migration)| *migration).collect();
+        let prepared_migrations = state_transfer::prepare_migrations(&state_migrations)?;
+        #[cfg(not(loscd_eval))]
+        for (name, version) in prepared_migrations.migrated_states() {
+            info!("swap_crates(): migrated state {:?} to version {}", name, version);
+        }
+        prepared_migrations.commit()
+    };
+
+    let migrated_states = match apply_staged_swaps() {
+        Ok(migrated_states) => migrated_states,
+        Err(e) => {
+            error!("swap_crates(): swap failed, restoring the old crates' dependents. Error: {}", e);
+            undo_log.roll_back(kernel_mmi_ref, verbose_log);
+            drop(staged);
+            return_to_cache(swap_requests, namespace_of_new_crates, is_optimized);
+            return Err(e);
+        }
+    };
+
+    // The swap can no longer be undone from this point on, as the old crates are now removed.
+    let StagedSwap { new_crate_names, crate_swaps, state_transfer_functions: _, state_migrations: _, health_check_sec } = staged;
+    let old_crates_are_loaded: Vec<bool> = crate_swaps.iter().map(Option::is_some).collect();
+    drop(crate_swaps);
+
     #[cfg(not(loscd_eval))]
     let (mut future_swap_requests, cached_crates) = if cache_old_crates {
         (
@@ -208,25 +682,351 @@ pub fn swap_crates(
     };
 
 
+    // Remove all of the old crates now that we're fully done using them.
+    // This doesn't mean each crate will be immediately dropped -- they still might be in use by other crates or tasks.
+    for ((req, new_crate_name), is_old_crate_loaded) in swap_requests.iter().zip(new_crate_names.iter()).zip(old_crates_are_loaded.iter()) {
+        if !is_old_crate_loaded { continue; }
+        let SwapRequest { old_crate_name, old_namespace, new_crate_object_file: _, new_namespace, reexport_new_symbols_as_old } = req;
+        let old_crate_name = match old_crate_name {
+            Some(ocn) => ocn,
+            _ => continue,
+        };
+        // Remove the old crate from the namespace that it was previously in, and remove its sections' symbols too.
+        if let Some(old_crate_ref) = old_namespace.crate_tree().lock().remove(old_crate_name.as_bytes()) {
+            // Unless the old crate may be swapped back in later, e.g., from the cache or a checkpoint,
+            // it will never be executed again, so its lazily-linked functions can be freed.
+            if !cache_old_crates && !old_crate_ref.is_shared() {
+                mod_mgmt::lazy::free_lazy_functions(&old_crate_ref);
+            }
+            {
+                let old_crate = old_crate_ref.lock_as_ref();
+
+                core::mem::forget(old_crate_ref.clone());
+
+
+                #[cfg(not(loscd_eval))]
+                info!("  Removed old crate {:?} ({:?}) from namespace {}", old_crate_name, &*old_crate, old_namespace.name());
+
+                if cache_old_crates {
+                    #[cfg(not(loscd_eval))]
+                    {
+                        // Here, we setup the crate cache to enable the removed old crate to be quickly swapped back in in the future.
+                        // This removed old crate will be useful when a future swap request includes the following:
+                        // (1) the future `new_crate_object_file`        ==  the current `old_crate.object_file`
+                        // (2) the future `old_crate_name`               ==  the current `new_crate_name`
+                        // (3) the future `reexport_new_symbols_as_old`  ==  true if the old crate had any reexported symbols
+                        //     -- to understand this, see the docs for `LoadedCrate.reexported_prefix`
+                        let future_swap_req = SwapRequest {
+                            old_crate_name: Some(new_crate_name.clone()),
+                            old_namespace: ByAddress(Arc::clone(new_namespace)),
+                            new_crate_object_file: ByAddress(old_crate.object_file.clone()),
+                            new_namespace: ByAddress(Arc::clone(old_namespace)),
+                            reexport_new_symbols_as_old: !old_crate.reexported_symbols.is_empty(),
+                        };
+                        future_swap_requests.push(future_swap_req);
+                    }
+                }
+                
+                // Remove all of the symbols belonging to the old crate from the namespace it was in.
+                // If reexport_new_symbols_as_old is true, we MUST NOT remove the old_crate's symbols from this symbol map,
+                // because we already replaced them above with mappings that redirect to the corresponding new crate sections.
+                if !reexport_new_symbols_as_old {
+                    let mut old_ns_symbol_map = old_namespace.symbol_map().lock();
+                    for old_sec in old_crate.global_sections_iter() {
+                        if old_ns_symbol_map.remove(&old_sec.name).is_none() {
+                            error!("swap_crates(): couldn't find old symbol {:?} in the old crate's namespace: {}.", old_sec.name, old_namespace.name());
+                        }
+                    }
+                }
+
+                // If the old crate had reexported its symbols, we should remove those reexports here,
+                // because they're no longer active since the old crate is being removed. 
+                for sym in &old_crate.reexported_symbols {
+                    let _old_reexported_symbol = old_namespace.symbol_map().lock().remove(sym);
+                    if _old_reexported_symbol.is_none() {
+                        warn!("swap_crates(): the old_crate {:?}'s reexported symbol was not in its old namespace, couldn't be removed.", sym);
+                    }
+                }
+
+                if cache_old_crates {
+                    // TODO: could maybe optimize transfer of old symbols from this namespace to cached_crates namespace 
+                    //       by saving the removed symbols above and directly adding them to the cached_crates.symbol_map instead of iterating over all old_crate.sections.
+                    //       This wil only really be faster once qp_trie supports a non-iterator-based (non-extend) Trie merging function.
+                    #[cfg(not(loscd_eval))]
+                    cached_crates.add_symbols(old_crate.sections.values(), verbose_log); 
+                }
+            } // drops lock for `old_crate_ref`
+            
+            if cache_old_crates {
+                #[cfg(not(loscd_eval))]
+                cached_crates.crate_tree().lock().insert(old_crate_name.as_str().into(), old_crate_ref);
+            }
+
+            #[cfg(loscd_eval)]
+            core::mem::forget(old_crate_ref);
+        }
+        else {
+            error!("swap_crates(): couldn't remove old crate {} from old namespace {}!", old_crate_name, old_namespace.name());
+            continue;
+        }
+    }
+
     #[cfg(loscd_eval)]
-    let mut hpet_total_symbol_finding = 0;
-    #[cfg(loscd_eval)]
-    let mut hpet_total_rewriting_relocations = 0;
-    #[cfg(loscd_eval)]
-    let mut hpet_total_fixing_dependencies = 0;
+    let start_symbol_cleanup = hpet.get_counter();
+
+    // Here, we move all of the new crates into the actual new namespace where they belong. 
+    for ((req, new_crate_name), is_old_crate_loaded) in swap_requests.iter().zip(new_crate_names.iter()).zip(old_crates_are_loaded.iter()) {
+        // We only expect the new crate to have been loaded into the temp namespace if the old crate was actually loaded in the old namespace
+        if !is_old_crate_loaded { continue; }
+        let new_crate_ref = match namespace_of_new_crates.crate_tree().lock().remove(new_crate_name.as_bytes()) {
+            Some(new_crate_ref) => new_crate_ref,
+            None => {
+                error!("BUG: swap_crates(): new crate {:?} specified by swap request was not found in the new namespace", new_crate_name);
+                continue;
+            }
+        };
+        
+        #[cfg(not(loscd_eval))]
+        debug!("swap_crates(): adding new crate {:?} to namespace {}", new_crate_ref, req.new_namespace.name());
+
+        req.new_namespace.add_symbols(new_crate_ref.lock_as_ref().sections.values(), verbose_log);
+        req.new_namespace.crate_tree().lock().insert(new_crate_name.as_str().into(), new_crate_ref.clone());
+    }
+    
+    // Other crates may have been loaded from their object files into the `namespace_of_new_crates` as dependendencies (required by the new crates specified by swap requests).
+    // Thus, we need to move all **newly-loaded** crates from the `namespace_of_new_crates` into the proper new namespace;
+    // for this, we add only the non-shared (exclusive) crates, because shared crates are those that were previously loaded (and came from the backup namespace).
+    namespace_of_new_crates.for_each_crate(true, |new_crate_name, new_crate_ref| {
+        if !new_crate_ref.is_shared() {
+            // TODO FIXME: we may not want to add the new crate to this namespace, we might want to add it to one of its recursive namespaces
+            //             (e.g., `this_namespace` could be an application ns, but the new_crate could be a kernel crate that belongs in `this_namespaces`'s recursive kernel namespace).
+            //
+            // To infer which actual namespace this newly-loaded crate should be added to (we don't want to add kernel crates to an application namespace),
+            // we could iterate over all of the new crates in the swap requests that transitively depend on this new_crate,
+            // and then look at which `new_namespace` those new crates in the swap requests were destined for.
+            // Then, we use the highest-level namespace that we can, but it cannot be higher than any one `new_namespace'.
+            // 
+            // As an example, suppose there are 3 new crates from the swap request list that depend on this new_crate_ref here. 
+            // If two of those were application crates that belong to a higher-level "applications" namespace (their `new_namespace`), 
+            // but one of them was a kernel crate that belonged to a lower "kernel" namespace that was found in that "applications" namespace recursive children, 
+            // the highest-level namespace we could add this `new_crate_ref` to would be that "kernel" namespace,
+            // meaning that we had inferred that this `new_crate_ref` was also a kernel crate that belonged in the "kernel" namespace.
+            //
+            // This follows the rule that crates in a lower-level namespace cannot depend on those in a higher-level namespace. 
+            //
+            // Note that we don't just want to put the crate in the lowest namespace we can, 
+            // because that could result in putting an application crate in a kernel namespace. 
+            // 
+            let mut target_ns = this_namespace;
+
+            // FIXME: currently we use a hack to determine which namespace this freshly-loaded crate should be added to,
+            //        based on which directory its object file 
+            {
+                let objfile_path = Path::new(new_crate_ref.lock_as_ref().object_file.lock().get_absolute_path());
+                if objfile_path.components().skip(1).next() == Some(mod_mgmt::CrateType::Kernel.default_namespace_name()) {
+                    let new_target_ns = this_namespace.recursive_namespace().unwrap_or(this_namespace);
+                    #[cfg(not(loscd_eval))]
+                    warn!("temp fix: changing target_ns from {} to {}, for crate {:?}", this_namespace.name(), new_target_ns.name(), new_crate_ref);
+                    target_ns = new_target_ns;
+                }
+
+            }
+
+            // #[cfg(not(loscd_eval))]
+            // warn!("swap_crates(): untested scenario of adding new non-requested (dependency) crate {:?} to namespace {}", new_crate_ref, target_ns.name());
+            target_ns.add_symbols(new_crate_ref.lock_as_ref().sections.values(), verbose_log);
+            target_ns.crate_tree().lock().insert(new_crate_name.into(), new_crate_ref.clone());
+        }
+        else {
+            #[cfg(not(loscd_eval))] {
+                if this_namespace.get_crate(new_crate_name).is_none() { 
+                    error!("BUG: shared crate {} was not already in the current (backup) namespace", new_crate_name);
+                }
+                // else {
+                //     debug!("shared crate {} was in the current namespace like we expected.", new_crate_name);
+                // }
+            }
+        }
+        true
+    });
+
     #[cfg(loscd_eval)]
-    let mut hpet_total_bss_transfer = 0;
+    let end_symbol_cleanup = hpet.get_counter();
 
-    // The name of the new crate in each swap request. There is one entry per swap request.
-    let mut new_crate_names: Vec<String> = Vec::with_capacity(swap_requests.len());
-    // Whether the old crate was actually loaded into the old namespace. There is one entry per swap request.
-    let mut old_crates_are_loaded: Vec<bool> = Vec::with_capacity(swap_requests.len());
+    // Here, we move all of the new crate object files from the temp namespace directory into the namespace directory where they belong,
+    // and any crate object files that get replaced will be moved to the temp namespace directory. 
+    // This ensures that future usage of the newly swapped-in crates will use the new updated crate object files, not the old ones. 
+    // Effectively, we're swapping the new crate object file with the old. 
+    // Also, since the SwapRequest struct uses direct file references, we don't need to update them when we move the file. 
+    for req in swap_requests.iter() {
+        if let Err(_e) = swap_crate_object_file(req) {
+            error!("swap_crates(): couldn't move the object files of swap request {:?}: {}", req, _e);
+        }
+    }
 
-    // Now that we have loaded all of the new modules into the new namepsace in isolation,
-    // we simply need to fix up all of the relocations `WeakDependents` for each of the existing sections
-    // that depend on the old crate that we're replacing here,
-    // such that they refer to the new_module instead of the old_crate.
-    for req in &swap_requests {
+    #[allow(unused_mut)]
+    let mut reverse_requests = None;
+    if cache_old_crates {
+        #[cfg(not(loscd_eval))]
+        {
+            debug!("swap_crates() [end]: adding old_crates to cache. \n   future_swap_requests: {:?}, \n   old_crates: {:?}", 
+                future_swap_requests, cached_crates.crate_names(true));
+            reverse_requests = Some(future_swap_requests.clone());
+            UNLOADED_CRATE_CACHE.lock().insert(future_swap_requests, cached_crates);
+        }
+    }
+
+
+    #[cfg(all(loscd_eval, not(downtime_eval)))] {
+        // done with everything, print out values
+
+        warn!("Measured time in units of HPET ticks:
+            load crates, {}
+            find symbols, {}
+            rewrite relocations, {}
+            fix dependencies, {}
+            BSS transfer, {}
+            symbol cleanup, {}
+            HPET PERIOD (femtosec): {}
+            ",
+            hpet_after_load_crates - hpet_start_swap,
+            hpet_total_symbol_finding,
+            hpet_total_rewriting_relocations,
+            hpet_total_fixing_dependencies,
+            hpet_total_bss_transfer,
+            end_symbol_cleanup - start_symbol_cleanup,
+            hpet.counter_period_femtoseconds(),
+        );
+    }
+
+    Ok(SwapOutcome::Committed { reverse_requests, health_check_sec, migrated_states })
+    // here, "namespace_of_new_crates is dropped, but its crates have already been added to the current namespace 
+}
+
+
+/// A crate swap that has been staged from a list of swap requests but not yet applied.
+struct StagedSwap<'a> {
+    /// The name of the new crate in each swap request. There is one entry per swap request.
+    new_crate_names: Vec<String>,
+    /// The changes needed to replace the old crate with the new crate. There is one entry per swap request,
+    /// which is `None` if the old crate wasn't actually loaded into the old namespace.
+    crate_swaps: Vec<Option<StagedCrateSwap<'a>>>,
+    /// The state transfer functions to invoke, along with the symbol names they were requested by.
+    state_transfer_functions: Vec<(String, StrongSectionRef)>,
+    /// The state migrations declared in the new crates, along with the sections of the functions that declared them.
+    state_migrations: Vec<(StrongSectionRef, StateMigration)>,
+    /// The requested health check function, if any.
+    health_check_sec: Option<StrongSectionRef>,
+}
+
+/// The changes needed to replace one old crate with one new crate.
+struct StagedCrateSwap<'a> {
+    old_crate_ref: StrongCrateRef,
+    /// The namespace that actually contains the old crate, which may be a recursive namespace of the requested one.
+    old_namespace: Arc<CrateNamespace>,
+    new_crate_ref: StrongCrateRef,
+    /// Pairs of `.data` or `.bss` sections from the old crate and the new crate, 
+    /// in which the old section's contents will be copied into the new section.
+    data_transfers: Vec<(StrongSectionRef, StrongSectionRef)>,
+    /// The relocations in other crates' sections that will be rewritten to point to the new crate.
+    relocations: Vec<StagedRelocation>,
+    /// The new crate's sections that will be reexported under the symbol names of the old sections they replace,
+    /// along with the namespace whose symbol map contains the old section.
+    reexports: Vec<(&'a CrateNamespace, StrRef, StrongSectionRef)>,
+    /// The ABI incompatibilities between the old crate and the new crate that were tolerated.
+    abi_incompatibilities: Vec<AbiIncompatibility>,
+}
+
+/// A relocation in a `target_sec` that will be redirected from an old source section to a new one.
+#[derive(Clone)]
+struct StagedRelocation {
+    target_sec: StrongSectionRef,
+    relocation: RelocationEntry,
+    old_source_sec: StrongSectionRef,
+    new_source_sec: StrongSectionRef,
+}
+
+impl StagedSwap<'_> {
+    /// Describes the changes this staged swap would make.
+    fn to_plan(&self, swap_requests: &SwapRequestList, namespace_of_new_crates: &CrateNamespace, is_optimized: bool) -> SwapPlan {
+        let crate_swaps = swap_requests.iter()
+            .zip(self.new_crate_names.iter())
+            .zip(self.crate_swaps.iter())
+            .map(|((req, new_crate_name), crate_swap)| {
+                let mut planned = PlannedCrateSwap {
+                    old_crate: req.old_crate_name.clone(),
+                    old_crate_loaded: crate_swap.is_some(),
+                    new_crate: new_crate_name.clone(),
+                    new_namespace: req.new_namespace.name().to_string(),
+                    data_sections: 0,
+                    relocations: 0,
+                    dependent_crates: BTreeSet::new(),
+                    reexported_symbols: 0,
+                    abi_incompatibilities: Vec::new(),
+                };
+                if let Some(crate_swap) = crate_swap {
+                    planned.data_sections = crate_swap.data_transfers.len();
+                    planned.relocations = crate_swap.relocations.len();
+                    planned.reexported_symbols = crate_swap.reexports.len();
+                    planned.abi_incompatibilities = crate_swap.abi_incompatibilities.clone();
+                    planned.dependent_crates = crate_swap.relocations.iter()
+                        .filter_map(|r| r.target_sec.parent_crate.upgrade())
+                        .map(|c| c.lock_as_ref().crate_name.to_string())
+                        .collect();
+                }
+                planned
+            })
+            .collect();
+
+        let mut new_dependencies = Vec::new();
+        namespace_of_new_crates.for_each_crate(true, |crate_name, crate_ref| {
+            if !crate_ref.is_shared() && !self.new_crate_names.iter().any(|n| n == crate_name) {
+                new_dependencies.push(crate_name.to_string());
+            }
+            true
+        });
+
+        SwapPlan {
+            from_cache: is_optimized,
+            crate_swaps,
+            new_dependencies,
+            state_transfer_functions: self.state_transfer_functions.iter().map(|(_, sec)| sec.name.to_string()).collect(),
+            state_migrations: self.state_migrations.iter().map(|(_, migration)| *migration).collect(),
+            health_check_function: self.health_check_sec.as_ref().map(|sec| sec.name.to_string()),
+            blocking_tasks: quiescence::find_blocking_tasks(&self.old_crate_texts()),
+        }
+    }
+
+    /// Returns the text section bounds of each old crate that will be swapped out.
+    fn old_crate_texts(&self) -> Vec<OldCrateText> {
+        self.crate_swaps.iter().flatten()
+            .filter_map(|crate_swap| OldCrateText::of(&crate_swap.old_crate_ref, Some(&crate_swap.old_namespace)))
+            .collect()
+    }
+}
+
+
+/// Finds everything needed to carry out the given swap requests without modifying any running crates,
+/// such that a swap that cannot succeed is rejected before anything is changed. 
+fn stage_swap_requests<'a>(
+    this_namespace: &'a Arc<CrateNamespace>,
+    swap_requests: &SwapRequestList,
+    namespace_of_new_crates: &CrateNamespace,
+    is_optimized: bool,
+    state_transfer_functions: &[String],
+    health_check_symbol: Option<&str>,
+    kernel_mmi_ref: &MmiRef,
+    verbose_log: bool,
+) -> Result<StagedSwap<'a>, &'static str> {
+    let mut new_crate_names: Vec<String> = Vec::with_capacity(swap_requests.len());
+    let mut crate_swaps: Vec<Option<StagedCrateSwap>> = Vec::with_capacity(swap_requests.len());
+    let abi_check_policy = abi::abi_check_policy();
+    // Crates that are replaced in this same list of swap requests don't need to remain ABI-compatible with each other.
+    let old_crate_names: BTreeSet<&str> = swap_requests.iter()
+        .filter_map(|req| req.old_crate_name.as_deref())
+        .collect();
+
+    for req in swap_requests {
         let SwapRequest { old_crate_name, old_namespace, new_crate_object_file, new_namespace: _new_ns, reexport_new_symbols_as_old } = req; 
         let reexport_new_symbols_as_old = *reexport_new_symbols_as_old;
 
@@ -235,11 +1035,8 @@ pub fn swap_crates(
         new_crate_names.push(new_crate_name.clone());
 
         // Get a reference to the old crate that is currently loaded into the `old_namespace`.
-        let old_crate_ref = match old_crate_name.as_deref().and_then(|ocn| CrateNamespace::get_crate_and_namespace(old_namespace, ocn)) {
-            Some((ocr, _ns)) => {
-                old_crates_are_loaded.push(true);
-                ocr
-            }
+        let (old_crate_ref, old_crate_namespace) = match old_crate_name.as_deref().and_then(|ocn| CrateNamespace::get_crate_and_namespace(old_namespace, ocn)) {
+            Some((ocr, ns)) => (ocr, ns),
             _ => {
                 // If the `old_crate_name` was `None`, or the old crate wasn't found, that means it wasn't currently loaded. 
                 // Therefore, we don't need to do any symbol dependency replacement. 
@@ -248,14 +1045,10 @@ pub fn swap_crates(
                     #[cfg(not(loscd_eval))]
                     info!("swap_crates(): note: old crate {:?} was not currently loaded into old_namespace {:?}", ocn, old_namespace.name());
                 }
-                old_crates_are_loaded.push(false);
+                crate_swaps.push(None);
                 continue; 
             }
         };
-        let old_crate = old_crate_ref.lock_as_mut().ok_or_else(|| {
-            error!("Unimplemented: swap_crates(), old_crate: {:?}, doesn't yet support deep copying shared crates to get a new exclusive mutable instance", old_crate_ref);
-            "Unimplemented: swap_crates() doesn't yet support deep copying shared crates to get a new exclusive mutable instance"
-        })?;
 
         let new_crate_ref = if is_optimized {
             debug!("swap_crates(): OPTIMIZED: looking for new crate {:?} in cache", new_crate_name);
@@ -267,24 +1060,46 @@ pub fn swap_crates(
             namespace_of_new_crates.get_crate(&new_crate_name)
                 .ok_or("BUG: Couldn't get new crate that should've just been loaded into a new temporary namespace")?
         };
+        // A crate from the cache or a checkpoint is shared, e.g., with the checkpoint or the clone of it kept when it was swapped out,
+        // so it can't be modified.
+        // That's fine as long as its reexported symbols needn't change, which is the case unless it reexports any.
+        if new_crate_ref.is_shared() && (reexport_new_symbols_as_old || !new_crate_ref.lock_as_ref().reexported_symbols.is_empty()) {
+            error!("Unimplemented: swap_crates(), new_crate: {:?}, doesn't yet support deep copying shared crates to change their reexported symbols", new_crate_ref);
+            return Err("Unimplemented: swap_crates() doesn't yet support deep copying shared crates to change their reexported symbols");
+        }
 
-        // scope the lock on the `new_crate_ref`
-        {
-            let mut new_crate = new_crate_ref.lock_as_mut().ok_or(
-                "BUG: swap_crates(): new_crate was unexpectedly shared in another namespace (couldn't get as exclusively mutable)...?"
-            )?;
+        // Ensure that the new crate won't break any crates that depend on the old crate and aren't also being swapped.
+        // This must occur before locking the old and new crates, as it locks the old crate's dependents.
+        let mut abi_incompatibilities = Vec::new();
+        if abi_check_policy != AbiCheckPolicy::Ignore {
+            for mut incompatibility in abi::check_crate_replacement(&old_crate_ref, new_crate_object_file) {
+                incompatibility.dependent_crates.retain(|dep| !old_crate_names.contains(dep.as_str()));
+                if !incompatibility.dependent_crates.is_empty() {
+                    warn!("swap_crates(): new crate {:?} is ABI-incompatible with old crate {:?}: symbol {:?}: {}. Dependent crates: {:?}",
+                        new_crate_name, old_crate_name, incompatibility.symbol, incompatibility.reason, incompatibility.dependent_crates
+                    );
+                    abi_incompatibilities.push(incompatibility);
+                }
+            }
+            if abi_check_policy == AbiCheckPolicy::Reject && !abi_incompatibilities.is_empty() {
+                return Err("new crate is ABI-incompatible with crates that depend on the old crate; swap those dependent crates in the same request list");
+            }
+        }
+
+        let mut data_transfers = Vec::new();
+        let mut relocations = Vec::new();
+        let mut reexports = Vec::new();
 
-            // currently we're always clearing out the new crate's reexports because we recalculate them every time
-            new_crate.reexported_symbols.clear();
+        // scope the locks on the `old_crate_ref` and `new_crate_ref`
+        {
+            let old_crate = old_crate_ref.lock_as_ref();
+            let new_crate = new_crate_ref.lock_as_ref();
 
             let old_crate_name_without_hash = String::from(old_crate.crate_name_without_hash());
             let new_crate_name_without_hash = String::from(new_crate.crate_name_without_hash());
             let crates_have_same_name = old_crate_name_without_hash == new_crate_name_without_hash;
 
-            #[cfg(loscd_eval)]
-            let hpet_start_bss_transfer = hpet.get_counter();
-
-            // Go through all the `.data` and `.bss` sections and copy over the old_sec into the new source_sec,
+            // Go through all the `.data` and `.bss` sections and find the new source_sec that each old_sec will be copied into,
             // as they represent static variables that would otherwise result in a loss of data.
             for old_sec in old_crate.data_sections_iter() {
                 let old_sec_name_without_hash = old_sec.name_without_hash();
@@ -302,19 +1117,11 @@ pub fn swap_crates(
                         .filter(|_| iter.next().is_none()) // ensure single element
                         .ok_or("couldn't find destination section in new crate to copy old_sec's data into (.data/.bss state transfer)")
                 }?;
-
-                #[cfg(not(loscd_eval))]
-                debug!("swap_crates(): copying .data or .bss section from old {:?} to new {:?}", &*old_sec, new_dest_sec);
-                old_sec.copy_section_data_to(&new_dest_sec)?;
-            }
-
-            #[cfg(loscd_eval)] {
-                let hpet_end_bss_transfer = hpet.get_counter();
-                hpet_total_bss_transfer += hpet_end_bss_transfer - hpet_start_bss_transfer;
+                data_transfers.push((Arc::clone(old_sec), Arc::clone(new_dest_sec)));
             }
 
             // We need to find all of the "weak dependents" (sections that depend on the sections in the old crate)
-            // and replace them by rewriting their relocation entries to point to the corresponding new section in the new_crate.
+            // and the corresponding new section in the new_crate that their relocation entries will be rewritten to point to.
             //
             // Note that we only need to iterate through sections from the old crate that are public/global,
             // i.e., those that were previously added to this namespace's symbol map,
@@ -334,11 +1141,9 @@ pub fn swap_crates(
                 debug!("swap_crates(): old_sec_name: {:?}, old_sec: {:?}", old_sec.name, old_sec);
                 let old_sec_name_without_hash = old_sec.name_without_hash();
 
-
                 // This closure finds the section in the `new_crate` that corresponds to the given `old_sec` from the `old_crate`.
-                // And, if enabled, it will reexport that new section under the same name as the `old_sec`.
                 // We put this procedure in a closure because it's relatively expensive, allowing us to run it only when necessary.
-                let find_corresponding_new_section = |new_crate_reexported_symbols: &mut BTreeSet<StrRef>| -> Result<StrongSectionRef, &'static str> {
+                let find_corresponding_new_section = || -> Result<StrongSectionRef, &'static str> {
                     // Use the new namespace to find the new source_sec that old target_sec should point to.
                     // The new source_sec must have the same name as the old one (old_sec here),
                     // otherwise it wouldn't be a valid swap -- the target_sec's parent crate should have also been swapped.
@@ -364,413 +1169,311 @@ pub fn swap_crates(
                         "couldn't find section in the new crate that corresponds to a match of the old section"
                     })?;
                     #[cfg(not(loscd_eval))]
-                    debug!("swap_crates(): found match for old source_sec {:?}, new source_sec: {:?}", &*old_sec, &*new_crate_source_sec);
-
-                    if reexport_new_symbols_as_old && old_sec.global {
-                        // reexport the new source section under the old sec's name, i.e., redirect the old mapping to the new source sec
-                        let reexported_name = old_sec.name.clone();
-                        new_crate_reexported_symbols.insert(reexported_name.clone());
-                        let _old_val = old_sec_ns.symbol_map().lock().insert(reexported_name, Arc::downgrade(&new_crate_source_sec));
-                        if _old_val.is_none() { 
-                            warn!("swap_crates(): reexported new crate section that replaces old section {:?}, but that old section unexpectedly didn't exist in the symbol map", old_sec.name);
-                        }
-                    }
+                    debug!("swap_crates(): found match for old source_sec {:?}, new source_sec: {:?}", &**old_sec, &*new_crate_source_sec);
                     Ok(new_crate_source_sec)
                 };
 
-
                 // the section from the `new_crate` that corresponds to the `old_sec` from the `old_crate`
                 let mut new_sec: Option<StrongSectionRef> = None;
 
                 // Iterate over all sections that depend on the old_sec. 
-                let mut dead_weak_deps_to_remove: Vec<usize> = Vec::new();
-                for (i, weak_dep) in old_sec.inner.read().sections_dependent_on_me.iter().enumerate() {
+                for weak_dep in old_sec.inner.read().sections_dependent_on_me.iter() {
                     let target_sec = if let Some(sr) = weak_dep.section.upgrade() {
                         sr
-                    } else {
-                        #[cfg(not(any(loscd_eval, downtime_eval)))]
-                        trace!("Removing dead weak dependency on old_sec: {}", old_sec.name);
-                        dead_weak_deps_to_remove.push(i);
-                        continue;
-                    };
-                    let relocation_entry = weak_dep.relocation;
-
-                    #[cfg(loscd_eval)]
-                    let start_symbol_finding = hpet.get_counter();
-                    
-
-                    // get the section from the new crate that corresponds to the `old_sec`
-                    let new_source_sec = if let Some(ref nsr) = new_sec {
-                        #[cfg(not(loscd_eval))]
-                        trace!("using cached version of new source section");
-                        nsr
-                    } else {
-                        #[cfg(not(loscd_eval))]
-                        trace!("Finding new source section from scratch");
-                        let nsr = find_corresponding_new_section(&mut new_crate.reexported_symbols)?;
-                        new_sec.get_or_insert(nsr)
-                    };
-
-                    #[cfg(loscd_eval)] {
-                        let end_symbol_finding = hpet.get_counter();
-                        hpet_total_symbol_finding += end_symbol_finding - start_symbol_finding;
-                    }
-
-                    #[cfg(not(loscd_eval))]
-                    debug!("    swap_crates(): target_sec: {:?}, old source sec: {:?}, new source sec: {:?}", &*target_sec, &*old_sec, &*new_source_sec);
-
-                    // If the target_sec's mapped pages aren't writable (which is common in the case of swapping),
-                    // then we need to temporarily remap them as writable here so we can fix up the target_sec's new relocation entry.
-                    {
-                        #[cfg(loscd_eval)]
-                        let start_rewriting_relocations = hpet.get_counter();
-
-                        let mut target_sec_mapped_pages = target_sec.mapped_pages.lock();
-                        let target_sec_initial_flags = target_sec_mapped_pages.flags();
-                        if !target_sec_initial_flags.is_writable() {
-                            target_sec_mapped_pages.remap(&mut kernel_mmi_ref.lock().page_table, target_sec_initial_flags | EntryFlags::WRITABLE)?;
-                        }
-
-                        write_relocation(
-                            relocation_entry, 
-                            target_sec_mapped_pages.as_slice_mut(0, target_sec.mapped_pages_offset + target_sec.size)?,
-                            target_sec.mapped_pages_offset, 
-                            new_source_sec.virt_addr,
-                            verbose_log
-                        )?;
-
-                        #[cfg(loscd_eval)] {
-                            let end_rewriting_relocations = hpet.get_counter();
-                            hpet_total_rewriting_relocations += end_rewriting_relocations - start_rewriting_relocations;
-                        }
-
-                        #[cfg(not(loscd_eval))] {
-                            // If we temporarily remapped the target_sec's mapped pages as writable, undo that here
-                            if !target_sec_initial_flags.is_writable() {
-                                target_sec_mapped_pages.remap(&mut kernel_mmi_ref.lock().page_table, target_sec_initial_flags)?;
-                            };
-                        }
-                    }
-                
-
-                    #[cfg(loscd_eval)]
-                    let start_fixing_dependencies = hpet.get_counter();
-
-                    // Tell the new source_sec that the existing target_sec depends on it.
-                    // Note that we don't need to do this if we're re-swapping in a cached crate,
-                    // because that crate's sections' dependents are already properly set up from when it was first swapped in.
-                    if !is_optimized {
-                        new_source_sec.inner.write().sections_dependent_on_me.push(WeakDependent {
-                            section: Arc::downgrade(&target_sec),
-                            relocation: relocation_entry,
-                        });
-                    }
+                    } else {
+                        // Dead weak dependencies are removed when the swap is applied.
+                        continue;
+                    };
+                    let relocation = weak_dep.relocation;
 
-                    // Tell the existing target_sec that it no longer depends on the old source section (old_sec),
-                    // and that it now depends on the new source_sec.
-                    let mut found_strong_dependency = false;
-                    for mut strong_dep in target_sec.inner.write().sections_i_depend_on.iter_mut() {
-                        if Arc::ptr_eq(&strong_dep.section, &old_sec) && strong_dep.relocation == relocation_entry {
-                            strong_dep.section = Arc::clone(&new_source_sec);
-                            found_strong_dependency = true;
-                            break;
+                    // get the section from the new crate that corresponds to the `old_sec`
+                    let new_source_sec = if let Some(ref nsr) = new_sec {
+                        #[cfg(not(loscd_eval))]
+                        trace!("using cached version of new source section");
+                        nsr
+                    } else {
+                        #[cfg(not(loscd_eval))]
+                        trace!("Finding new source section from scratch");
+                        let nsr = find_corresponding_new_section()?;
+                        if reexport_new_symbols_as_old && old_sec.global {
+                            reexports.push((old_sec_ns, old_sec.name.clone(), Arc::clone(&nsr)));
                         }
-                    }
-                    if !found_strong_dependency {
-                        error!("Couldn't find/remove the existing StrongDependency from target_sec {:?} to old_sec {:?}",
-                            target_sec.name, old_sec.name);
-                        return Err("Couldn't find/remove the target_sec's StrongDependency on the old crate section");
-                    }
+                        new_sec.get_or_insert(nsr)
+                    };
 
-                    #[cfg(loscd_eval)] {
-                        let end_fixing_dependencies = hpet.get_counter();
-                        hpet_total_fixing_dependencies += end_fixing_dependencies - start_fixing_dependencies;
+                    // Ensure the target_sec's dependency on the old_sec can be redirected to the new source_sec.
+                    // The relocation itself can always be rewritten, as it was already written once when the target_sec was loaded.
+                    let has_strong_dependency = target_sec.inner.read().sections_i_depend_on.iter()
+                        .any(|strong_dep| Arc::ptr_eq(&strong_dep.section, old_sec) && strong_dep.relocation == relocation);
+                    if !has_strong_dependency {
+                        error!("Couldn't find the existing StrongDependency from target_sec {:?} to old_sec {:?}",
+                            target_sec.name, old_sec.name);
+                        return Err("Couldn't find the target_sec's StrongDependency on the old crate section");
                     }
-                } // end of loop that iterates over all weak deps in the old_sec
 
-                {
-                    let mut old_sec_inner = old_sec.inner.write();
-                    for index in dead_weak_deps_to_remove {
-                        old_sec_inner.sections_dependent_on_me.remove(index);
-                    }
+                    relocations.push(StagedRelocation {
+                        target_sec,
+                        relocation,
+                        old_source_sec: Arc::clone(old_sec),
+                        new_source_sec: Arc::clone(new_source_sec),
+                    });
                 }
-                
-            } // end of loop that rewrites dependencies for sections that depend on the old_crate
-
-            
-        } // end of scope, drops lock on `new_crate_ref`
-    } // end of iterating over all swap requests to fix up old crate dependents
-
+            }
+        } // end of scope, drops locks on `old_crate_ref` and `new_crate_ref`
+
+        crate_swaps.push(Some(StagedCrateSwap {
+            old_crate_ref,
+            old_namespace: Arc::clone(old_crate_namespace),
+            new_crate_ref,
+            data_transfers,
+            relocations,
+            reexports,
+            abi_incompatibilities,
+        }));
+    }
 
-    // Execute the provided state transfer functions
+    // Find the provided state transfer functions
+    let mut state_transfer_fn_secs = Vec::with_capacity(state_transfer_functions.len());
     for symbol in state_transfer_functions {
-        let state_transfer_fn_sec = namespace_of_new_crates.get_symbol_or_load(&symbol, Some(this_namespace), kernel_mmi_ref, verbose_log).upgrade()
+        let state_transfer_fn_sec = namespace_of_new_crates.get_symbol_or_load(symbol, Some(this_namespace), kernel_mmi_ref, verbose_log).upgrade()
             // as a backup, search fuzzily to accommodate state transfer function symbol names without full hashes
-            .or_else(|| namespace_of_new_crates.get_symbol_starting_with(&symbol).upgrade())
+            .or_else(|| namespace_of_new_crates.get_symbol_starting_with(symbol).upgrade())
             .ok_or("couldn't find specified state transfer function in the new CrateNamespace")?;
-        // FIXME SAFETY: None. swap_crates should probably be unsafe as there is no guaranteed that the state transfer functions have the correct signature.
-        let st_fn = unsafe { state_transfer_fn_sec.as_func::<StateTransferFunction>() }?;
-        #[cfg(not(loscd_eval))]
-        debug!("swap_crates(): invoking the state transfer function {:?} with old_ns: {:?}, new_ns: {:?}", symbol, this_namespace.name(), namespace_of_new_crates.name());
-        st_fn(this_namespace, &namespace_of_new_crates)?;
+        state_transfer_fn_secs.push((symbol.clone(), state_transfer_fn_sec));
     }
 
-    // Sanity check that we correctly populated the lists "new_crate_names" and "old_crates_are_loaded". 
-    if swap_requests.len() != new_crate_names.len() &&  swap_requests.len() != old_crates_are_loaded.len() {
-        return Err("BUG: swap_crates(): didn't properly populate the list of `new_crate_names` and/or `old_crates_are_loaded`.");
-    }
+    let state_migrations = find_state_migrations(namespace_of_new_crates)?;
 
-    // Remove all of the old crates now that we're fully done using them.
-    // This doesn't mean each crate will be immediately dropped -- they still might be in use by other crates or tasks.
-    for ((req, new_crate_name), is_old_crate_loaded) in swap_requests.iter().zip(new_crate_names.iter()).zip(old_crates_are_loaded.iter()) {
-        if !is_old_crate_loaded { continue; }
-        let SwapRequest { old_crate_name, old_namespace, new_crate_object_file: _, new_namespace, reexport_new_symbols_as_old } = req;
-        let old_crate_name = match old_crate_name {
-            Some(ocn) => ocn,
-            _ => continue,
-        };
-        // Remove the old crate from the namespace that it was previously in, and remove its sections' symbols too.
-        if let Some(old_crate_ref) = old_namespace.crate_tree().lock().remove(old_crate_name.as_bytes()) {
-            {
-                let old_crate = old_crate_ref.lock_as_ref();
+    // Find the health check function, which is treated the same as a state transfer function.
+    let health_check_sec = match health_check_symbol {
+        Some(symbol) => Some(
+            namespace_of_new_crates.get_symbol_or_load(symbol, Some(this_namespace), kernel_mmi_ref, verbose_log).upgrade()
+                .or_else(|| namespace_of_new_crates.get_symbol_starting_with(symbol).upgrade())
+                .ok_or("couldn't find specified health check function in the new CrateNamespace")?
+        ),
+        None => None,
+    };
 
-                core::mem::forget(old_crate_ref.clone());
+    Ok(StagedSwap {
+        new_crate_names,
+        crate_swaps,
+        state_transfer_functions: state_transfer_fn_secs,
+        state_migrations,
+        health_check_sec,
+    })
+}
 
 
-                #[cfg(not(loscd_eval))]
-                info!("  Removed old crate {:?} ({:?}) from namespace {}", old_crate_name, &*old_crate, old_namespace.name());
+/// Finds the state migrations declared with `state_transfer::state_migration!()` in the given namespace's crates,
+/// which only contains the new crates and the crates newly loaded alongside them.
+fn find_state_migrations(namespace_of_new_crates: &CrateNamespace) -> Result<Vec<(StrongSectionRef, StateMigration)>, &'static str> {
+    let mut descriptor_secs = Vec::new();
+    namespace_of_new_crates.for_each_crate(false, |_crate_name, crate_ref| {
+        descriptor_secs.extend(
+            crate_ref.lock_as_ref().sections.values()
+                .filter(|sec| is_state_migration_descriptor(sec))
+                .cloned()
+        );
+        true
+    });
 
-                if cache_old_crates {
-                    #[cfg(not(loscd_eval))]
-                    {
-                        // Here, we setup the crate cache to enable the removed old crate to be quickly swapped back in in the future.
-                        // This removed old crate will be useful when a future swap request includes the following:
-                        // (1) the future `new_crate_object_file`        ==  the current `old_crate.object_file`
-                        // (2) the future `old_crate_name`               ==  the current `new_crate_name`
-                        // (3) the future `reexport_new_symbols_as_old`  ==  true if the old crate had any reexported symbols
-                        //     -- to understand this, see the docs for `LoadedCrate.reexported_prefix`
-                        let future_swap_req = SwapRequest {
-                            old_crate_name: Some(new_crate_name.clone()),
-                            old_namespace: ByAddress(Arc::clone(new_namespace)),
-                            new_crate_object_file: ByAddress(old_crate.object_file.clone()),
-                            new_namespace: ByAddress(Arc::clone(old_namespace)),
-                            reexport_new_symbols_as_old: !old_crate.reexported_symbols.is_empty(),
-                        };
-                        future_swap_requests.push(future_swap_req);
-                    }
-                }
-                
-                // Remove all of the symbols belonging to the old crate from the namespace it was in.
-                // If reexport_new_symbols_as_old is true, we MUST NOT remove the old_crate's symbols from this symbol map,
-                // because we already replaced them above with mappings that redirect to the corresponding new crate sections.
-                if !reexport_new_symbols_as_old {
-                    let mut old_ns_symbol_map = old_namespace.symbol_map().lock();
-                    for old_sec in old_crate.global_sections_iter() {
-                        if old_ns_symbol_map.remove(&old_sec.name).is_none() {
-                            error!("swap_crates(): couldn't find old symbol {:?} in the old crate's namespace: {}.", old_sec.name, old_namespace.name());
-                            return Err("couldn't find old symbol {:?} in the old crate's namespace");
-                        }
-                    }
-                }
+    let mut state_migrations = Vec::with_capacity(descriptor_secs.len());
+    for sec in descriptor_secs {
+        // SAFETY: the function generated by `state_migration!()` under this symbol name is a `StateMigrationDescriptor`.
+        let descriptor = unsafe { sec.as_func::<StateMigrationDescriptor>() }?;
+        let migration = descriptor();
+        #[cfg(not(loscd_eval))]
+        debug!("swap_crates(): found state migration {:?} from version {} to {} in {:?}", 
+            migration.name, migration.from_version, migration.to_version, sec.name
+        );
+        state_migrations.push((sec, migration));
+    }
+    Ok(state_migrations)
+}
 
-                // If the old crate had reexported its symbols, we should remove those reexports here,
-                // because they're no longer active since the old crate is being removed. 
-                for sym in &old_crate.reexported_symbols {
-                    let _old_reexported_symbol = old_namespace.symbol_map().lock().remove(sym);
-                    if _old_reexported_symbol.is_none() {
-                        warn!("swap_crates(): the old_crate {:?}'s reexported symbol was not in its old namespace, couldn't be removed.", sym);
-                    }
-                }
+/// Returns whether the given section is a function generated by `state_transfer::state_migration!()`,
+/// i.e., its name is `<path>::__state_migration::h<hash>`.
+fn is_state_migration_descriptor(sec: &LoadedSection) -> bool {
+    sec.typ == SectionType::Text && sec.name_without_hash()
+        .strip_suffix(SECTION_HASH_DELIMITER)
+        .and_then(|name| name.strip_suffix(STATE_MIGRATION_SYMBOL))
+        .map_or(false, |path| path.ends_with("::"))
+}
 
-                if cache_old_crates {
-                    // TODO: could maybe optimize transfer of old symbols from this namespace to cached_crates namespace 
-                    //       by saving the removed symbols above and directly adding them to the cached_crates.symbol_map instead of iterating over all old_crate.sections.
-                    //       This wil only really be faster once qp_trie supports a non-iterator-based (non-extend) Trie merging function.
-                    #[cfg(not(loscd_eval))]
-                    cached_crates.add_symbols(old_crate.sections.values(), verbose_log); 
-                }
-            } // drops lock for `old_crate_ref`
-            
-            if cache_old_crates {
-                #[cfg(not(loscd_eval))]
-                cached_crates.crate_tree().lock().insert(old_crate_name.as_str().into(), old_crate_ref);
-            }
 
-            #[cfg(loscd_eval)]
-            core::mem::forget(old_crate_ref);
+/// Moves the new crate object file of the given swap request into its new namespace's directory,
+/// and moves the old crate's object file that it replaces into the directory that the new one came from.
+fn swap_crate_object_file(req: &SwapRequest) -> Result<(), &'static str> {
+    let SwapRequest { old_crate_name, old_namespace, new_crate_object_file, new_namespace, reexport_new_symbols_as_old: _ } = req;
+
+    let source_dir_ref = new_crate_object_file.lock().get_parent_dir().ok_or("BUG: new_crate_object_file has no parent directory")?;
+    let dest_dir_ref   = new_namespace.dir().deref();
+    // // If the directories are the same (not overridden), we don't need to do anything.
+    if Arc::ptr_eq(&source_dir_ref, dest_dir_ref) {
+        #[cfg(not(any(loscd_eval, downtime_eval)))]
+        trace!("swap_crates(): skipping crate file swap for {:?}", req);
+        return Ok(());
+    }
+
+    // Move the new crate object file from the temp namespace dir into the namespace dir that it belongs to.
+    if let Some((mut replaced_old_crate_file, original_source_dir)) = move_file(new_crate_object_file, dest_dir_ref)? {
+        // If we replaced a crate object file, put that replaced file back in the source directory, thus completing the "swap" operation.
+        // (Note that the file that we replaced should be the same as the old_crate_file.) 
+        #[cfg(not(any(loscd_eval, downtime_eval)))]
+        trace!("swap_crates(): new_crate_object_file replaced existing (old_crate) object file {:?}", replaced_old_crate_file.get_name());
+
+        replaced_old_crate_file.set_parent_dir(Arc::downgrade(&original_source_dir));
+        if let Some(_f) = original_source_dir.lock().insert(replaced_old_crate_file)? {
+            // There shouldn't be a similarly-named file in the original source dir anymore, since we moved it.
+            // However, this isn't necessarily a real problem; we can continue execution, but I'd like to log an error for sanity checking purposes.
+            error!("swap_crates(): unexpectedly replaced file {:?} that was in source directory {:?}", _f.get_name(), original_source_dir.lock().get_absolute_path());
         }
-        else {
-            error!("swap_crates(): couldn't remove old crate {} from old namespace {}!", old_crate_name, old_namespace.name());
-            continue;
+    } else {
+        // If inserting the new crate object file didn't end up replacing the existing crate object file (the old_crate's object file), 
+        // then we need to remove the old_crate's object file here, if one was specified. 
+        if let Some(ocn) = old_crate_name {
+            #[cfg(not(any(loscd_eval, downtime_eval)))]
+            trace!("swap_crates(): new_crate_object_file did not replace old_crate's object file, so we're removing the old_crate's object file now");
+            let (old_crate_object_file, _old_ns) = CrateNamespace::get_crate_object_file_starting_with(old_namespace, &*ocn).ok_or_else(|| {
+                error!("BUG: swap_crates(): couldn't find old crate's object file starting with {:?} in old namespace {:?}.", ocn, old_namespace.name());
+                "BUG: swap_crates(): couldn't find old crate's object file in old namespace!"
+            })?;
+            let mut removed_old_crate_file = old_namespace.dir().lock().remove(&FileOrDir::File(Arc::clone(&old_crate_object_file))).ok_or_else(|| {
+                error!("BUG: swap_crates(): couldn't remove old crate's object file {:?} from old namespace {:?}.", old_crate_object_file.lock().get_name(), old_namespace.name());
+                "BUG: swap_crates(): couldn't remove old crate's object file from old namespace!"
+            })?;
+            removed_old_crate_file.set_parent_dir(Arc::downgrade(&source_dir_ref));
+            if let Some(_f) = source_dir_ref.lock().insert(removed_old_crate_file)? {
+                // This is not necessarily a problem, but is currently unexpected behavior.
+                warn!("swap_crates(): unexpectedly replaced file {:?} that was in source directory {:?}", _f.get_name(), source_dir_ref.lock().get_absolute_path());
+            } 
+        } else {
+            // If there's no old crate to be replaced (we're just adding a new crate), then we don't need to do anything here. 
         }
     }
+    Ok(())
+}
 
-    #[cfg(loscd_eval)]
-    let start_symbol_cleanup = hpet.get_counter();
-
-    // Here, we move all of the new crates into the actual new namespace where they belong. 
-    for ((req, new_crate_name), is_old_crate_loaded) in swap_requests.iter().zip(new_crate_names.iter()).zip(old_crates_are_loaded.iter()) {
-        // We only expect the new crate to have been loaded into the temp namespace if the old crate was actually loaded in the old namespace
-        if !is_old_crate_loaded { continue; }
-        let new_crate_ref = namespace_of_new_crates.crate_tree().lock().remove(new_crate_name.as_bytes())
-            .ok_or("BUG: swap_crates(): new crate specified by swap request was not found in the new namespace")?;
-        
-        #[cfg(not(loscd_eval))]
-        debug!("swap_crates(): adding new crate {:?} to namespace {}", new_crate_ref, req.new_namespace.name());
-
-        req.new_namespace.add_symbols(new_crate_ref.lock_as_ref().sections.values(), verbose_log);
-        req.new_namespace.crate_tree().lock().insert(new_crate_name.as_str().into(), new_crate_ref.clone());
-    }
-    
-    // Other crates may have been loaded from their object files into the `namespace_of_new_crates` as dependendencies (required by the new crates specified by swap requests).
-    // Thus, we need to move all **newly-loaded** crates from the `namespace_of_new_crates` into the proper new namespace;
-    // for this, we add only the non-shared (exclusive) crates, because shared crates are those that were previously loaded (and came from the backup namespace).
-    namespace_of_new_crates.for_each_crate(true, |new_crate_name, new_crate_ref| {
-        if !new_crate_ref.is_shared() {
-            // TODO FIXME: we may not want to add the new crate to this namespace, we might want to add it to one of its recursive namespaces
-            //             (e.g., `this_namespace` could be an application ns, but the new_crate could be a kernel crate that belongs in `this_namespaces`'s recursive kernel namespace).
-            //
-            // To infer which actual namespace this newly-loaded crate should be added to (we don't want to add kernel crates to an application namespace),
-            // we could iterate over all of the new crates in the swap requests that transitively depend on this new_crate,
-            // and then look at which `new_namespace` those new crates in the swap requests were destined for.
-            // Then, we use the highest-level namespace that we can, but it cannot be higher than any one `new_namespace'.
-            // 
-            // As an example, suppose there are 3 new crates from the swap request list that depend on this new_crate_ref here. 
-            // If two of those were application crates that belong to a higher-level "applications" namespace (their `new_namespace`), 
-            // but one of them was a kernel crate that belonged to a lower "kernel" namespace that was found in that "applications" namespace recursive children, 
-            // the highest-level namespace we could add this `new_crate_ref` to would be that "kernel" namespace,
-            // meaning that we had inferred that this `new_crate_ref` was also a kernel crate that belonged in the "kernel" namespace.
-            //
-            // This follows the rule that crates in a lower-level namespace cannot depend on those in a higher-level namespace. 
-            //
-            // Note that we don't just want to put the crate in the lowest namespace we can, 
-            // because that could result in putting an application crate in a kernel namespace. 
-            // 
-            let mut target_ns = this_namespace;
 
-            // FIXME: currently we use a hack to determine which namespace this freshly-loaded crate should be added to,
-            //        based on which directory its object file 
-            {
-                let objfile_path = Path::new(new_crate_ref.lock_as_ref().object_file.lock().get_absolute_path());
-                if objfile_path.components().skip(1).next() == Some(mod_mgmt::CrateType::Kernel.default_namespace_name()) {
-                    let new_target_ns = this_namespace.recursive_namespace().unwrap_or(this_namespace);
-                    #[cfg(not(loscd_eval))]
-                    warn!("temp fix: changing target_ns from {} to {}, for crate {:?}", this_namespace.name(), new_target_ns.name(), new_crate_ref);
-                    target_ns = new_target_ns;
-                }
+/// A record of the changes made to the running crates while applying a staged swap,
+/// such that they can be undone if the swap fails before the old crates are removed. 
+struct UndoLog<'a> {
+    /// The relocations that were rewritten to point to a new section, in the order they were rewritten.
+    relocations: Vec<StagedRelocation>,
+    /// The symbols that were reexported, along with the namespace and the symbol map's previous value for that symbol. 
+    reexports: Vec<(&'a CrateNamespace, StrRef, Option<WeakSectionRef>)>,
+    /// Whether a `WeakDependent` was added to each new source section for every rewritten relocation.
+    added_weak_dependents: bool,
+}
 
+impl UndoLog<'_> {
+    /// Restores every recorded change, in reverse order, such that the old crates are used again.
+    /// 
+    /// This continues past errors to restore as much as possible.
+    fn roll_back(self, kernel_mmi_ref: &MmiRef, verbose_log: bool) {
+        for (ns, reexported_name, previous) in self.reexports.into_iter().rev() {
+            let mut symbol_map = ns.symbol_map().lock();
+            match previous {
+                Some(weak_sec) => { symbol_map.insert(reexported_name, weak_sec); }
+                None => { symbol_map.remove(&reexported_name); }
             }
-
-            // #[cfg(not(loscd_eval))]
-            // warn!("swap_crates(): untested scenario of adding new non-requested (dependency) crate {:?} to namespace {}", new_crate_ref, target_ns.name());
-            target_ns.add_symbols(new_crate_ref.lock_as_ref().sections.values(), verbose_log);
-            target_ns.crate_tree().lock().insert(new_crate_name.into(), new_crate_ref.clone());
         }
-        else {
-            #[cfg(not(loscd_eval))] {
-                if this_namespace.get_crate(new_crate_name).is_none() { 
-                    error!("BUG: shared crate {} was not already in the current (backup) namespace", new_crate_name);
-                }
-                // else {
-                //     debug!("shared crate {} was in the current namespace like we expected.", new_crate_name);
-                // }
+
+        for StagedRelocation { target_sec, relocation, old_source_sec, new_source_sec } in self.relocations.into_iter().rev() {
+            if let Err(_e) = rewrite_relocation(&target_sec, relocation, old_source_sec.virt_addr, kernel_mmi_ref, verbose_log) {
+                error!("swap_crates(): couldn't restore relocation in {:?} to point to old section {:?}: {}", target_sec.name, old_source_sec.name, _e);
+            }
+            // The StrongDependency may not have been replaced if the swap failed partway through redirecting this relocation.
+            replace_strong_dependency(&target_sec, relocation, &new_source_sec, &old_source_sec);
+            if self.added_weak_dependents {
+                new_source_sec.inner.write().sections_dependent_on_me.retain(|weak_dep|
+                    !(weak_dep.relocation == relocation && Weak::as_ptr(&weak_dep.section) == Arc::as_ptr(&target_sec))
+                );
             }
         }
-        true
-    });
-
-    #[cfg(loscd_eval)]
-    let end_symbol_cleanup = hpet.get_counter();
+    }
+}
 
-    // Here, we move all of the new crate object files from the temp namespace directory into the namespace directory where they belong,
-    // and any crate object files that get replaced will be moved to the temp namespace directory. 
-    // This ensures that future usage of the newly swapped-in crates will use the new updated crate object files, not the old ones. 
-    // Effectively, we're swapping the new crate object file with the old. 
-    // Also, since the SwapRequest struct uses direct file references, we don't need to update them when we move the file. 
-    for req in swap_requests.iter() {
-        let SwapRequest { old_crate_name, old_namespace, new_crate_object_file, new_namespace, reexport_new_symbols_as_old: _ } = req;
 
-        let source_dir_ref = new_crate_object_file.lock().get_parent_dir().ok_or("BUG: new_crate_object_file has no parent directory")?;
-        let dest_dir_ref   = new_namespace.dir().deref();
-        // // If the directories are the same (not overridden), we don't need to do anything.
-        if Arc::ptr_eq(&source_dir_ref, dest_dir_ref) {
-            #[cfg(not(any(loscd_eval, downtime_eval)))]
-            trace!("swap_crates(): skipping crate file swap for {:?}", req);
-            continue;
-        }
+/// Rewrites the given `relocation` in the `target_sec` such that it refers to a source section at `source_sec_addr`.
+/// 
+/// If the target_sec's mapped pages aren't writable (which is common in the case of swapping),
+/// then we need to temporarily remap them as writable here so we can fix up the target_sec's relocation entry.
+fn rewrite_relocation(
+    target_sec: &LoadedSection,
+    relocation: RelocationEntry,
+    source_sec_addr: VirtualAddress,
+    kernel_mmi_ref: &MmiRef,
+    verbose_log: bool,
+) -> Result<(), &'static str> {
+    let mut target_sec_mapped_pages = target_sec.mapped_pages.lock();
+    let target_sec_initial_flags = target_sec_mapped_pages.flags();
+    if !target_sec_initial_flags.is_writable() {
+        target_sec_mapped_pages.remap(&mut kernel_mmi_ref.lock().page_table, target_sec_initial_flags | EntryFlags::WRITABLE)?;
+    }
 
-        // Move the new crate object file from the temp namespace dir into the namespace dir that it belongs to.
-        if let Some((mut replaced_old_crate_file, original_source_dir)) = move_file(new_crate_object_file, dest_dir_ref)? {
-            // If we replaced a crate object file, put that replaced file back in the source directory, thus completing the "swap" operation.
-            // (Note that the file that we replaced should be the same as the old_crate_file.) 
-            #[cfg(not(any(loscd_eval, downtime_eval)))]
-            trace!("swap_crates(): new_crate_object_file replaced existing (old_crate) object file {:?}", replaced_old_crate_file.get_name());
+    let result = write_relocation(
+        relocation, 
+        target_sec_mapped_pages.as_slice_mut(0, target_sec.mapped_pages_offset + target_sec.size)?,
+        target_sec.mapped_pages_offset, 
+        source_sec_addr,
+        verbose_log
+    );
 
-            replaced_old_crate_file.set_parent_dir(Arc::downgrade(&original_source_dir));
-            if let Some(_f) = original_source_dir.lock().insert(replaced_old_crate_file)? {
-                // There shouldn't be a similarly-named file in the original source dir anymore, since we moved it.
-                // However, this isn't necessarily a real problem; we can continue execution, but I'd like to log an error for sanity checking purposes.
-                error!("swap_crates(): unexpectedly replaced file {:?} that was in source directory {:?}", _f.get_name(), original_source_dir.lock().get_absolute_path());
-            }
-        } else {
-            // If inserting the new crate object file didn't end up replacing the existing crate object file (the old_crate's object file), 
-            // then we need to remove the old_crate's object file here, if one was specified. 
-            if let Some(ocn) = old_crate_name {
-                #[cfg(not(any(loscd_eval, downtime_eval)))]
-                trace!("swap_crates(): new_crate_object_file did not replace old_crate's object file, so we're removing the old_crate's object file now");
-                let (old_crate_object_file, _old_ns) = CrateNamespace::get_crate_object_file_starting_with(old_namespace, &*ocn).ok_or_else(|| {
-                    error!("BUG: swap_crates(): couldn't find old crate's object file starting with {:?} in old namespace {:?}.", ocn, old_namespace.name());
-                    "BUG: swap_crates(): couldn't find old crate's object file in old namespace!"
-                })?;
-                let mut removed_old_crate_file = old_namespace.dir().lock().remove(&FileOrDir::File(Arc::clone(&old_crate_object_file))).ok_or_else(|| {
-                    error!("BUG: swap_crates(): couldn't remove old crate's object file {:?} from old namespace {:?}.", old_crate_object_file.lock().get_name(), old_namespace.name());
-                    "BUG: swap_crates(): couldn't remove old crate's object file from old namespace!"
-                })?;
-                removed_old_crate_file.set_parent_dir(Arc::downgrade(&source_dir_ref));
-                if let Some(_f) = source_dir_ref.lock().insert(removed_old_crate_file)? {
-                    // This is not necessarily a problem, but is currently unexpected behavior.
-                    warn!("swap_crates(): unexpectedly replaced file {:?} that was in source directory {:?}", _f.get_name(), source_dir_ref.lock().get_absolute_path());
-                } 
-            } else {
-                // If there's no old crate to be replaced (we're just adding a new crate), then we don't need to do anything here. 
-            }
-        }
+    #[cfg(not(loscd_eval))] {
+        // If we temporarily remapped the target_sec's mapped pages as writable, undo that here
+        if !target_sec_initial_flags.is_writable() {
+            target_sec_mapped_pages.remap(&mut kernel_mmi_ref.lock().page_table, target_sec_initial_flags)?;
+        };
     }
+    result
+}
 
-    if cache_old_crates {
-        #[cfg(not(loscd_eval))]
-        {
-            debug!("swap_crates() [end]: adding old_crates to cache. \n   future_swap_requests: {:?}, \n   old_crates: {:?}", 
-                future_swap_requests, cached_crates.crate_names(true));
-            UNLOADED_CRATE_CACHE.lock().insert(future_swap_requests, cached_crates);
+
+/// Changes the `target_sec`'s `StrongDependency` on the section `from` (via the given `relocation`)
+/// to be a dependency on the section `to` instead. 
+/// 
+/// Returns `false` if the `target_sec` had no such dependency.
+fn replace_strong_dependency(
+    target_sec: &LoadedSection,
+    relocation: RelocationEntry,
+    from: &StrongSectionRef,
+    to: &StrongSectionRef,
+) -> bool {
+    for strong_dep in target_sec.inner.write().sections_i_depend_on.iter_mut() {
+        if Arc::ptr_eq(&strong_dep.section, from) && strong_dep.relocation == relocation {
+            strong_dep.section = Arc::clone(to);
+            return true;
         }
     }
+    false
+}
 
 
-    #[cfg(all(loscd_eval, not(downtime_eval)))] {
-        // done with everything, print out values
-
-        warn!("Measured time in units of HPET ticks:
-            load crates, {}
-            find symbols, {}
-            rewrite relocations, {}
-            fix dependencies, {}
-            BSS transfer, {}
-            symbol cleanup, {}
-            HPET PERIOD (femtosec): {}
-            ",
-            hpet_after_load_crates - hpet_start_swap,
-            hpet_total_symbol_finding,
-            hpet_total_rewriting_relocations,
-            hpet_total_fixing_dependencies,
-            hpet_total_bss_transfer,
-            end_symbol_cleanup - start_symbol_cleanup,
-            hpet.counter_period_femtoseconds(),
-        );
+/// Puts previously-cached crates back into the cache if swapping them in was aborted, 
+/// such that a future swap with the same swap requests can still use them.
+fn return_to_cache(swap_requests: SwapRequestList, namespace_of_new_crates: CrateNamespace, is_optimized: bool) {
+    if is_optimized {
+        UNLOADED_CRATE_CACHE.lock().insert(swap_requests, namespace_of_new_crates);
     }
+}
 
-    Ok(())
-    // here, "namespace_of_new_crates is dropped, but its crates have already been added to the current namespace 
+
+/// Invokes the given health check function until it reports that the new crates are healthy,
+/// it returns an error, or the `timeout` elapses.
+fn run_health_check(health_check_sec: &LoadedSection, timeout: Duration) -> Result<(), &'static str> {
+    // FIXME SAFETY: None, same as for state transfer functions.
+    let health_check_fn = unsafe { health_check_sec.as_func::<HealthCheckFunction>() }?;
+    let deadline = sleep::get_current_time() + timeout;
+    loop {
+        if health_check_fn()? {
+            return Ok(());
+        }
+        if sleep::get_current_time() >= deadline {
+            return Err("health check didn't report that the new crates were healthy before its timeout");
+        }
+        let _ = sleep::sleep(HEALTH_CHECK_POLL_INTERVAL);
+    }
 }
 
 
@@ -862,7 +1565,7 @@ pub type SwapRequestList = Vec<SwapRequest>;
 /// under different names, allowing it to fulfill dependencies on both the old crate and the new crate.
 /// In general, this option is not needed. 
 /// 
-#[derive(Eq, PartialEq, Hash)]
+#[derive(Clone, Eq, PartialEq, Hash)]
 pub struct SwapRequest {
     // Note: the usage of `ByAddress` is to allow us to hash and compare reference types like Arc
     //       to make sure that they point to the same file rather than having the same actual contents.
@@ -941,17 +1644,17 @@ impl SwapRequest {
         // Check that the old crate is actually in the old namespace; 
         // it may be currently loaded into the old namespace, 
         // but if not, we look to see if its crate object file is there.
-        let (old_crate_full_name, real_old_namespace) = match old_crate_name {
+        let (old_crate_full_name, old_crate_ref, real_old_namespace) = match old_crate_name {
             None | Some("") => {
                 // If the old crate name is empty, that means there is no old crate to replace. 
-                (None, &old_namespace)
+                (None, None, &old_namespace)
             }    
             Some(ocn) => {
                 // Look for a single loaded crate that matches the `old_crate_name` prefix.
                 let mut matching_crates = CrateNamespace::get_crates_starting_with(&old_namespace, ocn);
                 if matching_crates.len() == 1 {
-                    let (old_crate_full_name, _ocr, real_old_namespace) = matching_crates.remove(0);
-                    (Some(old_crate_full_name.to_string()), real_old_namespace)
+                    let (old_crate_full_name, ocr, real_old_namespace) = matching_crates.remove(0);
+                    (Some(old_crate_full_name.to_string()), Some(ocr), real_old_namespace)
                 } else {
                     // If we couldn't find a single loaded crate, then the old crate may not be loaded yet. 
                     // Thus, we should instead look for a single crate **object file** that matches the `old_crate_name` prefix.
@@ -960,7 +1663,7 @@ impl SwapRequest {
                         let (old_crate_file, real_old_namespace) = matching_files.remove(0);
                         let old_crate_file_path = Path::new(old_crate_file.lock().get_name());
                         let old_crate_full_name = crate_name_from_path(&old_crate_file_path).to_string();
-                        (Some(old_crate_full_name), real_old_namespace)
+                        (Some(old_crate_full_name), None, real_old_namespace)
                     } else {
                         // Here, we couldn't find a single matching loaded crate or crate object file, so we return an error. 
                         let matches_vec = if !matching_crates.is_empty() {
@@ -1032,6 +1735,20 @@ impl SwapRequest {
             }
         };
 
+        // Warn early about ABI incompatibilities, which `swap_crates()` will reject 
+        // unless the affected dependent crates are also swapped in the same list of requests.
+        match old_crate_ref {
+            Some(old_crate_ref) if abi::abi_check_policy() != AbiCheckPolicy::Ignore => {
+                for incompatibility in abi::check_crate_replacement(&old_crate_ref, &verified_new_crate_file) {
+                    warn!("SwapRequest::new(): new crate is ABI-incompatible with old crate {:?}: symbol {:?}: {}. \
+                        These dependent crates must also be swapped: {:?}",
+                        old_crate_full_name, incompatibility.symbol, incompatibility.reason, incompatibility.dependent_crates
+                    );
+                }
+            }
+            _ => { }
+        }
+
         Ok(SwapRequest {
             old_crate_name: old_crate_full_name,
             old_namespace: ByAddress(Arc::clone(real_old_namespace)),
@@ -0,0 +1,260 @@
+//! Detects tasks that are executing within the old crates being swapped out.
+//!
+//! Rewriting relocations while another task is executing the old crates' code
+//! (or will return into it) can cause that task to observe a partially-swapped crate.
+//! Before a swap is applied, every other task that can reach the old crates through its namespace
+//! is briefly suspended so that its stack can be walked;
+//! a task "blocks" the swap if its instruction pointer or any return address on its stack
+//! lies within the text of an old crate.
+//! The old crates are *quiescent* once no task blocks the swap.
+//!
+//! How blocking tasks are handled is determined by the given [`QuiescencePolicy`].
+
+use core::{
+    fmt,
+    ops::Range,
+    time::Duration,
+};
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use memory::VirtualAddress;
+use mod_mgmt::{CrateNamespace, StrongCrateRef};
+use sleep;
+use stack_trace;
+use task::{self, TaskRef};
+
+/// How long to wait between successive quiescence checks, which gives blocking tasks time to run.
+const QUIESCENCE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long to wait for suspended tasks that are running on other CPUs to be switched out.
+const SWITCH_OUT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The maximum number of stack frames to walk for each task.
+const MAX_STACK_FRAMES: usize = 256;
+
+/// How `swap_crates()` handles other tasks that are executing within the old crates being replaced.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuiescencePolicy {
+    /// Don't check whether any tasks are executing within the old crates.
+    Ignore,
+    /// Abort the swap if any task is executing within the old crates.
+    Reject,
+    /// Wait up to the given duration for all tasks to leave the old crates,
+    /// and abort the swap if they haven't by then.
+    Wait(Duration),
+    /// Same as `Wait`, but once no task is executing within the old crates,
+    /// keep all other tasks that can reach the old crates suspended until the swap has been applied or rolled back,
+    /// such that none of them can enter the old crates while the swap is in progress.
+    ///
+    /// Note that a parked task that holds a lock needed by the swap will cause it to deadlock,
+    /// so this is best suited to swapping crates that aren't involved in crate management.
+    Park(Duration),
+}
+
+/// A task that prevents the old crates from being quiescent.
+#[derive(Clone, Debug)]
+pub struct BlockingTask {
+    /// The ID of the blocking task.
+    pub id: usize,
+    /// The name of the blocking task.
+    pub name: String,
+    /// Why the task blocks the swap.
+    pub reason: BlockingReason,
+}
+
+/// Why a [`BlockingTask`] prevents the old crates from being quiescent.
+#[derive(Clone, Debug)]
+pub enum BlockingReason {
+    /// The task's instruction pointer or one of the return addresses on its stack
+    /// is within the text of the given old crate.
+    ExecutingIn {
+        crate_name: String,
+        address: VirtualAddress,
+    },
+    /// The task didn't get switched out (and its context saved) in time to check its stack.
+    StillRunning,
+    /// The task's stack couldn't be fully walked, so it may be executing within an old crate.
+    UnknownStack(&'static str),
+}
+
+impl fmt::Display for BlockingTask {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "task {} ({:?}) ", self.id, self.name)?;
+        match &self.reason {
+            BlockingReason::ExecutingIn { crate_name, address } => write!(f, "is executing in crate {:?} at {:#X}", crate_name, address),
+            BlockingReason::StillRunning => write!(f, "is still running"),
+            BlockingReason::UnknownStack(e) => write!(f, "has a stack that couldn't be walked: {}", e),
+        }
+    }
+}
+
+
+/// The text section of an old crate that is being swapped out.
+pub(crate) struct OldCrateText {
+    pub(crate) crate_name: String,
+    pub(crate) range: Range<VirtualAddress>,
+    /// The namespace containing the old crate, or `None` if it's unknown,
+    /// in which case every task is assumed to be able to execute the old crate.
+    pub(crate) namespace: Option<Arc<CrateNamespace>>,
+}
+
+impl OldCrateText {
+    /// Returns the bounds of the given crate's text section, if it has one.
+    ///
+    /// If given, `namespace` must be the namespace that contains the crate.
+    pub(crate) fn of(crate_ref: &StrongCrateRef, namespace: Option<&Arc<CrateNamespace>>) -> Option<OldCrateText> {
+        let krate = crate_ref.lock_as_ref();
+        krate.text_pages.as_ref().map(|(_mp, range)| OldCrateText {
+            crate_name: krate.crate_name.to_string(),
+            range: range.clone(),
+            namespace: namespace.cloned(),
+        })
+    }
+
+    /// Returns whether code in a task running in the given namespace can refer to this old crate,
+    /// i.e., whether this old crate's namespace is that namespace or one of its recursive namespaces.
+    ///
+    /// Crates only depend on crates in their own namespace or its recursive namespaces,
+    /// so no other task can execute within (or return into) this old crate.
+    fn is_reachable_from(&self, task_namespace: &Arc<CrateNamespace>) -> bool {
+        let Some(old_namespace) = &self.namespace else { return true };
+        let mut namespace = Some(task_namespace);
+        while let Some(ns) = namespace {
+            if Arc::ptr_eq(ns, old_namespace) {
+                return true;
+            }
+            namespace = ns.recursive_namespace();
+        }
+        false
+    }
+}
+
+/// Tasks that were parked by [`wait_for_quiescence()`], which are resumed when this is dropped.
+pub(crate) struct ParkedTasks(Vec<TaskRef>);
+
+impl Drop for ParkedTasks {
+    fn drop(&mut self) {
+        resume_all(&self.0);
+    }
+}
+
+
+/// Waits for the given old crates to become quiescent according to the given `policy`.
+///
+/// On success, returns the tasks that were parked under [`QuiescencePolicy::Park`],
+/// which remain suspended until the returned value is dropped.
+/// Otherwise, logs and returns an error describing the tasks that blocked the swap.
+pub(crate) fn wait_for_quiescence(old_crates: &[OldCrateText], policy: QuiescencePolicy) -> Result<ParkedTasks, &'static str> {
+    let (timeout, park) = match policy {
+        QuiescencePolicy::Ignore => return Ok(ParkedTasks(Vec::new())),
+        QuiescencePolicy::Reject => (Duration::ZERO, false),
+        QuiescencePolicy::Wait(timeout) => (timeout, false),
+        QuiescencePolicy::Park(timeout) => (timeout, true),
+    };
+    if old_crates.is_empty() {
+        return Ok(ParkedTasks(Vec::new()));
+    }
+
+    let deadline = sleep::get_current_time() + timeout;
+    loop {
+        let tasks = suspend_other_tasks(old_crates);
+        let blocking_tasks = check_suspended_tasks(&tasks, old_crates);
+        if blocking_tasks.is_empty() && park {
+            return Ok(ParkedTasks(tasks));
+        }
+        resume_all(&tasks);
+        if blocking_tasks.is_empty() {
+            return Ok(ParkedTasks(Vec::new()));
+        }
+
+        if sleep::get_current_time() >= deadline {
+            for _blocking_task in &blocking_tasks {
+                error!("swap_crates(): old crates aren't quiescent: {}", _blocking_task);
+            }
+            return Err("other tasks are executing within the old crates being swapped out");
+        }
+        #[cfg(not(loscd_eval))]
+        debug!("swap_crates(): waiting for {} task(s) to leave the old crates", blocking_tasks.len());
+        let _ = sleep::sleep(QUIESCENCE_POLL_INTERVAL);
+    }
+}
+
+/// Returns the tasks that are currently executing within the given old crates, without waiting.
+pub(crate) fn find_blocking_tasks(old_crates: &[OldCrateText]) -> Vec<BlockingTask> {
+    if old_crates.is_empty() {
+        return Vec::new();
+    }
+    let tasks = suspend_other_tasks(old_crates);
+    let blocking_tasks = check_suspended_tasks(&tasks, old_crates);
+    resume_all(&tasks);
+    blocking_tasks
+}
+
+
+/// Suspends every task except for the current task and idle tasks
+/// that can execute within any of the given old crates, based on its namespace,
+/// returning the tasks that weren't already suspended.
+fn suspend_other_tasks(old_crates: &[OldCrateText]) -> Vec<TaskRef> {
+    let current_task_id = task::get_my_current_task_id();
+    let all_tasks: Vec<TaskRef> = task::TASKLIST.lock().values().cloned().collect();
+    all_tasks.into_iter()
+        .filter(|t| t.id != current_task_id && !t.is_an_idle_task)
+        .filter(|t| old_crates.iter().any(|old_crate| old_crate.is_reachable_from(t.get_namespace())))
+        .filter(|t| !t.suspend())
+        .collect()
+}
+
+fn resume_all(tasks: &[TaskRef]) {
+    for t in tasks {
+        t.resume();
+    }
+}
+
+/// Walks the stacks of the given suspended tasks and returns those that block the given old crates,
+/// first waiting briefly for tasks that are still running to be switched out.
+fn check_suspended_tasks(tasks: &[TaskRef], old_crates: &[OldCrateText]) -> Vec<BlockingTask> {
+    let mut blocking_tasks = Vec::new();
+
+    // A suspended task keeps running until it is next preempted or yields.
+    let deadline = sleep::get_current_time() + SWITCH_OUT_TIMEOUT;
+    while tasks.iter().any(|t| !t.is_context_saved()) && sleep::get_current_time() < deadline {
+        let _ = sleep::sleep(QUIESCENCE_POLL_INTERVAL);
+    }
+
+    for t in tasks {
+        if !t.is_context_saved() {
+            blocking_tasks.push(BlockingTask { id: t.id, name: t.name.clone(), reason: BlockingReason::StillRunning });
+            continue;
+        }
+        // Tasks that are being initialized or have exited have no saved context to check.
+        if t.saved_registers().is_none() {
+            continue;
+        }
+
+        let mut executing_in = None;
+        // SAFE: the task is suspended and not running, so it stays switched out while its stack is walked.
+        let walk_result = unsafe {
+            stack_trace::stack_trace_of_task(
+                t,
+                &mut |stack_frame, _stack_frame_iter| {
+                    let address = VirtualAddress::new_canonical(stack_frame.call_site_address() as usize);
+                    executing_in = old_crates.iter()
+                        .find(|old_crate| old_crate.range.contains(&address))
+                        .map(|old_crate| BlockingReason::ExecutingIn { crate_name: old_crate.crate_name.clone(), address });
+                    executing_in.is_none()
+                },
+                Some(MAX_STACK_FRAMES),
+            )
+        };
+        let reason = match (executing_in, walk_result) {
+            (Some(reason), _) => reason,
+            (None, Err(e)) => BlockingReason::UnknownStack(e),
+            (None, Ok(())) => continue,
+        };
+        blocking_tasks.push(BlockingTask { id: t.id, name: t.name.clone(), reason });
+    }
+    blocking_tasks
+}
@@ -23,6 +23,7 @@ extern crate intel_ethernet;
 extern crate nic_buffers;
 extern crate nic_queues;
 extern crate nic_initialization;
+extern crate iommu;
 
 pub mod test_e1000_driver;
 mod regs;
@@ -38,10 +39,11 @@ use kernel_config::memory::PAGE_SIZE;
 use interrupts::{eoi, register_interrupt};
 use x86_64::structures::idt::InterruptStackFrame;
 use network_interface_card:: NetworkInterfaceCard;
-use nic_initialization::{allocate_memory, init_rx_buf_pool, init_rx_queue, init_tx_queue};
+use nic_initialization::{allocate_memory, init_rx_buf_pool, init_rx_queue, init_tx_queue, init_tx_buffer};
 use intel_ethernet::descriptors::{LegacyRxDescriptor, LegacyTxDescriptor};
 use nic_buffers::{TransmitBuffer, ReceiveBuffer, ReceivedFrame};
 use nic_queues::{RxQueue, TxQueue, RxQueueRegisters, TxQueueRegisters};
+use iommu::DmaDomain;
 
 pub const INTEL_VEND:           u16 = 0x8086;  // Vendor ID for Intel 
 pub const E1000_DEV:            u16 = 0x100E;  // Device ID for the e1000 Qemu, Bochs, and VirtualBox emmulated NICs
@@ -148,8 +150,7 @@ pub struct E1000Nic {
 impl NetworkInterfaceCard for E1000Nic {
 
     fn send_packet(&mut self, transmit_buffer: TransmitBuffer) -> Result<(), &'static str> {
-        self.tx_queue.send_on_queue(transmit_buffer);
-        Ok(())
+        self.tx_queue.send_on_queue(transmit_buffer)
     }
 
     fn get_received_frame(&mut self) -> Option<ReceivedFrame> {
@@ -192,6 +193,10 @@ impl E1000Nic {
         // memory mapped base address
         let mem_base = e1000_pci_dev.determine_mem_base(0)?;
 
+        // Restrict the NIC's DMA to the memory that we map into its domain,
+        // which must be done before enabling bus mastering.
+        let dma_domain = iommu::domain_for_device(e1000_pci_dev)?;
+
         // set the bus mastering bit for this PciDevice, which allows it to use DMA
         e1000_pci_dev.pci_set_command_bus_master_bit();
 
@@ -212,9 +217,9 @@ impl E1000Nic {
         })?;
 
         // initialize the buffer pool
-        init_rx_buf_pool(RX_BUFFER_POOL_SIZE, E1000_RX_BUFFER_SIZE_IN_BYTES, &RX_BUFFER_POOL)?;
+        init_rx_buf_pool(RX_BUFFER_POOL_SIZE, E1000_RX_BUFFER_SIZE_IN_BYTES, &RX_BUFFER_POOL, Some(&dma_domain))?;
 
-        let (rx_descs, rx_buffers) = Self::rx_init(&mut mapped_registers, &mut rx_registers)?;
+        let (rx_descs, rx_buffers) = Self::rx_init(&mut mapped_registers, &mut rx_registers, &dma_domain)?;
         let rxq = RxQueue {
             id: 0,
             regs: rx_registers,
@@ -227,10 +232,11 @@ impl E1000Nic {
             // here the cpu id is irrelevant because there's no DCA or MSI 
             cpu_id: None,
             rx_buffer_pool: &RX_BUFFER_POOL,
-            filter_num: None
+            filter_num: None,
+            dma_domain: dma_domain.clone(),
         };
 
-        let tx_descs = Self::tx_init(&mut mapped_registers, &mut tx_registers)?;
+        let tx_descs = Self::tx_init(&mut mapped_registers, &mut tx_registers, &dma_domain)?;
         let txq = TxQueue {
             id: 0,
             regs: tx_registers,
@@ -238,6 +244,8 @@ impl E1000Nic {
             num_tx_descs: E1000_NUM_TX_DESC,
             tx_cur: 0,
             cpu_id: None,
+            tx_buffer: init_tx_buffer(&dma_domain)?,
+            dma_domain,
         };
 
         let e1000_nic = E1000Nic {
@@ -342,13 +350,14 @@ impl E1000Nic {
     /// and returns a tuple including both of them.
     fn rx_init(
         regs: &mut E1000Registers, 
-        rx_regs: &mut E1000RxQueueRegisters
+        rx_regs: &mut E1000RxQueueRegisters,
+        dma_domain: &DmaDomain,
     ) -> Result<(
         BorrowedSliceMappedPages<LegacyRxDescriptor, Mutable>, 
         Vec<ReceiveBuffer>
     ), &'static str> {
         // get the queue of rx descriptors and its corresponding rx buffers     
-        let (rx_descs, rx_bufs_in_use) = init_rx_queue(E1000_NUM_RX_DESC as usize, &RX_BUFFER_POOL, E1000_RX_BUFFER_SIZE_IN_BYTES as usize, rx_regs)?;          
+        let (rx_descs, rx_bufs_in_use) = init_rx_queue(E1000_NUM_RX_DESC as usize, &RX_BUFFER_POOL, E1000_RX_BUFFER_SIZE_IN_BYTES as usize, rx_regs, dma_domain)?;          
             
         // Write the tail index.
         // Note that the e1000 SDM states that we should set the RDT (tail index) to the index *beyond* the last receive descriptor, 
@@ -367,10 +376,11 @@ impl E1000Nic {
     /// Initialize the array of tramsmit descriptors and return them.
     fn tx_init(
         regs: &mut E1000Registers, 
-        tx_regs: &mut E1000TxQueueRegisters
+        tx_regs: &mut E1000TxQueueRegisters,
+        dma_domain: &DmaDomain,
     ) -> Result<BorrowedSliceMappedPages<LegacyTxDescriptor, Mutable>, &'static str> {
         // get the queue of tx descriptors     
-        let tx_descs = init_tx_queue(E1000_NUM_TX_DESC as usize, tx_regs)?;
+        let tx_descs = init_tx_queue(E1000_NUM_TX_DESC as usize, tx_regs, dma_domain)?;
         regs.tctl.write(regs::TCTL_EN | regs::TCTL_PSP);
         Ok(tx_descs)
     }       
@@ -407,6 +417,7 @@ impl E1000Nic {
         if (status & INT_RX) == INT_RX {
             // debug!("e1000::handle_interrupt(): receive interrupt");
             self.poll_receive()?;
+            network_interface_card::notify_frame_received();
             handled = true;
         }
 
@@ -4,7 +4,8 @@
 #![feature(abi_x86_interrupt)]
 
 use log::{warn, debug, trace};
-use memory::{VirtualAddress, Page};
+use memory::{VirtualAddress, Page, PAGE_SIZE};
+use kernel_config::memory::KERNEL_STACK_SIZE_IN_PAGES;
 use signal_handler::{Signal, SignalContext, ErrorCode};
 use x86_64::{
     registers::control::Cr2,
@@ -45,7 +46,10 @@ pub fn init(idt_ref: &'static LockedIdt) {
         idt.segment_not_present.set_handler_fn(segment_not_present_handler);
         idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
         idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        let options = idt.page_fault.set_handler_fn(page_fault_handler);
+        unsafe {
+            options.set_stack_index(tss::PAGE_FAULT_IST_INDEX as u16);
+        }
         // reserved: 0x0F
         idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
         idt.alignment_check.set_handler_fn(alignment_check_handler);
@@ -75,6 +79,33 @@ macro_rules! println_both {
 }
 
 
+/// Returns the value of the frame pointer (RBP) in the context interrupted by the given `stack_frame`.
+///
+/// This must be inlined at the very start of an interrupt handler. RBP is callee-saved,
+/// so it still holds the interrupted value, unless the handler's prologue has already
+/// pushed it and pointed RBP at the handler's own frame, in which case the interrupted value
+/// is the one that was pushed, i.e., the value that RBP now points to.
+#[inline(always)]
+fn interrupted_frame_pointer(stack_frame: &InterruptStackFrame) -> usize {
+    let (rbp, rsp): (usize, usize);
+    unsafe {
+        core::arch::asm!(
+            "mov {}, rbp",
+            "mov {}, rsp",
+            out(reg) rbp,
+            out(reg) rsp,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+    let handler_frame = rsp .. (stack_frame as *const InterruptStackFrame as usize);
+    if handler_frame.contains(&rbp) {
+        unsafe { *(rbp as *const usize) }
+    } else {
+        rbp
+    }
+}
+
+
 /// Kills the current task (the one that caused an exception) by unwinding it.
 /// 
 /// # Important Note
@@ -267,7 +298,7 @@ extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
     // don't halt here, this isn't a fatal/permanent failure, just a brief pause.
 }
 
-/// exception 0x02, also used for TLB Shootdown IPIs and sampling interrupts.
+/// exception 0x02, also used for TLB Shootdown IPIs, sampling interrupts, and soft lockup reports.
 ///
 /// # Important Note
 /// Acquiring ANY locks in this function, even irq-safe ones, could cause a deadlock
@@ -275,6 +306,8 @@ extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
 /// another regular interrupt. 
 /// This includes printing to the log (e.g., `debug!()`) or the screen.
 extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    // Obtain the interrupted frame pointer first, before this handler's code may reuse RBP.
+    let frame_pointer = interrupted_frame_pointer(&stack_frame);
     let mut expected_nmi = false;
 
     // currently we're using NMIs to send TLB shootdown IPIs
@@ -287,6 +320,15 @@ extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
         }
     }
 
+    // The watchdog on another CPU uses NMIs to make a locked-up CPU record what it's stuck on.
+    if watchdog::record_soft_lockup(
+        stack_frame.instruction_pointer.as_u64() as usize,
+        stack_frame.stack_pointer.as_u64() as usize,
+        frame_pointer,
+    ) {
+        expected_nmi = true;
+    }
+
     // Performance monitoring hardware uses NMIs to trigger a sampling interrupt.
     match pmu_x86::handle_sample(&stack_frame) {
         // A PMU sample did occur and was properly handled, so this NMI was expected. 
@@ -387,9 +429,59 @@ extern "x86-interrupt" fn general_protection_fault_handler(stack_frame: Interrup
 }
 
 /// exception 0x0E
+///
+/// The CPU delivers page faults onto the current CPU's dedicated page fault stack
+/// (see [`tss::PAGE_FAULT_IST_INDEX`]), where this handler does nothing but switch off of it,
+/// as a nested page fault would overwrite it.
+/// The fault is re-delivered to this same handler on the interrupted stack or,
+/// if the fault may have been caused by the interrupted task growing into the lazily-mapped part
+/// of its stack, on the part of the page fault stack beneath the region used by the CPU.
 extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
     let accessed_vaddr = Cr2::read_raw() as usize;
 
+    if let Some(pf_stack_top) = tss::page_fault_stack_top() {
+        if is_on_page_fault_entry_stack(pf_stack_top.value()) {
+            let stack_pointer = stack_frame.stack_pointer.as_u64() as usize;
+            if !may_be_stack_overflow(accessed_vaddr, stack_pointer) {
+                // SAFETY: the interrupted stack pointer is within a stack that wasn't the cause of this fault.
+                unsafe { redeliver_page_fault(&stack_frame, error_code, stack_pointer) }
+            }
+            if !is_on_page_fault_stack(stack_pointer, pf_stack_top.value()) {
+                // SAFETY: the part of the page fault stack beneath the entry region is only used
+                // to handle a possible stack overflow, during which interrupts remain disabled
+                // unless the faulting task is killed, in which case it never returns there.
+                // Nested page faults are delivered onto the entry region instead.
+                unsafe { redeliver_page_fault(&stack_frame, error_code, pf_stack_top.value() - tss::PAGE_FAULT_ENTRY_STACK_SIZE) }
+            }
+            // The page fault stack itself overflowed while handling a fault, so there's no stack to switch to.
+            handle_unresolved_page_fault(&stack_frame, error_code);
+            return;
+        }
+    }
+
+    // We're no longer on the region of the page fault stack used by the CPU,
+    // so check whether this fault can be resolved, e.g., by backing a page of a lazy mapping.
+    if let Some(address) = VirtualAddress::new(accessed_vaddr) {
+        let fault = memory::PageFault {
+            address,
+            was_present: error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION),
+            was_write: error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE),
+            was_instruction_fetch: error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH),
+        };
+        if memory::resolve_page_fault(&fault) {
+            return;
+        }
+    }
+    handle_unresolved_page_fault(&stack_frame, error_code)
+}
+
+/// Reports a page fault that couldn't be resolved and kills the faulting task.
+///
+/// This is always inlined so that unwinding skips the same number of frames as for other exceptions.
+#[inline(always)]
+fn handle_unresolved_page_fault(stack_frame: &InterruptStackFrame, error_code: PageFaultErrorCode) {
+    let accessed_vaddr = Cr2::read_raw() as usize;
+
     #[cfg(not(downtime_eval))] {
         println_both!("\nEXCEPTION: PAGE FAULT while accessing {:#x}\n\
             error code: {:?}\n{:#X?}",
@@ -402,7 +494,70 @@ extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, e
         }
     }
     
-    kill_and_halt(0xE, &stack_frame, Some(ErrorCode::PageFaultError { accessed_address: accessed_vaddr, pf_error: error_code }), true)
+    kill_and_halt(0xE, stack_frame, Some(ErrorCode::PageFaultError { accessed_address: accessed_vaddr, pf_error: error_code }), true)
+}
+
+/// The maximum distance beneath the interrupted stack pointer at which a faulting access
+/// is considered a possible overflow of the interrupted task's stack.
+const MAX_STACK_OVERFLOW_DISTANCE: usize = 16 * PAGE_SIZE;
+
+/// Returns whether a page fault on the given `accessed_vaddr` may have been caused by
+/// overflowing the stack that `stack_pointer` points into.
+///
+/// This doesn't inspect the current task, so it can't deadlock on a lock held by the faulting task.
+fn may_be_stack_overflow(accessed_vaddr: usize, stack_pointer: usize) -> bool {
+    accessed_vaddr < stack_pointer.saturating_add(PAGE_SIZE)
+        && stack_pointer.saturating_sub(accessed_vaddr) <= MAX_STACK_OVERFLOW_DISTANCE
+}
+
+/// Returns whether the given `stack_pointer` points into the page fault stack whose top is `pf_stack_top`.
+///
+/// The page fault stack has the size of a kernel stack, as allocated in `captain` and `ap_start`.
+fn is_on_page_fault_stack(stack_pointer: usize, pf_stack_top: usize) -> bool {
+    stack_pointer < pf_stack_top
+        && pf_stack_top - stack_pointer <= KERNEL_STACK_SIZE_IN_PAGES * PAGE_SIZE
+}
+
+/// Returns whether we're currently running on the region of the page fault stack
+/// onto which the CPU pushes page faults.
+#[inline(always)]
+fn is_on_page_fault_entry_stack(pf_stack_top: usize) -> bool {
+    let stack_pointer: usize;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) stack_pointer, options(nomem, nostack, preserves_flags)); }
+    stack_pointer < pf_stack_top
+        && pf_stack_top - stack_pointer <= tss::PAGE_FAULT_ENTRY_STACK_SIZE
+}
+
+/// Re-delivers a page fault to [`page_fault_handler`] on the stack whose top is `new_stack_top`.
+///
+/// This pushes the same exception stack frame there that the CPU would've pushed
+/// had the page fault not been delivered onto the page fault stack,
+/// such that stack traces and unwinding proceed through the re-delivered fault exactly as they would otherwise.
+///
+/// # Safety
+/// `new_stack_top` must point into a usable stack, none of which beneath it is in use.
+/// Interrupts must be disabled, as they are upon entry into [`page_fault_handler`].
+unsafe fn redeliver_page_fault(stack_frame: &InterruptStackFrame, error_code: PageFaultErrorCode, new_stack_top: usize) -> ! {
+    // Like the CPU, align the new stack pointer to 16 bytes before pushing the exception stack frame.
+    core::arch::asm!(
+        "mov rsp, {new_sp}",
+        "push {ss}",
+        "push {sp}",
+        "push {rflags}",
+        "push {cs}",
+        "push {rip}",
+        "push {error_code}",
+        "jmp {handler}",
+        new_sp = in(reg) new_stack_top & !0xF,
+        ss = in(reg) stack_frame.stack_segment,
+        sp = in(reg) stack_frame.stack_pointer.as_u64(),
+        rflags = in(reg) stack_frame.cpu_flags,
+        cs = in(reg) stack_frame.code_segment,
+        rip = in(reg) stack_frame.instruction_pointer.as_u64(),
+        error_code = in(reg) error_code.bits(),
+        handler = in(reg) page_fault_handler as usize,
+        options(noreturn),
+    )
 }
 
 
@@ -1,5 +1,6 @@
 //! Defines support functions needed for swapping of corrupted crates to a different address for fault tolerance
 //! 
+//! Which crate to swap after a fault is decided by the recovery policies in the `fault_policy` crate.
 
 #![no_std]
 
@@ -11,7 +12,6 @@ extern crate fs_node;
 extern crate path;
 extern crate crate_swap;
 extern crate task;
-extern crate fault_log;
 
 use core::ptr;
 use core::ops::Range;
@@ -30,8 +30,7 @@ use mod_mgmt::{
     IntoCrateObjectFile,
 };
 use path::Path;
-use crate_swap::{SwapRequest, swap_crates};
-use fault_log::{RecoveryAction, FaultEntry, remove_unhandled_exceptions, log_handled_fault};
+use crate_swap::{QuiescencePolicy, SwapRequest, swap_crates};
 
 /// A data structure to hold the ranges of memory used by the old crate and the new crate.
 /// The crate only maintains the values as virtual addresses and holds no references to any
@@ -133,7 +132,10 @@ pub fn do_self_swap(
         state_transfer_functions,
         kernel_mmi_ref,
         verbose_log,
-        false // enable crate_cahce
+        false, // enable crate_cahce
+        None,
+        // The faulted task's crates are swapped during its own recovery, so they're not quiescent by definition.
+        QuiescencePolicy::Ignore,
     );
 
     let ocn = crate_name;
@@ -362,189 +364,3 @@ pub fn self_swap_handler(crate_name: &str) -> Result<SwapRanges, String> {
 
     Ok(swap_ranges)
 }
-
-/// null crate swap policy.
-/// When this policy is enabled no crate swapping is performed
-fn null_swap_policy() -> Option<String> {
-
-    #[cfg(not(downtime_eval))]
-    debug!("Running null swap policy");
-
-    // We get all unhanlded faults
-    let unhandled_list: Vec<FaultEntry> = remove_unhandled_exceptions();
-
-    // No unhandled faults. Nothing to do. This happens when restartable tasks get killed or 
-    // exits without encountering a panic or an exception
-    if unhandled_list.is_empty(){
-        debug!("No unhandled errors in the fault log");
-        return None
-    }
-    // For the first fault we mark as task restarted.
-    // For any subsequent fault we mark them as multiple fault recovery
-    for (i, fe) in unhandled_list.iter().enumerate() {
-        let mut fe = fe.clone();
-        if i == 0 {
-            fe.action_taken = RecoveryAction::TaskRestarted;
-        } else {
-            fe.action_taken = RecoveryAction::MultipleFaultRecovery;
-        }
-        log_handled_fault(fe);
-    }
-    return None
-}
-
-/// simple swap policy. 
-/// When this swap policy is enabled always the crate which the last fault occurs 
-/// is swapped.
-#[cfg(use_crate_replacement)]
-fn simple_swap_policy() -> Option<String> {
-
-    #[cfg(not(downtime_eval))]
-    debug!("Running simple swap policy");
-
-    // We get all unhanlded faults
-    let unhandled_list: Vec<FaultEntry> = remove_unhandled_exceptions();
-    if unhandled_list.is_empty(){
-        debug!("No unhandled errors in the fault log");
-        return None
-    }
-
-    // For the first fault we take action.
-    // For any subsequent fault we mark them as multiple fault recovery
-    for (i, fe) in unhandled_list.iter().enumerate() {
-        let mut fe = fe.clone();
-        if i == 0 {
-            let crate_to_swap = unhandled_list[0].crate_error_occured.clone();
-
-            // If the crate fault occured is not logged we can only restart
-            if crate_to_swap.is_none() {
-                debug!("No information on where the first failure occured");
-                fe.action_taken = RecoveryAction::TaskRestarted;
-            } else {
-                // If the crate is logged we mark that as replaced crate
-                fe.action_taken = RecoveryAction::FaultCrateReplaced;
-                fe.replaced_crates.push(crate_to_swap.unwrap().clone());
-            }
-
-        } else {
-            fe.action_taken = RecoveryAction::MultipleFaultRecovery;
-        }
-        log_handled_fault(fe);
-    }
-
-    let crate_to_swap = unhandled_list[0].crate_error_occured.clone();
-
-    #[cfg(not(downtime_eval))]
-    debug!("Repalce : {:?}",crate_to_swap);
-
-    crate_to_swap
-}
-
-/// slightly advanced swap policy. 
-/// When this policy is enabled the following actions are taken in order to the number of time same fault is detected. 
-/// 1) Restart the task
-/// 2) Replace the crate fault is detected
-/// 3) Replace the application crate
-#[cfg(use_crate_replacement)]
-fn iterative_swap_policy() -> Option<String> {
-
-    #[cfg(not(downtime_eval))]
-    debug!("Running iterative swap policy");
-
-    // We get all unhanlded faults
-    let unhandled_list: Vec<FaultEntry> = remove_unhandled_exceptions();
-    if unhandled_list.is_empty(){
-        debug!("No unhandled errors in the fault log");
-        return None
-    }
-    
-    
-    let mut crate_to_swap = None;
-
-    // For the first fault we take action.
-    // For any subsequent fault we mark them as multiple fault recovery
-    for (i, fe) in unhandled_list.iter().enumerate() {
-        let mut fe = fe.clone();
-        if i == 0 {
-
-            if let Some(error_crate) = unhandled_list[0].crate_error_occured.clone() {
-
-                let error_crate_name = error_crate.clone();
-                let error_crate_name_simple = error_crate_name.split("-").next().unwrap_or(&error_crate_name);
-
-                // We check whether the fault is logged previously
-                let fe_last_fault = fault_log::get_the_most_recent_match(error_crate_name_simple);
-                match fe_last_fault {
-
-                    // If the fault is logged we check the action taken and then take the next drastic action.
-                    Some(fault_entry) => {
-                        if fault_entry.action_taken == RecoveryAction::FaultCrateReplaced {
-                            if let Some(app_crate) = fault_entry.running_app_crate {
-                                // last action : 2  -> Next action : 3
-                                crate_to_swap = Some(app_crate.clone());
-                                fe.action_taken = RecoveryAction::IterativelyCrateReplaced;
-                                fe.replaced_crates.push(app_crate.clone());
-                            } else {
-                                // last action : 2 -> Next action : 2 due to insufficient details
-                                crate_to_swap = Some(error_crate.clone());
-                                fe.action_taken = RecoveryAction::FaultCrateReplaced;
-                                fe.replaced_crates.push(error_crate.clone());
-                            }
-                        } else if fault_entry.action_taken == RecoveryAction::TaskRestarted || fault_entry.action_taken == RecoveryAction::MultipleFaultRecovery {
-                            // last action : 1  -> Next action : 2
-                            crate_to_swap = Some(error_crate.clone());
-                            fe.action_taken = RecoveryAction::FaultCrateReplaced;
-                            fe.replaced_crates.push(error_crate.clone());
-                        } else if fault_entry.action_taken == RecoveryAction::None {
-                            // last action : None  -> Next action : 1
-                            crate_to_swap = None;
-                            fe.action_taken = RecoveryAction::TaskRestarted;
-                        } else {
-                            // We have exhausted all our fault tolerance stages. So we just reuse stage 2 
-                            crate_to_swap = Some(error_crate.clone());
-                            fe.action_taken = RecoveryAction::FaultCrateReplaced;
-                            fe.replaced_crates.push(error_crate.clone());
-                        }
-                    }
-
-                    // If the fault is not logged we start from step 1
-                    None => {
-                        crate_to_swap = None;
-                        fe.action_taken = RecoveryAction::TaskRestarted;
-                    }
-                }
-            } else {
-                // If the crate fault occured is not logged we can only restart
-                debug!("No information on where the first failure occured");
-                fe.action_taken = RecoveryAction::TaskRestarted;
-            }
-        } else {
-            fe.action_taken = RecoveryAction::MultipleFaultRecovery;
-        }
-        log_handled_fault(fe);
-    }
-
-    debug!("Repalce : {:?}",crate_to_swap);
-
-    crate_to_swap
-}
-
-/// This function returns the name of the crate to replace if required. 
-/// Returns none if no crate is needed to be replaced. 
-pub fn get_crate_to_swap() -> Option<String> {
-
-    #[cfg(not(use_crate_replacement))]
-    {
-        return null_swap_policy();
-    }
-
-
-    #[cfg(use_crate_replacement)]
-    {
-        #[cfg(not(use_iterative_replacement))]
-        return simple_swap_policy();
-
-        #[cfg(use_iterative_replacement)]
-        return iterative_swap_policy() ;
-    }
-}
@@ -14,6 +14,7 @@ extern crate memory;
 extern crate task;
 extern crate apic;
 extern crate irq_safety;
+extern crate tsc;
 
 use alloc::{
     string::{String,ToString},
@@ -22,7 +23,7 @@ use alloc::{
 use memory::VirtualAddress;
 use apic::get_my_apic_id;
 use irq_safety::MutexIrqSafe;
-use core::panic::PanicInfo;
+use core::{panic::PanicInfo, time::Duration};
 
 /// The possible faults (panics and exceptions) encountered 
 /// during operations.
@@ -40,6 +41,10 @@ pub enum FaultType {
     NMI,
     DivideByZero,
     Panic,
+    /// A CPU stopped scheduling, e.g., because a task spun with preemption disabled.
+    SoftLockup,
+    /// A task remained blocked for longer than the watchdog's threshold.
+    HungTask,
     UnknownException(u8)
 }
 
@@ -76,7 +81,11 @@ pub enum RecoveryAction {
     IterativelyCrateReplaced,
     /// This fault is handled as a recovery for different fault. 
     /// Used when additional faults occur during unwinding.  
-    MultipleFaultRecovery
+    MultipleFaultRecovery,
+    /// No recovery is attempted; the fault was only reported, e.g., by the watchdog.
+    Reported,
+    /// The task was not restarted because its recovery policy allows no more restarts.
+    GaveUp,
 }
 
 
@@ -99,10 +108,16 @@ pub struct FaultEntry {
     pub instruction_pointer: Option<VirtualAddress>,    
     /// Crate the address at which exception occured located
     pub crate_error_occured: Option<String>,
+    /// Call site addresses of the stack frames at the time of the fault, if a backtrace was captured
+    pub backtrace: Vec<usize>,
     /// List of crates reloaded from memory to recover from fault
     pub replaced_crates: Vec<String>,
     /// Recovery Action taken as a result of the fault
     pub action_taken: RecoveryAction,
+    /// The recovery policy that chose `action_taken`, if any
+    pub recovery_policy: Option<String>,
+    /// Time at which the fault occured, see [`current_time()`]
+    pub time: Option<Duration>,
 }
 
 impl FaultEntry {
@@ -119,12 +134,20 @@ impl FaultEntry {
             address_accessed: None,
             instruction_pointer: None,
             crate_error_occured: None,
+            backtrace: Vec::new(),
             replaced_crates: Vec::<String>::new(),
             action_taken: RecoveryAction::None,
+            recovery_policy: None,
+            time: current_time(),
         }
     }
 }
 
+/// Returns the time elapsed since the TSC was reset, which is used to timestamp faults.
+pub fn current_time() -> Option<Duration> {
+    tsc::tsc_ticks().to_ns().map(|ns| Duration::from_nanos(ns as u64))
+}
+
 
 /// The structure to hold the list of all faults so far occured in the system
 static FAULT_LIST: MutexIrqSafe<Vec<FaultEntry>> = MutexIrqSafe::new(Vec::new());
@@ -212,6 +235,39 @@ pub fn log_panic_entry(panic_info: &PanicInfo) {
     update_and_insert_fault_entry_internal(fe, None);
 }
 
+/// Add a new soft lockup instance, detected by the watchdog, to the fault log. 
+/// 
+/// Unlike other faults, this is logged after the fact from another task:
+/// `task` is the task that was stuck on `core`, `instruction_pointer` is where it was interrupted,
+/// and `backtrace` holds the call site addresses of its stack frames.
+pub fn log_soft_lockup(core: u8, task: Option<&task::TaskRef>, instruction_pointer: usize, backtrace: Vec<usize>) {
+    let mut fe = FaultEntry::new(FaultType::SoftLockup);
+    fe.core = Some(core);
+    fe.backtrace = backtrace;
+    let instruction_pointer = VirtualAddress::new_canonical(instruction_pointer);
+    fe.instruction_pointer = Some(instruction_pointer);
+    if let Some(task) = task {
+        fe.running_task = Some(task.name.clone());
+        fe.running_app_crate = task.app_crate.as_ref().map(|x| x.lock_as_ref().crate_name.to_string());
+        fe.crate_error_occured = task.get_namespace().get_crate_containing_address(instruction_pointer, false)
+            .map(|x| x.lock_as_ref().crate_name.to_string());
+    }
+    fe.action_taken = RecoveryAction::Reported;
+    FAULT_LIST.lock().push(fe);
+}
+
+/// Add a new hung task instance, detected by the watchdog, to the fault log. 
+/// 
+/// Unlike other faults, the given `task` is not the current task.
+pub fn log_hung_task(task: &task::TaskRef) {
+    let mut fe = FaultEntry::new(FaultType::HungTask);
+    fe.core = task.running_on_cpu().or_else(|| task.pinned_core());
+    fe.running_task = Some(task.name.clone());
+    fe.running_app_crate = task.app_crate.as_ref().map(|x| x.lock_as_ref().crate_name.to_string());
+    fe.action_taken = RecoveryAction::Reported;
+    FAULT_LIST.lock().push(fe);
+}
+
 /// Removes the unhandled faults from the fault log and returns. 
 /// Is useful when we update the recovery detail about unhandled exceptions. 
 pub fn remove_unhandled_exceptions() -> Vec<FaultEntry> {
@@ -236,38 +292,22 @@ pub fn print_fault_log() {
     let list = FAULT_LIST.lock();
     for x in list.iter() {
         println_both!("{:?}", x);
+        if !x.backtrace.is_empty() {
+            println_both!("  Backtrace:");
+            for call_site in x.backtrace.iter() {
+                println_both!("    {:>#018X}", call_site);
+            }
+        }
     }
     println_both!("------------------ END OF LOG --------------------------");
 }
 
+/// Returns a copy of all entries currently in the fault log.
+pub fn get_fault_log() -> Vec<FaultEntry> {
+    FAULT_LIST.lock().clone()
+}
+
 /// Add a `FaultEntry` to fault log.
 pub fn log_handled_fault(fe: FaultEntry){
     FAULT_LIST.lock().push(fe);
 }
-
-/// Provides the most recent entry in the log for given crate
-/// Utility function for iterative crate replacement
-pub fn get_the_most_recent_match(error_crate: &str) -> Option<FaultEntry> {
-
-    #[cfg(not(downtime_eval))]
-    debug!("getting the most recent match");
-
-    let mut fe :Option<FaultEntry> = None;
-    for fault_entry in FAULT_LIST.lock().iter() {
-        if let Some(crate_error_occured) = &fault_entry.crate_error_occured {
-            let error_crate_name = crate_error_occured.clone();
-            let error_crate_name_simple = error_crate_name.split('-').next().unwrap_or(&error_crate_name);
-            if error_crate_name_simple == error_crate {
-                let item = fault_entry.clone();
-                fe = Some(item);
-            }
-        }
-    }
-
-    if fe.is_none(){
-
-        #[cfg(not(downtime_eval))]
-        debug!("No recent entries for the given crate {}", error_crate);
-    }
-    fe
-}
\ No newline at end of file
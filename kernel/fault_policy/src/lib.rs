@@ -0,0 +1,389 @@
+//! Declarative policies that decide how to recover restartable tasks from faults.
+//!
+//! A [`RecoveryPolicy`] limits how many times a task may be restarted within a window of time,
+//! delays each restart with an exponential backoff, and escalates from restarting the task
+//! to replacing the crate in which the fault occurred or the task's application crate.
+//! It also restricts which crates may be replaced, and whether to notify a supervisor
+//! once it gives up on restarting a task.
+//!
+//! A policy applies to the faults that occur in a given crate, to the faults of tasks spawned
+//! by a given application, or to all other faults, in that order of precedence.
+//! When a restartable task exits, [`decide_recovery()`] evaluates the policy of its first unhandled fault
+//! against the earlier faults in the `fault_log` that the same policy handled within its window.
+//! The chosen action and the policy that chose it are recorded in the `fault_log`.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use async_channel::Sender;
+use core::{fmt, time::Duration};
+use fault_log::{FaultEntry, RecoveryAction};
+use log::{debug, warn};
+use mod_mgmt::crate_name_without_hash;
+use spin::Mutex;
+
+/// The window in which the default policy counts restarts, which is unbounded.
+///
+/// Thus, like the fixed recovery policies that preceded configurable ones,
+/// the default policy escalates based on every earlier restart of the same task, no matter how long ago it was.
+pub const DEFAULT_WINDOW: Duration = Duration::MAX;
+
+/// Crates can only be replaced after a fault if Theseus was built with crate replacement support.
+const CRATE_REPLACEMENT_SUPPORTED: bool = cfg!(use_crate_replacement);
+
+/// The policies that have been set, see [`set_policy()`].
+static POLICIES: Mutex<BTreeMap<PolicyScope, RecoveryPolicy>> = Mutex::new(BTreeMap::new());
+
+/// The channel on which the supervisor is notified of tasks that are no longer restarted.
+static SUPERVISOR: Mutex<Option<Sender<FaultEntry>>> = Mutex::new(None);
+
+
+/// The faults that a [`RecoveryPolicy`] applies to.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PolicyScope {
+    /// Faults that occurred in the crate of the given name, excluding its hash.
+    Crate(String),
+    /// Faults in tasks spawned by the application crate of the given name, excluding its hash.
+    App(String),
+    /// Faults that no other policy applies to.
+    Default,
+}
+
+impl PolicyScope {
+    /// Returns whether the given earlier fault `fe` falls into this scope, given the current `fault`.
+    ///
+    /// The default scope covers the earlier faults of the same task.
+    fn covers(&self, fe: &FaultEntry, fault: &FaultEntry) -> bool {
+        match self {
+            PolicyScope::Crate(name) => fe.crate_error_occured.as_deref().map(crate_name_without_hash) == Some(name),
+            PolicyScope::App(name) => fe.running_app_crate.as_deref().map(crate_name_without_hash) == Some(name),
+            PolicyScope::Default => fe.running_task.is_some() && fe.running_task == fault.running_task,
+        }
+    }
+}
+
+impl fmt::Display for PolicyScope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PolicyScope::Crate(name) => write!(f, "crate {}", name),
+            PolicyScope::App(name) => write!(f, "app {}", name),
+            PolicyScope::Default => write!(f, "default"),
+        }
+    }
+}
+
+
+/// One step of a policy's escalation path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecoveryStep {
+    /// Restart the task without replacing any crate.
+    RestartTask,
+    /// Replace the crate in which the fault occurred, then restart the task.
+    ReplaceFaultingCrate,
+    /// Replace the task's application crate, then restart the task.
+    /// If the task doesn't belong to an application, the faulting crate is replaced instead.
+    ReplaceAppCrate,
+}
+
+impl fmt::Display for RecoveryStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            RecoveryStep::RestartTask => "restart task",
+            RecoveryStep::ReplaceFaultingCrate => "replace faulting crate",
+            RecoveryStep::ReplaceAppCrate => "replace app crate",
+        })
+    }
+}
+
+
+/// The crates that a policy may replace.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReplaceableCrates {
+    None,
+    All,
+    /// Only the crates of the given names, excluding their hashes.
+    Only(Vec<String>),
+    /// All crates except those of the given names, excluding their hashes.
+    AllExcept(Vec<String>),
+}
+
+impl ReplaceableCrates {
+    /// Returns whether the crate of the given name, with or without its hash, may be replaced.
+    pub fn contains(&self, crate_name: &str) -> bool {
+        let crate_name = crate_name_without_hash(crate_name);
+        match self {
+            ReplaceableCrates::None => false,
+            ReplaceableCrates::All => true,
+            ReplaceableCrates::Only(names) => names.iter().any(|n| n == crate_name),
+            ReplaceableCrates::AllExcept(names) => !names.iter().any(|n| n == crate_name),
+        }
+    }
+}
+
+impl fmt::Display for ReplaceableCrates {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplaceableCrates::None => write!(f, "none"),
+            ReplaceableCrates::All => write!(f, "all"),
+            ReplaceableCrates::Only(names) => write!(f, "only {}", names.join(", ")),
+            ReplaceableCrates::AllExcept(names) => write!(f, "all except {}", names.join(", ")),
+        }
+    }
+}
+
+
+/// An exponential backoff between consecutive restarts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    /// The delay before the first restart within a policy's window.
+    pub initial: Duration,
+    /// The delay doubles with every further restart up to this maximum.
+    pub max: Duration,
+}
+
+impl Backoff {
+    /// Returns the delay before the restart that follows `prior_restarts` restarts within a policy's window.
+    pub fn delay(&self, prior_restarts: usize) -> Duration {
+        u32::try_from(prior_restarts).ok()
+            .and_then(|n| 2u32.checked_pow(n))
+            .and_then(|factor| self.initial.checked_mul(factor))
+            .map_or(self.max, |delay| delay.min(self.max))
+    }
+}
+
+
+/// A declarative policy for recovering restartable tasks from faults.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecoveryPolicy {
+    /// The number of restarts within `window` after which a faulting task is no longer restarted.
+    pub max_restarts: usize,
+    /// The window of time, ending at the current fault, in which earlier restarts are counted.
+    /// `Duration::MAX` counts every earlier restart, see [`DEFAULT_WINDOW`].
+    pub window: Duration,
+    /// The delay before each restart, if any.
+    pub backoff: Option<Backoff>,
+    /// The step taken for each consecutive restart within `window`.
+    /// The last step is repeated once all steps have been taken.
+    pub escalation: Vec<RecoveryStep>,
+    /// The crates that may be replaced; if a step would replace any other crate, the task is only restarted.
+    pub replaceable_crates: ReplaceableCrates,
+    /// Whether to notify the supervisor when giving up on a task, see [`set_supervisor()`].
+    pub notify_supervisor: bool,
+}
+
+impl Default for RecoveryPolicy {
+    /// Returns a policy that restarts a task immediately every time it faults.
+    ///
+    /// If Theseus is built with the `use_crate_replacement` config, the faulting crate is also replaced every time.
+    /// With the `use_iterative_replacement` config as well, the faulting crate is only replaced
+    /// if restarting the task didn't help, followed by the task's application crate.
+    fn default() -> Self {
+        let escalation = if cfg!(all(use_crate_replacement, use_iterative_replacement)) {
+            vec![
+                RecoveryStep::RestartTask,
+                RecoveryStep::ReplaceFaultingCrate,
+                RecoveryStep::ReplaceAppCrate,
+                RecoveryStep::ReplaceFaultingCrate,
+            ]
+        } else if cfg!(use_crate_replacement) {
+            vec![RecoveryStep::ReplaceFaultingCrate]
+        } else {
+            vec![RecoveryStep::RestartTask]
+        };
+        RecoveryPolicy {
+            max_restarts: usize::MAX,
+            window: DEFAULT_WINDOW,
+            backoff: None,
+            escalation,
+            replaceable_crates: ReplaceableCrates::All,
+            notify_supervisor: false,
+        }
+    }
+}
+
+impl fmt::Display for RecoveryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.max_restarts == usize::MAX {
+            write!(f, "unlimited restarts")?;
+        } else if self.window == Duration::MAX {
+            write!(f, "at most {} restarts", self.max_restarts)?;
+        } else {
+            write!(f, "at most {} restarts within {:?}", self.max_restarts, self.window)?;
+        }
+        if let Some(backoff) = self.backoff {
+            write!(f, ", backoff from {:?} to {:?}", backoff.initial, backoff.max)?;
+        }
+        write!(f, ", escalation: ")?;
+        for (i, step) in self.escalation.iter().enumerate() {
+            write!(f, "{}{}", if i == 0 { "" } else { " -> " }, step)?;
+        }
+        write!(f, ", replaceable crates: {}", self.replaceable_crates)?;
+        if self.notify_supervisor {
+            write!(f, ", notify supervisor")?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Sets the policy for the given scope, returning the policy it replaced, if any.
+pub fn set_policy(scope: PolicyScope, policy: RecoveryPolicy) -> Option<RecoveryPolicy> {
+    POLICIES.lock().insert(normalize(scope), policy)
+}
+
+/// Removes the policy for the given scope, such that a less specific policy applies instead.
+///
+/// Removing the default policy restores [`RecoveryPolicy::default()`].
+pub fn remove_policy(scope: PolicyScope) -> Option<RecoveryPolicy> {
+    POLICIES.lock().remove(&normalize(scope))
+}
+
+/// Returns every policy that has been set, followed by the default policy if it hasn't been set.
+pub fn policies() -> Vec<(PolicyScope, RecoveryPolicy)> {
+    let policies = POLICIES.lock();
+    let mut list: Vec<_> = policies.iter().map(|(scope, policy)| (scope.clone(), policy.clone())).collect();
+    if !policies.contains_key(&PolicyScope::Default) {
+        list.push((PolicyScope::Default, RecoveryPolicy::default()));
+    }
+    list
+}
+
+/// Sets the channel on which the supervisor is notified of every task that a policy gave up on,
+/// returning the previous one, if any.
+///
+/// The supervisor receives the fault after which the task was no longer restarted.
+/// Notifications are dropped if the channel is full.
+pub fn set_supervisor(sender: Option<Sender<FaultEntry>>) -> Option<Sender<FaultEntry>> {
+    core::mem::replace(&mut *SUPERVISOR.lock(), sender)
+}
+
+
+/// How to recover a restartable task that has exited, as decided by [`decide_recovery()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecoveryDecision {
+    /// The scope of the policy that made this decision, or `None` if the task exited without a fault.
+    pub scope: Option<PolicyScope>,
+    /// Whether the task should be restarted.
+    pub restart: bool,
+    /// How long to wait before restarting the task.
+    pub delay: Duration,
+    /// The crate to replace before restarting the task, if any.
+    pub crate_to_replace: Option<String>,
+}
+
+/// Decides how to recover the current restartable task, which has just exited,
+/// from the unhandled faults in the `fault_log`, and marks those faults as handled.
+///
+/// The first unhandled fault is handled by its policy; any further faults occurred while handling it,
+/// e.g., during unwinding, so they're marked as [`RecoveryAction::MultipleFaultRecovery`].
+pub fn decide_recovery() -> RecoveryDecision {
+    let unhandled = fault_log::remove_unhandled_exceptions();
+    let Some(fault) = unhandled.first() else {
+        // The task exited or was killed without a fault, so it's simply restarted.
+        debug!("No unhandled errors in the fault log");
+        return RecoveryDecision { scope: None, restart: true, delay: Duration::ZERO, crate_to_replace: None };
+    };
+
+    let (scope, policy) = policy_for(fault);
+    let prior_restarts = fault_log::get_fault_log().iter()
+        .filter(|fe| matches!(fe.action_taken,
+            RecoveryAction::TaskRestarted | RecoveryAction::FaultCrateReplaced | RecoveryAction::IterativelyCrateReplaced
+        ))
+        .filter(|fe| fe.recovery_policy.as_deref() == Some(scope.to_string().as_str()))
+        .filter(|fe| scope.covers(fe, fault) && within_window(fe, fault, policy.window))
+        .count();
+
+    let mut fe = fault.clone();
+    fe.recovery_policy = Some(scope.to_string());
+    let decision = if prior_restarts >= policy.max_restarts {
+        fe.action_taken = RecoveryAction::GaveUp;
+        RecoveryDecision { scope: Some(scope.clone()), restart: false, delay: Duration::ZERO, crate_to_replace: None }
+    } else {
+        let step = policy.escalation.get(prior_restarts)
+            .or_else(|| policy.escalation.last())
+            .copied()
+            .unwrap_or(RecoveryStep::RestartTask);
+        let crate_to_replace = match step {
+            RecoveryStep::RestartTask => None,
+            RecoveryStep::ReplaceFaultingCrate => fe.crate_error_occured.clone(),
+            RecoveryStep::ReplaceAppCrate => fe.running_app_crate.clone().or_else(|| fe.crate_error_occured.clone()),
+        };
+        let crate_to_replace = crate_to_replace.filter(|crate_name| {
+            let replaceable = CRATE_REPLACEMENT_SUPPORTED && policy.replaceable_crates.contains(crate_name);
+            if !replaceable {
+                debug!("Policy {} can't replace crate {:?}, only restarting the task", scope, crate_name);
+            }
+            replaceable
+        });
+        fe.action_taken = match crate_to_replace {
+            None => RecoveryAction::TaskRestarted,
+            Some(ref crate_name) if Some(crate_name) == fe.running_app_crate.as_ref() => RecoveryAction::IterativelyCrateReplaced,
+            Some(_) => RecoveryAction::FaultCrateReplaced,
+        };
+        fe.replaced_crates.extend(crate_to_replace.clone());
+        RecoveryDecision {
+            scope: Some(scope.clone()),
+            restart: true,
+            delay: policy.backoff.map_or(Duration::ZERO, |backoff| backoff.delay(prior_restarts)),
+            crate_to_replace,
+        }
+    };
+    debug!("Recovery policy {} ({}) decided {:?} after {} restarts", scope, policy, decision, prior_restarts);
+
+    if fe.action_taken == RecoveryAction::GaveUp && policy.notify_supervisor {
+        notify_supervisor(fe.clone());
+    }
+    fault_log::log_handled_fault(fe);
+    for other in &unhandled[1..] {
+        let mut other = other.clone();
+        other.action_taken = RecoveryAction::MultipleFaultRecovery;
+        fault_log::log_handled_fault(other);
+    }
+    decision
+}
+
+/// Returns the most specific policy that applies to the given fault.
+fn policy_for(fault: &FaultEntry) -> (PolicyScope, RecoveryPolicy) {
+    let policies = POLICIES.lock();
+    let crate_scope = fault.crate_error_occured.as_deref().map(|c| PolicyScope::Crate(crate_name_without_hash(c).to_string()));
+    let app_scope = fault.running_app_crate.as_deref().map(|c| PolicyScope::App(crate_name_without_hash(c).to_string()));
+    crate_scope.into_iter()
+        .chain(app_scope)
+        .chain(Some(PolicyScope::Default))
+        .find_map(|scope| policies.get(&scope).cloned().map(|policy| (scope, policy)))
+        .unwrap_or_else(|| (PolicyScope::Default, RecoveryPolicy::default()))
+}
+
+/// Returns whether the earlier fault `fe` occurred within `window` before the given `fault`.
+///
+/// Faults without a timestamp are conservatively treated as recent.
+fn within_window(fe: &FaultEntry, fault: &FaultEntry, window: Duration) -> bool {
+    match (fe.time, fault.time) {
+        (Some(earlier), Some(now)) => now.saturating_sub(earlier) <= window,
+        _ => true,
+    }
+}
+
+fn notify_supervisor(fe: FaultEntry) {
+    match SUPERVISOR.lock().as_ref() {
+        Some(sender) => if sender.try_send(fe).is_err() {
+            warn!("Couldn't notify the fault supervisor, its channel is full or disconnected");
+        }
+        None => warn!("Gave up restarting task {:?}, but there is no fault supervisor to notify", fe.running_task),
+    }
+}
+
+fn normalize(scope: PolicyScope) -> PolicyScope {
+    match scope {
+        PolicyScope::Crate(name) => PolicyScope::Crate(crate_name_without_hash(&name).to_string()),
+        PolicyScope::App(name) => PolicyScope::App(crate_name_without_hash(&name).to_string()),
+        PolicyScope::Default => PolicyScope::Default,
+    }
+}
@@ -0,0 +1,541 @@
+//! A buddy allocator for general-purpose frames, with per-CPU caches of single frames.
+//!
+//! Free general-purpose frames are kept in blocks of `2^order` frames, each of which
+//! starts at a frame number that is aligned to its size.
+//! Each order has its own set of free blocks, such that finding a free block of a given size
+//! only requires looking at the sets of that order and above, and freeing a block only requires
+//! checking whether its "buddy" (the other half of the next-larger block) is also free,
+//! in which case the two are merged.
+//!
+//! Most allocations are for a single frame, e.g., when mapping individual pages,
+//! so each CPU keeps a small cache of free single frames that it can allocate from and free into
+//! without contending for the locks on the buddy lists.
+//! A cache is refilled with a batch of frames when it runs empty and flushed when it's full.
+//!
+//! Each NUMA node has its own buddy lists, such that frames can be allocated from a specific node;
+//! see the `numa` module. Each CPU's cache is refilled from its own node's lists whenever possible.
+//!
+//! The buddy lists are heap-allocated sorted vectors, so they're only used after heap allocation is available;
+//! before that, the frame allocator uses its original statically-sized list of free chunks.
+//! The heap may allocate frames when it grows, so the buddy lists never allocate heap memory
+//! while their lock is held: any room needed to insert blocks is allocated before taking the lock.
+//! Reserved frames are never managed by the buddy allocator.
+
+use alloc::vec::Vec;
+use core::{
+    mem,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use irq_safety::MutexIrqSafe;
+use memory_structs::{Frame, FrameRange, PhysicalAddress};
+use spin::{Mutex, Once};
+use super::{AllocationError, FRAME_SIZE};
+use crate::numa::{self, MAX_NUMA_NODES};
+
+/// The largest order of a block, which is large enough for a 1 GiB huge frame.
+pub const MAX_ORDER: usize = 18;
+/// The number of different block orders, i.e., sizes.
+pub const NUM_ORDERS: usize = MAX_ORDER + 1;
+/// The maximum number of CPUs that can have their own cache of free frames.
+pub(crate) const MAX_CPUS: usize = 256;
+/// The maximum number of frames in each CPU's cache.
+const CACHE_CAPACITY: usize = 32;
+/// The number of frames that a cache is refilled with or flushed at once.
+const CACHE_BATCH: usize = CACHE_CAPACITY / 2;
+
+/// Whether the buddy allocator has taken over the allocation of general-purpose frames.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+/// Whether allocations and deallocations of single frames use the per-CPU caches.
+static CACHING_ENABLED: AtomicBool = AtomicBool::new(true);
+/// The function that returns the ID of the current CPU, which selects that CPU's cache.
+static CURRENT_CPU_FUNC: Once<fn() -> usize> = Once::new();
+
+/// Each NUMA node's sets of free blocks of each order, keyed by each block's starting frame number.
+static BUDDY_LISTS: [Mutex<BuddyLists>; MAX_NUMA_NODES] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY_LISTS: Mutex<BuddyLists> = Mutex::new(BuddyLists::new());
+    [EMPTY_LISTS; MAX_NUMA_NODES]
+};
+
+/// Each CPU's cache of free single frames.
+static FRAME_CACHES: [MutexIrqSafe<FrameCache>; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY_CACHE: MutexIrqSafe<FrameCache> = MutexIrqSafe::new(FrameCache::new());
+    [EMPTY_CACHE; MAX_CPUS]
+};
+/// The total number of frames held in all of the per-CPU caches.
+static CACHED_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+struct BuddyLists {
+    /// For each order, the sorted starting frame numbers of the free blocks of that order.
+    free: [Vec<usize>; NUM_ORDERS],
+    free_frames: usize,
+}
+
+impl BuddyLists {
+    const fn new() -> BuddyLists {
+        const EMPTY_LIST: Vec<usize> = Vec::new();
+        BuddyLists { free: [EMPTY_LIST; NUM_ORDERS], free_frames: 0 }
+    }
+
+    /// Returns the order of a free list that doesn't have room for `inserts` more blocks, if any.
+    fn list_without_room_for(&self, inserts: usize) -> Option<usize> {
+        self.free.iter().position(|list| list.capacity() - list.len() < inserts)
+    }
+
+    /// Inserts the given free block into the free list of the given order,
+    /// which must have room for it (see [`with_room_for()`]).
+    fn insert(&mut self, order: usize, number: usize) {
+        let list = &mut self.free[order];
+        debug_assert!(list.len() < list.capacity(), "BUG: buddy list has no room for another block");
+        if let Err(index) = list.binary_search(&number) {
+            list.insert(index, number);
+        }
+    }
+
+    /// Removes the given block from the free list of the given order, returning `true` if it was free.
+    fn remove(&mut self, order: usize, number: usize) -> bool {
+        let list = &mut self.free[order];
+        match list.binary_search(&number) {
+            Ok(index) => {
+                list.remove(index);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Adds the given block to the free lists, merging it with its buddy (repeatedly) if that is also free.
+    fn free_block(&mut self, mut number: usize, mut order: usize) {
+        self.free_frames += 1 << order;
+        while order < MAX_ORDER {
+            let buddy = number ^ (1 << order);
+            if !self.remove(order, buddy) {
+                break;
+            }
+            number = number.min(buddy);
+            order += 1;
+        }
+        self.insert(order, number);
+    }
+
+    /// Adds the given range of frames to the free lists, split into the largest possible aligned blocks.
+    ///
+    /// Each free list must have room for [`max_blocks_in_range()`] more blocks.
+    fn free_range(&mut self, start: usize, end_exclusive: usize) {
+        let mut number = start;
+        while number < end_exclusive {
+            let order = largest_block_order(number, end_exclusive - number);
+            self.free_block(number, order);
+            number += 1 << order;
+        }
+    }
+
+    /// Removes a free block of at least the given `order` from the free lists,
+    /// preferring the smallest such block, and returns its starting frame number and actual order.
+    ///
+    /// A larger block is not split here, because inserting its unused parts back into the free lists
+    /// may require heap allocation; the caller must free them later instead.
+    fn remove_block(&mut self, order: usize) -> Option<(usize, usize)> {
+        let found_order = (order..NUM_ORDERS).find(|&o| !self.free[o].is_empty())?;
+        let number = self.free[found_order].remove(0);
+        self.free_frames -= 1 << found_order;
+        Some((number, found_order))
+    }
+
+    /// Returns the order and starting frame number of the free block that contains the given frame number.
+    fn find_free_block_containing(&self, number: usize) -> Option<(usize, usize)> {
+        (0..NUM_ORDERS)
+            .map(|order| (order, number & !((1 << order) - 1)))
+            .find(|(order, start)| self.free[*order].binary_search(start).is_ok())
+    }
+}
+
+/// Runs `f` on the buddy lists of the given NUMA `node` once each of its free lists has room
+/// for at least `inserts` more blocks, such that `f` can insert them without allocating heap memory.
+///
+/// If a free list must grow, its new, larger storage is allocated without holding the lock,
+/// and its old storage is deallocated after releasing the lock.
+fn with_room_for<R>(node: usize, inserts: usize, f: impl FnOnce(&mut BuddyLists) -> R) -> R {
+    loop {
+        let mut lists = BUDDY_LISTS[node].lock();
+        let Some(order) = lists.list_without_room_for(inserts) else {
+            return f(&mut lists);
+        };
+        // Grow generously, such that this is rarely needed.
+        let capacity = (lists.free[order].len() + inserts) * 2;
+        drop(lists);
+
+        let mut grown = Vec::with_capacity(capacity);
+        let mut lists = BUDDY_LISTS[node].lock();
+        let list = &mut lists.free[order];
+        // Another CPU may have changed this list while the lock was released.
+        if list.len() + inserts <= capacity && list.capacity() < capacity {
+            grown.extend_from_slice(list);
+            mem::swap(list, &mut grown);
+        }
+        drop(lists);
+        // `grown` now holds the old storage (or the unused new storage), which is deallocated here.
+    }
+}
+
+/// Returns the maximum number of blocks that a range of `num_frames` frames is split into when freed.
+fn max_blocks_in_range(num_frames: usize) -> usize {
+    // At most two blocks of each order below the largest, plus as many of the largest blocks as fit.
+    2 * NUM_ORDERS + (num_frames >> MAX_ORDER)
+}
+
+/// Returns the order of the largest block that starts at the given frame number
+/// (and is thus aligned to its size) and is no larger than `max_frames`.
+fn largest_block_order(number: usize, max_frames: usize) -> usize {
+    let alignment_order = if number == 0 { MAX_ORDER } else { number.trailing_zeros() as usize };
+    let size_order = (usize::BITS - 1 - max_frames.leading_zeros()) as usize;
+    alignment_order.min(size_order).min(MAX_ORDER)
+}
+
+/// Returns the order of the smallest block that can hold `num_frames` frames.
+fn order_for(num_frames: usize) -> usize {
+    num_frames.next_power_of_two().trailing_zeros() as usize
+}
+
+fn frame(number: usize) -> Frame {
+    Frame::containing_address(PhysicalAddress::new_canonical(number * FRAME_SIZE))
+}
+
+fn frame_range(start: usize, end_exclusive: usize) -> Option<FrameRange> {
+    (start < end_exclusive).then(|| FrameRange::new(frame(start), frame(end_exclusive - 1)))
+}
+
+
+/// A per-CPU cache of free single frames.
+struct FrameCache {
+    frames: [usize; CACHE_CAPACITY],
+    len: usize,
+}
+
+impl FrameCache {
+    const fn new() -> FrameCache {
+        FrameCache { frames: [0; CACHE_CAPACITY], len: 0 }
+    }
+}
+
+/// Returns the current CPU's cache and the NUMA node of the current CPU,
+/// if per-CPU caching is enabled and the current CPU is known.
+fn current_cache() -> Option<(&'static MutexIrqSafe<FrameCache>, usize)> {
+    if !CACHING_ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let cpu = current_cpu()?;
+    FRAME_CACHES.get(cpu).map(|cache| (cache, numa::cpu_node(cpu)))
+}
+
+/// Returns the ID of the current CPU, if known.
+pub(crate) fn current_cpu() -> Option<usize> {
+    CURRENT_CPU_FUNC.get().map(|func| func())
+}
+
+/// Returns the NUMA node of the current CPU.
+pub(crate) fn current_node() -> usize {
+    current_cpu().map_or(0, numa::cpu_node)
+}
+
+/// Adds the given range of frames to the buddy lists of the NUMA node(s) that they belong to.
+fn free_into_lists(start: usize, end_exclusive: usize) {
+    numa::for_each_node_piece(start, end_exclusive, |node, piece_start, piece_end| {
+        with_room_for(node, max_blocks_in_range(piece_end - piece_start), |lists| lists.free_range(piece_start, piece_end));
+    });
+}
+
+/// Adds the given single frame to the buddy lists of the NUMA node that it belongs to.
+fn free_frame_into_lists(number: usize) {
+    with_room_for(numa::node_of_frame(number), 1, |lists| lists.free_block(number, 0));
+}
+
+
+/// Returns `true` if the buddy allocator has taken over the allocation of general-purpose frames.
+pub(crate) fn is_active() -> bool {
+    ACTIVE.load(Ordering::Acquire)
+}
+
+/// Hands the given free general-purpose frames over to the buddy allocator,
+/// which then takes over the allocation of all general-purpose frames.
+pub(crate) fn activate<I: IntoIterator<Item = FrameRange>>(free_frames: I) {
+    for frames in free_frames {
+        free_into_lists(frames.start().number(), frames.end().number() + 1);
+    }
+    ACTIVE.store(true, Ordering::Release);
+}
+
+/// Moves every free frame into the buddy lists of the NUMA node that it belongs to,
+/// which must be done after the NUMA topology changes.
+pub(crate) fn redistribute_free_frames() {
+    drain_caches();
+    for lists in BUDDY_LISTS.iter() {
+        let free_blocks = {
+            let mut lists = lists.lock();
+            lists.free_frames = 0;
+            mem::take(&mut lists.free)
+        };
+        for (order, blocks) in free_blocks.iter().enumerate() {
+            for &number in blocks {
+                free_into_lists(number, number + (1 << order));
+            }
+        }
+    }
+}
+
+/// Sets the function used to find the current CPU's cache of free frames.
+pub(crate) fn set_current_cpu_func(func: fn() -> usize) {
+    CURRENT_CPU_FUNC.call_once(|| func);
+}
+
+/// Enables or disables the per-CPU caches of free single frames.
+/// Disabling them returns all cached frames to the buddy lists.
+pub(crate) fn set_per_cpu_caching(enabled: bool) {
+    CACHING_ENABLED.store(enabled, Ordering::Relaxed);
+    if !enabled {
+        drain_caches();
+    }
+}
+
+/// Returns all frames in all per-CPU caches to the buddy lists.
+///
+/// Like all other frees into the buddy lists, this may allocate heap memory, but not while holding any lock.
+fn drain_caches() {
+    for cache in FRAME_CACHES.iter() {
+        let mut drained = [0; CACHE_CAPACITY];
+        let len = {
+            let mut cache = cache.lock();
+            let len = cache.len;
+            drained[..len].copy_from_slice(&cache.frames[..len]);
+            cache.len = 0;
+            len
+        };
+        for &number in &drained[..len] {
+            free_frame_into_lists(number);
+        }
+        CACHED_FRAMES.fetch_sub(len, Ordering::Relaxed);
+    }
+}
+
+/// Allocates `num_frames` frames that start at a multiple of `alignment` frames.
+///
+/// If `node` is `Some`, the frames are only allocated from that NUMA node.
+/// Otherwise, they're allocated from the current CPU's NUMA node if possible,
+/// or from the nearest other NUMA node that has enough free frames.
+///
+/// Returns the allocated frames and the unused frames of the allocated block, if any,
+/// which the caller must free (via [`free_range()`]) once it's safe to allocate heap memory.
+pub(crate) fn allocate(
+    num_frames: usize,
+    alignment: usize,
+    node: Option<usize>,
+) -> Result<(FrameRange, Option<FrameRange>), AllocationError> {
+    if num_frames == 1 && alignment == 1 {
+        if let Some(result) = allocate_cached(node) {
+            return Ok(result);
+        }
+    }
+
+    let order = order_for(num_frames.max(alignment));
+    if order > MAX_ORDER {
+        return Err(AllocationError::OutOfAddressSpace(num_frames));
+    }
+    let Some((number, block_order)) = remove_block_near(order, node) else {
+        // A few frames may be stranded in the per-CPU caches, of which we can only use a single frame,
+        // as merging them back into larger blocks may require heap allocation.
+        if num_frames == 1 && alignment == 1 && node.is_none() {
+            if let Some(number) = steal_cached_frame() {
+                return Ok((FrameRange::new(frame(number), frame(number)), None));
+            }
+        }
+        return Err(AllocationError::OutOfAddressSpace(num_frames));
+    };
+    let allocated = FrameRange::new(frame(number), frame(number + num_frames - 1));
+    Ok((allocated, frame_range(number + num_frames, number + (1 << block_order))))
+}
+
+/// Removes a free block of at least the given `order` from the buddy lists of the given NUMA `node`,
+/// or if `node` is `None`, from those of the current CPU's NUMA node or the nearest other node.
+fn remove_block_near(order: usize, node: Option<usize>) -> Option<(usize, usize)> {
+    if let Some(node) = node {
+        return BUDDY_LISTS.get(node)?.lock().remove_block(order);
+    }
+    let (nodes, count) = numa::nodes_by_distance(current_node());
+    nodes[..count].iter().find_map(|&node| BUDDY_LISTS[node].lock().remove_block(order))
+}
+
+/// Takes a single frame from any CPU's cache.
+fn steal_cached_frame() -> Option<usize> {
+    FRAME_CACHES.iter().find_map(|cache| {
+        let mut cache = cache.lock();
+        if cache.len == 0 {
+            return None;
+        }
+        cache.len -= 1;
+        CACHED_FRAMES.fetch_sub(1, Ordering::Relaxed);
+        Some(cache.frames[cache.len])
+    })
+}
+
+/// Allocates a single frame from the current CPU's cache, refilling it with a batch of frames if it's empty.
+/// If a NUMA `node` is given, the cache is only used if the current CPU is on that node.
+///
+/// Returns the allocated frame and the unused frames of the block used to refill the cache, if any.
+fn allocate_cached(node: Option<usize>) -> Option<(FrameRange, Option<FrameRange>)> {
+    let (cache, cpu_node) = current_cache()?;
+    if node.map_or(false, |node| node != cpu_node) {
+        return None;
+    }
+    let mut cache = cache.lock();
+    let mut unused = None;
+    if cache.len == 0 {
+        let (number, block_order) = remove_block_near(order_for(CACHE_BATCH), node)?;
+        for (i, slot) in cache.frames[..CACHE_BATCH].iter_mut().enumerate() {
+            *slot = number + i;
+        }
+        cache.len = CACHE_BATCH;
+        CACHED_FRAMES.fetch_add(CACHE_BATCH, Ordering::Relaxed);
+        unused = frame_range(number + CACHE_BATCH, number + (1 << block_order));
+    }
+    cache.len -= 1;
+    CACHED_FRAMES.fetch_sub(1, Ordering::Relaxed);
+    let number = cache.frames[cache.len];
+    Some((FrameRange::new(frame(number), frame(number)), unused))
+}
+
+/// Allocates the `num_frames` frames starting at the given `start` frame.
+///
+/// Returns the allocated frames and the leftover frames before and after them
+/// within the allocated blocks, which the caller must free (via [`free_range()`])
+/// once it's safe to allocate heap memory.
+pub(crate) fn allocate_at(
+    start: Frame,
+    num_frames: usize,
+) -> Result<(FrameRange, Option<FrameRange>, Option<FrameRange>), AllocationError> {
+    allocate_at_internal(start, num_frames).or_else(|_| {
+        // Some of the requested frames may be in the per-CPU caches.
+        drain_caches();
+        allocate_at_internal(start, num_frames)
+    })
+}
+
+fn allocate_at_internal(
+    start: Frame,
+    num_frames: usize,
+) -> Result<(FrameRange, Option<FrameRange>, Option<FrameRange>), AllocationError> {
+    let first = start.number();
+    let end_exclusive = first.checked_add(num_frames)
+        .ok_or(AllocationError::AddressNotFree(start, num_frames))?;
+    // Free blocks never span multiple NUMA nodes, so neither can the requested frames.
+    let node = numa::node_of_frame(first);
+    if numa::node_of_frame(end_exclusive - 1) != node {
+        return Err(AllocationError::AddressNotFree(start, num_frames));
+    }
+    let mut lists = BUDDY_LISTS[node].lock();
+
+    // First, ensure that every requested frame is within a free block.
+    let mut number = first;
+    let mut blocks_start = None;
+    let mut blocks_end = first;
+    while number < end_exclusive {
+        let (order, block) = lists.find_free_block_containing(number)
+            .ok_or(AllocationError::AddressNotFree(start, num_frames))?;
+        blocks_start.get_or_insert(block);
+        blocks_end = block + (1 << order);
+        number = blocks_end;
+    }
+    let blocks_start = blocks_start.unwrap_or(first);
+
+    // Second, remove all of those blocks from the free lists.
+    let mut number = first;
+    while number < end_exclusive {
+        let Some((order, block)) = lists.find_free_block_containing(number) else { break };
+        lists.remove(order, block);
+        lists.free_frames -= 1 << order;
+        number = block + (1 << order);
+    }
+    drop(lists);
+
+    Ok((
+        FrameRange::new(frame(first), frame(end_exclusive - 1)),
+        frame_range(blocks_start, first),
+        frame_range(end_exclusive, blocks_end),
+    ))
+}
+
+/// Frees the given general-purpose frames, which were allocated by the buddy allocator
+/// or by the original allocator before the buddy allocator was activated.
+pub(crate) fn free(frames: &FrameRange) {
+    if frames.size_in_frames() == 1 {
+        // Only frames on the current CPU's NUMA node are kept in its cache.
+        if let Some((cache, _)) = current_cache()
+            .filter(|(_, cpu_node)| *cpu_node == numa::node_of_frame(frames.start().number()))
+        {
+            let mut flushed = [0; CACHE_BATCH];
+            let is_flushed = {
+                let mut cache = cache.lock();
+                let is_full = cache.len == CACHE_CAPACITY;
+                if is_full {
+                    // Flush the older half of the cache to make room.
+                    flushed.copy_from_slice(&cache.frames[..CACHE_BATCH]);
+                    cache.frames.copy_within(CACHE_BATCH.., 0);
+                    cache.len -= CACHE_BATCH;
+                }
+                let len = cache.len;
+                cache.frames[len] = frames.start().number();
+                cache.len += 1;
+                is_full
+            };
+            CACHED_FRAMES.fetch_add(1, Ordering::Relaxed);
+            // The cache's lock must be released before inserting into the buddy lists,
+            // as that may allocate heap memory, which may in turn allocate frames.
+            if is_flushed {
+                for &number in &flushed {
+                    free_frame_into_lists(number);
+                }
+                CACHED_FRAMES.fetch_sub(CACHE_BATCH, Ordering::Relaxed);
+            }
+            return;
+        }
+    }
+    free_range(frames);
+}
+
+/// Frees the given general-purpose frames directly into the buddy lists, bypassing the per-CPU caches.
+pub(crate) fn free_range(frames: &FrameRange) {
+    if frames.size_in_frames() == 0 {
+        return;
+    }
+    free_into_lists(frames.start().number(), frames.end().number() + 1);
+}
+
+/// Statistics about the buddy allocator's free general-purpose frames.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BuddyStats {
+    /// The number of free frames in the buddy lists, excluding those in the per-CPU caches.
+    pub free_frames: usize,
+    /// The number of free frames held in the per-CPU caches.
+    pub cached_frames: usize,
+    /// The number of free blocks of each order, i.e., of `2^order` frames.
+    pub free_blocks: [usize; NUM_ORDERS],
+    /// The number of free frames in each NUMA node's buddy lists, excluding those in the per-CPU caches.
+    pub free_frames_by_node: [usize; MAX_NUMA_NODES],
+}
+
+/// Returns statistics about the buddy allocator's free frames.
+pub(crate) fn stats() -> BuddyStats {
+    let mut stats = BuddyStats {
+        cached_frames: CACHED_FRAMES.load(Ordering::Relaxed),
+        ..Default::default()
+    };
+    for (node, lists) in BUDDY_LISTS.iter().enumerate() {
+        let lists = lists.lock();
+        for (count, set) in stats.free_blocks.iter_mut().zip(lists.free.iter()) {
+            *count += set.len();
+        }
+        stats.free_frames += lists.free_frames;
+        stats.free_frames_by_node[node] = lists.free_frames;
+    }
+    stats
+}
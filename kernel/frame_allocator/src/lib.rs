@@ -8,15 +8,23 @@
 //! and does so behind the scenes using the same single interface. 
 //! Early pre-heap allocations are limited to tracking a small number of available chunks (currently 32).
 //! 
-//! Once heap allocation is available, it uses a dynamically-allocated list of frame chunks to track allocations.
+//! Once heap allocation is available, free general-purpose frames are handed over to a buddy allocator
+//! (see the `buddy` module), which merges freed frames back into larger blocks and keeps
+//! a per-CPU cache of single frames to avoid contention on its lock.
+//! Reserved frames are always tracked using a dynamically-allocated list of frame chunks.
+//! The `legacy_frame_allocator` cfg option keeps using that list of chunks for general-purpose frames as well.
+//!
+//! Once the system's NUMA topology is known (see [`set_numa_topology()`]), the buddy allocator keeps
+//! each NUMA node's free frames separately, allowing frames to be allocated from a specific node
+//! via [`allocate_frames_on_node()`]. Other allocations prefer the current CPU's NUMA node.
 //! 
 //! The core allocation function is [`allocate_frames_deferred()`](fn.allocate_frames_deferred.html), 
 //! but there are several convenience functions that offer simpler interfaces for general usage. 
 //!
 //! # Notes and Missing Features
-//! This allocator currently does **not** merge freed chunks (de-fragmentation). 
-//! We don't need to do so until we actually run out of address space or until 
-//! a requested address is in a chunk that needs to be merged.
+//! The list of chunks does **not** merge freed chunks (de-fragmentation),
+//! so frames freed before the buddy allocator takes over (or with `legacy_frame_allocator`)
+//! remain in separate chunks.
 
 #![no_std]
 
@@ -27,21 +35,30 @@ extern crate memory_structs;
 extern crate spin;
 #[macro_use] extern crate static_assertions;
 extern crate intrusive_collections;
+extern crate task_group;
+extern crate memory_accounting;
+extern crate irq_safety;
 
 #[cfg(test)]
 mod test;
 
 mod static_array_rb_tree;
+mod buddy;
+mod numa;
+mod owners;
 // mod static_array_linked_list;
 
 
-use core::{borrow::Borrow, cmp::{Ordering, min, max}, fmt, ops::{Deref, DerefMut}, marker::PhantomData};
+use core::{borrow::Borrow, cmp::{Ordering, min, max}, fmt, ops::{Deref, DerefMut}, marker::PhantomData, sync::atomic::{self, AtomicUsize}};
 use kernel_config::memory::*;
-use memory_structs::{PhysicalAddress, Frame, FrameRange};
+use memory_structs::{PhysicalAddress, Frame, FrameRange, PageSize};
 use spin::Mutex;
 use intrusive_collections::Bound;
 use static_array_rb_tree::*;
 
+pub use buddy::BuddyStats;
+pub use numa::MAX_NUMA_NODES;
+
 const FRAME_SIZE: usize = PAGE_SIZE;
 const MIN_FRAME: Frame = Frame::containing_address(PhysicalAddress::zero());
 const MAX_FRAME: Frame = Frame::containing_address(PhysicalAddress::new_canonical(usize::MAX));
@@ -62,6 +79,44 @@ static GENERAL_REGIONS: Mutex<StaticArrayRBTree<Chunk>> = Mutex::new(StaticArray
 /// rather just where they exist and which regions are known to this allocator.
 static RESERVED_REGIONS: Mutex<StaticArrayRBTree<Chunk>> = Mutex::new(StaticArrayRBTree::empty());
 
+/// For each CPU, the number of frame allocator operations that it's in the middle of,
+/// during which it may hold the frame allocator's locks. See [`is_busy_on_current_cpu()`].
+static BUSY_CPUS: [AtomicUsize; buddy::MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const IDLE: AtomicUsize = AtomicUsize::new(0);
+    [IDLE; buddy::MAX_CPUS]
+};
+
+/// Marks the current CPU as being in the middle of a frame allocator operation until dropped.
+struct BusyGuard(usize);
+
+impl BusyGuard {
+    fn new() -> BusyGuard {
+        // Until the current CPU can be identified, only the BSP is running.
+        let cpu = buddy::current_cpu().unwrap_or(0) % buddy::MAX_CPUS;
+        BUSY_CPUS[cpu].fetch_add(1, atomic::Ordering::Acquire);
+        BusyGuard(cpu)
+    }
+}
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) {
+        BUSY_CPUS[self.0].fetch_sub(1, atomic::Ordering::Release);
+    }
+}
+
+/// Returns `true` if the current CPU is in the middle of allocating or deallocating frames,
+/// in which case it may hold the frame allocator's locks.
+///
+/// Code that interrupts other code, such as a page fault resolver, must not allocate frames if this is `true`,
+/// as the interrupted code may hold those locks, which would deadlock.
+/// The frame allocator's locks don't disable preemption, so this is also `true` if another task
+/// was preempted on this CPU in the middle of a frame allocator operation.
+pub fn is_busy_on_current_cpu() -> bool {
+    let cpu = buddy::current_cpu().unwrap_or(0) % buddy::MAX_CPUS;
+    BUSY_CPUS[cpu].load(atomic::Ordering::Acquire) != 0
+}
+
 
 /// Initialize the frame allocator with the given list of available and reserved physical memory regions.
 ///
@@ -449,10 +504,18 @@ fn into_allocated_frames(frames: FrameRange) -> AllocatedFrames {
 impl Drop for AllocatedFrames {
     fn drop(&mut self) {
         if self.size_in_frames() == 0 { return; }
+        let _busy = BusyGuard::new();
 
         let (list, typ) = if frame_is_in_list(&RESERVED_REGIONS.lock(), self.start()) {
             (&FREE_RESERVED_FRAMES_LIST, MemoryRegionType::Reserved)
         } else {
+            // General-purpose frames may have been charged to a task group when allocated.
+            owners::release(&self.frames);
+            memory_accounting::uncharge_frames(self.size_in_frames());
+            if buddy::is_active() {
+                buddy::free(&self.frames);
+                return;
+            }
             (&FREE_GENERAL_FRAMES_LIST, MemoryRegionType::Free)
         };
         // trace!("frame_allocator: deallocating {:?}, typ {:?}", self, typ);
@@ -570,9 +633,11 @@ impl<'list> DeferredAllocAction<'list> {
 }
 impl<'list> Drop for DeferredAllocAction<'list> {
     fn drop(&mut self) {
+        let _busy = BusyGuard::new();
         // Insert all of the chunks, both allocated and free ones, into the list. 
         if self.free1.size_in_frames() > 0 {
             match self.free1.typ {
+                MemoryRegionType::Free if buddy::is_active() => buddy::free_range(&self.free1),
                 MemoryRegionType::Free     => { self.free_list.lock().insert(self.free1.clone()).unwrap(); }
                 MemoryRegionType::Reserved => { self.reserved_list.lock().insert(self.free1.clone()).unwrap(); }
                 _ => error!("BUG likely: DeferredAllocAction encountered free1 chunk {:?} of a type Unknown", self.free1),
@@ -580,6 +645,7 @@ impl<'list> Drop for DeferredAllocAction<'list> {
         }
         if self.free2.size_in_frames() > 0 {
             match self.free2.typ {
+                MemoryRegionType::Free if buddy::is_active() => buddy::free_range(&self.free2),
                 MemoryRegionType::Free     => { self.free_list.lock().insert(self.free2.clone()).unwrap(); }
                 MemoryRegionType::Reserved => { self.reserved_list.lock().insert(self.free2.clone()).unwrap(); }
                 _ => error!("BUG likely: DeferredAllocAction encountered free2 chunk {:?} of a type Unknown", self.free2),
@@ -694,7 +760,8 @@ fn find_specific_chunk(
 /// Searches the given `list` for any chunk large enough to hold at least `num_frames`.
 fn find_any_chunk<'list>(
     list: &'list mut StaticArrayRBTree<Chunk>,
-    num_frames: usize
+    num_frames: usize,
+    alignment: usize,
 ) -> Result<(AllocatedFrames, DeferredAllocAction<'static>), AllocationError> {
     // During the first pass, we ignore designated regions.
     match list.0 {
@@ -705,8 +772,8 @@ fn find_any_chunk<'list>(
                     if  chunk.size_in_frames() < num_frames || chunk.typ != MemoryRegionType::Free {
                         continue;
                     } 
-                    else {
-                        return allocate_from_chosen_chunk(*chunk.start(), num_frames, &chunk.clone(), ValueRefMut::Array(elem));
+                    if let Some(start_frame) = aligned_start_within(chunk, num_frames, alignment) {
+                        return allocate_from_chosen_chunk(start_frame, num_frames, &chunk.clone(), ValueRefMut::Array(elem));
                     }
                 }
             }
@@ -717,8 +784,10 @@ fn find_any_chunk<'list>(
             // This results in an O(1) allocation time in the general case, until all address ranges are already in use.
             let mut cursor = tree.upper_bound_mut(Bound::<&Chunk>::Unbounded);
             while let Some(chunk) = cursor.get().map(|w| w.deref()) {
-                if num_frames <= chunk.size_in_frames() && chunk.typ == MemoryRegionType::Free {
-                    return allocate_from_chosen_chunk(*chunk.start(), num_frames, &chunk.clone(), ValueRefMut::RBTree(cursor));
+                if chunk.typ == MemoryRegionType::Free {
+                    if let Some(start_frame) = aligned_start_within(chunk, num_frames, alignment) {
+                        return allocate_from_chosen_chunk(start_frame, num_frames, &chunk.clone(), ValueRefMut::RBTree(cursor));
+                    }
                 }
                 warn!("Frame allocator: inefficient scenario: had to search multiple chunks \
                     (skipping {:?}) while trying to allocate {} frames at any address.",
@@ -737,6 +806,19 @@ fn find_any_chunk<'list>(
 }
 
 
+/// Returns the first `Frame` in the given `chunk` that is aligned to `alignment` frames
+/// and from which `num_frames` frames fit within the remainder of that `chunk`.
+fn aligned_start_within(chunk: &Chunk, num_frames: usize, alignment: usize) -> Option<Frame> {
+    let start = chunk.start().number();
+    let aligned_start = start.checked_add((alignment - start % alignment) % alignment)?;
+    let aligned_end = aligned_start.checked_add(num_frames - 1)?;
+    if aligned_end <= chunk.end().number() {
+        Some(*chunk.start() + (aligned_start - start))
+    } else {
+        None
+    }
+}
+
 
 /// The final part of the main allocation routine that splits the given chosen chunk
 /// into multiple smaller chunks, thereby "allocating" frames from it.
@@ -910,6 +992,9 @@ fn add_reserved_region(
 /// Allocation is based on a red-black tree and is thus `O(log(n))`.
 /// Fragmentation isn't cleaned up until we're out of address space, but that's not really a big deal.
 /// 
+/// If the current task belongs to a task group, general-purpose frames are charged to that group,
+/// and allocation fails if that would exceed the group's frame limit.
+/// 
 /// # Arguments
 /// * `requested_paddr`: if `Some`, the returned `AllocatedFrames` will start at the `Frame`
 ///   containing this `PhysicalAddress`. 
@@ -930,7 +1015,43 @@ pub fn allocate_frames_deferred(
         warn!("frame_allocator: requested an allocation of 0 frames... stupid!");
         return Err("cannot allocate zero frames");
     }
-    
+
+    // Charge the frames to the current task's group up front in order to enforce its frame limit.
+    // Only general-purpose frames are charged, so the charge is undone if reserved frames were allocated.
+    let _busy = BusyGuard::new();
+    let owner = task_group::charge_current_frames(num_frames)?;
+    let result = find_free_frames(requested_paddr, num_frames);
+    account_allocated_frames(&result, num_frames, owner);
+    result
+}
+
+/// Completes the accounting of an allocation of `num_frames` that was charged to the `owner` group up front.
+///
+/// Successfully-allocated general-purpose frames are charged to memory accounting and recorded as owned by `owner`.
+/// Only general-purpose frames are charged, so the group's charge is undone
+/// if the allocation failed or reserved frames were allocated.
+fn account_allocated_frames<E>(
+    result: &Result<(AllocatedFrames, DeferredAllocAction<'static>), E>,
+    num_frames: usize,
+    owner: Option<task_group::TaskGroupRef>,
+) {
+    match (result, owner) {
+        (Ok((af, _)), owner) if !frame_is_in_list(&RESERVED_REGIONS.lock(), af.start()) => {
+            memory_accounting::charge_frames(num_frames);
+            if let Some(group) = owner {
+                owners::record(af, group);
+            }
+        }
+        (_, Some(group)) => group.uncharge_frames(num_frames),
+        _ => { }
+    }
+}
+
+/// The internal routine of [`allocate_frames_deferred()`] that actually finds free frames.
+fn find_free_frames(
+    requested_paddr: Option<PhysicalAddress>,
+    num_frames: usize,
+) -> Result<(AllocatedFrames, DeferredAllocAction<'static>), &'static str> {
     if let Some(paddr) = requested_paddr {
         let start_frame = Frame::containing_address(paddr);
         let end_frame = start_frame + (num_frames - 1);
@@ -942,7 +1063,12 @@ pub fn allocate_frames_deferred(
             // If allocation failed, then the requested `start_frame` may be found in the general-purpose list
             // or may represent a new, previously-unknown reserved region that we must add.
             // We first attempt to allocate it from the general-purpose free regions.
-            if let Ok(result) = find_specific_chunk(&mut FREE_GENERAL_FRAMES_LIST.lock(), start_frame, num_frames) {
+            let general_result = if buddy::is_active() {
+                allocate_specific_from_buddy(start_frame, num_frames)
+            } else {
+                find_specific_chunk(&mut FREE_GENERAL_FRAMES_LIST.lock(), start_frame, num_frames)
+            };
+            if let Ok(result) = general_result {
                 Ok(result)
             } 
             // If we failed to allocate the requested frames from the general list,
@@ -964,11 +1090,71 @@ pub fn allocate_frames_deferred(
                 Err(AllocationError::AddressNotFree(start_frame, num_frames))
             }
         }
+    } else if buddy::is_active() {
+        allocate_from_buddy(num_frames, 1, None)
     } else {
-        find_any_chunk(&mut FREE_GENERAL_FRAMES_LIST.lock(), num_frames)
+        find_any_chunk(&mut FREE_GENERAL_FRAMES_LIST.lock(), num_frames, 1)
     }.map_err(From::from) // convert from AllocationError to &str
 }
 
+/// Allocates `num_frames` general-purpose frames aligned to `alignment` frames from the buddy allocator,
+/// optionally only from the given NUMA `node`.
+fn allocate_from_buddy(
+    num_frames: usize,
+    alignment: usize,
+    node: Option<usize>,
+) -> Result<(AllocatedFrames, DeferredAllocAction<'static>), AllocationError> {
+    let (frames, unused) = buddy::allocate(num_frames, alignment, node)?;
+    Ok((
+        into_allocated_frames(frames),
+        DeferredAllocAction::new(unused.map(free_chunk), None),
+    ))
+}
+
+/// Allocates the `num_frames` general-purpose frames starting at `start_frame` from the buddy allocator.
+fn allocate_specific_from_buddy(
+    start_frame: Frame,
+    num_frames: usize,
+) -> Result<(AllocatedFrames, DeferredAllocAction<'static>), AllocationError> {
+    let (frames, before, after) = buddy::allocate_at(start_frame, num_frames)?;
+    Ok((
+        into_allocated_frames(frames),
+        DeferredAllocAction::new(before.map(free_chunk), after.map(free_chunk)),
+    ))
+}
+
+/// Returns a free general-purpose `Chunk` of the given frames.
+fn free_chunk(frames: FrameRange) -> Chunk {
+    Chunk { typ: MemoryRegionType::Free, frames }
+}
+
+
+/// Allocates `num_huge_frames` contiguous huge frames of the given `page_size`
+/// from general-purpose memory, i.e., a range of physical frames
+/// whose starting address is aligned to that `page_size`.
+///
+/// The returned `AllocatedFrames` span `num_huge_frames * page_size.num_4k_pages()` normal frames,
+/// and are intended to be mapped using huge page mappings.
+/// Like [`allocate_frames_deferred()`], this charges the frames to the current task's group.
+pub fn allocate_huge_frames(page_size: PageSize, num_huge_frames: usize) -> Option<AllocatedFrames> {
+    if num_huge_frames == 0 {
+        warn!("frame_allocator: requested an allocation of 0 huge frames... stupid!");
+        return None;
+    }
+    let num_frames = num_huge_frames.checked_mul(page_size.num_4k_pages())?;
+    let _busy = BusyGuard::new();
+    let owner = task_group::charge_current_frames(num_frames).ok()?;
+    let alignment = page_size.num_4k_pages();
+    // The lock must be released before the deferred action is dropped, as that re-acquires it.
+    let allocation = if buddy::is_active() {
+        allocate_from_buddy(num_frames, alignment, None)
+    } else {
+        find_any_chunk(&mut FREE_GENERAL_FRAMES_LIST.lock(), num_frames, alignment)
+    };
+    account_allocated_frames(&allocation, num_frames, owner);
+    allocation.ok().map(|(af, _action)| af)
+}
+
 
 /// Similar to [`allocated_frames_deferred()`](fn.allocate_frames_deferred.html),
 /// but accepts a size value for the allocated frames in number of bytes instead of number of frames. 
@@ -1029,16 +1215,124 @@ pub fn allocate_frames_at(paddr: PhysicalAddress, num_frames: usize) -> Result<A
 }
 
 
+/// Allocates the given number of general-purpose frames from the given NUMA `node`,
+/// with no constraints on the starting physical address.
+///
+/// Unlike other allocation functions, which prefer the current CPU's NUMA node but fall back to other nodes,
+/// this fails if the given `node` doesn't have enough free frames.
+/// Like [`allocate_frames_deferred()`], this charges the frames to the current task's group.
+pub fn allocate_frames_on_node(node: usize, num_frames: usize) -> Option<AllocatedFrames> {
+    if num_frames == 0 || node >= numa_node_count() {
+        return None;
+    }
+    if !buddy::is_active() {
+        // Without the buddy allocator, all frames are on the only node.
+        return allocate_frames(num_frames);
+    }
+    let _busy = BusyGuard::new();
+    let owner = task_group::charge_current_frames(num_frames).ok()?;
+    let allocation = allocate_from_buddy(num_frames, 1, Some(node));
+    account_allocated_frames(&allocation, num_frames, owner);
+    allocation.ok().map(|(af, _action)| af)
+}
+
+
+/// Sets the system's NUMA topology, after which each NUMA node's free frames are kept separately.
+///
+/// * `memory_ranges`: the ACPI proximity domain of each range of physical memory.
+///   Frames outside of these ranges are considered to be on node 0.
+/// * `cpu_domains`: the proximity domain of each CPU, identified by its APIC ID.
+/// * `distance`: returns the relative distance from one proximity domain to another, if known,
+///   in which the distance from a domain to itself is 10.
+///
+/// Proximity domains need not be contiguous: the NUMA nodes used by all other functions in this crate
+/// are dense indices from `0` to [`numa_node_count()`], assigned to the proximity domains in increasing order.
+/// There can be at most [`MAX_NUMA_NODES`] different proximity domains.
+/// This requires the buddy allocator, so it must be invoked after [`convert_to_heap_allocated()`].
+pub fn set_numa_topology<M, C, D>(memory_ranges: M, cpu_domains: C, distance: D) -> Result<(), &'static str>
+where
+    M: IntoIterator<Item = (usize, FrameRange)>,
+    C: IntoIterator<Item = (usize, usize)>,
+    D: Fn(usize, usize) -> Option<u8>,
+{
+    if !buddy::is_active() {
+        return Err("NUMA-aware frame allocation requires the buddy frame allocator");
+    }
+    numa::set_topology(
+        memory_ranges.into_iter().map(|(domain, frames)| (domain, frames.start().number(), frames.end().number() + 1)),
+        cpu_domains,
+        distance,
+    )?;
+    let _busy = BusyGuard::new();
+    buddy::redistribute_free_frames();
+    Ok(())
+}
+
+/// Returns the number of NUMA nodes in the system, which is 1 if the NUMA topology is unknown.
+pub fn numa_node_count() -> usize {
+    numa::node_count()
+}
+
+/// Returns the NUMA node of the CPU that is currently executing.
+pub fn current_numa_node() -> usize {
+    buddy::current_node()
+}
+
+/// Returns the NUMA node that the given physical address belongs to.
+pub fn numa_node_of(paddr: PhysicalAddress) -> usize {
+    numa::node_of_frame(Frame::containing_address(paddr).number())
+}
+
+/// Returns the relative distance from the NUMA node `from` to the NUMA node `to`,
+/// or `None` if either node doesn't exist.
+pub fn numa_distance(from: usize, to: usize) -> Option<u8> {
+    numa::distance(from, to)
+}
+
+
 /// Converts the frame allocator from using static memory (a primitive array) to dynamically-allocated memory.
 /// 
 /// Call this function once heap allocation is available. 
 /// Calling this multiple times is unnecessary but harmless, as it will do nothing after the first invocation.
 #[doc(hidden)] 
 pub fn convert_to_heap_allocated() {
+    let _busy = BusyGuard::new();
     FREE_GENERAL_FRAMES_LIST.lock().convert_to_heap_allocated();
     FREE_RESERVED_FRAMES_LIST.lock().convert_to_heap_allocated();
     GENERAL_REGIONS.lock().convert_to_heap_allocated();
     RESERVED_REGIONS.lock().convert_to_heap_allocated();
+
+    #[cfg(not(legacy_frame_allocator))] {
+        if !buddy::is_active() {
+            // Hand all free general-purpose frames over to the buddy allocator,
+            // which requires heap allocation to track its free blocks.
+            let free_chunks = core::mem::take(&mut *FREE_GENERAL_FRAMES_LIST.lock());
+            buddy::activate(free_chunks.iter().map(|chunk| chunk.frames.clone()));
+        }
+    }
+}
+
+/// Sets the function that returns the ID of the current CPU,
+/// which the frame allocator uses to select that CPU's cache of free frames.
+///
+/// Until this is set, frames are not cached on a per-CPU basis.
+/// This can only be set once; subsequent calls do nothing.
+pub fn set_current_cpu_func(func: fn() -> usize) {
+    buddy::set_current_cpu_func(func);
+}
+
+/// Enables or disables the per-CPU caches of free frames, which are enabled by default.
+///
+/// Disabling them returns all cached frames to the allocator's shared pool of free frames.
+pub fn set_per_cpu_caching(enabled: bool) {
+    let _busy = BusyGuard::new();
+    buddy::set_per_cpu_caching(enabled);
+}
+
+/// Returns statistics about the buddy allocator's free frames,
+/// or `None` if the buddy allocator isn't being used to allocate general-purpose frames.
+pub fn buddy_allocator_stats() -> Option<BuddyStats> {
+    buddy::is_active().then(buddy::stats)
 }
 
 /// A debugging function used to dump the full internal state of the frame allocator. 
@@ -1046,6 +1340,9 @@ pub fn convert_to_heap_allocated() {
 pub fn dump_frame_allocator_state() {
     debug!("----------------- FREE GENERAL FRAMES ---------------");
     FREE_GENERAL_FRAMES_LIST.lock().iter().for_each(|e| debug!("\t {:?}", e) );
+    if let Some(stats) = buddy_allocator_stats() {
+        debug!("\t buddy allocator: {:?}", stats);
+    }
     debug!("-----------------------------------------------------");
     debug!("----------------- FREE RESERVED FRAMES --------------");
     FREE_RESERVED_FRAMES_LIST.lock().iter().for_each(|e| debug!("\t {:?}", e) );
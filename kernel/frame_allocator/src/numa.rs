@@ -0,0 +1,168 @@
+//! The NUMA topology of the system, i.e., which NUMA node each range of physical memory
+//! and each CPU belongs to, and the relative distances between NUMA nodes.
+//!
+//! The buddy allocator keeps a separate pool of free frames for each NUMA node,
+//! using this topology to determine which pool a frame belongs to
+//! and which other nodes' pools to fall back to once a node's own pool runs out.
+//! Until a topology is set, all memory and all CPUs are considered to be on node 0.
+//!
+//! NUMA nodes are dense indices from `0` to [`node_count()`], which are assigned to the system's
+//! (possibly sparse) proximity domains in increasing order when the topology is set.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use spin::RwLock;
+
+/// The maximum number of NUMA nodes whose memory the frame allocator can track separately.
+pub const MAX_NUMA_NODES: usize = 16;
+/// The maximum number of CPUs whose NUMA node can be known.
+const MAX_CPUS: usize = 256;
+
+/// The distance from a NUMA node to itself, as defined by ACPI.
+const LOCAL_DISTANCE: u8 = 10;
+/// The default distance between two different NUMA nodes, used if their actual distance is unknown.
+const REMOTE_DISTANCE: u8 = 20;
+
+/// The number of NUMA nodes in the system.
+static NODE_COUNT: AtomicUsize = AtomicUsize::new(1);
+/// The ranges of frames that belong to each NUMA node, as `(start, end_exclusive, node)` tuples
+/// of frame numbers, sorted by their starting frame number.
+static NODE_RANGES: RwLock<Vec<(usize, usize, usize)>> = RwLock::new(Vec::new());
+/// The NUMA node of each CPU.
+static CPU_NODES: [AtomicU8; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NODE_0: AtomicU8 = AtomicU8::new(0);
+    [NODE_0; MAX_CPUS]
+};
+/// The relative distance from each NUMA node to each other NUMA node.
+static DISTANCES: RwLock<[[u8; MAX_NUMA_NODES]; MAX_NUMA_NODES]> = RwLock::new(default_distances());
+
+const fn default_distances() -> [[u8; MAX_NUMA_NODES]; MAX_NUMA_NODES] {
+    let mut distances = [[REMOTE_DISTANCE; MAX_NUMA_NODES]; MAX_NUMA_NODES];
+    let mut node = 0;
+    while node < MAX_NUMA_NODES {
+        distances[node][node] = LOCAL_DISTANCE;
+        node += 1;
+    }
+    distances
+}
+
+/// Sets the NUMA topology of the system.
+///
+/// * `memory_ranges`: the proximity domain and `(start, end_exclusive)` frame numbers of each range of memory.
+/// * `cpu_domains`: the proximity domain of each CPU.
+/// * `distance`: returns the relative distance between two proximity domains, if known.
+pub(crate) fn set_topology<M, C, D>(memory_ranges: M, cpu_domains: C, distance: D) -> Result<(), &'static str>
+where
+    M: IntoIterator<Item = (usize, usize, usize)>,
+    C: IntoIterator<Item = (usize, usize)>,
+    D: Fn(usize, usize) -> Option<u8>,
+{
+    let mut ranges: Vec<(usize, usize, usize)> = memory_ranges.into_iter()
+        .map(|(domain, start, end_exclusive)| (start, end_exclusive, domain))
+        .filter(|(start, end_exclusive, _)| start < end_exclusive)
+        .collect();
+    ranges.sort_unstable();
+    if ranges.windows(2).any(|pair| pair[0].1 > pair[1].0) {
+        return Err("NUMA memory ranges overlap");
+    }
+    let cpu_domains: Vec<(usize, usize)> = cpu_domains.into_iter().collect();
+    if cpu_domains.iter().any(|(cpu, _)| *cpu >= MAX_CPUS) {
+        return Err("a CPU's ID was too large to record its NUMA node");
+    }
+
+    // Each node's index is the position of its proximity domain among all (sorted) proximity domains.
+    let mut domains: Vec<usize> = ranges.iter().map(|(.., domain)| *domain)
+        .chain(cpu_domains.iter().map(|(_, domain)| *domain))
+        .collect();
+    domains.sort_unstable();
+    domains.dedup();
+    if domains.len() > MAX_NUMA_NODES {
+        return Err("too many NUMA nodes, the frame allocator supports at most `MAX_NUMA_NODES`");
+    }
+    let node_of_domain = |domain: usize| domains.binary_search(&domain).unwrap_or(0);
+    for (.., domain) in ranges.iter_mut() {
+        *domain = node_of_domain(*domain);
+    }
+    let node_count = domains.len().max(1);
+
+    let mut distances = default_distances();
+    for (from, &from_domain) in domains.iter().enumerate() {
+        for (to, &to_domain) in domains.iter().enumerate() {
+            if let Some(d) = distance(from_domain, to_domain) {
+                distances[from][to] = d;
+            }
+        }
+    }
+
+    for (cpu, domain) in cpu_domains {
+        CPU_NODES[cpu].store(node_of_domain(domain) as u8, Ordering::Relaxed);
+    }
+    *DISTANCES.write() = distances;
+    *NODE_RANGES.write() = ranges;
+    NODE_COUNT.store(node_count, Ordering::Release);
+    Ok(())
+}
+
+/// Returns the number of NUMA nodes in the system.
+pub(crate) fn node_count() -> usize {
+    NODE_COUNT.load(Ordering::Acquire)
+}
+
+/// Returns the NUMA node of the given CPU.
+pub(crate) fn cpu_node(cpu: usize) -> usize {
+    CPU_NODES.get(cpu).map_or(0, |node| node.load(Ordering::Relaxed) as usize)
+}
+
+/// Returns the relative distance between two NUMA nodes.
+pub(crate) fn distance(from: usize, to: usize) -> Option<u8> {
+    let count = node_count();
+    (from < count && to < count).then(|| DISTANCES.read()[from][to])
+}
+
+/// Returns the NUMA node that the frame with the given number belongs to.
+///
+/// Frames that aren't within any known NUMA memory range are considered to be on node 0.
+pub(crate) fn node_of_frame(number: usize) -> usize {
+    let ranges = NODE_RANGES.read();
+    let index = ranges.partition_point(|(start, ..)| *start <= number);
+    match index.checked_sub(1).map(|i| ranges[i]) {
+        Some((_, end_exclusive, node)) if number < end_exclusive => node,
+        _ => 0,
+    }
+}
+
+/// Splits the range of frames from `start` to `end_exclusive` at NUMA node boundaries,
+/// invoking `func` with the node and `(start, end_exclusive)` frame numbers of each piece.
+pub(crate) fn for_each_node_piece<F: FnMut(usize, usize, usize)>(start: usize, end_exclusive: usize, mut func: F) {
+    let mut number = start;
+    while number < end_exclusive {
+        let (node, piece_end) = {
+            let ranges = NODE_RANGES.read();
+            let index = ranges.partition_point(|(range_start, ..)| *range_start <= number);
+            match index.checked_sub(1).map(|i| ranges[i]) {
+                // The frame is within a known range, which may end before `end_exclusive`.
+                Some((_, range_end, node)) if number < range_end => (node, range_end),
+                // The frame is in a gap between known ranges, which ends where the next range begins.
+                _ => (0, ranges.get(index).map_or(usize::MAX, |(next_start, ..)| *next_start)),
+            }
+        };
+        let piece_end = piece_end.min(end_exclusive);
+        func(node, number, piece_end);
+        number = piece_end;
+    }
+}
+
+/// Returns all NUMA nodes sorted by their distance from the given `node`, starting with that `node` itself,
+/// along with the number of nodes.
+pub(crate) fn nodes_by_distance(node: usize) -> ([usize; MAX_NUMA_NODES], usize) {
+    let count = node_count();
+    let mut nodes = [0; MAX_NUMA_NODES];
+    for (i, n) in nodes.iter_mut().enumerate() {
+        *n = i;
+    }
+    let distances = DISTANCES.read();
+    let from = node.min(MAX_NUMA_NODES - 1);
+    nodes[..count].sort_unstable_by_key(|&to| (to != node, distances[from][to], to));
+    (nodes, count)
+}
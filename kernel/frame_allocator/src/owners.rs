@@ -0,0 +1,103 @@
+//! Records the task group that owns each range of allocated general-purpose frames,
+//! such that freed frames are credited back to the group that allocated them
+//! rather than to the group of whichever task frees them.
+//!
+//! Only frames allocated by tasks in a task group are recorded.
+//! Since `AllocatedFrames` can be split, merged, and converted to and from page table entries,
+//! ownership is tracked by frame range here instead of within each `AllocatedFrames` object.
+//!
+//! The heap may allocate frames when it grows, so the list of owned ranges never allocates
+//! heap memory while its lock is held, just like the buddy lists: any room needed to insert
+//! a range is allocated before taking the lock. Likewise, groups are uncharged (and possibly dropped)
+//! only after releasing the lock.
+
+use alloc::vec::Vec;
+use core::{cmp::{min, max}, mem, sync::atomic::{AtomicUsize, Ordering}};
+use memory_structs::{Frame, FrameRange};
+use spin::Mutex;
+use task_group::TaskGroupRef;
+
+/// A range of allocated frames and the group that owns them.
+struct OwnedRange {
+    start: Frame,
+    /// The last frame in this range (inclusive).
+    end: Frame,
+    group: TaskGroupRef,
+}
+
+/// The non-overlapping owned ranges of allocated frames, sorted by their first frame.
+static OWNERS: Mutex<Vec<OwnedRange>> = Mutex::new(Vec::new());
+
+/// The number of ranges in `OWNERS`, which allows freeing frames to skip locking it
+/// in the common case that no frames are owned by any group.
+static OWNED_RANGES: AtomicUsize = AtomicUsize::new(0);
+
+/// Runs `f` on the owned ranges once they have room for at least one more range,
+/// such that `f` can insert one without allocating heap memory.
+///
+/// If the list must grow, its new, larger storage is allocated without holding the lock,
+/// and its old storage is deallocated after releasing the lock.
+fn with_room_for_one<R>(f: impl FnOnce(&mut Vec<OwnedRange>) -> R) -> R {
+    loop {
+        let mut owners = OWNERS.lock();
+        if owners.len() < owners.capacity() {
+            let ret = f(&mut owners);
+            OWNED_RANGES.store(owners.len(), Ordering::Release);
+            return ret;
+        }
+        // Grow generously, such that this is rarely needed.
+        let capacity = (owners.len() + 1) * 2;
+        drop(owners);
+
+        let mut grown = Vec::with_capacity(capacity);
+        let mut owners = OWNERS.lock();
+        // Another CPU may have changed the list while the lock was released.
+        if owners.len() < capacity && owners.capacity() < capacity {
+            grown.extend(owners.drain(..));
+            mem::swap(&mut *owners, &mut grown);
+        }
+        drop(owners);
+        // `grown` now holds the old storage (or the unused new storage), which is deallocated here.
+    }
+}
+
+/// Records that the given newly-allocated `frames` were charged to the given `group`.
+pub(crate) fn record(frames: &FrameRange, group: TaskGroupRef) {
+    let (start, end) = (*frames.start(), *frames.end());
+    with_room_for_one(|owners| {
+        let index = owners.partition_point(|owned| owned.start < start);
+        owners.insert(index, OwnedRange { start, end, group });
+    });
+}
+
+/// Credits the given freed `frames` back to the groups that they were charged to when allocated.
+pub(crate) fn release(frames: &FrameRange) {
+    if OWNED_RANGES.load(Ordering::Acquire) == 0 || frames.size_in_frames() == 0 {
+        return;
+    }
+    let (start, end) = (*frames.start(), *frames.end());
+    loop {
+        // Remove one overlapping range at a time, keeping whatever parts of it weren't freed.
+        // Removing it makes room for one of those parts, and `with_room_for_one` for the other.
+        let freed = with_room_for_one(|owners| {
+            // Owned ranges never overlap, so the first one ending at or after `start`
+            // is the only candidate that can still overlap the freed frames.
+            let index = owners.partition_point(|owned| owned.end < start);
+            if owners.get(index).map_or(true, |owned| owned.start > end) {
+                return None;
+            }
+            let owned = owners.remove(index);
+            let freed_start = max(owned.start, start);
+            let freed_end = min(owned.end, end);
+            if owned.end > freed_end {
+                owners.insert(index, OwnedRange { start: freed_end + 1, end: owned.end, group: owned.group.clone() });
+            }
+            if owned.start < freed_start {
+                owners.insert(index, OwnedRange { start: owned.start, end: freed_start - 1, group: owned.group.clone() });
+            }
+            Some((owned.group, freed_end.number() - freed_start.number() + 1))
+        });
+        let Some((group, num_frames)) = freed else { break };
+        group.uncharge_frames(num_frames);
+    }
+}
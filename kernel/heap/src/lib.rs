@@ -1,6 +1,10 @@
 //! The global allocator for the system. 
 //! It starts off as a single fixed size allocator.
 //! When a more complex heap is set up, it is set as the default allocator.
+//!
+//! Allocations from the default allocator made by tasks in a task group are recorded
+//! in the `owners` module, such that freeing one credits the group it was charged to
+//! rather than the group of whichever task frees it.
 
 #![feature(allocator_api)]
 #![no_std]
@@ -11,6 +15,10 @@ extern crate spin;
 extern crate memory;
 extern crate kernel_config;
 extern crate block_allocator;
+extern crate task_group;
+extern crate memory_accounting;
+
+mod owners;
 
 use alloc::alloc::{GlobalAlloc, Layout};
 use memory::EntryFlags;
@@ -76,10 +84,33 @@ unsafe impl GlobalAlloc for Heap {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         match DEFAULT_ALLOCATOR.get() {
             Some(allocator) => {
-                allocator.alloc(layout)
+                // Enforce the heap limit of the current task's group, if any.
+                let owner = match task_group::charge_current_heap(layout.size()) {
+                    Ok(owner) => owner,
+                    Err(_) => return core::ptr::null_mut(),
+                };
+                let ptr = allocator.alloc(layout);
+                if let Some(group) = owner {
+                    let recorded = if ptr.is_null() { Err(group) } else { owners::record(&**allocator, ptr, group) };
+                    if let Err(group) = recorded {
+                        group.uncharge_heap(layout.size());
+                        if !ptr.is_null() {
+                            allocator.dealloc(ptr, layout);
+                        }
+                        return core::ptr::null_mut();
+                    }
+                }
+                if !ptr.is_null() {
+                    memory_accounting::charge_heap(layout.size());
+                }
+                ptr
             }
             None => {       
-                self.initial_allocator.lock().allocate(layout)
+                let ptr = self.initial_allocator.lock().allocate(layout);
+                if !ptr.is_null() {
+                    memory_accounting::charge_heap(layout.size());
+                }
+                ptr
             }
         }
     }
@@ -89,10 +120,15 @@ unsafe impl GlobalAlloc for Heap {
             self.initial_allocator.lock().deallocate(ptr, layout);
         }
         else {
+            // Credit the group that this memory was charged to when it was allocated.
+            if let Some(group) = owners::release(ptr) {
+                group.uncharge_heap(layout.size());
+            }
             DEFAULT_ALLOCATOR.get()
                 .expect("Ptr passed to dealloc is not within the initial allocator's range, and another allocator has not been set up")
                 .dealloc(ptr, layout);
         }
+        memory_accounting::uncharge_heap(layout.size());
     }
 
 }
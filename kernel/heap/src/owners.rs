@@ -0,0 +1,176 @@
+//! Records the task group that owns each heap allocation made by a task in a task group,
+//! such that freeing it credits that group rather than the group of whichever task frees it.
+//!
+//! Owners are tracked out of band in a hash table keyed by the allocation's address,
+//! so allocations made by tasks outside of any group carry no extra header or bookkeeping,
+//! and freeing them only requires checking an atomic counter while no group owns any allocations.
+//!
+//! The table's storage is allocated directly from the default allocator, bypassing the global allocator,
+//! and never while its lock is held: any room needed to insert an owner is allocated before taking the lock.
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use alloc::sync::Arc;
+use core::{cmp::max, mem, ptr};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use irq_safety::MutexIrqSafe;
+use task_group::{TaskGroup, TaskGroupRef};
+
+/// The smallest number of slots in the table once it has been allocated.
+const MIN_CAPACITY: usize = 64;
+
+/// An entry in the table; an `addr` of 0 denotes an empty slot.
+#[derive(Clone, Copy)]
+struct Slot {
+    addr: usize,
+    owner: *const TaskGroup,
+}
+
+/// An open-addressing hash table with linear probing whose capacity is zero or a power of two.
+struct OwnerTable {
+    slots: *mut Slot,
+    capacity: usize,
+    len: usize,
+}
+
+// The table is only accessed while holding the lock in `OWNERS`.
+unsafe impl Send for OwnerTable { }
+
+/// The owning group of each heap allocation made by a task in a group, keyed by its address.
+static OWNERS: MutexIrqSafe<OwnerTable> = MutexIrqSafe::new(OwnerTable {
+    slots: ptr::null_mut(),
+    capacity: 0,
+    len: 0,
+});
+
+/// The number of allocations in `OWNERS`, which allows freeing memory to skip locking it
+/// in the common case that no allocations are owned by any group.
+static OWNED_ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+impl OwnerTable {
+    /// Returns `true` if another owner can be inserted without exceeding a load factor of 3/4.
+    fn has_room_for_one(&self) -> bool {
+        (self.len + 1) * 4 <= self.capacity * 3
+    }
+
+    fn home_index(&self, addr: usize) -> usize {
+        // Fibonacci hashing: the top bits of the product are well-distributed.
+        (addr >> 4).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (usize::BITS - self.capacity.trailing_zeros())
+    }
+
+    fn slot(&self, index: usize) -> Slot {
+        unsafe { *self.slots.add(index) }
+    }
+
+    fn set_slot(&mut self, index: usize, slot: Slot) {
+        unsafe { *self.slots.add(index) = slot }
+    }
+
+    /// Inserts the given owner, which the table must have room for.
+    fn insert(&mut self, addr: usize, owner: *const TaskGroup) {
+        let mask = self.capacity - 1;
+        let mut index = self.home_index(addr);
+        while self.slot(index).addr != 0 {
+            index = (index + 1) & mask;
+        }
+        self.set_slot(index, Slot { addr, owner });
+        self.len += 1;
+    }
+
+    /// Removes and returns the owner of the given address, if any.
+    fn remove(&mut self, addr: usize) -> Option<*const TaskGroup> {
+        if self.len == 0 {
+            return None;
+        }
+        let mask = self.capacity - 1;
+        let mut index = self.home_index(addr);
+        loop {
+            match self.slot(index).addr {
+                0 => return None,
+                a if a == addr => break,
+                _ => index = (index + 1) & mask,
+            }
+        }
+        let owner = self.slot(index).owner;
+        self.len -= 1;
+
+        // Shift back later entries of the same probe sequence into the vacated slot,
+        // such that lookups never stop early at an empty slot.
+        let mut vacant = index;
+        let mut next = index;
+        loop {
+            next = (next + 1) & mask;
+            let slot = self.slot(next);
+            if slot.addr == 0 {
+                break;
+            }
+            let home = self.home_index(slot.addr);
+            let stays = if vacant <= next {
+                vacant < home && home <= next
+            } else {
+                vacant < home || home <= next
+            };
+            if !stays {
+                self.set_slot(vacant, slot);
+                vacant = next;
+            }
+        }
+        self.set_slot(vacant, Slot { addr: 0, owner: ptr::null() });
+        Some(owner)
+    }
+}
+
+/// Records that the given newly-allocated memory at `addr` was charged to the given `group`.
+///
+/// The table grows by allocating from the given default `allocator`.
+/// Returns the `group` back if that fails, in which case the allocation should fail.
+pub(crate) fn record(allocator: &dyn GlobalAlloc, addr: *mut u8, group: TaskGroupRef) -> Result<(), TaskGroupRef> {
+    loop {
+        let mut owners = OWNERS.lock();
+        if owners.has_room_for_one() {
+            owners.insert(addr as usize, Arc::into_raw(group));
+            OWNED_ALLOCATIONS.store(owners.len, Ordering::Release);
+            return Ok(());
+        }
+        let capacity = max(owners.capacity * 2, MIN_CAPACITY);
+        drop(owners);
+
+        let layout = Layout::array::<Slot>(capacity).expect("BUG: heap owner table is too large");
+        let slots = unsafe { allocator.alloc_zeroed(layout) } as *mut Slot;
+        if slots.is_null() {
+            return Err(group);
+        }
+        let mut grown = OwnerTable { slots, capacity, len: 0 };
+        let mut owners = OWNERS.lock();
+        // Another CPU may have grown the table while the lock was released.
+        if owners.capacity < capacity {
+            for index in 0..owners.capacity {
+                let slot = owners.slot(index);
+                if slot.addr != 0 {
+                    grown.insert(slot.addr, slot.owner);
+                }
+            }
+            mem::swap(&mut *owners, &mut grown);
+        }
+        drop(owners);
+        // `grown` now holds the old storage (or the unused new storage), which is deallocated here.
+        if grown.capacity != 0 {
+            let layout = Layout::array::<Slot>(grown.capacity).expect("BUG: heap owner table is too large");
+            unsafe { allocator.dealloc(grown.slots as *mut u8, layout) };
+        }
+    }
+}
+
+/// Removes and returns the group that the memory at `addr` was charged to when allocated, if any.
+///
+/// This must be called before that memory is deallocated,
+/// such that its address can't be reused (and recorded) by another allocation in the meantime.
+pub(crate) fn release(addr: *mut u8) -> Option<TaskGroupRef> {
+    if OWNED_ALLOCATIONS.load(Ordering::Acquire) == 0 {
+        return None;
+    }
+    let mut owners = OWNERS.lock();
+    let owner = owners.remove(addr as usize);
+    OWNED_ALLOCATIONS.store(owners.len, Ordering::Release);
+    drop(owners);
+    owner.map(|owner| unsafe { Arc::from_raw(owner) })
+}
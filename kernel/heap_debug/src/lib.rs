@@ -0,0 +1,461 @@
+//! A debugging heap allocator that detects heap corruption as early as possible
+//! and reports allocations that were leaked.
+//!
+//! The [`DebugAllocator`] wraps another allocator (by default, the `multiple_heaps` allocator)
+//! and surrounds each allocation with a header and with redzones of guard bytes:
+//!
+//! ```text
+//! | padding | Header | front redzone | requested bytes | back redzone |
+//!                                    ^ returned pointer
+//! ```
+//!
+//! * The redzones are checked when the allocation is freed, which detects buffer overflows and underflows.
+//! * Freed memory is poisoned and held in a quarantine for a while before being returned
+//!   to the inner allocator, which detects writes to freed memory (use-after-free)
+//!   when the allocation leaves the quarantine.
+//! * The header records whether the allocation is live or freed, which detects double frees
+//!   and frees of pointers that weren't returned by this allocator.
+//! * The header also records the allocating task and, if enabled via [`set_call_site_recording()`],
+//!   the call sites that led to the allocation, obtained using the `stack_trace` crate.
+//!
+//! All live allocations are linked together, such that [`outstanding_allocations_since()`]
+//! can list the allocations that are still live since a checkpoint set by [`set_checkpoint()`],
+//! which is how the `heap_leaks` application finds leaked allocations.
+//!
+//! Detected errors are logged along with the offending allocation's details;
+//! a double free or invalid free is otherwise ignored, so as to avoid corrupting the inner allocator.
+//!
+//! To use this allocator instead of the regular per-core heaps, build Theseus with `THESEUS_CONFIG=debug_heap`.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{
+    alloc::{GlobalAlloc, Layout},
+    boxed::Box,
+    vec::Vec,
+};
+use core::{
+    mem::size_of,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+use irq_safety::MutexIrqSafe;
+use log::error;
+use spin::Once;
+
+/// The maximum number of call sites recorded for each allocation.
+pub const MAX_CALL_SITES: usize = 8;
+
+/// The size of each redzone before and after the requested bytes.
+const REDZONE_SIZE: usize = 16;
+/// The value that each byte of a redzone is filled with.
+const REDZONE_BYTE: u8 = 0xFD;
+/// The value that each byte of freed memory is filled with.
+const FREED_BYTE: u8 = 0xDD;
+/// The size of each header, which is padded such that the front redzone starts at a 16-byte boundary.
+const HEADER_SIZE: usize = (size_of::<Header>() + 15) / 16 * 16;
+/// The minimum alignment of each block allocated from the inner allocator.
+const MIN_ALIGN: usize = 16;
+/// The number of freed allocations that are held in quarantine before being returned to the inner allocator.
+const QUARANTINE_CAPACITY: usize = 256;
+/// The maximum number of CPUs, used to prevent each CPU from recursively recording call sites.
+const MAX_CPUS: usize = 256;
+
+/// The header state of a live allocation.
+const LIVE_MAGIC: u64 = 0x4845_4150_4C49_5645; // "HEAPLIVE"
+/// The header state of a freed allocation that is in quarantine.
+const FREED_MAGIC: u64 = 0x4845_4150_4652_4545; // "HEAPFREE"
+
+/// Whether the debug allocator has been installed as the system's heap allocator.
+static INSTALLED: Once<()> = Once::new();
+/// Whether call sites are currently recorded for each new allocation.
+static RECORD_CALL_SITES: AtomicBool = AtomicBool::new(false);
+/// Whether each CPU is currently recording call sites, which itself may allocate.
+static RECORDING_ON_CPU: [AtomicBool; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NOT_RECORDING: AtomicBool = AtomicBool::new(false);
+    [NOT_RECORDING; MAX_CPUS]
+};
+/// The sequence number given to the next allocation.
+static NEXT_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+/// The sequence number of the first allocation made after the most recent checkpoint.
+static CHECKPOINT: AtomicU64 = AtomicU64::new(0);
+/// The number of double frees and invalid frees detected so far.
+static INVALID_FREES: AtomicUsize = AtomicUsize::new(0);
+/// The number of corrupted redzones and corrupted freed allocations detected so far.
+static CORRUPTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// The live allocations and the quarantined freed allocations.
+static STATE: MutexIrqSafe<State> = MutexIrqSafe::new(State {
+    live: HeaderList::new(),
+    quarantine: HeaderList::new(),
+});
+
+struct State {
+    live: HeaderList,
+    quarantine: HeaderList,
+}
+
+/// The bookkeeping info stored immediately before the front redzone of each allocation.
+#[repr(C)]
+struct Header {
+    magic: u64,
+    /// The number of bytes requested by the caller.
+    size: usize,
+    /// The distance from the start of the inner allocator's block to the returned pointer.
+    offset: usize,
+    /// The alignment of the inner allocator's block.
+    block_align: usize,
+    sequence: u64,
+    task_id: usize,
+    call_sites: [usize; MAX_CALL_SITES],
+    prev: *mut Header,
+    next: *mut Header,
+}
+
+impl Header {
+    /// Returns the header of the allocation at the given pointer returned by the debug allocator.
+    fn of(ptr: *mut u8) -> *mut Header {
+        ptr.wrapping_sub(REDZONE_SIZE + HEADER_SIZE) as *mut Header
+    }
+
+    fn data(&self) -> *mut u8 {
+        (self as *const Header as *mut u8).wrapping_add(HEADER_SIZE + REDZONE_SIZE)
+    }
+
+    fn block(&self) -> *mut u8 {
+        self.data().wrapping_sub(self.offset)
+    }
+
+    fn block_layout(&self) -> Layout {
+        // SAFETY: this layout was successfully created upon allocation.
+        unsafe { Layout::from_size_align_unchecked(self.offset + self.size + REDZONE_SIZE, self.block_align) }
+    }
+
+    fn info(&self) -> AllocationInfo {
+        AllocationInfo {
+            address: self.data() as usize,
+            size: self.size,
+            sequence: self.sequence,
+            task_id: self.task_id,
+            call_sites: self.call_sites,
+        }
+    }
+}
+
+/// An intrusive doubly-linked list of allocation headers.
+struct HeaderList {
+    head: *mut Header,
+    tail: *mut Header,
+    len: usize,
+}
+
+// SAFETY: the headers in a list are only accessed while holding the lock on the `STATE` that contains it.
+unsafe impl Send for HeaderList { }
+
+impl HeaderList {
+    const fn new() -> HeaderList {
+        HeaderList { head: ptr::null_mut(), tail: ptr::null_mut(), len: 0 }
+    }
+
+    /// Adds the given header to the end of this list.
+    unsafe fn push_back(&mut self, header: *mut Header) {
+        (*header).prev = self.tail;
+        (*header).next = ptr::null_mut();
+        if self.tail.is_null() {
+            self.head = header;
+        } else {
+            (*self.tail).next = header;
+        }
+        self.tail = header;
+        self.len += 1;
+    }
+
+    /// Removes the given header, which must be in this list.
+    unsafe fn remove(&mut self, header: *mut Header) {
+        let (prev, next) = ((*header).prev, (*header).next);
+        if prev.is_null() { self.head = next; } else { (*prev).next = next; }
+        if next.is_null() { self.tail = prev; } else { (*next).prev = prev; }
+        (*header).prev = ptr::null_mut();
+        (*header).next = ptr::null_mut();
+        self.len -= 1;
+    }
+
+    /// Removes and returns the first header in this list, if any.
+    unsafe fn pop_front(&mut self) -> Option<*mut Header> {
+        let header = self.head;
+        if header.is_null() {
+            return None;
+        }
+        self.remove(header);
+        Some(header)
+    }
+
+    /// Returns an iterator over the headers in this list, from oldest to newest.
+    fn iter(&self) -> impl Iterator<Item = &Header> {
+        // SAFETY: every header in this list is valid while the list is borrowed.
+        core::iter::successors(unsafe { self.head.as_ref() }, |h| unsafe { h.next.as_ref() })
+    }
+}
+
+/// Details about a live allocation.
+#[derive(Clone, Copy, Debug)]
+pub struct AllocationInfo {
+    /// The address returned to the caller.
+    pub address: usize,
+    /// The number of bytes requested by the caller.
+    pub size: usize,
+    /// The order in which this allocation was made, relative to all other allocations.
+    pub sequence: u64,
+    /// The ID of the task that made this allocation.
+    pub task_id: usize,
+    call_sites: [usize; MAX_CALL_SITES],
+}
+
+impl AllocationInfo {
+    /// Returns the call sites that led to this allocation, innermost first,
+    /// which is empty if call sites weren't being recorded when this allocation was made.
+    pub fn call_sites(&self) -> &[usize] {
+        let len = self.call_sites.iter().position(|&c| c == 0).unwrap_or(MAX_CALL_SITES);
+        &self.call_sites[..len]
+    }
+}
+
+/// Statistics about the allocations made through the debug allocator.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DebugHeapStats {
+    /// The number of allocations that have not yet been freed.
+    pub live_allocations: usize,
+    /// The number of bytes requested by those allocations.
+    pub live_bytes: usize,
+    /// The number of freed allocations that are in quarantine.
+    pub quarantined_allocations: usize,
+    /// The number of double frees and frees of invalid pointers detected so far.
+    pub invalid_frees: usize,
+    /// The number of buffer overflows, underflows, and writes to freed memory detected so far.
+    pub corruptions: usize,
+}
+
+/// Returns `true` if the debug allocator has been installed as the system's heap allocator.
+pub fn is_installed() -> bool {
+    INSTALLED.is_completed()
+}
+
+/// Initializes the per-core heaps and installs a [`DebugAllocator`] that wraps them
+/// as the system's heap allocator.
+///
+/// This should be called instead of `multiple_heaps::switch_to_multiple_heaps()`.
+pub fn switch_to_debug_heap() -> Result<(), &'static str> {
+    let multiple_heaps = multiple_heaps::initialize_multiple_heaps()?;
+    heap::set_allocator(Box::new(DebugAllocator::new(multiple_heaps)));
+    INSTALLED.call_once(|| ());
+    Ok(())
+}
+
+/// Enables or disables recording the call sites of each new allocation.
+///
+/// This is disabled by default because obtaining a stack trace upon each allocation is slow.
+/// Recording call sites acquires the locks on crate metadata,
+/// so it should not be enabled while crates are being loaded or swapped.
+pub fn set_call_site_recording(enable: bool) {
+    RECORD_CALL_SITES.store(enable, Ordering::Release);
+}
+
+/// Sets a checkpoint, after which new allocations are included in [`outstanding_allocations_since()`].
+///
+/// Returns the checkpoint, which is also returned by subsequent calls to [`checkpoint()`].
+pub fn set_checkpoint() -> u64 {
+    let checkpoint = NEXT_SEQUENCE.load(Ordering::Relaxed);
+    CHECKPOINT.store(checkpoint, Ordering::Relaxed);
+    checkpoint
+}
+
+/// Returns the most recent checkpoint set by [`set_checkpoint()`].
+pub fn checkpoint() -> u64 {
+    CHECKPOINT.load(Ordering::Relaxed)
+}
+
+/// Returns all allocations that were made after the given `checkpoint` and have not yet been freed,
+/// from oldest to newest.
+pub fn outstanding_allocations_since(checkpoint: u64) -> Vec<AllocationInfo> {
+    // We cannot allocate while holding the lock, as that would deadlock,
+    // so we reserve enough space beforehand for the allocations that exist right now.
+    // Any allocations made after that are newer than what the caller asked about.
+    let count = STATE.lock().live.iter().filter(|h| h.sequence >= checkpoint).count();
+    let mut allocations = Vec::with_capacity(count);
+    let state = STATE.lock();
+    for header in state.live.iter().filter(|h| h.sequence >= checkpoint) {
+        if allocations.len() == allocations.capacity() {
+            break;
+        }
+        allocations.push(header.info());
+    }
+    allocations
+}
+
+/// Returns statistics about the allocations made through the debug allocator.
+pub fn stats() -> DebugHeapStats {
+    let state = STATE.lock();
+    DebugHeapStats {
+        live_allocations: state.live.len,
+        live_bytes: state.live.iter().map(|h| h.size).sum(),
+        quarantined_allocations: state.quarantine.len,
+        invalid_frees: INVALID_FREES.load(Ordering::Relaxed),
+        corruptions: CORRUPTIONS.load(Ordering::Relaxed),
+    }
+}
+
+
+/// A heap allocator that wraps an inner allocator in order to detect
+/// heap corruption, double frees, and leaks.
+///
+/// See the [crate-level documentation](crate) for more details.
+pub struct DebugAllocator<A: GlobalAlloc> {
+    inner: A,
+}
+
+impl<A: GlobalAlloc> DebugAllocator<A> {
+    /// Returns a new debug allocator that obtains memory from the given `inner` allocator.
+    pub const fn new(inner: A) -> DebugAllocator<A> {
+        DebugAllocator { inner }
+    }
+
+    /// Returns a freed allocation to the inner allocator, first checking that it wasn't written to.
+    unsafe fn release(&self, header: *mut Header) {
+        let h = &*header;
+        let data = core::slice::from_raw_parts(h.data(), h.size);
+        if let Some(offset) = data.iter().position(|&b| b != FREED_BYTE) {
+            CORRUPTIONS.fetch_add(1, Ordering::Relaxed);
+            report("use after free: freed memory was written to", h.info(), Some(offset));
+        }
+        self.inner.dealloc(h.block(), h.block_layout());
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for DebugAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let align = layout.align().max(MIN_ALIGN);
+        let offset = (HEADER_SIZE + REDZONE_SIZE + align - 1) / align * align;
+        let Some(block_layout) = offset.checked_add(layout.size())
+            .and_then(|size| size.checked_add(REDZONE_SIZE))
+            .and_then(|size| Layout::from_size_align(size, align).ok())
+            else { return ptr::null_mut() };
+
+        let block = self.inner.alloc(block_layout);
+        if block.is_null() {
+            return block;
+        }
+        let data = block.add(offset);
+        let header = Header::of(data);
+        header.write(Header {
+            magic: LIVE_MAGIC,
+            size: layout.size(),
+            offset,
+            block_align: align,
+            sequence: NEXT_SEQUENCE.fetch_add(1, Ordering::Relaxed),
+            task_id: task::get_my_current_task_id(),
+            call_sites: [0; MAX_CALL_SITES],
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+        });
+        ptr::write_bytes(data.sub(REDZONE_SIZE), REDZONE_BYTE, REDZONE_SIZE);
+        ptr::write_bytes(data.add(layout.size()), REDZONE_BYTE, REDZONE_SIZE);
+
+        if RECORD_CALL_SITES.load(Ordering::Acquire) {
+            record_call_sites(&mut (*header).call_sites);
+        }
+        STATE.lock().live.push_back(header);
+        data
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let header = Header::of(ptr);
+        match (*header).magic {
+            LIVE_MAGIC => { }
+            FREED_MAGIC => {
+                INVALID_FREES.fetch_add(1, Ordering::Relaxed);
+                report("double free", (*header).info(), None);
+                return;
+            }
+            _ => {
+                INVALID_FREES.fetch_add(1, Ordering::Relaxed);
+                error!("heap_debug: invalid free of {:p} ({:?}), which was not allocated or has a corrupted header", ptr, layout);
+                return;
+            }
+        }
+        let h = &*header;
+        if h.size != layout.size() {
+            error!("heap_debug: freeing {:p} with size {}, but it was allocated with size {}", ptr, layout.size(), h.size);
+        }
+        let front = core::slice::from_raw_parts(ptr.sub(REDZONE_SIZE), REDZONE_SIZE);
+        let back = core::slice::from_raw_parts(ptr.add(h.size), REDZONE_SIZE);
+        if front.iter().any(|&b| b != REDZONE_BYTE) {
+            CORRUPTIONS.fetch_add(1, Ordering::Relaxed);
+            report("buffer underflow: the front redzone was overwritten", h.info(), None);
+        }
+        if let Some(offset) = back.iter().position(|&b| b != REDZONE_BYTE) {
+            CORRUPTIONS.fetch_add(1, Ordering::Relaxed);
+            report("buffer overflow: the back redzone was overwritten", h.info(), Some(h.size + offset));
+        }
+
+        // Errors are reported before acquiring this lock, in case logging them allocates memory.
+        let mut state = STATE.lock();
+        if (*header).magic != LIVE_MAGIC {
+            // Another CPU freed this allocation concurrently.
+            drop(state);
+            INVALID_FREES.fetch_add(1, Ordering::Relaxed);
+            report("double free", (*header).info(), None);
+            return;
+        }
+        state.live.remove(header);
+        (*header).magic = FREED_MAGIC;
+        ptr::write_bytes(ptr, FREED_BYTE, h.size);
+        state.quarantine.push_back(header);
+
+        // Release the oldest quarantined allocation once the quarantine is full.
+        // The inner allocator may itself free memory, so the lock must be released first.
+        let oldest = if state.quarantine.len > QUARANTINE_CAPACITY {
+            state.quarantine.pop_front()
+        } else {
+            None
+        };
+        drop(state);
+        if let Some(oldest) = oldest {
+            self.release(oldest);
+        }
+    }
+}
+
+/// Records the call sites of the current allocation into the given array,
+/// unless this CPU is already recording call sites, in which case this is a nested allocation.
+fn record_call_sites(call_sites: &mut [usize; MAX_CALL_SITES]) {
+    let cpu = apic::get_my_apic_id() as usize % MAX_CPUS;
+    if RECORDING_ON_CPU[cpu].swap(true, Ordering::Acquire) {
+        return;
+    }
+    let mut i = 0;
+    // Skip the frames within the allocator itself, i.e., this debug allocator and the global `Heap`.
+    let mut frames_to_skip = 2;
+    let _ = stack_trace::stack_trace(
+        &mut |stack_frame, _| {
+            if frames_to_skip > 0 {
+                frames_to_skip -= 1;
+                return true;
+            }
+            call_sites[i] = stack_frame.call_site_address() as usize;
+            i += 1;
+            i < MAX_CALL_SITES
+        },
+        Some(MAX_CALL_SITES + 2),
+    );
+    RECORDING_ON_CPU[cpu].store(false, Ordering::Release);
+}
+
+/// Logs an error about the given allocation.
+fn report(error: &str, info: AllocationInfo, offset: Option<usize>) {
+    error!("heap_debug: {} at {:#X} (size {}, offset {:?}), allocated by task {} (allocation #{}) at {:X?}",
+        error, info.address, info.size, offset, info.task_id, info.sequence, info.call_sites(),
+    );
+}
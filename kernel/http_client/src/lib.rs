@@ -10,9 +10,12 @@ extern crate smoltcp;
 extern crate network_manager;
 extern crate hpet;
 extern crate httparse;
+extern crate network_interface_card;
+extern crate async_executor;
 #[macro_use] extern crate smoltcp_helper;
 
 use core::str;
+use core::time::Duration;
 use alloc::vec::Vec;
 use alloc::string::String;
 use hpet::get_hpet;
@@ -24,7 +27,7 @@ use smoltcp_helper::{millis_since, poll_iface};
 
 /// The states that implement the finite state machine for 
 /// sending and receiving the HTTP request and response, respectively.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum HttpState {
     /// The socket is connected, but the HTTP request has not yet been sent.
     Requesting,
@@ -144,59 +147,132 @@ pub fn send_request(
     tcp_socket: &mut ConnectedTcpSocket,
     timeout_millis: Option<u64>,
 ) -> Result<HttpResponse, &'static str> {
+    let mut transaction = HttpTransaction::new(&request, timeout_millis)?;
+    // busy-poll the interface until the full response has been received
+    while transaction.step(tcp_socket)? != Progress::Done { }
+    transaction.into_response()
+}
 
-    // validate the HTTP request 
-    if !check_http_request(request.as_bytes()) {
-        return Err("http_client: given HTTP request was improperly formatted or incomplete");
+/// The asynchronous counterpart of [`send_request()`], which has identical arguments and behavior.
+/// 
+/// Instead of busy-polling the network interface, the returned future waits for a NIC
+/// to receive a new frame whenever no packets were sent or received,
+/// which allows a single task to multiplex many HTTP requests on an `async_executor::Executor`.
+/// The interface is still polled at least every [`ASYNC_POLL_INTERVAL`] 
+/// such that TCP retransmissions and timeouts are handled.
+pub async fn send_request_async(
+    request: HttpRequest, 
+    tcp_socket: &mut ConnectedTcpSocket<'_, '_, '_, '_, '_>,
+    timeout_millis: Option<u64>,
+) -> Result<HttpResponse, &'static str> {
+    let mut transaction = HttpTransaction::new(&request, timeout_millis)?;
+    loop {
+        // Start listening for new frames *before* polling the interface,
+        // such that a frame received during this step isn't missed. 
+        let frame_received = network_interface_card::wait_for_received_frame();
+        match transaction.step(tcp_socket)? {
+            Progress::Done   => break,
+            Progress::Active => async_executor::yield_now().await,
+            Progress::Idle   => {
+                let _ = async_executor::timeout(ASYNC_POLL_INTERVAL, frame_received).await;
+            }
+        }
     }
+    transaction.into_response()
+}
+
+/// The maximum duration that [`send_request_async()`] will wait for an incoming frame
+/// before polling the network interface again.
+pub const ASYNC_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+
+/// The result of one step of an [`HttpTransaction`].
+#[derive(Debug, PartialEq, Eq)]
+enum Progress {
+    /// The full HTTP response has been received.
+    Done,
+    /// Packets were sent or received during this step.
+    Active,
+    /// No packets were sent or received during this step.
+    Idle,
+}
 
-    let ConnectedTcpSocket { iface, sockets, handle } = tcp_socket;
+/// The in-progress state of sending one HTTP request and receiving its response,
+/// which is driven forward by repeatedly invoking [`HttpTransaction::step()`].
+struct HttpTransaction<'r> {
+    request: &'r HttpRequest,
+    timeout_millis: Option<u64>,
+    state: HttpState,
+    packet_byte_buffer:   Vec<u8>,
+    packet_header_length: Option<usize>,
+    response_status_code: Option<u16>,
+    response_reason:      Option<String>,
+    startup_time: u64,
+    latest_packet_timestamp: u64,
+    _loop_ctr: usize,
+}
 
-    let mut _loop_ctr = 0;
-    let mut state = HttpState::Requesting;
-    let mut packet_byte_buffer:   Vec<u8> = Vec::new();
-    let mut packet_header_length: Option<usize> = None;
-    let mut response_status_code: Option<u16> = None;
-    let mut response_reason:      Option<String> = None;
+impl<'r> HttpTransaction<'r> {
+    fn new(request: &'r HttpRequest, timeout_millis: Option<u64>) -> Result<HttpTransaction<'r>, &'static str> {
+        // validate the HTTP request 
+        if !check_http_request(request.as_bytes()) {
+            return Err("http_client: given HTTP request was improperly formatted or incomplete");
+        }
 
-    let startup_time = hpet_ticks!();
-    let mut latest_packet_timestamp = startup_time;
+        let startup_time = hpet_ticks!();
+        Ok(HttpTransaction {
+            request,
+            timeout_millis,
+            state: HttpState::Requesting,
+            packet_byte_buffer: Vec::new(),
+            packet_header_length: None,
+            response_status_code: None,
+            response_reason: None,
+            startup_time,
+            latest_packet_timestamp: startup_time,
+            _loop_ctr: 0,
+        })
+    }
 
-    // in the loop below, we do the actual work of sending the request and receiving the response 
-    loop { 
-        _loop_ctr += 1;
+    /// Polls the network interface once and advances the HTTP state machine,
+    /// doing the actual work of sending the request and receiving the response.
+    fn step(&mut self, tcp_socket: &mut ConnectedTcpSocket) -> Result<Progress, &'static str> {
+        let ConnectedTcpSocket { iface, sockets, handle } = tcp_socket;
+        self._loop_ctr += 1;
 
-        let _packet_io_occurred = poll_iface(&iface, sockets, startup_time)?;
+        let packet_io_occurred = poll_iface(iface, sockets, self.startup_time)?;
 
         // check if we have timed out
-        if let Some(t) = timeout_millis {
-            if millis_since(latest_packet_timestamp)? > t {
-                error!("http_client: timed out after {} ms, in state {:?}", t, state);
+        if let Some(t) = self.timeout_millis {
+            if millis_since(self.latest_packet_timestamp)? > t {
+                error!("http_client: timed out after {} ms, in state {:?}", t, self.state);
                 return Err("http_client: timed out");
             }
         }
 
         let mut socket = sockets.get::<TcpSocket>(*handle);
+        let _loop_ctr = self._loop_ctr;
 
-        state = match state {
+        let prev_state = self.state;
+        self.state = match self.state {
             HttpState::Requesting if socket.can_send() => {
-                debug!("http_client: sending HTTP request: {:?}", request);
-                socket.send_slice(request.as_ref()).expect("http_client: cannot send request");
-                latest_packet_timestamp = hpet_ticks!();
+                debug!("http_client: sending HTTP request: {:?}", self.request);
+                socket.send_slice(self.request.as_ref()).expect("http_client: cannot send request");
+                self.latest_packet_timestamp = hpet_ticks!();
                 HttpState::ReceivingResponse
             }
 
             HttpState::ReceivingResponse if socket.can_recv() => {
                 // Stay in the receiving state for now; will be changed later if we receive the entire packet.
                 let mut new_state = HttpState::ReceivingResponse;
-                let orig_packet_length = packet_byte_buffer.len();
+                let orig_packet_length = self.packet_byte_buffer.len();
 
                 let recv_result = socket.recv(|data| {
                     // Eagerly append ALL of the received data onto the end of our packet slice, 
                     // which is necessary to attempt to parse it as an HTTP response.
                     // Later, we can remove bytes towards the end if we ended up appending too many bytes,
                     // e.g., we received more than enough bytes and some of them were for the next packet.
-                    packet_byte_buffer.extend_from_slice(data);
+                    self.packet_byte_buffer.extend_from_slice(data);
 
                     let bytes_popped_off = {
                         // Check to see if we've received the full HTTP response:
@@ -204,7 +280,7 @@ pub fn send_request(
                         // Second, by getting the content length header and seeing if we've received the full content (in num bytes)
                         let mut headers = [httparse::EMPTY_HEADER; 64];
                         let mut response = httparse::Response::new(&mut headers);
-                        match response.parse(&packet_byte_buffer) {
+                        match response.parse(&self.packet_byte_buffer) {
                             Ok(httparse::Status::Partial) => {
                                 trace!("http_client: received partial HTTP response...");
                                 // we haven't received all of the HTTP header bytes yet, 
@@ -213,9 +289,9 @@ pub fn send_request(
                             }
 
                             Ok(httparse::Status::Complete(total_header_len)) => {
-                                packet_header_length = Some(total_header_len);
-                                response_status_code = response.code;
-                                response_reason = response.reason.map(|s| String::from(s));
+                                self.packet_header_length = Some(total_header_len);
+                                self.response_status_code = response.code;
+                                self.response_reason = response.reason.map(|s| String::from(s));
 
                                 // Here: we've received all headers, but we may not be done receiving the full response.
                                 // If there is a "Content-Length" header present, we can use that to see if all the bytes are received.
@@ -230,12 +306,12 @@ pub fn send_request(
                                         )
                                     {
                                         Ok(content_length) => {
-                                            // debug!("http_client: packet_byte_buffer len: {}, content_length: {}, header_len: {} (_loop_ctr: {})", 
-                                            //     packet_byte_buffer.len(), content_length, total_header_len, _loop_ctr
+                                            // debug!("http_client: self.packet_byte_buffer len: {}, content_length: {}, header_len: {} (_loop_ctr: {})", 
+                                            //     self.packet_byte_buffer.len(), content_length, total_header_len, _loop_ctr
                                             // );
                                             // the total num of bytes that we want is the length of all the headers + the content
                                             let expected_length = total_header_len + content_length;
-                                            if packet_byte_buffer.len() < expected_length {
+                                            if self.packet_byte_buffer.len() < expected_length {
                                                 // here: we haven't gotten all of the content bytes yet, so we pop off all of the bytes received so far
                                                 data.len()
                                             } else {
@@ -275,7 +351,7 @@ pub fn send_request(
 
                     // Since we eagerly appended all of the received bytes onto this buffer, 
                     // we need to fix that up based on how many bytes we actually ended up popping off the recv buffer
-                    packet_byte_buffer.truncate(orig_packet_length + bytes_popped_off);
+                    self.packet_byte_buffer.truncate(orig_packet_length + bytes_popped_off);
 
                     (bytes_popped_off, ())
                 });
@@ -286,16 +362,16 @@ pub fn send_request(
                 }
 
                 // if we just received another packet (the packet buffer changed size), then update the timeout deadline
-                if orig_packet_length != packet_byte_buffer.len() {
-                    latest_packet_timestamp = hpet_ticks!();
+                if orig_packet_length != self.packet_byte_buffer.len() {
+                    self.latest_packet_timestamp = hpet_ticks!();
                 }
                 
                 new_state
             }
 
             HttpState::Responded => {
-                debug!("http_client: received full {}-byte HTTP response (_loop_ctr: {}).", packet_byte_buffer.len(), _loop_ctr);
-                break;
+                debug!("http_client: received full {}-byte HTTP response (_loop_ctr: {}).", self.packet_byte_buffer.len(), _loop_ctr);
+                return Ok(Progress::Done);
             }
 
             HttpState::ReceivingResponse if !socket.may_recv() => {
@@ -305,21 +381,24 @@ pub fn send_request(
 
             _ => { 
                 // if _loop_ctr % 50000 == 0 {
-                //     warn!("http_client: waiting in state {:?} for socket to send/recv ...", state);
+                //     warn!("http_client: waiting in state {:?} for socket to send/recv ...", self.state);
                 // }
-                state
+                self.state
             }
-        }
-    }
+        };
 
+        Ok(if packet_io_occurred || self.state != prev_state { Progress::Active } else { Progress::Idle })
+    }
 
-    // debug!("http_client: exiting HTTP state loop with state: {:?} (_loop_ctr: {})", state, _loop_ctr);
+    /// Consumes this transaction and returns the fully-received HTTP response.
+    fn into_response(self) -> Result<HttpResponse, &'static str> {
+        // debug!("http_client: exiting HTTP state loop with state: {:?} (_loop_ctr: {})", self.state, self._loop_ctr);
 
-    Ok(HttpResponse {
-        packet: packet_byte_buffer,
-        header_length: packet_header_length.ok_or("BUG: received full HTTP response but couldn't determine packet header length")?,
-        status_code: response_status_code.ok_or("BUG: received full HTTP response but couldn't determine its status code")?,
-        reason: response_reason.ok_or("BUG: received full HTTP response but couldn't determine its reason phrase")?,
-    })
-    
+        Ok(HttpResponse {
+            packet: self.packet_byte_buffer,
+            header_length: self.packet_header_length.ok_or("BUG: received full HTTP response but couldn't determine packet header length")?,
+            status_code: self.response_status_code.ok_or("BUG: received full HTTP response but couldn't determine its status code")?,
+            reason: self.response_reason.ok_or("BUG: received full HTTP response but couldn't determine its reason phrase")?,
+        })
+    }
 }
@@ -69,15 +69,18 @@ pub fn is_exception_handler_with_error_code(address: u64) -> bool {
 /// # Arguments: 
 /// * `double_fault_stack_top_unusable`: the address of the top of a newly allocated stack,
 ///    to be used as the double fault exception handler stack.
+/// * `page_fault_stack_top_unusable`: the address of the top of a newly allocated stack,
+///    to be used as the page fault exception handler stack.
 /// * `privilege_stack_top_unusable`: the address of the top of a newly allocated stack,
 ///    to be used as the privilege stack (Ring 3 -> Ring 0 stack).
 pub fn init(
     double_fault_stack_top_unusable: VirtualAddress,
+    page_fault_stack_top_unusable: VirtualAddress,
     privilege_stack_top_unusable: VirtualAddress
 ) -> Result<&'static LockedIdt, &'static str> {
     let bsp_id = apic::get_bsp_id().ok_or("couldn't get BSP's id")?;
     info!("Setting up TSS & GDT for BSP (id {})", bsp_id);
-    gdt::create_and_load_tss_gdt(bsp_id, double_fault_stack_top_unusable, privilege_stack_top_unusable);
+    gdt::create_and_load_tss_gdt(bsp_id, double_fault_stack_top_unusable, page_fault_stack_top_unusable, privilege_stack_top_unusable);
 
     // Before loading this new IDT, we must copy over all exception handlers from the early IDT.
     // However, we can't just clone `EARLY_IDT` into `IDT`, because we must 
@@ -153,10 +156,11 @@ pub fn init(
 pub fn init_ap(
     apic_id: u8, 
     double_fault_stack_top_unusable: VirtualAddress, 
+    page_fault_stack_top_unusable: VirtualAddress, 
     privilege_stack_top_unusable: VirtualAddress,
 ) -> Result<&'static LockedIdt, &'static str> {
     info!("Setting up TSS & GDT for AP {}", apic_id);
-    gdt::create_and_load_tss_gdt(apic_id, double_fault_stack_top_unusable, privilege_stack_top_unusable);
+    gdt::create_and_load_tss_gdt(apic_id, double_fault_stack_top_unusable, page_fault_stack_top_unusable, privilege_stack_top_unusable);
 
     // We've already created the IDT initially (currently all CPUs share the initial IDT),
     // so we only need to re-load it here for each AP.
@@ -302,9 +306,11 @@ extern "x86-interrupt" fn lapic_timer_handler(_stack_frame: InterruptStackFrame)
     // info!(" ({}) APIC TIMER HANDLER! TICKS = {}", apic::get_my_apic_id(), _ticks);
 
     // Callback to the sleep API to unblock tasks whose waiting time is over
-    // and alert to update the number of ticks elapsed
-    sleep::increment_tick_count();
-    sleep::unblock_sleeping_tasks();
+    // and to re-arm this CPU's timer for its next event (if tickless).
+    sleep::tick::handle_timer_interrupt();
+
+    // Check whether any other CPU has stopped scheduling.
+    watchdog::check_for_soft_lockups();
     
     // we must acknowledge the interrupt first before handling it because we switch tasks here, which doesn't return
     eoi(None); // None, because 0x22 IRQ cannot possibly be a PIC interrupt
@@ -0,0 +1,188 @@
+//! DMA domains, which define the memory that a device is allowed to access via DMA.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{fmt, ops::Deref};
+use irq_safety::MutexIrqSafe;
+use memory::{get_kernel_mmi_ref, MappedPages, PhysicalAddress, PAGE_SIZE};
+use crate::tables::{IommuTable, ADDRESS_MASK, SL_READ, SL_WRITE};
+
+/// An address that a device uses to access memory via DMA,
+/// which is translated by the IOMMU into a physical address.
+///
+/// Currently, the memory mapped into a [`DmaDomain`] is identity-mapped,
+/// such that each IO virtual address equals the physical address it's translated to.
+pub type IoVirtualAddress = PhysicalAddress;
+
+/// A set of memory that one device is allowed to access via DMA.
+///
+/// A device can only access memory that has been mapped into its domain with [`DmaDomain::map()`];
+/// any other DMA access is blocked by the IOMMU and recorded as a fault (see [`crate::report_faults()`]).
+///
+/// If there is no IOMMU, or if DMA remapping isn't supported, the domain doesn't restrict DMA at all,
+/// but drivers should still map their memory into it so that they work correctly when it does.
+pub struct DmaDomain {
+    /// The domain ID used by the IOMMU to tag this domain's cached translations.
+    id: u16,
+    /// The physical address of the top-level second-level page table, which never changes.
+    root_paddr: Option<PhysicalAddress>,
+    /// The second-level page tables that translate this domain's IO virtual addresses,
+    /// or `None` if this domain doesn't restrict DMA.
+    tables: Option<MutexIrqSafe<DomainTables>>,
+}
+
+impl DmaDomain {
+    /// Creates a new domain that restricts DMA to the memory mapped into it.
+    pub(crate) fn new(id: u16, levels: usize, coherent: bool) -> Result<DmaDomain, &'static str> {
+        let root = IommuTable::new()?;
+        Ok(DmaDomain {
+            id,
+            root_paddr: Some(root.paddr()),
+            tables: Some(MutexIrqSafe::new(DomainTables {
+                root,
+                subtables: BTreeMap::new(),
+                levels,
+                coherent,
+            })),
+        })
+    }
+
+    /// Creates a domain that doesn't restrict DMA, used when DMA remapping isn't available.
+    pub(crate) fn unrestricted() -> DmaDomain {
+        DmaDomain { id: 0, root_paddr: None, tables: None }
+    }
+
+    /// Returns the domain ID of this domain.
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Returns `true` if the IOMMU restricts DMA to the memory mapped into this domain.
+    pub fn is_remapped(&self) -> bool {
+        self.tables.is_some()
+    }
+
+    /// Returns the physical address of this domain's top-level second-level page table.
+    pub(crate) fn root_table_paddr(&self) -> Option<PhysicalAddress> {
+        self.root_paddr
+    }
+
+    /// Allows the device(s) in this domain to access the given `MappedPages` via DMA.
+    ///
+    /// If `writable` is `false`, the device can only read from the memory.
+    ///
+    /// Returns the IO virtual address that the device should use to access the start of `mp`.
+    /// The memory stays mapped into this domain until it is passed to [`DmaDomain::unmap()`],
+    /// so it must not be dropped before then.
+    pub fn map(&self, mp: &MappedPages, writable: bool) -> Result<IoVirtualAddress, &'static str> {
+        let frames = translate_frames(mp)?;
+        let start = *frames.first().ok_or("DmaDomain::map(): MappedPages were empty")?;
+        let Some(tables) = &self.tables else { return Ok(start) };
+
+        let flags = if writable { SL_READ | SL_WRITE } else { SL_READ };
+        let mut tables = tables.lock();
+        for iova in frames {
+            tables.set_leaf_entry(iova, iova.value() as u64 | flags)?;
+        }
+        drop(tables);
+
+        // The IOMMU may have cached the previously not-present entries.
+        crate::invalidate_after_map(self.id)?;
+        Ok(start)
+    }
+
+    /// Revokes the device(s) in this domain's access to the given `MappedPages`,
+    /// which must have been previously mapped with [`DmaDomain::map()`].
+    ///
+    /// Once this returns, the device can no longer access `mp` and it can be safely dropped.
+    pub fn unmap(&self, mp: &MappedPages) -> Result<(), &'static str> {
+        let Some(tables) = &self.tables else { return Ok(()) };
+        let frames = translate_frames(mp)?;
+        let mut tables = tables.lock();
+        for iova in frames {
+            tables.set_leaf_entry(iova, 0)?;
+        }
+        // Invalidate while holding the domain's lock such that a concurrent `map()`
+        // of the same frames can't be undone by a stale invalidation.
+        crate::invalidate_domain_iotlb(self.id)
+    }
+}
+
+impl fmt::Debug for DmaDomain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DmaDomain")
+            .field("id", &self.id)
+            .field("is_remapped", &self.is_remapped())
+            .finish()
+    }
+}
+
+/// Returns the starting physical address of each page of the given `MappedPages`.
+///
+/// This must be done before acquiring any IOMMU locks, because allocating
+/// the IOMMU's tables also requires the kernel's page table.
+fn translate_frames(mp: &MappedPages) -> Result<Vec<PhysicalAddress>, &'static str> {
+    let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("DmaDomain: kernel MMI was not yet initialized")?;
+    let kernel_mmi = kernel_mmi_ref.lock();
+    mp.deref().clone().into_iter()
+        .map(|page| kernel_mmi.page_table.translate_page(page)
+            .map(|frame| frame.start_address())
+            .ok_or("DmaDomain: a page of the MappedPages was not mapped in the kernel's page table")
+        )
+        .collect()
+}
+
+/// The second-level page tables of a [`DmaDomain`].
+struct DomainTables {
+    /// The top-level table.
+    root: IommuTable,
+    /// All lower-level tables, keyed by their physical address,
+    /// which is what the entries of higher-level tables point to.
+    subtables: BTreeMap<PhysicalAddress, IommuTable>,
+    /// The number of levels of tables, i.e., 3 for 39-bit or 4 for 48-bit IO virtual addresses.
+    levels: usize,
+    /// Whether the IOMMU snoops the CPU caches when walking these tables.
+    coherent: bool,
+}
+
+impl DomainTables {
+    /// Sets the leaf entry that translates the page at the given `iova` to `value`,
+    /// creating the intermediate tables as needed.
+    ///
+    /// If `value` is `0` (not present), no intermediate tables are created.
+    fn set_leaf_entry(&mut self, iova: IoVirtualAddress, value: u64) -> Result<(), &'static str> {
+        let iova = iova.value();
+        if iova >= 1 << (12 + 9 * self.levels) {
+            return Err("DmaDomain: physical address is beyond the IOMMU's address width");
+        }
+        let coherent = self.coherent;
+        let mut table_paddr: Option<PhysicalAddress> = None;
+        for level in (1 .. self.levels).rev() {
+            let index = (iova >> (12 + 9 * level)) & 0x1FF;
+            let table = match table_paddr {
+                None => &mut self.root,
+                Some(paddr) => self.subtables.get_mut(&paddr).ok_or("BUG: DmaDomain: missing IOMMU page table")?,
+            };
+            let entry = table.get(index);
+            let next_paddr = if entry & (SL_READ | SL_WRITE) != 0 {
+                PhysicalAddress::new_canonical((entry & ADDRESS_MASK) as usize)
+            } else if value == 0 {
+                return Ok(());
+            } else {
+                let new_table = IommuTable::new()?;
+                let paddr = new_table.paddr();
+                table.set(index, paddr.value() as u64 | SL_READ | SL_WRITE, coherent);
+                self.subtables.insert(paddr, new_table);
+                paddr
+            };
+            table_paddr = Some(next_paddr);
+        }
+
+        let index = (iova / PAGE_SIZE) & 0x1FF;
+        let table = match table_paddr {
+            None => &mut self.root,
+            Some(paddr) => self.subtables.get_mut(&paddr).ok_or("BUG: DmaDomain: missing IOMMU page table")?,
+        };
+        table.set(index, value, coherent);
+        Ok(())
+    }
+}
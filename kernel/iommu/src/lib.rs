@@ -5,6 +5,7 @@
 #![allow(dead_code)]
 #![no_std]
 
+extern crate alloc;
 extern crate irq_safety;
 #[macro_use] extern crate log;
 #[macro_use] extern crate static_assertions;
@@ -13,13 +14,27 @@ extern crate spin;
 extern crate volatile;
 extern crate zerocopy;
 extern crate bitflags;
+extern crate pci;
 
+use alloc::{collections::{btree_map::Entry, BTreeMap}, sync::Arc};
 use spin::Once;
 use irq_safety::MutexIrqSafe;
 use memory::{PageTable, EntryFlags, PhysicalAddress, allocate_frames_at, allocate_pages, BorrowedMappedPages, Mutable};
+use pci::{PciDevice, PciLocation};
+use volatile::Volatile;
 
 mod regs;
+mod tables;
+mod domain;
 use regs::*;
+use tables::*;
+pub use domain::{DmaDomain, IoVirtualAddress};
+
+/// The domain ID used for the context entries of devices that are allowed untranslated DMA.
+const PASS_THROUGH_DOMAIN_ID: u16 = 1;
+/// The first domain ID given to a [`DmaDomain`].
+/// Domain ID 0 is reserved when the IOMMU is in caching mode.
+const FIRST_DOMAIN_ID: u16 = 2;
 
 /// Struct representing IOMMU (TODO: rename since this is specific to Intel VT-d)
 pub struct IntelIommu {
@@ -31,6 +46,32 @@ pub struct IntelIommu {
     register_base_address: PhysicalAddress,
     /// Memory mapped control registers
     regs: BorrowedMappedPages<IntelIommuRegisters, Mutable>,
+    /// The state of DMA remapping, which is enabled upon the first call to [`domain_for_device()`].
+    remapping: Remapping,
+    /// The domain of each device that has been given one, keyed by the device's source ID.
+    domains: BTreeMap<u16, Arc<DmaDomain>>,
+    /// The domain ID that will be given to the next [`DmaDomain`].
+    next_domain_id: u16,
+}
+
+/// The state of DMA remapping in an IOMMU.
+enum Remapping {
+    /// DMA remapping hasn't been enabled yet.
+    Disabled,
+    /// DMA remapping isn't supported by this IOMMU, so DMA is never restricted.
+    Unsupported,
+    /// DMA remapping is enabled using the given root table and context tables.
+    Enabled {
+        root_table: IommuTable,
+        /// The context table for each PCI bus number.
+        context_tables: BTreeMap<u8, IommuTable>,
+        /// The number of levels of second-level page tables, i.e., 3 or 4.
+        levels: usize,
+        /// The adjusted guest address width used in context entries, i.e., 1 or 2.
+        agaw: u64,
+        /// Whether the IOMMU snoops the CPU caches when walking its tables.
+        coherent: bool,
+    },
 }
 
 /// Singleton representing IOMMU (TODO: could there be more than one IOMMU?)
@@ -38,8 +79,9 @@ static IOMMU: Once<MutexIrqSafe<IntelIommu>> = Once::new();
 
 /// Initialize the IOMMU hardware.
 ///
-/// Currently this just sets up basic structures and prints out information about the IOMMU;
-/// it doesn't actually create any I/O device page tables.
+/// This just sets up basic structures and prints out information about the IOMMU.
+/// DMA remapping is enabled later, once PCI devices have been discovered,
+/// upon the first call to [`domain_for_device()`].
 ///
 /// # Arguments
 /// * `host_address_width`: number of address bits available for DMA
@@ -95,6 +137,9 @@ pub fn init(host_address_width: u8,
         pci_segment_number,
         register_base_address,
         regs,
+        remapping: Remapping::Disabled,
+        domains: BTreeMap::new(),
+        next_domain_id: FIRST_DOMAIN_ID,
     };
 
     // initialize the iommu singleton with this object
@@ -134,12 +179,263 @@ fn set_command_bit(
     bit_value: bool,
     condition: impl Fn(GlobalStatus) -> bool
 ) -> Result<(), &'static str> {
-    let iommu = &mut IOMMU.get().ok_or("IOMMU not initialized!")?.lock();
-    let tmp = iommu.regs.gstatus.read();
-    let tmp = tmp & 0x96ffffff;
-    let bits = command as u32;
-    let cmd = if bit_value { tmp | bits } else { tmp & (!bits) };
-    iommu.regs.gcommand.write(cmd);
-    while !condition(GlobalStatus::from_bits_truncate(iommu.regs.gstatus.read())) {}
+    IOMMU.get().ok_or("IOMMU not initialized!")?.lock().set_command_bit(command, bit_value, condition);
     Ok(())
 }
+
+/// Returns the DMA domain of the given PCI device, which defines the memory it can access via DMA.
+///
+/// Upon the first call for a given device, a new empty domain is created and the device is attached to it,
+/// which blocks all of the device's DMA until memory is mapped into the domain with [`DmaDomain::map()`].
+/// Devices that never get a domain are allowed untranslated DMA, such that drivers
+/// that don't yet use this interface continue to work.
+///
+/// If there is no IOMMU or it doesn't support DMA remapping, the returned domain doesn't restrict DMA.
+pub fn domain_for_device(device: &PciDevice) -> Result<Arc<DmaDomain>, &'static str> {
+    let Some(iommu) = IOMMU.get() else { return Ok(Arc::new(DmaDomain::unrestricted())) };
+    let mut iommu = iommu.lock();
+    let source_id = source_id(&device.location);
+    if let Some(domain) = iommu.domains.get(&source_id) {
+        return Ok(domain.clone());
+    }
+    if !iommu.enable_remapping()? {
+        return Ok(Arc::new(DmaDomain::unrestricted()));
+    }
+    let domain = Arc::new(iommu.create_domain()?);
+    iommu.attach(&device.location, &domain)?;
+    iommu.domains.insert(source_id, domain.clone());
+    info!("IOMMU: attached PCI device {:?} to DMA domain {}", device.location, domain.id());
+    Ok(domain)
+}
+
+/// Logs and clears all DMA remapping faults recorded by the IOMMU,
+/// e.g., those caused by a device accessing memory that wasn't mapped into its domain.
+///
+/// Returns the number of faults that were reported.
+pub fn report_faults() -> usize {
+    IOMMU.get().map_or(0, |iommu| iommu.lock().report_faults())
+}
+
+/// Invalidates the IOTLB entries of the given domain after new memory was mapped into it,
+/// which is only needed if the IOMMU may cache not-present entries.
+pub(crate) fn invalidate_after_map(domain_id: u16) -> Result<(), &'static str> {
+    let mut iommu = IOMMU.get().ok_or("IOMMU not initialized!")?.lock();
+    if iommu.capability().caching_mode() {
+        iommu.invalidate_iotlb(Some(domain_id))
+    } else {
+        iommu.flush_write_buffer();
+        Ok(())
+    }
+}
+
+/// Invalidates the IOTLB entries of the given domain, e.g., after memory was unmapped from it.
+pub(crate) fn invalidate_domain_iotlb(domain_id: u16) -> Result<(), &'static str> {
+    IOMMU.get().ok_or("IOMMU not initialized!")?.lock().invalidate_iotlb(Some(domain_id))
+}
+
+/// Returns the source ID that identifies DMA requests from the device at the given PCI location.
+fn source_id(location: &PciLocation) -> u16 {
+    (location.bus() << 8) | (location.slot() << 3) | location.function()
+}
+
+impl IntelIommu {
+    fn capability(&self) -> Capability {
+        Capability(self.regs.cap.read())
+    }
+
+    fn extended_capability(&self) -> ExtendedCapability {
+        ExtendedCapability(self.regs.ecap.read())
+    }
+
+    /// Writes a command to the Global Command register; see [`set_command_bit()`].
+    fn set_command_bit(
+        &mut self,
+        command: GlobalCommand, 
+        bit_value: bool,
+        condition: impl Fn(GlobalStatus) -> bool
+    ) {
+        let tmp = self.regs.gstatus.read();
+        let tmp = tmp & 0x96ffffff;
+        let bits = command as u32;
+        let cmd = if bit_value { tmp | bits } else { tmp & (!bits) };
+        self.regs.gcommand.write(cmd);
+        while !condition(GlobalStatus::from_bits_truncate(self.regs.gstatus.read())) {}
+    }
+
+    /// Returns the 64-bit register at the given `offset` from the register base address,
+    /// i.e., one whose offset is given by the capability or extended capability register.
+    fn register_at(&mut self, offset: usize) -> Result<&mut Volatile<u64>, &'static str> {
+        if offset + 8 > core::mem::size_of::<IntelIommuRegisters>() {
+            return Err("IOMMU register was beyond the first page of registers");
+        }
+        let base: *mut IntelIommuRegisters = &mut *self.regs;
+        // SAFETY: the offset is within the mapped registers, and the IOMMU's registers are 8-byte aligned.
+        Ok(unsafe { &mut *((base as *mut u8).add(offset) as *mut Volatile<u64>) })
+    }
+
+    /// Flushes the IOMMU's internal write buffers, if it requires that,
+    /// such that it observes all prior modifications of its tables.
+    fn flush_write_buffer(&mut self) {
+        if self.capability().requires_write_buffer_flush() {
+            self.set_command_bit(GlobalCommand::WBF, true, |x: GlobalStatus| { !x.intersects(GlobalStatus::WBFS) });
+        }
+    }
+
+    /// Invalidates all entries of the IOMMU's context-entry cache.
+    fn invalidate_context_cache(&mut self) {
+        self.flush_write_buffer();
+        self.regs.context_command.write(context_command::ICC | context_command::CIRG_GLOBAL);
+        while self.regs.context_command.read() & context_command::ICC != 0 {}
+    }
+
+    /// Invalidates the IOTLB entries of the given domain, or all IOTLB entries if `domain_id` is `None`.
+    fn invalidate_iotlb(&mut self, domain_id: Option<u16>) -> Result<(), &'static str> {
+        self.flush_write_buffer();
+        let mut command = iotlb_command::IVT | match domain_id {
+            Some(id) => iotlb_command::IIRG_DOMAIN | ((id as u64) << iotlb_command::DID_SHIFT),
+            None => iotlb_command::IIRG_GLOBAL,
+        };
+        if self.capability().supports_drain() {
+            command |= iotlb_command::DR | iotlb_command::DW;
+        }
+        // The IOTLB Invalidate register follows the Invalidate Address register.
+        let offset = self.extended_capability().iotlb_register_offset() + 8;
+        let register = self.register_at(offset)?;
+        register.write(command);
+        while register.read() & iotlb_command::IVT != 0 {}
+        Ok(())
+    }
+
+    /// Enables DMA remapping if it hasn't been enabled yet.
+    ///
+    /// Every PCI device is first given a pass-through context entry,
+    /// such that devices without a domain can continue to perform DMA.
+    ///
+    /// Returns `false` if this IOMMU doesn't support DMA remapping as used here.
+    fn enable_remapping(&mut self) -> Result<bool, &'static str> {
+        match self.remapping {
+            Remapping::Enabled { .. } => return Ok(true),
+            Remapping::Unsupported => return Ok(false),
+            Remapping::Disabled => { }
+        }
+
+        let cap = self.capability();
+        let ecap = self.extended_capability();
+        let (levels, agaw) = if cap.supports_agaw(2) {
+            (4, 2)
+        } else if cap.supports_agaw(1) {
+            (3, 1)
+        } else {
+            warn!("IOMMU doesn't support 3-level or 4-level page tables, so DMA will not be remapped.");
+            self.remapping = Remapping::Unsupported;
+            return Ok(false);
+        };
+        if !ecap.supports_pass_through() {
+            warn!("IOMMU doesn't support pass-through translation, so DMA will not be remapped.");
+            self.remapping = Remapping::Unsupported;
+            return Ok(false);
+        }
+
+        self.remapping = Remapping::Enabled {
+            root_table: IommuTable::new()?,
+            context_tables: BTreeMap::new(),
+            levels,
+            agaw,
+            coherent: ecap.page_walk_coherency(),
+        };
+        let result = pci::pci_device_iter().try_for_each(|device|
+            self.set_context_entry(&device.location, CONTEXT_PASS_THROUGH, PASS_THROUGH_DOMAIN_ID)
+        );
+        if result.is_err() {
+            self.remapping = Remapping::Disabled;
+            return result.map(|_| false);
+        }
+
+        let Remapping::Enabled { root_table, .. } = &self.remapping else { unreachable!() };
+        let root_table_paddr = root_table.paddr().value() as u64;
+        self.regs.root_table_address.write(root_table_paddr);
+        self.set_command_bit(GlobalCommand::SRTP, true, |x: GlobalStatus| { x.intersects(GlobalStatus::RTPS) });
+        self.invalidate_context_cache();
+        self.invalidate_iotlb(None)?;
+        self.set_command_bit(GlobalCommand::TE, true, |x: GlobalStatus| { x.intersects(GlobalStatus::TES) });
+        info!("IOMMU: enabled DMA remapping with {}-level page tables.", levels);
+        Ok(true)
+    }
+
+    /// Creates a new empty domain.
+    fn create_domain(&mut self) -> Result<DmaDomain, &'static str> {
+        let Remapping::Enabled { levels, coherent, .. } = self.remapping else {
+            return Err("BUG: IOMMU: DMA remapping was not enabled");
+        };
+        if self.next_domain_id as u64 >= self.capability().num_domains() {
+            return Err("IOMMU: out of domain IDs");
+        }
+        let id = self.next_domain_id;
+        self.next_domain_id += 1;
+        DmaDomain::new(id, levels, coherent)
+    }
+
+    /// Attaches the device at the given PCI location to the given `domain`,
+    /// such that its DMA is translated by that domain's page tables.
+    fn attach(&mut self, location: &PciLocation, domain: &DmaDomain) -> Result<(), &'static str> {
+        let root = domain.root_table_paddr().ok_or("BUG: IOMMU: cannot attach a device to an unrestricted domain")?;
+        self.set_context_entry(location, root.value() as u64, domain.id())?;
+        // The device's previous pass-through context entry may be cached.
+        self.invalidate_context_cache();
+        self.invalidate_iotlb(None)
+    }
+
+    /// Sets the context entry of the device at the given PCI location.
+    ///
+    /// The `low` word holds the translation type and second-level page table pointer;
+    /// the present bit and the address width and domain ID fields are filled in here.
+    fn set_context_entry(&mut self, location: &PciLocation, low: u64, domain_id: u16) -> Result<(), &'static str> {
+        let Remapping::Enabled { root_table, context_tables, agaw, coherent, .. } = &mut self.remapping else {
+            return Err("BUG: IOMMU: DMA remapping was not enabled");
+        };
+        let bus = location.bus() as u8;
+        let context_table = match context_tables.entry(bus) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let table = e.insert(IommuTable::new()?);
+                root_table.set_entry128(bus as usize, table.paddr().value() as u64 | PRESENT, 0, *coherent);
+                table
+            }
+        };
+        let devfn = (source_id(location) & 0xFF) as usize;
+        let high = *agaw | ((domain_id as u64) << CONTEXT_DOMAIN_ID_SHIFT);
+        context_table.set_entry128(devfn, low | PRESENT, high, *coherent);
+        Ok(())
+    }
+
+    /// Logs and clears all recorded faults; see [`report_faults()`].
+    fn report_faults(&mut self) -> usize {
+        let cap = self.capability();
+        let mut count = 0;
+        for i in 0 .. cap.num_fault_recording_registers() {
+            let offset = cap.fault_recording_offset() + i * 16;
+            let Ok(high) = self.register_at(offset + 8).map(|r| r.read()) else { break };
+            // The Fault (F) bit
+            if high & (1 << 63) == 0 {
+                continue;
+            }
+            let Ok(low) = self.register_at(offset).map(|r| r.read()) else { break };
+            let source_id = high & 0xFFFF;
+            let reason = (high >> 32) & 0xFF;
+            let is_read = high & (1 << 62) != 0;
+            error!("IOMMU: DMA remapping fault: device {:02x}:{:02x}.{} {} address {:#X}, fault reason {:#X}",
+                source_id >> 8, (source_id >> 3) & 0x1F, source_id & 0x7,
+                if is_read { "read from" } else { "wrote to" },
+                low & !0xFFF, reason,
+            );
+            // The F bit is cleared by writing 1 to it.
+            if let Ok(r) = self.register_at(offset + 8) {
+                r.write(1 << 63);
+            }
+            count += 1;
+        }
+        let status = self.regs.fault_status.read();
+        self.regs.fault_status.write(status & (FaultStatus::PFO | FaultStatus::PPF).bits());
+        count
+    }
+}
@@ -1,7 +1,7 @@
 //! Structures needed for interacting with the IOMMU.
 
 use zerocopy::FromBytes;
-use volatile::{ReadOnly, WriteOnly};
+use volatile::{ReadOnly, Volatile, WriteOnly};
 use bitflags::bitflags;
 use core::fmt;
 
@@ -21,8 +21,18 @@ pub struct IntelIommuRegisters {
     pub gcommand:           WriteOnly<u32>,    // 0x18
     /// Global status register
     pub gstatus:            ReadOnly<u32>,     // 0x1c
-    /// Unimplemented (may be architecturally defined)
-    _unimplemented:         [u8; 4096-0x20],   // 0x20-0xFFF
+    /// Root table address register
+    pub root_table_address: Volatile<u64>,     // 0x20
+    /// Context command register
+    pub context_command:    Volatile<u64>,     // 0x28
+    /// Reserved
+    _reserved1:             [u8; 4],           // 0x30 - 0x33
+    /// Fault status register
+    pub fault_status:       Volatile<u32>,     // 0x34
+    /// Unimplemented (may be architecturally defined).
+    /// The IOTLB registers and fault recording registers are somewhere in here,
+    /// at offsets given by the extended capability and capability registers.
+    _unimplemented:         [u8; 4096-0x38],   // 0x38-0xFFF
 }
 // TODO: Hardware may use more than 4kB, which means the registers may occupy
 //       more than one contiguous page.
@@ -33,6 +43,24 @@ const_assert_eq!(core::mem::size_of::<IntelIommuRegisters>(), 4096);
 pub struct Capability(pub u64);
 
 impl Capability {
+    /// Returns the number of domain IDs supported by the IOMMU.
+    pub fn num_domains(&self) -> u64 { 1 << (4 + 2 * self.nd()) }
+    /// Returns whether the adjusted guest address widths (AGAW) supported for second-level translation
+    /// include the given `agaw`, where `1` means 39 bits (3-level paging) and `2` means 48 bits (4-level paging).
+    pub fn supports_agaw(&self, agaw: u64) -> bool { self.sagaw() & (1 << agaw) != 0 }
+    /// Returns whether the IOMMU requires that its write buffers be flushed before invalidations.
+    pub fn requires_write_buffer_flush(&self) -> bool { self.rwbf() }
+    /// Returns whether the IOMMU may cache not-present entries, which then also require invalidation.
+    pub fn caching_mode(&self) -> bool { self.cm() }
+    /// Returns whether the IOMMU supports draining reads and writes when invalidating the IOTLB.
+    pub fn supports_drain(&self) -> bool { self.drd() && self.dwd() }
+    /// Returns the maximum guest address width, in bits.
+    pub fn max_guest_address_width(&self) -> u64 { self.mgaw() }
+    /// Returns the offset of the first fault recording register from the register base address.
+    pub fn fault_recording_offset(&self) -> usize { self.fro() as usize * 16 }
+    /// Returns the number of fault recording registers.
+    pub fn num_fault_recording_registers(&self) -> usize { self.nfr() as usize }
+
     fn esrtps(&self)  -> bool { (self.0) & (1 << 63) != 0 }
     fn esirtps(&self) -> bool { (self.0) & (1 << 62) != 0 }
     fn fl5lp(&self)   -> bool { (self.0) & (1 << 60) != 0 }
@@ -88,6 +116,14 @@ impl fmt::Debug for Capability {
 pub struct ExtendedCapability(pub u64);
 
 impl ExtendedCapability {
+    /// Returns the offset of the IOTLB registers from the register base address.
+    pub fn iotlb_register_offset(&self) -> usize { self.iro() as usize * 16 }
+    /// Returns whether the IOMMU supports pass-through translation, in which untranslated DMA is allowed.
+    pub fn supports_pass_through(&self) -> bool { self.pt() }
+    /// Returns whether the IOMMU snoops the CPU caches when walking its translation structures.
+    /// If not, those structures must be flushed from the CPU caches after being modified.
+    pub fn page_walk_coherency(&self) -> bool { self.c() }
+
     fn rprivs(&self)  -> bool { (self.0) & (1 << 53) != 0 }
     fn adms(&self)    -> bool { (self.0) & (1 << 52) != 0 }
     fn rps(&self)     -> bool { (self.0) & (1 << 49) != 0 }
@@ -204,3 +240,37 @@ bitflags! {
         const TES   = 1 << 31;
     }
 }
+
+/// Bits and fields of the Context Command register.
+pub mod context_command {
+    /// Invalidate Context-Cache: set to request an invalidation, cleared by hardware once it's done.
+    pub const ICC: u64 = 1 << 63;
+    /// Context Invalidation Request Granularity: global invalidation.
+    pub const CIRG_GLOBAL: u64 = 0b01 << 61;
+}
+
+/// Bits and fields of the IOTLB Invalidate register.
+pub mod iotlb_command {
+    /// Invalidate IOTLB: set to request an invalidation, cleared by hardware once it's done.
+    pub const IVT: u64 = 1 << 63;
+    /// IOTLB Invalidation Request Granularity: global invalidation.
+    pub const IIRG_GLOBAL: u64 = 0b01 << 60;
+    /// IOTLB Invalidation Request Granularity: domain-selective invalidation.
+    pub const IIRG_DOMAIN: u64 = 0b10 << 60;
+    /// Drain Reads.
+    pub const DR: u64 = 1 << 49;
+    /// Drain Writes.
+    pub const DW: u64 = 1 << 48;
+    /// The shift of the Domain ID field.
+    pub const DID_SHIFT: u64 = 32;
+}
+
+bitflags! {
+    /// Fault status register flags.
+    pub struct FaultStatus: u32 {
+        /// Primary Fault Overflow
+        const PFO = 1 << 0;
+        /// Primary Pending Fault
+        const PPF = 1 << 1;
+    }
+}
@@ -0,0 +1,80 @@
+//! The in-memory translation structures that the IOMMU walks to remap DMA:
+//! the root table, context tables, and second-level page tables.
+//!
+//! Each of these structures is a single 4KiB table of 64-bit words.
+//! Root and context entries are 128 bits (two words), while second-level page table entries are one word.
+
+use memory::{create_contiguous_mapping, BorrowedSliceMappedPages, EntryFlags, Mutable, PhysicalAddress, PAGE_SIZE};
+
+/// The number of 64-bit words in one table.
+pub const WORDS_PER_TABLE: usize = PAGE_SIZE / core::mem::size_of::<u64>();
+
+/// The present bit of a root entry or context entry.
+pub const PRESENT: u64 = 1 << 0;
+/// The mask of the physical address bits in any entry.
+pub const ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// The Translation Type field of a context entry (in its low word) that allows
+/// the device's DMA to pass through untranslated.
+pub const CONTEXT_PASS_THROUGH: u64 = 0b10 << 2;
+/// The shift of the Domain ID field of a context entry (in its high word).
+pub const CONTEXT_DOMAIN_ID_SHIFT: u64 = 8;
+
+/// The read permission bit of a second-level page table entry.
+pub const SL_READ: u64 = 1 << 0;
+/// The write permission bit of a second-level page table entry.
+pub const SL_WRITE: u64 = 1 << 1;
+
+
+/// A zeroed 4KiB table that the IOMMU reads, along with its physical address.
+pub struct IommuTable {
+    words: BorrowedSliceMappedPages<u64, Mutable>,
+    paddr: PhysicalAddress,
+}
+
+impl IommuTable {
+    /// Allocates a new table with all entries not present.
+    pub fn new() -> Result<IommuTable, &'static str> {
+        let (mp, paddr) = create_contiguous_mapping(PAGE_SIZE, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE)?;
+        let mut words = mp.into_borrowed_slice_mut(0, WORDS_PER_TABLE).map_err(|(_mp, err)| err)?;
+        words.fill(0);
+        Ok(IommuTable { words, paddr })
+    }
+
+    /// Returns the physical address of this table.
+    pub fn paddr(&self) -> PhysicalAddress {
+        self.paddr
+    }
+
+    /// Returns the word at the given `index`.
+    pub fn get(&self, index: usize) -> u64 {
+        self.words[index]
+    }
+
+    /// Sets the word at the given `index` to the given `value`.
+    ///
+    /// If the IOMMU doesn't snoop the CPU caches (`coherent` is `false`),
+    /// the modified word is flushed from the CPU caches so that the IOMMU will observe it.
+    pub fn set(&mut self, index: usize, value: u64, coherent: bool) {
+        self.words[index] = value;
+        if !coherent {
+            // Use `clflush` directly, as the `_mm_clflush` intrinsic requires SSE2, which the kernel doesn't enable.
+            // SAFETY: the address is within this table, which is mapped.
+            unsafe {
+                core::arch::asm!("clflush [{}]", in(reg) &self.words[index] as *const u64, options(nostack, preserves_flags));
+            }
+        }
+    }
+
+    /// Sets the 128-bit entry at the given `index`, e.g., a root entry or context entry,
+    /// writing its high word before its low word, which contains the present bit.
+    pub fn set_entry128(&mut self, index: usize, low: u64, high: u64, coherent: bool) {
+        self.set(index * 2 + 1, high, coherent);
+        self.set(index * 2, low, coherent);
+    }
+
+    /// Returns the low word of the 128-bit entry at the given `index`.
+    pub fn entry128_low(&self, index: usize) -> u64 {
+        self.get(index * 2)
+    }
+}
@@ -39,6 +39,7 @@ extern crate physical_nic;
 extern crate virtual_nic;
 extern crate zerocopy;
 extern crate hashbrown;
+extern crate iommu;
 
 mod regs;
 mod queue_registers;
@@ -66,6 +67,7 @@ use nic_initialization::*;
 use intel_ethernet::descriptors::{AdvancedRxDescriptor, AdvancedTxDescriptor};    
 use nic_buffers::{TransmitBuffer, ReceiveBuffer, ReceivedFrame};
 use nic_queues::{RxQueue, TxQueue};
+use iommu::DmaDomain;
 use rand::{
     SeedableRng,
     RngCore,
@@ -192,8 +194,7 @@ impl NetworkInterfaceCard for IxgbeNic {
     fn send_packet(&mut self, transmit_buffer: TransmitBuffer) -> Result<(), &'static str> {
         // by default, when using the physical NIC interface, we send on queue 0.
         let qid = 0;
-        self.tx_queues[qid].send_on_queue(transmit_buffer);
-        Ok(())
+        self.tx_queues[qid].send_on_queue(transmit_buffer)
     }
 
     fn get_received_frame(&mut self) -> Option<ReceivedFrame> {
@@ -206,7 +207,7 @@ impl NetworkInterfaceCard for IxgbeNic {
     fn poll_receive(&mut self) -> Result<(), &'static str> {
         // by default, when using the physical NIC interface, we receive on queue 0.
         let qid = 0;
-        self.rx_queues[qid].poll_queue_and_store_received_packets()
+        poll_rx_queue(&mut self.rx_queues[qid])
     }
 
     fn mac_address(&self) -> [u8; 6] {
@@ -288,6 +289,10 @@ impl IxgbeNic {
         // 16-byte aligned memory mapped base address
         let mem_base =  ixgbe_pci_dev.determine_mem_base(0)?;
 
+        // Restrict the NIC's DMA to the memory that we map into its domain,
+        // which must be done before enabling bus mastering.
+        let dma_domain = iommu::domain_for_device(ixgbe_pci_dev)?;
+
         // set the bus mastering bit for this PciDevice, which allows it to use DMA
         ixgbe_pci_dev.pci_set_command_bus_master_bit();
 
@@ -308,10 +313,10 @@ impl IxgbeNic {
         let mac_addr_hardware = Self::read_mac_address_from_nic(&mut mapped_registers_mac);
 
         // initialize the buffer pool
-        init_rx_buf_pool(RX_BUFFER_POOL_SIZE, rx_buffer_size_kbytes as u16 * 1024, &RX_BUFFER_POOL)?;
+        init_rx_buf_pool(RX_BUFFER_POOL_SIZE, rx_buffer_size_kbytes as u16 * 1024, &RX_BUFFER_POOL, Some(&dma_domain))?;
 
         // create the rx desc queues and their packet buffers
-        let (mut rx_descs, mut rx_buffers) = Self::rx_init(&mut mapped_registers1, &mut mapped_registers2, &mut rx_mapped_registers, num_rx_descriptors, rx_buffer_size_kbytes)?;
+        let (mut rx_descs, mut rx_buffers) = Self::rx_init(&mut mapped_registers1, &mut mapped_registers2, &mut rx_mapped_registers, num_rx_descriptors, rx_buffer_size_kbytes, &dma_domain)?;
         
         // create the vec of rx queues
         let mut rx_queues = Vec::with_capacity(rx_descs.len());
@@ -328,7 +333,8 @@ impl IxgbeNic {
                 received_frames: VecDeque::new(),
                 cpu_id : None,
                 rx_buffer_pool: &RX_BUFFER_POOL,
-                filter_num: None
+                filter_num: None,
+                dma_domain: dma_domain.clone(),
             };
             rx_queues.push(rx_queue);
             id += 1;
@@ -336,7 +342,7 @@ impl IxgbeNic {
 
 
         // create the tx descriptor queues
-        let mut tx_descs = Self::tx_init(&mut mapped_registers2, &mut mapped_registers_mac, &mut tx_mapped_registers, num_tx_descriptors)?;
+        let mut tx_descs = Self::tx_init(&mut mapped_registers2, &mut mapped_registers_mac, &mut tx_mapped_registers, num_tx_descriptors, &dma_domain)?;
         
         // create the vec of tx queues
         let mut tx_queues = Vec::with_capacity(tx_descs.len());
@@ -349,6 +355,8 @@ impl IxgbeNic {
                 num_tx_descs: num_tx_descriptors,
                 tx_cur: 0,
                 cpu_id : None,
+                dma_domain: dma_domain.clone(),
+                tx_buffer: init_tx_buffer(&dma_domain)?,
             };
             tx_queues.push(tx_queue);
             id += 1;
@@ -789,7 +797,8 @@ impl IxgbeNic {
         regs: &mut IntelIxgbeRegisters2, 
         rx_regs: &mut Vec<IxgbeRxQueueRegisters>,
         num_rx_descs: u16,
-        rx_buffer_size_kbytes: RxBufferSizeKiB
+        rx_buffer_size_kbytes: RxBufferSizeKiB,
+        dma_domain: &DmaDomain,
     ) -> Result<(
         Vec<BorrowedSliceMappedPages<AdvancedRxDescriptor, Mutable>>, 
         Vec<Vec<ReceiveBuffer>>
@@ -814,7 +823,7 @@ impl IxgbeNic {
             let rxq = &mut rx_regs[qid as usize];        
 
             // get the queue of rx descriptors and their corresponding rx buffers
-            let (rx_descs, rx_bufs_in_use) = init_rx_queue(num_rx_descs as usize, &RX_BUFFER_POOL, rx_buffer_size_kbytes as usize * 1024, rxq)?;          
+            let (rx_descs, rx_bufs_in_use) = init_rx_queue(num_rx_descs as usize, &RX_BUFFER_POOL, rx_buffer_size_kbytes as usize * 1024, rxq, dma_domain)?;          
             
             //set the size of the packet buffers and the descriptor format used
             let mut val = rxq.srrctl.read();
@@ -874,7 +883,8 @@ impl IxgbeNic {
         regs: &mut IntelIxgbeRegisters2, 
         regs_mac: &mut IntelIxgbeMacRegisters, 
         tx_regs: &mut Vec<IxgbeTxQueueRegisters>,
-        num_tx_descs: u16
+        num_tx_descs: u16,
+        dma_domain: &DmaDomain,
     ) -> Result<Vec<BorrowedSliceMappedPages<AdvancedTxDescriptor, Mutable>>, &'static str> {
         // disable transmission
         Self::disable_transmission(regs);
@@ -900,7 +910,7 @@ impl IxgbeNic {
         for qid in 0..IXGBE_NUM_TX_QUEUES_ENABLED {
             let txq = &mut tx_regs[qid as usize];
 
-            let tx_descs = init_tx_queue(num_tx_descs as usize, txq)?;
+            let tx_descs = init_tx_queue(num_tx_descs as usize, txq, dma_domain)?;
         
             if qid == 0 {
                 // enable transmit operation, only have to do this for the first queue
@@ -1292,7 +1302,17 @@ pub fn tx_send_mq(qid: usize, nic_id: PciLocation, packet: Option<TransmitBuffer
     let nic_ref = get_ixgbe_nic(nic_id)?;
     let mut nic = nic_ref.lock();  
 
-    nic.tx_queues[qid].send_on_queue(packet);
+    nic.tx_queues[qid].send_on_queue(packet)
+}
+
+/// Collects the frames newly received on the given rx queue,
+/// notifying any tasks waiting for a received frame if there were any.
+fn poll_rx_queue(rx_queue: &mut RxQueue<IxgbeRxQueueRegisters,AdvancedRxDescriptor>) -> Result<(), &'static str> {
+    let num_frames = rx_queue.received_frames.len();
+    rx_queue.poll_queue_and_store_received_packets()?;
+    if rx_queue.received_frames.len() > num_frames {
+        network_interface_card::notify_frame_received();
+    }
     Ok(())
 }
 
@@ -1302,7 +1322,7 @@ fn rx_interrupt_handler(qid: u8, nic_id: PciLocation) -> Option<u8> {
     match get_ixgbe_nic(nic_id) {
         Ok(ref ixgbe_nic_ref) => {
             let mut ixgbe_nic = ixgbe_nic_ref.lock();
-            let _ = ixgbe_nic.rx_queues[qid as usize].poll_queue_and_store_received_packets();
+            let _ = poll_rx_queue(&mut ixgbe_nic.rx_queues[qid as usize]);
             ixgbe_nic.interrupt_num.get(&qid).map(|int| *int)
         }
         Err(e) => {
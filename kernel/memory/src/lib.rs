@@ -37,14 +37,18 @@ pub mod paging;
 pub use self::paging::{
     PageTable, Mapper, Mutability, Mutable, Immutable,
     MappedPages, BorrowedMappedPages, BorrowedSliceMappedPages,
+    PageFault, PageFaultResolver, LazyMappingStats,
+    lazy_mapping_stats, register_page_fault_resolver, resolve_page_fault,
+    CopyOnWriteStats, copy_on_write_stats,
 };
 
-pub use memory_structs::{Frame, Page, FrameRange, PageRange, VirtualAddress, PhysicalAddress};
+pub use memory_structs::{Frame, Page, FrameRange, PageRange, PageSize, VirtualAddress, PhysicalAddress};
 pub use page_allocator::{AllocatedPages, allocate_pages, allocate_pages_at,
-    allocate_pages_by_bytes, allocate_pages_by_bytes_at};
+    allocate_pages_by_bytes, allocate_pages_by_bytes_at, allocate_huge_pages};
 
 pub use frame_allocator::{AllocatedFrames, MemoryRegionType, PhysicalMemoryRegion,
-    allocate_frames_by_bytes_at, allocate_frames_by_bytes, allocate_frames_at};
+    allocate_frames_by_bytes_at, allocate_frames_by_bytes, allocate_frames_at, allocate_huge_frames,
+    allocate_frames_on_node, current_numa_node, numa_node_count, numa_node_of};
 
 #[cfg(target_arch = "x86_64")]
 use memory_x86_64::{BootInformation, get_kernel_address, get_boot_info_mem_area, find_section_memory_bounds,
@@ -110,6 +114,8 @@ pub fn create_contiguous_mapping(size_in_bytes: usize, flags: EntryFlags) -> Res
 /// then see [`create_contiguous_mapping()`](fn.create_contiguous_mapping.html).
 /// Returns the new `MappedPages.` 
 /// 
+/// The frames are allocated from the current CPU's NUMA node whenever that node has free frames.
+/// 
 /// # Locking / Deadlock
 /// Currently, this function acquires the lock on the kernel's `MemoryManagementInfo` instance.
 /// Thus, the caller should ensure that lock is not held when invoking this function.
@@ -120,6 +126,21 @@ pub fn create_mapping(size_in_bytes: usize, flags: EntryFlags) -> Result<MappedP
 }
 
 
+/// A convenience function that creates a new lazy memory mapping,
+/// whose pages are only backed by (zeroed) physical frames when they are first accessed.
+/// This is useful for large buffers that may only be sparsely used.
+/// See [`Mapper::map_allocated_pages_lazily()`] for more details.
+/// 
+/// # Locking / Deadlock
+/// Currently, this function acquires the lock on the kernel's `MemoryManagementInfo` instance.
+/// Thus, the caller should ensure that lock is not held when invoking this function.
+pub fn create_lazy_mapping(size_in_bytes: usize, flags: EntryFlags) -> Result<MappedPages, &'static str> {
+    let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("create_lazy_mapping(): KERNEL_MMI was not yet initialized!")?;
+    let allocated_pages = allocate_pages_by_bytes(size_in_bytes).ok_or("memory::create_lazy_mapping(): couldn't allocate pages!")?;
+    kernel_mmi_ref.lock().page_table.map_allocated_pages_lazily(allocated_pages, flags)
+}
+
+
 static BROADCAST_TLB_SHOOTDOWN_FUNC: Once<fn(PageRange)> = Once::new();
 
 /// Set the function callback that will be invoked every time a TLB shootdown is necessary,
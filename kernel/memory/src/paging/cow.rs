@@ -0,0 +1,307 @@
+//! Support for copy-on-write sharing of frames between multiple mappings.
+//!
+//! [`MappedPages::copy_on_write()`] creates a new mapping that shares the frames of an existing mapping
+//! instead of eagerly copying them, mapping each shared frame as read-only into both mappings.
+//! The first write to a shared page causes a page fault, upon which [`resolve_cow_page_fault()`]
+//! gives the faulting page its own private copy of that frame.
+//!
+//! Each shared frame is reference counted, such that the last remaining mapping of a shared frame
+//! takes ownership of it upon a write (without copying it) or deallocates it when unmapped.
+//!
+//! Otherwise, the shared frame is copied into a new frame that is temporarily mapped to a *copy window*,
+//! a single page reserved ahead of time, because pages can't be allocated while resolving a page fault.
+//!
+//! [`MappedPages::copy_on_write()`]: super::MappedPages::copy_on_write
+
+use alloc::collections::BTreeMap;
+use core::{
+    mem,
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use irq_safety::MutexIrqSafe;
+use kernel_config::memory::PAGE_SIZE;
+use super::{
+    get_current_p4, tlb_flush_virt_addr,
+    mapper::INTO_ALLOCATED_FRAMES_FUNC,
+    EntryFlags, Frame, FrameRange, Mapper, PageFault, PageRange,
+};
+use {AllocatedFrames, AllocatedPages, BROADCAST_TLB_SHOOTDOWN_FUNC, Page, PageSize};
+
+/// The bookkeeping state of all copy-on-write mappings.
+struct CowState {
+    /// All mappings that may share frames, keyed by their starting page.
+    regions: BTreeMap<Page, CowRegion>,
+    /// The number of mappings that currently share each shared frame.
+    shared_frames: BTreeMap<Frame, usize>,
+    /// The page that a new frame is temporarily mapped to while a shared frame is copied into it,
+    /// along with the root page table frame that its page tables exist in.
+    copy_window: Option<(AllocatedPages, Frame)>,
+}
+
+static COW_STATE: MutexIrqSafe<CowState> = MutexIrqSafe::new(CowState {
+    regions: BTreeMap::new(),
+    shared_frames: BTreeMap::new(),
+    copy_window: None,
+});
+/// The total number of pages that were copied upon a write to a shared frame.
+static COPIED_PAGES: AtomicUsize = AtomicUsize::new(0);
+/// The total number of shared frames that were reclaimed without copying by the last mapping sharing them.
+static RECLAIMED_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+/// The bookkeeping info for a single copy-on-write mapping.
+struct CowRegion {
+    /// The pages covered by this mapping.
+    pages: PageRange,
+    /// The flags that this mapping's pages should have once they no longer share a frame.
+    flags: EntryFlags,
+    /// The root page table frame that this mapping exists in.
+    page_table_p4: Frame,
+}
+
+/// Statistics about copy-on-write sharing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CopyOnWriteStats {
+    /// The number of frames currently mapped as shared, read-only frames by copy-on-write mappings.
+    /// This includes frames whose other sharers have since been unmapped, until they are written to or unmapped.
+    pub shared_frames: usize,
+    /// The total number of pages that have been copied upon the first write to a shared frame.
+    pub copied_pages: usize,
+    /// The total number of shared frames that were written to by the last mapping sharing them,
+    /// which thus took ownership of the frame without copying it.
+    pub reclaimed_pages: usize,
+}
+
+/// Returns the current statistics about copy-on-write sharing.
+pub fn copy_on_write_stats() -> CopyOnWriteStats {
+    CopyOnWriteStats {
+        shared_frames: COW_STATE.lock().shared_frames.len(),
+        copied_pages: COPIED_PAGES.load(Ordering::Relaxed),
+        reclaimed_pages: RECLAIMED_PAGES.load(Ordering::Relaxed),
+    }
+}
+
+/// Returns the flags that a shared page should be mapped with,
+/// given the `flags` that the page would have if it didn't share its frame.
+pub(super) fn shared_flags(flags: EntryFlags) -> EntryFlags {
+    let mut shared = flags;
+    shared.set(EntryFlags::WRITABLE, false);
+    shared.set(EntryFlags::EXCLUSIVE, false);
+    shared
+}
+
+/// Reserves the copy window page and creates the page tables that cover it,
+/// if that hasn't already been done.
+///
+/// This must be invoked before any pages are shared,
+/// because neither pages nor page tables can be allocated while resolving a page fault.
+pub(super) fn reserve_copy_window(mapper: &mut Mapper) -> Result<(), &'static str> {
+    if COW_STATE.lock().copy_window.is_some() {
+        return Ok(());
+    }
+    let pages = page_allocator::allocate_pages(1).ok_or("couldn't allocate a copy window page for copy-on-write mappings")?;
+    mapper.create_p1_tables(&pages, EntryFlags::PRESENT | EntryFlags::WRITABLE);
+    let mut state = COW_STATE.lock();
+    if state.copy_window.is_none() {
+        state.copy_window = Some((pages, mapper.target_p4));
+    }
+    Ok(())
+}
+
+/// Maps each of the `dest_pages` to the same frame as the corresponding page in `src_pages`,
+/// marking those frames as shared and mapping them as read-only in both ranges of pages.
+///
+/// The P1 page tables covering the `dest_pages` must already exist.
+/// The caller is responsible for broadcasting a TLB shootdown for the `src_pages`.
+pub(super) fn share_pages(
+    mapper: &mut Mapper,
+    src_pages: &PageRange,
+    src_flags: EntryFlags,
+    dest_pages: &PageRange,
+    dest_flags: EntryFlags,
+) -> Result<(), &'static str> {
+    let into_allocated_frames = INTO_ALLOCATED_FRAMES_FUNC.get()
+        .ok_or("BUG: share_pages(): the `INTO_ALLOCATED_FRAMES_FUNC` callback was not initialized")?;
+    let mut state = COW_STATE.lock();
+
+    // Check all source pages before modifying any of them, such that a failure leaves them untouched.
+    for page in src_pages.clone() {
+        let (pte, page_size) = mapper.leaf_entry_mut(page).ok_or("share_pages(): page was not mapped")?;
+        let frame = pte.pointed_frame().ok_or("share_pages(): page was not mapped")?;
+        if page_size != PageSize::Normal4K {
+            return Err("share_pages(): huge pages cannot be shared");
+        }
+        if !pte.flags().is_exclusive() && !state.shared_frames.contains_key(&frame) {
+            return Err("share_pages(): page maps a frame that it doesn't own");
+        }
+    }
+
+    for (src_page, dest_page) in src_pages.clone().into_iter().zip(dest_pages.clone()) {
+        let frame = {
+            let (src_pte, _) = mapper.leaf_entry_mut(src_page).ok_or("BUG: share_pages(): source page was not mapped")?;
+            let frame = src_pte.pointed_frame().ok_or("BUG: share_pages(): source page was not mapped")?;
+            src_pte.set_flags(shared_flags(src_pte.flags()));
+            tlb_flush_virt_addr(src_page.start_address());
+            frame
+        };
+        // An exclusively-owned frame becomes shared by two mappings; an already-shared frame gains one more.
+        *state.shared_frames.entry(frame).or_insert(1) += 1;
+
+        let (dest_pte, _) = mapper.leaf_entry_mut(dest_page).ok_or("BUG: share_pages(): destination page table did not exist")?;
+        let shared_frame = into_allocated_frames(FrameRange::new(frame, frame));
+        dest_pte.set_entry(shared_frame.as_allocated_frame(), shared_flags(dest_flags));
+        // The shared frame is deallocated by whichever mapping releases it last.
+        mem::forget(shared_frame);
+    }
+
+    let page_table_p4 = mapper.target_p4;
+    state.regions.entry(*src_pages.start()).or_insert(CowRegion {
+        pages: src_pages.clone(),
+        flags: src_flags,
+        page_table_p4,
+    });
+    state.regions.insert(*dest_pages.start(), CowRegion {
+        pages: dest_pages.clone(),
+        flags: dest_flags,
+        page_table_p4,
+    });
+    Ok(())
+}
+
+/// Removes the copy-on-write mapping starting at the given page,
+/// such that writes to its shared pages will no longer be resolved.
+pub(super) fn unregister_cow_region(start: Page) {
+    COW_STATE.lock().regions.remove(&start);
+}
+
+/// Changes the flags that the pages of the copy-on-write mapping starting at the given page
+/// should have once they no longer share a frame.
+pub(super) fn set_cow_region_flags(start: Page, flags: EntryFlags) {
+    if let Some(region) = COW_STATE.lock().regions.get_mut(&start) {
+        region.flags = flags;
+    }
+}
+
+/// Releases one mapping's reference to the given shared `frame`, which has just been unmapped.
+///
+/// Returns `true` if that was the last reference, in which case the caller owns the frame
+/// and must deallocate it.
+pub(super) fn release_shared_frame(frame: Frame) -> bool {
+    let mut state = COW_STATE.lock();
+    let Some(refcount) = state.shared_frames.get_mut(&frame) else { return false };
+    *refcount -= 1;
+    if *refcount == 0 {
+        state.shared_frames.remove(&frame);
+        true
+    } else {
+        false
+    }
+}
+
+/// Gives the faulting page its own private frame if the fault was caused by a write
+/// to a shared page of a writable copy-on-write mapping.
+pub(super) fn resolve_cow_page_fault(fault: &PageFault) -> bool {
+    if !fault.was_present || !fault.was_write {
+        return false;
+    }
+    let page = Page::containing_address(fault.address);
+    let mut state = COW_STATE.lock();
+    let Some((_, region)) = state.regions.range(..=page).next_back() else { return false };
+    if !region.pages.contains(&page)
+        || region.page_table_p4 != get_current_p4()
+        || !region.flags.is_writable()
+    {
+        return false;
+    }
+    let flags = region.flags;
+    if frame_allocator::is_busy_on_current_cpu() {
+        error!("Cannot copy shared page {:?} upon write in the middle of a frame allocator operation", page);
+        return false;
+    }
+
+    match unshare_page(&mut state, page, flags) {
+        Ok(()) => true,
+        Err(e) => {
+            error!("Failed to copy shared page {:?} upon write: {}", page, e);
+            false
+        }
+    }
+}
+
+/// Maps the given shared `page` with the given `flags` to a private frame,
+/// which is a copy of the shared frame unless this `page` was the last one sharing it.
+fn unshare_page(state: &mut CowState, page: Page, flags: EntryFlags) -> Result<(), &'static str> {
+    let mut mapper = Mapper::from_current();
+    let (pte, page_size) = mapper.leaf_entry_mut(page).ok_or("the shared page was not mapped")?;
+    if page_size != PageSize::Normal4K {
+        return Err("the shared page was unexpectedly mapped as a huge page");
+    }
+    if pte.flags().is_writable() {
+        // Another CPU already unshared this page, so this fault was caused by a stale TLB entry.
+        tlb_flush_virt_addr(page.start_address());
+        return Ok(());
+    }
+    let frame = pte.pointed_frame().ok_or("the shared page was not mapped")?;
+    let refcount = state.shared_frames.get(&frame).copied().ok_or("the page's frame was not shared")?;
+
+    if refcount == 1 {
+        // No other mapping shares this frame anymore, so this page can take ownership of it.
+        state.shared_frames.remove(&frame);
+        pte.set_flags(flags);
+        tlb_flush_virt_addr(page.start_address());
+        RECLAIMED_PAGES.fetch_add(1, Ordering::Relaxed);
+        return Ok(());
+    }
+
+    let (window, window_p4) = state.copy_window.as_ref()
+        .map(|(pages, p4)| (*pages.start(), *p4))
+        .ok_or("BUG: the copy window for copy-on-write mappings was not reserved")?;
+    if window_p4 != mapper.target_p4 {
+        return Err("the copy window doesn't exist in the current page table");
+    }
+    let new_frame = frame_allocator::allocate_frames(1).ok_or("couldn't allocate a frame, out of memory")?;
+    copy_page_into_frame(&mut mapper, page, window, &new_frame)?;
+
+    let (pte, _) = mapper.leaf_entry_mut(page).ok_or("the shared page was not mapped")?;
+    pte.set_entry(new_frame.as_allocated_frame(), flags);
+    // The new frame is now owned by the `MappedPages` containing this page, which deallocates it when unmapped.
+    mem::forget(new_frame);
+    tlb_flush_virt_addr(page.start_address());
+
+    if let Some(count) = state.shared_frames.get_mut(&frame) {
+        *count -= 1;
+    }
+    COPIED_PAGES.fetch_add(1, Ordering::Relaxed);
+
+    // Other CPUs may still map this page to the shared frame.
+    #[cfg(not(bm_map))]
+    {
+        if let Some(func) = BROADCAST_TLB_SHOOTDOWN_FUNC.get() {
+            func(PageRange::new(page, page));
+        }
+    }
+    Ok(())
+}
+
+/// Copies the contents of the given mapped `page` into the given `frame`,
+/// which is temporarily mapped to the `window` page during the copy.
+fn copy_page_into_frame(
+    mapper: &mut Mapper,
+    page: Page,
+    window: Page,
+    frame: &AllocatedFrames,
+) -> Result<(), &'static str> {
+    let (window_pte, _) = mapper.leaf_entry_mut(window).ok_or("BUG: the copy window's page table did not exist")?;
+    window_pte.set_entry(frame.as_allocated_frame(), EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE);
+    // Another CPU may have used the window to copy a different frame, so this CPU's TLB may be stale.
+    tlb_flush_virt_addr(window.start_address());
+    // SAFETY: the page is mapped as readable, and the window was just mapped as writable to a frame
+    //         that nothing else can access. The window is only used while holding the lock on `COW_STATE`.
+    unsafe {
+        ptr::copy_nonoverlapping(page.start_address().value() as *const u8, window.start_address().value() as *mut u8, PAGE_SIZE);
+    }
+    // The window doesn't own the frame, so it's simply cleared rather than unmapped.
+    window_pte.zero();
+    tlb_flush_virt_addr(window.start_address());
+    Ok(())
+}
@@ -0,0 +1,213 @@
+//! Support for demand paging, i.e., mappings whose frames are allocated lazily upon first access.
+//!
+//! A lazy mapping, created by [`Mapper::map_allocated_pages_lazily()`], reserves a range of virtual pages
+//! and creates the page tables that cover them, but doesn't allocate any frames up front.
+//! Instead, the page fault handler invokes [`resolve_page_fault()`], which backs the faulting page
+//! with a newly-allocated, zeroed frame and then lets the faulting access be retried.
+//! This is useful for large, sparsely-accessed buffers, which would otherwise need to be fully
+//! allocated and zeroed when created.
+//!
+//! Because resolving such a fault allocates a frame, a lazy page can't be backed while the faulting CPU
+//! is in the middle of a frame allocator operation, which may hold the frame allocator's locks.
+//! For the same reason, the kernel heap is always mapped eagerly:
+//! the frame allocator allocates heap memory while holding its locks,
+//! so a fault on a lazily-mapped heap page would have to allocate frames at exactly such a time.
+//!
+//! Other subsystems can handle page faults of their own by registering
+//! additional resolvers with [`register_page_fault_resolver()`].
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{
+    mem,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use irq_safety::MutexIrqSafe;
+use kernel_config::memory::PAGE_SIZE;
+use super::{
+    get_current_p4, tlb_flush_virt_addr,
+    EntryFlags, Frame, Mapper, PageRange,
+};
+use {Page, PageSize, VirtualAddress};
+
+/// Information about a page fault, which is passed to each page fault resolver.
+#[derive(Clone, Copy, Debug)]
+pub struct PageFault {
+    /// The virtual address whose access caused the page fault.
+    pub address: VirtualAddress,
+    /// Whether the faulting page was present, i.e., the fault was caused by a protection violation.
+    pub was_present: bool,
+    /// Whether the fault was caused by a write access.
+    pub was_write: bool,
+    /// Whether the fault was caused by an instruction fetch.
+    pub was_instruction_fetch: bool,
+}
+
+/// A function that attempts to resolve a page fault, e.g., by mapping the faulting page.
+///
+/// Returns `true` if the fault was resolved, in which case the faulting access will be retried.
+///
+/// Resolvers are invoked from within the page fault handler with interrupts disabled,
+/// so they must not allocate heap memory or acquire locks that may be held by the faulting code.
+pub type PageFaultResolver = fn(&PageFault) -> bool;
+
+/// The additional page fault resolvers registered by other subsystems.
+static PAGE_FAULT_RESOLVERS: MutexIrqSafe<Vec<PageFaultResolver>> = MutexIrqSafe::new(Vec::new());
+
+/// All currently-existing lazy mappings, keyed by their starting page.
+static LAZY_REGIONS: MutexIrqSafe<BTreeMap<Page, LazyRegion>> = MutexIrqSafe::new(BTreeMap::new());
+/// The total number of pages reserved by all lazy mappings.
+static RESERVED_LAZY_PAGES: AtomicUsize = AtomicUsize::new(0);
+/// The total number of pages of all lazy mappings that have been backed by frames.
+static RESIDENT_LAZY_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+/// The bookkeeping info for a single lazy mapping.
+struct LazyRegion {
+    /// The pages covered by this lazy mapping.
+    pages: PageRange,
+    /// The flags used to map each page upon first access.
+    flags: EntryFlags,
+    /// The root page table frame that this lazy mapping was created in.
+    page_table_p4: Frame,
+    /// The number of pages that have been backed by frames so far.
+    resident_pages: usize,
+}
+
+/// Statistics about the memory usage of all lazy mappings.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LazyMappingStats {
+    /// The total number of virtual pages reserved by lazy mappings.
+    pub reserved_pages: usize,
+    /// The number of those pages that have been accessed and are thus backed by physical frames.
+    pub resident_pages: usize,
+}
+
+/// Returns the current statistics about the memory usage of all lazy mappings.
+pub fn lazy_mapping_stats() -> LazyMappingStats {
+    LazyMappingStats {
+        reserved_pages: RESERVED_LAZY_PAGES.load(Ordering::Relaxed),
+        resident_pages: RESIDENT_LAZY_PAGES.load(Ordering::Relaxed),
+    }
+}
+
+/// Registers a function that will be invoked to resolve page faults
+/// that weren't caused by accessing a lazy or copy-on-write mapping.
+///
+/// Resolvers are invoked in the order they were registered, until one of them resolves the fault.
+pub fn register_page_fault_resolver(resolver: PageFaultResolver) {
+    PAGE_FAULT_RESOLVERS.lock().push(resolver);
+}
+
+/// Attempts to resolve the given page fault, which should be called by the page fault handler.
+///
+/// First, this checks whether the faulting address is part of a lazy mapping in the current page table,
+/// and if so, backs the faulting page with a new zeroed frame.
+/// Second, this checks whether the fault was a write to a shared page of a copy-on-write mapping,
+/// and if so, gives that page its own private copy of the shared frame.
+/// Otherwise, each registered [`PageFaultResolver`] is invoked.
+///
+/// Returns `true` if the fault was resolved and the faulting access can be retried,
+/// or `false` if the fault is a genuine error.
+pub fn resolve_page_fault(fault: &PageFault) -> bool {
+    resolve_lazy_page_fault(fault)
+        || super::cow::resolve_cow_page_fault(fault)
+        || PAGE_FAULT_RESOLVERS.lock().iter().any(|resolver| resolver(fault))
+}
+
+/// Backs the faulting page with a new frame if it belongs to a lazy mapping
+/// that permits the faulting access.
+fn resolve_lazy_page_fault(fault: &PageFault) -> bool {
+    if fault.was_present {
+        return false;
+    }
+    let page = Page::containing_address(fault.address);
+    let mut regions = LAZY_REGIONS.lock();
+    let Some((_, region)) = regions.range_mut(..=page).next_back() else { return false };
+    if !region.pages.contains(&page)
+        || region.page_table_p4 != get_current_p4()
+        || (fault.was_write && !region.flags.is_writable())
+        || (fault.was_instruction_fetch && !region.flags.is_executable())
+    {
+        return false;
+    }
+    if frame_allocator::is_busy_on_current_cpu() {
+        error!("Cannot back lazily-mapped page {:?} in the middle of a frame allocator operation", page);
+        return false;
+    }
+
+    match populate_lazy_page(page, region.flags) {
+        Ok(true) => {
+            region.resident_pages += 1;
+            RESIDENT_LAZY_PAGES.fetch_add(1, Ordering::Relaxed);
+            true
+        }
+        // Another CPU already backed this page while we were waiting for the lock.
+        Ok(false) => true,
+        Err(e) => {
+            error!("Failed to back lazily-mapped page {:?}: {}", page, e);
+            false
+        }
+    }
+}
+
+/// Maps the given `page` of a lazy mapping to a newly-allocated zeroed frame.
+///
+/// Returns `Ok(false)` if the page was already mapped.
+fn populate_lazy_page(page: Page, flags: EntryFlags) -> Result<bool, &'static str> {
+    let mut mapper = Mapper::from_current();
+    let (pte, page_size) = mapper.leaf_entry_mut(page).ok_or("the page table for the lazy page didn't exist")?;
+    if page_size != PageSize::Normal4K {
+        return Err("the lazy page was unexpectedly mapped as a huge page");
+    }
+    if !pte.is_unused() {
+        return Ok(false);
+    }
+
+    let frame = frame_allocator::allocate_frames(1).ok_or("couldn't allocate a frame, out of memory")?;
+    // The page must be writable while we zero it.
+    pte.set_entry(frame.as_allocated_frame(), flags.into_writable());
+    // The frame is now owned by the lazy `MappedPages`, which deallocates it when unmapped.
+    mem::forget(frame);
+    // SAFETY: the page was just mapped as writable to a frame that nothing else can access.
+    unsafe {
+        core::ptr::write_bytes(page.start_address().value() as *mut u8, 0, PAGE_SIZE);
+    }
+    if !flags.is_writable() {
+        pte.set_flags(flags);
+        tlb_flush_virt_addr(page.start_address());
+    }
+    Ok(true)
+}
+
+/// Registers a new lazy mapping that covers the given `pages`,
+/// each of which will be mapped with the given `flags` upon first access.
+pub(super) fn register_lazy_region(pages: PageRange, flags: EntryFlags, page_table_p4: Frame) {
+    RESERVED_LAZY_PAGES.fetch_add(pages.size_in_pages(), Ordering::Relaxed);
+    LAZY_REGIONS.lock().insert(*pages.start(), LazyRegion {
+        pages,
+        flags,
+        page_table_p4,
+        resident_pages: 0,
+    });
+}
+
+/// Removes the lazy mapping starting at the given page, such that none of its pages
+/// will be backed by frames anymore upon future accesses.
+pub(super) fn unregister_lazy_region(start: Page) {
+    if let Some(region) = LAZY_REGIONS.lock().remove(&start) {
+        RESERVED_LAZY_PAGES.fetch_sub(region.pages.size_in_pages(), Ordering::Relaxed);
+        RESIDENT_LAZY_PAGES.fetch_sub(region.resident_pages, Ordering::Relaxed);
+    }
+}
+
+/// Changes the flags used to map the not-yet-accessed pages of the lazy mapping starting at the given page.
+pub(super) fn set_lazy_region_flags(start: Page, flags: EntryFlags) {
+    if let Some(region) = LAZY_REGIONS.lock().get_mut(&start) {
+        region.flags = flags;
+    }
+}
+
+/// Returns the number of pages of the lazy mapping starting at the given page
+/// that have been backed by frames.
+pub(super) fn lazy_region_resident_pages(start: Page) -> usize {
+    LAZY_REGIONS.lock().get(&start).map_or(0, |region| region.resident_pages)
+}
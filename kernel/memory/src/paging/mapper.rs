@@ -18,9 +18,11 @@ use core::{
     ptr::{NonNull, Unique},
     slice,
 };
-use {BROADCAST_TLB_SHOOTDOWN_FUNC, VirtualAddress, PhysicalAddress, Page, Frame, FrameRange, AllocatedPages, AllocatedFrames}; 
+use {BROADCAST_TLB_SHOOTDOWN_FUNC, VirtualAddress, PhysicalAddress, Page, PageSize, Frame, FrameRange, AllocatedPages, AllocatedFrames}; 
 use paging::{
+    cow,
     get_current_p4,
+    lazy,
     PageRange,
     table::{P4, Table, Level4},
 };
@@ -28,7 +30,7 @@ use spin::Once;
 use kernel_config::memory::{PAGE_SIZE, ENTRIES_PER_PAGE_TABLE};
 use super::{EntryFlags, tlb_flush_virt_addr};
 use zerocopy::FromBytes;
-use page_table_entry::UnmapResult;
+use page_table_entry::{PageTableEntry, UnmapResult};
 
 /// This is a private callback used to convert `UnmappedFrames` into `AllocatedFrames`.
 /// 
@@ -164,6 +166,20 @@ impl Mapper {
         frames: AllocatedFrames,
         flags: EntryFlags,
     ) -> Result<(MappedPages, AllocatedFrames), &'static str> {
+        let mapped_pages = self.map_frames(pages, &frames, flags, true)?;
+        Ok((mapped_pages, frames))
+    }
+
+
+    /// Maps the given `pages` to the given `frames`, marking each P1 entry as `EXCLUSIVE`
+    /// only if `exclusive` is `true`, i.e., if the new `MappedPages` will own those `frames`.
+    fn map_frames(
+        &mut self,
+        pages: AllocatedPages,
+        frames: &AllocatedFrames,
+        flags: EntryFlags,
+        exclusive: bool,
+    ) -> Result<MappedPages, &'static str> {
         let mut top_level_flags = flags.clone() | EntryFlags::PRESENT;
         // P4, P3, and P2 entries should never set NO_EXECUTE, only the lowest-level P1 entry should. 
         // top_level_flags.set(EntryFlags::WRITABLE, true); // is the same true for the WRITABLE bit?
@@ -172,7 +188,8 @@ impl Mapper {
         // because another page table frame may re-use (create another alias for) it without us knowing here.
         // Only the lowest-level P1 entry can be considered exclusive, only if it's mapped truly exclusively using this function.
         top_level_flags.set(EntryFlags::EXCLUSIVE, false);
-        let actual_flags = flags | EntryFlags::EXCLUSIVE | EntryFlags::PRESENT;
+        let mut actual_flags = flags | EntryFlags::PRESENT;
+        actual_flags.set(EntryFlags::EXCLUSIVE, exclusive);
 
         let pages_count = pages.size_in_pages();
         let frames_count = frames.size_in_frames();
@@ -197,14 +214,14 @@ impl Mapper {
             p1[page.p1_index()].set_entry(frame, actual_flags);
         }
 
-        Ok((
-            MappedPages {
-                page_table_p4: self.target_p4.clone(),
-                pages,
-                flags: actual_flags,
-            },
-            frames,
-        ))
+        Ok(MappedPages {
+            page_table_p4: self.target_p4.clone(),
+            pages,
+            flags: actual_flags,
+            page_size: PageSize::Normal4K,
+            lazy: false,
+            copy_on_write: false,
+        })
     }
     
 
@@ -229,6 +246,23 @@ impl Mapper {
     }
 
 
+    /// Maps the given virtual `AllocatedPages` to the given physical `AllocatedFrames` without taking ownership of them,
+    /// which allows the same frames to be mapped multiple times, e.g., into multiple tasks as shared memory.
+    /// 
+    /// The `frames` will not be deallocated when the returned `MappedPages` is unmapped,
+    /// so the caller must ensure that the `frames` outlive the returned `MappedPages`.
+    /// 
+    /// Consumes the given `AllocatedPages` and returns a `MappedPages` object which contains those `AllocatedPages`.
+    pub fn map_allocated_pages_to_shared(
+        &mut self,
+        pages: AllocatedPages,
+        frames: &AllocatedFrames,
+        flags: EntryFlags,
+    ) -> Result<MappedPages, &'static str> {
+        self.map_frames(pages, frames, flags, false)
+    }
+
+
     /// Maps the given `AllocatedPages` to randomly chosen (allocated) physical frames.
     /// 
     /// Consumes the given `AllocatedPages` and returns a `MappedPages` object which contains those `AllocatedPages`.
@@ -267,8 +301,270 @@ impl Mapper {
             page_table_p4: self.target_p4.clone(),
             pages,
             flags: actual_flags,
+            page_size: PageSize::Normal4K,
+            lazy: false,
+            copy_on_write: false,
+        })
+    }
+
+
+    /// Maps the given virtual `AllocatedPages` to the given physical `AllocatedFrames`
+    /// using huge pages of the given `page_size`, i.e., 2MiB or 1GiB pages.
+    ///
+    /// Both `pages` and `frames` must start at an address aligned to the `page_size`
+    /// and must span a whole number of huge pages, as returned by
+    /// [`allocate_huge_pages()`] and [`allocate_huge_frames()`].
+    /// Mapping 1GiB pages also requires that the CPU supports them (the `pdpe1gb` CPUID feature).
+    /// If `page_size` is [`PageSize::Normal4K`], this is identical to [`Mapper::map_allocated_pages_to()`].
+    ///
+    /// Consumes the given `AllocatedPages` and returns a `MappedPages` object which contains those `AllocatedPages`.
+    ///
+    /// [`allocate_huge_pages()`]: crate::allocate_huge_pages
+    /// [`allocate_huge_frames()`]: crate::allocate_huge_frames
+    pub fn map_allocated_huge_pages_to(
+        &mut self,
+        pages: AllocatedPages,
+        frames: AllocatedFrames,
+        flags: EntryFlags,
+        page_size: PageSize,
+    ) -> Result<MappedPages, &'static str> {
+        if !page_size.is_huge() {
+            return self.map_allocated_pages_to(pages, frames, flags);
+        }
+
+        let mut top_level_flags = flags.clone() | EntryFlags::PRESENT;
+        // P4 and P3 entries should never set NO_EXECUTE, only the huge page entry itself should.
+        top_level_flags.set(EntryFlags::NO_EXECUTE, false);
+        // As with normal pages, only the leaf entry can be considered exclusive.
+        top_level_flags.set(EntryFlags::EXCLUSIVE, false);
+        let actual_flags = flags | EntryFlags::EXCLUSIVE | EntryFlags::PRESENT;
+
+        let pages_count = pages.size_in_pages();
+        let frames_count = frames.size_in_frames();
+        if pages_count != frames_count {
+            error!("map_allocated_huge_pages_to(): pages {:?} count {} must equal frames {:?} count {}!", 
+                pages, pages_count, frames, frames_count
+            );
+            return Err("map_allocated_huge_pages_to(): page count must equal frame count");
+        }
+        if !page_size.is_aligned(pages.start().number())
+            || !page_size.is_aligned(frames.start().number())
+            || !page_size.is_aligned(pages_count)
+        {
+            error!("map_allocated_huge_pages_to(): pages {:?} and frames {:?} must be aligned to {:?} pages", 
+                pages, frames, page_size
+            );
+            return Err("map_allocated_huge_pages_to(): pages and frames must be aligned to the huge page size");
+        }
+
+        // iterate over the first page and frame of each huge page in lockstep
+        let step = page_size.num_4k_pages();
+        for (page, frame) in pages.deref().clone().into_iter().step_by(step).zip(frames.into_iter().step_by(step)) {
+            let p3 = self.p4_mut().next_table_create(page.p4_index(), top_level_flags);
+            let entry = match page_size {
+                PageSize::Huge1G => &mut p3[page.p3_index()],
+                _ => &mut p3.next_table_create(page.p3_index(), top_level_flags)[page.p2_index()],
+            };
+
+            if !entry.is_unused() {
+                error!("map_allocated_huge_pages_to(): page {:#X} -> frame {:#X}, page was already in use!", page.start_address(), frame.start_address());
+                return Err("map_allocated_huge_pages_to(): page was already in use");
+            } 
+
+            entry.set_entry(frame, actual_flags.into_huge());
+        }
+
+        // As in `map_allocated_pages_to()`, the frames are deallocated when the `MappedPages` is unmapped.
+        core::mem::forget(frames);
+
+        Ok(MappedPages {
+            page_table_p4: self.target_p4.clone(),
+            pages,
+            flags: actual_flags,
+            page_size,
+            lazy: false,
+            copy_on_write: false,
+        })
+    }
+
+
+    /// Maps the given `AllocatedPages` to newly-allocated physical frames using huge pages of the given `page_size`.
+    ///
+    /// The `pages` must be aligned to the `page_size`, as described in [`Mapper::map_allocated_huge_pages_to()`].
+    /// Unlike [`Mapper::map_allocated_pages()`], the frames are allocated as a single contiguous range,
+    /// because each huge page must be backed by physically-contiguous memory.
+    ///
+    /// Consumes the given `AllocatedPages` and returns a `MappedPages` object which contains those `AllocatedPages`.
+    pub fn map_allocated_huge_pages(&mut self, pages: AllocatedPages, flags: EntryFlags, page_size: PageSize)
+        -> Result<MappedPages, &'static str>
+    {
+        if !page_size.is_huge() {
+            return self.map_allocated_pages(pages, flags);
+        }
+        if !page_size.is_aligned(pages.size_in_pages()) {
+            return Err("map_allocated_huge_pages(): pages must span a whole number of huge pages");
+        }
+        let frames = frame_allocator::allocate_huge_frames(page_size, pages.size_in_pages() / page_size.num_4k_pages())
+            .ok_or("map_allocated_huge_pages(): couldn't allocate aligned huge frames, out of memory")?;
+        self.map_allocated_huge_pages_to(pages, frames, flags, page_size)
+    }
+
+
+    /// Maps the given `AllocatedPages` lazily, such that each page is only backed by a newly-allocated,
+    /// zeroed physical frame when it is first accessed, i.e., upon the first page fault on that page.
+    ///
+    /// The page tables covering the `pages` are created eagerly, so resolving a page fault
+    /// only needs to allocate a frame and set a single page table entry.
+    /// The returned `MappedPages` can be used like any other, but it cannot be split or merged.
+    /// See [`MappedPages::resident_pages()`] for how many of its pages are currently backed by frames.
+    ///
+    /// Lazily-mapped memory must not be accessed while holding a lock that the frame allocator acquires,
+    /// as doing so would deadlock the page fault handler.
+    ///
+    /// Consumes the given `AllocatedPages` and returns a `MappedPages` object which contains those `AllocatedPages`.
+    pub fn map_allocated_pages_lazily(&mut self, pages: AllocatedPages, flags: EntryFlags)
+        -> Result<MappedPages, &'static str>
+    {
+        let mut top_level_flags = flags.clone() | EntryFlags::PRESENT;
+        // P4, P3, and P2 entries should never set NO_EXECUTE, only the lowest-level P1 entry should. 
+        top_level_flags.set(EntryFlags::NO_EXECUTE, false);
+        // As with eager mappings, only the lowest-level P1 entry can be considered exclusive.
+        top_level_flags.set(EntryFlags::EXCLUSIVE, false);
+        let actual_flags = flags | EntryFlags::EXCLUSIVE | EntryFlags::PRESENT;
+
+        if pages.size_in_pages() == 0 {
+            return Err("map_allocated_pages_lazily(): cannot lazily map zero pages");
+        }
+
+        self.create_p1_tables(&pages, top_level_flags);
+        lazy::register_lazy_region(pages.deref().clone(), actual_flags, self.target_p4);
+
+        Ok(MappedPages {
+            page_table_p4: self.target_p4.clone(),
+            pages,
+            flags: actual_flags,
+            page_size: PageSize::Normal4K,
+            lazy: true,
+            copy_on_write: false,
         })
     }
+
+
+    /// Creates the P1 page tables (and their parent tables) that cover the given non-empty range of `pages`,
+    /// without mapping any of the `pages` themselves.
+    pub(super) fn create_p1_tables(&mut self, pages: &PageRange, top_level_flags: EntryFlags) {
+        // Visit the first page covered by each P1 table.
+        let mut page = *pages.start();
+        loop {
+            let p3 = self.p4_mut().next_table_create(page.p4_index(), top_level_flags);
+            let p2 = p3.next_table_create(page.p3_index(), top_level_flags);
+            let _p1 = p2.next_table_create(page.p2_index(), top_level_flags);
+
+            let next_table_page_number = (page.number() / ENTRIES_PER_PAGE_TABLE + 1) * ENTRIES_PER_PAGE_TABLE;
+            if next_table_page_number > pages.end().number() {
+                break;
+            }
+            page += next_table_page_number - page.number();
+        }
+    }
+
+
+    /// Returns the leaf page table entry that maps the given `page`,
+    /// along with the size of the (possibly huge) page mapped by that entry.
+    ///
+    /// Returns `None` if a page table on the way to that entry doesn't exist.
+    pub(super) fn leaf_entry_mut(&mut self, page: Page) -> Option<(&mut PageTableEntry, PageSize)> {
+        let p3 = self.p4_mut().next_table_mut(page.p4_index())?;
+        if p3[page.p3_index()].flags().is_huge() {
+            return Some((&mut p3[page.p3_index()], PageSize::Huge1G));
+        }
+        let p2 = p3.next_table_mut(page.p3_index())?;
+        if p2[page.p2_index()].flags().is_huge() {
+            return Some((&mut p2[page.p2_index()], PageSize::Huge2M));
+        }
+        let p1 = p2.next_table_mut(page.p2_index())?;
+        Some((&mut p1[page.p1_index()], PageSize::Normal4K))
+    }
+
+    /// Demotes the huge page containing the given `page` into 512 pages of the next smaller size,
+    /// which are mapped by a newly-created lower-level page table with the same flags and frames.
+    ///
+    /// This is needed before unmapping or remapping only part of a huge page,
+    /// e.g., one of the pieces created by [`MappedPages::split()`].
+    /// The new page table is filled in while mapped at a temporary page before it replaces the huge page entry,
+    /// so the huge page's memory remains accessible throughout.
+    fn demote_huge_page(&mut self, page: Page) -> Result<(), &'static str> {
+        let (start_frame, flags, page_size) = match self.leaf_entry_mut(page) {
+            Some((pte, page_size)) if page_size.is_huge() => (
+                pte.pointed_frame().ok_or("demote_huge_page(): huge page was not mapped")?,
+                pte.flags(),
+                page_size,
+            ),
+            _ => return Err("demote_huge_page(): page was not mapped by a huge page"),
+        };
+        let smaller_size = match page_size {
+            PageSize::Huge1G => PageSize::Huge2M,
+            _ => PageSize::Normal4K,
+        };
+        let mut child_flags = flags;
+        child_flags.set(EntryFlags::HUGE_PAGE, smaller_size.is_huge());
+        // The new table's entry is a non-leaf entry, so it uses the same flags as in `next_table_create()`.
+        let mut table_flags = flags;
+        table_flags.set(EntryFlags::NO_EXECUTE | EntryFlags::EXCLUSIVE | EntryFlags::HUGE_PAGE, false);
+        let table_flags = table_flags.into_writable() | EntryFlags::PRESENT;
+
+        let table_frame = frame_allocator::allocate_frames(1).ok_or("demote_huge_page(): couldn't allocate a page table frame")?;
+        let temp_page = page_allocator::allocate_pages(1).ok_or("demote_huge_page(): couldn't allocate a temporary page")?;
+        let (mut temp_mp, table_frame) = self.internal_map_to(temp_page, table_frame, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE)?;
+        {
+            let entries: &mut [PageTableEntry] = temp_mp.as_slice_mut(0, ENTRIES_PER_PAGE_TABLE)?;
+            let huge_frames = INTO_ALLOCATED_FRAMES_FUNC.get()
+                .ok_or("BUG: Mapper::demote_huge_page(): the `INTO_ALLOCATED_FRAMES_FUNC` callback was not initialized")
+                .map(|into_func| into_func(FrameRange::new(start_frame, start_frame + (page_size.num_4k_pages() - 1))))?;
+            for (entry, frame) in entries.iter_mut().zip(huge_frames.into_iter().step_by(smaller_size.num_4k_pages())) {
+                entry.set_entry(frame, child_flags);
+            }
+            // These frames are still owned by the huge page's `MappedPages`, which is now mapped by the new entries.
+            mem::forget(huge_frames);
+        }
+        match temp_mp.unmap_into_parts(self) {
+            // The unmapped frame is the same one as `table_frame`, so it must not be dropped here.
+            Ok((_temp_page, unmapped_frame)) => mem::forget(unmapped_frame),
+            Err(_temp_mp) => return Err("demote_huge_page(): failed to unmap the temporary page"),
+        }
+
+        let (pte, _) = self.leaf_entry_mut(page).ok_or("BUG: demote_huge_page(): huge page entry disappeared")?;
+        pte.set_entry(table_frame.as_allocated_frame(), table_flags);
+        mem::forget(table_frame); // we currently forget frames allocated as page table frames since we don't yet have a way to track them.
+
+        // Invalidating any single address within the huge page invalidates the entire huge page's TLB entry.
+        let huge_page = Page::containing_address(VirtualAddress::new_canonical(
+            page.start_address().value() & !(page_size.size_in_bytes() - 1)
+        ));
+        tlb_flush_virt_addr(huge_page.start_address());
+        if let Some(func) = BROADCAST_TLB_SHOOTDOWN_FUNC.get() {
+            func(PageRange::new(huge_page, huge_page));
+        }
+        Ok(())
+    }
+
+    /// Returns the leaf page table entry that maps the given `page` and the size of the page it maps,
+    /// first demoting the huge page that contains `page` if that huge page isn't entirely within `range`.
+    fn leaf_entry_within_mut(&mut self, page: Page, range: &PageRange) -> Option<(&mut PageTableEntry, PageSize)> {
+        loop {
+            let page_size = self.leaf_entry_mut(page)?.1;
+            let huge_start = page.number() - page.number() % page_size.num_4k_pages();
+            let huge_end = huge_start + (page_size.num_4k_pages() - 1);
+            if !page_size.is_huge() || (huge_start >= range.start().number() && huge_end <= range.end().number()) {
+                break;
+            }
+            if let Err(e) = self.demote_huge_page(page) {
+                error!("Mapper: failed to demote huge page containing {:?}: {}", page, e);
+                return None;
+            }
+        }
+        self.leaf_entry_mut(page)
+    }
 }
 
 // This implementation block contains a hacky function for non-bijective mappings 
@@ -329,6 +625,9 @@ impl Mapper {
             page_table_p4: mapper.target_p4.clone(),
             pages,
             flags: actual_flags,
+            page_size: PageSize::Normal4K,
+            lazy: false,
+            copy_on_write: false,
         })
     }
 }
@@ -350,6 +649,14 @@ pub struct MappedPages {
     pages: AllocatedPages,
     // The EntryFlags that define the page permissions of this mapping
     flags: EntryFlags,
+    /// The size of the pages this mapping was created with, i.e., whether it was mapped using huge pages.
+    /// Huge pages shared with another `MappedPages` after a [`MappedPages::split()`]
+    /// may since have been demoted into smaller pages.
+    page_size: PageSize,
+    /// Whether this mapping is lazy, i.e., its pages are only backed by frames upon first access.
+    lazy: bool,
+    /// Whether this mapping may share some of its frames with other mappings in a copy-on-write manner.
+    copy_on_write: bool,
 }
 impl Deref for MappedPages {
     type Target = PageRange;
@@ -366,6 +673,9 @@ impl MappedPages {
             page_table_p4: Frame::containing_address(PhysicalAddress::zero()),
             pages: AllocatedPages::empty(),
             flags: EntryFlags::zero(),
+            page_size: PageSize::Normal4K,
+            lazy: false,
+            copy_on_write: false,
         }
     }
 
@@ -374,6 +684,46 @@ impl MappedPages {
         self.flags
     }
 
+    /// Returns the size of the pages this `MappedPages` was originally mapped with.
+    pub fn page_size(&self) -> PageSize {
+        self.page_size
+    }
+
+    /// Returns `true` if this is a lazy mapping, whose pages are only backed by frames upon first access.
+    /// 
+    /// See [`Mapper::map_allocated_pages_lazily()`].
+    pub fn is_lazy(&self) -> bool {
+        self.lazy
+    }
+
+    /// Returns `true` if this mapping may share some of its frames with other mappings in a copy-on-write manner.
+    /// 
+    /// See [`MappedPages::copy_on_write()`].
+    pub fn is_copy_on_write(&self) -> bool {
+        self.copy_on_write
+    }
+
+    /// Returns the number of pages in this `MappedPages` that are currently backed by physical frames.
+    /// 
+    /// This is only less than its total number of pages if this is a lazy mapping.
+    pub fn resident_pages(&self) -> usize {
+        if self.lazy {
+            lazy::lazy_region_resident_pages(*self.pages.start())
+        } else {
+            self.size_in_pages()
+        }
+    }
+
+    /// Returns whether the given `page` of this `MappedPages` is currently backed by a physical frame.
+    ///
+    /// This is only `false` for pages outside of this `MappedPages` or pages of a lazy mapping
+    /// that haven't been accessed yet.
+    /// For a lazy mapping, this must only be invoked while its page table is the active one.
+    pub fn is_page_resident(&self, page: Page) -> bool {
+        self.pages.contains(&page)
+            && (!self.lazy || Mapper::from_current().translate_page(page).is_some())
+    }
+
     /// Merges the given `MappedPages` object `mp` into this `MappedPages` object (`self`).
     ///
     /// For example, if you have the following `MappedPages` objects:    
@@ -381,7 +731,7 @@ impl MappedPages {
     /// * `mp`, with a page range including two pages at 0x3000 and 0x4000
     /// Then this `MappedPages` object will be updated to cover three pages from `[0x2000:0x4000]` inclusive.
     /// 
-    /// In addition, the `MappedPages` objects must have the same flags and page table root frame
+    /// In addition, the `MappedPages` objects must have the same flags, page size, and page table root frame
     /// (i.e., they must have all been mapped using the same set of page tables).
     /// 
     /// If an error occurs, such as the `mappings` not being contiguous or having different flags, 
@@ -401,6 +751,19 @@ impl MappedPages {
                 self.flags, mp.flags);
             return Err(("failed to merge MappedPages that were mapped with different flags", mp));
         }
+        if mp.lazy || self.lazy {
+            error!("MappedPages::merge(): lazy mappings cannot be merged");
+            return Err(("failed to merge MappedPages because lazy mappings cannot be merged", mp));
+        }
+        if mp.copy_on_write || self.copy_on_write {
+            error!("MappedPages::merge(): copy-on-write mappings cannot be merged");
+            return Err(("failed to merge MappedPages because copy-on-write mappings cannot be merged", mp));
+        }
+        if mp.page_size != self.page_size {
+            error!("MappedPages::merge(): mappings had different page sizes: {:?} vs. {:?}",
+                self.page_size, mp.page_size);
+            return Err(("failed to merge MappedPages that were mapped with different page sizes", mp));
+        }
 
         // Attempt to merge the page ranges together, which will fail if they're not contiguous.
         // First, take ownership of the AllocatedPages inside of the `mp` argument.
@@ -430,11 +793,22 @@ impl MappedPages {
     /// 
     /// Returns an `Err` containing this `MappedPages` (`self`) if `at_page` is not within its bounds.
     /// 
+    /// If this `MappedPages` is mapped using huge pages, `at_page` need not be aligned to the huge page size.
+    /// A huge page that ends up shared by both halves stays mapped as a huge page
+    /// until either half is unmapped or remapped, at which point it is demoted into smaller pages.
+    /// 
+    /// Lazy and copy-on-write mappings cannot be split, so this returns an `Err` containing this `MappedPages` (`self`) for them.
+    /// 
     /// # Note
     /// No remapping actions or page reallocations will occur on either a failure or a success.
     /// 
     /// [`core::slice::split_at()`]: https://doc.rust-lang.org/core/primitive.slice.html#method.split_at
     pub fn split(mut self, at_page: Page) -> Result<(MappedPages, MappedPages), MappedPages> {
+        if self.lazy || self.copy_on_write {
+            error!("MappedPages::split(): lazy and copy-on-write mappings cannot be split");
+            return Err(self);
+        }
+
         // Take ownership of the `AllocatedPages` inside of the `MappedPages` so we can split it.
         let alloc_pages_owned = core::mem::replace(&mut self.pages, AllocatedPages::empty());
 
@@ -444,11 +818,17 @@ impl MappedPages {
                     page_table_p4: self.page_table_p4,
                     pages: first_ap,
                     flags: self.flags,
+                    page_size: self.page_size,
+                    lazy: self.lazy,
+                    copy_on_write: self.copy_on_write,
                 },
                 MappedPages {
                     page_table_p4: self.page_table_p4,
                     pages: second_ap,
                     flags: self.flags,
+                    page_size: self.page_size,
+                    lazy: self.lazy,
+                    copy_on_write: self.copy_on_write,
                 }
                 // When returning here, `self` will be dropped, but it's empty so it has no effect.
             )),
@@ -503,6 +883,63 @@ impl MappedPages {
         Ok(new_mapped_pages)
     }
 
+
+    /// Creates a copy of this `MappedPages` that shares its underlying frames in a copy-on-write manner,
+    /// such that the contents of each page are only copied upon the first write to that page
+    /// through either this `MappedPages` or the new one.
+    /// 
+    /// Until then, the shared frames are mapped as read-only into both mappings,
+    /// and are only deallocated once the last mapping that shares them is unmapped.
+    /// The returned `MappedPages` is mapped with the given `new_flags`, if provided,
+    /// otherwise it is mapped with the same flags as this `MappedPages`.
+    /// 
+    /// Unlike [`MappedPages::deep_copy()`], this is cheap regardless of this mapping's size.
+    /// Both this `MappedPages` and the returned copy can no longer be split or merged.
+    /// 
+    /// # Errors
+    /// Returns an error if this `MappedPages` is a lazy mapping, was mapped with huge pages,
+    /// or maps frames that it does not exclusively own (other than frames it shares copy-on-write).
+    /// In that case, [`MappedPages::deep_copy()`] can be used instead.
+    pub fn copy_on_write(&mut self, new_flags: Option<EntryFlags>, active_table_mapper: &mut Mapper) -> Result<MappedPages, &'static str> {
+        if self.size_in_pages() == 0 {
+            return Err("MappedPages::copy_on_write(): cannot share an empty mapping");
+        }
+        if self.lazy || self.page_size.is_huge() {
+            return Err("MappedPages::copy_on_write(): lazy mappings and huge page mappings cannot be shared");
+        }
+        if active_table_mapper.target_p4 != self.page_table_p4 {
+            return Err("MappedPages::copy_on_write(): current P4 must equal the original P4 of the shared MappedPages");
+        }
+
+        let new_pages = page_allocator::allocate_pages(self.size_in_pages()).ok_or("MappedPages::copy_on_write(): couldn't allocate pages")?;
+        let new_flags = new_flags.unwrap_or(self.flags);
+        let mut top_level_flags = new_flags.clone() | EntryFlags::PRESENT;
+        top_level_flags.set(EntryFlags::NO_EXECUTE, false);
+        top_level_flags.set(EntryFlags::EXCLUSIVE, false);
+        let actual_flags = new_flags | EntryFlags::EXCLUSIVE | EntryFlags::PRESENT;
+
+        cow::reserve_copy_window(active_table_mapper)?;
+        active_table_mapper.create_p1_tables(&new_pages, top_level_flags);
+        cow::share_pages(active_table_mapper, &self.pages, self.flags, &new_pages, actual_flags)?;
+        self.copy_on_write = true;
+
+        #[cfg(not(bm_map))]
+        {
+            if let Some(func) = BROADCAST_TLB_SHOOTDOWN_FUNC.get() {
+                func(self.pages.deref().clone());
+            }
+        }
+
+        Ok(MappedPages {
+            page_table_p4: self.page_table_p4.clone(),
+            pages: new_pages,
+            flags: actual_flags,
+            page_size: PageSize::Normal4K,
+            lazy: false,
+            copy_on_write: true,
+        })
+    }
+
     
     /// Change the permissions (`new_flags`) of this `MappedPages`'s page table entries.
     ///
@@ -520,16 +957,34 @@ impl MappedPages {
             return Ok(());
         }
 
-        for page in self.pages.clone() {
-            let p1 = active_table_mapper.p4_mut()
-                .next_table_mut(page.p4_index())
-                .and_then(|p3| p3.next_table_mut(page.p3_index()))
-                .and_then(|p2| p2.next_table_mut(page.p2_index()))
-                .ok_or("mapping code does not support huge pages")?;
+        if self.lazy {
+            lazy::set_lazy_region_flags(*self.pages.start(), new_flags | EntryFlags::PRESENT);
+        }
+        if self.copy_on_write {
+            cow::set_cow_region_flags(*self.pages.start(), new_flags | EntryFlags::PRESENT);
+        }
+
+        let mut page = *self.pages.start();
+        loop {
+            let (pte, page_size) = active_table_mapper.leaf_entry_within_mut(page, &self.pages)
+                .ok_or("remap(): page was not mapped")?;
             
-            p1[page.p1_index()].set_flags(new_flags | EntryFlags::PRESENT);
+            if self.lazy && pte.is_unused() {
+                // This page of a lazy mapping hasn't been accessed yet, so it will be mapped with the new flags.
+            } else if self.copy_on_write && !pte.flags().is_exclusive() {
+                // This page still maps a shared frame, so it must remain read-only until it's written to.
+                pte.set_flags(cow::shared_flags(new_flags) | EntryFlags::PRESENT);
+            } else if page_size.is_huge() {
+                pte.set_flags(new_flags.into_huge() | EntryFlags::PRESENT);
+            } else {
+                pte.set_flags(new_flags | EntryFlags::PRESENT);
+            }
 
             tlb_flush_virt_addr(page.start_address());
+            if page.number() + page_size.num_4k_pages() > self.pages.end().number() {
+                break;
+            }
+            page += page_size.num_4k_pages();
         }
         
         if let Some(func) = BROADCAST_TLB_SHOOTDOWN_FUNC.get() {
@@ -593,30 +1048,56 @@ impl MappedPages {
             );
         }   
 
+        // Ensure that the pages of a lazy mapping can no longer be backed by frames while being unmapped.
+        if self.lazy {
+            lazy::unregister_lazy_region(*self.pages.start());
+        }
+        // Ensure that the pages of a copy-on-write mapping can no longer be unshared while being unmapped.
+        if self.copy_on_write {
+            cow::unregister_cow_region(*self.pages.start());
+        }
+
         let mut first_frame_range: Option<AllocatedFrames> = None; // this is what we'll return
         let mut current_frame_range: Option<AllocatedFrames> = None;
 
-        for page in self.pages.clone() {            
-            let p1 = active_table_mapper.p4_mut()
-                .next_table_mut(page.p4_index())
-                .and_then(|p3| p3.next_table_mut(page.p3_index()))
-                .and_then(|p2| p2.next_table_mut(page.p2_index()))
-                .ok_or("mapping code does not support huge pages")?;
-            let pte = &mut p1[page.p1_index()];
+        let mut page = *self.pages.start();
+        loop {
+            let (pte, page_size) = active_table_mapper.leaf_entry_within_mut(page, &self.pages)
+                .ok_or("unmap(): page not mapped")?;
             if pte.is_unused() {
-                return Err("unmap(): page not mapped");
+                if !self.lazy {
+                    return Err("unmap(): page not mapped");
+                }
+                // This page of a lazy mapping was never accessed, so it was never backed by a frame.
+                if page.number() + page_size.num_4k_pages() > self.pages.end().number() {
+                    break;
+                }
+                page += page_size.num_4k_pages();
+                continue;
             }
 
-            let unmapped_frames = pte.set_unmapped();
+            let unmapped_frames = pte.set_unmapped_huge(page_size);
             tlb_flush_virt_addr(page.start_address());
+            let is_last_page = page.number() + page_size.num_4k_pages() > self.pages.end().number();
+            page += page_size.num_4k_pages();
 
             // Here, create (or extend) a contiguous ranges of frames here based on the `unmapped_frames`
-            // freed from the newly-unmapped P1 PTE entry above.
-            match unmapped_frames {
-                UnmapResult::Exclusive(newly_unmapped_frames) => {
+            // freed from the newly-unmapped leaf PTE entry above.
+            let owned_frames = match unmapped_frames {
+                UnmapResult::Exclusive(newly_unmapped_frames) => Some(newly_unmapped_frames.deref().clone()),
+                // The last mapping of a shared copy-on-write frame owns that frame.
+                UnmapResult::NonExclusive(frames) if self.copy_on_write && cow::release_shared_frame(*frames.start()) => Some(frames),
+                UnmapResult::NonExclusive(_frames) => {
+                    // trace!("Note: FYI: page {:X?} -> frames {:X?} was just unmapped but not mapped as EXCLUSIVE.", page, _frames);
+                    None
+                }
+            };
+
+            match owned_frames {
+                Some(newly_unmapped_frames) => {
                     let newly_unmapped_frames = INTO_ALLOCATED_FRAMES_FUNC.get()
                         .ok_or("BUG: Mapper::unmap(): the `INTO_ALLOCATED_FRAMES_FUNC` callback was not initialized")
-                        .map(|into_func| into_func(newly_unmapped_frames.deref().clone()))?;
+                        .map(|into_func| into_func(newly_unmapped_frames))?;
 
                     if let Some(mut curr_frames) = current_frame_range.take() {
                         match curr_frames.merge(newly_unmapped_frames) {
@@ -650,9 +1131,11 @@ impl MappedPages {
                         current_frame_range = Some(newly_unmapped_frames);
                     }
                 }
-                UnmapResult::NonExclusive(_frames) => {
-                    // trace!("Note: FYI: page {:X?} -> frames {:X?} was just unmapped but not mapped as EXCLUSIVE.", page, _frames);
-                }
+                None => { }
+            }
+
+            if is_last_page {
+                break;
             }
         }
     
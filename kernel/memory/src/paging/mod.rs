@@ -9,6 +9,8 @@
 
 mod temporary_page;
 mod mapper;
+mod lazy;
+mod cow;
 #[cfg(not(mapper_spillful))]
 mod table;
 #[cfg(mapper_spillful)]
@@ -22,6 +24,11 @@ pub use self::{
         Mapper, MappedPages, BorrowedMappedPages, BorrowedSliceMappedPages,
         Mutability, Mutable, Immutable,
     },
+    lazy::{
+        PageFault, PageFaultResolver, LazyMappingStats,
+        lazy_mapping_stats, register_page_fault_resolver, resolve_page_fault,
+    },
+    cow::{CopyOnWriteStats, copy_on_write_stats},
 };
 
 use core::{
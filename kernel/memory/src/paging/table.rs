@@ -87,7 +87,7 @@ impl<L: HierarchicalLevel> Table<L> {
         flags: EntryFlags,
     ) -> &mut Table<L::NextLevel> {
         if self.next_table(index).is_none() {
-            assert!(!self[index].flags().is_huge(), "mapping code does not support huge pages");
+            assert!(!self[index].flags().is_huge(), "next_table_create(): cannot create a page table within an existing huge page mapping");
             let af = frame_allocator::allocate_frames(1).expect("next_table_create(): no frames available");
             self[index].set_entry(af.as_allocated_frame(), flags.into_writable() | EntryFlags::PRESENT); // must be PRESENT | WRITABLE for x86_64
             self.next_table_mut(index).unwrap().zero();
@@ -0,0 +1,155 @@
+//! Accounting of the memory allocated by each task.
+//!
+//! The frame allocator, page allocator, and heap charge every allocation
+//! to the system-wide totals and to the [`MemoryCounters`] of the current task,
+//! and credit every deallocation back to the current task's counters.
+//! Thus, a task's counters reflect the *net* memory it has allocated,
+//! which is negative if it has freed more memory than it allocated,
+//! e.g., when it drops memory that was allocated by another task.
+//!
+//! Only general-purpose frames are accounted for, not reserved frames (e.g., MMIO regions).
+//! Memory allocated before tasking is initialized only counts towards the system-wide totals.
+//!
+//! Because the allocators are below the `task` crate in the dependency graph,
+//! they find the current task's counters via a callback
+//! that the `task` crate registers using [`set_current_counters_func()`].
+
+#![no_std]
+
+use core::sync::atomic::{AtomicIsize, Ordering};
+use spin::Once;
+
+/// A snapshot of the memory allocated by a task, or by the whole system.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// The net number of physical frames allocated.
+    pub frames: isize,
+    /// The net number of virtual pages allocated.
+    pub pages: isize,
+    /// The net number of heap bytes allocated.
+    pub heap_bytes: isize,
+    /// The net number of heap allocations, i.e., allocations minus deallocations.
+    pub heap_allocations: isize,
+}
+
+impl core::ops::Add for MemoryUsage {
+    type Output = MemoryUsage;
+    fn add(self, other: MemoryUsage) -> MemoryUsage {
+        MemoryUsage {
+            frames: self.frames + other.frames,
+            pages: self.pages + other.pages,
+            heap_bytes: self.heap_bytes + other.heap_bytes,
+            heap_allocations: self.heap_allocations + other.heap_allocations,
+        }
+    }
+}
+
+impl core::ops::AddAssign for MemoryUsage {
+    fn add_assign(&mut self, other: MemoryUsage) {
+        *self = *self + other;
+    }
+}
+
+/// The counters of the memory allocated by a single task, or by the whole system.
+#[derive(Debug, Default)]
+pub struct MemoryCounters {
+    frames: AtomicIsize,
+    pages: AtomicIsize,
+    heap_bytes: AtomicIsize,
+    heap_allocations: AtomicIsize,
+}
+
+impl MemoryCounters {
+    /// Returns a new set of counters that are all zero.
+    pub const fn new() -> MemoryCounters {
+        MemoryCounters {
+            frames: AtomicIsize::new(0),
+            pages: AtomicIsize::new(0),
+            heap_bytes: AtomicIsize::new(0),
+            heap_allocations: AtomicIsize::new(0),
+        }
+    }
+
+    /// Returns a snapshot of these counters.
+    pub fn usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            frames: self.frames.load(Ordering::Relaxed),
+            pages: self.pages.load(Ordering::Relaxed),
+            heap_bytes: self.heap_bytes.load(Ordering::Relaxed),
+            heap_allocations: self.heap_allocations.load(Ordering::Relaxed),
+        }
+    }
+
+    fn add_frames(&self, frames: isize) {
+        self.frames.fetch_add(frames, Ordering::Relaxed);
+    }
+
+    fn add_pages(&self, pages: isize) {
+        self.pages.fetch_add(pages, Ordering::Relaxed);
+    }
+
+    fn add_heap(&self, bytes: isize, allocations: isize) {
+        self.heap_bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.heap_allocations.fetch_add(allocations, Ordering::Relaxed);
+    }
+}
+
+/// The memory allocated by the whole system, including before tasking was initialized.
+static TOTAL: MemoryCounters = MemoryCounters::new();
+
+/// Returns a snapshot of the memory allocated by the whole system.
+pub fn total_usage() -> MemoryUsage {
+    TOTAL.usage()
+}
+
+
+/// The signature of the callback that invokes the given closure
+/// with the current task's memory counters, if there is a current task.
+pub type CurrentCountersFunc = fn(&mut dyn FnMut(&MemoryCounters));
+
+static CURRENT_COUNTERS_FUNC: Once<CurrentCountersFunc> = Once::new();
+
+/// Registers the callback used by the allocators to find the current task's memory counters.
+///
+/// This is invoked by the `task` crate when tasking is initialized.
+pub fn set_current_counters_func(func: CurrentCountersFunc) {
+    CURRENT_COUNTERS_FUNC.call_once(|| func);
+}
+
+/// Invokes the given closure with the system-wide counters and then with the current task's counters.
+fn with_counters(f: &mut dyn FnMut(&MemoryCounters)) {
+    f(&TOTAL);
+    if let Some(func) = CURRENT_COUNTERS_FUNC.get() {
+        func(f);
+    }
+}
+
+/// Charges `frames` newly-allocated physical frames to the current task.
+pub fn charge_frames(frames: usize) {
+    with_counters(&mut |c| c.add_frames(frames as isize));
+}
+
+/// Credits `frames` deallocated physical frames back to the current task.
+pub fn uncharge_frames(frames: usize) {
+    with_counters(&mut |c| c.add_frames(-(frames as isize)));
+}
+
+/// Charges `pages` newly-allocated virtual pages to the current task.
+pub fn charge_pages(pages: usize) {
+    with_counters(&mut |c| c.add_pages(pages as isize));
+}
+
+/// Credits `pages` deallocated virtual pages back to the current task.
+pub fn uncharge_pages(pages: usize) {
+    with_counters(&mut |c| c.add_pages(-(pages as isize)));
+}
+
+/// Charges a new heap allocation of `bytes` to the current task.
+pub fn charge_heap(bytes: usize) {
+    with_counters(&mut |c| c.add_heap(bytes as isize, 1));
+}
+
+/// Credits a heap deallocation of `bytes` back to the current task.
+pub fn uncharge_heap(bytes: usize) {
+    with_counters(&mut |c| c.add_heap(-(bytes as isize), -1));
+}
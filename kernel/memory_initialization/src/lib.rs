@@ -10,7 +10,7 @@ extern crate no_drop;
 extern crate multiboot2;
 extern crate bootloader_modules;
 
-use memory::{MmiRef, MappedPages, VirtualAddress, PhysicalAddress};
+use memory::{MmiRef, MappedPages, PageSize, VirtualAddress, PhysicalAddress};
 use kernel_config::memory::{KERNEL_HEAP_START, KERNEL_HEAP_INITIAL_SIZE};
 use multiboot2::BootInformation;
 use alloc::{
@@ -82,7 +82,21 @@ pub fn init_memory_management(
     let heap_mapped_pages = {
         let pages = memory::allocate_pages_by_bytes_at(VirtualAddress::new_canonical(heap_start), heap_initial_size)?;
         debug!("Initial heap starts at: {:#X}, size: {:#X}, pages: {:?}", heap_start, heap_initial_size, pages);
-        let heap_mp = page_table.map_allocated_pages(pages, HEAP_FLAGS).map_err(|e| {
+        // Map the heap using 2MiB huge pages to reduce TLB pressure, which requires physically-contiguous frames.
+        // If the heap isn't 2MiB-aligned or that much contiguous memory isn't available,
+        // fall back to mapping the heap with normal pages.
+        let huge_frames = if PageSize::Huge2M.is_aligned(pages.start().number()) && PageSize::Huge2M.is_aligned(pages.size_in_pages()) {
+            memory::allocate_huge_frames(PageSize::Huge2M, pages.size_in_pages() / PageSize::Huge2M.num_4k_pages())
+        } else {
+            None
+        };
+        let heap_mp = match huge_frames {
+            Some(frames) => page_table.map_allocated_huge_pages_to(pages, frames, HEAP_FLAGS, PageSize::Huge2M),
+            None => {
+                warn!("Couldn't map the initial heap using 2MiB huge pages, mapping it with normal pages instead.");
+                page_table.map_allocated_pages(pages, HEAP_FLAGS)
+            }
+        }.map_err(|e| {
             error!("Failed to map kernel heap memory pages, {} bytes starting at virtual address {:#X}. Error: {:?}",
                 KERNEL_HEAP_INITIAL_SIZE, KERNEL_HEAP_START, e
             );
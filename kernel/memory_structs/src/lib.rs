@@ -294,6 +294,54 @@ impl Page {
 }
 
 
+/// The size of a single page mapping, i.e., the amount of memory covered by one leaf page table entry.
+///
+/// Huge pages are mapped by a P2 entry (2 MiB) or a P3 entry (1 GiB) that has the
+/// [`EntryFlags::HUGE_PAGE`] bit set, which reduces TLB pressure for large mappings.
+/// Both the virtual pages and the physical frames of a huge page mapping
+/// must be aligned to the page size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PageSize {
+    /// A normal 4 KiB page, mapped by a P1 entry.
+    Normal4K,
+    /// A 2 MiB huge page, mapped by a P2 entry.
+    Huge2M,
+    /// A 1 GiB huge page, mapped by a P3 entry.
+    Huge1G,
+}
+impl PageSize {
+    /// Returns the size of one page of this size in bytes.
+    pub const fn size_in_bytes(&self) -> usize {
+        self.num_4k_pages() * PAGE_SIZE
+    }
+
+    /// Returns the number of normal 4 KiB pages (or frames) covered by one page of this size,
+    /// which is also the required alignment (in pages) of a mapping of this size.
+    pub const fn num_4k_pages(&self) -> usize {
+        match self {
+            PageSize::Normal4K => 1,
+            PageSize::Huge2M   => 512,
+            PageSize::Huge1G   => 512 * 512,
+        }
+    }
+
+    /// Returns `true` if this is a huge page size.
+    pub const fn is_huge(&self) -> bool {
+        !matches!(self, PageSize::Normal4K)
+    }
+
+    /// Returns `true` if the given page or frame `number` is aligned to this page size.
+    pub const fn is_aligned(&self, number: usize) -> bool {
+        number % self.num_4k_pages() == 0
+    }
+}
+impl Default for PageSize {
+    fn default() -> Self {
+        PageSize::Normal4K
+    }
+}
+
+
 
 /// A macro for defining `PageRange` and `FrameRange` structs
 /// and implementing their common traits, which are generally identical.
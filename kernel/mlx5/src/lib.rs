@@ -6,6 +6,10 @@
 //! * setting up a single send and receive queue
 //! * functions to send packets
 //! 
+//! Received packets are not yet collected from the receive queue.
+//! Once they are, the receive path must invoke `network_interface_card::notify_frame_received()`
+//! so that tasks waiting for incoming frames are woken up.
+//! 
 //! All information is taken from the Mellanox Adapters Programmer’s Reference Manual (PRM) Rev 0.54,
 //! unless otherwise specified. 
 
@@ -514,7 +518,7 @@ impl ConnectX5Nic {
         // }
 
         // initialize the rx buffer pool
-        init_rx_buf_pool(num_rx_descs, mtu, &RX_BUFFER_POOL)?;
+        init_rx_buf_pool(num_rx_descs, mtu, &RX_BUFFER_POOL, None)?;
 
         // Create the RQ
         let completed_cmd = cmdq.create_and_execute_command(
@@ -0,0 +1,191 @@
+//! Checks whether crates are ABI-compatible with the crates that they replace or link against,
+//! using the [`CrateAbi`] metadata that the build embeds into each crate object file.
+//!
+//! ABI metadata describes each crate's public symbols and the type layouts of their
+//! function signatures and static variables; see `tools/emit_crate_abi` for how it is generated.
+//! Crates without ABI metadata (e.g., the `nano_core`, or crates built out of tree) are never
+//! considered incompatible, as there is nothing to compare them against.
+//!
+//! Incompatibilities are handled according to the system-wide [`AbiCheckPolicy`].
+
+use crate::*;
+use core::sync::atomic::{AtomicU8, Ordering};
+pub use crate_metadata_serde::{CrateAbi, SymbolAbi, SymbolKind, ABI_SECTION_NAME};
+
+/// What to do when an ABI incompatibility is found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AbiCheckPolicy {
+    /// Don't check ABI compatibility at all.
+    Ignore = 0,
+    /// Log a warning and continue.
+    Warn = 1,
+    /// Return an error, aborting the load or swap.
+    Reject = 2,
+}
+
+static ABI_CHECK_POLICY: AtomicU8 = AtomicU8::new(AbiCheckPolicy::Reject as u8);
+
+/// Returns the current system-wide [`AbiCheckPolicy`], which is `Reject` by default.
+pub fn abi_check_policy() -> AbiCheckPolicy {
+    match ABI_CHECK_POLICY.load(Ordering::Relaxed) {
+        0 => AbiCheckPolicy::Ignore,
+        1 => AbiCheckPolicy::Warn,
+        _ => AbiCheckPolicy::Reject,
+    }
+}
+
+/// Sets the system-wide [`AbiCheckPolicy`].
+pub fn set_abi_check_policy(policy: AbiCheckPolicy) {
+    ABI_CHECK_POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+
+/// Parses the ABI metadata embedded in the given crate object file, if any.
+pub fn parse_crate_abi(elf_file: &ElfFile) -> Result<Option<CrateAbi>, &'static str> {
+    let Some(abi_sec) = elf_file.find_section_by_name(ABI_SECTION_NAME) else {
+        return Ok(None);
+    };
+    let bytes = abi_sec.raw_data(elf_file);
+    let (crate_abi, _): (CrateAbi, _) = bincode::serde::decode_from_slice(bytes, bincode::config::standard())
+        .map_err(|_e| {
+            error!("parse_crate_abi(): error deserializing ABI metadata: {_e}");
+            "couldn't deserialize crate ABI metadata"
+        })?;
+    Ok(Some(crate_abi))
+}
+
+/// Reads the ABI metadata embedded in the given crate object file, if any.
+pub fn read_crate_abi(crate_object_file: &FileRef) -> Result<Option<CrateAbi>, &'static str> {
+    let file = crate_object_file.lock();
+    let mapped_pages = file.as_mapping()?;
+    let bytes: &[u8] = mapped_pages.as_slice(0, file.len())?;
+    let elf_file = ElfFile::new(bytes)?;
+    parse_crate_abi(&elf_file)
+}
+
+/// Same as [`read_crate_abi()`], but treats a failure to read ABI metadata the same as no metadata.
+fn read_crate_abi_or_none(crate_object_file: &FileRef) -> Option<CrateAbi> {
+    read_crate_abi(crate_object_file).unwrap_or_else(|_e| {
+        #[cfg(not(loscd_eval))]
+        debug!("Couldn't read ABI metadata from {:?}: {}", crate_object_file.try_lock().map(|f| f.get_name()), _e);
+        None
+    })
+}
+
+
+/// A public symbol of an old crate that is used by other crates,
+/// but that is either missing from or incompatible with the new crate replacing it.
+#[derive(Clone, Debug)]
+pub struct AbiIncompatibility {
+    /// The name of the old crate's symbol, without the hash suffix.
+    pub symbol: String,
+    /// Why the new crate's symbol is incompatible.
+    pub reason: &'static str,
+    /// The names of the crates that depend on the old crate's symbol.
+    pub dependent_crates: BTreeSet<String>,
+}
+
+/// Checks whether the new crate in the given object file can replace the given old crate
+/// without breaking the other crates that currently depend on the old crate.
+///
+/// Returns an [`AbiIncompatibility`] for each of the old crate's public symbols that is used by another crate
+/// but is missing from or incompatible with the new crate, which is empty if the crates are compatible
+/// or if either of them lacks ABI metadata.
+///
+/// This acquires the lock on the old crate and the crates that depend on it,
+/// so none of them can be locked by the caller.
+pub fn check_crate_replacement(
+    old_crate_ref: &StrongCrateRef,
+    new_crate_object_file: &FileRef,
+) -> Vec<AbiIncompatibility> {
+    let (old_object_file, old_crate_name_without_hash, used_symbols) = {
+        let old_crate = old_crate_ref.lock_as_ref();
+        // The old crate's public symbols that other crates use, along with the crates that use them.
+        let used_symbols: Vec<(String, Vec<WeakCrateRef>)> = old_crate.global_sections_iter()
+            .filter_map(|sec| {
+                let dependents: Vec<WeakCrateRef> = sec.inner.read().sections_dependent_on_me.iter()
+                    .filter_map(|weak_dep| weak_dep.section.upgrade())
+                    .map(|dep_sec| dep_sec.parent_crate.clone())
+                    .collect();
+                (!dependents.is_empty()).then(|| (String::from(sec.name_without_hash()), dependents))
+            })
+            .collect();
+        (old_crate.object_file.clone(), String::from(old_crate.crate_name_without_hash()), used_symbols)
+    };
+    if used_symbols.is_empty() {
+        return Vec::new();
+    }
+
+    let (Some(old_abi), Some(new_abi)) = (read_crate_abi_or_none(&old_object_file), read_crate_abi_or_none(new_crate_object_file)) else {
+        return Vec::new();
+    };
+    let new_crate_name = crate_name_from_path(&Path::new(new_crate_object_file.lock().get_name())).to_string();
+    let new_crate_name_without_hash = new_crate_name.split(CRATE_HASH_DELIMITER).next().unwrap_or(&new_crate_name);
+
+    let mut incompatibilities = Vec::new();
+    for (symbol, dependents) in used_symbols {
+        let Some(old_sym) = old_abi.exports.get(&symbol) else { continue };
+        // The new crate may have a different name, in which case its symbols will too.
+        let new_symbol = replace_containing_crate_name(&symbol, &old_crate_name_without_hash, new_crate_name_without_hash)
+            .unwrap_or_else(|| symbol.clone());
+        let reason = match new_abi.exports.get(&new_symbol) {
+            None => "the new crate doesn't define this symbol",
+            Some(new_sym) if !new_sym.is_compatible_with(old_sym) => "the new crate's symbol has a different type or size",
+            Some(_) => continue,
+        };
+        let dependent_crates = dependents.into_iter()
+            .filter_map(|weak_crate| weak_crate.upgrade())
+            .filter(|dep_crate| !dep_crate.ptr_eq(old_crate_ref))
+            .map(|dep_crate| dep_crate.lock_as_ref().crate_name.to_string())
+            .collect();
+        incompatibilities.push(AbiIncompatibility { symbol, reason, dependent_crates });
+    }
+    incompatibilities
+}
+
+
+/// Checks that a symbol found via fuzzy matching is compatible with the symbol
+/// that the crate being loaded was originally built against.
+///
+/// * `importer_abi`: the ABI metadata of the crate being loaded.
+/// * `requested_symbol`: the full name of the symbol that the crate being loaded requested.
+/// * `found_sec`: the section that was found via fuzzy matching.
+/// * `exporter_abis`: a cache of the ABI metadata of the crates that contain fuzzy-matched sections.
+pub(crate) fn check_fuzzy_match(
+    importer_abi: &CrateAbi,
+    requested_symbol: &str,
+    found_sec: &LoadedSection,
+    exporter_abis: &mut BTreeMap<StrRef, Option<CrateAbi>>,
+) -> Result<(), &'static str> {
+    let policy = abi_check_policy();
+    if policy == AbiCheckPolicy::Ignore {
+        return Ok(());
+    }
+    let Some(expected) = importer_abi.imports.get(LoadedSection::section_name_without_hash(requested_symbol)) else {
+        return Ok(());
+    };
+    let Some(exporter_ref) = found_sec.parent_crate.upgrade() else {
+        return Ok(());
+    };
+    let (exporter_name, exporter_file) = {
+        let exporter = exporter_ref.lock_as_ref();
+        (exporter.crate_name.clone(), exporter.object_file.clone())
+    };
+    let exporter_abi = exporter_abis.entry(exporter_name)
+        .or_insert_with(|| read_crate_abi_or_none(&exporter_file));
+    let Some(found) = exporter_abi.as_ref().and_then(|abi| abi.exports.get(found_sec.name_without_hash())) else {
+        return Ok(());
+    };
+    if found.is_compatible_with(expected) {
+        return Ok(());
+    }
+
+    warn!("ABI mismatch: symbol {:?} was fuzzy-matched to {:?}, which has ABI {:?} instead of the expected {:?}",
+        requested_symbol, found_sec.name, found, expected
+    );
+    match policy {
+        AbiCheckPolicy::Reject => Err("fuzzy-matched symbol is ABI-incompatible with the symbol the crate was built against"),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,385 @@
+//! Exports the dependency graph of the crates or sections in a [`CrateNamespace`]
+//! in Graphviz DOT or JSON format.
+//!
+//! Each section-level edge points from a dependent section to the section it depends on.
+//! It records how many relocations the dependent section holds as `StrongDependency`s
+//! and how many the depended-on section holds as `WeakDependent`s.
+//! These counts should always be equal; an edge on which they differ indicates
+//! inconsistent dependency metadata, e.g., after a faulty crate swap.
+//! Crate-level edges combine the section-level edges between sections of two different crates.
+//!
+//! Sections and crates outside of the exported namespaces that are directly connected
+//! to the exported ones are included as "external" nodes.
+
+use core::{fmt::Write, str::FromStr};
+use crate::*;
+
+/// Whether a [`DependencyGraph`] has a node for each crate or for each section.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GraphKind {
+    Crates,
+    Sections,
+}
+
+impl FromStr for GraphKind {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "crates" => Ok(GraphKind::Crates),
+            "sections" => Ok(GraphKind::Sections),
+            _ => Err("graph kind must be \"crates\" or \"sections\""),
+        }
+    }
+}
+
+impl fmt::Display for GraphKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            GraphKind::Crates => "crates",
+            GraphKind::Sections => "sections",
+        })
+    }
+}
+
+/// The output format of an exported [`DependencyGraph`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GraphFormat {
+    /// The Graphviz DOT language, in which each crate's sections are grouped into a cluster.
+    Dot,
+    /// A JSON object with `kind`, `namespaces`, `nodes`, and `edges` fields,
+    /// which can be compared using the `tools/diff_dependency_graphs` host tool.
+    Json,
+}
+
+impl FromStr for GraphFormat {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dot" => Ok(GraphFormat::Dot),
+            "json" => Ok(GraphFormat::Json),
+            _ => Err("graph format must be \"dot\" or \"json\""),
+        }
+    }
+}
+
+/// A crate or section in a [`DependencyGraph`].
+#[derive(Clone, Debug)]
+pub struct GraphNode {
+    /// The unique ID of this node, which is the crate name for a crate
+    /// or `<crate name>/<section name>` for a section.
+    pub id: String,
+    /// The name of the crate, or of the section's parent crate.
+    pub crate_name: String,
+    /// The name of the section, or `None` for a crate.
+    pub section_name: Option<String>,
+    /// The name of the namespace that contains the crate,
+    /// or `None` if it is outside of the exported namespaces.
+    pub namespace: Option<String>,
+}
+
+/// A dependency between two nodes of a [`DependencyGraph`].
+#[derive(Clone, Debug)]
+pub struct GraphEdge {
+    /// The index of the dependent node.
+    pub from: usize,
+    /// The index of the node that is depended on.
+    pub to: usize,
+    /// The number of relocations recorded as `StrongDependency`s in the dependent node.
+    pub strong: usize,
+    /// The number of relocations recorded as `WeakDependent`s in the depended-on node.
+    pub weak: usize,
+}
+
+impl GraphEdge {
+    /// Returns `true` if the dependent and depended-on nodes agree on the number of relocations.
+    pub fn is_consistent(&self) -> bool {
+        self.strong == self.weak
+    }
+}
+
+/// A snapshot of the dependencies between the crates or sections in one or more namespaces.
+///
+/// Nodes are sorted by ID and edges are sorted by their nodes, such that exports are easy to compare.
+#[derive(Clone, Debug)]
+pub struct DependencyGraph {
+    pub kind: GraphKind,
+    /// The names of the exported namespaces, starting with the namespace the graph was obtained from.
+    pub namespaces: Vec<String>,
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+}
+
+/// A section that was found while building a [`DependencyGraph`].
+struct FoundSection {
+    section: StrongSectionRef,
+    crate_name: String,
+    /// The namespace that contains this section's crate, or `None` if it's external.
+    namespace: Option<String>,
+}
+
+impl FoundSection {
+    fn node_id(&self, kind: GraphKind) -> String {
+        match kind {
+            GraphKind::Crates => self.crate_name.clone(),
+            GraphKind::Sections => format!("{}/{}", self.crate_name, self.section.name),
+        }
+    }
+}
+
+/// Returns a key that uniquely identifies the given section.
+fn section_key(section: &StrongSectionRef) -> usize {
+    Arc::as_ptr(section) as usize
+}
+
+impl CrateNamespace {
+    /// Returns the dependency graph of the crates or sections in this namespace,
+    /// including its recursive namespaces if `recursive` is `true`.
+    pub fn dependency_graph(&self, kind: GraphKind, recursive: bool) -> DependencyGraph {
+        let mut namespaces: Vec<&CrateNamespace> = vec![self];
+        if recursive {
+            let mut ns = self;
+            while let Some(recursive_ns) = ns.recursive_namespace() {
+                namespaces.push(recursive_ns);
+                ns = recursive_ns;
+            }
+        }
+
+        // First, find every section in the exported namespaces.
+        let mut sections: BTreeMap<usize, FoundSection> = BTreeMap::new();
+        for ns in &namespaces {
+            ns.for_each_crate(false, |crate_name, crate_ref| {
+                let krate = crate_ref.lock_as_ref();
+                for sec in krate.sections.values() {
+                    sections.insert(section_key(sec), FoundSection {
+                        section: Arc::clone(sec),
+                        crate_name: crate_name.to_string(),
+                        namespace: Some(ns.name().to_string()),
+                    });
+                }
+                true
+            });
+        }
+
+        // Second, find the external sections that the exported sections depend on or are depended on by.
+        let mut external_sections: Vec<StrongSectionRef> = Vec::new();
+        for found in sections.values() {
+            let inner = found.section.inner.read();
+            external_sections.extend(inner.sections_i_depend_on.iter().map(|dep| Arc::clone(&dep.section)));
+            external_sections.extend(inner.sections_dependent_on_me.iter().filter_map(|dep| dep.section.upgrade()));
+        }
+        for sec in external_sections {
+            sections.entry(section_key(&sec)).or_insert_with(|| FoundSection {
+                crate_name: sec.parent_crate.upgrade()
+                    .map(|c| c.lock_as_ref().crate_name.to_string())
+                    .unwrap_or_else(|| String::from("<unknown>")),
+                section: sec,
+                namespace: None,
+            });
+        }
+
+        // Third, create a node for each crate or section, sorted by ID.
+        let mut nodes_by_id: BTreeMap<String, GraphNode> = BTreeMap::new();
+        for found in sections.values() {
+            nodes_by_id.entry(found.node_id(kind))
+                // An exported crate may also have been found as an external crate through another section.
+                .and_modify(|node| if node.namespace.is_none() {
+                    node.namespace = found.namespace.clone();
+                })
+                .or_insert_with_key(|id| GraphNode {
+                    id: id.clone(),
+                    crate_name: found.crate_name.clone(),
+                    section_name: match kind {
+                        GraphKind::Crates => None,
+                        GraphKind::Sections => Some(found.section.name.to_string()),
+                    },
+                    namespace: found.namespace.clone(),
+                });
+        }
+        let node_indices: BTreeMap<&str, usize> = nodes_by_id.keys()
+            .enumerate()
+            .map(|(index, id)| (id.as_str(), index))
+            .collect();
+        let node_of_section: BTreeMap<usize, usize> = sections.iter()
+            .map(|(key, found)| (*key, node_indices[found.node_id(kind).as_str()]))
+            .collect();
+        drop(node_indices);
+        let nodes: Vec<GraphNode> = nodes_by_id.into_values().collect();
+
+        // Finally, count the relocations of every edge that has at least one exported endpoint,
+        // from both the dependent section's and the depended-on section's point of view.
+        let mut edges: BTreeMap<(usize, usize), GraphEdge> = BTreeMap::new();
+        let mut add_relocation = |dependent: &StrongSectionRef, depended_on: &StrongSectionRef, is_strong: bool| {
+            let (Some(dependent_found), Some(depended_on_found)) = (sections.get(&section_key(dependent)), sections.get(&section_key(depended_on))) else {
+                return;
+            };
+            if dependent_found.namespace.is_none() && depended_on_found.namespace.is_none() {
+                return;
+            }
+            let from = node_of_section[&section_key(dependent)];
+            let to = node_of_section[&section_key(depended_on)];
+            if kind == GraphKind::Crates && from == to {
+                return;
+            }
+            let edge = edges.entry((from, to)).or_insert(GraphEdge { from, to, strong: 0, weak: 0 });
+            if is_strong {
+                edge.strong += 1;
+            } else {
+                edge.weak += 1;
+            }
+        };
+        for found in sections.values() {
+            let inner = found.section.inner.read();
+            for strong_dep in &inner.sections_i_depend_on {
+                add_relocation(&found.section, &strong_dep.section, true);
+            }
+            for weak_dep in &inner.sections_dependent_on_me {
+                if let Some(dependent) = weak_dep.section.upgrade() {
+                    add_relocation(&dependent, &found.section, false);
+                }
+            }
+        }
+
+        DependencyGraph {
+            kind,
+            namespaces: namespaces.iter().map(|ns| ns.name().to_string()).collect(),
+            nodes,
+            edges: edges.into_values().collect(),
+        }
+    }
+}
+
+impl DependencyGraph {
+    /// Writes this graph to the given writer in the given format.
+    pub fn write<W: fmt::Write>(&self, format: GraphFormat, out: &mut W) -> fmt::Result {
+        match format {
+            GraphFormat::Dot => self.write_dot(out),
+            GraphFormat::Json => self.write_json(out),
+        }
+    }
+
+    /// Returns the edges whose dependent and depended-on nodes disagree on the number of relocations.
+    pub fn inconsistent_edges(&self) -> impl Iterator<Item = &GraphEdge> {
+        self.edges.iter().filter(|edge| !edge.is_consistent())
+    }
+
+    fn write_dot<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        writeln!(out, "digraph {} {{", DotStr(&self.kind.to_string()))?;
+        writeln!(out, "    label={};", DotStr(&format!("dependencies between {} in {}", self.kind, self.namespaces.join(", "))))?;
+        writeln!(out, "    node [shape=box];")?;
+        match self.kind {
+            GraphKind::Crates => {
+                for node in &self.nodes {
+                    write_dot_node(out, "    ", node, &node.crate_name)?;
+                }
+            }
+            GraphKind::Sections => {
+                // Group each crate's sections into a cluster, relying on the nodes being sorted by crate name.
+                let mut current_crate: Option<&str> = None;
+                for node in &self.nodes {
+                    if current_crate != Some(node.crate_name.as_str()) {
+                        if current_crate.is_some() {
+                            writeln!(out, "    }}")?;
+                        }
+                        writeln!(out, "    subgraph {} {{", DotStr(&format!("cluster_{}", node.crate_name)))?;
+                        writeln!(out, "        label={};", DotStr(&node.crate_name))?;
+                        current_crate = Some(node.crate_name.as_str());
+                    }
+                    write_dot_node(out, "        ", node, node.section_name.as_deref().unwrap_or_default())?;
+                }
+                if current_crate.is_some() {
+                    writeln!(out, "    }}")?;
+                }
+            }
+        }
+        for edge in &self.edges {
+            let (from, to) = (&self.nodes[edge.from].id, &self.nodes[edge.to].id);
+            if edge.is_consistent() {
+                writeln!(out, "    {} -> {} [label=\"{}\"];", DotStr(from), DotStr(to), edge.strong)?;
+            } else {
+                writeln!(out, "    {} -> {} [label=\"strong: {}, weak: {}\", color=red, style=dashed];",
+                    DotStr(from), DotStr(to), edge.strong, edge.weak
+                )?;
+            }
+        }
+        writeln!(out, "}}")
+    }
+
+    fn write_json<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        writeln!(out, "{{")?;
+        writeln!(out, "  \"kind\": {},", JsonStr(&self.kind.to_string()))?;
+        write!(out, "  \"namespaces\": [")?;
+        for (i, ns) in self.namespaces.iter().enumerate() {
+            write!(out, "{}{}", if i == 0 { "" } else { ", " }, JsonStr(ns))?;
+        }
+        writeln!(out, "],")?;
+        writeln!(out, "  \"nodes\": [")?;
+        for (i, node) in self.nodes.iter().enumerate() {
+            write!(out, "    {{\"id\": {}, \"crate\": {}, \"section\": ", JsonStr(&node.id), JsonStr(&node.crate_name))?;
+            match &node.section_name {
+                Some(section_name) => write!(out, "{}", JsonStr(section_name))?,
+                None => write!(out, "null")?,
+            }
+            write!(out, ", \"namespace\": ")?;
+            match &node.namespace {
+                Some(namespace) => write!(out, "{}", JsonStr(namespace))?,
+                None => write!(out, "null")?,
+            }
+            writeln!(out, "}}{}", if i + 1 == self.nodes.len() { "" } else { "," })?;
+        }
+        writeln!(out, "  ],")?;
+        writeln!(out, "  \"edges\": [")?;
+        for (i, edge) in self.edges.iter().enumerate() {
+            writeln!(out, "    {{\"from\": {}, \"to\": {}, \"strong\": {}, \"weak\": {}}}{}",
+                JsonStr(&self.nodes[edge.from].id),
+                JsonStr(&self.nodes[edge.to].id),
+                edge.strong,
+                edge.weak,
+                if i + 1 == self.edges.len() { "" } else { "," },
+            )?;
+        }
+        writeln!(out, "  ]")?;
+        writeln!(out, "}}")
+    }
+}
+
+fn write_dot_node<W: fmt::Write>(out: &mut W, indent: &str, node: &GraphNode, label: &str) -> fmt::Result {
+    if node.namespace.is_some() {
+        writeln!(out, "{}{} [label={}];", indent, DotStr(&node.id), DotStr(label))
+    } else {
+        writeln!(out, "{}{} [label={}, style=dashed, color=gray];", indent, DotStr(&node.id), DotStr(label))
+    }
+}
+
+/// Displays a string as a quoted DOT ID.
+struct DotStr<'s>(&'s str);
+impl fmt::Display for DotStr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_char('"')?;
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                c => f.write_char(c)?,
+            }
+        }
+        f.write_char('"')
+    }
+}
+
+/// Displays a string as a JSON string literal.
+struct JsonStr<'s>(&'s str);
+impl fmt::Display for JsonStr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_char('"')?;
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                c => f.write_char(c)?,
+            }
+        }
+        f.write_char('"')
+    }
+}
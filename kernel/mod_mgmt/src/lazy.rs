@@ -0,0 +1,387 @@
+//! Lazy symbol resolution, which allows a crate to be loaded before the crates that it calls into.
+//!
+//! By default, loading a crate resolves every relocation in it, recursively loading
+//! every crate that it depends on, which makes starting large applications slow.
+//! When lazy linking is enabled for a [`CrateNamespace`], a relocation that refers to a function
+//! that isn't loaded yet is instead pointed at a small generated *trampoline*.
+//! The first call through a trampoline finds that function (loading its crate if needed)
+//! and then jumps to the function; subsequent calls through the trampoline jump to the cached address.
+//!
+//! Other tasks may be executing a call site while its function is being resolved, and modifying
+//! an instruction that another CPU may be executing is unsafe on x86, so call sites in code
+//! keep calling through the trampoline. Only call sites in data, e.g., function pointer tables,
+//! are patched to refer directly to the function, as those can be updated with an atomic store.
+//!
+//! Only relocations that can safely be redirected to a trampoline are resolved lazily:
+//! * absolute 64-bit relocations without an addend, which the large code model uses
+//!   for every call to a function in another crate, and
+//! * relocations to symbols that the crate's embedded ABI metadata (see the [`abi`](crate::abi) module)
+//!   describes as functions, as data can't be replaced with a trampoline.
+//!   Crates without ABI metadata are always linked eagerly.
+//!
+//! Note the following limitations:
+//! * Until it is resolved, a lazily-linked function's address is the address of its trampoline,
+//!   so comparing it against a pointer to the same function will fail.
+//! * Resolution allocates memory and takes crate management locks, and a failure to resolve
+//!   a function makes the trampoline execute an invalid instruction, such that the calling task is killed
+//!   by the invalid opcode exception handler. Thus, lazy linking is intended for application namespaces,
+//!   and shouldn't be used for crates that may be called from interrupt handlers or the heap allocator.
+//! * Dependencies on a lazily-linked function are only recorded once it is resolved,
+//!   so the crate that provides it isn't considered to be used by the calling crate before then.
+
+use crate::*;
+use crate::abi::{CrateAbi, SymbolKind};
+use core::convert::TryFrom;
+use core::sync::atomic::AtomicUsize;
+use memory::PAGE_SIZE;
+
+/// The size of each trampoline, which is larger than its code such that each one is aligned.
+const TRAMPOLINE_SIZE: usize = 32;
+const TRAMPOLINES_PER_PAGE: usize = PAGE_SIZE / TRAMPOLINE_SIZE;
+
+/// All lazily-linked functions in the system, and the pages that hold their trampolines.
+///
+/// A lazily-linked function's index in `functions` is the same as its trampoline's index.
+/// A function and its trampoline are freed once the crate that calls it is unloaded or swapped out,
+/// see [`free_lazy_functions()`], after which its index may be reused for another function.
+static LAZY_FUNCTIONS: Mutex<LazyFunctions> = Mutex::new(LazyFunctions {
+    functions: Vec::new(),
+    trampoline_pages: Vec::new(),
+});
+
+struct LazyFunctions {
+    functions: Vec<Option<LazyFunction>>,
+    trampoline_pages: Vec<MappedPages>,
+}
+
+/// A function that one crate calls into, which hasn't been resolved yet.
+struct LazyFunction {
+    /// The demangled name of the function.
+    symbol: String,
+    /// The namespace that the calling crate was loaded into, which is used to find the function.
+    namespace: Weak<CrateNamespace>,
+    /// The crate that calls the function.
+    caller: WeakCrateRef,
+    /// The sections and relocations that currently refer to this function's trampoline.
+    call_sites: Vec<(WeakSectionRef, RelocationEntry)>,
+    /// The address of the function, once it has been resolved.
+    resolved: Option<VirtualAddress>,
+}
+
+/// Statistics about lazily-linked functions, see [`lazy_linking_stats()`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LazyLinkingStats {
+    /// The number of trampolines created for functions that weren't loaded when their caller was.
+    pub trampolines: usize,
+    /// How many of those functions have since been called and resolved.
+    pub resolved: usize,
+}
+
+/// Returns statistics about all functions that have been lazily linked in any namespace.
+pub fn lazy_linking_stats() -> LazyLinkingStats {
+    let lazy_functions = LAZY_FUNCTIONS.lock();
+    LazyLinkingStats {
+        trampolines: lazy_functions.functions.iter().flatten().count(),
+        resolved: lazy_functions.functions.iter().flatten().filter(|f| f.resolved.is_some()).count(),
+    }
+}
+
+
+impl CrateNamespace {
+    /// Enables lazy linking for crates that are subsequently loaded into the given namespace,
+    /// including those loaded on demand in order to find missing symbols.
+    ///
+    /// See the [`lazy`](crate::lazy) module for details.
+    pub fn enable_lazy_linking(namespace: &Arc<CrateNamespace>) {
+        *namespace.lazy_linking.lock() = Some(Arc::downgrade(namespace));
+    }
+
+    /// Disables lazy linking for crates that are subsequently loaded into this namespace.
+    /// Functions that were already lazily linked are still resolved upon their first call.
+    pub fn disable_lazy_linking(&self) {
+        *self.lazy_linking.lock() = None;
+    }
+
+    /// Returns whether lazy linking is enabled for this namespace.
+    pub fn is_lazy_linking_enabled(&self) -> bool {
+        self.lazy_linking.lock().is_some()
+    }
+}
+
+
+/// Returns whether the given crate ABI describes the given imported symbol as a function.
+pub(crate) fn is_function_import(crate_abi: &CrateAbi, demangled_symbol: &str) -> bool {
+    crate_abi.imports.get(LoadedSection::section_name_without_hash(demangled_symbol))
+        .map_or(false, |symbol_abi| symbol_abi.kind == SymbolKind::Function)
+}
+
+/// Registers the given relocation in `target_sec` as a call site of the lazily-linked function `symbol`,
+/// and returns the address of that function's trampoline, to which the relocation should be written.
+///
+/// `crate_functions` holds the index of each lazily-linked function of the crate being loaded,
+/// such that all call sites of a function within one crate share a single trampoline.
+pub(crate) fn add_call_site(
+    namespace: &Weak<CrateNamespace>,
+    symbol: &str,
+    target_sec: &StrongSectionRef,
+    relocation: RelocationEntry,
+    crate_functions: &mut BTreeMap<String, usize>,
+    kernel_mmi_ref: &MmiRef,
+) -> Result<VirtualAddress, &'static str> {
+    let mut lazy_functions = LAZY_FUNCTIONS.lock();
+    let call_site = (Arc::downgrade(target_sec), relocation);
+    if let Some(&index) = crate_functions.get(symbol) {
+        lazy_functions.functions[index].as_mut()
+            .ok_or("BUG: lazily-linked function was freed while its caller was being loaded")?
+            .call_sites.push(call_site);
+        return lazy_functions.trampoline_address(index);
+    }
+
+    // Reuse the index and trampoline of a function that was freed, if any.
+    let index = lazy_functions.functions.iter().position(Option::is_none)
+        .unwrap_or(lazy_functions.functions.len());
+    if index >= lazy_functions.trampoline_pages.len() * TRAMPOLINES_PER_PAGE {
+        let first_index = lazy_functions.trampoline_pages.len() * TRAMPOLINES_PER_PAGE;
+        let pages = allocate_trampoline_page(first_index, kernel_mmi_ref)?;
+        lazy_functions.trampoline_pages.push(pages);
+    }
+    let function = LazyFunction {
+        symbol: String::from(symbol),
+        namespace: namespace.clone(),
+        caller: target_sec.parent_crate.clone(),
+        call_sites: vec![call_site],
+        resolved: None,
+    };
+    if index == lazy_functions.functions.len() {
+        lazy_functions.functions.push(Some(function));
+    } else {
+        lazy_functions.functions[index] = Some(function);
+    }
+    crate_functions.insert(String::from(symbol), index);
+    lazy_functions.trampoline_address(index)
+}
+
+/// Returns whether any lazily-linked function was resolved to a function in the given crate,
+/// in which case that function's trampoline may still jump into the crate.
+pub(crate) fn is_resolved_into(krate: &LoadedCrate) -> bool {
+    let Some((_, text_range)) = krate.text_pages.as_ref() else { return false };
+    LAZY_FUNCTIONS.lock().functions.iter()
+        .filter_map(|f| f.as_ref().and_then(|f| f.resolved))
+        .any(|address| text_range.contains(&address))
+}
+
+/// Frees the lazily-linked functions called by the given crate, as well as those whose calling crate
+/// no longer exists, along with any trampoline pages that no longer hold the trampoline of any function.
+///
+/// This must only be called once the given crate can no longer be executed,
+/// i.e., when it is unloaded or swapped out without being kept around to be swapped back in later,
+/// as its unresolved call sites still jump to the freed trampolines, which may then be reused.
+pub fn free_lazy_functions(caller: &StrongCrateRef) {
+    let mut lazy_functions = LAZY_FUNCTIONS.lock();
+    let mut freed = 0;
+    for slot in lazy_functions.functions.iter_mut() {
+        let is_freeable = slot.as_ref().map_or(false, |function|
+            function.caller.upgrade().map_or(true, |c| c.ptr_eq(caller))
+        );
+        if is_freeable {
+            *slot = None;
+            freed += 1;
+        }
+    }
+    while let Some(None) = lazy_functions.functions.last() {
+        lazy_functions.functions.pop();
+    }
+    let pages_in_use = (lazy_functions.functions.len() + TRAMPOLINES_PER_PAGE - 1) / TRAMPOLINES_PER_PAGE;
+    lazy_functions.trampoline_pages.truncate(pages_in_use);
+    #[cfg(not(loscd_eval))]
+    if freed > 0 {
+        debug!("Freed {} lazily-linked functions, {} trampoline pages remain", freed, lazy_functions.trampoline_pages.len());
+    }
+}
+
+impl LazyFunctions {
+    fn trampoline_address(&self, index: usize) -> Result<VirtualAddress, &'static str> {
+        let page = self.trampoline_pages.get(index / TRAMPOLINES_PER_PAGE)
+            .ok_or("BUG: lazily-linked function has no trampoline")?;
+        Ok(page.start_address() + (index % TRAMPOLINES_PER_PAGE) * TRAMPOLINE_SIZE)
+    }
+}
+
+/// Allocates and fills in a page of trampolines, the first of which is for the function at `first_index`.
+///
+/// Each trampoline pushes its function's index and jumps to [`lazy_trampoline_entry`]:
+/// ```asm
+/// push <index>                       ; 68 <imm32>
+/// movabs r11, lazy_trampoline_entry  ; 49 BB <imm64>
+/// jmp r11                            ; 41 FF E3
+/// ```
+/// The rest of each trampoline is padded with `int3` instructions.
+fn allocate_trampoline_page(first_index: usize, kernel_mmi_ref: &MmiRef) -> Result<MappedPages, &'static str> {
+    let mut pages = allocate_and_map_as_writable(PAGE_SIZE, TEXT_SECTION_FLAGS, kernel_mmi_ref)?;
+    {
+        let page_bytes: &mut [u8] = pages.as_slice_mut(0, PAGE_SIZE)?;
+        let entry = lazy_trampoline_entry as usize as u64;
+        for (i, trampoline) in page_bytes.chunks_exact_mut(TRAMPOLINE_SIZE).enumerate() {
+            let index = u32::try_from(first_index + i)
+                .ok()
+                .filter(|index| *index <= i32::MAX as u32)
+                .ok_or("too many lazily-linked functions")?;
+            trampoline.fill(0xCC);
+            trampoline[0] = 0x68;
+            trampoline[1..5].copy_from_slice(&index.to_le_bytes());
+            trampoline[5..7].copy_from_slice(&[0x49, 0xBB]);
+            trampoline[7..15].copy_from_slice(&entry.to_le_bytes());
+            trampoline[15..18].copy_from_slice(&[0x41, 0xFF, 0xE3]);
+        }
+    }
+    pages.remap(&mut kernel_mmi_ref.lock().page_table, TEXT_SECTION_FLAGS)?;
+    Ok(pages)
+}
+
+
+/// The common entry point of all trampolines, which resolves the function whose index
+/// the trampoline pushed onto the stack and then jumps to that function.
+///
+/// All argument registers are preserved across resolution, such that the function receives
+/// the arguments that its caller passed to the trampoline.
+/// SIMD registers needn't be preserved because the kernel is built without SSE.
+#[naked]
+unsafe extern "C" fn lazy_trampoline_entry() {
+    core::arch::asm!(
+        // The stack holds the function's index, followed by the caller's return address.
+        // After pushing 8 registers, the stack is 16-byte aligned, as it was before the caller's call.
+        "
+        push rdi
+        push rsi
+        push rdx
+        push rcx
+        push r8
+        push r9
+        push rax
+        push r10
+        mov rdi, [rsp + 64]
+        call {resolve}
+        ",
+        // If the function couldn't be resolved, fault such that the calling task is killed.
+        // Otherwise, overwrite the index with the function's address, then restore the registers
+        // and "return" into the function, which will return directly to the caller.
+        "
+        test rax, rax
+        jz 2f
+        mov [rsp + 64], rax
+        pop r10
+        pop rax
+        pop r9
+        pop r8
+        pop rcx
+        pop rdx
+        pop rsi
+        pop rdi
+        ret
+        2:
+        ud2
+        ",
+        resolve = sym resolve_lazy_function,
+        options(noreturn)
+    )
+}
+
+/// Resolves the lazily-linked function at the given `index` and returns its address.
+///
+/// Returns `0` if the function couldn't be resolved, as the trampoline can't return an error to its caller;
+/// the trampoline then kills the calling task instead of jumping to the function.
+extern "C" fn resolve_lazy_function(index: usize) -> usize {
+    match resolve(index) {
+        Ok(address) => address.value(),
+        Err(e) => {
+            let symbol = LAZY_FUNCTIONS.lock().functions.get(index)
+                .and_then(|f| f.as_ref().map(|f| f.symbol.clone()));
+            error!("couldn't resolve lazily-linked function {:?}: {}", symbol, e);
+            0
+        }
+    }
+}
+
+fn resolve(index: usize) -> Result<VirtualAddress, &'static str> {
+    let (symbol, namespace) = {
+        let lazy_functions = LAZY_FUNCTIONS.lock();
+        let function = lazy_functions.functions.get(index)
+            .and_then(Option::as_ref)
+            .ok_or("the lazily-linked function was freed, or its index is invalid")?;
+        if let Some(address) = function.resolved {
+            return Ok(address);
+        }
+        (function.symbol.clone(), function.namespace.clone())
+    };
+    let namespace = namespace.upgrade().ok_or("the calling crate's namespace no longer exists")?;
+    let kernel_mmi_ref = memory::get_kernel_mmi_ref().ok_or("couldn't get kernel_mmi_ref")?;
+    let source_sec = namespace.get_symbol_or_load(&symbol, None, kernel_mmi_ref, false)
+        .upgrade()
+        .ok_or("couldn't find the function, nor load its containing crate")?;
+
+    // Another task may have resolved the same function in the meantime, or it may have been freed.
+    // If its index was reused for another function, that one is only resolved here if it has the same symbol and namespace.
+    let call_sites = {
+        let mut lazy_functions = LAZY_FUNCTIONS.lock();
+        let function = lazy_functions.functions.get_mut(index)
+            .and_then(Option::as_mut)
+            .filter(|f| f.symbol == symbol && Weak::ptr_eq(&f.namespace, &Arc::downgrade(&namespace)))
+            .ok_or("the lazily-linked function was freed while it was being resolved")?;
+        if let Some(address) = function.resolved {
+            return Ok(address);
+        }
+        function.resolved = Some(source_sec.virt_addr);
+        core::mem::take(&mut function.call_sites)
+    };
+    #[cfg(not(loscd_eval))]
+    debug!("Resolved lazily-linked function {:?} to {:#X} for {} call sites", symbol, source_sec.virt_addr, call_sites.len());
+
+    for (weak_target_sec, relocation) in call_sites {
+        // The calling section may have since been unloaded or swapped out.
+        let Some(target_sec) = weak_target_sec.upgrade() else { continue };
+        if target_sec.typ != SectionType::Text {
+            patch_data_call_site(&target_sec, relocation, source_sec.virt_addr, kernel_mmi_ref)?;
+        }
+        // Record the dependency, just like eager linking does.
+        source_sec.inner.write().sections_dependent_on_me.push(WeakDependent {
+            section: Arc::downgrade(&target_sec),
+            relocation,
+        });
+        target_sec.inner.write().sections_i_depend_on.push(StrongDependency {
+            section: Arc::clone(&source_sec),
+            relocation,
+        });
+    }
+    Ok(source_sec.virt_addr)
+}
+
+/// Atomically overwrites the given lazily-linked `relocation` in the data section `target_sec`,
+/// which refers to a trampoline, to refer directly to the resolved function at `function_addr`.
+///
+/// Other tasks may read the relocation's value concurrently, so it's only patched if it is aligned
+/// such that it can be written with a single atomic store; otherwise, it keeps referring to the trampoline.
+fn patch_data_call_site(
+    target_sec: &StrongSectionRef,
+    relocation: RelocationEntry,
+    function_addr: VirtualAddress,
+    kernel_mmi_ref: &MmiRef,
+) -> Result<(), &'static str> {
+    let offset = target_sec.mapped_pages_offset + relocation.offset;
+    if offset % core::mem::size_of::<usize>() != 0 {
+        return Ok(());
+    }
+    let mut target_sec_mapped_pages = target_sec.mapped_pages.lock();
+    let target_sec_initial_flags = target_sec_mapped_pages.flags();
+    if !target_sec_initial_flags.is_writable() {
+        target_sec_mapped_pages.remap(&mut kernel_mmi_ref.lock().page_table, target_sec_initial_flags | EntryFlags::WRITABLE)?;
+    }
+    let value: &mut usize = target_sec_mapped_pages.as_type_mut(offset)?;
+    // SAFE: the value is aligned, and `AtomicUsize` has the same in-memory representation as `usize`.
+    let value = unsafe { &*(value as *mut usize as *const AtomicUsize) };
+    // Lazily-linked relocations have no addend, so the value is just the function's address.
+    value.store(function_addr.value(), Ordering::Release);
+    if !target_sec_initial_flags.is_writable() {
+        target_sec_mapped_pages.remap(&mut kernel_mmi_ref.lock().page_table, target_sec_initial_flags)?;
+    }
+    Ok(())
+}
@@ -1,10 +1,11 @@
 #![no_std]
 #![feature(let_chains)]
+#![feature(naked_functions)]
 
 #[macro_use] extern crate alloc;
 #[macro_use] extern crate log;
 
-use core::{cmp::max, fmt, mem::size_of, ops::{Deref, Range}};
+use core::{cmp::max, fmt, mem::{size_of, ManuallyDrop}, ops::{Deref, Range}, sync::atomic::{AtomicBool, Ordering}};
 use alloc::{boxed::Box, collections::{BTreeMap, btree_map, BTreeSet}, string::{String, ToString}, sync::{Arc, Weak}, vec::Vec};
 use spin::{Mutex, Once};
 use xmas_elf::{ElfFile, sections::{SHF_ALLOC, SHF_EXECINSTR, SHF_TLS, SHF_WRITE, SectionData, ShType}, symbol_table::{Binding, Type}};
@@ -20,9 +21,14 @@ use path::Path;
 use memfs::MemFile;
 use hashbrown::HashMap;
 use rangemap::RangeMap;
+use task_group::TaskGroupRef;
 pub use crate_name_utils::*;
 pub use crate_metadata::*;
 
+pub mod abi;
+pub mod graph;
+pub mod lazy;
+pub mod unload;
 pub mod parse_nano_core;
 pub mod replace_nano_core_crates;
 mod serde;
@@ -70,6 +76,9 @@ static TLS_INITIALIZER: Mutex<TlsInitializer> = Mutex::new(TlsInitializer::empty
 /// and is structured atop the given `recursive_namespace`. 
 /// If no `recursive_namespace` is provided, the default initial kernel namespace will be used. 
 /// 
+/// Each application namespace only holds the crates of the applications running in it,
+/// so [unloading on application exit](CrateNamespace::enable_unload_on_app_exit) is enabled for it.
+/// 
 /// # Return
 /// The returned `CrateNamespace` will itself be empty, having no crates and no symbols in its map.
 /// 
@@ -89,6 +98,7 @@ pub fn create_application_namespace(recursive_namespace: Option<Arc<CrateNamespa
         NamespaceDir::new(default_app_namespace_dir),
         Some(recursive_namespace),
     ));
+    new_app_namespace.enable_unload_on_app_exit();
 
     Ok(new_app_namespace)
 }
@@ -408,8 +418,11 @@ impl fmt::Debug for IntoCrateObjectFile {
 /// 
 /// When dropped, the application crate will be removed 
 /// from the `CrateNamespace` into which it was originally loaded.
+/// If that namespace has [unloading on application exit](CrateNamespace::enable_unload_on_app_exit) enabled,
+/// the crates in it that the application depended on and that are no longer referenced are unloaded too,
+/// e.g., the application's private crates; see the [`unload`] module.
 pub struct AppCrateRef {
-    crate_ref: StrongCrateRef,
+    crate_ref: ManuallyDrop<StrongCrateRef>,
     namespace: Arc<CrateNamespace>,
 }
 impl Deref for AppCrateRef {
@@ -421,6 +434,26 @@ impl Deref for AppCrateRef {
 impl Drop for AppCrateRef {
     fn drop(&mut self) {
         // trace!("### Dropping AppCrateRef {:?} from namespace {:?}", self.crate_ref, self.namespace.name());
+        let (app_crate_name, app_crate_memory, dependencies) = self.remove_from_namespace();
+        // The application crate must be dropped before the crates it depended on are no longer referenced.
+        // SAFE: `crate_ref` is not used after this point.
+        unsafe { ManuallyDrop::drop(&mut self.crate_ref) };
+        if !self.namespace.is_unload_on_app_exit_enabled() {
+            return;
+        }
+        let unloaded = self.namespace.unload_unreferenced(dependencies);
+        debug!("Unloaded application crate {:?} and {} unreferenced crates {:?} from namespace {:?}, reclaiming {} bytes",
+            app_crate_name, unloaded.crate_names.len(), unloaded.crate_names, self.namespace.name(),
+            app_crate_memory.total_bytes() + unloaded.memory.total_bytes(),
+        );
+    }
+}
+impl AppCrateRef {
+    /// Removes the application crate and its symbols from its namespace.
+    ///
+    /// Returns the crate's name, the memory it occupies, and the names of the crates it depends on.
+    fn remove_from_namespace(&self) -> (StrRef, CrateMemoryUsage, Vec<StrRef>) {
+        let dependencies = unload::dependency_names(&self.crate_ref).into_iter().collect();
         let crate_locked = self.crate_ref.lock_as_ref();
         // First, remove the actual crate from the namespace.
         if let Some(_removed_app_crate) = self.namespace.crate_tree().lock().remove(&crate_locked.crate_name) {
@@ -439,6 +472,7 @@ impl Drop for AppCrateRef {
         } else {
             error!("BUG: the dropped AppCrateRef {:?} could not be removed from namespace {:?}", self.crate_ref, self.namespace.name());
         }
+        (crate_locked.crate_name.clone(), crate_locked.memory_usage(), dependencies)
     }
 }
 
@@ -507,6 +541,21 @@ pub struct CrateNamespace {
     /// Thus, it is false by default, and should only be enabled with expert knowledge, 
     /// ideally only temporarily in order to manually load a given crate.
     fuzzy_symbol_matching: bool,
+
+    /// If lazy linking is enabled, a weak reference to this namespace itself,
+    /// which lazily-linked functions use to find their symbols once they are first called.
+    /// See the [`lazy`] module for more.
+    lazy_linking: Mutex<Option<Weak<CrateNamespace>>>,
+
+    /// Whether dropping an [`AppCrateRef`] also unloads the crates in this namespace
+    /// that it depended on and that are no longer referenced.
+    /// False by default, but enabled for namespaces created by [`create_application_namespace()`].
+    /// See the [`unload`] module for more.
+    unload_on_app_exit: AtomicBool,
+
+    /// The task group that tasks spawned into this namespace join, if any,
+    /// which bounds the resources consumed by the applications running in this namespace.
+    task_group: Mutex<Option<TaskGroupRef>>,
 }
 
 impl CrateNamespace {
@@ -525,6 +574,9 @@ impl CrateNamespace {
             crate_tree: Mutex::new(Trie::new()),
             symbol_map: Mutex::new(SymbolMap::new()),
             fuzzy_symbol_matching: false,
+            lazy_linking: Mutex::new(None),
+            unload_on_app_exit: AtomicBool::new(false),
+            task_group: Mutex::new(None),
         }
     } 
 
@@ -544,6 +596,19 @@ impl CrateNamespace {
         self.recursive_namespace.as_ref()
     }
 
+    /// Returns the task group that tasks spawned into this namespace join, if any.
+    pub fn task_group(&self) -> Option<TaskGroupRef> {
+        self.task_group.lock().clone()
+    }
+
+    /// Sets the task group that tasks spawned into this namespace will join,
+    /// or clears it if `None`.
+    ///
+    /// This only affects tasks spawned afterwards; existing tasks remain in their current groups.
+    pub fn set_task_group(&self, group: Option<TaskGroupRef>) {
+        *self.task_group.lock() = group;
+    }
+
     /// Returns a new copy of this namespace's initial TLS area,
     /// which can be used as the initial TLS area data for a new task.
     pub fn get_tls_initializer_data(&self) -> TlsDataImage {
@@ -609,6 +674,17 @@ impl CrateNamespace {
         }
     }
 
+    /// Returns the combined memory occupied by the sections of all crates in this namespace.
+    /// If `recursive` is true, crates in recursive namespaces are included as well.
+    pub fn memory_usage(&self, recursive: bool) -> CrateMemoryUsage {
+        let mut usage = CrateMemoryUsage::default();
+        self.for_each_crate(recursive, |_crate_name, crate_ref| {
+            usage += crate_ref.lock_as_ref().memory_usage();
+            true
+        });
+        usage
+    }
+
     /// Acquires the lock on this `CrateNamespace`'s crate list and returns the crate 
     /// that matches the given `crate_name`, if it exists in this namespace.
     /// If it does not exist in this namespace, then the recursive namespace is searched as well.
@@ -820,7 +896,7 @@ impl CrateNamespace {
             info!("loaded new application crate: {:?}, num sections: {}, added {} new symbols", new_crate.crate_name, new_crate.sections.len(), _new_syms);
         }
         Ok(AppCrateRef {
-            crate_ref: new_crate_ref,
+            crate_ref: ManuallyDrop::new(new_crate_ref),
             namespace: Arc::clone(namespace),
         })
     }
@@ -952,6 +1028,9 @@ impl CrateNamespace {
             crate_tree: Mutex::new(self.crate_tree.lock().clone()),
             symbol_map: Mutex::new(self.symbol_map.lock().clone()),
             fuzzy_symbol_matching: self.fuzzy_symbol_matching,
+            lazy_linking: Mutex::new(None),
+            unload_on_app_exit: AtomicBool::new(false),
+            task_group: Mutex::new(self.task_group.lock().clone()),
         }
     }
 
@@ -1067,8 +1146,9 @@ impl CrateNamespace {
             _ => return Err("BUG: load_crate_sections(): couldn't get crate object file path"),
         };
 
-        // Parse the crate file as an ELF file
+        // Parse the crate file as an ELF file, once its signature has been verified.
         let byte_slice: &[u8] = mapped_pages.as_slice(0, size_in_bytes)?;
+        crate_signatures::verify_crate_object(byte_slice, &crate_name)?;
         let elf_file = ElfFile::new(byte_slice)?; // returns Err(&str) if ELF parse fails
 
         // Check that elf_file is a relocatable type 
@@ -2081,6 +2161,24 @@ impl CrateNamespace {
         if verbose_log { debug!("=========== moving on to the relocations for crate {} =========", new_crate.crate_name); }
         let symtab = find_symbol_table(&elf_file)?;
 
+        // Lazy linking can't be used when temporarily resolving symbols against a backup namespace,
+        // which won't be available later, nor with fuzzy matching, as its ABI checks need the ELF file.
+        let lazy_namespace = if temp_backup_namespace.is_none() && !self.fuzzy_symbol_matching {
+            self.lazy_linking.lock().clone()
+        } else {
+            None
+        };
+        let mut lazy_functions: BTreeMap<String, usize> = BTreeMap::new();
+
+        // The ABI that this crate was built against, used to check symbols found via fuzzy matching
+        // and to determine which symbols are functions that can be lazily linked.
+        let crate_abi = if abi::abi_check_policy() == abi::AbiCheckPolicy::Ignore && lazy_namespace.is_none() {
+            None
+        } else {
+            abi::parse_crate_abi(elf_file)?
+        };
+        let mut foreign_crate_abis = BTreeMap::new();
+
         // Fix up the sections that were just loaded, using proper relocation info.
         // Iterate over every non-zero relocation section in the file
         for sec in elf_file.section_iter().filter(|sec| sec.get_type() == Ok(ShType::Rela) && sec.size() != 0) {
@@ -2172,10 +2270,30 @@ impl CrateNamespace {
                                 };
                                 let demangled = demangle(source_sec_name).to_string();
 
+                                // If lazy linking is enabled, calls to functions that aren't loaded yet go through a trampoline instead.
+                                let relocation_entry = RelocationEntry::from_elf_relocation(rela_entry);
+                                if let Some(lazy_namespace) = lazy_namespace.as_ref()
+                                    && let Some(crate_abi) = crate_abi.as_ref()
+                                    && relocation_entry.is_absolute_64bit()
+                                    && relocation_entry.addend == 0
+                                    && lazy::is_function_import(crate_abi, &demangled)
+                                    && self.get_symbol_internal(&demangled).is_none()
+                                {
+                                    let trampoline = lazy::add_call_site(lazy_namespace, &demangled, &target_sec, relocation_entry, &mut lazy_functions, kernel_mmi_ref)?;
+                                    write_relocation(relocation_entry, target_sec_slice, target_sec.mapped_pages_offset, trampoline, verbose_log)?;
+                                    target_sec_data_was_modified = true;
+                                    continue;
+                                }
+
                                 // search for the symbol's demangled name in the kernel's symbol map
-                                self.get_symbol_or_load(&demangled, temp_backup_namespace, kernel_mmi_ref, verbose_log)
+                                let source_sec = self.get_symbol_or_load(&demangled, temp_backup_namespace, kernel_mmi_ref, verbose_log)
                                     .upgrade()
-                                    .ok_or("Couldn't get symbol for foreign relocation entry, nor load its containing crate")
+                                    .ok_or("Couldn't get symbol for foreign relocation entry, nor load its containing crate")?;
+                                // If the symbol was found via fuzzy matching, ensure it's compatible with the one this crate expects.
+                                if let Some(crate_abi) = crate_abi.as_ref() && source_sec.name.as_str() != demangled {
+                                    abi::check_fuzzy_match(crate_abi, &demangled, &source_sec, &mut foreign_crate_abis)?;
+                                }
+                                Ok(source_sec)
                             }
                             else {
                                 let _source_sec_header = source_sec_entry
@@ -0,0 +1,156 @@
+//! Unloads crates that are no longer referenced from a [`CrateNamespace`], reclaiming their memory.
+//!
+//! A crate can only be unloaded once nothing else refers to it, i.e., when:
+//! * no section in another crate depends on any of its sections,
+//! * it isn't shared with another namespace and no other reference to it is held,
+//!   e.g., the [`AppCrateRef`] of a running application,
+//! * it has no thread-local storage (TLS) sections, as those are part of every task's TLS area,
+//! * no lazily-linked function was resolved to one of its functions (see the [`lazy`](crate::lazy) module),
+//!   as that function's trampoline may still jump to it.
+//!
+//! Note that this doesn't check whether a task is currently executing within a crate,
+//! which the caller must ensure when unloading a crate that isn't an application's private crate.
+//! For that reason, dropping an [`AppCrateRef`] only unloads the crates that the application depended on
+//! if unloading on application exit has been enabled for its namespace, which is the case for
+//! the per-application namespaces created by [`create_application_namespace()`](crate::create_application_namespace).
+
+use crate::*;
+
+/// The crates that were unloaded from a [`CrateNamespace`] and the memory that they occupied.
+#[derive(Clone, Debug, Default)]
+pub struct UnloadedCrates {
+    /// The names of the unloaded crates, in the order they were unloaded.
+    pub crate_names: Vec<StrRef>,
+    /// The combined memory occupied by the unloaded crates' sections.
+    pub memory: CrateMemoryUsage,
+}
+
+impl CrateNamespace {
+    /// Enables unloading, whenever an [`AppCrateRef`] in this namespace is dropped,
+    /// the crates in this namespace that its application depended on and that are no longer referenced.
+    ///
+    /// This should only be enabled for namespaces whose crates are only executed by their applications' tasks.
+    pub fn enable_unload_on_app_exit(&self) {
+        self.unload_on_app_exit.store(true, Ordering::Release);
+    }
+
+    /// Disables unloading the crates that an application depended on when its [`AppCrateRef`] is dropped.
+    /// This is the default, except for namespaces created by [`create_application_namespace()`](crate::create_application_namespace).
+    pub fn disable_unload_on_app_exit(&self) {
+        self.unload_on_app_exit.store(false, Ordering::Release);
+    }
+
+    /// Returns whether unloading on application exit is enabled for this namespace.
+    pub fn is_unload_on_app_exit_enabled(&self) -> bool {
+        self.unload_on_app_exit.load(Ordering::Acquire)
+    }
+
+    /// Unloads the crate with the given name from this `CrateNamespace` (not its recursive namespace),
+    /// removing its symbols from this namespace's symbol map.
+    ///
+    /// Returns an error if the crate isn't loaded in this namespace or if it is still referenced,
+    /// e.g., if any other crate depends on it; see the [module-level docs](crate::unload) for details.
+    /// The crates that it depended on are not unloaded.
+    pub fn unload_crate(&self, crate_name: &str) -> Result<UnloadedCrates, &'static str> {
+        let removed = {
+            let mut crate_tree = self.crate_tree.lock();
+            let crate_ref = crate_tree.get(crate_name.as_bytes())
+                .ok_or("crate isn't loaded in this namespace")?;
+            if let Some(reason) = why_crate_is_referenced(crate_ref) {
+                return Err(reason);
+            }
+            crate_tree.remove(crate_name.as_bytes())
+                .ok_or("BUG: unload_crate(): crate was removed from the namespace while it was locked")?
+        };
+        let mut unloaded = UnloadedCrates::default();
+        self.remove_unloaded_crate(removed, &mut unloaded);
+        Ok(unloaded)
+    }
+
+    /// Unloads every crate in this `CrateNamespace` (not its recursive namespace) that is no longer referenced,
+    /// including crates that become unreferenced once the crates that depend on them are unloaded.
+    ///
+    /// This should only be used on namespaces of application crates,
+    /// as kernel crates may be executing even if no other crate depends on them.
+    pub fn unload_unreferenced_crates(&self) -> UnloadedCrates {
+        self.unload_unreferenced(self.crate_names(false))
+    }
+
+    /// Unloads those of the given crates that are in this `CrateNamespace` and no longer referenced,
+    /// along with the crates in this namespace that they depended on that become unreferenced as a result.
+    pub(crate) fn unload_unreferenced(&self, mut candidates: Vec<StrRef>) -> UnloadedCrates {
+        let mut unloaded = UnloadedCrates::default();
+        // Unloading a crate may leave the crates it depended on unreferenced, so repeat until none are left.
+        while !candidates.is_empty() {
+            let mut next_candidates = BTreeSet::new();
+            for crate_name in candidates {
+                let removed = {
+                    let mut crate_tree = self.crate_tree.lock();
+                    match crate_tree.get(crate_name.as_bytes()) {
+                        Some(crate_ref) if why_crate_is_referenced(crate_ref).is_none() => crate_tree.remove(crate_name.as_bytes()),
+                        _ => None,
+                    }
+                };
+                if let Some(crate_ref) = removed {
+                    next_candidates.extend(dependency_names(&crate_ref));
+                    self.remove_unloaded_crate(crate_ref, &mut unloaded);
+                }
+            }
+            candidates = next_candidates.into_iter().collect();
+        }
+        unloaded
+    }
+
+    /// Removes the symbols of the given crate, which has already been removed from this namespace's crate tree,
+    /// and then drops it, adding it to the given `unloaded` crates.
+    fn remove_unloaded_crate(&self, crate_ref: StrongCrateRef, unloaded: &mut UnloadedCrates) {
+        let krate = crate_ref.lock_as_ref();
+        let section_ptrs: BTreeSet<*const LoadedSection> = krate.sections.values().map(Arc::as_ptr).collect();
+        let mut symbol_map = self.symbol_map.lock();
+        for name in krate.global_sections_iter().map(|sec| &sec.name).chain(krate.reexported_symbols.iter()) {
+            // Only remove symbols that still refer to this crate's sections, e.g., not ones that were swapped.
+            let refers_to_this_crate = symbol_map.get(name).map_or(false, |weak_sec|
+                weak_sec.upgrade().map_or(true, |sec| section_ptrs.contains(&Arc::as_ptr(&sec)))
+            );
+            if refers_to_this_crate {
+                symbol_map.remove(name);
+            }
+        }
+        #[cfg(not(loscd_eval))]
+        debug!("Unloaded crate {:?} from namespace {:?}", krate.crate_name, self.name);
+        unloaded.memory += krate.memory_usage();
+        unloaded.crate_names.push(krate.crate_name.clone());
+        drop(symbol_map);
+        drop(krate);
+        lazy::free_lazy_functions(&crate_ref);
+    }
+}
+
+/// Returns why the given crate cannot be unloaded, or `None` if it is no longer referenced.
+fn why_crate_is_referenced(crate_ref: &StrongCrateRef) -> Option<&'static str> {
+    if crate_ref.is_shared() {
+        return Some("crate is shared with another namespace or still in use");
+    }
+    if CowArc::shallow_count(crate_ref) > 1 {
+        return Some("crate is still in use, e.g., by a running application");
+    }
+    let krate = crate_ref.lock_as_ref();
+    if !krate.tls_sections.is_empty() {
+        return Some("crate has TLS sections, which can't be unloaded");
+    }
+    if !krate.crates_dependent_on_me().is_empty() {
+        return Some("other crates depend on this crate");
+    }
+    if lazy::is_resolved_into(&krate) {
+        return Some("a lazily-linked function was resolved to this crate");
+    }
+    None
+}
+
+/// Returns the names of the crates that the given crate depends on.
+pub(crate) fn dependency_names(crate_ref: &StrongCrateRef) -> BTreeSet<StrRef> {
+    crate_ref.lock_as_ref().crates_i_depend_on().into_iter()
+        .filter_map(|weak_crate| weak_crate.upgrade())
+        .map(|dep_crate| dep_crate.lock_as_ref().crate_name.clone())
+        .collect()
+}
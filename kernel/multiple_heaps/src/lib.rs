@@ -95,7 +95,10 @@ const HEAP_GROWTH_AMOUNT: usize = 2;
 /// Creates and initializes the multiple heaps using the apic id as the key, which is mapped to a heap.
 /// If we want to change the value the heap id is based on, we would substitute 
 /// the lapic iterator with an iterator containing the desired keys.
-fn initialize_multiple_heaps() -> Result<MultipleHeaps, &'static str> {
+///
+/// This is public so that other allocators (e.g., the `heap_debug` allocator) can wrap the multiple heaps;
+/// to use the multiple heaps directly, call [`switch_to_multiple_heaps()`] instead.
+pub fn initialize_multiple_heaps() -> Result<MultipleHeaps, &'static str> {
     let mut multiple_heaps = MultipleHeaps::empty();
 
     for (apic_id, _lapic) in apic::get_lapics().iter() {
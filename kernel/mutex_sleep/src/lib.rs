@@ -3,10 +3,17 @@
 //! These are Theseus-specific locking types that ensure mutual exclusion
 //! using [`spin::Mutex`] and [`spin::RwLock`] under the hood;
 //! see those types for more details on how they work.
+//!
+//! When a priority scheduler is in use, both lock types implement priority inheritance:
+//! a task waiting for a lock boosts the priority of lower-priority tasks holding that lock,
+//! which avoids unbounded priority inversion. See the `priority_inheritance` module.
 
 #![no_std]
 
+extern crate alloc;
+
 mod mutex;
+mod priority_inheritance;
 mod rwlock;
 
 pub use mutex::*;
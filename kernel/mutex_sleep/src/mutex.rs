@@ -1,16 +1,23 @@
+use core::cell::Cell;
 use core::fmt;
 use core::ops::{Deref, DerefMut};
 use spin::{Mutex, MutexGuard};
 use wait_queue::WaitQueue;
 use lockable::{Lockable, LockableSized};
+use crate::priority_inheritance::PriorityInheritance;
 
 /// A mutual exclusion wrapper that puts a `Task` to sleep while waiting for the lock to become available. 
 /// 
 /// A sleeping `Task` has a "blocked" runstate, meaning that it will not be scheduled in. 
 /// Once the lock becomes available, `Task`s that are sleeping while waiting for the lock
 /// will be notified (woken up) so they can attempt to acquire the lock again.
+/// 
+/// When a priority scheduler is in use, a `Task` waiting for the lock temporarily
+/// boosts the priority of the lower-priority `Task` holding the lock, if any,
+/// until that holder releases the lock (priority inheritance).
 pub struct MutexSleep<T: ?Sized> {
     queue: WaitQueue,
+    holders: PriorityInheritance,
     lock: Mutex<T>,
 }
 
@@ -21,6 +28,7 @@ pub struct MutexSleep<T: ?Sized> {
 pub struct MutexSleepGuard<'a, T: ?Sized + 'a> {
     guard: MutexGuard<'a, T>,
     queue: &'a WaitQueue,
+    holders: &'a PriorityInheritance,
 }
 
 // Same unsafe impls as `std::sync::Mutex`
@@ -38,6 +46,7 @@ impl<T> MutexSleep<T> {
         MutexSleep {
             lock: Mutex::new(data),
             queue: WaitQueue::new(),
+            holders: PriorityInheritance::new(),
         }
     }
 
@@ -64,24 +73,48 @@ impl<T: ?Sized> MutexSleep<T> {
     ///
     /// The returned guard may be dereferenced to access the protected data;
     /// the lock will be released when the returned guard falls out of scope and is dropped.
+    ///
+    /// While waiting, this `Task` boosts the priority of the `Task` holding the lock
+    /// if the holder has a lower priority than this `Task`.
     pub fn lock(&self) -> Result<MutexSleepGuard<T>, &'static str> {
         // Fast path: check for the uncontended case.
         if let Some(guard) = self.try_lock() {
             return Ok(guard);
         }
-        // Slow path if already locked elsewhere: wait until we obtain the lock.
-        self.queue
-            .wait_until(&|| self.try_lock())
-            .map_err(|_| "failed to add current task to waitqueue")
+        // Slow path if already locked elsewhere: wait until we obtain the lock,
+        // boosting the current holder before each time we go to sleep.
+        // Boosting changes other tasks' priorities, so it must not be done within
+        // the wait queue's condition, which runs with the wait queue locked.
+        // Thus, if we're woken up but fail to acquire the lock again,
+        // we stop waiting, boost the new holder, and then resume waiting.
+        loop {
+            self.holders.boost_holders();
+            let woken = Cell::new(false);
+            let guard = self.queue
+                .wait_until(&|| match self.try_lock() {
+                    Some(guard) => Some(Some(guard)),
+                    None if woken.replace(true) => Some(None),
+                    None => None,
+                })
+                .map_err(|_| {
+                    self.holders.stopped_waiting();
+                    "failed to add current task to waitqueue"
+                })?;
+            if let Some(guard) = guard {
+                return Ok(guard);
+            }
+        }
     }
 
     /// Tries to lock the MutexSleep. If it is already locked, it will return `None`.
     /// Otherwise it returns a guard within `Some`.
     pub fn try_lock(&self) -> Option<MutexSleepGuard<T>> {
         self.lock.try_lock().map(|spinlock_guard| {
+            self.holders.acquired();
             MutexSleepGuard {
                 guard: spinlock_guard,
                 queue: &self.queue,
+                holders: &self.holders,
             }
         })
     }
@@ -137,6 +170,8 @@ impl<'a, T: ?Sized> DerefMut for MutexSleepGuard<'a, T> {
 
 impl<'a, T: ?Sized> Drop for MutexSleepGuard<'a, T> {
     fn drop(&mut self) {
+        // Restore this task's original priority if it was boosted while holding the lock.
+        self.holders.released();
         // Notify a task on the waitqueue that the lock is released,
         // which occurs automatically when the inner `guard` is dropped after this method executes.
         self.queue.notify_one();
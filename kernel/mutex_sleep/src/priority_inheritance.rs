@@ -0,0 +1,254 @@
+//! Priority inheritance for the sleeping lock types in this crate.
+//!
+//! When a high-priority task blocks on a lock held by a low-priority task,
+//! medium-priority tasks can preempt the holder and thereby indefinitely delay
+//! the high-priority waiter, i.e., priority inversion.
+//! To prevent this, a waiting task temporarily raises (boosts) the priority of each
+//! lower-priority holder up to its own priority, via [`scheduler::set_priority()`].
+//!
+//! Boosts are tracked per task and per held lock in one global registry:
+//! * A task's base priority is recorded when it is first boosted.
+//!   When it releases a lock, only the boost inherited through that lock is dropped,
+//!   and its priority is recomputed from its base priority and the boosts
+//!   it still inherits through the other locks it holds.
+//! * A boosted holder that is itself blocked on another lock passes the boost on
+//!   to the holders of that lock, and so on down the chain of blocked owners.
+//!
+//! The registry is protected by an interrupt-safe lock, as it is accessed both with
+//! interrupts enabled (when acquiring or releasing a lock) and from within a wait queue's
+//! condition (when a woken waiter retries the lock), during which interrupts are disabled.
+//! Boosting holders is never done within a wait queue's condition, though,
+//! since that changes the priorities of other tasks in the scheduler's runqueues.
+//!
+//! Holders are only tracked when a scheduler that supports task priorities is in use,
+//! i.e., when compiled with `cfg(priority_scheduler)`; otherwise, this is a no-op.
+
+#[cfg(priority_scheduler)]
+use alloc::{collections::BTreeMap, vec::Vec};
+#[cfg(priority_scheduler)]
+use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(priority_scheduler)]
+use irq_safety::MutexIrqSafe;
+#[cfg(priority_scheduler)]
+use task::TaskRef;
+
+/// The maximum number of locks that a boost is propagated through,
+/// which bounds the work done when the chain of blocked owners contains a deadlock cycle.
+#[cfg(priority_scheduler)]
+const MAX_PROPAGATION_STEPS: usize = 64;
+
+/// The source of unique lock IDs, starting at 1 because 0 means "not yet assigned".
+#[cfg(priority_scheduler)]
+static NEXT_LOCK_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// The priority inheritance state of all tracked locks and tasks.
+#[cfg(priority_scheduler)]
+static REGISTRY: MutexIrqSafe<Registry> = MutexIrqSafe::new(Registry {
+    holders: BTreeMap::new(),
+    tasks: BTreeMap::new(),
+});
+
+#[cfg(priority_scheduler)]
+struct Registry {
+    /// The tasks currently holding each lock, keyed by lock ID.
+    holders: BTreeMap<usize, Vec<TaskRef>>,
+    /// The state of each task that is boosted or blocked on a lock, keyed by task ID.
+    tasks: BTreeMap<usize, TaskState>,
+}
+
+/// The priority inheritance state of a single task.
+#[cfg(priority_scheduler)]
+#[derive(Default)]
+struct TaskState {
+    /// The priority this task had before it was first boosted,
+    /// or `None` if it is not currently boosted.
+    base_priority: Option<u8>,
+    /// The highest priority this task inherits through each lock it holds, keyed by lock ID.
+    boosts: BTreeMap<usize, u8>,
+    /// The ID of the lock this task is currently blocked on, if any.
+    blocked_on: Option<usize>,
+}
+
+#[cfg(priority_scheduler)]
+impl TaskState {
+    fn is_empty(&self) -> bool {
+        self.base_priority.is_none() && self.boosts.is_empty() && self.blocked_on.is_none()
+    }
+
+    /// Returns the priority this task should currently run at,
+    /// i.e., the highest of its base priority and all of its inherited priorities.
+    fn effective_priority(&self) -> Option<u8> {
+        self.boosts.values().copied().chain(self.base_priority).max()
+    }
+}
+
+#[cfg(priority_scheduler)]
+impl Registry {
+    /// Boosts every holder of the given lock up to `priority`,
+    /// then continues with the locks that those holders are blocked on.
+    fn propagate(&mut self, lock_id: usize, priority: u8) {
+        let Registry { holders, tasks } = self;
+        let mut pending = alloc::vec![lock_id];
+        let mut steps = 0;
+        while let Some(lock_id) = pending.pop() {
+            steps += 1;
+            if steps > MAX_PROPAGATION_STEPS {
+                log::warn!("mutex_sleep: stopped propagating a priority boost after {} locks", MAX_PROPAGATION_STEPS);
+                return;
+            }
+            for holder in holders.get(&lock_id).into_iter().flatten() {
+                let state = tasks.entry(holder.id).or_default();
+                let inherited = state.boosts.get(&lock_id).copied();
+                if inherited.map_or(false, |p| p >= priority) {
+                    // This boost has already been inherited through this lock (and passed on).
+                    continue;
+                }
+                let Some(current_priority) = scheduler::get_priority(holder) else { continue };
+                if state.base_priority.is_none() && current_priority >= priority {
+                    // An unboosted holder that already runs at this priority doesn't need a boost,
+                    // but it must still pass the boost on if it is blocked itself.
+                    pending.extend(state.blocked_on);
+                    continue;
+                }
+                state.base_priority.get_or_insert(current_priority);
+                state.boosts.insert(lock_id, priority);
+                apply_priority(holder, state);
+                pending.extend(state.blocked_on);
+            }
+        }
+    }
+}
+
+/// Sets the given task's priority to its current effective priority.
+#[cfg(priority_scheduler)]
+fn apply_priority(task: &TaskRef, state: &TaskState) {
+    if let Some(priority) = state.effective_priority() {
+        if scheduler::get_priority(task) != Some(priority) {
+            if let Err(e) = scheduler::set_priority(task, priority) {
+                log::warn!("mutex_sleep: failed to set priority of lock holder {:?} to {}: {}", task, priority, e);
+            }
+        }
+    }
+}
+
+/// Tracks which tasks currently hold a lock in order to boost their priorities
+/// on behalf of higher-priority tasks that are waiting to acquire that lock.
+pub(crate) struct PriorityInheritance {
+    /// The unique ID of this lock in the global registry, assigned on first use.
+    #[cfg(priority_scheduler)]
+    id: AtomicUsize,
+}
+
+impl PriorityInheritance {
+    pub(crate) const fn new() -> PriorityInheritance {
+        PriorityInheritance {
+            #[cfg(priority_scheduler)]
+            id: AtomicUsize::new(0),
+        }
+    }
+
+    #[cfg(priority_scheduler)]
+    fn id(&self) -> usize {
+        let id = self.id.load(Ordering::Acquire);
+        if id != 0 {
+            return id;
+        }
+        let new_id = NEXT_LOCK_ID.fetch_add(1, Ordering::Relaxed);
+        match self.id.compare_exchange(0, new_id, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => new_id,
+            Err(existing_id) => existing_id,
+        }
+    }
+
+    /// Records the current task as a holder of the lock.
+    ///
+    /// This must be called by the task that has just acquired the lock.
+    #[inline]
+    pub(crate) fn acquired(&self) {
+        #[cfg(priority_scheduler)] {
+            if let Some(curr_task) = task::get_my_current_task() {
+                let lock_id = self.id();
+                let mut registry = REGISTRY.lock();
+                if let Some(state) = registry.tasks.get_mut(&curr_task.id) {
+                    state.blocked_on = None;
+                    if state.is_empty() {
+                        registry.tasks.remove(&curr_task.id);
+                    }
+                }
+                registry.holders.entry(lock_id).or_default().push(curr_task);
+            }
+        }
+    }
+
+    /// Boosts every holder of the lock whose priority is lower than the current task's priority
+    /// up to the current task's priority, along with the holders of any locks
+    /// that those holders are themselves blocked on.
+    ///
+    /// This must be called by a task that has failed to acquire the lock
+    /// and is about to block while waiting for it, but not from within a wait queue's condition.
+    #[inline]
+    pub(crate) fn boost_holders(&self) {
+        #[cfg(priority_scheduler)] {
+            let Some(curr_task) = task::get_my_current_task() else { return };
+            let Some(my_priority) = scheduler::get_priority(&curr_task) else { return };
+            let lock_id = self.id();
+            let mut registry = REGISTRY.lock();
+            registry.tasks.entry(curr_task.id).or_default().blocked_on = Some(lock_id);
+            registry.propagate(lock_id, my_priority);
+        }
+    }
+
+    /// Records that the current task is no longer waiting for the lock
+    /// even though it didn't acquire it.
+    ///
+    /// This must be called by a task whose wait for the lock has failed.
+    #[inline]
+    pub(crate) fn stopped_waiting(&self) {
+        #[cfg(priority_scheduler)] {
+            let curr_task_id = task::get_my_current_task_id();
+            let mut registry = REGISTRY.lock();
+            if let Some(state) = registry.tasks.get_mut(&curr_task_id) {
+                state.blocked_on = None;
+                if state.is_empty() {
+                    registry.tasks.remove(&curr_task_id);
+                }
+            }
+        }
+    }
+
+    /// Removes the current task from the holders of the lock and drops the boost
+    /// it inherited through the lock, recomputing its priority from its base priority
+    /// and the boosts it still inherits through other locks it holds.
+    ///
+    /// This must be called by the task that is releasing the lock.
+    #[inline]
+    pub(crate) fn released(&self) {
+        #[cfg(priority_scheduler)] {
+            let curr_task_id = task::get_my_current_task_id();
+            let lock_id = self.id();
+            let mut registry = REGISTRY.lock();
+            let Registry { holders, tasks } = &mut *registry;
+
+            let mut curr_task = None;
+            if let Some(lock_holders) = holders.get_mut(&lock_id) {
+                if let Some(index) = lock_holders.iter().position(|t| t.id == curr_task_id) {
+                    curr_task = Some(lock_holders.swap_remove(index));
+                }
+                if lock_holders.is_empty() {
+                    holders.remove(&lock_id);
+                }
+            }
+            let (Some(curr_task), Some(state)) = (curr_task, tasks.get_mut(&curr_task_id)) else { return };
+            if state.boosts.remove(&lock_id).is_none() {
+                return;
+            }
+            apply_priority(&curr_task, state);
+            if state.boosts.is_empty() {
+                state.base_priority = None;
+                if state.is_empty() {
+                    tasks.remove(&curr_task_id);
+                }
+            }
+        }
+    }
+}
@@ -1,8 +1,10 @@
+use core::cell::Cell;
 use core::fmt;
 use core::ops::{Deref, DerefMut};
 use spin::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 use wait_queue::WaitQueue;
 use lockable::{Lockable, LockableSized};
+use crate::priority_inheritance::PriorityInheritance;
 
 /// A multi-reader, single-writer mutual exclusion wrapper that puts a `Task` to sleep
 /// while waiting for the lock to become available. 
@@ -12,8 +14,13 @@ use lockable::{Lockable, LockableSized};
 /// A sleeping `Task` has a "blocked" runstate, meaning that it will not be scheduled in. 
 /// Once the lock becomes available, `Task`s that are sleeping while waiting for the lock
 /// will be notified (woken up) so they can attempt to acquire the lock again.
+/// 
+/// When a priority scheduler is in use, a `Task` waiting for the lock temporarily
+/// boosts the priority of all lower-priority `Task`s holding the lock (readers or the writer)
+/// until each holder releases the lock (priority inheritance).
 pub struct RwLockSleep<T: ?Sized> {
     queue: WaitQueue,
+    holders: PriorityInheritance,
     rwlock: RwLock<T>,
 }
 
@@ -25,6 +32,7 @@ pub struct RwLockSleep<T: ?Sized> {
 pub struct RwLockSleepReadGuard<'a, T: ?Sized + 'a> {
     guard: RwLockReadGuard<'a, T>,
     queue: &'a WaitQueue,
+    holders: &'a PriorityInheritance,
 }
 
 /// A guard that allows the locked data to be mutably accessed,
@@ -35,6 +43,7 @@ pub struct RwLockSleepReadGuard<'a, T: ?Sized + 'a> {
 pub struct RwLockSleepWriteGuard<'a, T: ?Sized + 'a> {
     guard: RwLockWriteGuard<'a, T>,
     queue: &'a WaitQueue,
+    holders: &'a PriorityInheritance,
 }
 
 // Same unsafe impls as `std::sync::RwLock`
@@ -52,6 +61,7 @@ impl<T> RwLockSleep<T> {
         RwLockSleep {
             rwlock: RwLock::new(data),
             queue: WaitQueue::new(),
+            holders: PriorityInheritance::new(),
         }
     }
 
@@ -99,10 +109,26 @@ impl<T: ?Sized> RwLockSleep<T> {
         if let Some(guard) = self.try_read() {
             return Ok(guard);
         }
-        // Slow path if already locked elsewhere: wait until we obtain the lock.
-        self.queue
-            .wait_until(&|| self.try_read())
-            .map_err(|_| "failed to add current task to waitqueue")
+        // Slow path if already locked elsewhere: wait until we obtain the lock,
+        // boosting the current writer before each time we go to sleep.
+        // As with `MutexSleep::lock()`, boosting is done outside of the wait queue's condition.
+        loop {
+            self.holders.boost_holders();
+            let woken = Cell::new(false);
+            let guard = self.queue
+                .wait_until(&|| match self.try_read() {
+                    Some(guard) => Some(Some(guard)),
+                    None if woken.replace(true) => Some(None),
+                    None => None,
+                })
+                .map_err(|_| {
+                    self.holders.stopped_waiting();
+                    "failed to add current task to waitqueue"
+                })?;
+            if let Some(guard) = guard {
+                return Ok(guard);
+            }
+        }
     }
 
     /// Attempt to acquire this lock with shared read (immutable) access.
@@ -124,12 +150,14 @@ impl<T: ?Sized> RwLockSleep<T> {
     /// }
     /// ```
     pub fn try_read(&self) -> Option<RwLockSleepReadGuard<T>> {
-        self.rwlock.try_read().map(|spinlock_guard| 
+        self.rwlock.try_read().map(|spinlock_guard| {
+            self.holders.acquired();
             RwLockSleepReadGuard {
                 guard: spinlock_guard,
-                queue: &self.queue
+                queue: &self.queue,
+                holders: &self.holders,
             }
-        )
+        })
     }
 
     /// Return the number of readers that currently hold the lock (including upgradable readers).
@@ -199,10 +227,26 @@ impl<T: ?Sized> RwLockSleep<T> {
         if let Some(guard) = self.try_write() {
             return Ok(guard);
         }
-        // Slow path if already locked elsewhere: wait until we obtain the write lock.
-        self.queue
-            .wait_until(&|| self.try_write())
-            .map_err(|_| "failed to add current task to waitqueue")
+        // Slow path if already locked elsewhere: wait until we obtain the write lock,
+        // boosting the current readers or writer before each time we go to sleep.
+        // As with `MutexSleep::lock()`, boosting is done outside of the wait queue's condition.
+        loop {
+            self.holders.boost_holders();
+            let woken = Cell::new(false);
+            let guard = self.queue
+                .wait_until(&|| match self.try_write() {
+                    Some(guard) => Some(Some(guard)),
+                    None if woken.replace(true) => Some(None),
+                    None => None,
+                })
+                .map_err(|_| {
+                    self.holders.stopped_waiting();
+                    "failed to add current task to waitqueue"
+                })?;
+            if let Some(guard) = guard {
+                return Ok(guard);
+            }
+        }
     }
 
     /// Attempt to acquire this lock with exclusive write (mutable) access.
@@ -224,12 +268,14 @@ impl<T: ?Sized> RwLockSleep<T> {
     /// }
     /// ```
     pub fn try_write(&self) -> Option<RwLockSleepWriteGuard<T>> {
-        self.rwlock.try_write().map(|spinlock_guard|
+        self.rwlock.try_write().map(|spinlock_guard| {
+            self.holders.acquired();
             RwLockSleepWriteGuard {
                 guard: spinlock_guard,
                 queue: &self.queue,
+                holders: &self.holders,
             }
-        )
+        })
     }
 
     /// Returns a mutable reference to the underlying data.
@@ -290,6 +336,8 @@ impl<'rwlock, T: ?Sized> DerefMut for RwLockSleepWriteGuard<'rwlock, T> {
 
 impl<'rwlock, T: ?Sized> Drop for RwLockSleepReadGuard<'rwlock, T> {
     fn drop(&mut self) {
+        // Restore this task's original priority if it was boosted while holding the lock.
+        self.holders.released();
         // Notify a task on the waitqueue that the lock is released,
         // which occurs automatically when the inner `guard` is dropped after this method executes.
         self.queue.notify_one();
@@ -298,6 +346,8 @@ impl<'rwlock, T: ?Sized> Drop for RwLockSleepReadGuard<'rwlock, T> {
 
 impl<'rwlock, T: ?Sized> Drop for RwLockSleepWriteGuard<'rwlock, T> {
     fn drop(&mut self) {
+        // Restore this task's original priority if it was boosted while holding the lock.
+        self.holders.released();
         // Notify a task on the waitqueue that the lock is released,
         // which occurs automatically when the inner `guard` is dropped after this method executes.
         self.queue.notify_one();
@@ -24,7 +24,6 @@ extern crate multiboot2;
 extern crate kernel_config; // our configuration options, just a set of const definitions.
 extern crate irq_safety; // for irq-safe locking and interrupt utilities
 extern crate logger;
-extern crate state_store;
 extern crate memory; // the virtual memory subsystem
 extern crate no_drop;
 extern crate stack;
@@ -80,7 +79,6 @@ fn shutdown(msg: core::fmt::Arguments) -> ! {
 /// * Bootstraps the OS, including [logging](../logger/index.html) 
 ///   and basic early [exception handlers](../exceptions_early/fn.init.html)
 /// * Sets up basic [virtual memory](../memory/fn.init.html)
-/// * Initializes the [state_store](../state_store/index.html) module
 /// * Finally, calls the Captain module, which initializes and configures the rest of Theseus.
 ///
 /// If a failure occurs and is propagated back up to this function, the OS is shut down.
@@ -142,10 +140,6 @@ pub extern "C" fn nano_core_start(
     ) = try_exit!(memory_initialization::init_memory_management(boot_info));
     println_raw!("nano_core_start(): initialized memory subsystem."); 
 
-    state_store::init();
-    trace!("state_store initialized.");
-    println_raw!("nano_core_start(): initialized state store.");     
-
     // initialize the module management subsystem, so we can create the default crate namespace
     let default_namespace = match mod_mgmt::init(bootloader_modules, kernel_mmi_ref.lock().deref_mut()) {
         Ok(namespace) => namespace,
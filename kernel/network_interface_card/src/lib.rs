@@ -1,8 +1,14 @@
 #![no_std]
 
 extern crate nic_buffers;
+extern crate spin;
+extern crate wait_queue;
 
+use core::future::Future;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use nic_buffers::{TransmitBuffer, ReceivedFrame};
+use spin::Once;
+use wait_queue::WaitQueue;
 
 
 /// A trait that defines the necessary minimum functions that all network interface card (NIC) drivers
@@ -25,3 +31,33 @@ pub trait NetworkInterfaceCard {
     /// otherwise it will return the regular MAC address defined by the NIC hardware.
     fn mac_address(&self) -> [u8; 6];
 }
+
+
+/// The number of frames received by all NICs, used to detect new frames in [`wait_for_received_frame()`].
+static RECEIVED_FRAME_COUNT: AtomicUsize = AtomicUsize::new(0);
+/// The queue of tasks and futures waiting for a NIC to receive a frame.
+static RECEIVED_FRAME_WAITERS: Once<WaitQueue> = Once::new();
+
+fn received_frame_waiters() -> &'static WaitQueue {
+    RECEIVED_FRAME_WAITERS.call_once(WaitQueue::new)
+}
+
+/// Notifies all tasks and futures waiting in [`wait_for_received_frame()`] that a new frame has been received.
+///
+/// NIC drivers should invoke this from their receive interrupt handler
+/// after the newly-received frames have been collected.
+pub fn notify_frame_received() {
+    RECEIVED_FRAME_COUNT.fetch_add(1, Ordering::Release);
+    received_frame_waiters().notify_all();
+}
+
+/// Returns a future that completes once any NIC has received a new frame,
+/// i.e., once [`notify_frame_received()`] is next invoked.
+///
+/// This allows async network code to wait for incoming data without busy-polling.
+pub fn wait_for_received_frame() -> impl Future<Output = ()> {
+    let start = RECEIVED_FRAME_COUNT.load(Ordering::Acquire);
+    received_frame_waiters().wait_until_async(move || {
+        if RECEIVED_FRAME_COUNT.load(Ordering::Acquire) != start { Some(()) } else { None }
+    })
+}
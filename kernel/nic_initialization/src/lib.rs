@@ -13,13 +13,16 @@ extern crate intel_ethernet;
 extern crate nic_buffers;
 extern crate volatile;
 extern crate nic_queues;
+extern crate iommu;
 
-use memory::{EntryFlags, PhysicalAddress, allocate_pages_by_bytes, allocate_frames_by_bytes_at, get_kernel_mmi_ref, MappedPages, create_contiguous_mapping, Mutable, BorrowedSliceMappedPages};
+use memory::{EntryFlags, PhysicalAddress, PageSize, PAGE_SIZE, allocate_pages_by_bytes, allocate_frames_by_bytes_at, allocate_huge_pages, allocate_huge_frames,
+    get_kernel_mmi_ref, MappedPages, create_contiguous_mapping, Mutable, BorrowedSliceMappedPages};
 use pci::PciDevice;
 use alloc::vec::Vec;
 use intel_ethernet::descriptors::{RxDescriptor, TxDescriptor};
-use nic_buffers::ReceiveBuffer;
+use nic_buffers::{ReceiveBuffer, TransmitBuffer};
 use nic_queues::{RxQueueRegisters, TxQueueRegisters};
+use iommu::DmaDomain;
 
 /// The mapping flags used for pages that the NIC will map.
 pub const NIC_MAPPING_FLAGS: EntryFlags = EntryFlags::from_bits_truncate(
@@ -64,14 +67,33 @@ pub fn allocate_memory(mem_base: PhysicalAddress, mem_size_in_bytes: usize) -> R
 
 /// Initialize the receive buffer pool from where receive buffers are taken and returned
 /// 
+/// The buffers are carved out of a single mapping backed by 2MiB huge pages, which reduces TLB misses
+/// when the driver accesses many buffers; if there isn't enough contiguous physical memory for that,
+/// each buffer is mapped separately using normal pages instead.
+/// 
 /// # Arguments
 /// * `num_rx_buffers`: number of buffers that are initially added to the pool 
 /// * `buffer_size`: size of the receive buffers in bytes
 /// * `rx_buffer_pool`: buffer pool to initialize
-pub fn init_rx_buf_pool(num_rx_buffers: usize, buffer_size: u16, rx_buffer_pool: &'static mpmc::Queue<ReceiveBuffer>) -> Result<(), &'static str> {
+/// * `dma_domain`: the DMA domain of the NIC, into which each buffer is mapped,
+///    or `None` if the NIC's DMA isn't remapped.
+pub fn init_rx_buf_pool(
+    num_rx_buffers: usize,
+    buffer_size: u16,
+    rx_buffer_pool: &'static mpmc::Queue<ReceiveBuffer>,
+    dma_domain: Option<&DmaDomain>,
+) -> Result<(), &'static str> {
     let length = buffer_size;
-    for _i in 0..num_rx_buffers {
-        let (mp, phys_addr) = create_contiguous_mapping(length as usize, NIC_MAPPING_FLAGS)?; 
+    let buffers = create_huge_page_buffers(num_rx_buffers, length as usize).or_else(|_e| {
+        warn!("init_rx_buf_pool(): couldn't map rx buffers using huge pages ({}), using normal pages instead", _e);
+        (0..num_rx_buffers)
+            .map(|_| create_contiguous_mapping(length as usize, NIC_MAPPING_FLAGS))
+            .collect::<Result<Vec<_>, _>>()
+    })?;
+    for (_i, (mp, mut phys_addr)) in buffers.into_iter().enumerate() {
+        if let Some(domain) = dma_domain {
+            phys_addr = domain.map(&mp, true)?;
+        }
         let rx_buf = ReceiveBuffer::new(mp, phys_addr, length, rx_buffer_pool);
         if rx_buffer_pool.push(rx_buf).is_err() {
             // if the queue is full, it returns an Err containing the object trying to be pushed
@@ -83,6 +105,51 @@ pub fn init_rx_buf_pool(num_rx_buffers: usize, buffer_size: u16, rx_buffer_pool:
     Ok(())
 }
 
+/// Creates the buffer from which a transmit queue's NIC reads every packet sent on that queue,
+/// if the NIC's DMA is remapped, i.e., if the NIC can't read from arbitrary `TransmitBuffer`s.
+///
+/// The buffer is large enough for any `TransmitBuffer` and is mapped into the NIC's DMA domain once, here,
+/// such that sending a packet doesn't have to map and unmap its buffer.
+/// Its `phys_addr` is the IO virtual address that the NIC uses to access it.
+///
+/// # Arguments
+/// * `dma_domain`: the DMA domain of the NIC
+pub fn init_tx_buffer(dma_domain: &DmaDomain) -> Result<Option<TransmitBuffer>, &'static str> {
+    if !dma_domain.is_remapped() {
+        return Ok(None);
+    }
+    let mut tx_buffer = TransmitBuffer::new(u16::MAX)?;
+    tx_buffer.phys_addr = dma_domain.map(&tx_buffer.mp, false)?;
+    Ok(Some(tx_buffer))
+}
+
+/// Creates `num_buffers` physically-contiguous buffers of `buffer_size` bytes each
+/// by splitting a single mapping that is backed by 2MiB huge pages.
+/// 
+/// Returns each buffer's `MappedPages` along with its starting physical address.
+/// The unused remainder of the last huge page is unmapped and freed.
+fn create_huge_page_buffers(num_buffers: usize, buffer_size: usize) -> Result<Vec<(MappedPages, PhysicalAddress)>, &'static str> {
+    let pages_per_buffer = (buffer_size + PAGE_SIZE - 1) / PAGE_SIZE;
+    let total_pages = num_buffers.checked_mul(pages_per_buffer).ok_or("buffer pool size overflowed")?;
+    let huge_page_size = PageSize::Huge2M.num_4k_pages();
+    let num_huge_pages = (total_pages + huge_page_size - 1) / huge_page_size;
+
+    let pages = allocate_huge_pages(PageSize::Huge2M, num_huge_pages).ok_or("couldn't allocate huge pages")?;
+    let frames = allocate_huge_frames(PageSize::Huge2M, num_huge_pages).ok_or("couldn't allocate huge frames")?;
+    let start_paddr = frames.start_address();
+    let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("KERNEL_MMI was not yet initialized!")?;
+    let mut remaining = kernel_mmi_ref.lock().page_table.map_allocated_huge_pages_to(pages, frames, NIC_MAPPING_FLAGS, PageSize::Huge2M)?;
+
+    let mut buffers = Vec::with_capacity(num_buffers);
+    for i in 0..num_buffers {
+        let split_at = *remaining.start() + pages_per_buffer;
+        let (buffer, rest) = remaining.split(split_at).map_err(|_| "couldn't split huge page mapping into buffers")?;
+        buffers.push((buffer, start_paddr + i * pages_per_buffer * PAGE_SIZE));
+        remaining = rest;
+    }
+    Ok(buffers)
+}
+
 /// Steps to create and initialize a receive descriptor queue
 /// 
 /// # Arguments
@@ -90,13 +157,21 @@ pub fn init_rx_buf_pool(num_rx_buffers: usize, buffer_size: u16, rx_buffer_pool:
 /// * `rx_buffer_pool`: pool from which to take receive buffers
 /// * `buffer_size`: size of each buffer in the pool in bytes
 /// * `rxq_regs`: registers needed to set up a receive queue 
-pub fn init_rx_queue<T: RxDescriptor, S:RxQueueRegisters>(num_desc: usize, rx_buffer_pool: &'static mpmc::Queue<ReceiveBuffer>, buffer_size: usize, rxq_regs: &mut S)
-    -> Result<(BorrowedSliceMappedPages<T, Mutable>, Vec<ReceiveBuffer>), &'static str> 
+/// * `dma_domain`: the DMA domain of the NIC, into which the descriptors and any newly-created buffers are mapped.
+///    The buffers in `rx_buffer_pool` must have already been mapped into it.
+pub fn init_rx_queue<T: RxDescriptor, S:RxQueueRegisters>(
+    num_desc: usize,
+    rx_buffer_pool: &'static mpmc::Queue<ReceiveBuffer>,
+    buffer_size: usize,
+    rxq_regs: &mut S,
+    dma_domain: &DmaDomain,
+) -> Result<(BorrowedSliceMappedPages<T, Mutable>, Vec<ReceiveBuffer>), &'static str> 
 {    
     let size_in_bytes_of_all_rx_descs_per_queue = num_desc * core::mem::size_of::<T>();
     
     // Rx descriptors must be 128 byte-aligned, which is satisfied below because it's aligned to a page boundary.
-    let (rx_descs_mapped_pages, rx_descs_starting_phys_addr) = create_contiguous_mapping(size_in_bytes_of_all_rx_descs_per_queue, NIC_MAPPING_FLAGS)?;
+    let (rx_descs_mapped_pages, _rx_descs_phys_addr) = create_contiguous_mapping(size_in_bytes_of_all_rx_descs_per_queue, NIC_MAPPING_FLAGS)?;
+    let rx_descs_starting_phys_addr = dma_domain.map(&rx_descs_mapped_pages, true)?;
 
     // cast our physically-contiguous MappedPages into a slice of receive descriptors
     let mut rx_descs = rx_descs_mapped_pages.into_borrowed_slice_mut::<T>(0, num_desc)
@@ -110,10 +185,9 @@ pub fn init_rx_queue<T: RxDescriptor, S:RxQueueRegisters>(num_desc: usize, rx_bu
         let rx_buf = rx_buffer_pool.pop()
             .ok_or("Couldn't obtain a ReceiveBuffer from the pool")
             .or_else(|_e| {
-                create_contiguous_mapping(buffer_size, NIC_MAPPING_FLAGS)
-                    .map(|(buf_mapped, buf_paddr)| 
-                        ReceiveBuffer::new(buf_mapped, buf_paddr, buffer_size as u16, rx_buffer_pool)
-                    )
+                let (buf_mapped, _buf_paddr) = create_contiguous_mapping(buffer_size, NIC_MAPPING_FLAGS)?;
+                let buf_iova = dma_domain.map(&buf_mapped, true)?;
+                Ok::<_, &'static str>(ReceiveBuffer::new(buf_mapped, buf_iova, buffer_size as u16, rx_buffer_pool))
             })?;
         let paddr_buf = rx_buf.phys_addr;
         rx_bufs_in_use.push(rx_buf); 
@@ -145,13 +219,16 @@ pub fn init_rx_queue<T: RxDescriptor, S:RxQueueRegisters>(num_desc: usize, rx_bu
 /// # Arguments
 /// * `num_desc`: number of descriptors in the queue
 /// * `txq_regs`: registers needed to set up a transmit queue
-pub fn init_tx_queue<T: TxDescriptor, S: TxQueueRegisters>(num_desc: usize, txq_regs: &mut S) 
+/// * `dma_domain`: the DMA domain of the NIC, into which the descriptors are mapped
+pub fn init_tx_queue<T: TxDescriptor, S: TxQueueRegisters>(num_desc: usize, txq_regs: &mut S, dma_domain: &DmaDomain) 
     -> Result<BorrowedSliceMappedPages<T, Mutable>, &'static str> 
 {
     let size_in_bytes_of_all_tx_descs = num_desc * core::mem::size_of::<T>();
     
     // Tx descriptors must be 128 byte-aligned, which is satisfied below because it's aligned to a page boundary.
-    let (tx_descs_mapped_pages, tx_descs_starting_phys_addr) = create_contiguous_mapping(size_in_bytes_of_all_tx_descs, NIC_MAPPING_FLAGS)?;
+    let (tx_descs_mapped_pages, _tx_descs_phys_addr) = create_contiguous_mapping(size_in_bytes_of_all_tx_descs, NIC_MAPPING_FLAGS)?;
+    // The NIC writes back the status of each descriptor, so it must be writable.
+    let tx_descs_starting_phys_addr = dma_domain.map(&tx_descs_mapped_pages, true)?;
 
     // cast our physically-contiguous MappedPages into a slice of transmit descriptors
     let mut tx_descs = tx_descs_mapped_pages.into_borrowed_slice_mut::<T>(0, num_desc)
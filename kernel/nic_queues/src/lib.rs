@@ -13,14 +13,17 @@ extern crate alloc;
 extern crate memory;
 extern crate intel_ethernet;
 extern crate nic_buffers;
+extern crate iommu;
 
 use alloc::{
     vec::Vec,
-    collections::VecDeque
+    collections::VecDeque,
+    sync::Arc,
 };
 use memory::{create_contiguous_mapping, EntryFlags, BorrowedSliceMappedPages, Mutable};
 use intel_ethernet::descriptors::{RxDescriptor, TxDescriptor};
 use nic_buffers::{ReceiveBuffer, ReceivedFrame, TransmitBuffer};
+use iommu::DmaDomain;
 
 /// The mapping flags used for pages that the NIC will map.
 pub const NIC_MAPPING_FLAGS: EntryFlags = EntryFlags::from_bits_truncate(
@@ -79,7 +82,9 @@ pub struct RxQueue<S: RxQueueRegisters, T: RxDescriptor> {
     /// Pool where `ReceiveBuffer`s are stored.
     pub rx_buffer_pool: &'static mpmc::Queue<ReceiveBuffer>,
     /// The filter id for the physical NIC filter that is set for this queue
-    pub filter_num: Option<u8>
+    pub filter_num: Option<u8>,
+    /// The DMA domain of the NIC, into which all receive buffers must be mapped.
+    pub dma_domain: Arc<DmaDomain>,
 }
 
 impl<S: RxQueueRegisters, T: RxDescriptor> RxQueue<S,T> {
@@ -106,8 +111,9 @@ impl<S: RxQueueRegisters, T: RxDescriptor> RxQueue<S,T> {
                     warn!("NIC RX BUF POOL WAS EMPTY.... reallocating! This means that no task is consuming the accumulated received ethernet frames.");
                     // if the pool was empty, then we allocate a new receive buffer
                     let len = self.rx_buffer_size_bytes;
-                    let (mp, phys_addr) = create_contiguous_mapping(len as usize, NIC_MAPPING_FLAGS)?;
-                    ReceiveBuffer::new(mp, phys_addr, len, self.rx_buffer_pool)
+                    let (mp, _phys_addr) = create_contiguous_mapping(len as usize, NIC_MAPPING_FLAGS)?;
+                    let iova = self.dma_domain.map(&mp, true)?;
+                    ReceiveBuffer::new(mp, iova, len, self.rx_buffer_pool)
                 }
             };
 
@@ -159,7 +165,13 @@ pub struct TxQueue<S: TxQueueRegisters, T: TxDescriptor> {
     pub tx_cur: u16,
     /// The cpu which this queue is mapped to. 
     /// This in itself doesn't guarantee anything but we use this value when setting the cpu id for interrupts and DCA.
-    pub cpu_id : Option<u8>
+    pub cpu_id : Option<u8>,
+    /// The DMA domain of the NIC, into which `tx_buffer` is mapped.
+    pub dma_domain: Arc<DmaDomain>,
+    /// If the NIC's DMA is remapped, the buffer from which the NIC reads every packet sent on this queue,
+    /// which is mapped into `dma_domain` once when the queue is created (see `nic_initialization::init_tx_buffer()`).
+    /// Otherwise, the NIC reads each packet directly from the `TransmitBuffer` it was given in.
+    pub tx_buffer: Option<TransmitBuffer>,
 }
 
 impl<S: TxQueueRegisters, T: TxDescriptor> TxQueue<S,T> {
@@ -167,8 +179,18 @@ impl<S: TxQueueRegisters, T: TxDescriptor> TxQueue<S,T> {
     /// 
     /// # Arguments:
     /// * `transmit_buffer`: buffer containing the packet to be sent
-    pub fn send_on_queue(&mut self, transmit_buffer: TransmitBuffer) {
-        self.tx_descs[self.tx_cur as usize].send(transmit_buffer.phys_addr, transmit_buffer.length);  
+    pub fn send_on_queue(&mut self, transmit_buffer: TransmitBuffer) -> Result<(), &'static str> {
+        let length = transmit_buffer.length;
+        // Only one packet is sent at a time, so a single buffer mapped into the NIC's DMA domain suffices.
+        let packet_addr = match self.tx_buffer.as_mut() {
+            Some(tx_buffer) => {
+                let len = length as usize;
+                tx_buffer.as_slice_mut::<u8>(0, len)?.copy_from_slice(transmit_buffer.as_slice::<u8>(0, len)?);
+                tx_buffer.phys_addr
+            }
+            None => transmit_buffer.phys_addr,
+        };
+        self.tx_descs[self.tx_cur as usize].send(packet_addr, length);  
         // update the tx_cur value to hold the next free descriptor
         let old_cur = self.tx_cur;
         self.tx_cur = (self.tx_cur + 1) % self.num_tx_descs;
@@ -177,6 +199,7 @@ impl<S: TxQueueRegisters, T: TxDescriptor> TxQueue<S,T> {
         self.regs.set_tdt(self.tx_cur as u32);
         // Wait for the packet to be sent
         self.tx_descs[old_cur as usize].wait_for_packet_tx();
+        Ok(())
     }
 }
 
@@ -15,6 +15,7 @@ extern crate sha3;
 extern crate percent_encoding;
 extern crate rand;
 extern crate http_client;
+extern crate crate_signatures;
 extern crate itertools;
 #[macro_use] extern crate smoltcp_helper;
 
@@ -268,7 +269,10 @@ pub fn download_crates(
                     
                 if let Some(hash_value) = hash_file_str.split_whitespace().next() {
                     if verify_hash(file.content.as_result_err_str()?, hash_value) {
-                        // success! the hash matched, so the file was properly downloaded
+                        // success! the hash matched, so the file was properly downloaded.
+                        // Now check that it was built by a trusted party, as the hash only guarantees its integrity.
+                        crate_signatures::verify_crate_object(file.content.as_result_err_str()?, &file.name)
+                            .map_err(|_e| "ota_update_client: downloaded file's signature couldn't be verified")?;
                     } else {
                         error!("ota_update_client: downloaded file {:?} did not match the expected hash value! Try downloading it again.", file.name);
                         return Err("ota_update_client: downloaded file did not match the expected hash value");
@@ -23,6 +23,7 @@ extern crate memory_structs;
 extern crate spin;
 #[macro_use] extern crate static_assertions;
 extern crate intrusive_collections;
+extern crate memory_accounting;
 use intrusive_collections::Bound;
 
 
@@ -32,7 +33,7 @@ mod static_array_rb_tree;
 
 use core::{borrow::Borrow, cmp::Ordering, fmt, ops::{Deref, DerefMut}};
 use kernel_config::memory::*;
-use memory_structs::{VirtualAddress, Page, PageRange};
+use memory_structs::{VirtualAddress, Page, PageRange, PageSize};
 use spin::{Mutex, Once};
 use static_array_rb_tree::*;
 
@@ -276,6 +277,7 @@ impl Drop for AllocatedPages {
     fn drop(&mut self) {
 		if self.size_in_pages() == 0 { return; }
 		// trace!("page_allocator: deallocating {:?}", self);
+		memory_accounting::uncharge_pages(self.size_in_pages());
 
 		// Simply add the newly-deallocated chunk to the free pages list.
 		let mut locked_list = FREE_PAGE_LIST.lock();
@@ -430,7 +432,8 @@ fn find_specific_chunk(
 /// and only allocates from the designated regions as a backup option.
 fn find_any_chunk<'list>(
 	list: &'list mut StaticArrayRBTree<Chunk>,
-	num_pages: usize
+	num_pages: usize,
+	alignment: usize,
 ) -> Result<(AllocatedPages, DeferredAllocAction<'static>), AllocationError> {
 	let designated_low_end = DESIGNATED_PAGES_LOW_END.get().ok_or(AllocationError::NotInitialized)?;
 
@@ -446,8 +449,8 @@ fn find_any_chunk<'list>(
 					{
 						continue;
 					} 
-					else {
-						return adjust_chosen_chunk(*chunk.start(), num_pages, &chunk.clone(), ValueRefMut::Array(elem));
+					if let Some(start_page) = aligned_start_within(chunk, num_pages, alignment) {
+						return adjust_chosen_chunk(start_page, num_pages, &chunk.clone(), ValueRefMut::Array(elem));
 					}
 				}
 			}
@@ -473,7 +476,9 @@ fn find_any_chunk<'list>(
 					break; // move on to searching through the designated regions
 				}
 				if num_pages < chunk.size_in_pages() {
-					return adjust_chosen_chunk(*chunk.start(), num_pages, &chunk.clone(), ValueRefMut::RBTree(cursor));
+					if let Some(start_page) = aligned_start_within(chunk, num_pages, alignment) {
+						return adjust_chosen_chunk(start_page, num_pages, &chunk.clone(), ValueRefMut::RBTree(cursor));
+					}
 				}
 				warn!("Page allocator: unlikely scenario: had to search multiple chunks while trying to allocate {} pages at any address.", num_pages);
 				cursor.move_prev();
@@ -488,8 +493,8 @@ fn find_any_chunk<'list>(
 		Inner::Array(ref mut arr) => {
 			for elem in arr.iter_mut() {
 				if let Some(chunk) = elem {
-					if num_pages <= chunk.size_in_pages() {
-						return adjust_chosen_chunk(*chunk.start(), num_pages, &chunk.clone(), ValueRefMut::Array(elem));
+					if let Some(start_page) = aligned_start_within(chunk, num_pages, alignment) {
+						return adjust_chosen_chunk(start_page, num_pages, &chunk.clone(), ValueRefMut::Array(elem));
 					}
 				}
 			}
@@ -512,7 +517,9 @@ fn find_any_chunk<'list>(
 			let mut cursor = tree.upper_bound_mut(Bound::Included(designated_low_end));
 			while let Some(chunk) = cursor.get().map(|w| w.deref()) {
 				if num_pages < chunk.size_in_pages() {
-					return adjust_chosen_chunk(*chunk.start(), num_pages, &chunk.clone(), ValueRefMut::RBTree(cursor));
+					if let Some(start_page) = aligned_start_within(chunk, num_pages, alignment) {
+						return adjust_chosen_chunk(start_page, num_pages, &chunk.clone(), ValueRefMut::RBTree(cursor));
+					}
 				}
 				cursor.move_prev();
 			}
@@ -525,7 +532,9 @@ fn find_any_chunk<'list>(
 					break; 
 				}
 				if num_pages < chunk.size_in_pages() {
-					return adjust_chosen_chunk(*chunk.start(), num_pages, &chunk.clone(), ValueRefMut::RBTree(cursor));
+					if let Some(start_page) = aligned_start_within(chunk, num_pages, alignment) {
+						return adjust_chosen_chunk(start_page, num_pages, &chunk.clone(), ValueRefMut::RBTree(cursor));
+					}
 				}
 				cursor.move_prev();
 			}
@@ -536,6 +545,20 @@ fn find_any_chunk<'list>(
 }
 
 
+/// Returns the first `Page` in the given `chunk` that is aligned to `alignment` pages
+/// and from which `num_pages` pages fit within the remainder of that `chunk`.
+fn aligned_start_within(chunk: &Chunk, num_pages: usize, alignment: usize) -> Option<Page> {
+	let start = chunk.start().number();
+	let aligned_start = start.checked_add((alignment - start % alignment) % alignment)?;
+	let aligned_end = aligned_start.checked_add(num_pages - 1)?;
+	if aligned_end <= chunk.end().number() {
+		Some(*chunk.start() + (aligned_start - start))
+	} else {
+		None
+	}
+}
+
+
 /// The final part of the main allocation routine. 
 ///
 /// The given chunk is the one we've chosen to allocate from. 
@@ -634,11 +657,36 @@ pub fn allocate_pages_deferred(
 	// - Can fit the requested size (starting at the requested address) within the chunk.
 	// - The chunk can only be within in a designated region if a specific address was requested, 
 	//   or all other non-designated chunks are already in use.
-	if let Some(vaddr) = requested_vaddr {
+	let result = if let Some(vaddr) = requested_vaddr {
 		find_specific_chunk(&mut locked_list, Page::containing_address(vaddr), num_pages)
 	} else {
-		find_any_chunk(&mut locked_list, num_pages)
-	}.map_err(From::from) // convert from AllocationError to &str
+		find_any_chunk(&mut locked_list, num_pages, 1)
+	}.map_err(From::from); // convert from AllocationError to &str
+
+	if result.is_ok() {
+		memory_accounting::charge_pages(num_pages);
+	}
+	result
+}
+
+
+/// Allocates `num_huge_pages` contiguous huge pages of the given `page_size`,
+/// i.e., a range of virtual pages whose starting address is aligned to that `page_size`.
+///
+/// The returned `AllocatedPages` span `num_huge_pages * page_size.num_4k_pages()` normal pages,
+/// and are intended to be mapped using huge page mappings.
+/// See [`allocate_pages_deferred()`](fn.allocate_pages_deferred.html) for more details. 
+pub fn allocate_huge_pages(page_size: PageSize, num_huge_pages: usize) -> Option<AllocatedPages> {
+	if num_huge_pages == 0 {
+		warn!("PageAllocator: requested an allocation of 0 huge pages... stupid!");
+		return None;
+	}
+	let num_pages = num_huge_pages.checked_mul(page_size.num_4k_pages())?;
+	// The lock must be released before the deferred action is dropped, as that re-acquires it.
+	let allocation = find_any_chunk(&mut FREE_PAGE_LIST.lock(), num_pages, page_size.num_4k_pages());
+	let (allocated_pages, _action) = allocation.ok()?;
+	memory_accounting::charge_pages(num_pages);
+	Some(allocated_pages)
 }
 
 
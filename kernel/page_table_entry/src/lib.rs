@@ -13,7 +13,7 @@
 #![no_std]
 
 use core::ops::Deref;
-use memory_structs::{Frame, FrameRange, PAGE_TABLE_ENTRY_FRAME_MASK, EntryFlags, PhysicalAddress};
+use memory_structs::{Frame, FrameRange, PageSize, PAGE_TABLE_ENTRY_FRAME_MASK, EntryFlags, PhysicalAddress};
 use bit_field::BitField;
 use kernel_config::memory::PAGE_SHIFT;
 use zerocopy::FromBytes;
@@ -48,15 +48,27 @@ impl PageTableEntry {
     /// i.e., owned by this entry and not mapped anywhere else by any other entries,
     /// then this function returns those frames.
     /// This is useful because those returned frames can then be safely deallocated.
+    ///
+    /// This must only be used on a P1 entry, which maps a single 4KiB frame;
+    /// use [`PageTableEntry::set_unmapped_huge()`] for P2 or P3 entries that map huge pages.
     pub fn set_unmapped(&mut self) -> UnmapResult {
+        self.set_unmapped_huge(PageSize::Normal4K)
+    }
+
+    /// Removes the mapping represented by this page table entry,
+    /// which maps a single page of the given `page_size`.
+    ///
+    /// The page size cannot be determined from the entry itself,
+    /// because it depends on the level of the page table that contains this entry:
+    /// a P2 entry maps a 2MiB huge page and a P3 entry maps a 1GiB huge page.
+    ///
+    /// See [`PageTableEntry::set_unmapped()`] for more details about the returned frames.
+    pub fn set_unmapped_huge(&mut self, page_size: PageSize) -> UnmapResult {
         let frame = self.frame_value();
         let flags = self.flags();
         self.zero();
 
-        // Since we don't support huge pages, this PTE can only cover one 4KiB frame.
-        // Once we support huge pages, we can use a type parameter
-        // to specify whether this is a 4KiB, 2MiB, or 1GiB PTE.
-        let frame_range = FrameRange::new(frame, frame);
+        let frame_range = FrameRange::new(frame, frame + (page_size.num_4k_pages() - 1));
         if flags.is_exclusive() {
             UnmapResult::Exclusive(UnmappedFrames(frame_range))
         } else {
@@ -4,11 +4,10 @@
 #![feature(fn_traits)]
 
 // extern crate alloc;
-#[macro_use] extern crate lazy_static;
 extern crate port_io;
 extern crate irq_safety;
 extern crate spin;
-extern crate state_store;
+extern crate state_transfer;
 #[macro_use] extern crate log;
 extern crate x86_64;
 
@@ -17,7 +16,7 @@ use irq_safety::hold_interrupts;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::Mutex;
 // use spin::Once;
-use state_store::{get_state, insert_state, SSCached};
+use state_transfer::{CachedState, VersionedState};
 
 
 //standard port to write to on CMOS to select registers
@@ -33,13 +32,13 @@ static CMOS_WRITE_SETTINGS: Mutex<Port<u8>> = Mutex::new(Port::new(CMOS_READ_POR
 static CMOS_READ: Mutex<Port<u8>> = Mutex::new( Port::new(CMOS_READ_PORT));
 
 
-type RtcTicks = AtomicUsize;
-lazy_static! {
-    static ref RTC_TICKS: SSCached<RtcTicks> = {
-        insert_state(RtcTicks::new(0));
-        get_state::<RtcTicks>()
-    };
+/// The number of RTC ticks, which is kept in the `state_transfer` registry such that it survives swapping this crate.
+struct RtcTicks(AtomicUsize);
+unsafe impl VersionedState for RtcTicks {
+    const NAME: &'static str = "rtc::RtcTicks";
+    const VERSION: u32 = 1;
 }
+static RTC_TICKS: CachedState<RtcTicks> = CachedState::new();
 
 pub type RtcInterruptFunction = fn(Option<usize>);
 
@@ -134,12 +133,9 @@ pub fn read_rtc() -> RtcTime {
 }
 
 pub fn get_rtc_ticks() -> Result<usize, ()> {
-     if let Some(ticks) = RTC_TICKS.get() {
-         Ok(ticks.load(Ordering::Acquire))
-     }
-     else {
-        Err(())
-     }
+    RTC_TICKS.get_or_register(|| RtcTicks(AtomicUsize::new(0)))
+        .map(|ticks| ticks.0.load(Ordering::Acquire))
+        .map_err(|_e| ())
 }
 
 
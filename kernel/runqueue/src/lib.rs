@@ -38,6 +38,14 @@ pub fn get_runqueue(which_core: u8) -> Option<&'static RwLockPreempt<RunQueue>>
     RunQueue::get_runqueue(which_core)
 }
 
+/// Returns `true` if the runqueue of the given core, which is an `apic_id`,
+/// contains a runnable task other than that core's idle task.
+pub fn has_runnable_task(which_core: u8) -> bool {
+    get_runqueue(which_core).map_or(false, |rq| 
+        rq.read().iter().any(|t| !t.is_an_idle_task && t.is_runnable())
+    )
+}
+
 /// Returns the "least busy" core
 pub fn get_least_busy_core() -> Option<u8> {
     RunQueue::get_least_busy_core()
@@ -10,8 +10,20 @@ cfg_if::cfg_if! {
     }
 }
 
+use core::sync::atomic::{AtomicU64, Ordering};
 use task::TaskRef;
 
+/// The maximum number of CPUs, which is the number of possible APIC IDs.
+const MAX_CPUS: usize = u8::MAX as usize + 1;
+
+/// The TSC value at which each CPU last invoked the scheduler,
+/// used to account for the CPU time consumed by each task group
+/// and to detect CPUs that have stopped scheduling.
+static LAST_SCHEDULED: [AtomicU64; MAX_CPUS] = {
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; MAX_CPUS]
+};
+
 /// Yields the current CPU by selecting a new `Task` to run 
 /// and then switching to that new `Task`.
 ///
@@ -32,6 +44,7 @@ pub fn schedule() -> bool {
     }
 
     let apic_id = preemption_guard.apic_id();
+    account_cpu_time(apic_id);
 
     let Some(next_task) = scheduler::select_next_task(apic_id) else {
         return false; // keep running the same current task
@@ -49,6 +62,30 @@ pub fn schedule() -> bool {
     did_switch
 }
 
+/// Returns the TSC value at which the given CPU last invoked the scheduler
+/// with preemption enabled, or `None` if it has never done so.
+pub fn last_scheduled(apic_id: u8) -> Option<u64> {
+    match LAST_SCHEDULED[apic_id as usize].load(Ordering::Relaxed) {
+        0 => None,
+        tsc => Some(tsc),
+    }
+}
+
+/// Charges the CPU time that the current task has run for since the last scheduler invocation
+/// on this CPU to the current task's group, if it belongs to one.
+fn account_cpu_time(apic_id: u8) {
+    let now = u128::from(tsc::tsc_ticks()) as u64;
+    let last = LAST_SCHEDULED[apic_id as usize].swap(now, Ordering::Relaxed);
+    if last == 0 {
+        return;
+    }
+    let _ = task::with_current_task(|t| {
+        if let Some(group) = t.group() {
+            group.account_cpu_time(now.saturating_sub(last));
+        }
+    });
+}
+
 /// Changes the priority of the given task with the given priority level.
 /// Priority values must be between 40 (maximum priority) and 0 (minimum prriority).
 /// This function returns an error when a scheduler without priority is loaded. 
@@ -94,6 +94,11 @@ fn select_next_task_priority(apic_id: u8) -> Option<NextTaskResult>  {
             continue;
         }
 
+        // must not be in a task group that has exhausted its CPU share
+        if t.group().map_or(false, |g| g.is_cpu_throttled()) {
+            continue;
+        }
+
         // if this task is pinned, it must not be pinned to a different core
         if let Some(pinned) = t.pinned_core() {
             if pinned != apic_id {
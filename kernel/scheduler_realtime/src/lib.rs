@@ -45,6 +45,11 @@ pub fn select_next_task(apic_id: u8) -> Option<TaskRef> {
             continue;
         }
 
+        // must not be in a task group that has exhausted its CPU share
+        if t.group().map_or(false, |g| g.is_cpu_throttled()) {
+            continue;
+        }
+
         // found a runnable task
         chosen_task_index = Some(i);
         break;
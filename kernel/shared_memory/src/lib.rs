@@ -0,0 +1,166 @@
+//! Named shared memory regions that can be mapped by multiple tasks, for zero-copy IPC.
+//!
+//! A [`SharedMemory`] object is a reference-counted set of physical frames with a unique name,
+//! through which other tasks (possibly in other namespaces) can [`open`] it.
+//! Each task maps the shared frames into its own [`SharedMapping`] with whichever flags it needs,
+//! e.g., read-only for a task that should only observe the shared contents.
+//! The frames are deallocated once every `SharedMemory` handle and every `SharedMapping` is dropped.
+//!
+//! The [`ring`] module builds a lock-free single-producer single-consumer ring buffer
+//! on top of a shared memory region, in which messages are written and read in place.
+//!
+//! [`open`]: SharedMemory::open
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod ring;
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::{Arc, Weak},
+};
+use core::ops::{Deref, DerefMut};
+use memory::{AllocatedFrames, EntryFlags, MappedPages, Mapper};
+use spin::Mutex;
+
+/// All existing shared memory objects, by name.
+static SHARED_MEMORY_OBJECTS: Mutex<BTreeMap<String, Weak<SharedFrames>>> = Mutex::new(BTreeMap::new());
+
+/// The frames backing a shared memory object, which are deallocated when this is dropped.
+struct SharedFrames {
+    name: String,
+    frames: AllocatedFrames,
+}
+
+impl Drop for SharedFrames {
+    fn drop(&mut self) {
+        let mut objects = SHARED_MEMORY_OBJECTS.lock();
+        // The name may have been reused by a newer object since this one's last handle was dropped.
+        if objects.get(&self.name).map_or(false, |weak| weak.strong_count() == 0) {
+            objects.remove(&self.name);
+        }
+    }
+}
+
+/// A handle to a named, reference-counted region of shared physical memory.
+///
+/// Cloning a `SharedMemory` handle is cheap and does not copy the underlying memory.
+#[derive(Clone)]
+pub struct SharedMemory(Arc<SharedFrames>);
+
+impl SharedMemory {
+    /// Creates a new zero-filled shared memory object of at least `size_in_bytes`,
+    /// which others can then open using the given `name`.
+    ///
+    /// Returns an error if a shared memory object with that `name` already exists.
+    pub fn create(name: &str, size_in_bytes: usize) -> Result<SharedMemory, &'static str> {
+        if size_in_bytes == 0 {
+            return Err("cannot create an empty shared memory object");
+        }
+        let frames = memory::allocate_frames_by_bytes(size_in_bytes)
+            .ok_or("couldn't allocate frames for the shared memory object")?;
+        let shm = SharedMemory(Arc::new(SharedFrames {
+            name: name.to_string(),
+            frames,
+        }));
+        // The newly-allocated frames may contain stale data from a previous user.
+        shm.map(EntryFlags::WRITABLE)?.as_slice_mut::<u8>(0, shm.size_in_bytes())?.fill(0);
+
+        // Note: the new `shm` is dropped after this lock guard upon returning an error,
+        //       which is required because dropping it acquires this lock.
+        let mut objects = SHARED_MEMORY_OBJECTS.lock();
+        if objects.get(name).map_or(false, |weak| weak.strong_count() > 0) {
+            return Err("a shared memory object with that name already exists");
+        }
+        objects.insert(name.to_string(), Arc::downgrade(&shm.0));
+        Ok(shm)
+    }
+
+    /// Returns a new handle to the existing shared memory object with the given `name`, if any.
+    pub fn open(name: &str) -> Option<SharedMemory> {
+        SHARED_MEMORY_OBJECTS.lock().get(name)
+            .and_then(Weak::upgrade)
+            .map(SharedMemory)
+    }
+
+    /// Returns the name of this shared memory object.
+    pub fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    /// Returns the size in bytes of this shared memory object,
+    /// which is its requested size rounded up to a multiple of the page size.
+    pub fn size_in_bytes(&self) -> usize {
+        self.0.frames.size_in_frames() * memory::PAGE_SIZE
+    }
+
+    /// Returns the number of handles to and mappings of this shared memory object.
+    pub fn reference_count(&self) -> usize {
+        Arc::strong_count(&self.0)
+    }
+
+    /// Maps this shared memory object into the kernel's page table with the given `flags`.
+    ///
+    /// See [`SharedMemory::map_into()`].
+    pub fn map(&self, flags: EntryFlags) -> Result<SharedMapping, &'static str> {
+        let kernel_mmi_ref = memory::get_kernel_mmi_ref().ok_or("KERNEL_MMI was not yet initialized!")?;
+        let mut kernel_mmi = kernel_mmi_ref.lock();
+        self.map_into(&mut kernel_mmi.page_table, flags)
+    }
+
+    /// Maps this shared memory object into the page table of the given `mapper` with the given `flags`.
+    ///
+    /// Each mapping has its own virtual address and can use different flags,
+    /// but all mappings access the same underlying frames.
+    /// The returned `SharedMapping` keeps this shared memory object alive.
+    pub fn map_into(&self, mapper: &mut Mapper, flags: EntryFlags) -> Result<SharedMapping, &'static str> {
+        let pages = memory::allocate_pages(self.0.frames.size_in_frames())
+            .ok_or("couldn't allocate pages for the shared memory mapping")?;
+        let mapped_pages = mapper.map_allocated_pages_to_shared(pages, &self.0.frames, flags)?;
+        Ok(SharedMapping {
+            mapped_pages,
+            shm: self.clone(),
+        })
+    }
+}
+
+impl core::fmt::Debug for SharedMemory {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("SharedMemory")
+            .field("name", &self.0.name)
+            .field("frames", &self.0.frames)
+            .finish()
+    }
+}
+
+/// A mapping of a [`SharedMemory`] object, which can be accessed like any other `MappedPages`.
+#[derive(Debug)]
+pub struct SharedMapping {
+    // Note: the mapped pages must be dropped (unmapped) before the shared frames can be deallocated,
+    //       which is ensured by the order of these fields.
+    mapped_pages: MappedPages,
+    shm: SharedMemory,
+}
+
+impl SharedMapping {
+    /// Returns the shared memory object that this is a mapping of.
+    pub fn shared_memory(&self) -> &SharedMemory {
+        &self.shm
+    }
+}
+
+impl Deref for SharedMapping {
+    type Target = MappedPages;
+    fn deref(&self) -> &MappedPages {
+        &self.mapped_pages
+    }
+}
+
+impl DerefMut for SharedMapping {
+    fn deref_mut(&mut self) -> &mut MappedPages {
+        &mut self.mapped_pages
+    }
+}
@@ -0,0 +1,232 @@
+//! A lock-free single-producer single-consumer ring buffer within a shared memory region.
+//!
+//! The sender creates a named ring with [`RingSender::create()`], and the receiver attaches to it
+//! by name with [`RingReceiver::open()`], each in its own mapping of the underlying [`SharedMemory`].
+//! Messages are written directly into a slot of the shared memory by the sender
+//! and read directly from that slot by the receiver, so they are never copied or allocated on the heap.
+//!
+//! The shared memory region begins with a header, followed by a fixed number of slots.
+//! Each slot holds the length of its message followed by up to `slot_size` bytes of message data.
+//! The `head` and `tail` counters in the header count the messages that have been sent and received,
+//! respectively, so the ring is empty when they're equal and full when they differ by the number of slots.
+
+use core::{
+    mem::size_of,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use memory::EntryFlags;
+use crate::{SharedMapping, SharedMemory};
+
+/// A value that identifies a shared memory region as containing a ring buffer.
+const RING_MAGIC: u64 = 0x5348_4D52_494E_4721; // "SHMRING!"
+/// The size of a cache line, which separates the sender's and receiver's counters to avoid false sharing.
+const CACHE_LINE_SIZE: usize = 64;
+/// The size of the length field at the beginning of each slot.
+const SLOT_LENGTH_SIZE: usize = size_of::<u64>();
+
+/// The header at the beginning of a ring buffer's shared memory region.
+#[repr(C, align(64))]
+struct RingHeader {
+    magic: u64,
+    slot_size: usize,
+    num_slots: usize,
+    receiver_attached: AtomicBool,
+    /// The number of messages sent so far, which is only written by the sender.
+    head: CacheAligned<AtomicUsize>,
+    /// The number of messages received so far, which is only written by the receiver.
+    tail: CacheAligned<AtomicUsize>,
+}
+
+#[repr(C, align(64))]
+struct CacheAligned<T>(T);
+
+const _: () = assert!(core::mem::align_of::<RingHeader>() == CACHE_LINE_SIZE);
+
+/// Returns the distance between the start of two consecutive slots.
+fn slot_stride(slot_size: usize) -> usize {
+    (SLOT_LENGTH_SIZE + slot_size + CACHE_LINE_SIZE - 1) / CACHE_LINE_SIZE * CACHE_LINE_SIZE
+}
+
+/// The state common to both ends of a ring buffer.
+struct Ring {
+    mapping: SharedMapping,
+    slot_size: usize,
+    num_slots: usize,
+}
+
+impl Ring {
+    fn header(&self) -> &RingHeader {
+        // SAFETY: the mapping is page-aligned and at least as large as the header, which was initialized
+        //         upon creation, and all of the header's fields that are modified after creation are atomic.
+        unsafe { &*(self.mapping.start_address().value() as *const RingHeader) }
+    }
+
+    /// Returns the byte offset of the slot used by the message with the given sequence number.
+    fn slot_offset(&self, sequence: usize) -> usize {
+        size_of::<RingHeader>() + (sequence % self.num_slots) * slot_stride(self.slot_size)
+    }
+}
+
+/// The sending end of a shared memory ring buffer.
+pub struct RingSender {
+    ring: Ring,
+}
+
+impl RingSender {
+    /// Creates a new ring buffer in a new shared memory object with the given `name`,
+    /// which can hold `num_slots` messages of up to `slot_size` bytes each.
+    pub fn create(name: &str, slot_size: usize, num_slots: usize) -> Result<RingSender, &'static str> {
+        if slot_size == 0 || num_slots == 0 {
+            return Err("a shared memory ring must have a non-zero slot size and number of slots");
+        }
+        let size_in_bytes = size_of::<RingHeader>() + num_slots * slot_stride(slot_size);
+        let shm = SharedMemory::create(name, size_in_bytes)?;
+        let mapping = shm.map(EntryFlags::WRITABLE)?;
+        // SAFETY: the mapping is writable, page-aligned, and large enough for the header,
+        //         and no one else can access the ring before it's initialized.
+        unsafe {
+            (mapping.start_address().value() as *mut RingHeader).write(RingHeader {
+                magic: RING_MAGIC,
+                slot_size,
+                num_slots,
+                receiver_attached: AtomicBool::new(false),
+                head: CacheAligned(AtomicUsize::new(0)),
+                tail: CacheAligned(AtomicUsize::new(0)),
+            });
+        }
+        Ok(RingSender { ring: Ring { mapping, slot_size, num_slots } })
+    }
+
+    /// Returns the maximum size of a single message.
+    pub fn slot_size(&self) -> usize {
+        self.ring.slot_size
+    }
+
+    /// Attempts to send one message by invoking `write_message` on the next free slot,
+    /// which writes the message directly into the slot and returns the message's length.
+    ///
+    /// Returns an error without invoking `write_message` if the ring is full.
+    pub fn try_send_with<F: FnOnce(&mut [u8]) -> usize>(&mut self, write_message: F) -> Result<(), &'static str> {
+        let header = self.ring.header();
+        let head = header.head.0.load(Ordering::Relaxed);
+        if head.wrapping_sub(header.tail.0.load(Ordering::Acquire)) >= self.ring.num_slots {
+            return Err("the shared memory ring is full");
+        }
+
+        let offset = self.ring.slot_offset(head);
+        let slot_size = self.ring.slot_size;
+        let data = self.ring.mapping.as_slice_mut::<u8>(offset + SLOT_LENGTH_SIZE, slot_size)?;
+        let length = write_message(data).min(slot_size);
+        *self.ring.mapping.as_type_mut::<u64>(offset)? = length as u64;
+
+        // Publish the message, which must happen after its contents have been written.
+        self.ring.header().head.0.store(head.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Sends one message as in [`RingSender::try_send_with()`], yielding the CPU until the ring has a free slot.
+    pub fn send_with<F: FnOnce(&mut [u8]) -> usize>(&mut self, write_message: F) {
+        while self.is_full() {
+            scheduler::schedule();
+        }
+        // The receiver can only free up more slots, so this cannot fail.
+        let _ = self.try_send_with(write_message);
+    }
+
+    /// Attempts to send a copy of the given `message`, which must fit within one slot.
+    pub fn try_send(&mut self, message: &[u8]) -> Result<(), &'static str> {
+        if message.len() > self.ring.slot_size {
+            return Err("the message is larger than the shared memory ring's slot size");
+        }
+        self.try_send_with(|slot| {
+            slot[..message.len()].copy_from_slice(message);
+            message.len()
+        })
+    }
+
+    /// Returns `true` if the ring has no free slots.
+    pub fn is_full(&self) -> bool {
+        let header = self.ring.header();
+        header.head.0.load(Ordering::Relaxed).wrapping_sub(header.tail.0.load(Ordering::Acquire)) >= self.ring.num_slots
+    }
+}
+
+/// The receiving end of a shared memory ring buffer.
+pub struct RingReceiver {
+    ring: Ring,
+}
+
+impl RingReceiver {
+    /// Attaches to the existing ring buffer in the shared memory object with the given `name`.
+    ///
+    /// Only one receiver can be attached to a ring buffer at a time.
+    pub fn open(name: &str) -> Result<RingReceiver, &'static str> {
+        let shm = SharedMemory::open(name).ok_or("no shared memory object with that name exists")?;
+        if shm.size_in_bytes() < size_of::<RingHeader>() {
+            return Err("the shared memory object is too small to contain a ring");
+        }
+        let mapping = shm.map(EntryFlags::WRITABLE)?;
+        let (slot_size, num_slots) = {
+            let header: &u64 = mapping.as_type(0)?;
+            if *header != RING_MAGIC {
+                return Err("the shared memory object does not contain a ring");
+            }
+            let sizes: &[usize] = mapping.as_slice(size_of::<u64>(), 2)?;
+            (sizes[0], sizes[1])
+        };
+        let slots_fit = slot_size.checked_add(SLOT_LENGTH_SIZE + CACHE_LINE_SIZE)
+            .and_then(|_| num_slots.checked_mul(slot_stride(slot_size)))
+            .map_or(false, |slots_size| size_of::<RingHeader>() + slots_size <= shm.size_in_bytes());
+        if slot_size == 0 || num_slots == 0 || !slots_fit {
+            return Err("the shared memory ring's slots exceed its shared memory object");
+        }
+
+        let ring = Ring { mapping, slot_size, num_slots };
+        if ring.header().receiver_attached.swap(true, Ordering::AcqRel) {
+            return Err("another receiver is already attached to the shared memory ring");
+        }
+        Ok(RingReceiver { ring })
+    }
+
+    /// Attempts to receive one message by invoking `read_message` on it, directly within its slot,
+    /// and returns the result of `read_message`.
+    ///
+    /// Returns `None` without invoking `read_message` if the ring is empty.
+    pub fn try_receive_with<R, F: FnOnce(&[u8]) -> R>(&mut self, read_message: F) -> Option<R> {
+        let header = self.ring.header();
+        let tail = header.tail.0.load(Ordering::Relaxed);
+        if header.head.0.load(Ordering::Acquire) == tail {
+            return None;
+        }
+
+        let offset = self.ring.slot_offset(tail);
+        let length = (*self.ring.mapping.as_type::<u64>(offset).ok()? as usize).min(self.ring.slot_size);
+        let data = self.ring.mapping.as_slice::<u8>(offset + SLOT_LENGTH_SIZE, length).ok()?;
+        let result = read_message(data);
+
+        // Release the slot, which must happen after its contents have been read.
+        self.ring.header().tail.0.store(tail.wrapping_add(1), Ordering::Release);
+        Some(result)
+    }
+
+    /// Receives one message as in [`RingReceiver::try_receive_with()`], yielding the CPU until a message arrives.
+    pub fn receive_with<R, F: FnOnce(&[u8]) -> R>(&mut self, read_message: F) -> R {
+        while self.is_empty() {
+            scheduler::schedule();
+        }
+        // The sender can only add more messages, so this cannot fail.
+        self.try_receive_with(read_message).expect("BUG: shared memory ring was unexpectedly empty")
+    }
+
+    /// Returns `true` if the ring has no messages waiting to be received.
+    pub fn is_empty(&self) -> bool {
+        let header = self.ring.header();
+        header.head.0.load(Ordering::Acquire) == header.tail.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for RingReceiver {
+    fn drop(&mut self) {
+        self.ring.header().receiver_attached.store(false, Ordering::Release);
+    }
+}
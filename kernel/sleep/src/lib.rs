@@ -1,42 +1,97 @@
 //! Provides APIs for tasks to sleep for specified time durations.
 //!
 //! Key functions:
-//! * The [`sleep`] function delays the current task for a given number of ticks.
+//! * The [`sleep`] function delays the current task for a given [`Duration`].
+//! * The [`sleep_ticks`] function delays the current task for a given number of ticks.
 //! * The [`sleep_until`] function delays the current task until a specific moment in the future.
 //! * The [`sleep_periodic`] function allows for tasks to be delayed for periodic intervals
 //!  of time and can be used to implement a period task.
+//! * The [`sleep_async`] function returns a [`Future`] that completes after a given [`Duration`],
+//!  which can be `.await`ed without blocking the current task.
 //!
-//! TODO: use regular time-keeping abstractions like Duration and Instant.
+//! Time is measured using the TSC, and a "tick" is one timeslice of
+//! [`CONFIG_TIMESLICE_PERIOD_MICROSECONDS`].
+//! If the TSC frequency can't be calibrated, time is instead measured by counting
+//! the periodic local timer interrupts, so all sleeps have tick granularity
+//! and the `tickless` config option has no effect.
+//! By default, the local APIC timer fires periodically once per tick,
+//! so sleeping tasks are only woken up at tick granularity.
+//! With the `tickless` config option, the local APIC timer runs in one-shot mode instead,
+//! which allows sleeping tasks to be woken up with microsecond precision;
+//! see the [`tick`] module for more details.
 
 #![no_std]
+#[macro_use] extern crate log;
 extern crate task;
 extern crate irq_safety;
 extern crate alloc;
 #[macro_use] extern crate lazy_static;
 extern crate scheduler;
+extern crate apic;
+extern crate tsc;
+extern crate kernel_config;
 
-use core::sync::atomic::{Ordering, AtomicUsize};
+pub mod tick;
+
+pub use core::time::Duration;
+
+use core::sync::atomic::{Ordering, AtomicBool, AtomicU64, AtomicUsize};
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
 use alloc::collections::binary_heap::BinaryHeap;
 use irq_safety::MutexIrqSafe;
 use task::{get_my_current_task, TaskRef, RunState};
+use kernel_config::time::CONFIG_TIMESLICE_PERIOD_MICROSECONDS;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+const MICROS_PER_SEC: u128 = 1_000_000;
+
+/// An entity that is sleeping: either a blocked `Task` or the `Waker` of a pending [`Sleep`] future.
+#[derive(Clone)]
+enum Sleeper {
+    Task(TaskRef),
+    Waker(Waker),
+}
 
-/// Contains the `TaskRef` and the associated wakeup time for an entry in DELAYED_TASKLIST.
-#[derive(Clone, Eq, PartialEq)]
+/// Contains the sleeping entity and the associated wakeup time for an entry in DELAYED_TASKLIST.
+#[derive(Clone)]
 struct SleepingTaskNode {
-    resume_time: usize,
-    taskref: TaskRef,
+    /// The clock value at which this task should be woken up.
+    resume_time: u64,
+    /// A unique ID for this node, used to break ties between equal resume times.
+    id: usize,
+    sleeper: Sleeper,
 }
 
+impl SleepingTaskNode {
+    fn new(resume_time: u64, sleeper: Sleeper) -> SleepingTaskNode {
+        static NODE_ID_COUNTER: AtomicUsize = AtomicUsize::new(0);
+        SleepingTaskNode {
+            resume_time,
+            id: NODE_ID_COUNTER.fetch_add(1, Ordering::Relaxed),
+            sleeper,
+        }
+    }
+}
+
+impl PartialEq for SleepingTaskNode {
+    fn eq(&self, other: &Self) -> bool {
+        self.resume_time == other.resume_time && self.id == other.id
+    }
+}
+impl Eq for SleepingTaskNode { }
+
 // The priority queue depends on `Ord`.
 // Explicitly implement the trait so the queue becomes a min-heap
 // instead of a max-heap.
 impl Ord for SleepingTaskNode {
     fn cmp(&self, other: &Self) -> core::cmp::Ordering {
         // Notice that the we flip the ordering on resume_time.
-        // In case of a tie we compare taskids - this step is necessary
+        // In case of a tie we compare node IDs - this step is necessary
         // to make implementations of `PartialEq` and `Ord` consistent.
         other.resume_time.cmp(&self.resume_time)
-            .then_with(|| self.taskref.id.cmp(&other.taskref.id))
+            .then_with(|| self.id.cmp(&other.id))
     }
 }
 
@@ -54,25 +109,104 @@ lazy_static! {
         = MutexIrqSafe::new(BinaryHeap::new());
 }
 
-/// Keeps track of the next task that needs to unblock, by default, it is the maximum time
-static NEXT_DELAYED_TASK_UNBLOCK_TIME : AtomicUsize = AtomicUsize::new(usize::MAX);
+/// Keeps track of the clock value at which the next task needs to unblock;
+/// by default, it is the maximum time.
+static NEXT_DELAYED_TASK_UNBLOCK_TIME: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// The frequency in Hz of the clock used to measure time, or 0 if it hasn't been determined yet.
+static CLOCK_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// Whether time is measured by counting timer ticks because the TSC frequency is unavailable.
+static USE_TICK_CLOCK: AtomicBool = AtomicBool::new(false);
+/// The number of timer ticks that have elapsed, which is the clock if [`USE_TICK_CLOCK`] is set.
+static TICK_CLOCK: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the frequency of the clock in Hz.
+///
+/// This is the TSC frequency, which is calibrated early on during boot, so this is cheap.
+/// If the TSC frequency can't be obtained, this falls back to the frequency of the periodic timer tick.
+fn clock_frequency() -> u128 {
+    let frequency = CLOCK_FREQUENCY.load(Ordering::Acquire);
+    if frequency != 0 {
+        return frequency as u128;
+    }
+    let frequency = match tsc::get_tsc_frequency() {
+        Ok(tsc_frequency) => tsc_frequency as u64,
+        Err(_e) => {
+            error!("sleep: couldn't get the TSC frequency, falling back to the timer tick for timekeeping: {}", _e);
+            USE_TICK_CLOCK.store(true, Ordering::Release);
+            (MICROS_PER_SEC / CONFIG_TIMESLICE_PERIOD_MICROSECONDS as u128) as u64
+        }
+    };
+    match CLOCK_FREQUENCY.compare_exchange(0, frequency, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => frequency as u128,
+        Err(existing) => existing as u128,
+    }
+}
+
+/// Returns `true` if time is measured by counting timer ticks instead of using the TSC.
+pub(crate) fn uses_tick_clock() -> bool {
+    clock_frequency();
+    USE_TICK_CLOCK.load(Ordering::Acquire)
+}
+
+/// Advances the tick clock to the given number of elapsed ticks, if it is behind.
+///
+/// Every CPU reports the number of its own timer interrupts,
+/// so the tick clock follows the CPU that has been ticking the longest.
+pub(crate) fn advance_tick_clock(ticks: u64) {
+    TICK_CLOCK.fetch_max(ticks, Ordering::Relaxed);
+}
+
+/// Returns the current value of the clock: the TSC, or the tick clock if the TSC is unavailable.
+fn now() -> u64 {
+    if uses_tick_clock() {
+        TICK_CLOCK.load(Ordering::Relaxed)
+    } else {
+        u128::from(tsc::tsc_ticks()) as u64
+    }
+}
 
-/// This variable will track the number of ticks elapsed on the system to keep track of time
-static TICK_COUNT: AtomicUsize = AtomicUsize::new(0);
+/// Converts the given `duration` into a number of clock ticks.
+fn duration_to_clock(duration: Duration) -> u64 {
+    let clock_ticks = duration.as_nanos() * clock_frequency() / NANOS_PER_SEC;
+    core::cmp::min(clock_ticks, u64::MAX as u128) as u64
+}
 
-/// Returns the current time in ticks
+/// Converts the given number of clock ticks into microseconds, rounding up.
+#[cfg(tickless)]
+fn clock_to_micros(clock_ticks: u64) -> u64 {
+    let frequency = clock_frequency();
+    ((clock_ticks as u128 * MICROS_PER_SEC + frequency - 1) / frequency) as u64
+}
+
+/// Returns the number of clock ticks in one scheduler tick (timeslice).
+fn clock_per_tick() -> u64 {
+    core::cmp::max(clock_frequency() * CONFIG_TIMESLICE_PERIOD_MICROSECONDS as u128 / MICROS_PER_SEC, 1) as u64
+}
+
+/// Returns the current time in ticks, i.e., the number of timeslices elapsed since the clock was reset.
 pub fn get_current_time_in_ticks() -> usize {
-    TICK_COUNT.load(Ordering::SeqCst)
+    (now() / clock_per_tick()) as usize
+}
+
+/// Returns the amount of time elapsed since the clock was reset,
+/// with sub-microsecond precision if the TSC is available.
+pub fn get_current_time() -> Duration {
+    let nanos = now() as u128 * NANOS_PER_SEC / clock_frequency();
+    Duration::new(
+        (nanos / NANOS_PER_SEC) as u64,
+        (nanos % NANOS_PER_SEC) as u32,
+    )
 }
 
-/// Update the current tick count
-/// Used as a callback in the systick handler
-pub fn increment_tick_count() {
-    TICK_COUNT.fetch_add(1, Ordering::SeqCst);
+/// Returns the clock value at which the next sleeping task needs to be woken up,
+/// or `u64::MAX` if no tasks are sleeping.
+pub(crate) fn next_unblock_time() -> u64 {
+    NEXT_DELAYED_TASK_UNBLOCK_TIME.load(Ordering::SeqCst)
 }
 
 
-/// Helper function adds the id associated with a TaskRef to the list of delayed task.
+/// Helper function adds the given node to the list of delayed tasks.
 /// If the resume time is less than the current earliest resume time, then update it.
 fn add_to_delayed_tasklist(new_node: SleepingTaskNode) {
     let SleepingTaskNode { resume_time, .. } = new_node;
@@ -81,56 +215,93 @@ fn add_to_delayed_tasklist(new_node: SleepingTaskNode) {
     let next_unblock_time = NEXT_DELAYED_TASK_UNBLOCK_TIME.load(Ordering::SeqCst);
     if resume_time < next_unblock_time {
         NEXT_DELAYED_TASK_UNBLOCK_TIME.store(resume_time, Ordering::SeqCst);
+        // Ensure that this CPU's timer will fire in time to wake up this task.
+        tick::on_new_deadline(resume_time);
     }
 }
 
 /// Remove the next task from the delayed task list and unblock that task
 fn remove_next_task_from_delayed_tasklist() {
     let mut delayed_tasklist = DELAYED_TASKLIST.lock();
-    if let Some(SleepingTaskNode { taskref, .. }) = delayed_tasklist.pop() {
-        taskref.unblock().expect("failed to unblock sleeping task");
+    if let Some(SleepingTaskNode { sleeper, .. }) = delayed_tasklist.pop() {
+        match sleeper {
+            Sleeper::Task(taskref) => { 
+                taskref.unblock().expect("failed to unblock sleeping task");
+            }
+            Sleeper::Waker(waker) => waker.wake(),
+        }
 
         match delayed_tasklist.peek() {
             Some(SleepingTaskNode { resume_time, .. }) => 
                 NEXT_DELAYED_TASK_UNBLOCK_TIME.store(*resume_time, Ordering::SeqCst),
-            None => NEXT_DELAYED_TASK_UNBLOCK_TIME.store(usize::MAX, Ordering::SeqCst),
+            None => NEXT_DELAYED_TASK_UNBLOCK_TIME.store(u64::MAX, Ordering::SeqCst),
         }
     }
 }
 
+/// Removes the node with the given ID from the delayed task list without waking it up, if it's still there.
+fn remove_from_delayed_tasklist(id: usize) {
+    let mut delayed_tasklist = DELAYED_TASKLIST.lock();
+    if !delayed_tasklist.iter().any(|node| node.id == id) {
+        return;
+    }
+    let mut nodes = core::mem::take(&mut *delayed_tasklist).into_vec();
+    nodes.retain(|node| node.id != id);
+    *delayed_tasklist = BinaryHeap::from(nodes);
+
+    // The removed node may have been the next one to be woken up.
+    match delayed_tasklist.peek() {
+        Some(SleepingTaskNode { resume_time, .. }) =>
+            NEXT_DELAYED_TASK_UNBLOCK_TIME.store(*resume_time, Ordering::SeqCst),
+        None => NEXT_DELAYED_TASK_UNBLOCK_TIME.store(u64::MAX, Ordering::SeqCst),
+    }
+}
+
 /// Remove all tasks that have been delayed but are able to be unblocked now,
-/// the current tick count is provided by the system's interrupt tick count.
+/// based on the current value of the clock.
 pub fn unblock_sleeping_tasks() {
-    let ticks = TICK_COUNT.load(Ordering::SeqCst);
-    while ticks > NEXT_DELAYED_TASK_UNBLOCK_TIME.load(Ordering::SeqCst) {
+    let now = now();
+    while now >= NEXT_DELAYED_TASK_UNBLOCK_TIME.load(Ordering::SeqCst) {
         remove_next_task_from_delayed_tasklist();
     }
 }
 
-/// Blocks the current task by putting it to sleep for `duration` ticks.
-///
-/// Returns the current task's run state if it can't be blocked.
-pub fn sleep(duration: usize) -> Result<(), RunState> {
-    let current_tick_count = TICK_COUNT.load(Ordering::SeqCst);
-    let resume_time = current_tick_count + duration;
-
+/// Blocks the current task until the clock reaches the given `resume_time`.
+fn sleep_until_clock(resume_time: u64) -> Result<(), RunState> {
     let current_task = get_my_current_task().unwrap();
     // Add the current task to the delayed tasklist and then block it.
-    add_to_delayed_tasklist(SleepingTaskNode{taskref: current_task.clone(), resume_time});
+    add_to_delayed_tasklist(SleepingTaskNode::new(resume_time, Sleeper::Task(current_task.clone())));
     current_task.block()?;
     scheduler::schedule();
     Ok(())
 }
 
+/// Blocks the current task by putting it to sleep for the given `duration`.
+///
+/// With the `tickless` config option, the task is woken up within a few microseconds
+/// of the given `duration` elapsing; otherwise, it is woken up on the next tick afterwards.
+///
+/// Returns the current task's run state if it can't be blocked.
+pub fn sleep(duration: Duration) -> Result<(), RunState> {
+    sleep_until_clock(now().saturating_add(duration_to_clock(duration)))
+}
+
+/// Blocks the current task by putting it to sleep for `duration` ticks.
+///
+/// Returns the current task's run state if it can't be blocked.
+pub fn sleep_ticks(duration: usize) -> Result<(), RunState> {
+    sleep_until_clock(now().saturating_add((duration as u64).saturating_mul(clock_per_tick())))
+}
+
 /// Blocks the current task by putting it to sleep until a specific tick count is reached,
 /// given by `resume_time`.
 ///
 /// Returns the current task's run state if it can't be blocked.
 pub fn sleep_until(resume_time: usize) -> Result<(), RunState> {
-    let current_tick_count = TICK_COUNT.load(Ordering::SeqCst);
+    let current_tick_count = get_current_time_in_ticks();
 
     if resume_time > current_tick_count {
-        sleep(resume_time - current_tick_count)?;
+        sleep_until_clock((resume_time as u64).saturating_mul(clock_per_tick()))?;
     }
     
     Ok(())
@@ -143,3 +314,69 @@ pub fn sleep_periodic(last_resume_time: &AtomicUsize, period: usize) -> Result<(
     let new_resume_time = last_resume_time.fetch_add(period, Ordering::SeqCst) + period;
     sleep_until(new_resume_time)
 }
+
+/// Unblocks the given blocked `task` once the given `duration` has elapsed,
+/// e.g., to delay the start of a task that was spawned in the `Blocked` state.
+///
+/// The task must stay blocked until then, i.e., nothing else may unblock it in the meantime.
+pub fn unblock_after(task: TaskRef, duration: Duration) {
+    add_to_delayed_tasklist(SleepingTaskNode::new(
+        now().saturating_add(duration_to_clock(duration)),
+        Sleeper::Task(task),
+    ));
+}
+
+/// Returns a [`Future`] that completes once the given `duration` has elapsed.
+///
+/// Unlike [`sleep`], this does not block the current task;
+/// it is intended to be `.await`ed within an asynchronous executor.
+pub fn sleep_async(duration: Duration) -> Sleep {
+    Sleep {
+        resume_time: now().saturating_add(duration_to_clock(duration)),
+        registered: None,
+    }
+}
+
+/// A [`Future`] that completes at a specific point in time.
+///
+/// See [`sleep_async`].
+#[must_use = "futures do nothing unless polled or `.await`ed"]
+pub struct Sleep {
+    /// The clock value at which this future completes.
+    resume_time: u64,
+    /// The ID of the delayed tasklist node most recently added for this future
+    /// and the `Waker` in that node, if any.
+    registered: Option<(usize, Waker)>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
+        if now() >= this.resume_time {
+            return Poll::Ready(());
+        }
+        // Only add a new entry to the delayed tasklist if we haven't already done so for this waker.
+        if !this.registered.as_ref().map_or(false, |(_, w)| w.will_wake(cx.waker())) {
+            // Replace the node for the previous waker, which no longer needs to be woken.
+            if let Some((id, _)) = this.registered.take() {
+                remove_from_delayed_tasklist(id);
+            }
+            let node = SleepingTaskNode::new(this.resume_time, Sleeper::Waker(cx.waker().clone()));
+            this.registered = Some((node.id, cx.waker().clone()));
+            add_to_delayed_tasklist(node);
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        // Remove our node such that a dropped future doesn't keep its waker alive
+        // or cause a spurious wakeup once its resume time is reached.
+        if let Some((id, _)) = self.registered.take() {
+            remove_from_delayed_tasklist(id);
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Management of each CPU's local timer interrupt, i.e., the scheduler "tick".
+//!
+//! By default, each CPU's local APIC timer is configured in periodic mode,
+//! such that every CPU takes a timer interrupt once per timeslice, even when idle.
+//!
+//! With the `tickless` config option, each CPU's local APIC timer is instead programmed
+//! in one-shot mode (or TSC-deadline mode, if supported) for the next timer event only:
+//! * While a CPU is busy running tasks, the next event is the earlier of
+//!   the end of the current timeslice and the next sleeping task's resume time.
+//! * While a CPU is idle, its tick is stopped and the next event is
+//!   the next sleeping task's resume time only, if any.
+//!   The idle task is responsible for calling [`stop_tick()`] and [`restart_tick()`].
+//!
+//! One-shot mode requires the TSC; if its frequency is unavailable,
+//! the timer stays in periodic mode and its interrupts are counted to keep time instead.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(tickless)]
+use core::sync::atomic::{AtomicBool, AtomicU64};
+use apic;
+#[cfg(tickless)]
+use irq_safety;
+
+/// The maximum number of CPUs, which is the number of possible APIC IDs.
+const MAX_CPUS: usize = u8::MAX as usize + 1;
+
+/// The number of local timer interrupts that have occurred on each CPU.
+static TIMER_INTERRUPTS: [AtomicUsize; MAX_CPUS] = {
+    const ZERO: AtomicUsize = AtomicUsize::new(0);
+    [ZERO; MAX_CPUS]
+};
+
+/// The TSC value that each CPU's local timer is currently programmed to fire at,
+/// or `u64::MAX` if it is not programmed to fire at all.
+#[cfg(tickless)]
+static PROGRAMMED_DEADLINES: [AtomicU64; MAX_CPUS] = {
+    const NONE: AtomicU64 = AtomicU64::new(u64::MAX);
+    [NONE; MAX_CPUS]
+};
+
+/// Whether each CPU's periodic tick is currently stopped because that CPU is idle.
+#[cfg(tickless)]
+static TICK_STOPPED: [AtomicBool; MAX_CPUS] = {
+    const FALSE: AtomicBool = AtomicBool::new(false);
+    [FALSE; MAX_CPUS]
+};
+
+/// Returns the number of local timer interrupts that have occurred on the given CPU.
+pub fn timer_interrupt_count(apic_id: u8) -> usize {
+    TIMER_INTERRUPTS[apic_id as usize].load(Ordering::Relaxed)
+}
+
+/// Returns `true` if the given CPU's tick is currently stopped because it is idle.
+///
+/// This always returns `false` if the `tickless` config option is not enabled.
+pub fn is_tick_stopped(_apic_id: u8) -> bool {
+    #[cfg(tickless)] {
+        TICK_STOPPED[_apic_id as usize].load(Ordering::Relaxed)
+    }
+    #[cfg(not(tickless))] {
+        false
+    }
+}
+
+/// Handles a local timer interrupt on the current CPU.
+///
+/// This unblocks all sleeping tasks whose resume time has passed,
+/// and then, with the `tickless` config option, re-arms this CPU's timer for its next event.
+///
+/// This must be invoked from the local timer interrupt handler, with interrupts disabled.
+pub fn handle_timer_interrupt() {
+    let apic_id = apic::get_my_apic_id();
+    let interrupts = TIMER_INTERRUPTS[apic_id as usize].fetch_add(1, Ordering::Relaxed) + 1;
+    let uses_tick_clock = crate::uses_tick_clock();
+    if uses_tick_clock {
+        crate::advance_tick_clock(interrupts as u64);
+    }
+
+    crate::unblock_sleeping_tasks();
+
+    // The timer interrupt is followed by a call to the scheduler, so we must 
+    // always re-arm a full tick here in case a non-idle task is switched to.
+    // If no task is runnable, the idle task will stop the tick again.
+    #[cfg(tickless)] {
+        if uses_tick_clock {
+            return;
+        }
+        TICK_STOPPED[apic_id as usize].store(false, Ordering::Relaxed);
+        program_next_tick(apic_id);
+    }
+}
+
+/// Stops the periodic tick on the current CPU, which should be idle.
+///
+/// The current CPU's timer will still fire at the resume time of the next sleeping task.
+/// This does nothing if the `tickless` config option is not enabled or the TSC is unavailable.
+pub fn stop_tick() {
+    #[cfg(tickless)] {
+        if crate::uses_tick_clock() {
+            return;
+        }
+        let _held_interrupts = irq_safety::hold_interrupts();
+        let apic_id = apic::get_my_apic_id();
+        let next_unblock_time = crate::next_unblock_time();
+        let already_stopped = TICK_STOPPED[apic_id as usize].swap(true, Ordering::Relaxed);
+        if already_stopped && PROGRAMMED_DEADLINES[apic_id as usize].load(Ordering::Relaxed) <= next_unblock_time {
+            return;
+        }
+        program_timer(apic_id, next_unblock_time);
+    }
+}
+
+/// Restarts the periodic tick on the current CPU if it was previously stopped.
+///
+/// This must be called before switching from the idle task to another task.
+/// This does nothing if the `tickless` config option is not enabled or the TSC is unavailable.
+pub fn restart_tick() {
+    #[cfg(tickless)] {
+        if crate::uses_tick_clock() {
+            return;
+        }
+        let _held_interrupts = irq_safety::hold_interrupts();
+        let apic_id = apic::get_my_apic_id();
+        if TICK_STOPPED[apic_id as usize].swap(false, Ordering::Relaxed) {
+            program_next_tick(apic_id);
+        }
+    }
+}
+
+/// Ensures that the current CPU's timer fires no later than the given `deadline`,
+/// which is the clock value at which a newly-sleeping task must be woken up.
+pub(crate) fn on_new_deadline(_deadline: u64) {
+    #[cfg(tickless)] {
+        if crate::uses_tick_clock() {
+            return;
+        }
+        let _held_interrupts = irq_safety::hold_interrupts();
+        let apic_id = apic::get_my_apic_id();
+        if _deadline < PROGRAMMED_DEADLINES[apic_id as usize].load(Ordering::Relaxed) {
+            program_timer(apic_id, _deadline);
+        }
+    }
+}
+
+/// Programs the given CPU's timer to fire at the end of the next timeslice
+/// or at the next sleeping task's resume time, whichever comes first.
+#[cfg(tickless)]
+fn program_next_tick(apic_id: u8) {
+    let end_of_timeslice = crate::now().saturating_add(crate::clock_per_tick());
+    program_timer(apic_id, core::cmp::min(end_of_timeslice, crate::next_unblock_time()));
+}
+
+/// Programs the current CPU's local APIC timer to fire once at the given `deadline` TSC value,
+/// or stops it if `deadline` is `u64::MAX`.
+///
+/// Interrupts must be disabled, and `apic_id` must be the current CPU's APIC ID.
+#[cfg(tickless)]
+fn program_timer(apic_id: u8, deadline: u64) {
+    let Some(lapic) = apic::get_my_apic() else {
+        error!("BUG: couldn't get my LocalApic instance to program its timer");
+        return;
+    };
+    let mut lapic = lapic.write();
+    PROGRAMMED_DEADLINES[apic_id as usize].store(deadline, Ordering::Relaxed);
+
+    if deadline == u64::MAX {
+        lapic.stop_timer();
+    } else if apic::has_tsc_deadline() {
+        // This cannot fail because we checked for TSC-deadline support above.
+        let _ = lapic.set_timer_tsc_deadline(deadline);
+    } else {
+        lapic.set_timer_one_shot(crate::clock_to_micros(deadline.saturating_sub(crate::now())));
+    }
+}
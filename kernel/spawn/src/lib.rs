@@ -26,6 +26,7 @@ use alloc::{
 use debugit::debugit;
 use spin::Mutex;
 use irq_safety::enable_interrupts;
+use kernel_config::memory::{KERNEL_STACK_SIZE_IN_PAGES, PAGE_SIZE};
 use memory::{get_kernel_mmi_ref, MmiRef};
 use stack::Stack;
 use task::{Task, TaskRef, get_my_current_task, RestartInfo, TASKLIST, JoinableTaskRef, RunState};
@@ -35,6 +36,7 @@ use apic::get_my_apic_id;
 use fs_node::FileOrDir;
 use preemption::{hold_preemption, PreemptionGuard};
 use no_drop::NoDrop;
+use task_group::TaskGroupRef;
 
 #[cfg(simd_personality)]
 use task::SimdExt;
@@ -261,7 +263,9 @@ pub struct TaskBuilder<F, A, R> {
     _return_type: PhantomData<R>,
     name: Option<String>,
     stack: Option<Stack>,
+    stack_size: Option<usize>,
     parent: Option<TaskRef>,
+    group: Option<TaskGroupRef>,
     pin_on_core: Option<u8>,
     blocked: bool,
     idle: bool,
@@ -285,7 +289,9 @@ impl<F, A, R> TaskBuilder<F, A, R>
             _return_type: PhantomData,
             name: None,
             stack: None,
+            stack_size: None,
             parent: None,
+            group: None,
             pin_on_core: None,
             blocked: false,
             idle: false,
@@ -314,6 +320,19 @@ impl<F, A, R> TaskBuilder<F, A, R>
         self
     }
 
+    /// Set the maximum size in bytes of the new Task's stack, which will be a growable stack.
+    ///
+    /// The stack starts out with the default number of pages backed by frames
+    /// (or fewer, if `size_in_bytes` is smaller) and grows one page at a time upon page faults,
+    /// up to `size_in_bytes` rounded up to a whole number of pages.
+    /// See [`stack::alloc_growable_stack()`] for the caveats of using a growable stack.
+    ///
+    /// This is ignored if a specific `Stack` was provided via [`TaskBuilder::stack()`].
+    pub fn stack_size(mut self, size_in_bytes: usize) -> TaskBuilder<F, A, R> {
+        self.stack_size = Some(size_in_bytes);
+        self
+    }
+
     /// Set the "parent" Task from which the new Task will inherit certain states.
     ///
     /// See [`Task::new()`] for more details on what states are inherited.
@@ -323,6 +342,16 @@ impl<F, A, R> TaskBuilder<F, A, R>
         self
     }
 
+    /// Set the task group that the new Task will join.
+    ///
+    /// By default, the new Task joins the task group of the `CrateNamespace` it runs in, if that has one,
+    /// or otherwise the task group of its "parent" task, if it has one.
+    /// Spawning fails if the group's limit on the number of tasks has been reached.
+    pub fn group(mut self, group: TaskGroupRef) -> TaskBuilder<F, A, R> {
+        self.group = Some(group);
+        self
+    }
+
     /// Pin the new Task to a specific core.
     pub fn pin_on_core(mut self, core_apic_id: u8) -> TaskBuilder<F, A, R> {
         self.pin_on_core = Some(core_apic_id);
@@ -353,8 +382,27 @@ impl<F, A, R> TaskBuilder<F, A, R>
     /// It does not switch to it immediately; that will happen on the next scheduler invocation.
     #[inline(never)]
     pub fn spawn(self) -> Result<JoinableTaskRef, &'static str> {
+        let stack = match (self.stack, self.stack_size) {
+            (None, Some(size_in_bytes)) => {
+                let mmi = self.parent.as_ref()
+                    .map(|parent| parent.mmi.clone())
+                    .or_else(|| task::with_current_task(|t| t.mmi.clone()).ok())
+                    .ok_or("couldn't get the current task's MMI to allocate a growable stack")?;
+                let max_size_in_pages = (size_in_bytes + PAGE_SIZE - 1) / PAGE_SIZE;
+                if max_size_in_pages == 0 {
+                    return Err("a task's stack size must not be zero");
+                }
+                let stack = stack::alloc_growable_stack(
+                    KERNEL_STACK_SIZE_IN_PAGES,
+                    max_size_in_pages,
+                    &mut mmi.lock().page_table,
+                ).ok_or("couldn't allocate a growable stack for the new task")?;
+                Some(stack)
+            }
+            (stack, _) => stack,
+        };
         let mut new_task = Task::new(
-            self.stack,
+            stack,
             self.parent.as_ref(),
             task_cleanup_failure::<F, A, R>,
         )?;
@@ -391,6 +439,15 @@ impl<F, A, R> TaskBuilder<F, A, R>
             pb_func(&mut new_task)?;
         }
 
+        // An explicitly-given group takes precedence over the group inherited from the parent task.
+        // Otherwise, a task running in a namespace that has its own task group (e.g., an application
+        // spawned into a new namespace by the post-build function above) joins that namespace's group.
+        if let Some(group) = self.group.or_else(|| new_task.namespace.task_group()) {
+            if !new_task.group().map_or(false, |current| Arc::ptr_eq(current, &group)) {
+                new_task.set_group(Some(group))?;
+            }
+        }
+
         // Now that it has been fully initialized, mark the task as no longer `Initing`.
         if self.blocked {
             new_task.block_initing_task()
@@ -886,14 +943,17 @@ where
     F: FnOnce(A) -> R + Send + Clone + 'static,
 {
     {
+        // The recovery policy that applies to the task's fault decides whether and how to restart it.
+        let decision = fault_policy::decide_recovery();
+
         #[cfg(use_crate_replacement)]
         let mut se = fault_crate_swap::SwapRanges::default();
 
-        // Get the crate we should swap. Will be None if nothing is picked
+        // Swap the crate chosen by the policy, if any.
         #[cfg(use_crate_replacement)] {
-            if let Some(crate_to_swap) = fault_crate_swap::get_crate_to_swap() {
+            if let Some(crate_to_swap) = decision.crate_to_replace.as_ref() {
                 // Call the handler to swap the crates
-                let version = fault_crate_swap::self_swap_handler(&crate_to_swap);
+                let version = fault_crate_swap::self_swap_handler(crate_to_swap);
                 match version {
                     Ok(v) => {
                         se = v
@@ -907,40 +967,56 @@ where
 
         // Re-spawn a new instance of the task if it was spawned as a restartable task. 
         // We must not hold the current task's lock when calling spawn().
-        let restartable_info = current_task.with_restart_info(|restart_info_opt| {
-            restart_info_opt.map(|restart_info| {
-                #[cfg(use_crate_replacement)] {
-                    let func_ptr = &(restart_info.func) as *const _ as usize;
-                    let arg_ptr = &(restart_info.argument) as *const _ as usize;
-
-                    #[cfg(not(downtime_eval))] {
-                        debug!("func_ptr {:#X}", func_ptr);
-                        debug!("arg_ptr {:#X} , {}", arg_ptr, mem::size_of::<A>());
-                    }
-
-                    // func_ptr is of size 16. Argument is of the argument_size + 8.
-                    // This extra size comes due to argument and function both stored in +8 location pointed by the pointer. 
-                    // The exact location pointed by the pointer has value 0x1. (Indicates Some for option ?). 
-                    if fault_crate_swap::constant_offset_fix(&se, func_ptr, func_ptr + 16).is_ok() &&  fault_crate_swap::constant_offset_fix(&se, arg_ptr, arg_ptr + 8).is_ok() {
-                        #[cfg(not(downtime_eval))]
-                        debug!("Function and argument addresses corrected");
+        let restartable_info = if !decision.restart {
+            None
+        } else {
+            current_task.with_restart_info(|restart_info_opt| {
+                restart_info_opt.map(|restart_info| {
+                    #[cfg(use_crate_replacement)] {
+                        let func_ptr = &(restart_info.func) as *const _ as usize;
+                        let arg_ptr = &(restart_info.argument) as *const _ as usize;
+
+                        #[cfg(not(downtime_eval))] {
+                            debug!("func_ptr {:#X}", func_ptr);
+                            debug!("arg_ptr {:#X} , {}", arg_ptr, mem::size_of::<A>());
+                        }
+
+                        // func_ptr is of size 16. Argument is of the argument_size + 8.
+                        // This extra size comes due to argument and function both stored in +8 location pointed by the pointer. 
+                        // The exact location pointed by the pointer has value 0x1. (Indicates Some for option ?). 
+                        if fault_crate_swap::constant_offset_fix(&se, func_ptr, func_ptr + 16).is_ok() &&  fault_crate_swap::constant_offset_fix(&se, arg_ptr, arg_ptr + 8).is_ok() {
+                            #[cfg(not(downtime_eval))]
+                            debug!("Function and argument addresses corrected");
+                        }
                     }
-                }
 
-                let func: &F = restart_info.func.downcast_ref().expect("BUG: failed to downcast restartable task's function");
-                let arg : &A = restart_info.argument.downcast_ref().expect("BUG: failed to downcast restartable task's argument");
-                (func.clone(), arg.clone())
+                    let func: &F = restart_info.func.downcast_ref().expect("BUG: failed to downcast restartable task's function");
+                    let arg : &A = restart_info.argument.downcast_ref().expect("BUG: failed to downcast restartable task's argument");
+                    (func.clone(), arg.clone())
+                })
             })
-        });
+        };
 
-        if let Some((func, arg)) = restartable_info {
+        if !decision.restart {
+            warn!("Not restarting task {:?}, its recovery policy ({}) allows no more restarts",
+                current_task.name, decision.scope.as_ref().map(|s| s.to_string()).unwrap_or_default(),
+            );
+        } else if let Some((func, arg)) = restartable_info {
             let mut new_task = new_task_builder(func, arg)
                 .name(current_task.name.clone());
             if let Some(core) = current_task.pinned_core() {
                 new_task = new_task.pin_on_core(core);
             }
-            new_task.spawn_restartable(None)
+            // Delay the restart by spawning the new task as blocked until the policy's backoff has elapsed.
+            let delayed = !decision.delay.is_zero();
+            if delayed {
+                new_task = new_task.block();
+            }
+            let new_task = new_task.spawn_restartable(None)
                 .expect("Failed to respawn the restartable task");
+            if delayed {
+                sleep::unblock_after(TaskRef::clone(&new_task), decision.delay);
+            }
         } else {
             error!("BUG: Restartable task has no restart information available");
         }
@@ -1003,6 +1079,16 @@ pub fn create_idle_task() -> Result<JoinableTaskRef, &'static str> {
 fn idle_task_entry(_apic_id: u8) {
     info!("Entered idle task loop on core {}: {:?}", apic::get_my_apic_id(), task::get_my_current_task());
     loop {
+        // With a tickless timer, this core doesn't receive timer interrupts while idle,
+        // so we must explicitly switch to another task once one becomes runnable.
+        #[cfg(tickless)] {
+            if runqueue::has_runnable_task(_apic_id) {
+                sleep::tick::restart_tick();
+                scheduler::schedule();
+            } else {
+                sleep::tick::stop_tick();
+            }
+        }
         // TODO: put this core into a low-power state
         pause::spin_loop_hint();
     }
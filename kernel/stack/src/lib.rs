@@ -1,5 +1,10 @@
 //! Provides the `Stack` type that represents a Task's stack 
 //! and functions for allocating new stacks. 
+//!
+//! A stack is either fixed-size, with all of its pages backed by frames up front,
+//! or growable, in which only its topmost pages are backed by frames up front
+//! and the rest of its reserved pages are mapped lazily.
+//! A growable stack thus grows one page at a time, upon page faults, up to its maximum size.
 
 #![no_std]
 
@@ -32,6 +37,49 @@ pub fn alloc_stack(
     inner_alloc_stack(pages, page_table)
 }
 
+/// Allocates a new growable stack and maps it to the active page table. 
+///
+/// This reserves `max_size_in_pages` pages for the stack, plus an unmapped guard page 
+/// beneath the bottom of the stack in order to catch stack overflows. 
+/// Only the topmost `initial_size_in_pages` pages are backed by frames up front;
+/// the remaining pages are mapped lazily, such that the stack grows downwards
+/// one page at a time as the page faults caused by accessing them are resolved.
+///
+/// Because a growable stack may grow while its task is running arbitrary code,
+/// it must not be used by a task that may overflow its initial size
+/// while holding a lock that the frame allocator acquires.
+pub fn alloc_growable_stack(
+    initial_size_in_pages: usize,
+    max_size_in_pages: usize,
+    page_table: &mut Mapper, 
+) -> Option<Stack> {
+    let initial_size_in_pages = initial_size_in_pages.clamp(1, max_size_in_pages);
+    // Allocate enough pages for an additional guard page. 
+    let pages = page_allocator::allocate_pages(max_size_in_pages + 1)?;
+    let start_of_stack_pages = *pages.start() + 1; 
+    let (guard_page, stack_pages) = pages.split(start_of_stack_pages).ok()?;
+
+    let mut pages = match page_table.map_allocated_pages_lazily(
+        stack_pages, 
+        EntryFlags::WRITABLE, 
+    ) {
+        Ok(pages) => pages,
+        Err(e) => {
+            error!("alloc_growable_stack(): couldn't lazily map pages for the new Stack, error: {}", e);
+            return None;
+        }
+    };
+
+    // Back the initial pages right away by writing to each of them.
+    let first_initial_page = max_size_in_pages - initial_size_in_pages;
+    for page_index in first_initial_page..max_size_in_pages {
+        let byte: &mut u8 = pages.as_type_mut(page_index * PAGE_SIZE).ok()?;
+        *byte = 0;
+    }
+
+    Some(Stack { guard_page, pages })
+}
+
 /// The inner implementation of stack allocation. 
 /// 
 /// `pages` is the combined `AllocatedPages` object that holds
@@ -68,6 +116,8 @@ fn inner_alloc_stack(
 /// There is an unmapped guard page beneath the stack,
 /// which is a standard approach to detect stack overflow.
 /// 
+/// A growable stack's pages are mapped lazily, see [`alloc_growable_stack()`].
+/// 
 /// A stack is backed by and auto-derefs into `MappedPages`. 
 #[derive(Debug)]
 pub struct Stack {
@@ -107,6 +157,46 @@ impl Stack {
         self.pages.start_address()
     }
 
+    /// Returns `true` if this is a growable stack, whose pages are backed by frames upon first access.
+    pub fn is_growable(&self) -> bool {
+        self.pages.is_lazy()
+    }
+
+    /// Returns the number of bytes of this stack that are currently backed by frames.
+    ///
+    /// This is only less than its total size if this is a growable stack.
+    pub fn resident_size_in_bytes(&self) -> usize {
+        self.pages.resident_pages() * PAGE_SIZE
+    }
+
+    /// Returns the largest number of bytes of this stack that have ever been used,
+    /// or `None` if this isn't a growable stack.
+    ///
+    /// Each page of a growable stack is zeroed when it's first backed by a frame,
+    /// so this searches upwards from the bottom of its backed pages for the first non-zero word.
+    /// The word at the very bottom of the stack is skipped, as it's used to pass
+    /// a new task's entry function and argument rather than being part of its call stack.
+    /// This must only be invoked while the page table that this stack is mapped into is active.
+    pub fn high_water_mark(&self) -> Option<usize> {
+        if !self.is_growable() {
+            return None;
+        }
+        const WORDS_PER_PAGE: usize = PAGE_SIZE / core::mem::size_of::<usize>();
+        for (page_index, page) in self.pages.deref().clone().into_iter().enumerate() {
+            // Reading a page that hasn't been backed by a frame would back it.
+            if !self.pages.is_page_resident(page) {
+                continue;
+            }
+            let words: &[usize] = self.pages.as_slice(page_index * PAGE_SIZE, WORDS_PER_PAGE).ok()?;
+            let skip = if page_index == 0 { 1 } else { 0 };
+            if let Some(word_index) = words.iter().skip(skip).position(|word| *word != 0) {
+                let offset = page_index * PAGE_SIZE + (word_index + skip) * core::mem::size_of::<usize>();
+                return Some(self.pages.size_in_bytes() - offset);
+            }
+        }
+        Some(0)
+    }
+
     /// Creates a stack from its constituent parts: 
     /// a guard page and a series of mapped pages. 
     /// 
@@ -25,8 +25,9 @@ extern crate unwind;
 extern crate fallible_iterator;
 
 pub use mod_mgmt::{CrateNamespace, StrongSectionRef};
-pub use task::get_my_current_task;
+pub use task::{get_my_current_task, TaskRef};
 
+use alloc::sync::Arc;
 use unwind::{StackFrame, StackFrameIter};
 use fallible_iterator::FallibleIterator;
 
@@ -69,21 +70,69 @@ pub fn stack_trace(
         let namespace = task::with_current_task(|t| t.get_namespace().clone())
             .or_else(|_| mod_mgmt::get_initial_kernel_namespace().cloned().ok_or(()))
             .map_err(|_| "couldn't get current task's namespace or default namespace")?;
-        let mut stack_frame_iter = StackFrameIter::new(namespace, registers);
+        walk_stack_frames(StackFrameIter::new(namespace, registers), on_each_stack_frame, max_recursion)
+    })
+}
+
+/// Get a stack trace of another task, which must not be currently running,
+/// using the default stack tracer based on DWARF debug info.
+///
+/// The trace starts from the stack frame in which the given `task` was last switched out.
+/// See [`stack_trace()`] for a description of the other arguments.
+///
+/// # Safety
+/// The given `task` must remain switched out (e.g., suspended or blocked)
+/// until this function returns, as its stack is read while walking it.
+pub unsafe fn stack_trace_of_task(
+    task: &TaskRef,
+    on_each_stack_frame: &mut dyn FnMut(StackFrame, &StackFrameIter) -> bool,
+    max_recursion: Option<usize>,
+) -> Result<(), &'static str> {
+    let stack_frame_iter = StackFrameIter::for_task(task)?;
+    walk_stack_frames(stack_frame_iter, on_each_stack_frame, max_recursion.unwrap_or(usize::MAX))
+}
+
+/// Get a stack trace of a context that was interrupted, e.g., a task on a locked-up CPU,
+/// using the default stack tracer based on DWARF debug info.
+///
+/// The trace starts from the stack frame containing the interrupted `instruction_pointer`,
+/// using the given `stack_pointer` and `frame_pointer` (RBP) of the interrupted context.
+/// Symbols are resolved using the given `namespace`.
+/// See [`stack_trace()`] for a description of the other arguments.
+///
+/// # Safety
+/// The interrupted context's stack must remain unchanged (e.g., because that context is stuck)
+/// until this function returns, as that stack is read while walking it.
+pub unsafe fn stack_trace_of_interrupted_context(
+    namespace: Arc<CrateNamespace>,
+    instruction_pointer: usize,
+    stack_pointer: usize,
+    frame_pointer: usize,
+    on_each_stack_frame: &mut dyn FnMut(StackFrame, &StackFrameIter) -> bool,
+    max_recursion: Option<usize>,
+) -> Result<(), &'static str> {
+    let stack_frame_iter = StackFrameIter::for_interrupted_context(namespace, instruction_pointer, stack_pointer, frame_pointer);
+    walk_stack_frames(stack_frame_iter, on_each_stack_frame, max_recursion.unwrap_or(usize::MAX))
+}
 
-        // iterate over each frame in the call stack
-        let mut i = 0;
-        while let Some(frame) = stack_frame_iter.next()? {
-            let keep_going = on_each_stack_frame(frame, &stack_frame_iter);
-            if !keep_going {
-                return Ok(());
-            }
-            i += 1;
-            if i == max_recursion {
-                trace!("stack_trace(): reached maximum recursion depth of {} stack frames", max_recursion);
-                return Err("reached recursion limit for stack frames");
-            }
+/// Invokes `on_each_stack_frame` for each frame produced by the given `stack_frame_iter`.
+fn walk_stack_frames(
+    mut stack_frame_iter: StackFrameIter,
+    on_each_stack_frame: &mut dyn FnMut(StackFrame, &StackFrameIter) -> bool,
+    max_recursion: usize,
+) -> Result<(), &'static str> {
+    // iterate over each frame in the call stack
+    let mut i = 0;
+    while let Some(frame) = stack_frame_iter.next()? {
+        let keep_going = on_each_stack_frame(frame, &stack_frame_iter);
+        if !keep_going {
+            return Ok(());
         }
-        Ok(())
-    })
+        i += 1;
+        if i == max_recursion {
+            trace!("stack_trace(): reached maximum recursion depth of {} stack frames", max_recursion);
+            return Err("reached recursion limit for stack frames");
+        }
+    }
+    Ok(())
 }
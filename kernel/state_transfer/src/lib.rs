@@ -1,108 +1,497 @@
+//! Versioned system-wide states and the migration functions that transfer them across crate swaps.
+//!
+//! A crate that wants its state to survive being swapped declares a state type,
+//! implements [`VersionedState`] for it, and stores it in this crate's registry
+//! using [`get_or_register()`] instead of in its own statics.
+//! States are keyed by name, so the same state can be obtained by any version of that crate.
+//! Crates should get their states from the registry when needed rather than caching them in statics,
+//! because a swapped-in crate's statics are copied from the old crate as is, without being migrated.
+//! States that are read frequently, e.g., on hot paths, can instead be obtained without locking the registry
+//! through a [`CachedState`], which notices when the registered states have been replaced.
+//!
+//! When a new version of a crate changes a state type, it must also change that type's `VERSION`
+//! and declare how to convert the previous version into the new one using the [`state_migration!`] macro:
+//! ```ignore
+//! state_migration!(ticks_v1_to_v2: TicksV1 => TicksV2 = migrate_ticks);
+//!
+//! fn migrate_ticks(old: &TicksV1) -> Result<TicksV2, &'static str> { ... }
+//! ```
+//! `crate_swap::swap_crates()` discovers the migrations in the new crates
+//! and runs them (chaining them if needed) after the new crates are linked in but before the old crates are removed.
+//! The old versions of the states are left untouched until every migration has succeeded;
+//! if any migration fails, the swap is rolled back and the old crates keep using the old states.
+//! Migrations run on a snapshot of the registered states, taken under a short lock,
+//! such that tasks that access the registry (including tasks parked by the swap) never wait for them.
+//! Their results only replace the old states if none of those states was replaced or removed in the meantime,
+//! which is detected with a generation counter; otherwise, the swap is rolled back.
+//! Note that changes made to the contents of an old state while it is being migrated aren't carried over,
+//! so the tasks that modify a state should be quiesced by the swap.
+
 #![no_std]
 
 extern crate alloc;
-#[macro_use] extern crate log;
-extern crate memory;
-extern crate mod_mgmt;
-// #[macro_use] extern crate lazy_static;
-extern crate irq_safety;
-extern crate atomic_linked_list;
-extern crate task;
-extern crate hpet;
-
-extern crate runqueue_round_robin;
-extern crate runqueue_priority;
-
-use core::ops::Deref;
-use alloc::sync::Arc;
-use mod_mgmt::CrateNamespace;
-// use lazy_static::lazy::Lazy;
-use irq_safety::RwLockIrqSafe;
-use atomic_linked_list::atomic_map::AtomicMap;
-// use task::TaskRef;
-
-
-/// This function is used for live evolution from a round robin scheduler to a priority scheduler. 
-/// It first extracts the taskrefs from the round robin Runqueue,
-/// then converts them into priority taskrefs and places them on the priority Runqueue.
-pub fn prio_sched(_old_namespace: &Arc<CrateNamespace>, _new_namespace: &CrateNamespace) -> Result<(), &'static str> {
-
-    // just debugging info
-    #[cfg(not(loscd_eval))]
-    {
-        warn!("prio_sched(): at the top.");
-        let rq_rr_crate = CrateNamespace::get_crate_starting_with(_old_namespace, "runqueue_round_robin")
-            .map(|(_crate_name, crate_ref, _ns)| crate_ref)
-            .ok_or("Couldn't get runqueue_round_robin crate from old namespace")?;
-        let krate = rq_rr_crate.lock_as_ref();
-        for sec in krate.sections.values() {
-            if sec.name.contains("RUNQUEUES") {
-                debug!("Section {}\n\ttype: {:?}\n\tvaddr: {:#X}\n\tsize: {}\n", sec.name, sec.typ, sec.virt_addr, sec.size);
-            }
+
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    alloc::Layout,
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+use log::{debug, error};
+use spin::Mutex;
+
+
+/// A system-wide state that is stored in the registry under a name and version.
+///
+/// # Safety
+/// `VERSION` must be changed whenever the definition of the implementing type changes,
+/// because a registered state is reinterpreted as any type with the same `NAME`, `VERSION`, and layout,
+/// even if that type is defined in a different crate (or a different version of the same crate).
+pub unsafe trait VersionedState: Send + Sync + 'static {
+    /// The name of this state, which is the same across all of its versions, e.g., `"rtc::RtcTicks"`.
+    const NAME: &'static str;
+    /// The version of this state's type.
+    const VERSION: u32;
+}
+
+
+/// A reference-counted state object whose type has been erased,
+/// leaving only its version and layout.
+pub struct ErasedState {
+    /// The pointer obtained from `Arc::into_raw()`.
+    ptr: *const (),
+    version: u32,
+    layout: Layout,
+    increment: unsafe fn(*const ()),
+    decrement: unsafe fn(*const ()),
+}
+
+// SAFETY: an `ErasedState` can only be created from an `Arc<S>`, where `S: VersionedState` is `Send + Sync`.
+unsafe impl Send for ErasedState { }
+unsafe impl Sync for ErasedState { }
+
+impl ErasedState {
+    /// Erases the type of the given state.
+    pub fn new<S: VersionedState>(state: Arc<S>) -> ErasedState {
+        ErasedState {
+            ptr: Arc::into_raw(state) as *const (),
+            version: S::VERSION,
+            layout: Layout::new::<S>(),
+            increment: increment_strong_count::<S>,
+            decrement: decrement_strong_count::<S>,
+        }
+    }
+
+    /// The version of this state.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Returns this state as an `S`, if it has the same version and layout as `S`.
+    ///
+    /// The caller must ensure that this state has the same name as `S`.
+    pub fn downcast<S: VersionedState>(&self) -> Option<Arc<S>> {
+        if self.version != S::VERSION || self.layout != Layout::new::<S>() {
+            return None;
+        }
+        // SAFETY: `ptr` came from an `Arc` with the same name, version, and layout as `S`,
+        // which the `VersionedState` contract guarantees to be the same type.
+        unsafe {
+            Arc::increment_strong_count(self.ptr as *const S);
+            Some(Arc::from_raw(self.ptr as *const S))
+        }
+    }
+}
+
+impl Clone for ErasedState {
+    fn clone(&self) -> Self {
+        // SAFETY: `ptr` is a live `Arc` of the type that `increment` was instantiated for.
+        unsafe { (self.increment)(self.ptr) };
+        ErasedState { ..*self }
+    }
+}
+
+impl Drop for ErasedState {
+    fn drop(&mut self) {
+        // SAFETY: `ptr` is a live `Arc` of the type that `decrement` was instantiated for.
+        unsafe { (self.decrement)(self.ptr) };
+    }
+}
+
+unsafe fn increment_strong_count<S>(ptr: *const ()) {
+    Arc::increment_strong_count(ptr as *const S);
+}
+
+unsafe fn decrement_strong_count<S>(ptr: *const ()) {
+    Arc::decrement_strong_count(ptr as *const S);
+}
+
+
+/// The registry of all system-wide states, keyed by their names.
+static STATES: Mutex<BTreeMap<String, ErasedState>> = Mutex::new(BTreeMap::new());
+
+/// Incremented whenever a registered state is replaced or removed, which invalidates every [`CachedState`]
+/// and every snapshot taken by [`prepare_migrations()`].
+///
+/// This is only modified while `STATES` is locked.
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the registered state `S`.
+///
+/// Returns `None` if no state named `S::NAME` is registered,
+/// or if the registered state has a different version than `S`.
+pub fn get<S: VersionedState>() -> Option<Arc<S>> {
+    STATES.lock().get(S::NAME).and_then(ErasedState::downcast::<S>)
+}
+
+/// Returns the registered state `S`, registering the state returned by `init` if there is none.
+///
+/// Returns an error if a different version of `S` is registered,
+/// which means that the crate that registered it was swapped without a migration for it.
+pub fn get_or_register<S: VersionedState, F: FnOnce() -> S>(init: F) -> Result<Arc<S>, &'static str> {
+    if let Some(existing) = STATES.lock().get(S::NAME) {
+        return downcast_registered(existing);
+    }
+    // Don't hold the lock while running `init`, as it may access other states.
+    let new_state = ErasedState::new(Arc::new(init()));
+    let mut states = STATES.lock();
+    match states.get(S::NAME) {
+        // Another task registered this state in the meantime, so drop ours after unlocking the registry.
+        Some(existing) => {
+            let result = downcast_registered(existing);
+            drop(states);
+            drop(new_state);
+            result
+        }
+        None => {
+            let result = downcast_registered(&new_state);
+            states.insert(S::NAME.to_string(), new_state);
+            result
         }
-        warn!("REPLACING LAZY_STATIC RUNQUEUES...");
     }
+}
+
+fn downcast_registered<S: VersionedState>(state: &ErasedState) -> Result<Arc<S>, &'static str> {
+    state.downcast::<S>().ok_or_else(|| {
+        error!("State {:?} is registered as version {}, but version {} was requested", S::NAME, state.version, S::VERSION);
+        "a different version of the requested state is registered"
+    })
+}
+
+/// Removes the state named `S::NAME` from the registry,
+/// returning it if it was registered with the same version as `S`.
+pub fn remove<S: VersionedState>() -> Option<Arc<S>> {
+    let removed = {
+        let mut states = STATES.lock();
+        GENERATION.fetch_add(1, Ordering::Release);
+        states.remove(S::NAME)
+    };
+    removed.and_then(|state| state.downcast::<S>())
+}
+
+/// Returns the name and version of every registered state.
+pub fn registered_states() -> Vec<(String, u32)> {
+    STATES.lock().iter().map(|(name, state)| (name.clone(), state.version)).collect()
+}
+
+
+/// A registered state that can be obtained without locking the registry,
+/// which is intended to be placed in a static.
+///
+/// The first access, and the first access after any registered state has been replaced (e.g., by a migration),
+/// gets the state from the registry and caches it; subsequent accesses only read the cache.
+/// As other tasks may still be using a cached state when it is replaced, a cached state is never freed.
+/// Thus, this should only be used for states that are rarely replaced.
+///
+/// Because the cache is invalidated whenever any state is replaced, it is safe for a `CachedState`
+/// to be copied into a new version of its crate as is when that crate is swapped.
+pub struct CachedState<S: VersionedState> {
+    cached: AtomicPtr<CacheEntry<S>>,
+}
 
-    #[cfg(loscd_eval)]
-    let hpet = hpet::get_hpet().ok_or("couldn't get HPET timer")?;
-    #[cfg(loscd_eval)]
-    let hpet_start_state_transfer = hpet.get_counter();
+struct CacheEntry<S> {
+    generation: usize,
+    state: Arc<S>,
+}
 
-    // __lazy_static_create!(RQEMPTY, AtomicMap<u8, RwLockIrqSafe<runqueue_round_robin::RunQueue>>);
-    // lazy_static! { static ref RQEMPTY: AtomicMap<u8, RwLockIrqSafe<runqueue_round_robin::RunQueue>> = AtomicMap::new(); }
-    let rq_ptr = &runqueue_round_robin::RUNQUEUES as *const _ as usize;
-    let once_rq = core::mem::replace(
-        unsafe { &mut *(rq_ptr as *mut AtomicMap<u8, RwLockIrqSafe<runqueue_round_robin::RunQueue>>) }, 
-        AtomicMap::new()
-    );
+impl<S: VersionedState> CachedState<S> {
+    /// Creates an empty cache of the registered state `S`.
+    pub const fn new() -> CachedState<S> {
+        CachedState { cached: AtomicPtr::new(ptr::null_mut()) }
+    }
 
-    #[cfg(not(loscd_eval))]
-    warn!("obtained ownership of runqueues:");
-    for (core, rq) in once_rq.iter() {
-        #[cfg(not(loscd_eval))]
-        warn!("\tRunqueue on core {:?}: {:?}", core, rq);
-        runqueue_priority::RunQueue::init(*core)?;
-        for t in rq.read().iter() {
-            runqueue_priority::RunQueue::add_task_to_specific_runqueue(*core, t.deref().clone())?;
+    /// Returns the registered state `S`, registering the state returned by `init` if there is none.
+    ///
+    /// This only locks the registry if the cache is empty or outdated;
+    /// see [`get_or_register()`] for when an error is returned.
+    pub fn get_or_register<F: FnOnce() -> S>(&self, init: F) -> Result<&S, &'static str> {
+        // SAFETY: a non-null pointer refers to a leaked `CacheEntry`, which is never freed.
+        if let Some(entry) = unsafe { self.cached.load(Ordering::Acquire).as_ref() } {
+            if entry.generation == GENERATION.load(Ordering::Acquire) {
+                return Ok(&entry.state);
+            }
         }
+        // Read the generation before getting the state, such that a state replaced in the meantime
+        // invalidates this entry instead of being cached as up to date.
+        let generation = GENERATION.load(Ordering::Acquire);
+        let state = get_or_register(init)?;
+        let entry: &'static CacheEntry<S> = Box::leak(Box::new(CacheEntry { generation, state }));
+        self.cached.store(entry as *const CacheEntry<S> as *mut CacheEntry<S>, Ordering::Release);
+        Ok(&entry.state)
     }
+}
+
+
+/// The symbol name (without the crate and module path prefix) of the functions that return
+/// a [`StateMigration`], which are generated by [`state_migration!`]
+/// and take the form of a [`StateMigrationDescriptor`].
+pub const STATE_MIGRATION_SYMBOL: &str = "__state_migration";
+
+/// A function that returns the description of a state migration, generated by [`state_migration!`].
+pub type StateMigrationDescriptor = fn() -> StateMigration;
 
-    #[cfg(loscd_eval)] {
-        let hpet_end_state_transfer = hpet.get_counter();
-        warn!("Measured time in units of HPET ticks:
-            state transfer, {}
-            ",
-            hpet_end_state_transfer - hpet_start_state_transfer,
-        );
+/// A type-erased function that converts one version of a state into another.
+pub type MigrationFunction = fn(&ErasedState) -> Result<ErasedState, &'static str>;
+
+/// A function that converts a state from one version into another, declared with [`state_migration!`].
+#[derive(Clone, Copy, Debug)]
+pub struct StateMigration {
+    /// The name of the migrated state.
+    pub name: &'static str,
+    /// The name of the old state, which must be the same as `name`.
+    pub from_name: &'static str,
+    /// The version of the old state.
+    pub from_version: u32,
+    /// The version of the new state.
+    pub to_version: u32,
+    run: MigrationFunction,
+}
+
+impl StateMigration {
+    /// Describes a migration from `Old` to `New` that is carried out by the given function.
+    pub fn new<Old: VersionedState, New: VersionedState>(run: MigrationFunction) -> StateMigration {
+        StateMigration {
+            name: New::NAME,
+            from_name: Old::NAME,
+            from_version: Old::VERSION,
+            to_version: New::VERSION,
+            run,
+        }
     }
+}
 
-    core::mem::drop(once_rq);
+/// Converts the given `old` state into a `New` state using the given migration function.
+///
+/// This is used by the functions generated by [`state_migration!`].
+#[doc(hidden)]
+pub fn migrate<Old: VersionedState, New: VersionedState>(
+    old: &ErasedState,
+    migration: fn(&Old) -> Result<New, &'static str>,
+) -> Result<ErasedState, &'static str> {
+    let old = old.downcast::<Old>().ok_or("the state to migrate has a different version or layout than expected")?;
+    let new = migration(&old)?;
+    Ok(ErasedState::new(Arc::new(new)))
+}
+
+/// Declares a migration from the state type `Old` to the state type `New`,
+/// which `crate_swap::swap_crates()` runs when this crate is swapped in.
+///
+/// The migration function must have the signature `fn(&Old) -> Result<New, &'static str>`.
+/// Both types must implement [`VersionedState`] with the same `NAME`.
+/// The given module name must be unique within the invoking module.
+///
+/// # Example
+/// ```ignore
+/// state_migration!(ticks_v1_to_v2: TicksV1 => TicksV2 = migrate_ticks);
+/// ```
+#[macro_export]
+macro_rules! state_migration {
+    ($module:ident: $old:ty => $new:ty = $migration:path) => {
+        #[doc(hidden)]
+        pub mod $module {
+            #[allow(unused_imports)]
+            use super::*;
+
+            fn run(old: &$crate::ErasedState) -> ::core::result::Result<$crate::ErasedState, &'static str> {
+                $crate::migrate::<$old, $new>(old, $migration)
+            }
+
+            #[inline(never)]
+            pub fn __state_migration() -> $crate::StateMigration {
+                $crate::StateMigration::new::<$old, $new>(run)
+            }
 
-    #[cfg(not(loscd_eval))]
-    warn!("REPLACED LAZY_STATIC RUNQUEUES...");
+            // Ensures that the above function is included in this crate's object file, even though it's never called directly.
+            #[used]
+            static STATE_MIGRATION_DESCRIPTOR: $crate::StateMigrationDescriptor = __state_migration;
+        }
+    };
+}
 
 
-    Ok(())
+/// The new versions of states produced by running state migrations,
+/// which haven't yet replaced the registered versions.
+///
+/// The registry isn't locked in the meantime.
+/// Dropping this discards the new versions.
+pub struct PreparedMigrations {
+    /// The value of [`GENERATION`] when the migrated states were snapshotted.
+    generation: usize,
+    /// For each migrated state, its name, the registered version it was migrated from, and its new version.
+    migrated: Vec<(String, ErasedState, ErasedState)>,
+    /// The names of the states that the migrations apply to but that weren't registered,
+    /// which must not be registered before the migrations are committed.
+    unregistered: Vec<String>,
 }
 
+/// Runs the given migrations on a snapshot of the registered states, without changing the registry.
+///
+/// The registry is only locked while taking the snapshot, so the migration functions may access it,
+/// but any state that they replace or remove causes committing the migrations to fail.
+///
+/// For each state that the migrations apply to, migrations are chained from the registered version
+/// until the newest version that any of the given migrations produces is reached.
+/// States that aren't registered are skipped, as the new crates will register them directly.
+///
+/// Returns an error if any migration fails or if there is no chain of migrations to a state's newest version.
+pub fn prepare_migrations(migrations: &[StateMigration]) -> Result<PreparedMigrations, &'static str> {
+    for migration in migrations {
+        if migration.name != migration.from_name {
+            error!("State migration {:?} converts a different state {:?}", migration.name, migration.from_name);
+            return Err("a state migration's old and new states have different names");
+        }
+        if migration.from_version == migration.to_version {
+            error!("State migration {:?} converts version {} into itself", migration.name, migration.from_version);
+            return Err("a state migration's old and new states have the same version");
+        }
+    }
+
+    let mut names: Vec<&str> = migrations.iter().map(|m| m.name).collect();
+    names.sort_unstable();
+    names.dedup();
 
+    let (generation, snapshot) = {
+        let states = STATES.lock();
+        let snapshot: Vec<(&str, Option<ErasedState>)> = names.iter()
+            .map(|name| (*name, states.get(*name).cloned()))
+            .collect();
+        (GENERATION.load(Ordering::Acquire), snapshot)
+    };
+
+    let mut migrated = Vec::new();
+    let mut unregistered = Vec::new();
+    for (name, registered) in snapshot {
+        let Some(registered) = registered else {
+            unregistered.push(name.to_string());
+            continue;
+        };
+        let newest_version = migrations.iter()
+            .filter(|m| m.name == name)
+            .map(|m| m.to_version)
+            .max()
+            .unwrap_or(registered.version);
+
+        let mut current = registered.clone();
+        let mut steps = 0;
+        while current.version != newest_version {
+            let migration = migrations.iter()
+                .find(|m| m.name == name && m.from_version == current.version)
+                .ok_or_else(|| {
+                    error!("No state migration for {:?} from version {} towards version {}", name, current.version, newest_version);
+                    "there is no state migration from the registered version of a state to its newest version"
+                })?;
+            steps += 1;
+            if steps > migrations.len() {
+                return Err("state migrations form a cycle");
+            }
+            debug!("Migrating state {:?} from version {} to {}", name, migration.from_version, migration.to_version);
+            current = (migration.run)(&current).map_err(|e| {
+                error!("Failed to migrate state {:?} from version {} to {}: {}", name, migration.from_version, migration.to_version, e);
+                e
+            })?;
+        }
+        if steps > 0 {
+            migrated.push((name.to_string(), registered, current));
+        }
+    }
+    Ok(PreparedMigrations { generation, migrated, unregistered })
+}
 
-//////////////////////////////////////////////////////////
-///////////// Generic trait redefinitions ////////////////
-//////////////////////////////////////////////////////////
+impl PreparedMigrations {
+    /// Returns the name and new version of every migrated state.
+    pub fn migrated_states(&self) -> Vec<(String, u32)> {
+        self.migrated.iter().map(|(name, _, state)| (name.clone(), state.version)).collect()
+    }
 
-/// Just like core::convert::From
-trait MyFrom<T>: Sized {
-    fn from(t: T) -> Self;
+    /// Replaces the registered states with their new versions,
+    /// returning the previous versions such that they can be restored.
+    ///
+    /// Returns an error, leaving the registry unchanged, if any of the migrated states was replaced or removed,
+    /// or any of the skipped states was registered, since the migrations were prepared.
+    pub fn commit(self) -> Result<MigratedStates, &'static str> {
+        let PreparedMigrations { generation, migrated, unregistered } = self;
+        if migrated.is_empty() && unregistered.is_empty() {
+            return Ok(MigratedStates { previous: Vec::new() });
+        }
+        let mut states = STATES.lock();
+        // If no state was replaced or removed since the snapshot, only newly-registered states must be checked.
+        let replaced_or_removed = GENERATION.load(Ordering::Acquire) != generation
+            && migrated.iter().any(|(name, registered, _)| {
+                states.get(name).map_or(true, |current| !ptr::eq(current.ptr, registered.ptr))
+            });
+        let newly_registered = unregistered.iter().any(|name| states.contains_key(name));
+        if replaced_or_removed || newly_registered {
+            drop(states);
+            error!("A migrated state was replaced, removed, or registered while its migration was running");
+            return Err("a migrated state changed while its migration was running");
+        }
+        if !migrated.is_empty() {
+            GENERATION.fetch_add(1, Ordering::Release);
+        }
+        let previous = migrated.into_iter()
+            .map(|(name, _registered, new_state)| {
+                let previous_state = states.insert(name.clone(), new_state);
+                (name, previous_state)
+            })
+            .collect();
+        Ok(MigratedStates { previous })
+    }
 }
 
-/// Just like core::convert::Into
-trait MyInto<T>: Sized {
-    fn into(self) -> T;
+/// The previous versions of states that were replaced by committing [`PreparedMigrations`].
+///
+/// Dropping this discards the previous versions.
+pub struct MigratedStates {
+    previous: Vec<(String, Option<ErasedState>)>,
 }
 
-impl<T, U> MyInto<U> for T where U: MyFrom<T> {
-    fn into(self) -> U {
-        U::from(self)
+impl MigratedStates {
+    /// Returns `true` if no states were migrated.
+    pub fn is_empty(&self) -> bool {
+        self.previous.is_empty()
+    }
+
+    /// Puts the previous versions of the migrated states back into the registry,
+    /// e.g., when a swap is undone.
+    pub fn restore(self) {
+        let mut replaced = Vec::with_capacity(self.previous.len());
+        {
+            let mut states = STATES.lock();
+            GENERATION.fetch_add(1, Ordering::Release);
+            for (name, previous_state) in self.previous {
+                replaced.push(match previous_state {
+                    Some(state) => states.insert(name, state),
+                    None => states.remove(&name),
+                });
+            }
+        }
+        // The new versions are dropped here, after the registry is unlocked.
+        drop(replaced);
     }
 }
@@ -46,15 +46,19 @@ extern crate spin;
 extern crate kernel_config;
 extern crate crossbeam_utils;
 extern crate no_drop;
+extern crate task_group;
+extern crate tsc;
+extern crate memory_accounting;
 
 
 use core::{
     any::Any,
     fmt,
     hash::{Hash, Hasher},
+    mem::size_of,
     ops::Deref,
     panic::PanicInfo,
-    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
 };
 use alloc::{
     boxed::Box,
@@ -73,6 +77,8 @@ use spin::Mutex;
 use x86_64::registers::model_specific::FsBase;
 use preemption::PreemptionGuard;
 use no_drop::NoDrop;
+use task_group::{GroupMembership, TaskGroupRef};
+use memory_accounting::{MemoryCounters, MemoryUsage};
 
 /// The function signature of the callback that will be invoked
 /// when a given Task panics or otherwise fails, e.g., a machine exception occurs.
@@ -237,6 +243,25 @@ pub enum SimdExt {
     None,
 }
 
+
+/// The register values that a [`Task`] saved onto its stack when it was last switched out.
+///
+/// These are the registers needed to walk a non-running task's call stack;
+/// see [`Task::saved_registers()`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SavedRegisters {
+    /// The address at which the task will resume execution.
+    pub instruction_pointer: usize,
+    /// The value of the stack pointer once the task resumes execution.
+    pub stack_pointer: usize,
+    pub rbx: usize,
+    pub rbp: usize,
+    pub r12: usize,
+    pub r13: usize,
+    pub r14: usize,
+    pub r15: usize,
+}
+
 /// A struct holding data items needed to restart a `Task`.
 pub struct RestartInfo {
     /// Stores the argument of the task for restartable tasks
@@ -298,8 +323,9 @@ pub struct TaskInner {
     ///       the `TaskInner` structure, because it's not really related
     ///       to a specific task, but rather to a specific CPU's preemption status.
     preemption_guard: Option<PreemptionGuard>,
-    /// Data that should be dropped after switching away from a task that has exited.
-    /// Currently, this contains the previous Task's `TaskRef` removed from its TLS area.
+    /// The task that was switched out right before this task was switched in,
+    /// which is kept until the context switch has completed in order to mark its context as saved.
+    /// If that task has exited, this is the `TaskRef` removed from its TLS area, which is dropped then.
     drop_after_task_switch: Option<TaskRef>,
     /// The kernel stack, which all `Task`s must have in order to execute.
     pub kstack: Stack,
@@ -348,6 +374,23 @@ pub struct Task {
     ///
     /// This is not public because it permits interior mutability.
     runstate: AtomicCell<RunState>,
+    /// The TSC value at which this task last entered the [`RunState::Blocked`] state,
+    /// or `0` if it is not currently blocked. Used to detect hung tasks.
+    ///
+    /// This is not public because it permits interior mutability.
+    blocked_since: AtomicU64,
+    /// Whether this Task has been suspended, which prevents it from being scheduled in
+    /// regardless of its runstate, e.g., while the crates it uses are being swapped.
+    ///
+    /// This is not public because it permits interior mutability.
+    suspended: AtomicBool,
+    /// Whether this Task's execution context has been completely saved onto its stack,
+    /// i.e., whether its saved stack pointer is valid.
+    /// This is cleared right before this Task is switched out and set once the next task has been switched in.
+    /// A new Task's initial context is set up before it first runs, so this starts out `true`.
+    ///
+    /// This is not public because it permits interior mutability.
+    context_saved: AtomicBool,
     /// Whether this Task is joinable.
     /// * If `true`, another task holds the [`JoinableTaskRef`] object that was created
     ///   by [`TaskRef::new()`], which indicates that that other task is able to
@@ -368,6 +411,16 @@ pub struct Task {
     pub app_crate: Option<Arc<AppCrateRef>>,
     /// This `Task` is linked into and runs within the context of this [`CrateNamespace`].
     pub namespace: Arc<CrateNamespace>,
+    /// This `Task`'s membership in its [`task_group::TaskGroup`], if it belongs to one,
+    /// which bounds the resources that this task and the rest of its group can consume.
+    ///
+    /// This is not public because it permits interior mutability.
+    group: Option<GroupMembership>,
+    /// The memory that this `Task` has allocated and not yet freed,
+    /// as charged by the frame allocator, page allocator, and heap.
+    ///
+    /// This is not public because it permits interior mutability.
+    memory_counters: MemoryCounters,
     /// The function that should be run as a last-ditch attempt to recover from this task's failure,
     /// e.g., this can be called when unwinding itself fails. 
     /// Typically, it will point to this Task's specific instance of `spawn::task_cleanup_failure()`,
@@ -418,7 +471,9 @@ impl Task {
     /// Creates a new Task structure and initializes it to be non-Runnable.
     /// 
     /// By default, the new `Task` will inherit some of its states from the given `parent_task`:
-    /// its `Environment`, `MemoryManagementInfo`, `CrateNamespace`, and `app_crate` reference.
+    /// its `Environment`, `MemoryManagementInfo`, `CrateNamespace`, `app_crate` reference,
+    /// and task group. The new `Task` joins the parent's task group, if it has one,
+    /// which fails if that group's limit on the number of tasks has been reached.
     /// If necessary, those states can be changed by setting them for the returned `Task`.
     /// 
     /// # Arguments
@@ -450,9 +505,10 @@ impl Task {
                 taskref.namespace.clone(),
                 taskref.inner.lock().env.clone(),
                 taskref.app_crate.clone(),
+                taskref.group().cloned(),
             )
         };
-        let (mmi, namespace, env, app_crate) = parent_task
+        let (mmi, namespace, env, app_crate, group) = parent_task
             .map(clone_inherited_items)
             .ok_or(())
             .or_else(|_| with_current_task(clone_inherited_items))
@@ -462,7 +518,9 @@ impl Task {
             .or_else(|| stack::alloc_stack(KERNEL_STACK_SIZE_IN_PAGES, &mut mmi.lock().page_table))
             .ok_or("couldn't allocate kernel stack!")?;
 
-        Ok(Task::new_internal(kstack, mmi, namespace, env, app_crate, failure_cleanup_function))
+        let mut task = Task::new_internal(kstack, mmi, namespace, env, app_crate, failure_cleanup_function);
+        task.set_group(group)?;
+        Ok(task)
     }
     
     /// The internal routine for creating a `Task`, which does not make assumptions 
@@ -502,12 +560,17 @@ impl Task {
             name: format!("task_{}", task_id),
             running_on_cpu: AtomicCell::new(None.into()),
             runstate: AtomicCell::new(RunState::Initing),
+            blocked_since: AtomicU64::new(0),
+            suspended: AtomicBool::new(false),
+            context_saved: AtomicBool::new(true),
             // Tasks are not considered "joinable" until passed to `TaskRef::new()`
             joinable: AtomicBool::new(false),
             mmi,
             is_an_idle_task: false,
             app_crate,
             namespace,
+            group: None,
+            memory_counters: MemoryCounters::new(),
             failure_cleanup_function,
             tls_area,
 
@@ -535,6 +598,32 @@ impl Task {
         Arc::clone(&self.inner.lock().env)
     }
 
+    /// Returns the task group that this `Task` belongs to, if any.
+    pub fn group(&self) -> Option<&TaskGroupRef> {
+        self.group.as_ref().map(GroupMembership::group)
+    }
+
+    /// Moves this `Task` into the given task `group`, or out of its current group if `None`.
+    ///
+    /// This can only be done while a `Task` is being created, e.g., by the `spawn` crate,
+    /// since a `&mut Task` cannot be obtained afterwards.
+    ///
+    /// Returns an error if the new group's limit on the number of tasks has been reached,
+    /// in which case this `Task` is left in no group.
+    pub fn set_group(&mut self, group: Option<TaskGroupRef>) -> Result<(), &'static str> {
+        // Leave the current group first, in case the new group is the same group.
+        self.group = None;
+        self.group = group.map(GroupMembership::join).transpose()?;
+        Ok(())
+    }
+
+    /// Returns a snapshot of the memory that this `Task` has allocated and not yet freed.
+    ///
+    /// See the [`memory_accounting`] crate for how memory is charged to each task.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.memory_counters.usage()
+    }
+
     /// Returns `true` if this `Task` is currently running.
     pub fn is_running(&self) -> bool {
         self.running_on_cpu().is_some()
@@ -572,12 +661,103 @@ impl Task {
         self.runstate.load()
     }
 
-    /// Returns `true` if this `Task` is Runnable, i.e., able to be scheduled in.
+    /// Returns `true` if this `Task` is Runnable and not suspended, i.e., able to be scheduled in.
     ///
     /// # Note
     /// This does *NOT* mean that this `Task` is actually currently running, just that it is *able* to be run.
     pub fn is_runnable(&self) -> bool {
-        self.runstate() == RunState::Runnable
+        self.runstate() == RunState::Runnable && !self.is_suspended()
+    }
+
+    /// Returns `true` if this `Task` has been suspended by [`Task::suspend()`].
+    pub fn is_suspended(&self) -> bool {
+        self.suspended.load(Ordering::Acquire)
+    }
+
+    /// Suspends this `Task`, preventing it from being scheduled in until [`Task::resume()`] is called.
+    ///
+    /// Unlike blocking, suspending a task doesn't change its runstate,
+    /// so it doesn't interfere with the task blocking and unblocking itself.
+    /// If this `Task` is currently running, it will continue to run until it is next preempted or yields;
+    /// use [`Task::is_running()`] to check when it has been switched out.
+    ///
+    /// Returns `true` if this `Task` was already suspended.
+    pub fn suspend(&self) -> bool {
+        self.suspended.swap(true, Ordering::AcqRel)
+    }
+
+    /// Resumes this `Task` after a previous call to [`Task::suspend()`],
+    /// allowing it to be scheduled in again.
+    ///
+    /// Returns `true` if this `Task` was suspended.
+    pub fn resume(&self) -> bool {
+        self.suspended.swap(false, Ordering::AcqRel)
+    }
+
+    /// Returns `true` if this `Task` is switched out and its execution context has been completely saved onto its stack.
+    ///
+    /// A task is marked as no longer running slightly before its context is saved,
+    /// so this may briefly be `false` after [`Task::is_running()`] has become `false`.
+    pub fn is_context_saved(&self) -> bool {
+        !self.is_running() && self.context_saved.load(Ordering::Acquire)
+    }
+
+    /// Returns the register values that this `Task` saved onto its stack when it was last switched out,
+    /// which can be used to walk its call stack, e.g., with the `unwind` crate.
+    ///
+    /// Returns `None` if this `Task`'s context isn't currently saved (see [`Task::is_context_saved()`])
+    /// or if it is not in a state where it has a saved execution context, i.e., it is being initialized or has exited.
+    ///
+    /// The returned values are only meaningful while this `Task` remains switched out,
+    /// so the caller should ensure this `Task` has been suspended or blocked beforehand.
+    ///
+    /// # Locking / Deadlock
+    /// Obtains the lock on this `Task`'s inner state in order to read its saved stack pointer.
+    pub fn saved_registers(&self) -> Option<SavedRegisters> {
+        if !self.is_context_saved() || !matches!(self.runstate(), RunState::Runnable | RunState::Blocked) {
+            return None;
+        }
+        let saved_sp = self.inner.lock().saved_sp;
+        if saved_sp == 0 {
+            return None;
+        }
+        // The regular registers are always at the top of the saved context, regardless of SIMD extensions,
+        // and the context switch routine "returns" into this task's code by popping the last one (rip).
+        let context_end = saved_sp + self.saved_context_size();
+        let regular_context = context_end - size_of::<context_switch::ContextRegular>();
+        // SAFE: this task isn't running, so its saved context lies on its stack, which is mapped.
+        let context = unsafe {
+            core::ptr::read_unaligned(regular_context as *const context_switch::ContextRegular)
+        };
+        let [rbx, rbp, r12, r13, r14, r15] = context.callee_saved_registers();
+        Some(SavedRegisters {
+            instruction_pointer: context.instruction_pointer(),
+            stack_pointer: context_end,
+            rbx, rbp, r12, r13, r14, r15,
+        })
+    }
+
+    /// Returns the size of the execution context that this `Task` saves onto its stack when switched out.
+    fn saved_context_size(&self) -> usize {
+        #[cfg(simd_personality)] {
+            match self.simd {
+                SimdExt::AVX  => size_of::<context_switch::ContextAVX>(),
+                SimdExt::SSE  => size_of::<context_switch::ContextSSE>(),
+                SimdExt::None => size_of::<context_switch::ContextRegular>(),
+            }
+        }
+        #[cfg(not(simd_personality))] {
+            size_of::<context_switch::Context>()
+        }
+    }
+
+    /// Returns the TSC value at which this `Task` most recently became blocked,
+    /// or `None` if it is not currently blocked.
+    pub fn blocked_since(&self) -> Option<u64> {
+        match self.blocked_since.load(Ordering::Relaxed) {
+            0 => None,
+            tsc => Some(tsc),
+        }
     }
 
     /// Returns the namespace in which this `Task` is loaded/linked into and runs within.
@@ -700,6 +880,7 @@ impl Task {
         use RunState::{Blocked, Runnable};
 
         if self.runstate.compare_exchange(Runnable, Blocked).is_ok() {
+            self.mark_blocked_now();
             Ok(Runnable)
         } else if self.runstate.compare_exchange(Blocked, Blocked).is_ok() {
             warn!("Blocked an already blocked task: {:?}\n\t --> Current {:?}",
@@ -720,6 +901,7 @@ impl Task {
     /// or the current runstate on error.
     pub fn block_initing_task(&self) -> Result<RunState, RunState> {
         if self.runstate.compare_exchange(RunState::Initing, RunState::Blocked).is_ok() {
+            self.mark_blocked_now();
             Ok(RunState::Initing)
         } else {
             Err(self.runstate.load())
@@ -734,6 +916,7 @@ impl Task {
         use RunState::{Blocked, Runnable};
 
         if self.runstate.compare_exchange(Blocked, Runnable).is_ok() {
+            self.blocked_since.store(0, Ordering::Relaxed);
             Ok(Blocked)
         } else if self.runstate.compare_exchange(Runnable, Runnable).is_ok() {
             warn!("Unblocked an already runnable task: {:?}\n\t --> Current {:?}",
@@ -745,6 +928,13 @@ impl Task {
         }
     }
 
+    /// Records the current time as the moment this `Task` became blocked.
+    fn mark_blocked_now(&self) {
+        // Zero is reserved to mean "not blocked", which the TSC never reads after boot.
+        let now = u128::from(tsc::tsc_ticks()) as u64;
+        self.blocked_since.store(core::cmp::max(now, 1), Ordering::Relaxed);
+    }
+
     /// Makes this `Task` `Runnable` if it is a newly-spawned and fully initialized task.
     ///
     /// This is a special case only to be used when spawning a new task that
@@ -770,8 +960,8 @@ impl Task {
     /// Perform any actions needed after a context switch.
     /// 
     /// Currently this only does two things:
-    /// 1. Drops any data that the original previous task (before the context switch)
-    ///    prepared for us to drop, as specified by `TaskInner::drop_after_task_switch`.
+    /// 1. Marks the context of the previous task (before the context switch) as saved,
+    ///    and drops the `TaskRef` it prepared for us, as specified by `TaskInner::drop_after_task_switch`.
     /// 2. Obtains the preemption guard such that preemption can be re-enabled
     ///    when it is appropriate to do so.
     #[doc(hidden)]
@@ -779,6 +969,9 @@ impl Task {
         // Step 1: drop data from previously running task
         {
             let prev_task_data_to_drop = self.inner.lock().drop_after_task_switch.take();
+            if let Some(prev_task) = prev_task_data_to_drop.as_ref() {
+                prev_task.context_saved.store(true, Ordering::Release);
+            }
             drop(prev_task_data_to_drop);
         }
 
@@ -1063,7 +1256,8 @@ fn task_switch_inner(
         inner.saved_sp
     };
 
-    // Mark the current task as no longer running
+    // Mark the current task as no longer running; its context is saved during the context switch.
+    curr.context_saved.store(false, Ordering::Release);
     curr.running_on_cpu.store(None.into());
 
     // After this point, we may need to mutate the `curr_task_tls_slot` (if curr has exited),
@@ -1077,13 +1271,15 @@ fn task_switch_inner(
     // Thus, we need to remove or "deinit" the `TaskRef` in its TLS area
     // in order to ensure that its `TaskRef` reference count will be decremented properly
     // and thus its task struct will eventually be dropped.
-    // We store the removed `TaskRef` in the next Task struct so that it remains accessible
-    // until *after* the context switch.
-    if curr_task_has_exited {
+    // We store the removed `TaskRef` (or a new one, if it hasn't exited) in the next Task struct
+    // so that it remains accessible until *after* the context switch, when its context is marked as saved.
+    let _prev_taskref = if curr_task_has_exited {
         // trace!("task_switch(): deiniting current task TLS for: {:?}, next: {}", curr_task_tls_slot.as_deref(), next.deref());
-        let _prev_taskref = curr_task_tls_slot.take();
-        next.inner.lock().drop_after_task_switch = _prev_taskref;
-    }
+        curr_task_tls_slot.take()
+    } else {
+        curr_task_tls_slot.clone()
+    };
+    next.inner.lock().drop_after_task_switch = _prev_taskref;
 
     // Now, set the next task as the current task: the task running on this CPU.
     //
@@ -1384,6 +1580,10 @@ pub fn bootstrap_task(
         bootstrap_task_cleanup_failure,
     );
     bootstrap_task.name = format!("bootstrap_task_core_{}", apic_id);
+    // Allow the heap and frame allocators to charge allocations to the current task's group.
+    task_group::set_current_membership_func(with_current_group_membership);
+    // Allow the allocators to account for the memory allocated by the current task.
+    memory_accounting::set_current_counters_func(with_current_memory_counters);
     bootstrap_task.runstate.store(RunState::Runnable);
     bootstrap_task.running_on_cpu.store(Some(apic_id).into()); 
     bootstrap_task.inner.get_mut().pinned_core = Some(apic_id); // can only run on this CPU core
@@ -1414,6 +1614,26 @@ pub fn bootstrap_task(
 }
 
 
+/// Invokes the given function with the current task's group membership, if it has one.
+///
+/// This is registered as the [`task_group::CurrentMembershipFunc`] callback.
+fn with_current_group_membership(f: &mut dyn FnMut(&GroupMembership)) {
+    let _ = with_current_task(|t| {
+        if let Some(membership) = t.group.as_ref() {
+            f(membership);
+        }
+    });
+}
+
+
+/// Invokes the given function with the current task's memory counters.
+///
+/// This is registered as the [`memory_accounting::CurrentCountersFunc`] callback.
+fn with_current_memory_counters(f: &mut dyn FnMut(&MemoryCounters)) {
+    let _ = with_current_task(|t| f(&t.memory_counters));
+}
+
+
 /// This is just like `spawn::task_cleanup_failure()`,
 /// but for the initial tasks bootstrapped from each core's first execution context.
 /// 
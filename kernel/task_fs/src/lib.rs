@@ -10,6 +10,11 @@
 //!     about the task's memory management information
 //! 5) MmiFile: lazily computed file that contains information about the task's
 //!     memory management information
+//! 6) GroupFile: lazily computed file that holds the resource limits and usage
+//!     of the task group that the task belongs to
+//! 7) MemoryFile: lazily computed file that holds the memory allocated by the task
+//! 8) MemInfoFile: lazily computed file in the root directory that holds
+//!     the memory allocated by the whole system
 //! 
 //! * Note that all the structs here are NOT persistent in the filesystem EXCEPT
 //! for the TaskFs struct, which contains all the individual TaskDirs. This means 
@@ -20,7 +25,7 @@
 //! The hierarchy (tree) is as follows:
 //! 
 //!             TaskDir
-//!         TaskFile    MmiDir
+//!         TaskFile    MmiDir      GroupFile   MemoryFile
 //!                         MmiFile
 //! 
 
@@ -34,6 +39,8 @@ extern crate task;
 extern crate path;
 extern crate root;
 extern crate io;
+extern crate task_group;
+extern crate memory_accounting;
 
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
@@ -44,17 +51,22 @@ use memory::MappedPages;
 use task::{TaskRef, TASKLIST};
 use path::Path;
 use io::{ByteReader, ByteWriter, KnownLength, IoError};
+use task_group::TaskGroupRef;
+use memory_accounting::MemoryUsage;
 
 
 /// The name of the VFS directory that exposes task info in the root. 
 pub const TASKS_DIRECTORY_NAME: &str = "tasks";
 /// The absolute path of the tasks directory, which is currently below the root
 pub const TASKS_DIRECTORY_PATH: &str = "/tasks"; 
+/// The name of the VFS file that exposes the memory usage of the whole system in the root.
+pub const MEMINFO_FILE_NAME: &str = "meminfo";
 
 
-/// Initializes the tasks virtual filesystem directory within the root directory.
+/// Initializes the tasks virtual filesystem directory and the meminfo file within the root directory.
 pub fn init() -> Result<(), &'static str> {
     TaskFs::new()?;
+    MemInfoFile::new()?;
     Ok(())
 }
 
@@ -182,12 +194,22 @@ impl Directory for TaskDir {
             return Some(FileOrDir::Dir(Arc::new(Mutex::new(mmi_dir)) as DirRef));
         }
 
+        if child_name == "group" {
+            let group_file = GroupFile::new(self.taskref.clone());
+            return Some(FileOrDir::File(Arc::new(Mutex::new(group_file)) as FileRef));
+        }
+
+        if child_name == "memory" {
+            let memory_file = MemoryFile::new(self.taskref.clone());
+            return Some(FileOrDir::File(Arc::new(Mutex::new(memory_file)) as FileRef));
+        }
+
         None
     }
 
     /// Returns a string listing all the children in the directory
     fn list(&self) -> Vec<String> {
-        let children = vec!["mmi".to_string(), "taskInfo".to_string()];
+        let children = vec!["mmi".to_string(), "taskInfo".to_string(), "group".to_string(), "memory".to_string()];
         children
     }
 
@@ -247,13 +269,16 @@ impl TaskFile {
             " "
         };  
 
-        format!("{0:<10} {1}\n{2:<10} {3}\n{4:<10} {5:?}\n{6:<10} {7}\n{8:<10} {9}\n{10:<10} {11:<10}", 
+        let group = self.taskref.group().map(|g| format!("{} ({})", g.name(), g.id())).unwrap_or_else(|| String::from("-"));
+
+        format!("{0:<10} {1}\n{2:<10} {3}\n{4:<10} {5:?}\n{6:<10} {7}\n{8:<10} {9}\n{10:<10} {11:<10}\n{12:<10} {13}", 
             "name", self.taskref.name,
             "task id", self.taskref.id,
             "runstate", self.taskref.runstate(),
             "cpu", cpu,
             "pinned", pinned,
-            "task type", task_type
+            "task type", task_type,
+            "group", group
         )
     }
 }
@@ -463,3 +488,273 @@ impl File for MmiFile {
     }
 }
 
+
+
+
+
+/// Lazily computed file that contains the resource limits and usage
+/// of the task group that a task belongs to.
+pub struct GroupFile {
+    taskref: TaskRef,
+    task_id: usize,
+    path: Path, 
+}
+
+impl GroupFile {
+    pub fn new(taskref: TaskRef) -> GroupFile {
+        let task_id = taskref.id;
+        GroupFile {
+            taskref,
+            task_id,
+            path: Path::new(format!("{}/{}/group", TASKS_DIRECTORY_PATH, task_id)), 
+        }
+    }
+
+    /// Generates the group info string.
+    fn generate(&self) -> String {
+        match self.taskref.group() {
+            Some(group) => generate_group_info(group),
+            None => String::from("This task does not belong to a task group.\n"),
+        }
+    }
+}
+
+/// Generates a string describing the given task group's resource limits and usage.
+fn generate_group_info(group: &TaskGroupRef) -> String {
+    let limits = group.limits();
+    let usage = group.usage();
+    let limit = |l: Option<usize>| l.map(|l| format!("{}", l)).unwrap_or_else(|| String::from("-"));
+
+    format!("{0:<12} {1}\n{2:<12} {3}\n{4:<12} {5}\n\n{6:<12} {7:<12} {8}\n{9:<12} {10:<12} {11}\n{12:<12} {13:<12} {14}\n{15:<12} {16:<12} {17}\n{18:<12} {19:<12} {20}\n",
+        "group", group.name(),
+        "group id", group.id(),
+        "parent", group.parent().map(|p| format!("{}", p.id())).unwrap_or_else(|| String::from("-")),
+        "resource", "usage", "limit",
+        "tasks", usage.tasks, limit(limits.max_tasks),
+        "heap bytes", usage.heap_bytes, limit(limits.max_heap_bytes),
+        "frames", usage.frames, limit(limits.max_frames),
+        "cpu (ms)", usage.cpu_time.as_millis(), limits.cpu_percent.map(|p| format!("{}%", p)).unwrap_or_else(|| String::from("-")),
+    )
+}
+
+impl FsNode for GroupFile {
+    fn get_absolute_path(&self) -> String {
+        self.path.clone().into()
+    }
+
+    fn get_name(&self) -> String {
+        "group".to_string()
+    }
+
+    fn get_parent_dir(&self) -> Option<DirRef> {
+        let path = Path::new(format!("{}/{}", TASKS_DIRECTORY_PATH, self.task_id));
+        match Path::get_absolute(&path) {
+            Some(FileOrDir::Dir(d)) => Some(d),
+            _ => None,
+        }
+    }
+
+    fn set_parent_dir(&mut self, _: WeakDirRef) {
+        // do nothing
+    }
+}
+
+impl ByteReader for GroupFile {
+    fn read_at(&mut self, buf: &mut [u8], offset: usize) -> Result<usize, IoError> {
+        let output = self.generate();
+        if offset > output.len() {
+            return Err(IoError::InvalidInput);
+        }
+        let count = core::cmp::min(buf.len(), output.len() - offset);
+        buf[..count].copy_from_slice(&output.as_bytes()[offset..(offset + count)]);
+        Ok(count)
+    }
+}
+
+impl ByteWriter for GroupFile {
+    fn write_at(&mut self, _buffer: &[u8], _offset: usize) -> Result<usize, IoError> {
+        Err(IoError::from("not permitted to write task contents through the task VFS"))
+    } 
+    fn flush(&mut self) -> Result<(), IoError> { Ok(()) }
+}
+
+impl KnownLength for GroupFile {
+    fn len(&self) -> usize {
+        self.generate().len() 
+    }
+}
+
+impl File for GroupFile {
+    fn as_mapping(&self) -> Result<&MappedPages, &'static str> {
+        Err("task files are autogenerated, cannot be memory mapped")
+    }
+}
+
+
+
+
+
+/// Lazily computed file that contains the memory that a task has allocated and not yet freed.
+pub struct MemoryFile {
+    taskref: TaskRef,
+    task_id: usize,
+    path: Path, 
+}
+
+impl MemoryFile {
+    pub fn new(taskref: TaskRef) -> MemoryFile {
+        let task_id = taskref.id;
+        MemoryFile {
+            taskref,
+            task_id,
+            path: Path::new(format!("{}/{}/memory", TASKS_DIRECTORY_PATH, task_id)), 
+        }
+    }
+
+    /// Generates the memory usage string.
+    fn generate(&self) -> String {
+        generate_memory_usage(&self.taskref.memory_usage())
+    }
+}
+
+/// Generates a string describing the given memory usage.
+fn generate_memory_usage(usage: &MemoryUsage) -> String {
+    format!("{0:<18} {1}\n{2:<18} {3}\n{4:<18} {5}\n{6:<18} {7}\n",
+        "frames", usage.frames,
+        "pages", usage.pages,
+        "heap bytes", usage.heap_bytes,
+        "heap allocations", usage.heap_allocations,
+    )
+}
+
+impl FsNode for MemoryFile {
+    fn get_absolute_path(&self) -> String {
+        self.path.clone().into()
+    }
+
+    fn get_name(&self) -> String {
+        "memory".to_string()
+    }
+
+    fn get_parent_dir(&self) -> Option<DirRef> {
+        let path = Path::new(format!("{}/{}", TASKS_DIRECTORY_PATH, self.task_id));
+        match Path::get_absolute(&path) {
+            Some(FileOrDir::Dir(d)) => Some(d),
+            _ => None,
+        }
+    }
+
+    fn set_parent_dir(&mut self, _: WeakDirRef) {
+        // do nothing
+    }
+}
+
+impl ByteReader for MemoryFile {
+    fn read_at(&mut self, buf: &mut [u8], offset: usize) -> Result<usize, IoError> {
+        let output = self.generate();
+        if offset > output.len() {
+            return Err(IoError::InvalidInput);
+        }
+        let count = core::cmp::min(buf.len(), output.len() - offset);
+        buf[..count].copy_from_slice(&output.as_bytes()[offset..(offset + count)]);
+        Ok(count)
+    }
+}
+
+impl ByteWriter for MemoryFile {
+    fn write_at(&mut self, _buffer: &[u8], _offset: usize) -> Result<usize, IoError> {
+        Err(IoError::from("not permitted to write task contents through the task VFS"))
+    } 
+    fn flush(&mut self) -> Result<(), IoError> { Ok(()) }
+}
+
+impl KnownLength for MemoryFile {
+    fn len(&self) -> usize {
+        self.generate().len() 
+    }
+}
+
+impl File for MemoryFile {
+    fn as_mapping(&self) -> Result<&MappedPages, &'static str> {
+        Err("task files are autogenerated, cannot be memory mapped")
+    }
+}
+
+
+
+
+
+/// Lazily computed file in the root directory that contains the memory
+/// allocated by the whole system, similar to `/proc/meminfo` in Linux.
+pub struct MemInfoFile { }
+
+impl MemInfoFile {
+    fn new() -> Result<FileRef, &'static str> {
+        let root = root::get_root();
+        let file_ref = Arc::new(Mutex::new(MemInfoFile { })) as FileRef;
+        root.lock().insert(FileOrDir::File(file_ref.clone()))?;
+        Ok(file_ref)
+    }
+
+    /// Generates the system-wide memory info string.
+    fn generate(&self) -> String {
+        let lazy = memory::lazy_mapping_stats();
+        let cow = memory::copy_on_write_stats();
+        format!("{0}\n{1:<18} {2}\n{3:<18} {4}\n{5:<18} {6}\n{7:<18} {8}\n",
+            generate_memory_usage(&memory_accounting::total_usage()),
+            "lazy reserved", lazy.reserved_pages,
+            "lazy resident", lazy.resident_pages,
+            "cow shared", cow.shared_frames,
+            "cow copied", cow.copied_pages,
+        )
+    }
+}
+
+impl FsNode for MemInfoFile {
+    fn get_absolute_path(&self) -> String {
+        format!("/{}", MEMINFO_FILE_NAME)
+    }
+
+    fn get_name(&self) -> String {
+        String::from(MEMINFO_FILE_NAME)
+    }
+
+    fn get_parent_dir(&self) -> Option<DirRef> {
+        Some(root::get_root().clone())
+    }
+
+    fn set_parent_dir(&mut self, _: WeakDirRef) {
+        // do nothing
+    }
+}
+
+impl ByteReader for MemInfoFile {
+    fn read_at(&mut self, buf: &mut [u8], offset: usize) -> Result<usize, IoError> {
+        let output = self.generate();
+        if offset > output.len() {
+            return Err(IoError::InvalidInput);
+        }
+        let count = core::cmp::min(buf.len(), output.len() - offset);
+        buf[..count].copy_from_slice(&output.as_bytes()[offset..(offset + count)]);
+        Ok(count)
+    }
+}
+
+impl ByteWriter for MemInfoFile {
+    fn write_at(&mut self, _buffer: &[u8], _offset: usize) -> Result<usize, IoError> {
+        Err(IoError::from("not permitted to write the contents of the meminfo file"))
+    } 
+    fn flush(&mut self) -> Result<(), IoError> { Ok(()) }
+}
+
+impl KnownLength for MemInfoFile {
+    fn len(&self) -> usize {
+        self.generate().len() 
+    }
+}
+
+impl File for MemInfoFile {
+    fn as_mapping(&self) -> Result<&MappedPages, &'static str> {
+        Err("the meminfo file is autogenerated, cannot be memory mapped")
+    }
+}
@@ -0,0 +1,434 @@
+//! Task groups, which bound the resources that a set of tasks can consume.
+//!
+//! A [`TaskGroup`] has optional [`ResourceLimits`] on:
+//! * the number of tasks in the group, enforced when a task is spawned,
+//! * the number of heap bytes allocated by the group's tasks, enforced by the `heap` crate,
+//! * the number of physical frames allocated by the group's tasks, enforced by the `frame_allocator`,
+//! * the share of CPU time that the group's tasks may run for, enforced by the scheduler.
+//!
+//! Each `Task` belongs to at most one group, which it joins when it is created;
+//! by default, a new task joins the group of the application namespace it is spawned into, if any,
+//! or otherwise its parent task's group.
+//! Groups can themselves be nested: a group created with a parent group
+//! charges all of its resource usage to that parent (and its ancestors) as well,
+//! such that a parent group's limits bound the combined usage of all of its children.
+//!
+//! A task's membership in its group is represented by a [`GroupMembership`].
+//! Heap and frame usage is charged to the group of the task that allocates it.
+//! The allocators record that owning group with each allocation
+//! and credit the memory back to it when it is freed,
+//! regardless of which task frees it or whether the allocating task still exists.
+//!
+//! Because the heap and frame allocators are below the `task` crate in the dependency graph,
+//! they find the current task's group membership via a callback
+//! that the `task` crate registers using [`set_current_membership_func()`].
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    fmt,
+    time::Duration,
+    sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+};
+use spin::{Mutex, Once};
+
+/// A shareable reference to a [`TaskGroup`].
+pub type TaskGroupRef = Arc<TaskGroup>;
+
+/// The length of each CPU accounting period, in milliseconds.
+///
+/// A group's CPU share is the fraction of each period that its tasks may run for.
+pub const CPU_PERIOD_MILLIS: u64 = 100;
+
+/// All task groups that currently exist, indexed by their ID.
+static TASK_GROUPS: Mutex<BTreeMap<usize, Weak<TaskGroup>>> = Mutex::new(BTreeMap::new());
+
+/// The limits on the resources that a [`TaskGroup`] may consume.
+///
+/// A limit of `None` means that resource is unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// The maximum number of tasks in the group.
+    pub max_tasks: Option<usize>,
+    /// The maximum number of bytes that the group's tasks may allocate from the heap.
+    pub max_heap_bytes: Option<usize>,
+    /// The maximum number of physical frames that the group's tasks may allocate.
+    pub max_frames: Option<usize>,
+    /// The maximum share of CPU time that the group's tasks may run for,
+    /// as a percentage of a single CPU, e.g., `50` is half of one CPU
+    /// and `200` is two whole CPUs.
+    pub cpu_percent: Option<u32>,
+}
+
+/// A snapshot of the resources currently consumed by a [`TaskGroup`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+    /// The number of tasks in the group, including those in child groups.
+    pub tasks: usize,
+    /// The number of heap bytes allocated by the group's tasks.
+    pub heap_bytes: usize,
+    /// The number of physical frames allocated by the group's tasks.
+    pub frames: usize,
+    /// The total CPU time consumed by the group's tasks.
+    pub cpu_time: Duration,
+    /// The CPU time consumed by the group's tasks in the current accounting period.
+    pub cpu_time_this_period: Duration,
+}
+
+
+/// The value of an [`AtomicLimits`] CPU share that represents no limit.
+const UNLIMITED_CPU: u32 = u32::MAX;
+
+/// The lock-free representation of [`ResourceLimits`], in which `usize::MAX` represents no limit.
+///
+/// Limits are read by the scheduler and allocators, possibly from interrupt context,
+/// so they must not be protected by a lock.
+struct AtomicLimits {
+    max_tasks: AtomicUsize,
+    max_heap_bytes: AtomicUsize,
+    max_frames: AtomicUsize,
+    cpu_percent: AtomicU32,
+}
+
+impl AtomicLimits {
+    fn new(limits: ResourceLimits) -> AtomicLimits {
+        let atomic = AtomicLimits {
+            max_tasks: AtomicUsize::new(usize::MAX),
+            max_heap_bytes: AtomicUsize::new(usize::MAX),
+            max_frames: AtomicUsize::new(usize::MAX),
+            cpu_percent: AtomicU32::new(UNLIMITED_CPU),
+        };
+        atomic.store(limits);
+        atomic
+    }
+
+    fn load(&self) -> ResourceLimits {
+        let limit = |value: usize| if value == usize::MAX { None } else { Some(value) };
+        let cpu_percent = self.cpu_percent.load(Ordering::Relaxed);
+        ResourceLimits {
+            max_tasks: limit(self.max_tasks.load(Ordering::Relaxed)),
+            max_heap_bytes: limit(self.max_heap_bytes.load(Ordering::Relaxed)),
+            max_frames: limit(self.max_frames.load(Ordering::Relaxed)),
+            cpu_percent: if cpu_percent == UNLIMITED_CPU { None } else { Some(cpu_percent) },
+        }
+    }
+
+    fn store(&self, limits: ResourceLimits) {
+        self.max_tasks.store(limits.max_tasks.unwrap_or(usize::MAX), Ordering::Relaxed);
+        self.max_heap_bytes.store(limits.max_heap_bytes.unwrap_or(usize::MAX), Ordering::Relaxed);
+        self.max_frames.store(limits.max_frames.unwrap_or(usize::MAX), Ordering::Relaxed);
+        self.cpu_percent.store(limits.cpu_percent.unwrap_or(UNLIMITED_CPU), Ordering::Relaxed);
+    }
+}
+
+
+/// A group of tasks whose combined resource usage is bounded by [`ResourceLimits`].
+///
+/// See the [crate-level documentation](crate) for more details.
+pub struct TaskGroup {
+    id: usize,
+    name: String,
+    parent: Option<TaskGroupRef>,
+    limits: AtomicLimits,
+    tasks: AtomicUsize,
+    heap_bytes: AtomicUsize,
+    frames: AtomicUsize,
+    cpu_time: AtomicU64,
+    /// The TSC value at which the current CPU accounting period began.
+    period_start: AtomicU64,
+    /// The CPU time consumed in the current accounting period, in TSC ticks.
+    period_usage: AtomicU64,
+}
+
+impl TaskGroup {
+    /// Creates a new task group with the given `name` and `limits`.
+    ///
+    /// If a `parent` group is given, the new group's resource usage is also charged to that parent.
+    pub fn new(name: String, limits: ResourceLimits, parent: Option<TaskGroupRef>) -> TaskGroupRef {
+        static GROUP_ID_COUNTER: AtomicUsize = AtomicUsize::new(1);
+
+        let group = Arc::new(TaskGroup {
+            id: GROUP_ID_COUNTER.fetch_add(1, Ordering::Relaxed),
+            name,
+            parent,
+            limits: AtomicLimits::new(limits),
+            tasks: AtomicUsize::new(0),
+            heap_bytes: AtomicUsize::new(0),
+            frames: AtomicUsize::new(0),
+            cpu_time: AtomicU64::new(0),
+            period_start: AtomicU64::new(0),
+            period_usage: AtomicU64::new(0),
+        });
+        TASK_GROUPS.lock().insert(group.id, Arc::downgrade(&group));
+        group
+    }
+
+    /// Returns this group's unique ID.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Returns this group's name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns this group's parent group, if it has one.
+    pub fn parent(&self) -> Option<&TaskGroupRef> {
+        self.parent.as_ref()
+    }
+
+    /// Returns this group's current resource limits.
+    pub fn limits(&self) -> ResourceLimits {
+        self.limits.load()
+    }
+
+    /// Sets this group's resource limits.
+    ///
+    /// Lowering a limit below the current usage does not reclaim any resources;
+    /// it only prevents further resources from being consumed.
+    pub fn set_limits(&self, limits: ResourceLimits) {
+        self.limits.store(limits);
+    }
+
+    /// Returns a snapshot of the resources currently consumed by this group.
+    pub fn usage(&self) -> ResourceUsage {
+        ResourceUsage {
+            tasks: self.tasks.load(Ordering::Relaxed),
+            heap_bytes: self.heap_bytes.load(Ordering::Relaxed),
+            frames: self.frames.load(Ordering::Relaxed),
+            cpu_time: ticks_to_duration(self.cpu_time.load(Ordering::Relaxed)),
+            cpu_time_this_period: ticks_to_duration(self.period_usage.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Returns an iterator over this group and all of its ancestor groups.
+    fn ancestors(&self) -> impl Iterator<Item = &TaskGroup> {
+        core::iter::successors(Some(self), |&g| g.parent.as_deref())
+    }
+
+    /// Charges `amount` of the resource selected by `counter` to this group and its ancestors,
+    /// failing without charging anything if doing so would exceed any of their limits.
+    fn charge(
+        &self,
+        amount: usize,
+        counter: fn(&TaskGroup) -> &AtomicUsize,
+        limit: fn(&AtomicLimits) -> &AtomicUsize,
+    ) -> Result<(), &TaskGroup> {
+        for (charged, group) in self.ancestors().enumerate() {
+            let previous = counter(group).fetch_add(amount, Ordering::AcqRel);
+            let exceeded = previous.saturating_add(amount) > limit(&group.limits).load(Ordering::Relaxed);
+            if exceeded {
+                // Undo the charges to this group and to the groups before it.
+                for g in self.ancestors().take(charged + 1) {
+                    counter(g).fetch_sub(amount, Ordering::AcqRel);
+                }
+                return Err(group);
+            }
+        }
+        Ok(())
+    }
+
+    /// Credits `amount` of the resource selected by `counter` back to this group and its ancestors.
+    fn uncharge(&self, amount: usize, counter: fn(&TaskGroup) -> &AtomicUsize) {
+        for group in self.ancestors() {
+            let _ = counter(group).fetch_update(Ordering::AcqRel, Ordering::Acquire, |current| {
+                Some(current.saturating_sub(amount))
+            });
+        }
+    }
+
+    /// Credits `bytes` of heap memory that was charged to this group back to it and its ancestors.
+    pub fn uncharge_heap(&self, bytes: usize) {
+        self.uncharge(bytes, |g| &g.heap_bytes);
+    }
+
+    /// Credits `frames` physical frames that were charged to this group back to it and its ancestors.
+    pub fn uncharge_frames(&self, frames: usize) {
+        self.uncharge(frames, |g| &g.frames);
+    }
+
+    /// Charges the given CPU time (in TSC ticks) that one of this group's tasks just ran for
+    /// to this group and its ancestors.
+    ///
+    /// This is invoked by the scheduler when it switches away from a task in this group.
+    pub fn account_cpu_time(&self, ran_for_ticks: u64) {
+        let now = now();
+        let period = cpu_period_ticks();
+        for group in self.ancestors() {
+            group.cpu_time.fetch_add(ran_for_ticks, Ordering::Relaxed);
+            let start = group.period_start.load(Ordering::Relaxed);
+            if now.saturating_sub(start) >= period {
+                // A new accounting period has begun, so usage from the previous period is forgotten.
+                group.period_start.store(now, Ordering::Relaxed);
+                group.period_usage.store(ran_for_ticks, Ordering::Relaxed);
+            } else {
+                group.period_usage.fetch_add(ran_for_ticks, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Returns `true` if this group or any of its ancestors has exhausted its CPU share
+    /// for the current accounting period, meaning that the scheduler should not run
+    /// this group's tasks until the next period begins.
+    pub fn is_cpu_throttled(&self) -> bool {
+        let now = now();
+        let period = cpu_period_ticks();
+        self.ancestors().any(|group| {
+            let percent = group.limits.cpu_percent.load(Ordering::Relaxed);
+            if percent == UNLIMITED_CPU {
+                return false;
+            }
+            if now.saturating_sub(group.period_start.load(Ordering::Relaxed)) >= period {
+                return false;
+            }
+            let quota = period.saturating_mul(percent as u64) / 100;
+            group.period_usage.load(Ordering::Relaxed) >= quota
+        })
+    }
+}
+
+impl Drop for TaskGroup {
+    fn drop(&mut self) {
+        TASK_GROUPS.lock().remove(&self.id);
+    }
+}
+
+impl fmt::Debug for TaskGroup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TaskGroup")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("parent", &self.parent.as_ref().map(|p| p.id))
+            .finish()
+    }
+}
+
+/// Returns the task group with the given `id`, if it still exists.
+pub fn get_group(id: usize) -> Option<TaskGroupRef> {
+    TASK_GROUPS.lock().get(&id).and_then(Weak::upgrade)
+}
+
+/// Returns all task groups that currently exist, in order of their IDs.
+pub fn all_groups() -> Vec<TaskGroupRef> {
+    TASK_GROUPS.lock().values().filter_map(Weak::upgrade).collect()
+}
+
+/// Returns the current TSC value.
+fn now() -> u64 {
+    u128::from(tsc::tsc_ticks()) as u64
+}
+
+/// Returns the TSC frequency in ticks per second.
+fn tsc_frequency() -> u128 {
+    static FREQUENCY: Once<u128> = Once::new();
+    *FREQUENCY.call_once(|| tsc::get_tsc_frequency().unwrap_or_else(|_e| {
+        log::error!("task_group: couldn't get TSC frequency, CPU shares will be inaccurate: {}", _e);
+        1_000_000_000
+    }))
+}
+
+/// Returns the length of each CPU accounting period in TSC ticks.
+fn cpu_period_ticks() -> u64 {
+    (tsc_frequency() * CPU_PERIOD_MILLIS as u128 / 1000) as u64
+}
+
+/// Converts the given number of TSC ticks into a `Duration`.
+fn ticks_to_duration(ticks: u64) -> Duration {
+    Duration::from_nanos((ticks as u128 * 1_000_000_000 / tsc_frequency()) as u64)
+}
+
+
+/// A task's membership in a [`TaskGroup`].
+///
+/// Creating a membership adds a task to the group, and dropping it removes that task.
+pub struct GroupMembership {
+    group: TaskGroupRef,
+}
+
+impl GroupMembership {
+    /// Adds a new task to the given `group`, failing if that would exceed the group's task limit.
+    pub fn join(group: TaskGroupRef) -> Result<GroupMembership, &'static str> {
+        group.charge(1, |g| &g.tasks, |l| &l.max_tasks)
+            .map_err(|_g| "task group's limit on the number of tasks was reached")?;
+        Ok(GroupMembership { group })
+    }
+
+    /// Returns the group that this membership belongs to.
+    pub fn group(&self) -> &TaskGroupRef {
+        &self.group
+    }
+}
+
+impl Drop for GroupMembership {
+    fn drop(&mut self) {
+        self.group.uncharge(1, |g| &g.tasks);
+    }
+}
+
+impl fmt::Debug for GroupMembership {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("GroupMembership")
+            .field("group", &self.group)
+            .finish()
+    }
+}
+
+
+/// The signature of the callback that invokes the given closure
+/// with the current task's group membership, if it has one.
+pub type CurrentMembershipFunc = fn(&mut dyn FnMut(&GroupMembership));
+
+static CURRENT_MEMBERSHIP_FUNC: Once<CurrentMembershipFunc> = Once::new();
+
+/// Registers the callback used by the allocators to find the current task's group membership.
+///
+/// This is invoked by the `task` crate when tasking is initialized.
+pub fn set_current_membership_func(func: CurrentMembershipFunc) {
+    CURRENT_MEMBERSHIP_FUNC.call_once(|| func);
+}
+
+/// Invokes the given closure with the current task's group membership, if it has one.
+fn with_current_membership(f: &mut dyn FnMut(&GroupMembership)) {
+    if let Some(func) = CURRENT_MEMBERSHIP_FUNC.get() {
+        func(f);
+    }
+}
+
+/// Charges `bytes` of heap memory to the current task's group, if it has one.
+///
+/// Returns the group that was charged, which the heap must record with the allocation
+/// and credit via [`TaskGroup::uncharge_heap()`] when the allocation is freed.
+/// Returns an error if that would exceed the group's heap limit,
+/// in which case the allocation should fail.
+pub fn charge_current_heap(bytes: usize) -> Result<Option<TaskGroupRef>, &'static str> {
+    let mut result = Ok(None);
+    with_current_membership(&mut |m| result = m.group.charge(bytes, |g| &g.heap_bytes, |l| &l.max_heap_bytes)
+        .map(|_| Some(m.group.clone()))
+        .map_err(|_g| "task group's heap limit was reached")
+    );
+    result
+}
+
+/// Charges `frames` physical frames to the current task's group, if it has one.
+///
+/// Returns the group that was charged, which the frame allocator must record with the allocation
+/// and credit via [`TaskGroup::uncharge_frames()`] when those frames are freed.
+/// Returns an error if that would exceed the group's frame limit,
+/// in which case the allocation should fail.
+pub fn charge_current_frames(frames: usize) -> Result<Option<TaskGroupRef>, &'static str> {
+    let mut result = Ok(None);
+    with_current_membership(&mut |m| result = m.group.charge(frames, |g| &g.frames, |l| &l.max_frames)
+        .map(|_| Some(m.group.clone()))
+        .map_err(|_g| "task group's limit on physical frames was reached")
+    );
+    result
+}
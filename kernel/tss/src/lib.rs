@@ -15,10 +15,35 @@ use memory::VirtualAddress;
 
 /// The index of the double fault stack in a TaskStateSegment (TSS)
 pub const DOUBLE_FAULT_IST_INDEX: usize = 0;
+/// The index of the page fault stack in a TaskStateSegment (TSS).
+///
+/// Page faults are delivered onto their own stack so that a fault caused by a task
+/// running out of the already-backed part of its growable stack can be resolved,
+/// which would be impossible if delivering it required pushing onto that very stack.
+///
+/// Only the topmost [`PAGE_FAULT_ENTRY_STACK_SIZE`] bytes of that stack are used by the CPU,
+/// and no work that can fault may happen there, as a nested page fault would overwrite it.
+pub const PAGE_FAULT_IST_INDEX: usize = 1;
+
+/// The size of the region at the top of the page fault stack onto which the CPU pushes
+/// page faults; the rest of the page fault stack lies beneath it.
+pub const PAGE_FAULT_ENTRY_STACK_SIZE: usize = 4096;
 
 /// The TSS list, one per core, indexed by a key of apic_id.
 static TSS: AtomicMap<u8, Mutex<TaskStateSegment>> = AtomicMap::new();
 
+/// The top of each core's page fault stack, indexed by a key of apic_id.
+///
+/// This is kept outside of the TSS such that it can be read without locking.
+static PAGE_FAULT_STACK_TOPS: AtomicMap<u8, VirtualAddress> = AtomicMap::new();
+
+
+/// Returns the top of the current core's page fault stack, i.e., the page fault IST entry,
+/// without acquiring any locks.
+pub fn page_fault_stack_top() -> Option<VirtualAddress> {
+    PAGE_FAULT_STACK_TOPS.get(&apic::get_my_apic_id()).copied()
+}
+
 
 /// Sets the current core's TSS privilege stack 0 (RSP0) entry, which points to the stack that 
 /// the x86_64 hardware automatically switches to when transitioning from Ring 3 -> Ring 0.
@@ -41,12 +66,15 @@ pub fn tss_set_rsp0(new_privilege_stack_top: VirtualAddress) -> Result<(), &'sta
 pub fn create_tss(
     apic_id: u8, 
     double_fault_stack_top_unusable: VirtualAddress, 
+    page_fault_stack_top_unusable: VirtualAddress, 
     privilege_stack_top_unusable: VirtualAddress
 ) -> &'static Mutex<TaskStateSegment> {
     let mut tss = TaskStateSegment::new();
     // TSS.RSP0 is used in kernel space after a transition from Ring 3 -> Ring 0
     tss.privilege_stack_table[0] = x86_64::VirtAddr::new(privilege_stack_top_unusable.value() as u64);
     tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX] = x86_64::VirtAddr::new(double_fault_stack_top_unusable.value() as u64);
+    tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX] = x86_64::VirtAddr::new(page_fault_stack_top_unusable.value() as u64);
+    PAGE_FAULT_STACK_TOPS.insert(apic_id, page_fault_stack_top_unusable);
 
     // insert into TSS list
     TSS.insert(apic_id, Mutex::new(tss));
@@ -79,7 +79,7 @@ use mod_mgmt::{
     StrongSectionRef,
 };
 use memory::VirtualAddress;
-use task::{TaskRef, KillReason};
+use task::{Task, TaskRef, KillReason};
 
 
 /// This is the context/state that is used during unwinding and passed around
@@ -214,6 +214,55 @@ impl StackFrameIter {
         }
     }
 
+    /// Creates a new iterator over the stack frames of the given `task`, which must not be currently running,
+    /// starting from the frame in which that task was last switched out.
+    ///
+    /// Returns an error if `task` is running or doesn't have a saved execution context;
+    /// see [`Task::saved_registers()`](task::Task::saved_registers).
+    ///
+    /// # Safety
+    /// The `task` must remain switched out (e.g., suspended or blocked) for as long as
+    /// the returned iterator is used, because iterating reads values from that task's stack.
+    pub unsafe fn for_task(task: &Task) -> Result<Self, &'static str> {
+        let saved = task.saved_registers()
+            .ok_or("StackFrameIter::for_task(): task is running or has no saved execution context")?;
+
+        let mut registers = Registers::default();
+        registers[X86_64::RBX] = Some(saved.rbx as u64);
+        registers[X86_64::RBP] = Some(saved.rbp as u64);
+        registers[X86_64::RSP] = Some(saved.stack_pointer as u64);
+        registers[X86_64::R12] = Some(saved.r12 as u64);
+        registers[X86_64::R13] = Some(saved.r13 as u64);
+        registers[X86_64::R14] = Some(saved.r14 as u64);
+        registers[X86_64::R15] = Some(saved.r15 as u64);
+        registers[X86_64::RA]  = Some(saved.instruction_pointer as u64);
+        Ok(StackFrameIter::new(task.get_namespace().clone(), registers))
+    }
+
+    /// Creates a new iterator over the stack frames of a context that was interrupted,
+    /// e.g., by an NMI, starting from the frame containing the interrupted instruction.
+    ///
+    /// Only the instruction pointer, stack pointer, and frame pointer (RBP) of the interrupted context
+    /// are known, so stack frames whose unwind info depends on other registers can't be unwound.
+    ///
+    /// # Safety
+    /// The interrupted context's stack must remain unchanged for as long as the returned iterator is used,
+    /// e.g., because that context is stuck, as iterating reads values from that stack.
+    pub unsafe fn for_interrupted_context(
+        namespace: Arc<CrateNamespace>,
+        instruction_pointer: usize,
+        stack_pointer: usize,
+        frame_pointer: usize,
+    ) -> Self {
+        let mut registers = Registers::default();
+        registers[X86_64::RBP] = Some(frame_pointer as u64);
+        registers[X86_64::RSP] = Some(stack_pointer as u64);
+        // The interrupted instruction wasn't a call, so add `1` to offset the `1` that is subtracted
+        // from each return address to find its call site, which is thus the interrupted instruction itself.
+        registers[X86_64::RA]  = Some(instruction_pointer as u64 + 1);
+        StackFrameIter::new(namespace, registers)
+    }
+
     /// Returns the array of register values as they existed during the stack frame
     /// that is currently being iterated over. 
     /// 
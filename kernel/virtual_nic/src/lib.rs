@@ -77,8 +77,7 @@ impl<S: RxQueueRegisters, T: RxDescriptor, U: TxQueueRegisters, V: TxDescriptor>
     #[allow(dead_code)]
     pub fn send_packet_on_queue(&mut self, qid: usize, transmit_buffer: TransmitBuffer) -> Result<(), &'static str> {
         if qid >= self.tx_queues.len() { return Err("Invalid qid"); }
-        self.tx_queues[qid].send_on_queue(transmit_buffer);
-        Ok(())
+        self.tx_queues[qid].send_on_queue(transmit_buffer)
     }
 
     /// Retrieve a received frame from the specified queue.
@@ -100,8 +99,7 @@ impl<S: RxQueueRegisters, T: RxDescriptor, U: TxQueueRegisters, V: TxDescriptor>
 
 impl<S: RxQueueRegisters, T: RxDescriptor, U: TxQueueRegisters, V: TxDescriptor> NetworkInterfaceCard for VirtualNic<S,T,U,V> {
     fn send_packet(&mut self, transmit_buffer: TransmitBuffer) -> Result<(), &'static str> {
-        self.tx_queues[self.default_tx_queue].send_on_queue(transmit_buffer);
-        Ok(())
+        self.tx_queues[self.default_tx_queue].send_on_queue(transmit_buffer)
     }
 
     fn get_received_frame(&mut self) -> Option<ReceivedFrame> {
@@ -7,6 +7,7 @@ extern crate task;
 extern crate scheduler;
 
 
+use core::{future::Future, pin::Pin, task::{Context, Poll, Waker}};
 use alloc::collections::VecDeque;
 use irq_safety::MutexIrqSafe;
 use task::{TaskRef, RunState};
@@ -59,10 +60,41 @@ pub enum WaitError {
     CantBlockCurrentTask,
 }
 
+/// An entity that is waiting on a `WaitQueue`.
+enum Waiter {
+    /// A blocked `Task`, which is unblocked when notified.
+    Task(TaskRef),
+    /// The `Waker` of a pending `Future`, which is woken when notified.
+    Waker(Waker),
+}
+impl Waiter {
+    fn is_task(&self, task: &TaskRef) -> bool {
+        matches!(self, Waiter::Task(t) if t == task)
+    }
+
+    fn is_waker(&self, waker: &Waker) -> bool {
+        matches!(self, Waiter::Waker(w) if w.will_wake(waker))
+    }
+
+    /// Wakes up this waiter, returning `true` if it was successfully woken up.
+    fn wake(self) -> bool {
+        match self {
+            Waiter::Task(t) => t.unblock().is_ok(),
+            Waiter::Waker(w) => {
+                w.wake();
+                true
+            }
+        }
+    }
+}
+
 /// A queue in which multiple `Task`s can wait for other `Task`s to notify them.
 /// 
+/// In addition to blocking `Task`s, asynchronous `Future`s can also wait on a `WaitQueue`
+/// via [`WaitQueue::wait_until_async()`], in which case their `Waker` is woken when notified.
+/// 
 /// This can be shared across multiple `Task`s by wrapping it in an `Arc`. 
-pub struct WaitQueue(MutexIrqSafe<VecDeque<TaskRef>>);
+pub struct WaitQueue(MutexIrqSafe<VecDeque<Waiter>>);
 
 // ******************************************************************
 // ************ IMPORTANT IMPLEMENTATION NOTE ***********************
@@ -126,8 +158,8 @@ impl WaitQueue {
                 }
                 task::with_current_task(|curr_task| {
                     // This is only necessary because we're using a non-Set waitqueue collection that allows duplicates
-                    if !wq_locked.contains(curr_task) {
-                        wq_locked.push_back(curr_task.clone());
+                    if !wq_locked.iter().any(|w| w.is_task(curr_task)) {
+                        wq_locked.push_back(Waiter::Task(curr_task.clone()));
                     } else {
                         warn!("WaitQueue::wait_until():  task was already on waitqueue (potential spurious wakeup?). {:?}", curr_task);
                     }
@@ -158,8 +190,8 @@ impl WaitQueue {
                 }
                 task::with_current_task(|curr_task| {
                     // This is only necessary because we're using a non-Set waitqueue collection that allows duplicates
-                    if !wq_locked.contains(curr_task) {
-                        wq_locked.push_back(curr_task.clone());
+                    if !wq_locked.iter().any(|w| w.is_task(curr_task)) {
+                        wq_locked.push_back(Waiter::Task(curr_task.clone()));
                     } else {
                         warn!("WaitQueue::wait_until():  task was already on waitqueue (potential spurious wakeup?). {:?}", curr_task);
                     }
@@ -174,6 +206,24 @@ impl WaitQueue {
         }
     }
 
+    /// Returns a `Future` that completes once the given `condition` closure returns `Some(value)`,
+    /// resolving to that `value`.
+    /// 
+    /// This is the asynchronous counterpart of [`wait_until`](#method.wait_until):
+    /// rather than blocking the current `Task`, the returned `Future` registers its `Waker`
+    /// on this `WaitQueue` and is woken up (and the `condition` is re-checked) when notified.
+    /// 
+    /// As with `wait_until`, the `condition` is executed atomically with respect to the wait queue.
+    pub fn wait_until_async<R, F>(&self, condition: F) -> WaitUntil<'_, F> 
+        where F: FnMut() -> Option<R> + Unpin
+    {
+        WaitUntil {
+            queue: self,
+            condition,
+            registered: None,
+        }
+    }
+
     /// Wake up one random `Task` that is waiting on this queue.
     /// # Return
     /// * returns `true` if a `Task` was successfully woken up,
@@ -182,6 +232,14 @@ impl WaitQueue {
         self.notify(None)
     }
 
+    /// Wake up all `Task`s and `Future`s that are waiting on this queue.
+    /// 
+    /// Returns the number of waiters that were successfully woken up.
+    pub fn notify_all(&self) -> usize {
+        let mut wq_locked = self.0.lock();
+        wq_locked.drain(..).map(Waiter::wake).filter(|woken| *woken).count()
+    }
+
     /// Wake up a specific `Task` that is waiting on this queue.
     /// # Return
     /// * returns `true` if the given `Task` was waiting and was woken up,
@@ -205,19 +263,19 @@ impl WaitQueue {
         let mut wq_locked = self.0.lock();
         
         loop {
-            let tref = if let Some(ttw) = task_to_wakeup {
+            let waiter = if let Some(ttw) = task_to_wakeup {
                 // find a specific task to wake up
-                let index = wq_locked.iter().position(|t| t == ttw);
+                let index = wq_locked.iter().position(|w| w.is_task(ttw));
                 index.and_then(|i| wq_locked.remove(i))
             } else {
-                // just wake up the first task
+                // just wake up the first task or future
                 wq_locked.pop_front()
             };
 
-            // trace!("  notify: chose task to wakeup: {:?}", tref);
-            if let Some(t) = tref {
-                // trace!("WaitQueue::notify():  unblocked task on waitqueue\n    --> WQ: {:?}", &*wq_locked);
-                if t.unblock().is_ok() {
+            // trace!("  notify: chose waiter to wakeup");
+            if let Some(w) = waiter {
+                // trace!("WaitQueue::notify():  woke up waiter on waitqueue");
+                if w.wake() {
                     return true;
                 }
             } else {
@@ -226,4 +284,48 @@ impl WaitQueue {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+
+/// A `Future` that completes once its condition is met, 
+/// which is re-checked each time it is woken up by its `WaitQueue`.
+/// 
+/// See [`WaitQueue::wait_until_async()`].
+#[must_use = "futures do nothing unless polled or `.await`ed"]
+pub struct WaitUntil<'q, F> {
+    queue: &'q WaitQueue,
+    condition: F,
+    /// The `Waker` that this future has registered on the `queue`, if any.
+    registered: Option<Waker>,
+}
+
+impl<'q, R, F> Future for WaitUntil<'q, F> where F: FnMut() -> Option<R> + Unpin {
+    type Output = R;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<R> {
+        let this = self.get_mut();
+        let mut wq_locked = this.queue.0.lock();
+        if let Some(ret) = (this.condition)() {
+            // Remove our waker in case we were polled without being notified.
+            if let Some(waker) = this.registered.take() {
+                wq_locked.retain(|w| !w.is_waker(&waker));
+            }
+            return Poll::Ready(ret);
+        }
+        // This is only necessary because we're using a non-Set waitqueue collection that allows duplicates
+        if !wq_locked.iter().any(|w| w.is_waker(cx.waker())) {
+            wq_locked.push_back(Waiter::Waker(cx.waker().clone()));
+        }
+        this.registered = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<'q, F> Drop for WaitUntil<'q, F> {
+    fn drop(&mut self) {
+        // Remove our stale waker such that it doesn't absorb a notification meant for another waiter.
+        if let Some(waker) = self.registered.take() {
+            self.queue.0.lock().retain(|w| !w.is_waker(&waker));
+        }
+    }
+}
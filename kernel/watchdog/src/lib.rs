@@ -0,0 +1,389 @@
+//! A watchdog that detects soft lockups of CPUs and hung tasks.
+//!
+//! A *soft lockup* occurs when a CPU stops invoking the scheduler for a long time,
+//! e.g., because a task spins forever with preemption disabled.
+//! The scheduler records the time at which each CPU last scheduled,
+//! and [`check_for_soft_lockups()`] is invoked from the timer interrupt on every CPU
+//! to inspect the *other* CPUs, since a locked-up CPU can't be trusted to detect its own lockup.
+//! Upon detecting a lockup, the watchdog sends an NMI to the stuck CPU,
+//! whose NMI handler then calls [`record_soft_lockup()`] to record where it's stuck.
+//! The NMI may have interrupted a task holding any lock, e.g., the logger's or the task list's,
+//! so it only stores that snapshot in lock-free per-CPU slots;
+//! the [`watchdog_task()`] later walks the stuck task's stack starting from that snapshot
+//! and reports it along with the resulting backtrace to the log and the `fault_log`.
+//!
+//! A *hung task* is one that has been blocked for a long time.
+//! The [`watchdog_task()`] also periodically scans all tasks
+//! and reports those that have been blocked for longer than the threshold.
+//!
+//! The watchdog is only active while its task is running, which the `captain` spawns at boot
+//! only if Theseus is built with `THESEUS_CONFIG=watchdog`.
+//! Both thresholds and the interval between checks are configurable at runtime.
+//! Soft lockup detection is enabled by default, whereas hung task detection is not,
+//! because many tasks legitimately block forever, e.g., while waiting for input.
+//! Each stall and each blocked period is reported only once.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+use log::{error, warn};
+use task::RunState;
+
+/// The maximum number of CPUs, which is the number of possible APIC IDs.
+const MAX_CPUS: usize = u8::MAX as usize + 1;
+
+/// The default duration after which a CPU that hasn't scheduled is considered locked up.
+pub const DEFAULT_SOFT_LOCKUP_THRESHOLD: Duration = Duration::from_secs(10);
+/// The default interval between two consecutive watchdog checks.
+pub const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The soft lockup threshold in milliseconds, or `0` if soft lockup detection is disabled.
+static SOFT_LOCKUP_THRESHOLD_MS: AtomicU64 = AtomicU64::new(DEFAULT_SOFT_LOCKUP_THRESHOLD.as_millis() as u64);
+/// The hung task threshold in milliseconds, or `0` if hung task detection is disabled.
+static HUNG_TASK_THRESHOLD_MS: AtomicU64 = AtomicU64::new(0);
+/// The interval between two consecutive watchdog checks in milliseconds.
+static CHECK_INTERVAL_MS: AtomicU64 = AtomicU64::new(DEFAULT_CHECK_INTERVAL.as_millis() as u64);
+
+/// Whether the [`watchdog_task()`] is running, without which no lockups are detected.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// The TSC value after which the next soft lockup check may occur.
+static NEXT_SOFT_LOCKUP_CHECK: AtomicU64 = AtomicU64::new(0);
+
+/// For each CPU, the TSC value at which it last scheduled before the most recently reported stall,
+/// which ensures that each stall is reported only once.
+static REPORTED_STALLS: [AtomicU64; MAX_CPUS] = {
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; MAX_CPUS]
+};
+
+/// For each CPU, the number of TSC ticks for which it had been stalled when it was sent
+/// a watchdog NMI, or `0` if there is no pending soft lockup report for that CPU.
+static PENDING_REPORTS: [AtomicU64; MAX_CPUS] = {
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; MAX_CPUS]
+};
+
+/// For each CPU, a snapshot of where it was stuck, recorded by its NMI handler
+/// and not yet reported by the [`watchdog_task()`].
+static SNAPSHOTS: [LockupSnapshot; MAX_CPUS] = {
+    const EMPTY: LockupSnapshot = LockupSnapshot::empty();
+    [EMPTY; MAX_CPUS]
+};
+
+/// Where a locked-up CPU was stuck when it received the watchdog NMI.
+///
+/// This consists only of atomics such that it can be written from the NMI handler.
+struct LockupSnapshot {
+    /// Set by the NMI handler once the other fields are written, and cleared once reported.
+    ready: AtomicBool,
+    /// The number of TSC ticks for which the CPU had been stalled.
+    stalled_for: AtomicU64,
+    /// The address of the instruction at which the CPU was interrupted.
+    instruction_pointer: AtomicUsize,
+    /// The stack pointer at the time the CPU was interrupted.
+    stack_pointer: AtomicUsize,
+    /// The frame pointer (RBP) at the time the CPU was interrupted.
+    frame_pointer: AtomicUsize,
+    /// The ID of the task that was running on the CPU.
+    task_id: AtomicUsize,
+}
+
+impl LockupSnapshot {
+    const fn empty() -> Self {
+        LockupSnapshot {
+            ready: AtomicBool::new(false),
+            stalled_for: AtomicU64::new(0),
+            instruction_pointer: AtomicUsize::new(0),
+            stack_pointer: AtomicUsize::new(0),
+            frame_pointer: AtomicUsize::new(0),
+            task_id: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// Returns whether the [`watchdog_task()`] is running, i.e., whether the watchdog is active.
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::Relaxed)
+}
+
+/// Returns the duration after which a CPU that hasn't scheduled is considered locked up,
+/// or `None` if soft lockup detection is disabled.
+pub fn soft_lockup_threshold() -> Option<Duration> {
+    millis_to_threshold(SOFT_LOCKUP_THRESHOLD_MS.load(Ordering::Relaxed))
+}
+
+/// Sets the duration after which a CPU that hasn't scheduled is considered locked up.
+///
+/// `None` (or a zero duration) disables soft lockup detection.
+pub fn set_soft_lockup_threshold(threshold: Option<Duration>) {
+    SOFT_LOCKUP_THRESHOLD_MS.store(threshold_to_millis(threshold), Ordering::Relaxed);
+}
+
+/// Returns the duration after which a blocked task is considered hung,
+/// or `None` if hung task detection is disabled.
+pub fn hung_task_threshold() -> Option<Duration> {
+    millis_to_threshold(HUNG_TASK_THRESHOLD_MS.load(Ordering::Relaxed))
+}
+
+/// Sets the duration after which a blocked task is considered hung.
+///
+/// `None` (or a zero duration) disables hung task detection.
+pub fn set_hung_task_threshold(threshold: Option<Duration>) {
+    HUNG_TASK_THRESHOLD_MS.store(threshold_to_millis(threshold), Ordering::Relaxed);
+}
+
+/// Returns the interval between two consecutive watchdog checks.
+pub fn check_interval() -> Duration {
+    Duration::from_millis(CHECK_INTERVAL_MS.load(Ordering::Relaxed))
+}
+
+/// Sets the interval between two consecutive watchdog checks,
+/// which is rounded up to at least one millisecond.
+pub fn set_check_interval(interval: Duration) {
+    CHECK_INTERVAL_MS.store(core::cmp::max(interval.as_millis() as u64, 1), Ordering::Relaxed);
+}
+
+/// Checks whether any other CPU has stopped scheduling for longer than the soft lockup threshold,
+/// and if so, sends an NMI to that CPU such that it reports the task it's stuck in.
+///
+/// This must be invoked from the local timer interrupt handler.
+/// It is cheap enough to be called on every tick of every CPU,
+/// as only one CPU actually performs the check once per [`check_interval()`].
+///
+/// CPUs whose tick is stopped because they are idle are not considered locked up.
+/// Note that a lockup can only be detected while at least one other CPU still takes timer interrupts.
+pub fn check_for_soft_lockups() {
+    if !is_running() {
+        return;
+    }
+    let Some(threshold) = soft_lockup_threshold() else { return };
+    let now = now();
+    let next_check = NEXT_SOFT_LOCKUP_CHECK.load(Ordering::Relaxed);
+    if now < next_check {
+        return;
+    }
+    let Some(frequency) = tsc_frequency() else { return };
+    let interval = duration_to_tsc(check_interval(), frequency);
+    // Only one CPU needs to perform each check.
+    if NEXT_SOFT_LOCKUP_CHECK.compare_exchange(next_check, now.saturating_add(interval), Ordering::Relaxed, Ordering::Relaxed).is_err() {
+        return;
+    }
+
+    let threshold = duration_to_tsc(threshold, frequency);
+    let my_apic_id = apic::get_my_apic_id();
+    for cpu in 0..=u8::MAX {
+        if cpu == my_apic_id || sleep::tick::is_tick_stopped(cpu) {
+            continue;
+        }
+        let Some(last_scheduled) = scheduler::last_scheduled(cpu) else { continue };
+        let stalled_for = now.saturating_sub(last_scheduled);
+        if stalled_for < threshold {
+            continue;
+        }
+        if REPORTED_STALLS[cpu as usize].swap(last_scheduled, Ordering::Relaxed) == last_scheduled {
+            continue; // this stall was already reported
+        }
+
+        PENDING_REPORTS[cpu as usize].store(stalled_for, Ordering::Release);
+        if let Some(my_apic) = apic::get_my_apic() {
+            my_apic.write().send_nmi_ipi(apic::LapicIpiDestination::One(cpu));
+        } else {
+            PENDING_REPORTS[cpu as usize].store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Records where the current CPU is stuck, if the watchdog on another CPU
+/// has detected that this CPU is locked up and sent it an NMI.
+///
+/// This is intended to be invoked from the NMI handler with the instruction pointer,
+/// stack pointer, and frame pointer (RBP) of the interrupted context.
+/// It acquires no locks and doesn't log anything, as the interrupted task may hold any lock;
+/// the snapshot is reported later by the [`watchdog_task()`].
+///
+/// Returns `true` if a soft lockup was pending for this CPU, i.e., the NMI was sent by the watchdog.
+pub fn record_soft_lockup(instruction_pointer: usize, stack_pointer: usize, frame_pointer: usize) -> bool {
+    let cpu = apic::get_my_apic_id() as usize;
+    let stalled_for = PENDING_REPORTS[cpu].swap(0, Ordering::Acquire);
+    if stalled_for == 0 {
+        return false;
+    }
+    let snapshot = &SNAPSHOTS[cpu];
+    snapshot.stalled_for.store(stalled_for, Ordering::Relaxed);
+    snapshot.instruction_pointer.store(instruction_pointer, Ordering::Relaxed);
+    snapshot.stack_pointer.store(stack_pointer, Ordering::Relaxed);
+    snapshot.frame_pointer.store(frame_pointer, Ordering::Relaxed);
+    snapshot.task_id.store(task::get_my_current_task_id(), Ordering::Relaxed);
+    snapshot.ready.store(true, Ordering::Release);
+    true
+}
+
+/// The entry point of the watchdog task, which is spawned at boot if the `watchdog` config option is set.
+///
+/// Once per [`check_interval()`], this reports the soft lockups recorded by locked-up CPUs,
+/// as well as every task that has been blocked for longer than the [`hung_task_threshold()`],
+/// to the log and the `fault_log`.
+///
+/// Only one instance of this task runs at a time; any other instance returns immediately.
+pub fn watchdog_task(_: ()) {
+    if RUNNING.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    // The `blocked_since` time of each task that has already been reported as hung.
+    let mut reported: BTreeMap<usize, u64> = BTreeMap::new();
+    loop {
+        if let Err(_e) = sleep::sleep(check_interval()) {
+            error!("watchdog_task: failed to sleep, runstate was {:?}", _e);
+        }
+        report_soft_lockups();
+        match hung_task_threshold() {
+            Some(threshold) => check_for_hung_tasks(threshold, &mut reported),
+            None => reported.clear(),
+        }
+    }
+}
+
+/// The maximum number of stack frames in the backtrace of a locked-up CPU.
+const MAX_BACKTRACE_FRAMES: usize = 64;
+
+/// Reports the soft lockup snapshots that locked-up CPUs have recorded since the last call.
+fn report_soft_lockups() {
+    for (cpu, snapshot) in SNAPSHOTS.iter().enumerate() {
+        if !snapshot.ready.load(Ordering::Acquire) {
+            continue;
+        }
+        let stalled_for = snapshot.stalled_for.load(Ordering::Relaxed);
+        let instruction_pointer = snapshot.instruction_pointer.load(Ordering::Relaxed);
+        let stack_pointer = snapshot.stack_pointer.load(Ordering::Relaxed);
+        let frame_pointer = snapshot.frame_pointer.load(Ordering::Relaxed);
+        let task = task::get_task(snapshot.task_id.load(Ordering::Relaxed));
+        snapshot.ready.store(false, Ordering::Relaxed);
+
+        let stalled_for = tsc_frequency()
+            .map(|frequency| tsc_to_duration(stalled_for, frequency))
+            .unwrap_or_default();
+        error!("watchdog: soft lockup on CPU {}: stuck for {:?} at {:#X} in task {:?}",
+            cpu, stalled_for, instruction_pointer, task,
+        );
+        let backtrace = backtrace_of_stuck_cpu(cpu as u8, task.as_ref(), instruction_pointer, stack_pointer, frame_pointer);
+        fault_log::log_soft_lockup(cpu as u8, task.as_ref(), instruction_pointer, backtrace);
+    }
+}
+
+/// Walks and logs the stack of the given `task` that is stuck on the given `cpu`,
+/// starting from the context recorded by that CPU's NMI handler,
+/// and returns the call site address of each stack frame.
+fn backtrace_of_stuck_cpu(
+    cpu: u8,
+    task: Option<&task::TaskRef>,
+    instruction_pointer: usize,
+    stack_pointer: usize,
+    frame_pointer: usize,
+) -> Vec<usize> {
+    let mut backtrace = Vec::new();
+    // The stack can only be walked safely while it doesn't change, i.e., while the CPU is still stuck.
+    if scheduler::last_scheduled(cpu) != Some(REPORTED_STALLS[cpu as usize].load(Ordering::Relaxed)) {
+        error!("  CPU {} has scheduled again, so its stack can no longer be walked", cpu);
+        return backtrace;
+    }
+    let namespace = task.map(|t| t.get_namespace().clone())
+        .or_else(|| mod_mgmt::get_initial_kernel_namespace().cloned());
+    let Some(namespace) = namespace else {
+        error!("  couldn't get a namespace to walk the stack of CPU {}", cpu);
+        return backtrace;
+    };
+
+    error!("------------------ Stack Trace (DWARF) ---------------------------");
+    // SAFETY: the stuck CPU hasn't scheduled since it recorded its snapshot, so the frames above it are unchanged.
+    let result = unsafe {
+        stack_trace::stack_trace_of_interrupted_context(
+            namespace,
+            instruction_pointer,
+            stack_pointer,
+            frame_pointer,
+            &mut |stack_frame, stack_frame_iter| {
+                let call_site = stack_frame.call_site_address() as usize;
+                backtrace.push(call_site);
+                let symbol_offset = stack_frame_iter.namespace().get_section_containing_address(
+                    memory::VirtualAddress::new_canonical(call_site),
+                    false
+                ).map(|(sec, offset)| (sec.name.clone(), offset));
+                if let Some((symbol_name, offset)) = symbol_offset {
+                    error!("  {:>#018X} in {} + {:#X}", call_site, symbol_name, offset);
+                } else {
+                    error!("  {:>#018X} in ??", call_site);
+                }
+                true
+            },
+            Some(MAX_BACKTRACE_FRAMES),
+        )
+    };
+    match result {
+        Ok(()) => error!("  Beginning of stack"),
+        Err(e) => error!("  {}", e),
+    }
+    error!("---------------------- End of Stack Trace ------------------------");
+    backtrace
+}
+
+/// Reports all tasks that have been blocked for longer than the given `threshold`,
+/// skipping those whose current blocked period was already reported.
+fn check_for_hung_tasks(threshold: Duration, reported: &mut BTreeMap<usize, u64>) {
+    let Some(frequency) = tsc_frequency() else { return };
+    let now = now();
+    let blocked_tasks: Vec<_> = task::TASKLIST.lock()
+        .values()
+        .filter(|t| t.runstate() == RunState::Blocked)
+        .filter_map(|t| t.blocked_since().map(|since| (t.clone(), since)))
+        .collect();
+
+    // Forget tasks that have been unblocked (or reaped) since they were reported.
+    reported.retain(|id, since| blocked_tasks.iter().any(|(t, s)| t.id == *id && s == since));
+
+    let threshold = duration_to_tsc(threshold, frequency);
+    for (task, since) in blocked_tasks {
+        let blocked_for = now.saturating_sub(since);
+        if blocked_for < threshold || reported.get(&task.id) == Some(&since) {
+            continue;
+        }
+        warn!("watchdog: hung task {:?} has been blocked for {:?}", task, tsc_to_duration(blocked_for, frequency));
+        fault_log::log_hung_task(&task);
+        reported.insert(task.id, since);
+    }
+}
+
+fn millis_to_threshold(millis: u64) -> Option<Duration> {
+    match millis {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    }
+}
+
+fn threshold_to_millis(threshold: Option<Duration>) -> u64 {
+    threshold.map_or(0, |t| t.as_millis() as u64)
+}
+
+/// Returns the current TSC value.
+fn now() -> u64 {
+    u128::from(tsc::tsc_ticks()) as u64
+}
+
+/// Returns the TSC frequency in ticks per second, if it has been calibrated.
+fn tsc_frequency() -> Option<u128> {
+    tsc::get_tsc_frequency().ok().filter(|&f| f != 0)
+}
+
+fn duration_to_tsc(duration: Duration, frequency: u128) -> u64 {
+    (duration.as_nanos() * frequency / 1_000_000_000) as u64
+}
+
+fn tsc_to_duration(ticks: u64, frequency: u128) -> Duration {
+    Duration::from_nanos((ticks as u128 * 1_000_000_000 / frequency) as u64)
+}
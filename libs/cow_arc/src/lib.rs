@@ -103,6 +103,14 @@ impl<T> CowArc<T> {
         Arc::strong_count(&self.arc.inner_arc) > 1
     }
 
+    /// Returns the number of references to this instance of a `CowArc`,
+    /// i.e., this reference and the shallow clones of it (see [`clone_shallow`](#method.clone_shallow)).
+    ///
+    /// Unlike [`is_shared`](#method.is_shared), this does not count references created by `clone`.
+    pub fn shallow_count(this: &CowArc<T>) -> usize {
+        Arc::strong_count(&this.arc)
+    }
+
     /// Returns true if the two `CowArc`s point to the same value
     /// (not just values that compare as equal).
     pub fn ptr_eq(&self, other: &Self) -> bool {
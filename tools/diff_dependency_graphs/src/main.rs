@@ -0,0 +1,206 @@
+//! Differences two dependency graphs exported in JSON format by Theseus's `deps` application,
+//! e.g., from before and after a live evolution, and emits the differences as a DOT graph.
+//!
+//! By default, the hashes in crate and section names are ignored,
+//! such that a crate or section rebuilt with a new hash is treated as the same node.
+
+use getopts::Options;
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    fmt::Write as _,
+    fs,
+};
+
+/// The line that Theseus prints before a graph written to a serial port.
+const SERIAL_BEGIN_MARKER: &str = "-----BEGIN THESEUS DEPENDENCY GRAPH-----";
+/// The line that Theseus prints after a graph written to a serial port.
+const SERIAL_END_MARKER: &str = "-----END THESEUS DEPENDENCY GRAPH-----";
+
+const ADDED_COLOR: &str = "green";
+const REMOVED_COLOR: &str = "red";
+const CHANGED_COLOR: &str = "orange";
+const UNCHANGED_COLOR: &str = "gray";
+
+/// A dependency graph loaded from a JSON export.
+struct Graph {
+    kind: String,
+    /// The label of each node, keyed by its (possibly normalized) ID.
+    nodes: BTreeMap<String, String>,
+    /// The `(strong, weak)` dependency counts of each edge, keyed by its `(from, to)` node IDs.
+    edges: BTreeMap<(String, String), (u64, u64)>,
+}
+
+fn main() -> Result<(), String> {
+    let args: Vec<String> = env::args().collect();
+
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("", "exact", "compare crate and section names including their hashes");
+    opts.optflag("a", "all", "include unchanged nodes and edges in the output graph");
+    opts.optopt("o", "output", "write the DOT graph to FILE instead of stdout", "FILE");
+
+    let matches = opts.parse(&args[1..]).map_err(|e| e.to_string())?;
+    if matches.opt_present("h") {
+        usage("cargo run -- ", opts);
+        return Ok(());
+    }
+    let (old_path, new_path) = match matches.free.len() {
+        2 => (&matches.free[0], &matches.free[1]),
+        _ => {
+            usage("cargo run -- ", opts);
+            return Err("expected two JSON dependency graph files as arguments".to_string());
+        }
+    };
+
+    let exact = matches.opt_present("exact");
+    let old = load_graph(old_path, exact)?;
+    let new = load_graph(new_path, exact)?;
+    if old.kind != new.kind {
+        return Err(format!("cannot compare a graph of {} to a graph of {}", old.kind, new.kind));
+    }
+
+    let (dot, summary) = diff(&old, &new, matches.opt_present("a"));
+    match matches.opt_str("o") {
+        Some(path) => fs::write(&path, dot).map_err(|e| format!("couldn't write {:?}: {}", path, e))?,
+        None => print!("{}", dot),
+    }
+    eprintln!("{}", summary);
+    Ok(())
+}
+
+fn usage(program: &str, opts: Options) {
+    let brief = format!("Usage: {} [options] OLD_GRAPH.json NEW_GRAPH.json", program);
+    print!("{}", opts.usage(&brief));
+}
+
+/// Loads a graph from the given file, which may also contain other output captured from a serial port.
+fn load_graph(path: &str, exact: bool) -> Result<Graph, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("couldn't read {:?}: {}", path, e))?;
+    let contents = contents.replace('\r', "");
+    let json = match contents.find(SERIAL_BEGIN_MARKER) {
+        Some(begin) => {
+            let json = &contents[begin + SERIAL_BEGIN_MARKER.len()..];
+            let end = json.find(SERIAL_END_MARKER)
+                .ok_or_else(|| format!("{:?} is missing the end marker of the dependency graph", path))?;
+            &json[..end]
+        }
+        None => &contents[..],
+    };
+    let value: Value = serde_json::from_str(json).map_err(|e| format!("couldn't parse {:?}: {}", path, e))?;
+
+    let id = |value: &Value| -> Result<String, String> {
+        let id = value.as_str().ok_or_else(|| format!("{:?} contains a node ID that isn't a string", path))?;
+        Ok(if exact { id.to_string() } else { normalize(id) })
+    };
+    let kind = value["kind"].as_str().unwrap_or("crates").to_string();
+    let mut nodes = BTreeMap::new();
+    for node in value["nodes"].as_array().ok_or_else(|| format!("{:?} has no nodes", path))? {
+        let label = match node["section"].as_str() {
+            Some(section) if exact => section.to_string(),
+            Some(section) => normalize(section),
+            None => id(&node["id"])?,
+        };
+        nodes.insert(id(&node["id"])?, label);
+    }
+    let mut edges = BTreeMap::new();
+    for edge in value["edges"].as_array().ok_or_else(|| format!("{:?} has no edges", path))? {
+        // Different hashes of the same crate or section may be merged into one node, so add up their counts.
+        let counts: &mut (u64, u64) = edges.entry((id(&edge["from"])?, id(&edge["to"])?)).or_default();
+        counts.0 += edge["strong"].as_u64().unwrap_or(0);
+        counts.1 += edge["weak"].as_u64().unwrap_or(0);
+    }
+    Ok(Graph { kind, nodes, edges })
+}
+
+/// Removes the hashes from crate names, e.g., `k#mod_mgmt-1d9f6c0e8b2a4c57`,
+/// and from section names, e.g., `mod_mgmt::CrateNamespace::new::h0123456789abcdef`.
+fn normalize(id: &str) -> String {
+    let mut normalized = String::with_capacity(id.len());
+    let mut rest = id;
+    while !rest.is_empty() {
+        let hash_len = if let Some(hash) = rest.strip_prefix("::h") {
+            hex_digits(hash).map(|n| 3 + n)
+        } else if let Some(hash) = rest.strip_prefix('-') {
+            hex_digits(hash).map(|n| 1 + n)
+        } else {
+            None
+        };
+        match hash_len {
+            Some(len) => rest = &rest[len..],
+            None => {
+                let c = rest.chars().next().unwrap();
+                normalized.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    normalized
+}
+
+/// Returns the length of the hexadecimal hash at the start of the given string,
+/// if it is long enough to be a hash and isn't followed by other name characters.
+fn hex_digits(s: &str) -> Option<usize> {
+    let len = s.find(|c: char| !c.is_ascii_hexdigit()).unwrap_or(s.len());
+    let followed_by_name = s[len..].starts_with(|c: char| c.is_alphanumeric() || c == '_');
+    (len >= 16 && !followed_by_name).then_some(len)
+}
+
+/// Returns the DOT graph of the differences between the `old` and `new` graphs, and a one-line summary of them.
+fn diff(old: &Graph, new: &Graph, all: bool) -> (String, String) {
+    let mut dot = String::new();
+    let mut nodes_to_show = BTreeMap::new();
+    let mut edge_lines = Vec::new();
+    let (mut added_edges, mut removed_edges, mut changed_edges) = (0, 0, 0);
+
+    let edge_keys: BTreeSet<_> = old.edges.keys().chain(new.edges.keys()).collect();
+    for key in edge_keys {
+        let (color, label) = match (old.edges.get(key), new.edges.get(key)) {
+            (None, Some(&(strong, weak))) => {
+                added_edges += 1;
+                (ADDED_COLOR, format!("+ strong: {}, weak: {}", strong, weak))
+            }
+            (Some(&(strong, weak)), None) => {
+                removed_edges += 1;
+                (REMOVED_COLOR, format!("- strong: {}, weak: {}", strong, weak))
+            }
+            (Some(old_counts), Some(new_counts)) if old_counts != new_counts => {
+                changed_edges += 1;
+                (CHANGED_COLOR, format!("strong: {} -> {}, weak: {} -> {}", old_counts.0, new_counts.0, old_counts.1, new_counts.1))
+            }
+            (Some(_), Some(_)) if all => (UNCHANGED_COLOR, String::new()),
+            _ => continue,
+        };
+        nodes_to_show.insert(&key.0, UNCHANGED_COLOR);
+        nodes_to_show.insert(&key.1, UNCHANGED_COLOR);
+        edge_lines.push(format!("  {:?} -> {:?} [color={}, fontcolor={}, label={:?}];", key.0, key.1, color, color, label));
+    }
+
+    let (mut added_nodes, mut removed_nodes) = (0, 0);
+    let node_ids: BTreeSet<_> = old.nodes.keys().chain(new.nodes.keys()).collect();
+    for id in node_ids {
+        match (old.nodes.contains_key(id), new.nodes.contains_key(id)) {
+            (false, true) => { added_nodes += 1; nodes_to_show.insert(id, ADDED_COLOR); }
+            (true, false) => { removed_nodes += 1; nodes_to_show.insert(id, REMOVED_COLOR); }
+            _ if all => { nodes_to_show.entry(id).or_insert(UNCHANGED_COLOR); }
+            _ => { }
+        }
+    }
+
+    writeln!(dot, "digraph \"{} dependency graph diff\" {{", new.kind).unwrap();
+    writeln!(dot, "  node [shape=box];").unwrap();
+    for (id, color) in nodes_to_show {
+        let label = new.nodes.get(id).or_else(|| old.nodes.get(id)).unwrap_or(id);
+        writeln!(dot, "  {:?} [label={:?}, color={}, fontcolor={}];", id, label, color, color).unwrap();
+    }
+    for line in edge_lines {
+        writeln!(dot, "{}", line).unwrap();
+    }
+    writeln!(dot, "}}").unwrap();
+
+    let summary = format!("{}: {} nodes added, {} nodes removed; {} edges added, {} edges removed, {} edges changed",
+        new.kind, added_nodes, removed_nodes, added_edges, removed_edges, changed_edges,
+    );
+    (dot, summary)
+}
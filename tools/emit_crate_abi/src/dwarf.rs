@@ -0,0 +1,228 @@
+//! Computes a hash of the type of each function and static variable described by an object file's DWARF debug info.
+//!
+//! A function's type consists of its parameter and return types,
+//! and a static variable's type is the type of its value.
+//! Each type is described as a string that includes the names, sizes, and member offsets
+//! of the type and the types it contains (up to [`MAX_TYPE_DEPTH`] levels deep),
+//! which is then hashed.
+//! Thus, any change to a type's layout results in a different hash,
+//! as do most changes to the layouts of the types it contains or points to.
+
+use gimli::{AttributeValue, EndianSlice, LittleEndian, Unit, UnitOffset};
+use object::{Object, ObjectSection, ObjectSymbol, RelocationTarget};
+use std::{borrow::Cow, collections::HashMap, fmt::Write};
+
+type R<'a> = EndianSlice<'a, LittleEndian>;
+
+/// How many levels of nested types to include in a type's description.
+const MAX_TYPE_DEPTH: usize = 4;
+
+
+/// Returns the type hash of each function and static variable in the given object file,
+/// keyed by its mangled symbol name.
+pub fn layout_hashes(file: &object::File) -> Result<HashMap<String, u64>, String> {
+    let owned_dwarf = gimli::Dwarf::load(|id| load_section(file, id.name()))?;
+    let dwarf = owned_dwarf.borrow(|section| EndianSlice::new(section, LittleEndian));
+
+    let mut hashes = HashMap::new();
+    let mut units = dwarf.units();
+    while let Some(header) = units.next().map_err(|e| e.to_string())? {
+        let unit = dwarf.unit(header).map_err(|e| e.to_string())?;
+        let describer = TypeDescriber { dwarf: &dwarf, unit: &unit };
+        let mut entries = unit.entries();
+        while let Some((_, entry)) = entries.next_dfs().map_err(|e| e.to_string())? {
+            let is_function = match entry.tag() {
+                gimli::DW_TAG_subprogram => true,
+                gimli::DW_TAG_variable => false,
+                _ => continue,
+            };
+            let linkage_name = entry.attr_value(gimli::DW_AT_linkage_name).ok().flatten()
+                .or_else(|| entry.attr_value(gimli::DW_AT_MIPS_linkage_name).ok().flatten());
+            let Some(linkage_name) = linkage_name.and_then(|n| describer.string(n)) else { continue };
+
+            let mut description = String::new();
+            let described = if is_function {
+                describer.describe_function(entry.offset(), &mut description)
+            } else {
+                describer.describe_type_attr(entry, MAX_TYPE_DEPTH, &mut description)
+            };
+            if described.is_ok() {
+                hashes.entry(linkage_name).or_insert_with(|| fnv1a(description.as_bytes()));
+            }
+        }
+    }
+    Ok(hashes)
+}
+
+/// Returns the contents of the given section with its relocations applied,
+/// which is necessary because debug info in relocatable object files refers to other sections
+/// (e.g., `.debug_str`) via relocations rather than direct offsets.
+fn load_section<'d>(file: &object::File<'d>, name: &str) -> Result<Cow<'d, [u8]>, String> {
+    let Some(section) = file.section_by_name(name) else {
+        return Ok(Cow::Borrowed(&[]));
+    };
+    let data = section.data().map_err(|e| e.to_string())?;
+    let mut relocations = section.relocations().peekable();
+    if relocations.peek().is_none() {
+        return Ok(Cow::Borrowed(data));
+    }
+
+    let mut data = data.to_vec();
+    for (offset, relocation) in relocations {
+        let base = match relocation.target() {
+            RelocationTarget::Symbol(index) => file.symbol_by_index(index).map(|s| s.address()).unwrap_or(0),
+            _ => 0,
+        };
+        let value = base.wrapping_add(relocation.addend() as u64);
+        let offset = offset as usize;
+        match relocation.size() {
+            32 => if let Some(dest) = data.get_mut(offset .. offset + 4) {
+                dest.copy_from_slice(&(value as u32).to_le_bytes());
+            }
+            64 => if let Some(dest) = data.get_mut(offset .. offset + 8) {
+                dest.copy_from_slice(&value.to_le_bytes());
+            }
+            _ => { }
+        }
+    }
+    Ok(Cow::Owned(data))
+}
+
+/// The 64-bit FNV-1a hash, which never returns `0` because that denotes an unknown type.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let hash = bytes.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    hash.max(1)
+}
+
+
+struct TypeDescriber<'a, 'd> {
+    dwarf: &'a gimli::Dwarf<R<'d>>,
+    unit: &'a Unit<R<'d>>,
+}
+
+impl<'a, 'd> TypeDescriber<'a, 'd> {
+    fn string(&self, value: AttributeValue<R<'d>>) -> Option<String> {
+        self.dwarf.attr_string(self.unit, value).ok()
+            .map(|s| s.to_string_lossy().into_owned())
+    }
+
+    fn name(&self, entry: &gimli::DebuggingInformationEntry<R<'d>>) -> Option<String> {
+        entry.attr_value(gimli::DW_AT_name).ok().flatten().and_then(|n| self.string(n))
+    }
+
+    /// Describes a function's parameter types and return type.
+    fn describe_function(&self, offset: UnitOffset, out: &mut String) -> gimli::Result<()> {
+        out.push_str("fn(");
+        let mut tree = self.unit.entries_tree(Some(offset))?;
+        let mut children = tree.root()?.children();
+        while let Some(child) = children.next()? {
+            if child.entry().tag() == gimli::DW_TAG_formal_parameter {
+                self.describe_type_attr(child.entry(), MAX_TYPE_DEPTH, out)?;
+                out.push(',');
+            }
+        }
+        out.push_str(")->");
+        self.describe_type_attr(&self.unit.entry(offset)?, MAX_TYPE_DEPTH, out)
+    }
+
+    /// Describes the type referred to by the given entry's `DW_AT_type` attribute, if any.
+    fn describe_type_attr(&self, entry: &gimli::DebuggingInformationEntry<R<'d>>, depth: usize, out: &mut String) -> gimli::Result<()> {
+        match entry.attr_value(gimli::DW_AT_type)? {
+            Some(AttributeValue::UnitRef(offset)) => self.describe_type(offset, depth, out),
+            Some(_) => Err(gimli::Error::UnsupportedAttributeForm),
+            None => {
+                out.push_str("()");
+                Ok(())
+            }
+        }
+    }
+
+    fn describe_type(&self, offset: UnitOffset, depth: usize, out: &mut String) -> gimli::Result<()> {
+        let entry = self.unit.entry(offset)?;
+        let name = self.name(&entry).unwrap_or_default();
+        let size = entry.attr_value(gimli::DW_AT_byte_size)?.and_then(|s| s.udata_value());
+        match entry.tag() {
+            gimli::DW_TAG_typedef | gimli::DW_TAG_const_type | gimli::DW_TAG_volatile_type
+            | gimli::DW_TAG_restrict_type | gimli::DW_TAG_atomic_type => self.describe_type_attr(&entry, depth, out),
+
+            gimli::DW_TAG_pointer_type | gimli::DW_TAG_reference_type | gimli::DW_TAG_rvalue_reference_type => {
+                out.push('*');
+                if depth == 0 {
+                    out.push_str(&name);
+                    Ok(())
+                } else {
+                    self.describe_type_attr(&entry, depth - 1, out)
+                }
+            }
+
+            gimli::DW_TAG_array_type => {
+                out.push('[');
+                self.describe_type_attr(&entry, depth, out)?;
+                let mut tree = self.unit.entries_tree(Some(offset))?;
+                let mut children = tree.root()?.children();
+                while let Some(child) = children.next()? {
+                    let child = child.entry();
+                    if child.tag() == gimli::DW_TAG_subrange_type {
+                        let count = match child.attr_value(gimli::DW_AT_count)?.and_then(|c| c.udata_value()) {
+                            Some(count) => Some(count),
+                            None => child.attr_value(gimli::DW_AT_upper_bound)?.and_then(|u| u.udata_value()).map(|u| u + 1),
+                        };
+                        let _ = write!(out, ";{:?}", count);
+                    }
+                }
+                out.push(']');
+                Ok(())
+            }
+
+            gimli::DW_TAG_structure_type | gimli::DW_TAG_union_type | gimli::DW_TAG_class_type | gimli::DW_TAG_enumeration_type => {
+                let _ = write!(out, "{}<{:?}>", name, size);
+                if depth > 0 {
+                    out.push('{');
+                    self.describe_members(offset, depth - 1, out)?;
+                    out.push('}');
+                }
+                Ok(())
+            }
+
+            gimli::DW_TAG_subroutine_type => {
+                out.push_str("fn");
+                Ok(())
+            }
+
+            tag => {
+                let _ = write!(out, "{}:{}<{:?}>", tag, name, size);
+                Ok(())
+            }
+        }
+    }
+
+    /// Describes the members of a struct, union, or enum, including the variants of a Rust enum.
+    fn describe_members(&self, offset: UnitOffset, depth: usize, out: &mut String) -> gimli::Result<()> {
+        let mut tree = self.unit.entries_tree(Some(offset))?;
+        let mut children = tree.root()?.children();
+        while let Some(child) = children.next()? {
+            let child_entry = child.entry();
+            match child_entry.tag() {
+                gimli::DW_TAG_member => {
+                    let member_offset = child_entry.attr_value(gimli::DW_AT_data_member_location)?.and_then(|o| o.udata_value());
+                    let _ = write!(out, "{}@{:?}:", self.name(child_entry).unwrap_or_default(), member_offset);
+                    self.describe_type_attr(child_entry, depth, out)?;
+                    out.push(',');
+                }
+                gimli::DW_TAG_variant_part | gimli::DW_TAG_variant => {
+                    out.push('(');
+                    self.describe_members(child_entry.offset(), depth, out)?;
+                    out.push(')');
+                }
+                gimli::DW_TAG_enumerator => {
+                    let value = child_entry.attr_value(gimli::DW_AT_const_value)?.and_then(|v| v.udata_value());
+                    let _ = write!(out, "{}={:?},", self.name(child_entry).unwrap_or_default(), value);
+                }
+                _ => { }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+//! Generates the ABI metadata ([`CrateAbi`]) for every crate object file in a Theseus build.
+//!
+//! For each crate object file `<crate>.o` in the input directory,
+//! this writes a file `<crate>.abi` into the output directory,
+//! which contains that crate's `CrateAbi` serialized with `bincode`'s standard config.
+//! The build then embeds that file into the crate object file as the [`ABI_SECTION_NAME`] section,
+//! which `mod_mgmt` and `crate_swap` use to check whether a crate can safely replace
+//! or link against another crate at runtime.
+//!
+//! A crate's ABI metadata consists of:
+//! * its exports: every global symbol it defines, along with that symbol's kind, size,
+//!   and a hash of its type as described by the crate's DWARF debug info,
+//! * its imports: every symbol it uses from another crate in the same build,
+//!   along with the ABI of that symbol at build time.
+//!
+//! As such, this must run before debug info is stripped from the crate object files.
+
+mod dwarf;
+
+use crate_metadata_serde::{CrateAbi, SymbolAbi, SymbolKind, ABI_SECTION_NAME};
+use getopts::Options;
+use object::{Object, ObjectSymbol};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    fs,
+    path::{Path, PathBuf},
+};
+
+
+fn main() -> Result<(), String> {
+    let args: Vec<String> = env::args().collect();
+
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.reqopt(
+        "i",
+        "input",
+        "(required) path to the directory of crate object files, e.g., \"/path/to/build/grub-isofiles/modules/\"",
+        "INPUT_DIR"
+    );
+    opts.reqopt(
+        "o",
+        "output",
+        "(required) path to the directory where the ABI metadata file for each crate object file should be written",
+        "OUTPUT_DIR"
+    );
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            print_usage(&args[0], opts);
+            return Err(e.to_string());
+        }
+    };
+    if matches.opt_present("h") {
+        print_usage(&args[0], opts);
+        return Ok(());
+    }
+
+    let input_dir = PathBuf::from(matches.opt_str("i").unwrap());
+    let output_dir = PathBuf::from(matches.opt_str("o").unwrap());
+    fs::create_dir_all(&output_dir).map_err(|e| format!("couldn't create output directory {:?}: {}", output_dir, e))?;
+
+    let mut object_files: Vec<PathBuf> = fs::read_dir(&input_dir)
+        .map_err(|e| format!("couldn't read input directory {:?}: {}", input_dir, e))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().map_or(false, |ext| ext == "o"))
+        .collect();
+    object_files.sort();
+
+    let crates = object_files.iter()
+        .map(|path| parse_crate(path))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // All symbols exported by all crates, used to determine the ABI of each crate's imports.
+    let mut all_exports: HashMap<&str, SymbolAbi> = HashMap::new();
+    for krate in &crates {
+        for (mangled, (_, abi)) in &krate.exports {
+            all_exports.insert(mangled, *abi);
+        }
+    }
+
+    for (path, krate) in object_files.iter().zip(crates.iter()) {
+        let mut crate_abi = CrateAbi::default();
+        for (name, abi) in krate.exports.values() {
+            crate_abi.exports.entry(name.clone())
+                .and_modify(|existing| existing.layout_hash = 0) // ambiguous, so we can't know the layout
+                .or_insert(*abi);
+        }
+        for mangled in &krate.imports {
+            if let Some(abi) = all_exports.get(mangled.as_str()) {
+                crate_abi.imports.insert(demangle_without_hash(mangled), *abi);
+            }
+        }
+
+        let abi_file = output_dir.join(path.with_extension("abi").file_name().unwrap());
+        let bytes = bincode::serde::encode_to_vec(&crate_abi, bincode::config::standard())
+            .map_err(|e| format!("couldn't serialize ABI metadata for {:?}: {}", path, e))?;
+        fs::write(&abi_file, bytes).map_err(|e| format!("couldn't write {:?}: {}", abi_file, e))?;
+    }
+
+    Ok(())
+}
+
+
+/// The symbols defined and used by a single crate object file.
+struct ParsedCrate {
+    /// The ABI of each global symbol defined by this crate, keyed by its mangled name,
+    /// along with its demangled name without the hash.
+    exports: BTreeMap<String, (String, SymbolAbi)>,
+    /// The mangled names of the undefined symbols that this crate uses.
+    imports: Vec<String>,
+}
+
+fn parse_crate(path: &Path) -> Result<ParsedCrate, String> {
+    let data = fs::read(path).map_err(|e| format!("couldn't read {:?}: {}", path, e))?;
+    let file = object::File::parse(&*data).map_err(|e| format!("couldn't parse {:?}: {}", path, e))?;
+    if file.section_by_name(ABI_SECTION_NAME).is_some() {
+        return Err(format!("{:?} already contains a {} section", path, ABI_SECTION_NAME));
+    }
+
+    // A missing or malformed DWARF just means that type layouts are unknown.
+    let layout_hashes = dwarf::layout_hashes(&file).unwrap_or_else(|e| {
+        eprintln!("Warning: couldn't parse debug info in {:?}: {}", path, e);
+        HashMap::new()
+    });
+
+    let mut exports = BTreeMap::new();
+    let mut imports = Vec::new();
+    for symbol in file.symbols() {
+        let Ok(name) = symbol.name() else { continue };
+        if name.is_empty() {
+            continue;
+        }
+        if symbol.is_undefined() {
+            imports.push(name.to_string());
+            continue;
+        }
+        if !symbol.is_global() {
+            continue;
+        }
+        let (kind, size) = match symbol.kind() {
+            object::SymbolKind::Text => (SymbolKind::Function, 0),
+            object::SymbolKind::Data => (SymbolKind::Data, symbol.size()),
+            object::SymbolKind::Tls => (SymbolKind::Tls, symbol.size()),
+            _ => continue,
+        };
+        let abi = SymbolAbi {
+            kind,
+            size,
+            layout_hash: layout_hashes.get(name).copied().unwrap_or(0),
+        };
+        exports.insert(name.to_string(), (demangle_without_hash(name), abi));
+    }
+
+    Ok(ParsedCrate { exports, imports })
+}
+
+/// Demangles the given symbol name and removes its hash suffix,
+/// which matches the form of the symbol names in a `CrateAbi`.
+fn demangle_without_hash(mangled: &str) -> String {
+    format!("{:#}", rustc_demangle::demangle(mangled))
+}
+
+fn print_usage(program: &str, opts: Options) {
+    let brief = format!("Usage: {} -i INPUT_DIR -o OUTPUT_DIR", program);
+    print!("{}", opts.usage(&brief));
+}
@@ -0,0 +1,137 @@
+//! Signs every crate object file in a Theseus build with an Ed25519 key.
+//!
+//! Each crate object file `<crate>.o` in the input directory is signed in place
+//! by appending a [`CrateSignature`] trailer to it, which `mod_mgmt` and `ota_update_client`
+//! verify against the trusted keys built into the `nano_core` (see `kernel/crate_signatures`).
+//! Files that are already signed are re-signed, replacing their existing signature.
+//!
+//! As the signature covers the entire object file, this must be the final step
+//! that modifies the crate object files, e.g., after debug info is stripped.
+//!
+//! A signing key file contains the hex-encoded 32-byte seed of an Ed25519 key pair,
+//! which can be generated with `--generate-key`.
+
+use crate_metadata_serde::CrateSignature;
+use ed25519_compact::{KeyPair, Seed};
+use getopts::Options;
+use std::{
+    env,
+    fs,
+    path::{Path, PathBuf},
+};
+
+
+fn main() -> Result<(), String> {
+    let args: Vec<String> = env::args().collect();
+
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optopt(
+        "",
+        "generate-key",
+        "generate a new signing key, write it to KEY_FILE, and print its public key",
+        "KEY_FILE"
+    );
+    opts.optflag(
+        "p",
+        "public-key",
+        "print the hex-encoded public key of the signing key given by --key, which can be added to kernel/crate_signatures/trusted_keys.txt"
+    );
+    opts.optopt(
+        "k",
+        "key",
+        "path to the signing key file",
+        "KEY_FILE"
+    );
+    opts.optopt(
+        "i",
+        "input",
+        "path to the directory of crate object files to sign in place, e.g., \"/path/to/build/grub-isofiles/modules/\"",
+        "INPUT_DIR"
+    );
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            print_usage(&args[0], opts);
+            return Err(e.to_string());
+        }
+    };
+    if matches.opt_present("h") {
+        print_usage(&args[0], opts);
+        return Ok(());
+    }
+
+    if let Some(key_file) = matches.opt_str("generate-key") {
+        let key_file = PathBuf::from(key_file);
+        if key_file.exists() {
+            return Err(format!("key file {:?} already exists, refusing to overwrite it", key_file));
+        }
+        let seed = Seed::generate();
+        fs::write(&key_file, format!("{}\n", to_hex(&seed[..])))
+            .map_err(|e| format!("couldn't write key file {:?}: {}", key_file, e))?;
+        println!("{}", to_hex(&KeyPair::from_seed(seed).pk[..]));
+        return Ok(());
+    }
+
+    let key_file = matches.opt_str("k").ok_or("a signing key file must be given with --key")?;
+    let key_pair = read_key_pair(Path::new(&key_file))?;
+    if matches.opt_present("p") {
+        println!("{}", to_hex(&key_pair.pk[..]));
+        return Ok(());
+    }
+
+    let input_dir = PathBuf::from(matches.opt_str("i").ok_or("an input directory must be given with --input")?);
+    let mut object_files: Vec<PathBuf> = fs::read_dir(&input_dir)
+        .map_err(|e| format!("couldn't read input directory {:?}: {}", input_dir, e))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().map_or(false, |ext| ext == "o"))
+        .collect();
+    object_files.sort();
+
+    for path in &object_files {
+        sign_crate_object_file(path, &key_pair)?;
+    }
+    println!("Signed {} crate object files in {:?} with public key {}", object_files.len(), input_dir, to_hex(&key_pair.pk[..]));
+    Ok(())
+}
+
+/// Signs the given crate object file in place, replacing its existing signature, if any.
+fn sign_crate_object_file(path: &Path, key_pair: &KeyPair) -> Result<(), String> {
+    let file = fs::read(path).map_err(|e| format!("couldn't read crate object file {:?}: {}", path, e))?;
+    let (contents, _existing_signature) = CrateSignature::split(&file);
+    let signature = CrateSignature {
+        signature: *key_pair.sk.sign(contents, None),
+        public_key: *key_pair.pk,
+    };
+    let mut signed = Vec::with_capacity(contents.len() + CrateSignature::TRAILER_LEN);
+    signed.extend_from_slice(contents);
+    signed.extend_from_slice(&signature.to_trailer());
+    fs::write(path, signed).map_err(|e| format!("couldn't write signed crate object file {:?}: {}", path, e))
+}
+
+fn read_key_pair(key_file: &Path) -> Result<KeyPair, String> {
+    let hex = fs::read_to_string(key_file).map_err(|e| format!("couldn't read key file {:?}: {}", key_file, e))?;
+    let seed = from_hex(hex.trim())
+        .and_then(|bytes| Seed::from_slice(&bytes).ok())
+        .ok_or_else(|| format!("key file {:?} must contain a hex-encoded 32-byte seed", key_file))?;
+    Ok(KeyPair::from_seed(seed))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i .. i + 2], 16).ok())
+        .collect()
+}
+
+fn print_usage(program: &str, opts: Options) {
+    let brief = format!("Usage: {} --key KEY_FILE --input INPUT_DIR\n       {} --generate-key KEY_FILE", program, program);
+    print!("{}", opts.usage(&brief));
+}